@@ -0,0 +1,88 @@
+// Plays through a small program's prompts with frame hooks: one presses Enter when "Press ENTER"
+// shows up, another answers "y" to "Continue? (y/n)", and a third gives up if the program hasn't
+// finished after a while. It shows how to write a bot or a test against libpseudos in plain Rust.
+//
+// Run with: cargo run --example autoplay
+
+use libpseudos::dos_drive_table::DosDriveTable;
+use libpseudos::dos_event_handler::{DosEventHandler, KeyPressInfo, UnknownFuncAction, STANDARD_TICK_SECONDS};
+use libpseudos::dos_frame_hooks::{FrameHooks, HookAction};
+use libpseudos::dos_frame_slicing::{FrameRun, SliceEnd, OPCODES_PER_STANDARD_TICK};
+use libpseudos::emulator::{Emulator, EmulatorOptions, ProgramImage};
+use xachtsechs::machine8086::Machine8086;
+
+//   mov dx, prompt1; mov ah, 09h; int 21h
+// wait_enter:
+//   mov ah, 00h; int 16h; cmp al, 0dh; jne wait_enter
+//   mov dx, prompt2; mov ah, 09h; int 21h
+// wait_yes:
+//   mov ah, 00h; int 16h; cmp al, 'y'; jne wait_yes
+//   mov ax, 4c00h; int 21h
+// prompt1: db "Press ENTER$"
+// prompt2: db 13, 10, "Continue? (y/n)$"
+const PROMPT_PROGRAM: &[u8] = b"\xba\x23\x01\xb4\x09\xcd\x21\
+	\xb4\x00\xcd\x16\x3c\x0d\x75\xf8\
+	\xba\x2f\x01\xb4\x09\xcd\x21\
+	\xb4\x00\xcd\x16\x3c\x79\x75\xf8\
+	\xb8\x00\x4c\xcd\x21\
+	Press ENTER$\
+	\r\nContinue? (y/n)$";
+const MAX_FRAMES: u64 = 1000;
+
+fn make_machine() -> (DosEventHandler, Machine8086) {
+	let mut options = EmulatorOptions::default();
+	options.dos.unknown_function_action = UnknownFuncAction::Panic;
+	options.dos.unknown_interrupt_action = UnknownFuncAction::Panic;
+	options.video.print_screen_dir = None;
+	let program = ProgramImage::new(PROMPT_PROGRAM.to_vec(), b"C:\\PROMPT.COM");
+	let emulator = Emulator::new(Some(program), Box::new(DosDriveTable::new()), options).unwrap();
+	(emulator.handler, emulator.machine)
+}
+
+fn main() {
+	let (mut handler, mut machine) = make_machine();
+	let mut hooks = FrameHooks::new();
+	hooks.add_frame_hook(|context| {
+		if context.screen_contains(b"Press ENTER") {
+			println!("Frame {}: pressing Enter", context.frame_number());
+			context.press_key(KeyPressInfo { scan_code: 0x1c, ascii_char: b'\r' });
+			HookAction::Remove
+		} else {
+			HookAction::Keep
+		}
+	});
+	hooks.add_frame_hook(|context| {
+		if context.screen_contains(b"Continue? (y/n)") {
+			println!("Frame {}: answering y", context.frame_number());
+			context.press_key(KeyPressInfo { scan_code: 0x15, ascii_char: b'y' });
+			HookAction::Remove
+		} else {
+			HookAction::Keep
+		}
+	});
+	hooks.add_frame_hook(|context| {
+		if context.frame_number() >= MAX_FRAMES {
+			context.stop();
+		}
+		HookAction::Keep
+	});
+
+	loop {
+		handler.seconds_since_start += STANDARD_TICK_SECONDS;
+		let mut frame_run = FrameRun::new(OPCODES_PER_STANDARD_TICK, 1, 1);
+		loop {
+			match frame_run.run_slice(&mut machine, &mut handler).unwrap() {
+				SliceEnd::Slice => {}
+				SliceEnd::Frame => break,
+				SliceEnd::Exited(exit_code) => {
+					println!("The program exited with code {}", exit_code);
+					return;
+				}
+			}
+		}
+		if hooks.run(&mut machine, &mut handler).stop {
+			println!("The program didn't finish within {} frames", MAX_FRAMES);
+			std::process::exit(1);
+		}
+	}
+}
@@ -0,0 +1,97 @@
+// Measures how long it takes from a key being pressed to a program reading it and to the key it
+// echoes being presented on the screen, for different ways of slicing each frame. Host time is
+// simulated: slice i of frame k runs at k + i/n frames, input is polled just before each slice, and
+// the screen is presented after the last slice (and between slices if the policy allows).
+//
+// Run with: cargo run --release --example input_latency
+
+use libpseudos::dos_drive_table::DosDriveTable;
+use libpseudos::dos_event_handler::{DosEventHandler, KeyPressInfo, UnknownFuncAction, STANDARD_TICK_SECONDS};
+use libpseudos::dos_frame_slicing::{count_changed_cells, FrameRun, FrameSlicing, MidFramePresent, SliceEnd, OPCODES_PER_STANDARD_TICK};
+use libpseudos::emulator::{Emulator, EmulatorOptions, ProgramImage};
+use xachtsechs::machine8086::Machine8086;
+
+// loop: mov ah, 01h; int 16h; jz loop; mov ah, 00h; int 16h; mov ah, 0eh; int 10h; jmp loop
+const ECHO_PROGRAM: &[u8] = &[0xb4, 0x01, 0xcd, 0x16, 0x74, 0xfa, 0xb4, 0x00, 0xcd, 0x16, 0xb4, 0x0e, 0xcd, 0x10, 0xeb, 0xf0];
+const TEXT_PAGE_ADDR: usize = 0xb8000;
+const TEXT_PAGE_BYTES: usize = 80 * 25 * 2;
+const TRIALS: usize = 50;
+
+fn make_machine() -> (DosEventHandler, Machine8086) {
+	let mut options = EmulatorOptions::default();
+	options.dos.unknown_function_action = UnknownFuncAction::Panic;
+	options.dos.unknown_interrupt_action = UnknownFuncAction::Panic;
+	options.video.print_screen_dir = None;
+	let program = ProgramImage::new(ECHO_PROGRAM.to_vec(), b"C:\\ECHO.COM");
+	let emulator = Emulator::new(Some(program), Box::new(DosDriveTable::new()), options).unwrap();
+	(emulator.handler, emulator.machine)
+}
+
+// Returns the frames from the key being pressed to the program reading it, and to the echoed key
+// being presented.
+fn measure(slicing: &FrameSlicing, key_time: f64) -> (f64, f64) {
+	let (mut handler, mut machine) = make_machine();
+	let mut presented_text = machine.memory[TEXT_PAGE_ADDR .. TEXT_PAGE_ADDR + TEXT_PAGE_BYTES].to_vec();
+	let mut key_pressed = false;
+	let mut read_time = None;
+	for frame_index in 0 .. {
+		handler.seconds_since_start += STANDARD_TICK_SECONDS;
+		let mut frame_run = FrameRun::new(OPCODES_PER_STANDARD_TICK, 1, slicing.slices_per_frame);
+		let mut mid_frame_presents = 0;
+		loop {
+			let slice_time = frame_index as f64 + frame_run.finished_slices() as f64 / frame_run.slice_count() as f64;
+			if !key_pressed && key_time <= slice_time {
+				handler.key_press_queue.push_back(KeyPressInfo { scan_code: 0x25, ascii_char: b'k' });
+				key_pressed = true;
+			}
+			let slice_end = frame_run.run_slice(&mut machine, &mut handler).unwrap();
+			if key_pressed && read_time.is_none() && handler.key_press_queue.is_empty() {
+				read_time = Some(slice_time);
+			}
+			let text = &machine.memory[TEXT_PAGE_ADDR .. TEXT_PAGE_ADDR + TEXT_PAGE_BYTES];
+			let present = match slice_end {
+				SliceEnd::Slice => {
+					let changed_cells = count_changed_cells(&presented_text, text);
+					slicing.should_present_mid_frame(changed_cells, TEXT_PAGE_BYTES / 2, mid_frame_presents)
+				}
+				SliceEnd::Frame => true,
+				SliceEnd::Exited(_) => panic!("The echo program exited"),
+			};
+			if present {
+				if text[0] == b'k' {
+					return (read_time.unwrap() - key_time, slice_time - key_time);
+				}
+				presented_text.copy_from_slice(text);
+				if slice_end == SliceEnd::Slice {
+					mid_frame_presents += 1;
+				}
+			}
+			if slice_end == SliceEnd::Frame {
+				break;
+			}
+		}
+	}
+	unreachable!()
+}
+
+fn main() {
+	let frame_ms = STANDARD_TICK_SECONDS * 1000.;
+	println!("{:>7} {:>20} {:>14} {:>17}", "slices", "mid-frame present", "read (ms)", "presented (ms)");
+	for &mid_frame_present in [MidFramePresent::Never, MidFramePresent::WhenChanged(0.25), MidFramePresent::WhenChanged(0.)].iter() {
+		for &slices_per_frame in [1, 2, 4, 8, 16, 32, 64].iter() {
+			let slicing = FrameSlicing { slices_per_frame, mid_frame_present };
+			let (mut total_read, mut total_presented) = (0., 0.);
+			for trial in 0 .. TRIALS {
+				// Spread the keypresses over the second frame, after the program has started.
+				let (read, presented) = measure(&slicing, 1. + (trial as f64 + 0.5) / TRIALS as f64);
+				total_read += read;
+				total_presented += presented;
+			}
+			let policy = match mid_frame_present {
+				MidFramePresent::Never => "never".to_string(),
+				MidFramePresent::WhenChanged(fraction) => format!("{}% changed", fraction * 100.),
+			};
+			println!("{:>7} {:>20} {:>14.1} {:>17.1}", slices_per_frame, policy, total_read / TRIALS as f64 * frame_ms, total_presented / TRIALS as f64 * frame_ms);
+		}
+	}
+}
@@ -0,0 +1,51 @@
+// Measures how long it takes to draw the text screen into an image with TextRaster, which the SDL
+// frontend does every frame before uploading it, against drawing it a cell at a time the way the
+// frontend used to (TextRaster::render_by_cell). The screen is full of changing characters in all
+// the colours, with blinking and the cursor on, for the 14 and 16 pixel high character cells.
+//
+// Run with: cargo run --release --example render_bench
+
+use libpseudos::dos_palette::PaletteTransform;
+use libpseudos::dos_text_raster::{TextFont, TextRaster, TextScreen, GLYPH_WIDTH};
+use std::time::Instant;
+
+const FRAMES: usize = 2000;
+const COLUMNS: usize = 80;
+const ROWS: usize = 25;
+
+fn main() {
+	// A made up font with a different pattern for each character, so every pixel is looked up.
+	let font = TextFont::from_strip(|x, y| (x / GLYPH_WIDTH + x % GLYPH_WIDTH + y) & 3 == 0);
+	let palette = PaletteTransform::default();
+	let mut raster = TextRaster::new();
+	let mut text = vec![0; COLUMNS * ROWS * 2];
+
+	println!("{:>12} {:>8} {:>10} {:>14} {:>8}", "char height", "path", "frames", "ms per frame", "fps");
+	for &char_height in &[14, 16] {
+		for &by_cell in &[true, false] {
+			let start = Instant::now();
+			for frame in 0 .. FRAMES {
+				for (cell_index, cell) in text.chunks_mut(2).enumerate() {
+					cell[0] = (cell_index + frame) as u8;
+					cell[1] = (cell_index * 7 + frame) as u8;
+				}
+				let screen = TextScreen {
+					text: &text,
+					columns: COLUMNS,
+					rows: ROWS,
+					char_height,
+					blink_enabled: true,
+					blink_visible: frame % 32 < 16,
+					cursor: Some((frame % COLUMNS, frame / COLUMNS % ROWS, 12, 13)),
+				};
+				if by_cell {
+					raster.render_by_cell(&font, &palette, &screen);
+				} else {
+					raster.render(&font, &palette, &screen);
+				}
+			}
+			let frame_ms = start.elapsed().as_secs_f64() * 1000. / FRAMES as f64;
+			println!("{:>12} {:>8} {:>10} {:>14.3} {:>8.0}", char_height, if by_cell { "by cell" } else { "image" }, FRAMES, frame_ms, 1000. / frame_ms);
+		}
+	}
+}
@@ -0,0 +1,185 @@
+// Raw floppy disk images for the BIOS disk services (int 13h). Programs address the image by
+// cylinder, head and sector like a real drive, with the geometry worked out from the size of the
+// image. This is separate from the DOS file systems, which deal in files rather than sectors.
+// http://www.ctyme.com/intr/int-13.htm
+
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const FLOPPY_SECTOR_BYTES: usize = 512;
+
+// The status codes int 13h returns in AH, which the BIOS data area also keeps for AH=01h.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum DiskStatus {
+	Ok = 0x00,
+	InvalidFunction = 0x01,
+	WriteProtected = 0x03,
+	SectorNotFound = 0x04,
+	// The drive didn't answer, which is what happens when there's no disk in it.
+	Timeout = 0x80,
+	WriteFault = 0xcc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloppyGeometry {
+	pub cylinders: u16,
+	pub heads: u8,
+	pub sectors_per_track: u8,
+	// The CMOS drive type, which int 13h AH=08h returns in BL.
+	pub drive_type: u8,
+}
+
+const FLOPPY_GEOMETRIES: [FloppyGeometry; 4] = [
+	// 360K 5.25"
+	FloppyGeometry { cylinders: 40, heads: 2, sectors_per_track: 9, drive_type: 1 },
+	// 720K 3.5"
+	FloppyGeometry { cylinders: 80, heads: 2, sectors_per_track: 9, drive_type: 3 },
+	// 1.2M 5.25"
+	FloppyGeometry { cylinders: 80, heads: 2, sectors_per_track: 15, drive_type: 2 },
+	// 1.44M 3.5"
+	FloppyGeometry { cylinders: 80, heads: 2, sectors_per_track: 18, drive_type: 4 },
+];
+
+impl FloppyGeometry {
+	/// Finds the standard disk format an image of this size is for.
+	pub fn from_image_bytes(image_bytes: usize) -> Option<FloppyGeometry> {
+		FLOPPY_GEOMETRIES.iter().find(|geometry| geometry.image_bytes() == image_bytes).cloned()
+	}
+
+	pub fn sector_count(&self) -> usize {
+		self.cylinders as usize * self.heads as usize * self.sectors_per_track as usize
+	}
+
+	pub fn image_bytes(&self) -> usize {
+		self.sector_count() * FLOPPY_SECTOR_BYTES
+	}
+
+	/// Gets the index of a sector from the start of the disk, or None if it isn't on the disk.
+	/// Sectors are numbered from 1.
+	pub fn sector_index(&self, cylinder: u16, head: u8, sector: u8) -> Option<usize> {
+		if cylinder >= self.cylinders || head >= self.heads || sector == 0 || sector > self.sectors_per_track {
+			return None;
+		}
+		Some((cylinder as usize * self.heads as usize + head as usize) * self.sectors_per_track as usize + sector as usize - 1)
+	}
+}
+
+pub struct FloppyImage {
+	data: Vec<u8>,
+	geometry: FloppyGeometry,
+	write_protected: bool,
+	// The image file that writes are saved to, if the image came from one.
+	file: Option<File>,
+}
+
+impl std::fmt::Debug for FloppyImage {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("FloppyImage").field("geometry", &self.geometry).field("write_protected", &self.write_protected).finish()
+	}
+}
+
+impl FloppyImage {
+	/// Makes a disk from an image in memory, which has to be the size of a standard disk.
+	pub fn new(data: Vec<u8>, write_protected: bool) -> Result<FloppyImage, String> {
+		let geometry = FloppyGeometry::from_image_bytes(data.len())
+			.ok_or_else(|| format!("A floppy image of {} bytes isn't a 360K, 720K, 1.2M or 1.44M disk", data.len()))?;
+		Ok(FloppyImage {
+			data,
+			geometry,
+			write_protected,
+			file: None,
+		})
+	}
+
+	/// Loads an image file, which is written to when the program writes to the disk. The disk is
+	/// write protected if the file can't be written to.
+	pub fn open(path: &Path, write_protected: bool) -> Result<FloppyImage, String> {
+		let data = std::fs::read(path).map_err(|err| err.to_string())?;
+		let mut image = FloppyImage::new(data, write_protected)?;
+		if !image.write_protected {
+			match OpenOptions::new().write(true).open(path) {
+				Ok(file) => image.file = Some(file),
+				Err(_) => image.write_protected = true,
+			}
+		}
+		Ok(image)
+	}
+
+	pub fn geometry(&self) -> FloppyGeometry {
+		self.geometry
+	}
+
+	pub fn is_write_protected(&self) -> bool {
+		self.write_protected
+	}
+
+	pub fn data(&self) -> &[u8] {
+		&self.data
+	}
+
+	// Gets the byte range of a run of sectors. A run can go on past the end of the track to the
+	// next head and cylinder, like on BIOSes that do multi-track transfers, but not past the end of
+	// the disk.
+	fn sector_range(&self, cylinder: u16, head: u8, sector: u8, count: u8) -> Result<std::ops::Range<usize>, DiskStatus> {
+		if count == 0 {
+			return Err(DiskStatus::InvalidFunction);
+		}
+		let start_index = self.geometry.sector_index(cylinder, head, sector).ok_or(DiskStatus::SectorNotFound)?;
+		if start_index + count as usize > self.geometry.sector_count() {
+			return Err(DiskStatus::SectorNotFound);
+		}
+		Ok(start_index * FLOPPY_SECTOR_BYTES .. (start_index + count as usize) * FLOPPY_SECTOR_BYTES)
+	}
+
+	/// Reads count sectors, starting at a sector numbered from 1.
+	pub fn read_sectors(&self, cylinder: u16, head: u8, sector: u8, count: u8) -> Result<&[u8], DiskStatus> {
+		let range = self.sector_range(cylinder, head, sector, count)?;
+		Ok(&self.data[range])
+	}
+
+	/// Writes whole sectors, starting at a sector numbered from 1, and saves them to the image
+	/// file if there is one.
+	pub fn write_sectors(&mut self, cylinder: u16, head: u8, sector: u8, data: &[u8]) -> Result<(), DiskStatus> {
+		let count = data.len() / FLOPPY_SECTOR_BYTES;
+		if count * FLOPPY_SECTOR_BYTES != data.len() || count > 0xff {
+			return Err(DiskStatus::InvalidFunction);
+		}
+		let range = self.sector_range(cylinder, head, sector, count as u8)?;
+		if self.write_protected {
+			return Err(DiskStatus::WriteProtected);
+		}
+		if let Some(ref mut file) = self.file {
+			file.seek(SeekFrom::Start(range.start as u64)).and_then(|_| file.write_all(data)).map_err(|_| DiskStatus::WriteFault)?;
+		}
+		self.data[range].copy_from_slice(data);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_floppy_geometry() {
+		assert_eq!(FloppyGeometry::from_image_bytes(368640).map(|geometry| (geometry.cylinders, geometry.sectors_per_track)), Some((40, 9)));
+		assert_eq!(FloppyGeometry::from_image_bytes(1474560).map(|geometry| (geometry.cylinders, geometry.sectors_per_track)), Some((80, 18)));
+		assert_eq!(FloppyGeometry::from_image_bytes(1474561), None);
+		assert!(FloppyImage::new(vec![0; 1000], false).is_err());
+
+		let geometry = FloppyGeometry::from_image_bytes(737280).unwrap();
+		assert_eq!(geometry.sector_index(0, 0, 1), Some(0));
+		assert_eq!(geometry.sector_index(0, 1, 1), Some(9));
+		assert_eq!(geometry.sector_index(1, 0, 9), Some(26));
+		assert_eq!(geometry.sector_index(0, 0, 0), None);
+		assert_eq!(geometry.sector_index(0, 0, 10), None);
+		assert_eq!(geometry.sector_index(0, 2, 1), None);
+		assert_eq!(geometry.sector_index(80, 0, 1), None);
+
+		let mut image = FloppyImage::new(vec![0; 737280], false).unwrap();
+		assert_eq!(image.read_sectors(79, 1, 9, 1).map(|data| data.len()), Ok(FLOPPY_SECTOR_BYTES));
+		assert_eq!(image.read_sectors(79, 1, 9, 2), Err(DiskStatus::SectorNotFound));
+		assert_eq!(image.write_sectors(0, 0, 1, &[0; 100]), Err(DiskStatus::InvalidFunction));
+	}
+}
@@ -0,0 +1,182 @@
+// The keyboard's own settings: how fast held keys repeat (the typematic rate and delay), and its
+// LEDs. Programs change them through int 16h AH=03h, or by sending commands to the keyboard through
+// port 0x60. The LEDs aren't kept here, as they always show the lock toggles in the BIOS shift
+// status byte (see KeyModType), so setting the LEDs sets the toggles too.
+// http://stanislavs.org/helppc/keyboard_commands.html
+
+use crate::dos_event_handler::KeyModType;
+
+// The keyboard commands that take a data byte after them.
+const COMMAND_SET_LEDS: u8 = 0xed;
+const COMMAND_SET_TYPEMATIC: u8 = 0xf3;
+// What the keyboard answers each byte it's sent with.
+const KEYBOARD_ACK: u8 = 0xfa;
+
+/// The typematic rate and delay, as the indexes int 16h AH=03h and keyboard command F3h take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Typematic {
+	/// 0 (30 repeats a second) to 0x1f (2 a second).
+	pub rate: u8,
+	/// 0 (250ms) to 3 (1 second).
+	pub delay: u8,
+}
+
+impl Default for Typematic {
+	// What the BIOS sets at startup: 10.9 repeats a second after half a second.
+	fn default() -> Typematic {
+		Typematic { rate: 0x0b, delay: 1 }
+	}
+}
+
+impl Typematic {
+	/// From the data byte of keyboard command F3h, which has the rate in bits 0-4 and the delay in
+	/// bits 5-6.
+	pub fn from_command_byte(value: u8) -> Typematic {
+		Typematic { rate: value & 0x1f, delay: (value >> 5) & 0x03 }
+	}
+
+	pub fn repeats_per_second(&self) -> f64 {
+		// The period is (8 + A) * 2^B * 4.17ms, where A is bits 0-2 of the rate and B is bits 3-4.
+		let period_seconds = (8 + (self.rate & 0x07) as u32) as f64 * (1 << ((self.rate >> 3) & 0x03)) as f64 * 0.00417;
+		1. / period_seconds
+	}
+
+	pub fn delay_seconds(&self) -> f64 {
+		(self.delay & 0x03) as f64 * 0.25 + 0.25
+	}
+}
+
+/// Which of the keyboard's LEDs are on.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct KeyboardLeds {
+	pub scroll_lock: bool,
+	pub num_lock: bool,
+	pub caps_lock: bool,
+}
+
+impl KeyboardLeds {
+	/// The LEDs that show the toggles in a BIOS shift status byte.
+	pub fn from_key_mod(key_mod: u8) -> KeyboardLeds {
+		KeyboardLeds {
+			scroll_lock: key_mod & KeyModType::ScrollLock.bit() != 0,
+			num_lock: key_mod & KeyModType::NumLock.bit() != 0,
+			caps_lock: key_mod & KeyModType::CapsLock.bit() != 0,
+		}
+	}
+
+	/// From the data byte of keyboard command EDh, which has scroll lock in bit 0, num lock in bit 1
+	/// and caps lock in bit 2.
+	pub fn from_command_byte(value: u8) -> KeyboardLeds {
+		KeyboardLeds {
+			scroll_lock: value & 0x01 != 0,
+			num_lock: value & 0x02 != 0,
+			caps_lock: value & 0x04 != 0,
+		}
+	}
+
+	/// Sets the toggles in a BIOS shift status byte to match the LEDs.
+	pub fn apply_to_key_mod(&self, key_mod: u8) -> u8 {
+		let mut key_mod = key_mod;
+		for &(mod_type, on) in [(KeyModType::ScrollLock, self.scroll_lock), (KeyModType::NumLock, self.num_lock), (KeyModType::CapsLock, self.caps_lock)].iter() {
+			if on {
+				key_mod |= mod_type.bit();
+			} else {
+				key_mod &= !mod_type.bit();
+			}
+		}
+		key_mod
+	}
+}
+
+/// What a byte the program sent to the keyboard changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyboardCommandEffect {
+	None,
+	SetLeds(KeyboardLeds),
+	SetTypematic,
+}
+
+/// The keyboard as programs talk to it through ports 0x60 and 0x64. Only the commands that change
+/// the settings above do anything, but every byte is acknowledged so programs waiting for the
+/// answer carry on.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KeyboardController {
+	pub typematic: Typematic,
+	// The command sent to port 0x60 that's waiting for its data byte.
+	pending_command: Option<u8>,
+	// The byte waiting to be read from port 0x60.
+	output: Option<u8>,
+}
+
+impl KeyboardController {
+	pub fn new() -> KeyboardController {
+		KeyboardController::default()
+	}
+
+	/// Takes a byte written to port 0x60.
+	pub fn write_data(&mut self, value: u8) -> KeyboardCommandEffect {
+		self.output = Some(KEYBOARD_ACK);
+		match self.pending_command.take() {
+			Some(COMMAND_SET_LEDS) => KeyboardCommandEffect::SetLeds(KeyboardLeds::from_command_byte(value)),
+			Some(COMMAND_SET_TYPEMATIC) => {
+				self.typematic = Typematic::from_command_byte(value);
+				KeyboardCommandEffect::SetTypematic
+			}
+			_ => {
+				if value == COMMAND_SET_LEDS || value == COMMAND_SET_TYPEMATIC {
+					self.pending_command = Some(value);
+				}
+				KeyboardCommandEffect::None
+			}
+		}
+	}
+
+	/// Reads port 0x60, which is the keyboard's last answer.
+	pub fn read_data(&mut self) -> u8 {
+		self.output.take().unwrap_or(0)
+	}
+
+	/// Reads the status register at port 0x64. Bit 0 is set when there's an answer to read, and bit
+	/// 1 (the keyboard is still busy with the last byte) is never set.
+	pub fn read_status(&self) -> u8 {
+		if self.output.is_some() { 0x01 } else { 0x00 }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_typematic_timing() {
+		let slowest = Typematic { rate: 0x1f, delay: 3 };
+		assert!((slowest.repeats_per_second() - 2.0).abs() < 0.01);
+		assert_eq!(slowest.delay_seconds(), 1.);
+		let fastest = Typematic { rate: 0, delay: 0 };
+		assert!((fastest.repeats_per_second() - 30.0).abs() < 0.05);
+		assert_eq!(fastest.delay_seconds(), 0.25);
+		assert!((Typematic::default().repeats_per_second() - 10.9).abs() < 0.01);
+		assert_eq!(Typematic::from_command_byte(0x7f), Typematic { rate: 0x1f, delay: 3 });
+	}
+
+	#[test] fn test_keyboard_commands() {
+		let mut keyboard = KeyboardController::new();
+		assert_eq!(keyboard.read_status(), 0);
+		assert_eq!(keyboard.write_data(0xed), KeyboardCommandEffect::None);
+		assert_eq!(keyboard.read_status(), 1);
+		assert_eq!(keyboard.read_data(), KEYBOARD_ACK);
+		assert_eq!(keyboard.read_status(), 0);
+		let leds = KeyboardLeds { scroll_lock: false, num_lock: true, caps_lock: true };
+		assert_eq!(keyboard.write_data(0x06), KeyboardCommandEffect::SetLeds(leds));
+		// The data byte isn't taken as another command.
+		assert_eq!(keyboard.write_data(0xf3), KeyboardCommandEffect::None);
+		assert_eq!(keyboard.write_data(0x20), KeyboardCommandEffect::SetTypematic);
+		assert_eq!(keyboard.typematic, Typematic { rate: 0, delay: 1 });
+		// Commands that don't change anything still get an answer.
+		assert_eq!(keyboard.write_data(0xf4), KeyboardCommandEffect::None);
+		assert_eq!(keyboard.read_data(), KEYBOARD_ACK);
+
+		let key_mod = KeyModType::Insert.bit() | KeyModType::ScrollLock.bit();
+		assert_eq!(leds.apply_to_key_mod(key_mod), KeyModType::Insert.bit() | KeyModType::NumLock.bit() | KeyModType::CapsLock.bit());
+		assert_eq!(KeyboardLeds::from_key_mod(leds.apply_to_key_mod(key_mod)), leds);
+	}
+}
@@ -1,5 +1,7 @@
-use xachtsechs::machine8086::Machine8086;
-use xachtsechs::types::{DataLocation8, DataLocation16};
+use crate::machine::Machine;
+use crate::dos_memory::FIRST_MCB_SEGMENT;
+use xachtsechs::machine8086::INTERRUPT_TABLE_ENTRY_BYTES;
+use xachtsechs::types::{DataLocation8, DataLocation16, Reg};
 
 pub const BIOS_START: u32 = 0x40 << 4;
 const fn bios_off_u8(offset: u32) -> DataLocation8 {
@@ -11,23 +13,211 @@ const fn bios_off_u16(offset: u32) -> DataLocation16 {
 
 pub const BIOS_EQUIPMENT: DataLocation16 = bios_off_u16(10);
 pub const BIOS_MEMORY_SIZE_KB: DataLocation16 = bios_off_u16(0x13);
+/// The shift status byte, with the bits of KeyModType.
+pub const BIOS_KEYBOARD_FLAGS: DataLocation8 = bios_off_u8(0x17);
+/// The extended shift status byte. Bit 0 is set while Ctrl is held, and bit 1 while Alt is.
+pub const BIOS_KEYBOARD_FLAGS_2: DataLocation8 = bios_off_u8(0x18);
+// The status of the last floppy disk operation, as returned by int 13h AH=01h.
+pub const BIOS_DISKETTE_STATUS: DataLocation8 = bios_off_u8(0x41);
 pub const BIOS_VIDEO_MODE_INDEX: DataLocation8 = bios_off_u8(0x49);
 pub const BIOS_TEXT_COLUMN_COUNT: DataLocation16 = bios_off_u16(0x4a);
 pub const BIOS_TEXT_PAGE_BYTES: DataLocation16 = bios_off_u16(0x4c);
+// The offset of the active page from the start of text memory.
+pub const BIOS_ACTIVE_PAGE_OFFSET: DataLocation16 = bios_off_u16(0x4e);
 pub const BIOS_CURSOR_POSITION: [DataLocation16; 8] = [
 	bios_off_u16(0x50), bios_off_u16(0x52), bios_off_u16(0x54), bios_off_u16(0x56),
 	bios_off_u16(0x58), bios_off_u16(0x5a), bios_off_u16(0x5c), bios_off_u16(0x5e),
 ];
+// High byte is the start scanline, low byte is the end scanline, as passed to int 10h AH=01h.
+pub const BIOS_CURSOR_TYPE: DataLocation16 = bios_off_u16(0x60);
 pub const BIOS_ACTIVE_VIDEO_PAGE: DataLocation8 = bios_off_u8(0x62);
 pub const BIOS_VIDEO_IO_PORT_ADDRESS: DataLocation16 = bios_off_u16(0x63);
 pub const BIOS_SYSTEM_TIMER_COUNTER_ADDR_U32: u32 = BIOS_START + 0x6c;
 pub const BIOS_SYSTEM_TIMER_COUNTER_LOW: DataLocation16 = bios_off_u16(0x6c);
 pub const BIOS_SYSTEM_TIMER_COUNTER_HIGH: DataLocation16 = bios_off_u16(0x6e);
-pub const BIOS_TEXT_ROW_COUNT: DataLocation16 = bios_off_u16(0x84);
+// Set when the tick counter passes midnight, until int 1Ah AH=00h reports it.
+pub const BIOS_TIMER_OVERFLOW: DataLocation8 = bios_off_u8(0x70);
+// The status of the last hard disk operation.
+pub const BIOS_HARD_DISK_STATUS: DataLocation8 = bios_off_u8(0x74);
+// The number of text rows minus one.
+pub const BIOS_TEXT_LAST_ROW: DataLocation8 = bios_off_u8(0x84);
 pub const BIOS_CHAR_HEIGHT: DataLocation16 = bios_off_u16(0x85);
 
+// The DOS data area follows the BIOS data area. Things DOS functions need to return pointers to
+// are kept at the end of it.
+pub const DOS_DATA_SEGMENT: u16 = 0x50;
+// The print screen status byte starts it: 0 when done, 1 while printing and FFh for an error.
+pub const BIOS_PRINT_SCREEN_STATUS: DataLocation8 = bios_off_u8(0x100);
+// Int 21h AH=1Bh/1Ch return a pointer to the media descriptor byte of the drive.
+pub const DOS_MEDIA_DESCRIPTOR_OFFSET: u16 = 0xf0;
+// Media descriptor for a fixed disk.
+const DOS_MEDIA_DESCRIPTOR_FIXED_DISK: u8 = 0xf8;
+// Int 21h AH=63h returns a pointer to the DBCS lead byte table, which is a list of byte ranges
+// ended by a zero word. It's left empty, since only single byte character sets are supported.
+pub const DOS_DBCS_LEAD_BYTE_TABLE_OFFSET: u16 = 0xf2;
+
+// Small pieces of code the emulator puts in memory, such as the default interrupt handlers, go in
+// this region after the DOS data area, so they never overlap the program.
+pub const BIOS_STUB_REGION_START: u32 = BIOS_START + 0x200;
+pub const BIOS_STUB_REGION_BYTES: u32 = 0x100;
+const BIOS_STUB_SEGMENT: u16 = (BIOS_STUB_REGION_START >> 4) as u16;
+
+// DOS's own tables go after the stub region, up to the first MCB. Int 21h AH=52h returns a pointer
+// to the list of lists, which has the segment of the first MCB in the word before it. That's what
+// programs that walk the MCB chain look for, and the structures the list points to are there too,
+// but empty: no drive parameter blocks, a file table with no files, and no current directory
+// structures (LASTDRIVE is 0). The device chain is NUL, CON and CLOCK$.
+// http://www.ctyme.com/intr/rb-2983.htm
+pub const DOS_TABLES_SEGMENT: u16 = ((BIOS_STUB_REGION_START + BIOS_STUB_REGION_BYTES) >> 4) as u16;
+pub const DOS_LIST_OF_LISTS_OFFSET: u16 = 0x02;
+// The list of lists as long as DOS 5 has it.
+const DOS_LIST_OF_LISTS_BYTES: u16 = 0x66;
+const DOS_LIST_OF_LISTS_NUL_DEVICE_OFFSET: u32 = 0x22;
+const DOS_SFT_OFFSET: u16 = DOS_LIST_OF_LISTS_OFFSET + DOS_LIST_OF_LISTS_BYTES;
+const DOS_SFT_HEADER_BYTES: u16 = 6;
+const DOS_DEVICE_HEADER_BYTES: u16 = 18;
+const DOS_CON_DEVICE_OFFSET: u16 = DOS_SFT_OFFSET + DOS_SFT_HEADER_BYTES;
+const DOS_CLOCK_DEVICE_OFFSET: u16 = DOS_CON_DEVICE_OFFSET + DOS_DEVICE_HEADER_BYTES;
+// The strategy and interrupt routine of every device, which is a retf, since the devices are
+// handled by the emulator rather than called.
+const DOS_DEVICE_ROUTINE_OFFSET: u16 = DOS_CLOCK_DEVICE_OFFSET + DOS_DEVICE_HEADER_BYTES;
+const DOS_CDS_OFFSET: u16 = DOS_DEVICE_ROUTINE_OFFSET + 1;
+const DOS_TABLES_END_OFFSET: u16 = DOS_CDS_OFFSET;
+const RETF: u8 = 0xcb;
+
+const IRET: u8 = 0xcf;
+
+/// The interrupt the handler callback stubs call. Its vector is left at 0000:0000, so the CPU hands
+/// it to the event handler rather than jumping anywhere.
+pub const HANDLER_CALLBACK_INTERRUPT: u8 = 0xe8;
+/// The interrupts the event handler services. Their vectors point at a stub each that calls back
+/// into the handler, so a program that hooks one and chains to the old vector with pushf and a far
+/// call still gets the BIOS or DOS function it called.
+pub const SERVICED_INTERRUPTS: [u8; 11] = [0x05, 0x08, 0x10, 0x13, 0x14, 0x16, 0x1a, 0x20, 0x21, 0x28, 0x33];
+// int HANDLER_CALLBACK_INTERRUPT; retf 2, which keeps the flags the handler set rather than the
+// caller's, as DOS and the BIOS do. The callback stubs are the first ones in the stub region.
+const HANDLER_CALLBACK_STUB: [u8; 5] = [0xcd, HANDLER_CALLBACK_INTERRUPT, 0xca, 0x02, 0x00];
+// Where the return address the int instruction pushes points in a handler callback stub.
+const HANDLER_CALLBACK_RETURN_OFFSET: usize = 2;
+
+/// Hands out space for code stubs in the stub region.
+pub struct SynthBiosStubWriter<'a> {
+	machine: &'a mut dyn Machine,
+	next_addr: u32,
+}
+
+impl<'a> SynthBiosStubWriter<'a> {
+	pub fn new(machine: &'a mut dyn Machine) -> SynthBiosStubWriter<'a> {
+		SynthBiosStubWriter {
+			machine,
+			next_addr: BIOS_STUB_REGION_START,
+		}
+	}
+	
+	/// Copies the code into the stub region and returns its physical address. Panics if the region
+	/// is full, since the stubs are fixed and should always fit.
+	pub fn write_stub(&mut self, code: &[u8]) -> u32 {
+		let addr = self.next_addr;
+		assert!(addr + code.len() as u32 <= BIOS_STUB_REGION_START + BIOS_STUB_REGION_BYTES, "The BIOS stub region is full");
+		self.machine.insert_contiguous_bytes(code, addr as usize);
+		self.next_addr += code.len() as u32;
+		addr
+	}
+}
+
+/// Gets the segment and offset of a stub, with the segment at the start of the stub region.
+pub fn bios_stub_far_pointer(addr: u32) -> (u16, u16) {
+	(BIOS_STUB_SEGMENT, (addr - BIOS_STUB_REGION_START) as u16)
+}
+
+/// Whether a segment and offset (like an interrupt vector) points into the stub region the way
+/// bios_stub_far_pointer gives it.
+pub fn is_bios_stub_far_pointer(segment: u16, offset: u16) -> bool {
+	segment == BIOS_STUB_SEGMENT && (offset as u32) < BIOS_STUB_REGION_BYTES
+}
+
+/// The physical addresses of the stubs planted when the BIOS data area is set up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BiosStubs {
+	/// Where the interrupt vectors point by default.
+	pub iret: u32,
+	/// The far call entry point for XMS, which reports every function as not implemented.
+	pub xms_entry: u32,
+	/// The int 67h handler, which reports every EMS function as not supported.
+	pub ems_entry: u32,
+	/// The int 2Fh handler, which returns with AL unchanged, so installation checks find nothing.
+	pub int_2fh: u32,
+	/// The diskette parameter table, which int 1Eh points to rather than to code.
+	pub diskette_parameter_table: u32,
+	/// Where int 22h points for the program that was loaded, as there's no parent program for it to
+	/// go back to. The emulation stops when the program ends, so this is never reached that way,
+	/// but a program that jumps to it exits.
+	pub terminate: u32,
+}
+
+/// The handler callback stub for one of SERVICED_INTERRUPTS.
+pub fn handler_callback_stub(interrupt_index: u8) -> Option<u32> {
+	SERVICED_INTERRUPTS.iter().position(|&serviced| serviced == interrupt_index)
+		.map(|position| BIOS_STUB_REGION_START + (position * HANDLER_CALLBACK_STUB.len()) as u32)
+}
+
+/// For the handler when it's called with HANDLER_CALLBACK_INTERRUPT: the interrupt whose callback
+/// stub called it, found from the return address the CPU pushed. None if it wasn't called from a
+/// callback stub, such as by the program itself.
+pub fn handler_callback_interrupt(machine: &dyn Machine) -> Option<u8> {
+	let stack_addr = machine.get_seg_reg(Reg::SS, Reg::SP);
+	let (return_offset, return_segment) = (machine.peek_u16(stack_addr), machine.peek_u16(stack_addr + 2));
+	if return_segment != BIOS_STUB_SEGMENT {
+		return None;
+	}
+	let stub_offset = (return_offset as usize).checked_sub(HANDLER_CALLBACK_RETURN_OFFSET)?;
+	if stub_offset % HANDLER_CALLBACK_STUB.len() != 0 {
+		return None;
+	}
+	SERVICED_INTERRUPTS.get(stub_offset / HANDLER_CALLBACK_STUB.len()).cloned()
+}
+
+// Plants the stubs, and points every interrupt vector at a stub so programs that chain to the
+// previous handler don't jump to 0000:0000. The ones the handler services go back into it, and the
+// rest return straight away. HANDLER_CALLBACK_INTERRUPT's is the only vector left at 0000:0000.
+fn plant_bios_stubs(machine: &mut dyn Machine) -> BiosStubs {
+	let mut writer = SynthBiosStubWriter::new(machine);
+	for _ in SERVICED_INTERRUPTS.iter() {
+		writer.write_stub(&HANDLER_CALLBACK_STUB);
+	}
+	let stubs = BiosStubs {
+		iret: writer.write_stub(&[IRET]),
+		// mov ax, 0; mov bl, 80h; retf
+		// http://www.phatcode.net/res/219/files/xms30.txt
+		xms_entry: writer.write_stub(&[0xb8, 0x00, 0x00, 0xb3, 0x80, 0xcb]),
+		// mov ah, 84h; iret
+		ems_entry: writer.write_stub(&[0xb4, 0x84, IRET]),
+		int_2fh: writer.write_stub(&[IRET]),
+		// The timings and format of a 1.44M drive, with 18 sectors per track.
+		// http://www.ctyme.com/intr/rb-2445.htm
+		diskette_parameter_table: writer.write_stub(&[0xdf, 0x02, 0x25, 0x02, 0x12, 0x1b, 0xff, 0x6c, 0xf6, 0x0f, 0x08]),
+		// mov ah, 4ch; int 21h
+		terminate: writer.write_stub(&[0xb4, 0x4c, 0xcd, 0x21]),
+	};
+	for interrupt_index in 0 ..= 0xff {
+		let stub_addr = match interrupt_index {
+			HANDLER_CALLBACK_INTERRUPT => continue,
+			0x1e => stubs.diskette_parameter_table,
+			0x22 => stubs.terminate,
+			0x2f => stubs.int_2fh,
+			0x67 => stubs.ems_entry,
+			_ => handler_callback_stub(interrupt_index).unwrap_or(stubs.iret),
+		};
+		let (segment, offset) = bios_stub_far_pointer(stub_addr);
+		let entry_addr = interrupt_index as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+		machine.poke_u16(entry_addr, offset);
+		machine.poke_u16(entry_addr + 2, segment);
+	}
+	stubs
+}
+
 // http://www.bioscentral.com/misc/bda.htm
-pub fn initialise_bios_data_area(machine: &mut Machine8086) {
+pub fn initialise_bios_data_area(machine: &mut dyn Machine) -> BiosStubs {
 	// The BIOS Data Area starts at the start of the 0x40 segment.
 	// Equipment
 	machine.set_data_u16(&BIOS_EQUIPMENT, 0x0061);
@@ -37,4 +227,126 @@ pub fn initialise_bios_data_area(machine: &mut Machine8086) {
 	machine.set_data_u16(&BIOS_TEXT_COLUMN_COUNT, 80);
 	// Port for video I/O
 	machine.set_data_u16(&BIOS_VIDEO_IO_PORT_ADDRESS, 0xd403);
+	
+	machine.poke_u8(((DOS_DATA_SEGMENT as u32) << 4) + DOS_MEDIA_DESCRIPTOR_OFFSET as u32, DOS_MEDIA_DESCRIPTOR_FIXED_DISK);
+	machine.poke_u16(((DOS_DATA_SEGMENT as u32) << 4) + DOS_DBCS_LEAD_BYTE_TABLE_OFFSET as u32, 0);
+	write_dos_tables(machine);
+	
+	plant_bios_stubs(machine)
+}
+
+fn poke_far_pointer(machine: &mut dyn Machine, addr: u32, segment: u16, offset: u16) {
+	machine.poke_u16(addr, offset);
+	machine.poke_u16(addr + 2, segment);
+}
+
+// Writes a device header for a character device, which is followed in the chain by the one at
+// next_offset, or ends the chain.
+fn write_device_header(machine: &mut dyn Machine, addr: u32, next_offset: Option<u16>, attributes: u16, name: &[u8; 8]) {
+	match next_offset {
+		Some(next_offset) => poke_far_pointer(machine, addr, DOS_TABLES_SEGMENT, next_offset),
+		None => poke_far_pointer(machine, addr, 0xffff, 0xffff),
+	}
+	machine.poke_u16(addr + 4, attributes);
+	machine.poke_u16(addr + 6, DOS_DEVICE_ROUTINE_OFFSET);
+	machine.poke_u16(addr + 8, DOS_DEVICE_ROUTINE_OFFSET);
+	machine.insert_contiguous_bytes(name, addr as usize + 10);
+}
+
+fn write_dos_tables(machine: &mut dyn Machine) {
+	let tables_addr = (DOS_TABLES_SEGMENT as u32) << 4;
+	let tables_addr_of = |offset: u16| tables_addr + offset as u32;
+	machine.insert_contiguous_bytes(&[0; DOS_TABLES_END_OFFSET as usize], tables_addr as usize);
+	machine.poke_u16(tables_addr_of(DOS_LIST_OF_LISTS_OFFSET - 2), FIRST_MCB_SEGMENT);
+	
+	let list_addr = tables_addr_of(DOS_LIST_OF_LISTS_OFFSET);
+	// There are no drive parameter blocks, so the first one is the end of the chain.
+	poke_far_pointer(machine, list_addr, 0xffff, 0xffff);
+	poke_far_pointer(machine, list_addr + 0x04, DOS_TABLES_SEGMENT, DOS_SFT_OFFSET);
+	poke_far_pointer(machine, list_addr + 0x08, DOS_TABLES_SEGMENT, DOS_CLOCK_DEVICE_OFFSET);
+	poke_far_pointer(machine, list_addr + 0x0c, DOS_TABLES_SEGMENT, DOS_CON_DEVICE_OFFSET);
+	// The biggest sector size.
+	machine.poke_u16(list_addr + 0x10, 512);
+	poke_far_pointer(machine, list_addr + 0x16, DOS_TABLES_SEGMENT, DOS_CDS_OFFSET);
+	write_device_header(machine, list_addr + DOS_LIST_OF_LISTS_NUL_DEVICE_OFFSET, Some(DOS_CON_DEVICE_OFFSET), 0x8004, b"NUL     ");
+	
+	// The file table is the last one, and has no files.
+	poke_far_pointer(machine, tables_addr_of(DOS_SFT_OFFSET), 0xffff, 0xffff);
+	write_device_header(machine, tables_addr_of(DOS_CON_DEVICE_OFFSET), Some(DOS_CLOCK_DEVICE_OFFSET), 0x8013, b"CON     ");
+	write_device_header(machine, tables_addr_of(DOS_CLOCK_DEVICE_OFFSET), None, 0x8008, b"CLOCK$  ");
+	machine.poke_u8(tables_addr_of(DOS_DEVICE_ROUTINE_OFFSET), RETF);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use xachtsechs::machine8086::Machine8086;
+	
+	#[test] fn test_bios_stubs() {
+		let mut machine = Machine8086::new(1024*1024);
+		let stubs = initialise_bios_data_area(&mut machine);
+		for &stub_addr in [stubs.iret, stubs.xms_entry, stubs.ems_entry, stubs.int_2fh, stubs.diskette_parameter_table, stubs.terminate].iter() {
+			assert!((BIOS_STUB_REGION_START .. BIOS_STUB_REGION_START + BIOS_STUB_REGION_BYTES).contains(&stub_addr));
+		}
+		assert_eq!(machine.peek_u8(stubs.iret), IRET);
+		
+		// The vectors point at the stubs as segment:offset.
+		let vector = |machine: &Machine8086, interrupt_index: u32| {
+			let entry_addr = interrupt_index * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+			((machine.peek_u16(entry_addr + 2) as u32) << 4) + machine.peek_u16(entry_addr) as u32
+		};
+		assert_eq!(vector(&machine, 0x1c), stubs.iret);
+		assert_eq!(vector(&machine, 0x2f), stubs.int_2fh);
+		assert_eq!(vector(&machine, 0x1e), stubs.diskette_parameter_table);
+		assert_eq!(vector(&machine, 0x22), stubs.terminate);
+		assert_eq!(vector(&machine, 0x67), stubs.ems_entry);
+		assert_eq!(vector(&machine, 0x21), handler_callback_stub(0x21).unwrap());
+		assert_eq!(vector(&machine, 0x16), handler_callback_stub(0x16).unwrap());
+		assert_eq!(vector(&machine, HANDLER_CALLBACK_INTERRUPT as u32), 0);
+		assert_eq!(handler_callback_stub(0x1c), None);
+		let (segment, offset) = bios_stub_far_pointer(stubs.terminate);
+		assert!(is_bios_stub_far_pointer(segment, offset));
+		assert!(!is_bios_stub_far_pointer(0, 0));
+	}
+	
+	#[test] fn test_list_of_lists() {
+		let mut machine = Machine8086::new(1024*1024);
+		initialise_bios_data_area(&mut machine);
+		// The tables fit between the stub region and the first MCB.
+		let tables_addr = (DOS_TABLES_SEGMENT as u32) << 4;
+		assert!(tables_addr >= BIOS_STUB_REGION_START + BIOS_STUB_REGION_BYTES);
+		assert!(tables_addr + DOS_TABLES_END_OFFSET as u32 <= (FIRST_MCB_SEGMENT as u32) << 4);
+		
+		let list_addr = tables_addr + DOS_LIST_OF_LISTS_OFFSET as u32;
+		assert_eq!(machine.peek_u16(list_addr - 2), FIRST_MCB_SEGMENT);
+		let far_pointer = |machine: &Machine8086, addr: u32| ((machine.peek_u16(addr + 2) as u32) << 4) + machine.peek_u16(addr) as u32;
+		assert_eq!((machine.peek_u16(list_addr), machine.peek_u16(list_addr + 2)), (0xffff, 0xffff));
+		// The file table ends the chain of tables and has no files.
+		let sft_addr = far_pointer(&machine, list_addr + 0x04);
+		assert_eq!((machine.peek_u16(sft_addr), machine.peek_u16(sft_addr + 2), machine.peek_u16(sft_addr + 4)), (0xffff, 0xffff, 0));
+		assert_eq!(machine.peek_u8(list_addr + 0x21), 0);
+		
+		// The device chain goes from NUL in the list through CON and CLOCK$, which the list also
+		// points to.
+		let mut device_addr = list_addr + DOS_LIST_OF_LISTS_NUL_DEVICE_OFFSET;
+		let mut device_names = vec![];
+		loop {
+			device_names.push(machine.memory[device_addr as usize + 10 .. device_addr as usize + 18].to_vec());
+			assert_eq!(machine.peek_u8(tables_addr + machine.peek_u16(device_addr + 6) as u32), RETF);
+			if machine.peek_u16(device_addr) == 0xffff {
+				break;
+			}
+			device_addr = far_pointer(&machine, device_addr);
+		}
+		assert_eq!(device_names, vec![b"NUL     ".to_vec(), b"CON     ".to_vec(), b"CLOCK$  ".to_vec()]);
+		assert_eq!(far_pointer(&machine, list_addr + 0x08), device_addr);
+		assert_eq!(&machine.memory[far_pointer(&machine, list_addr + 0x0c) as usize + 10 ..][.. 8], b"CON     ");
+	}
+	
+	#[test] #[should_panic(expected = "The BIOS stub region is full")] fn test_bios_stub_region_full() {
+		let mut machine = Machine8086::new(1024*1024);
+		let mut writer = SynthBiosStubWriter::new(&mut machine);
+		writer.write_stub(&[IRET; BIOS_STUB_REGION_BYTES as usize]);
+		writer.write_stub(&[IRET]);
+	}
 }
@@ -0,0 +1,86 @@
+// What a runner does while the emulator isn't visible (eg. the window is minimised), and how the
+// emulated clock carries on when it's visible again. Running all the timer ticks that were missed
+// in one go would make programs think a lot of time passed at once and break their delay loops, so
+// the clock either carries on from where it stopped, or jumps forward without running the ticks.
+
+use serde::{Deserialize, Serialize};
+
+/// While in the background with SlowTick, one standard timer tick runs for this many that pass.
+pub const SLOW_TICK_DIVISOR: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BackgroundMode {
+	/// Stop running the program.
+	Pause,
+	/// Keep running timer ticks at a tenth of the usual rate, so music played from the timer
+	/// interrupt keeps going while the program itself barely advances.
+	SlowTick,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ResumeClock {
+	/// Carry on from where the emulated clock was, as if the time in the background didn't pass.
+	Continue,
+	/// Move the emulated clock forward by the ticks that didn't run, without running them, for
+	/// programs where the time of day matters.
+	SyncToHost,
+}
+
+#[derive(Debug, Clone)]
+pub struct BackgroundPolicy {
+	pub mode: BackgroundMode,
+	pub resume_clock: ResumeClock,
+	// The number of standard ticks that have passed and that have been run since going into the
+	// background, or None while in the foreground.
+	background_ticks: Option<(u64, u64)>,
+}
+
+impl BackgroundPolicy {
+	pub fn new(mode: BackgroundMode, resume_clock: ResumeClock) -> BackgroundPolicy {
+		BackgroundPolicy {
+			mode,
+			resume_clock,
+			background_ticks: None,
+		}
+	}
+
+	pub fn is_in_background(&self) -> bool {
+		self.background_ticks.is_some()
+	}
+
+	/// Moves the runner into or out of the background. When coming back to the foreground, returns
+	/// the number of standard ticks the clock should skip forward by (see
+	/// DosEventHandler::skip_standard_ticks), which is 0 unless the clock syncs to the host.
+	pub fn set_in_background(&mut self, in_background: bool) -> u64 {
+		match (in_background, self.background_ticks) {
+			(true, None) => {
+				self.background_ticks = Some((0, 0));
+				0
+			}
+			(false, Some((passed_ticks, run_ticks))) => {
+				self.background_ticks = None;
+				match self.resume_clock {
+					ResumeClock::Continue => 0,
+					ResumeClock::SyncToHost => passed_ticks - run_ticks,
+				}
+			}
+			_ => 0,
+		}
+	}
+
+	/// Called by the runner each time a standard tick of host time passes. Returns whether the
+	/// runner should run the tick.
+	pub fn should_run_tick(&mut self) -> bool {
+		match self.background_ticks {
+			None => true,
+			Some((ref mut passed_ticks, ref mut run_ticks)) => {
+				*passed_ticks += 1;
+				let run_tick = self.mode == BackgroundMode::SlowTick && *passed_ticks % SLOW_TICK_DIVISOR == 0;
+				if run_tick {
+					*run_ticks += 1;
+				}
+				run_tick
+			}
+		}
+	}
+}
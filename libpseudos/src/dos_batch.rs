@@ -0,0 +1,442 @@
+// A minimal batch file interpreter for the small scripts games are launched with. It only knows
+// the commands those scripts tend to use, rather than everything COMMAND.COM does.
+// http://www.robvanderwoude.com/battech.php
+// https://en.wikibooks.org/wiki/Guide_to_Windows_Commands
+
+use crate::dos_environment::{build_environment_block, parse_environment_var};
+use crate::dos_event_handler::{DosEventHandler, DosInterruptResult, HeadlessStartup};
+use crate::dos_file_system::{read_dos_file, DOS_ATTRIBUTE_DIRECTORY};
+use crate::exe_loader::load_program_into_machine;
+
+use xachtsechs::machine8086::Machine8086;
+
+// COMMAND.COM commands that aren't supported, which give an error instead of being looked for as
+// programs.
+const UNSUPPORTED_COMMANDS: &[&[u8]] = &[
+	b"BREAK", b"CLS", b"COPY", b"CTTY", b"DATE", b"DEL", b"DIR", b"ERASE", b"FOR", b"LH",
+	b"LOADHIGH", b"MD", b"MKDIR", b"PATH", b"PROMPT", b"RD", b"REN", b"RENAME", b"RMDIR", b"SHIFT",
+	b"TIME", b"TYPE", b"VER", b"VERIFY", b"VOL",
+];
+// Commands that can be followed straight away by one of these characters, as in "CD.." or "ECHO.".
+const ATTACHED_ARG_COMMANDS: &[&[u8]] = &[b"CHDIR", b"CD", b"ECHO"];
+const ATTACHED_ARG_CHARS: &[u8] = b".\\/";
+// The extensions a program name without one is tried with, in the order COMMAND.COM tries them.
+const PROGRAM_EXTENSIONS: &[&[u8]] = &[b".COM", b".EXE", b".BAT"];
+const PAUSE_MESSAGE: &[u8] = b"Press any key to continue . . .";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchStep {
+	/// A program has been loaded into the machine. Run it until it exits, then call step again.
+	RunProgram,
+	/// PAUSE is waiting for a key. Call step again once one has been queued.
+	WaitForKey,
+	/// The end of the batch file has been reached.
+	Finished,
+}
+
+#[derive(Debug)]
+pub struct BatchInterpreter {
+	lines: Vec<Vec<u8>>,
+	// The index of the line to run next.
+	next_line: usize,
+	// Whether each line is written to the screen before it runs. Changed with ECHO ON/OFF.
+	echo: bool,
+	environment_vars: Vec<(Vec<u8>, Vec<u8>)>,
+	// Whether a PAUSE has written its message and is waiting for a key.
+	pausing: bool,
+}
+
+fn is_space(c: u8) -> bool {
+	c == b' ' || c == b'\t'
+}
+
+fn trim_start(text: &[u8]) -> &[u8] {
+	let start = text.iter().position(|c| !is_space(*c)).unwrap_or(text.len());
+	&text[start..]
+}
+
+fn trim(text: &[u8]) -> &[u8] {
+	let text = trim_start(text);
+	let end = text.iter().rposition(|c| !is_space(*c)).map(|pos| pos + 1).unwrap_or(0);
+	&text[..end]
+}
+
+// Splits off the first word, returning it and the rest of the text after it.
+fn split_word(text: &[u8]) -> (&[u8], &[u8]) {
+	let text = trim_start(text);
+	let end = text.iter().position(|c| is_space(*c)).unwrap_or(text.len());
+	(&text[..end], &text[end..])
+}
+
+// Splits a line into the uppercased command name, the command as written, and the arguments after
+// it. The arguments keep the character that separated them from the command.
+fn split_command(line: &[u8]) -> (Vec<u8>, &[u8], &[u8]) {
+	let (word, _) = split_word(line);
+	let line = trim_start(line);
+	let upper_word = word.to_ascii_uppercase();
+	for command in ATTACHED_ARG_COMMANDS {
+		if upper_word.starts_with(command) && upper_word.len() > command.len() && ATTACHED_ARG_CHARS.contains(&upper_word[command.len()]) {
+			return (command.to_vec(), &line[..command.len()], &line[command.len()..]);
+		}
+	}
+	(upper_word, word, &line[word.len()..])
+}
+
+fn write_text(handler: &mut DosEventHandler, machine: &mut Machine8086, text: &[u8]) {
+	for c in text {
+		handler.write_dos_output_char(machine, *c);
+	}
+}
+
+fn write_line(handler: &mut DosEventHandler, machine: &mut Machine8086, text: &[u8]) {
+	write_text(handler, machine, text);
+	write_text(handler, machine, b"\r\n");
+}
+
+// Gets the current directory with the drive, eg. b"C:\\GAMES".
+fn current_dos_directory(handler: &DosEventHandler) -> Vec<u8> {
+	let file_system = handler.file_system();
+	let mut path = vec![b'A' + file_system.current_drive(), b':', b'\\'];
+	path.extend(file_system.current_directory());
+	path
+}
+
+fn file_exists(handler: &mut DosEventHandler, filename: &[u8]) -> bool {
+	match handler.file_system_mut().get_attributes(filename) {
+		Ok(attributes) => attributes & DOS_ATTRIBUTE_DIRECTORY == 0,
+		Err(_) => false,
+	}
+}
+
+impl BatchInterpreter {
+	/// Programs run by the batch file get the environment variables given here, along with any
+	/// that SET adds.
+	pub fn new(batch_text: &[u8], environment_vars: Vec<(Vec<u8>, Vec<u8>)>) -> BatchInterpreter {
+		// Anything after a Ctrl+Z is ignored.
+		let batch_text = batch_text.split(|c| *c == 0x1a).next().unwrap_or(&[]);
+		let lines = batch_text.split(|c| *c == b'\n').map(|line| {
+			line.strip_suffix(b"\r").unwrap_or(line).to_vec()
+		}).collect();
+		BatchInterpreter {
+			lines,
+			next_line: 0,
+			echo: true,
+			environment_vars,
+			pausing: false,
+		}
+	}
+
+	pub fn environment_vars(&self) -> &[(Vec<u8>, Vec<u8>)] {
+		&self.environment_vars
+	}
+
+	/// Runs lines of the batch file until one runs a program or waits for a key, or the batch file
+	/// ends. Errors say which line they happened on.
+	pub fn step(&mut self, handler: &mut DosEventHandler, machine: &mut Machine8086) -> Result<BatchStep, String> {
+		while self.next_line < self.lines.len() {
+			let line_index = self.next_line;
+			self.next_line += 1;
+			let result = self.run_line(line_index, handler, machine)
+				.map_err(|err| format!("Batch file line {}: {}", line_index + 1, err))?;
+			if let Some(batch_step) = result {
+				return Ok(batch_step);
+			}
+		}
+		Ok(BatchStep::Finished)
+	}
+
+	/// Runs the batch file without a window, running each program with
+	/// DosEventHandler::run_headless. Returns the exit code of the last program, or 0 if none ran.
+	/// PAUSE takes keys from the key press queue, and gives an error if it's empty.
+	pub fn run_headless(&mut self, handler: &mut DosEventHandler, machine: &mut Machine8086, max_steps_per_program: usize) -> Result<u8, String> {
+		let mut exit_code = 0;
+		loop {
+			match self.step(handler, machine)? {
+				BatchStep::RunProgram => exit_code = handler.run_headless(machine, max_steps_per_program, HeadlessStartup::Normal)?,
+				BatchStep::WaitForKey => return Err("Blocked waiting for a keypress".to_string()),
+				BatchStep::Finished => return Ok(exit_code),
+			}
+		}
+	}
+
+	fn run_line(&mut self, line_index: usize, handler: &mut DosEventHandler, machine: &mut Machine8086) -> Result<Option<BatchStep>, String> {
+		let mut line = trim_start(&self.lines[line_index]);
+		// Lines starting with @ aren't echoed, and lines starting with : are labels.
+		let mut echo_line = self.echo;
+		if line.first() == Some(&b'@') {
+			echo_line = false;
+			line = trim_start(&line[1..]);
+		}
+		if line.is_empty() || line[0] == b':' {
+			return Ok(None);
+		}
+		let line = self.expand_vars(line);
+		if echo_line && !self.pausing {
+			let mut prompt = current_dos_directory(handler);
+			prompt.push(b'>');
+			write_text(handler, machine, &prompt);
+			write_line(handler, machine, &line);
+		}
+		self.run_command(&line, line_index, handler, machine)
+	}
+
+	// Replaces %NAME% with the value of the environment variable, and %% with %. Batch files are
+	// run without parameters, so %0 to %9 are empty.
+	fn expand_vars(&self, line: &[u8]) -> Vec<u8> {
+		let mut expanded = vec![];
+		let mut pos = 0;
+		while pos < line.len() {
+			if line[pos] != b'%' {
+				expanded.push(line[pos]);
+				pos += 1;
+				continue;
+			}
+			let rest = &line[pos + 1..];
+			if rest.first() == Some(&b'%') {
+				expanded.push(b'%');
+				pos += 2;
+			} else if matches!(rest.first(), Some(c) if c.is_ascii_digit()) {
+				pos += 2;
+			} else if let Some(name_len) = rest.iter().position(|c| *c == b'%') {
+				let name = rest[..name_len].to_ascii_uppercase();
+				if let Some((_, value)) = self.environment_vars.iter().find(|(var_name, _)| *var_name == name) {
+					expanded.extend(value);
+				}
+				pos += name_len + 2;
+			} else {
+				expanded.push(b'%');
+				pos += 1;
+			}
+		}
+		expanded
+	}
+
+	fn run_command(&mut self, line: &[u8], line_index: usize, handler: &mut DosEventHandler, machine: &mut Machine8086) -> Result<Option<BatchStep>, String> {
+		let (command, command_name, args) = split_command(line);
+		match &command[..] {
+			b"REM" => {}
+			b"ECHO" => {
+				if args.first() == Some(&b'.') {
+					// "ECHO." writes the text after the dot, which can be nothing.
+					write_line(handler, machine, &args[1..]);
+				} else {
+					let text = trim_start(args);
+					match &trim(text).to_ascii_uppercase()[..] {
+						b"ON" => self.echo = true,
+						b"OFF" => self.echo = false,
+						b"" => write_line(handler, machine, if self.echo { b"ECHO is on" } else { b"ECHO is off" }),
+						_ => write_line(handler, machine, text),
+					}
+				}
+			}
+			b"SET" => {
+				if trim(args).is_empty() {
+					for (name, value) in self.environment_vars.clone() {
+						write_text(handler, machine, &name);
+						write_text(handler, machine, b"=");
+						write_line(handler, machine, &value);
+					}
+				} else {
+					// Setting a variable to nothing removes it.
+					let (name, value) = parse_environment_var(trim_start(args))?;
+					self.environment_vars.retain(|(var_name, _)| *var_name != name);
+					if !value.is_empty() {
+						self.environment_vars.push((name, value));
+					}
+				}
+			}
+			b"CD" | b"CHDIR" => {
+				let path = trim(args);
+				if path.is_empty() {
+					let directory = current_dos_directory(handler);
+					write_line(handler, machine, &directory);
+				} else if handler.file_system_mut().set_current_directory(path).is_err() {
+					write_line(handler, machine, b"Invalid directory");
+				}
+			}
+			b"PAUSE" => {
+				if !self.pausing {
+					write_text(handler, machine, PAUSE_MESSAGE);
+					self.pausing = true;
+				}
+				if handler.key_press_queue.pop_front().is_none() {
+					self.next_line = line_index;
+					return Ok(Some(BatchStep::WaitForKey));
+				}
+				self.pausing = false;
+				write_text(handler, machine, b"\r\n");
+			}
+			b"GOTO" => {
+				let (label, _) = split_word(args);
+				let label = label.strip_prefix(b":").unwrap_or(label).to_ascii_uppercase();
+				let label_index = self.lines.iter().position(|batch_line| {
+					let batch_line = trim_start(batch_line);
+					batch_line.first() == Some(&b':') && split_word(&batch_line[1..]).0.to_ascii_uppercase() == label
+				});
+				match label_index {
+					Some(label_index) => self.next_line = label_index + 1,
+					None => return Err(format!("Label not found: {}", String::from_utf8_lossy(&label))),
+				}
+			}
+			b"IF" => {
+				let (mut word, mut rest) = split_word(args);
+				let negate = word.eq_ignore_ascii_case(b"NOT");
+				if negate {
+					let (next_word, next_rest) = split_word(rest);
+					word = next_word;
+					rest = next_rest;
+				}
+				let condition = if word.eq_ignore_ascii_case(b"EXIST") {
+					let (filename, command_rest) = split_word(rest);
+					rest = command_rest;
+					file_exists(handler, filename)
+				} else {
+					// A string comparison with no spaces, such as "%1"=="".
+					let equals_pos = word.windows(2).position(|pair| pair == b"==")
+						.ok_or_else(|| format!("Unsupported batch command: {}", String::from_utf8_lossy(line)))?;
+					word[..equals_pos] == word[equals_pos + 2..]
+				};
+				if condition != negate {
+					return self.run_command(trim_start(rest), line_index, handler, machine);
+				}
+			}
+			b"CALL" => {
+				// Only programs can be called. Calling another batch file gives the same error as
+				// running it.
+				let (program_name, program_args) = split_word(args);
+				if program_name.is_empty() {
+					return Err("CALL needs a program to run".to_string());
+				}
+				return self.run_program(program_name, program_args, handler, machine).map(Some);
+			}
+			_ if UNSUPPORTED_COMMANDS.contains(&&command[..]) => {
+				return Err(format!("Unsupported batch command: {}", String::from_utf8_lossy(line)));
+			}
+			_ => return self.run_program(command_name, args, handler, machine).map(Some),
+		}
+		Ok(None)
+	}
+
+	fn run_program(&mut self, program_name: &[u8], args: &[u8], handler: &mut DosEventHandler, machine: &mut Machine8086) -> Result<BatchStep, String> {
+		let has_extension = program_name.iter().rposition(|c| *c == b'.') > program_name.iter().rposition(|c| *c == b'\\');
+		let program_path = if has_extension {
+			Some(program_name.to_vec()).filter(|program_path| file_exists(handler, program_path))
+		} else {
+			PROGRAM_EXTENSIONS.iter()
+				.map(|ext| [program_name, ext].concat())
+				.find(|program_path| file_exists(handler, program_path))
+		};
+		let program_path = program_path.ok_or_else(|| format!("Bad command or file name: {}", String::from_utf8_lossy(program_name)))?;
+		if program_path.to_ascii_uppercase().ends_with(b".BAT") {
+			return Err(format!("Unsupported batch command: running another batch file ({})", String::from_utf8_lossy(&program_path)));
+		}
+
+		let program_data = read_dos_file(handler.file_system_mut(), &program_path)
+			.map_err(|err| format!("Failed to read {}: {:?}", String::from_utf8_lossy(&program_path), err))?;
+		let mut full_program_path = current_dos_directory(handler);
+		if full_program_path.last() != Some(&b'\\') {
+			full_program_path.push(b'\\');
+		}
+		full_program_path.extend(program_path.to_ascii_uppercase());
+		let environment_block = build_environment_block(&self.environment_vars, &full_program_path);
+		load_program_into_machine(machine, &program_data, args, &environment_block, handler.file_system())
+			.map_err(|err| format!("Failed to load {}: {}", String::from_utf8_lossy(&program_path), err))?;
+		handler.result = DosInterruptResult::ShouldReturn;
+		Ok(BatchStep::RunProgram)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::KeyPressInfo;
+	use crate::dos_event_handler::tests::{make_test_dir, make_test_handler};
+	use crate::dos_file_system::StandardDosFileSystem;
+
+	// mov ax, 4c00h; int 21h
+	const EXIT_COM: &[u8] = &[0xb8, 0x00, 0x4c, 0xcd, 0x21];
+
+	fn make_batch_test(name: &str, files: &[(&str, &[u8])]) -> (DosEventHandler, Machine8086) {
+		let dir_path = make_test_dir(name);
+		for (filename, data) in files {
+			std::fs::write(dir_path.join(filename), data).unwrap();
+		}
+		let (mut handler, machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path)));
+		(handler, machine)
+	}
+
+	fn screen_line(handler: &DosEventHandler, machine: &Machine8086, row: usize) -> Vec<u8> {
+		let mut line = handler.get_screen_text(machine).swap_remove(row);
+		while matches!(line.last(), Some(0) | Some(b' ')) {
+			line.pop();
+		}
+		line
+	}
+
+	#[test] fn test_variables_reach_program() {
+		let (mut handler, mut machine) = make_batch_test("batch_vars", &[("PROG.COM", EXIT_COM)]);
+		let mut batch = BatchInterpreter::new(b"@echo off\r\nset game=zzt\r\nset unused=x\r\nset unused=\r\nPROG %GAME% /q 100%%\r\n", vec![]);
+		assert_eq!(batch.step(&mut handler, &mut machine), Ok(BatchStep::RunProgram));
+		assert_eq!(batch.environment_vars(), &[(b"GAME".to_vec(), b"zzt".to_vec())]);
+
+		let psp_start = 0x1000;
+		let command_tail = b" zzt /q 100%";
+		assert_eq!(machine.memory[psp_start + 0x80] as usize, command_tail.len());
+		assert_eq!(&machine.memory[psp_start + 0x81 .. psp_start + 0x81 + command_tail.len()], command_tail);
+		let environment_start = machine.peek_u16(psp_start as u32 + 0x2c) as usize * 16;
+		let environment_block = b"GAME=zzt\0\0\x01\0C:\\PROG.COM\0";
+		assert_eq!(&machine.memory[environment_start .. environment_start + environment_block.len()], &environment_block[..]);
+
+		assert_eq!(handler.run_headless(&mut machine, 100, HeadlessStartup::Normal), Ok(0));
+		assert_eq!(batch.step(&mut handler, &mut machine), Ok(BatchStep::Finished));
+	}
+
+	#[test] fn test_pause_waits_for_key() {
+		let (mut handler, mut machine) = make_batch_test("batch_pause", &[]);
+		let mut batch = BatchInterpreter::new(b"@ECHO OFF\r\nPAUSE\r\nECHO done\r\n", vec![]);
+		assert_eq!(batch.step(&mut handler, &mut machine), Ok(BatchStep::WaitForKey));
+		assert_eq!(batch.step(&mut handler, &mut machine), Ok(BatchStep::WaitForKey));
+		assert_eq!(screen_line(&handler, &machine, 0), PAUSE_MESSAGE);
+
+		handler.key_press_queue.push_back(KeyPressInfo { scan_code: 0x39, ascii_char: b' ' });
+		assert_eq!(batch.step(&mut handler, &mut machine), Ok(BatchStep::Finished));
+		assert!(handler.key_press_queue.is_empty());
+		assert_eq!(screen_line(&handler, &machine, 1), b"done");
+	}
+
+	#[test] fn test_programs_share_file() {
+		// Creates STATE.DAT containing "A":
+		//   mov ah, 3ch; mov cx, 0; mov dx, name; int 21h; mov bx, ax
+		//   mov ah, 40h; mov cx, 1; mov dx, data; int 21h; mov ah, 3eh; int 21h
+		//   mov ax, 4c00h; int 21h
+		let write_com = [
+			&[0xb4, 0x3c, 0xb9, 0x00, 0x00, 0xba, 0x1f, 0x01, 0xcd, 0x21, 0x89, 0xc3][..],
+			&[0xb4, 0x40, 0xb9, 0x01, 0x00, 0xba, 0x29, 0x01, 0xcd, 0x21, 0xb4, 0x3e, 0xcd, 0x21],
+			&[0xb8, 0x00, 0x4c, 0xcd, 0x21],
+			b"STATE.DAT\0A",
+		].concat();
+		// Exits with the first byte of STATE.DAT as the exit code:
+		//   mov ax, 3d00h; mov dx, name; int 21h; mov bx, ax
+		//   mov ah, 3fh; mov cx, 1; mov dx, data; int 21h; mov ah, 3eh; int 21h
+		//   mov ah, 4ch; mov al, [data]; int 21h
+		let read_com = [
+			&[0xb8, 0x00, 0x3d, 0xba, 0x1f, 0x01, 0xcd, 0x21, 0x89, 0xc3][..],
+			&[0xb4, 0x3f, 0xb9, 0x01, 0x00, 0xba, 0x29, 0x01, 0xcd, 0x21, 0xb4, 0x3e, 0xcd, 0x21],
+			&[0xb4, 0x4c, 0xa0, 0x29, 0x01, 0xcd, 0x21],
+			b"STATE.DAT\0\0",
+		].concat();
+		let (mut handler, mut machine) = make_batch_test("batch_programs", &[("WRITE.COM", &write_com), ("READ.COM", &read_com)]);
+		let batch_text = b"@ECHO OFF\r\nIF EXIST STATE.DAT GOTO read\r\nWRITE\r\n:read\r\nIF NOT EXIST STATE.DAT ECHO missing\r\nIF NOT \"%1\"==\"\" ECHO args\r\nCALL READ.COM\r\n";
+		let mut batch = BatchInterpreter::new(batch_text, vec![]);
+		assert_eq!(batch.run_headless(&mut handler, &mut machine, 1000), Ok(b'A'));
+		assert_eq!(screen_line(&handler, &machine, 0), b"");
+
+		let mut batch = BatchInterpreter::new(b"REM copying\r\nCOPY A.DAT B.DAT\r\n", vec![]);
+		assert_eq!(batch.step(&mut handler, &mut machine), Err("Batch file line 2: Unsupported batch command: COPY A.DAT B.DAT".to_string()));
+		let mut batch = BatchInterpreter::new(b"@CALL\r\n", vec![]);
+		assert_eq!(batch.step(&mut handler, &mut machine), Err("Batch file line 1: CALL needs a program to run".to_string()));
+		let mut batch = BatchInterpreter::new(b"@CALL MISSING\r\n", vec![]);
+		assert_eq!(batch.step(&mut handler, &mut machine), Err("Batch file line 1: Bad command or file name: MISSING".to_string()));
+	}
+}
@@ -0,0 +1,238 @@
+// The DOS date and time, which int 21h AH=2Ah-2Dh and the CLOCK$ device read and set.
+// http://stanislavs.org/helppc/int_21-2a.html
+//
+// There's no real time clock, so the clock starts at midnight on 1 January 1980, the earliest date
+// DOS has, and goes forward with the emulated time since the machine started. That keeps runs
+// repeatable. Setting the date or time only moves an offset from the emulated time, so the clock
+// carries on from what was set.
+
+use crate::dos_event_handler::STANDARD_TICK_SECONDS;
+
+/// The BIOS tick counter at 0040:006Ch goes back to 0 when it reaches this, at midnight.
+pub const BIOS_TICKS_PER_DAY: u32 = 0x1800b0;
+/// The size of the record the CLOCK$ device reads and writes: the number of days since 1 January
+/// 1980 as a word, then the minutes, hours, hundredths and seconds as bytes.
+pub const CLOCK_DEVICE_RECORD_BYTES: usize = 6;
+
+const HUNDREDTHS_PER_DAY: i64 = 24 * 60 * 60 * 100;
+const FIRST_YEAR: u16 = 1980;
+const LAST_YEAR: u16 = 2099;
+
+fn is_leap_year(year: u16) -> bool {
+	year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+	match month {
+		2 => if is_leap_year(year) { 29 } else { 28 },
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+fn days_in_year(year: u16) -> u16 {
+	if is_leap_year(year) { 366 } else { 365 }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DosDate {
+	pub year: u16,
+	pub month: u8,
+	pub day: u8,
+}
+
+impl DosDate {
+	/// Checks the date exists and is in the years DOS can store, 1980 to 2099.
+	pub fn new(year: u16, month: u8, day: u8) -> Option<DosDate> {
+		if !(FIRST_YEAR ..= LAST_YEAR).contains(&year) || !(1 ..= 12).contains(&month) || !(1 ..= days_in_month(year, month)).contains(&day) {
+			return None;
+		}
+		Some(DosDate { year, month, day })
+	}
+
+	/// The date a number of days after 1 January 1980, which stops at the end of 2099.
+	pub fn from_days_since_1980(days: u16) -> DosDate {
+		let mut days = days;
+		let mut year = FIRST_YEAR;
+		while days >= days_in_year(year) && year < LAST_YEAR {
+			days -= days_in_year(year);
+			year += 1;
+		}
+		let mut month = 1;
+		while days >= days_in_month(year, month) as u16 && month < 12 {
+			days -= days_in_month(year, month) as u16;
+			month += 1;
+		}
+		DosDate { year, month, day: (days + 1).min(days_in_month(year, month) as u16) as u8 }
+	}
+
+	pub fn days_since_1980(&self) -> u16 {
+		let year_days: u16 = (FIRST_YEAR .. self.year).map(days_in_year).sum();
+		let month_days: u16 = (1 .. self.month).map(|month| days_in_month(self.year, month) as u16).sum();
+		year_days + month_days + self.day as u16 - 1
+	}
+
+	/// The day of the week, where 0 is Sunday.
+	pub fn day_of_week(&self) -> u8 {
+		// 1 January 1980 was a Tuesday.
+		((self.days_since_1980() as u32 + 2) % 7) as u8
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DosTime {
+	pub hour: u8,
+	pub minute: u8,
+	pub second: u8,
+	pub hundredths: u8,
+}
+
+impl DosTime {
+	pub fn new(hour: u8, minute: u8, second: u8, hundredths: u8) -> Option<DosTime> {
+		if hour > 23 || minute > 59 || second > 59 || hundredths > 99 {
+			return None;
+		}
+		Some(DosTime { hour, minute, second, hundredths })
+	}
+
+	fn from_hundredths(hundredths: i64) -> DosTime {
+		DosTime {
+			hour: (hundredths / 360000) as u8,
+			minute: (hundredths / 6000 % 60) as u8,
+			second: (hundredths / 100 % 60) as u8,
+			hundredths: (hundredths % 100) as u8,
+		}
+	}
+
+	fn hundredths_since_midnight(&self) -> i64 {
+		((self.hour as i64 * 60 + self.minute as i64) * 60 + self.second as i64) * 100 + self.hundredths as i64
+	}
+}
+
+/// The emulated date and time. Everything takes the emulated seconds since the machine started
+/// (DosEventHandler::seconds_since_start), which the clock is counted from.
+#[derive(Debug, Clone, Default)]
+pub struct DosClock {
+	// Added to the time since the machine started to get the time since 1 January 1980. It's kept
+	// in whole hundredths, so a time that's set reads back exactly.
+	offset_hundredths: i64,
+}
+
+impl DosClock {
+	pub fn new() -> DosClock {
+		DosClock { offset_hundredths: 0 }
+	}
+
+	fn hundredths_since_1980(&self, seconds_since_start: f64) -> i64 {
+		let last_hundredth = (LAST_YEAR - FIRST_YEAR + 1) as i64 * 366 * HUNDREDTHS_PER_DAY;
+		((seconds_since_start * 100.) as i64 + self.offset_hundredths).max(0).min(last_hundredth)
+	}
+
+	pub fn date(&self, seconds_since_start: f64) -> DosDate {
+		let days = self.hundredths_since_1980(seconds_since_start) / HUNDREDTHS_PER_DAY;
+		DosDate::from_days_since_1980(days.min(u16::MAX as i64) as u16)
+	}
+
+	pub fn time(&self, seconds_since_start: f64) -> DosTime {
+		DosTime::from_hundredths(self.hundredths_since_1980(seconds_since_start) % HUNDREDTHS_PER_DAY)
+	}
+
+	/// Changes the date, keeping the time of day.
+	pub fn set_date(&mut self, seconds_since_start: f64, date: DosDate) {
+		let time = self.time(seconds_since_start);
+		self.set(seconds_since_start, date, time);
+	}
+
+	/// Changes the time of day, keeping the date.
+	pub fn set_time(&mut self, seconds_since_start: f64, time: DosTime) {
+		let date = self.date(seconds_since_start);
+		self.set(seconds_since_start, date, time);
+	}
+
+	fn set(&mut self, seconds_since_start: f64, date: DosDate, time: DosTime) {
+		let hundredths_since_1980 = date.days_since_1980() as i64 * HUNDREDTHS_PER_DAY + time.hundredths_since_midnight();
+		self.offset_hundredths = hundredths_since_1980 - (seconds_since_start * 100.) as i64;
+	}
+
+	/// Sets the time of day from a count of BIOS ticks since midnight (int 1Ah AH=01h).
+	pub fn set_bios_ticks(&mut self, seconds_since_start: f64, ticks: u32) {
+		let hundredths = (ticks.min(BIOS_TICKS_PER_DAY - 1) as f64 * STANDARD_TICK_SECONDS * 100.) as i64;
+		self.set_time(seconds_since_start, DosTime::from_hundredths(hundredths));
+	}
+
+	/// The BIOS tick count for the time of day, as a BIOS that had been counting since midnight
+	/// would have it.
+	pub fn bios_ticks(&self, seconds_since_start: f64) -> u32 {
+		let seconds = self.time(seconds_since_start).hundredths_since_midnight() as f64 / 100.;
+		((seconds / STANDARD_TICK_SECONDS) as u32).min(BIOS_TICKS_PER_DAY - 1)
+	}
+
+	/// The date and time in the CLOCK$ device's record format.
+	pub fn device_record(&self, seconds_since_start: f64) -> [u8; CLOCK_DEVICE_RECORD_BYTES] {
+		let days = self.date(seconds_since_start).days_since_1980();
+		let time = self.time(seconds_since_start);
+		[days as u8, (days >> 8) as u8, time.minute, time.hour, time.hundredths, time.second]
+	}
+
+	/// Sets the date and time from a CLOCK$ device record. Returns false, leaving the clock as it
+	/// was, if the record is too short or has a time that doesn't exist.
+	pub fn set_from_device_record(&mut self, seconds_since_start: f64, record: &[u8]) -> bool {
+		if record.len() < CLOCK_DEVICE_RECORD_BYTES {
+			return false;
+		}
+		let days = record[0] as u16 | ((record[1] as u16) << 8);
+		let date = DosDate::from_days_since_1980(days);
+		if date.days_since_1980() != days {
+			return false;
+		}
+		match DosTime::new(record[3], record[2], record[5], record[4]) {
+			Some(time) => {
+				self.set(seconds_since_start, date, time);
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_dates() {
+		assert_eq!(DosDate::new(1980, 1, 1).unwrap().days_since_1980(), 0);
+		assert_eq!(DosDate::new(1980, 1, 1).unwrap().day_of_week(), 2);
+		assert_eq!(DosDate::new(2000, 2, 29).unwrap().day_of_week(), 2);
+		assert_eq!(DosDate::new(1999, 2, 29), None);
+		assert_eq!(DosDate::new(1990, 2, 30), None);
+		assert_eq!(DosDate::new(1979, 12, 31), None);
+		assert_eq!(DosDate::new(1990, 13, 1), None);
+		for &(year, month, day) in [(1980, 1, 1), (1980, 12, 31), (1996, 2, 29), (2000, 3, 1), (2099, 12, 31)].iter() {
+			let date = DosDate::new(year, month, day).unwrap();
+			assert_eq!(DosDate::from_days_since_1980(date.days_since_1980()), date);
+		}
+	}
+
+	#[test] fn test_set_clock() {
+		let mut clock = DosClock::new();
+		assert_eq!(clock.date(0.), DosDate::new(1980, 1, 1).unwrap());
+		clock.set_date(10., DosDate::new(1994, 6, 15).unwrap());
+		clock.set_time(10., DosTime::new(12, 30, 45, 50).unwrap());
+		assert_eq!(clock.date(10.), DosDate::new(1994, 6, 15).unwrap());
+		assert_eq!(clock.time(10.), DosTime::new(12, 30, 45, 50).unwrap());
+		// It carries on from the time that was set.
+		assert_eq!(clock.time(25.), DosTime::new(12, 31, 0, 50).unwrap());
+
+		let record = clock.device_record(10.);
+		let mut other_clock = DosClock::new();
+		assert!(other_clock.set_from_device_record(0., &record));
+		assert_eq!(other_clock.date(0.), clock.date(10.));
+		assert_eq!(other_clock.time(0.), clock.time(10.));
+		assert!(!other_clock.set_from_device_record(0., &[0, 0, 60, 0, 0, 0]));
+		assert!(!other_clock.set_from_device_record(0., &[0, 0, 0]));
+
+		// The tick count is about 18.2 a second.
+		clock.set_time(0., DosTime::new(1, 0, 0, 0).unwrap());
+		assert_eq!(clock.bios_ticks(0.), 65543);
+	}
+}
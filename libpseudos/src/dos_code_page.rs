@@ -0,0 +1,34 @@
+// Code page 437, the character set of the IBM PC's text modes. The bytes below 20h are drawn as
+// symbols on the screen rather than being control characters, so they're mapped to the symbols
+// here too, except that 00h is a space.
+// https://en.wikipedia.org/wiki/Code_page_437
+
+const CP437_LOW: &str = " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+	└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// Gets the Unicode character for a character on the screen.
+pub fn cp437_to_char(c: u8) -> char {
+	match c {
+		0x00 ..= 0x1f => CP437_LOW.chars().nth(c as usize).unwrap(),
+		0x7f => '⌂',
+		0x80 ..= 0xff => CP437_HIGH.chars().nth(c as usize - 0x80).unwrap(),
+		_ => c as char,
+	}
+}
+
+pub fn cp437_to_string(text: &[u8]) -> String {
+	text.iter().map(|c| cp437_to_char(*c)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_cp437_to_string() {
+		assert_eq!(CP437_LOW.chars().count(), 0x20);
+		assert_eq!(CP437_HIGH.chars().count(), 0x80);
+		assert_eq!(cp437_to_string(b"\x00\x01 Hello\x7f"), " ☺ Hello⌂");
+		assert_eq!(cp437_to_string(b"\x9c\xb0\xc9\xcd\xbb\xe1\xfe"), "£░╔═╗ß■");
+	}
+}
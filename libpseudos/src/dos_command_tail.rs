@@ -0,0 +1,287 @@
+// The command tail is the text after the program name on the command line, which DOS stores in the
+// PSP at 0x80, and parses into the two FCBs at 0x5c and 0x6c.
+// https://en.wikipedia.org/wiki/Program_Segment_Prefix
+
+// The PSP has 127 bytes for the command tail, and one is needed for the 0x0d terminator.
+pub const MAX_COMMAND_TAIL_BYTES: usize = 126;
+
+// Characters that may separate a file name from whatever comes before it.
+const FCB_SEPARATOR_CHARS: &[u8] = b":.;,=+";
+// Characters that end a file name (along with control characters).
+const FCB_TERMINATOR_CHARS: &[u8] = b":.;,=+ \t/\"[]<>|";
+
+/// Joins the program arguments into a command tail, with a space before each argument like
+/// COMMAND.COM produces. Arguments containing spaces are quoted. The case is kept as is, since
+/// programs that read the tail directly may care about it, while the FCBs get uppercased names.
+pub fn build_command_tail<S: AsRef<[u8]>>(args: &[S]) -> Result<Vec<u8>, String> {
+	let mut command_tail = vec![];
+	for arg in args {
+		let arg = arg.as_ref();
+		command_tail.push(b' ');
+		if arg.is_empty() || arg.iter().any(|c| *c == b' ' || *c == b'\t') {
+			command_tail.push(b'"');
+			command_tail.extend(arg);
+			command_tail.push(b'"');
+		} else {
+			command_tail.extend(arg);
+		}
+	}
+	if command_tail.contains(&0x0d) {
+		return Err("Command line tail can't contain a carriage return".to_string());
+	}
+	if command_tail.len() > MAX_COMMAND_TAIL_BYTES {
+		return Err(format!("Command line tail too long: {} bytes, but the limit is {}", command_tail.len(), MAX_COMMAND_TAIL_BYTES));
+	}
+	Ok(command_tail)
+}
+
+/// Splits a command tail into the arguments that COMMAND.COM parses into the PSP FCBs. Switches
+/// (arguments starting with '/') are skipped, so "/Q *.ZZT" puts *.ZZT in the first FCB.
+pub fn command_tail_fcb_args(command_tail: &[u8]) -> Vec<&[u8]> {
+	command_tail.split(|c| *c == b' ' || *c == b'\t')
+		.filter(|arg| !arg.is_empty() && arg[0] != b'/')
+		.take(2)
+		.collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FcbFilename {
+	// Drive number where 1 is A:, or None if no drive was given.
+	pub drive: Option<u8>,
+	// The name and extension, padded with spaces, or None if they weren't given.
+	pub name: Option<[u8; 8]>,
+	pub ext: Option<[u8; 3]>,
+	pub has_wildcards: bool,
+	pub invalid_drive: bool,
+	// Number of bytes of the input that were parsed.
+	pub parsed_len: usize,
+}
+
+impl FcbFilename {
+	/// The value int 21h AH=29h returns in AL: 0xff for an invalid drive, 1 if there were
+	/// wildcards, otherwise 0.
+	pub fn result_code(&self) -> u8 {
+		if self.invalid_drive {
+			0xff
+		} else if self.has_wildcards {
+			1
+		} else {
+			0
+		}
+	}
+
+	/// Writes the drive byte, name and extension to the first 12 bytes of an FCB. Parts that
+	/// weren't given are cleared, unless the matching int 21h AH=29h flag bit (1 = drive, 2 = name,
+	/// 3 = extension) says to leave them alone.
+	pub fn write_to_fcb(&self, fcb: &mut [u8], flags: u8) {
+		match self.drive {
+			Some(drive) => fcb[0] = drive,
+			None if flags & 0b10 == 0 => fcb[0] = 0,
+			None => {}
+		}
+		match self.name {
+			Some(name) => fcb[1..9].copy_from_slice(&name),
+			None if flags & 0b100 == 0 => fcb[1..9].iter_mut().for_each(|c| *c = b' '),
+			None => {}
+		}
+		match self.ext {
+			Some(ext) => fcb[9..12].copy_from_slice(&ext),
+			None if flags & 0b1000 == 0 => fcb[9..12].iter_mut().for_each(|c| *c = b' '),
+			None => {}
+		}
+	}
+}
+
+fn is_fcb_terminator(c: u8) -> bool {
+	c < 0x20 || FCB_TERMINATOR_CHARS.contains(&c)
+}
+
+// Reads a name part into a field, uppercased and padded with spaces. A '*' fills the rest of the
+// field with '?'. Characters past the end of the field are skipped.
+fn parse_fcb_name_part(input: &[u8], pos: &mut usize, field: &mut [u8]) {
+	let mut field_pos = 0;
+	while let Some(&c) = input.get(*pos) {
+		if is_fcb_terminator(c) {
+			break;
+		}
+		*pos += 1;
+		if c == b'*' {
+			while field_pos < field.len() {
+				field[field_pos] = b'?';
+				field_pos += 1;
+			}
+		} else if field_pos < field.len() {
+			field[field_pos] = c.to_ascii_uppercase();
+			field_pos += 1;
+		}
+	}
+	field[field_pos..].iter_mut().for_each(|c| *c = b' ');
+}
+
+/// Parses a file name into FCB form, following the rules of int 21h AH=29h
+/// (http://www.ctyme.com/intr/rb-2779.htm). If bit 0 of the flags is set, a leading separator is
+/// skipped. Drive numbers that is_valid_drive rejects (where 1 is A:) are flagged as invalid.
+pub fn parse_fcb_filename(input: &[u8], flags: u8, is_valid_drive: &dyn Fn(u8) -> bool) -> FcbFilename {
+	let mut pos = 0;
+	let skip_whitespace = |pos: &mut usize| {
+		while input.get(*pos).is_some_and(|c| *c == b' ' || *c == b'\t') {
+			*pos += 1;
+		}
+	};
+	skip_whitespace(&mut pos);
+	if flags & 1 != 0 && input.get(pos).is_some_and(|c| FCB_SEPARATOR_CHARS.contains(c)) {
+		pos += 1;
+		skip_whitespace(&mut pos);
+	}
+
+	let mut drive = None;
+	let mut invalid_drive = false;
+	if let (Some(letter), Some(b':')) = (input.get(pos), input.get(pos + 1)) {
+		if letter.is_ascii_alphabetic() {
+			let drive_number = letter.to_ascii_uppercase() - b'A' + 1;
+			invalid_drive = !is_valid_drive(drive_number);
+			drive = Some(drive_number);
+			pos += 2;
+		}
+	}
+
+	let mut name = None;
+	if input.get(pos).is_some_and(|c| !is_fcb_terminator(*c)) {
+		let mut name_field = [b' '; 8];
+		parse_fcb_name_part(input, &mut pos, &mut name_field);
+		name = Some(name_field);
+	}
+	let mut ext = None;
+	if input.get(pos) == Some(&b'.') {
+		pos += 1;
+		let mut ext_field = [b' '; 3];
+		parse_fcb_name_part(input, &mut pos, &mut ext_field);
+		ext = Some(ext_field);
+	}
+
+	let has_wildcards = name.iter().any(|name| name.contains(&b'?')) || ext.iter().any(|ext| ext.contains(&b'?'));
+	FcbFilename {
+		drive,
+		name,
+		ext,
+		has_wildcards,
+		invalid_drive,
+		parsed_len: pos,
+	}
+}
+
+/// Lays out a file name like b"TOWN.ZZT" the way an FCB holds it, as an 8 character name and a 3
+/// character extension padded with spaces. The "." and ".." directories are all name.
+pub fn fcb_padded_name(filename: &[u8]) -> [u8; 11] {
+	let (name, ext) = match filename.iter().rposition(|c| *c == b'.') {
+		Some(dot_pos) if filename.iter().any(|c| *c != b'.') => (&filename[..dot_pos], &filename[dot_pos + 1..]),
+		_ => (filename, &b""[..]),
+	};
+	let mut padded_name = [b' '; 11];
+	for (padded_char, c) in padded_name[..8].iter_mut().zip(name.iter()) {
+		*padded_char = c.to_ascii_uppercase();
+	}
+	for (padded_char, c) in padded_name[8..].iter_mut().zip(ext.iter()) {
+		*padded_char = c.to_ascii_uppercase();
+	}
+	padded_name
+}
+
+/// Turns the 8 character name and 3 character extension of an FCB back into a name like
+/// b"TOWN.ZZT".
+pub fn fcb_unpadded_name(padded_name: &[u8]) -> Vec<u8> {
+	let trim_padding = |part: &[u8]| {
+		let end = part.iter().rposition(|c| *c != b' ').map_or(0, |pos| pos + 1);
+		part[..end].to_vec()
+	};
+	let mut filename = trim_padding(&padded_name[..8]);
+	let ext = trim_padding(&padded_name[8..11]);
+	if !ext.is_empty() {
+		filename.push(b'.');
+		filename.extend(ext);
+	}
+	filename
+}
+
+/// Whether a name laid out by fcb_padded_name matches the 11 character name and extension of a
+/// search FCB, where '?' matches any character, including the padding.
+pub fn fcb_name_matches(pattern: &[u8], padded_name: &[u8; 11]) -> bool {
+	pattern.len() == padded_name.len() && pattern.iter().zip(padded_name.iter()).all(|(pattern_char, c)| {
+		*pattern_char == b'?' || pattern_char.to_ascii_uppercase() == *c
+	})
+}
+
+/// The "X:" that goes before the name in an FCB with the given drive byte, where 1 is A: and 0 is
+/// the current drive, which needs nothing. Drive bytes past Z: don't name a drive, so they're None.
+pub fn fcb_drive_prefix(drive: u8) -> Option<Vec<u8>> {
+	match drive {
+		0 => Some(vec![]),
+		1 ..= 26 => Some(vec![b'A' + drive - 1, b':']),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_build_command_tail() {
+		assert_eq!(build_command_tail(&["b:save.dat", "/q", "*.zzt"]), Ok(b" b:save.dat /q *.zzt".to_vec()));
+		assert_eq!(build_command_tail(&["My Town.zzt"]), Ok(b" \"My Town.zzt\"".to_vec()));
+		assert_eq!(build_command_tail::<&str>(&[]), Ok(vec![]));
+		let long_arg = vec![b'x'; MAX_COMMAND_TAIL_BYTES];
+		assert!(build_command_tail(&[&long_arg]).is_err());
+		assert!(build_command_tail(&[&long_arg[1..]]).is_ok());
+	}
+
+	#[test] fn test_parse_fcb_filename() {
+		let all_drives_valid = |_| true;
+		let parsed = parse_fcb_filename(b"b:save.dat rest", 1, &all_drives_valid);
+		assert_eq!(parsed.drive, Some(2));
+		assert_eq!(parsed.name, Some(*b"SAVE    "));
+		assert_eq!(parsed.ext, Some(*b"DAT"));
+		assert_eq!(parsed.result_code(), 0);
+		assert_eq!(parsed.parsed_len, 10);
+
+		let parsed = parse_fcb_filename(b"  *.z?t", 0, &all_drives_valid);
+		assert_eq!(parsed.name, Some(*b"????????"));
+		assert_eq!(parsed.ext, Some(*b"Z?T"));
+		assert_eq!(parsed.result_code(), 1);
+
+		// Parsing stops at the first terminator.
+		let parsed = parse_fcb_filename(b"longfilename.text/x", 0, &all_drives_valid);
+		assert_eq!(parsed.name, Some(*b"LONGFILE"));
+		assert_eq!(parsed.ext, Some(*b"TEX"));
+		assert_eq!(parsed.parsed_len, 17);
+
+		let parsed = parse_fcb_filename(b"/p", 0, &all_drives_valid);
+		assert_eq!((parsed.drive, parsed.name, parsed.ext, parsed.parsed_len), (None, None, None, 0));
+
+		let parsed = parse_fcb_filename(b"q:file", 0, &|drive| drive == 3);
+		assert_eq!(parsed.result_code(), 0xff);
+
+		let mut fcb = [0xaa; 16];
+		parse_fcb_filename(b"x", 0, &all_drives_valid).write_to_fcb(&mut fcb, 0b1010);
+		assert_eq!(&fcb[..12], b"\xaaX       \xaa\xaa\xaa");
+	}
+
+	#[test] fn test_fcb_name_matches() {
+		assert_eq!(&fcb_padded_name(b"town.zzt"), b"TOWN    ZZT");
+		assert_eq!(&fcb_padded_name(b"README"), b"README     ");
+		assert_eq!(&fcb_padded_name(b".."), b"..         ");
+		assert_eq!(fcb_unpadded_name(b"TOWN    ZZT"), b"TOWN.ZZT");
+		assert_eq!(fcb_unpadded_name(b"README     "), b"README");
+		assert!(fcb_name_matches(b"????????ZZT", &fcb_padded_name(b"TOWN.ZZT")));
+		assert!(fcb_name_matches(b"???????????", &fcb_padded_name(b"README")));
+		// '?' matches the padding, but a name can't be longer than the pattern's non-space part.
+		assert!(fcb_name_matches(b"TOWN????ZZT", &fcb_padded_name(b"TOWN.ZZT")));
+		assert!(fcb_name_matches(b"T?      ZZT", &fcb_padded_name(b"T.ZZT")));
+		assert!(!fcb_name_matches(b"T?      ZZT", &fcb_padded_name(b"TOWN.ZZT")));
+		assert!(!fcb_name_matches(b"????????EXE", &fcb_padded_name(b"TOWN.ZZT")));
+		assert_eq!(fcb_drive_prefix(0), Some(vec![]));
+		assert_eq!(fcb_drive_prefix(3), Some(b"C:".to_vec()));
+		assert_eq!(fcb_drive_prefix(26), Some(b"Z:".to_vec()));
+		assert_eq!(fcb_drive_prefix(27), None);
+		assert_eq!(fcb_drive_prefix(0xc0), None);
+	}
+}
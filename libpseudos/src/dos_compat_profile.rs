@@ -0,0 +1,233 @@
+// Compatibility profiles, which collect the settings a particular program needs to run well, so
+// they don't have to be given as arguments every time. A profile only changes the settings it
+// names, and each line of a profile file is one of them:
+//
+//   # Comments start with a hash.
+//   name zzt
+//   program ZZT.EXE
+//   dos-version 3.30
+//   blink on
+//   cursor-emulation on
+//   speed 1.5
+//   refresh-rate 60
+//   slices-per-frame 4
+//   mouse off
+//   background slow
+//   sync-clock on
+//
+// "program" names a program the profile is for, in the root of the C: drive, and can be given more
+// than once. dos-version is what int 21h AH=30h reports, with two digits after the point. speed is
+// the speed multiplier, and background is "pause" or "slow" (see dos_background_policy). sync-clock
+// is how the timer carries on after being in the background: on moves the clock forward by the time
+// that passed, and off carries on from where it was. The built-in profiles are written the same
+// way.
+
+use crate::dos_background_policy::{BackgroundMode, ResumeClock};
+use crate::emulator::EmulatorOptions;
+
+/// A set of settings for a program. Settings that are None are left as they are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompatProfile {
+	pub name: String,
+	/// The programs the profile is picked for by default, in upper case.
+	pub program_names: Vec<String>,
+	/// The major and minor version int 21h AH=30h reports.
+	pub dos_version: Option<(u8, u8)>,
+	pub blink_enabled: Option<bool>,
+	pub cursor_emulation: Option<bool>,
+	pub speed_multiplier: Option<f64>,
+	pub refresh_rate_hz: Option<f64>,
+	pub slices_per_frame: Option<usize>,
+	pub mouse: Option<bool>,
+	pub background_mode: Option<BackgroundMode>,
+	pub resume_clock: Option<ResumeClock>,
+}
+
+// ZZT and Super ZZT ask whether to use the keyboard or the mouse at startup if there's a mouse
+// driver, so there isn't one. The rest are what the defaults were tuned for, written out so they
+// stay put if the defaults change.
+const ZZT_PROFILE: &str = "\
+name zzt
+program ZZT.EXE
+program SUPERZ.EXE
+blink on
+speed 1
+mouse off
+sync-clock off
+";
+
+// For programs that check the DOS version and refuse to run on one newer than they know.
+const DOS3_PROFILE: &str = "\
+name dos3
+dos-version 3.30
+";
+
+const BUILT_IN_PROFILES: &[&str] = &[ZZT_PROFILE, DOS3_PROFILE];
+
+fn parse_on_off(value: &str) -> Option<bool> {
+	match value {
+		"on" => Some(true),
+		"off" => Some(false),
+		_ => None,
+	}
+}
+
+fn parse_dos_version(value: &str) -> Option<(u8, u8)> {
+	let (major, minor) = value.split_once('.')?;
+	if major.is_empty() || minor.len() != 2 {
+		return None;
+	}
+	Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+impl CompatProfile {
+	/// Parses the text of a profile file. The name is used to say where errors are.
+	pub fn parse(text: &str, name: &str) -> Result<CompatProfile, String> {
+		let mut profile = CompatProfile::default();
+		for (line_index, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let location = format!("{}:{}", name, line_index + 1);
+			let (setting, value) = line.split_once(char::is_whitespace).map(|(setting, value)| (setting, value.trim())).unwrap_or((line, ""));
+			let expected = |what: &str| format!("{}: {} needs {}", location, setting, what);
+			match setting {
+				"name" => profile.name = value.to_string(),
+				"program" => {
+					if value.is_empty() || value.contains(['\\', '/']) {
+						return Err(expected("a file name in the root of the C: drive"));
+					}
+					profile.program_names.push(value.to_ascii_uppercase());
+				}
+				"dos-version" => profile.dos_version = Some(parse_dos_version(value).ok_or_else(|| expected("a version like 3.30"))?),
+				"blink" => profile.blink_enabled = Some(parse_on_off(value).ok_or_else(|| expected("on or off"))?),
+				"cursor-emulation" => profile.cursor_emulation = Some(parse_on_off(value).ok_or_else(|| expected("on or off"))?),
+				"speed" => profile.speed_multiplier = Some(value.parse().map_err(|_| expected("a number"))?),
+				"refresh-rate" => profile.refresh_rate_hz = Some(value.parse().map_err(|_| expected("a number of Hz"))?),
+				"slices-per-frame" => profile.slices_per_frame = Some(value.parse().map_err(|_| expected("a number"))?),
+				"mouse" => profile.mouse = Some(parse_on_off(value).ok_or_else(|| expected("on or off"))?),
+				"background" => profile.background_mode = Some(match value {
+					"pause" => BackgroundMode::Pause,
+					"slow" => BackgroundMode::SlowTick,
+					_ => return Err(expected("pause or slow")),
+				}),
+				"sync-clock" => profile.resume_clock = Some(match parse_on_off(value) {
+					Some(true) => ResumeClock::SyncToHost,
+					Some(false) => ResumeClock::Continue,
+					None => return Err(expected("on or off")),
+				}),
+				_ => return Err(format!("{}: Unknown setting \"{}\"", location, setting)),
+			}
+		}
+		Ok(profile)
+	}
+
+	/// The profiles that come with the emulator.
+	pub fn built_in() -> Vec<CompatProfile> {
+		BUILT_IN_PROFILES.iter().map(|text| CompatProfile::parse(text, "built-in profile").unwrap()).collect()
+	}
+
+	/// Finds the built-in profile with a name.
+	pub fn built_in_named(name: &str) -> Option<CompatProfile> {
+		CompatProfile::built_in().into_iter().find(|profile| profile.name.eq_ignore_ascii_case(name))
+	}
+
+	/// Finds the built-in profile for a program, by its file name.
+	pub fn built_in_for_program(program_name: &str) -> Option<CompatProfile> {
+		CompatProfile::built_in().into_iter().find(|profile| profile.is_for_program(program_name))
+	}
+
+	pub fn is_for_program(&self, program_name: &str) -> bool {
+		self.program_names.iter().any(|name| name.eq_ignore_ascii_case(program_name))
+	}
+
+	/// Changes the options the profile has settings for. Options that are set afterwards, such as
+	/// from arguments, still win over the profile.
+	pub fn apply(&self, options: &mut EmulatorOptions) {
+		if let Some(dos_version) = self.dos_version {
+			options.dos.dos_version = dos_version;
+		}
+		if let Some(blink_enabled) = self.blink_enabled {
+			options.video.blink_enabled = blink_enabled;
+		}
+		if let Some(cursor_emulation) = self.cursor_emulation {
+			options.video.cursor_emulation = cursor_emulation;
+		}
+		if let Some(speed_multiplier) = self.speed_multiplier {
+			options.cpu.speed_multiplier = speed_multiplier;
+		}
+		if self.refresh_rate_hz.is_some() {
+			options.video.refresh_rate_hz = self.refresh_rate_hz;
+		}
+		if let Some(slices_per_frame) = self.slices_per_frame {
+			options.cpu.frame_slicing.slices_per_frame = slices_per_frame;
+		}
+		if let Some(mouse) = self.mouse {
+			options.input.mouse = mouse;
+		}
+		if let Some(background_mode) = self.background_mode {
+			options.input.background_mode = background_mode;
+		}
+		if let Some(resume_clock) = self.resume_clock {
+			options.input.resume_clock = resume_clock;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_parse_and_apply_profile() {
+		let text = "# Old game\nname old\nprogram game.exe\nprogram Setup.exe\ndos-version 3.30\nblink off\ncursor-emulation off\n\
+			speed 0.5\nrefresh-rate 60\nslices-per-frame 4\nmouse on\nbackground slow\nsync-clock on\n";
+		let profile = CompatProfile::parse(text, "old.profile").unwrap();
+		assert_eq!(profile.name, "old");
+		assert_eq!(profile.program_names, vec!["GAME.EXE".to_string(), "SETUP.EXE".to_string()]);
+		assert!(profile.is_for_program("game.EXE"));
+		assert!(!profile.is_for_program("ZZT.EXE"));
+
+		let mut options = EmulatorOptions::default();
+		profile.apply(&mut options);
+		let mut expected = EmulatorOptions::default();
+		expected.dos.dos_version = (3, 30);
+		expected.video.blink_enabled = false;
+		expected.video.cursor_emulation = false;
+		expected.cpu.speed_multiplier = 0.5;
+		expected.video.refresh_rate_hz = Some(60.);
+		expected.cpu.frame_slicing.slices_per_frame = 4;
+		expected.input.mouse = true;
+		expected.input.background_mode = BackgroundMode::SlowTick;
+		expected.input.resume_clock = ResumeClock::SyncToHost;
+		assert_eq!(options, expected);
+		assert!(options.validate().is_ok());
+
+		// Settings a profile doesn't have are left alone.
+		let mut options = expected.clone();
+		CompatProfile::parse("dos-version 6.22", "new.profile").unwrap().apply(&mut options);
+		expected.dos.dos_version = (6, 22);
+		assert_eq!(options, expected);
+	}
+
+	#[test] fn test_parse_profile_errors() {
+		assert_eq!(CompatProfile::parse("blink maybe", "a.profile"), Err("a.profile:1: blink needs on or off".to_string()));
+		assert_eq!(CompatProfile::parse("\ndos-version 3.3", "a.profile"), Err("a.profile:2: dos-version needs a version like 3.30".to_string()));
+		assert_eq!(CompatProfile::parse("sync-clock yes", "a.profile"), Err("a.profile:1: sync-clock needs on or off".to_string()));
+		assert_eq!(CompatProfile::parse("speed fast", "a.profile"), Err("a.profile:1: speed needs a number".to_string()));
+		assert_eq!(CompatProfile::parse("program GAMES\\GAME.EXE", "a.profile"), Err("a.profile:1: program needs a file name in the root of the C: drive".to_string()));
+		assert_eq!(CompatProfile::parse("turbo on", "a.profile"), Err("a.profile:1: Unknown setting \"turbo\"".to_string()));
+	}
+
+	#[test] fn test_built_in_profiles() {
+		let profiles = CompatProfile::built_in();
+		assert_eq!(profiles.iter().map(|profile| profile.name.as_str()).collect::<Vec<_>>(), vec!["zzt", "dos3"]);
+		assert_eq!(CompatProfile::built_in_for_program("superz.exe").map(|profile| profile.name), Some("zzt".to_string()));
+		assert_eq!(CompatProfile::built_in_for_program("GAME.EXE"), None);
+		assert_eq!(CompatProfile::built_in_named("DOS3").and_then(|profile| profile.dos_version), Some((3, 30)));
+		// The ZZT profile is what ZZT gets without one, since it's the default program.
+		let mut options = EmulatorOptions::default();
+		CompatProfile::built_in_for_program(&options.dos.program_name).unwrap().apply(&mut options);
+		assert_eq!(options, EmulatorOptions::default());
+	}
+}
@@ -0,0 +1,141 @@
+// The country-specific formats that programs get from int 21h AH=38h to format dates, times and
+// amounts of money.
+// http://www.ctyme.com/intr/rb-2773.htm
+
+/// The size of the buffer int 21h AH=38h fills in.
+pub const COUNTRY_INFO_BYTES: usize = 34;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateFormat {
+	MonthDayYear = 0,
+	DayMonthYear = 1,
+	YearMonthDay = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CountryInfo {
+	/// The international dialling code of the country, eg. 1 for the US.
+	pub country_code: u16,
+	pub date_format: DateFormat,
+	/// At most 4 characters are kept.
+	pub currency_symbol: Vec<u8>,
+	pub thousands_separator: u8,
+	pub decimal_separator: u8,
+	pub date_separator: u8,
+	pub time_separator: u8,
+	/// Whether the currency symbol goes after the amount rather than before it.
+	pub currency_symbol_after: bool,
+	/// Whether there's a space between the currency symbol and the amount.
+	pub currency_space: bool,
+	/// The number of digits after the decimal separator in amounts of money.
+	pub currency_digits: u8,
+	pub time_24_hour: bool,
+	pub list_separator: u8,
+}
+
+impl Default for CountryInfo {
+	fn default() -> CountryInfo {
+		CountryInfo::united_states()
+	}
+}
+
+impl CountryInfo {
+	pub fn united_states() -> CountryInfo {
+		CountryInfo {
+			country_code: 1,
+			date_format: DateFormat::MonthDayYear,
+			currency_symbol: b"$".to_vec(),
+			thousands_separator: b',',
+			decimal_separator: b'.',
+			date_separator: b'-',
+			time_separator: b':',
+			currency_symbol_after: false,
+			currency_space: false,
+			currency_digits: 2,
+			time_24_hour: false,
+			list_separator: b',',
+		}
+	}
+
+	/// Looks up the formats MS-DOS uses for a country code. Only a few countries are known.
+	pub fn for_country_code(country_code: u16) -> Option<CountryInfo> {
+		let us = CountryInfo::united_states();
+		match country_code {
+			1 => Some(us),
+			// Code page 437 has the pound sign at 9Ch.
+			44 => Some(CountryInfo {
+				country_code,
+				date_format: DateFormat::DayMonthYear,
+				currency_symbol: b"\x9c".to_vec(),
+				date_separator: b'/',
+				time_24_hour: true,
+				..us
+			}),
+			49 => Some(CountryInfo {
+				country_code,
+				date_format: DateFormat::DayMonthYear,
+				currency_symbol: b"DM".to_vec(),
+				thousands_separator: b'.',
+				decimal_separator: b',',
+				date_separator: b'.',
+				currency_symbol_after: true,
+				currency_space: true,
+				time_24_hour: true,
+				list_separator: b';',
+				..us
+			}),
+			61 => Some(CountryInfo {
+				country_code,
+				date_format: DateFormat::DayMonthYear,
+				time_24_hour: true,
+				..us
+			}),
+			81 => Some(CountryInfo {
+				country_code,
+				date_format: DateFormat::YearMonthDay,
+				// Code page 437 has the yen sign at 9Dh.
+				currency_symbol: b"\x9d".to_vec(),
+				currency_digits: 0,
+				time_24_hour: true,
+				..us
+			}),
+			_ => None,
+		}
+	}
+
+	/// Lays out the info like the buffer filled in by int 21h AH=38h. The case map routine address
+	/// at 12h is left as 0.
+	pub fn to_bytes(&self) -> [u8; COUNTRY_INFO_BYTES] {
+		let mut bytes = [0u8; COUNTRY_INFO_BYTES];
+		bytes[0x00 .. 0x02].copy_from_slice(&(self.date_format as u16).to_le_bytes());
+		// The strings are null terminated, so there's room for 4 characters of currency symbol.
+		for (index, c) in self.currency_symbol.iter().take(4).enumerate() {
+			bytes[0x02 + index] = *c;
+		}
+		bytes[0x07] = self.thousands_separator;
+		bytes[0x09] = self.decimal_separator;
+		bytes[0x0b] = self.date_separator;
+		bytes[0x0d] = self.time_separator;
+		bytes[0x0f] = (self.currency_symbol_after as u8) | ((self.currency_space as u8) << 1);
+		bytes[0x10] = self.currency_digits;
+		bytes[0x11] = self.time_24_hour as u8;
+		bytes[0x16] = self.list_separator;
+		bytes
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_country_info_layout() {
+		let bytes = CountryInfo::united_states().to_bytes();
+		assert_eq!(&bytes[0x00 .. 0x12], b"\0\0$\0\0\0\0,\0.\0-\0:\0\0\x02\0");
+		assert_eq!(bytes[0x16], b',');
+
+		let bytes = CountryInfo::for_country_code(49).unwrap().to_bytes();
+		assert_eq!(&bytes[0x00 .. 0x12], b"\x01\0DM\0\0\0.\0,\0.\0:\0\x03\x02\x01");
+		assert_eq!(bytes[0x16], b';');
+		assert_eq!(CountryInfo::for_country_code(999), None);
+	}
+}
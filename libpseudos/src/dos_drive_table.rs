@@ -0,0 +1,369 @@
+use crate::dos_error_codes::DosErrorCode;
+use crate::dos_file_system::{DosFileAccessMode, DosFileSeekOrigin, DosFileSystem, DosMediaId, DosVolumeInfo, OpenHandleInfo, DOS_FIRST_FILE_HANDLE};
+
+// DOS allows drives A: to Z:.
+const MAX_DRIVE_COUNT: usize = 26;
+
+// Splits the drive letter off the front of a path, returning the drive index (0 is A:) if there
+// was one.
+fn split_drive(path: &[u8]) -> (Option<u8>, &[u8]) {
+	if path.len() >= 2 && path[1] == b':' && path[0].is_ascii_alphabetic() {
+		(Some(path[0].to_ascii_uppercase() - b'A'), &path[2..])
+	} else {
+		(None, path)
+	}
+}
+
+/// A file system made up of other file systems mounted as drive letters. Paths starting with a
+/// drive letter go to that drive, and other paths go to the current drive.
+#[derive(Debug)]
+pub struct DosDriveTable {
+	drives: Vec<Option<Box<dyn DosFileSystem>>>,
+	current_drive: u8,
+	// Each handle given to the program maps to a drive and the handle within that drive's file
+	// system.
+	file_handles: Vec<Option<(u8, u16)>>,
+	// The drive being searched by the last find_first_file.
+	search_drive: Option<u8>,
+}
+
+impl Default for DosDriveTable {
+	fn default() -> DosDriveTable {
+		DosDriveTable::new()
+	}
+}
+
+impl DosDriveTable {
+	pub fn new() -> DosDriveTable {
+		DosDriveTable {
+			drives: (0..MAX_DRIVE_COUNT).map(|_| None).collect(),
+			current_drive: 2,
+			file_handles: vec![],
+			search_drive: None,
+		}
+	}
+	
+	/// Mounts a file system as a drive (0 is A:), returning the file system that was previously
+	/// mounted there. Handles opened on the old file system become invalid.
+	pub fn mount(&mut self, drive: u8, file_system: Box<dyn DosFileSystem>) -> Result<Option<Box<dyn DosFileSystem>>, DosErrorCode> {
+		let old_file_system = self.unmount(drive)?;
+		self.drives[drive as usize] = Some(file_system);
+		Ok(old_file_system)
+	}
+	
+	/// Removes the file system mounted as a drive, eg. to simulate removing a floppy disk.
+	pub fn unmount(&mut self, drive: u8) -> Result<Option<Box<dyn DosFileSystem>>, DosErrorCode> {
+		if drive as usize >= MAX_DRIVE_COUNT {
+			return Err(DosErrorCode::InvalidDrive);
+		}
+		for slot in self.file_handles.iter_mut() {
+			if let Some((handle_drive, _)) = *slot {
+				if handle_drive == drive {
+					*slot = None;
+				}
+			}
+		}
+		if self.search_drive == Some(drive) {
+			self.search_drive = None;
+		}
+		Ok(self.drives[drive as usize].take())
+	}
+	
+	pub fn get_drive(&self, drive: u8) -> Option<&dyn DosFileSystem> {
+		match self.drives.get(drive as usize) {
+			Some(Some(file_system)) => Some(&**file_system),
+			_ => None,
+		}
+	}
+	
+	fn get_drive_mut(&mut self, drive: u8) -> Result<&mut dyn DosFileSystem, DosErrorCode> {
+		match self.drives.get_mut(drive as usize) {
+			Some(Some(file_system)) => Ok(&mut **file_system),
+			_ => Err(DosErrorCode::InvalidDrive),
+		}
+	}
+	
+	// Gets the drive a path refers to, and the rest of the path to pass on to that drive.
+	fn resolve_path<'a>(&self, path: &'a [u8]) -> (u8, &'a [u8]) {
+		let (drive, path) = split_drive(path);
+		(drive.unwrap_or(self.current_drive), path)
+	}
+	
+	fn add_handle(&mut self, drive: u8, drive_handle: u16) -> u16 {
+		let slot = match self.file_handles.iter().position(|slot| slot.is_none()) {
+			Some(pos) => pos,
+			None => {
+				self.file_handles.push(None);
+				self.file_handles.len() - 1
+			}
+		};
+		self.file_handles[slot] = Some((drive, drive_handle));
+		slot as u16 + DOS_FIRST_FILE_HANDLE
+	}
+	
+	fn get_drive_handle(&self, handle: u16) -> Result<(u8, u16), DosErrorCode> {
+		let handle_index = handle.checked_sub(DOS_FIRST_FILE_HANDLE).ok_or(DosErrorCode::InvalidFileHandle)? as usize;
+		if let Some(Some(drive_handle)) = self.file_handles.get(handle_index) {
+			Ok(*drive_handle)
+		} else {
+			Err(DosErrorCode::InvalidFileHandle)
+		}
+	}
+	
+	fn get_handle_file_system(&mut self, handle: u16) -> Result<(&mut dyn DosFileSystem, u16), DosErrorCode> {
+		let (drive, drive_handle) = self.get_drive_handle(handle)?;
+		Ok((self.get_drive_mut(drive)?, drive_handle))
+	}
+}
+
+impl DosFileSystem for DosDriveTable {
+	fn create(&mut self, filename: &[u8], attributes: u16) -> Result<u16, DosErrorCode> {
+		let (drive, path) = self.resolve_path(filename);
+		let drive_handle = self.get_drive_mut(drive)?.create(path, attributes)?;
+		Ok(self.add_handle(drive, drive_handle))
+	}
+	
+	fn open(&mut self, filename: &[u8], access_mode: DosFileAccessMode) -> Result<u16, DosErrorCode> {
+		let (drive, path) = self.resolve_path(filename);
+		let drive_handle = self.get_drive_mut(drive)?.open(path, access_mode)?;
+		Ok(self.add_handle(drive, drive_handle))
+	}
+	
+	fn close(&mut self, handle: u16) -> Result<(), DosErrorCode> {
+		let (file_system, drive_handle) = self.get_handle_file_system(handle)?;
+		file_system.close(drive_handle)?;
+		self.file_handles[(handle - DOS_FIRST_FILE_HANDLE) as usize] = None;
+		Ok(())
+	}
+	
+	fn duplicate(&mut self, handle: u16) -> Result<u16, DosErrorCode> {
+		let (drive, drive_handle) = self.get_drive_handle(handle)?;
+		let new_drive_handle = self.get_drive_mut(drive)?.duplicate(drive_handle)?;
+		Ok(self.add_handle(drive, new_drive_handle))
+	}
+	
+	fn force_duplicate(&mut self, handle: u16, new_handle: u16) -> Result<(), DosErrorCode> {
+		let new_slot = new_handle.checked_sub(DOS_FIRST_FILE_HANDLE).ok_or(DosErrorCode::InvalidFileHandle)? as usize;
+		let (drive, drive_handle) = self.get_drive_handle(handle)?;
+		if self.get_drive_handle(new_handle).is_ok() {
+			self.close(new_handle)?;
+		}
+		let new_drive_handle = self.get_drive_mut(drive)?.duplicate(drive_handle)?;
+		if new_slot >= self.file_handles.len() {
+			self.file_handles.resize(new_slot + 1, None);
+		}
+		self.file_handles[new_slot] = Some((drive, new_drive_handle));
+		Ok(())
+	}
+	
+	fn read(&mut self, handle: u16, destination: &mut [u8]) -> Result<u16, DosErrorCode> {
+		let (file_system, drive_handle) = self.get_handle_file_system(handle)?;
+		file_system.read(drive_handle, destination)
+	}
+	
+	fn write(&mut self, handle: u16, data: &[u8]) -> Result<u16, DosErrorCode> {
+		let (file_system, drive_handle) = self.get_handle_file_system(handle)?;
+		file_system.write(drive_handle, data)
+	}
+	
+	fn seek(&mut self, handle: u16, offset: u32, origin: DosFileSeekOrigin) -> Result<u32, DosErrorCode> {
+		let (file_system, drive_handle) = self.get_handle_file_system(handle)?;
+		file_system.seek(drive_handle, offset, origin)
+	}
+	
+	fn tell(&mut self, handle: u16) -> Result<u32, DosErrorCode> {
+		let (file_system, drive_handle) = self.get_handle_file_system(handle)?;
+		file_system.tell(drive_handle)
+	}
+	
+	fn truncate(&mut self, handle: u16) -> Result<u32, DosErrorCode> {
+		let (file_system, drive_handle) = self.get_handle_file_system(handle)?;
+		file_system.truncate(drive_handle)
+	}
+	
+	fn is_at_end(&mut self, handle: u16) -> Result<bool, DosErrorCode> {
+		let (file_system, drive_handle) = self.get_handle_file_system(handle)?;
+		file_system.is_at_end(drive_handle)
+	}
+	
+	fn flush_all(&mut self) -> Result<(), DosErrorCode> {
+		let mut result = Ok(());
+		for file_system in self.drives.iter_mut().flatten() {
+			result = result.and(file_system.flush_all());
+		}
+		result
+	}
+	
+	fn close_all(&mut self) {
+		for file_system in self.drives.iter_mut().flatten() {
+			file_system.close_all();
+		}
+		self.file_handles.clear();
+		self.search_drive = None;
+	}
+	
+	fn refresh(&mut self) {
+		for file_system in self.drives.iter_mut().flatten() {
+			file_system.refresh();
+		}
+	}
+	
+	fn find_first_file(&mut self, destination: &mut [u8], attributes: u16, search_spec: &[u8]) -> Result<(), DosErrorCode> {
+		let (drive, search_spec) = self.resolve_path(search_spec);
+		self.search_drive = None;
+		self.get_drive_mut(drive)?.find_first_file(destination, attributes, search_spec)?;
+		self.search_drive = Some(drive);
+		Ok(())
+	}
+	
+	fn find_next_file(&mut self, destination: &mut [u8]) -> Result<(), DosErrorCode> {
+		match self.search_drive {
+			Some(drive) => self.get_drive_mut(drive)?.find_next_file(destination),
+			None => Err(DosErrorCode::NoMoreFiles),
+		}
+	}
+	
+	fn delete(&mut self, filename: &[u8]) -> Result<(), DosErrorCode> {
+		let (drive, path) = self.resolve_path(filename);
+		self.get_drive_mut(drive)?.delete(path)
+	}
+	
+	fn get_attributes(&mut self, filename: &[u8]) -> Result<u16, DosErrorCode> {
+		let (drive, path) = self.resolve_path(filename);
+		self.get_drive_mut(drive)?.get_attributes(path)
+	}
+	
+	fn set_attributes(&mut self, filename: &[u8], attributes: u16) -> Result<(), DosErrorCode> {
+		let (drive, path) = self.resolve_path(filename);
+		self.get_drive_mut(drive)?.set_attributes(path, attributes)
+	}
+	
+	fn root_path(&self) -> Option<&std::path::Path> {
+		self.get_drive(self.current_drive).and_then(|file_system| file_system.root_path())
+	}
+	
+	fn open_handles(&self) -> Vec<OpenHandleInfo> {
+		// Each drive numbers its own handles, so they're given the numbers the program knows them by.
+		self.file_handles.iter().enumerate().filter_map(|(slot, drive_handle)| {
+			let (drive, drive_handle) = (*drive_handle)?;
+			let open_handle = self.get_drive(drive)?.open_handles().into_iter().find(|open_handle| open_handle.handle == drive_handle)?;
+			// The drive's own file system doesn't know which drive it is.
+			let mut dos_path = vec![b'A' + drive, b':'];
+			dos_path.extend(&open_handle.dos_path);
+			Some(OpenHandleInfo { handle: slot as u16 + DOS_FIRST_FILE_HANDLE, dos_path, ..open_handle })
+		}).collect()
+	}
+	
+	fn current_drive(&self) -> u8 {
+		self.current_drive
+	}
+	
+	fn set_current_drive(&mut self, drive: u8) -> Result<(), DosErrorCode> {
+		if self.get_drive(drive).is_some() {
+			self.current_drive = drive;
+			Ok(())
+		} else {
+			Err(DosErrorCode::InvalidDrive)
+		}
+	}
+	
+	fn volume_info(&self) -> Result<DosVolumeInfo, DosErrorCode> {
+		self.drive_volume_info(self.current_drive)
+	}
+	
+	fn drive_volume_info(&self, drive: u8) -> Result<DosVolumeInfo, DosErrorCode> {
+		match self.get_drive(drive) {
+			Some(file_system) => file_system.volume_info(),
+			None => Err(DosErrorCode::InvalidDrive),
+		}
+	}
+	
+	fn media_id(&self) -> Result<DosMediaId, DosErrorCode> {
+		self.drive_media_id(self.current_drive)
+	}
+	
+	fn set_media_id(&mut self, media_id: DosMediaId) -> Result<(), DosErrorCode> {
+		let current_drive = self.current_drive;
+		self.set_drive_media_id(current_drive, media_id)
+	}
+	
+	fn drive_media_id(&self, drive: u8) -> Result<DosMediaId, DosErrorCode> {
+		match self.get_drive(drive) {
+			Some(file_system) => file_system.media_id(),
+			None => Err(DosErrorCode::InvalidDrive),
+		}
+	}
+	
+	fn set_drive_media_id(&mut self, drive: u8, media_id: DosMediaId) -> Result<(), DosErrorCode> {
+		self.get_drive_mut(drive)?.set_media_id(media_id)
+	}
+	
+	fn current_directory(&self) -> Vec<u8> {
+		self.drive_current_directory(self.current_drive).unwrap_or_default()
+	}
+	
+	fn set_current_directory(&mut self, path: &[u8]) -> Result<(), DosErrorCode> {
+		// Each drive has its own current directory, so "A:\SAVES" doesn't change the current drive.
+		let (drive, path) = self.resolve_path(path);
+		self.get_drive_mut(drive).map_err(|_| DosErrorCode::PathNotFound)?.set_current_directory(path)
+	}
+	
+	fn drive_current_directory(&self, drive: u8) -> Result<Vec<u8>, DosErrorCode> {
+		match self.get_drive(drive) {
+			Some(file_system) => Ok(file_system.current_directory()),
+			None => Err(DosErrorCode::InvalidDrive),
+		}
+	}
+	
+	fn is_valid_drive(&self, drive: u8) -> bool {
+		self.get_drive(drive).is_some()
+	}
+	
+	fn drive_count(&self) -> u8 {
+		// DOS reports at least 5 drives (LASTDRIVE=E) even if they aren't all in use.
+		let last_mounted_drive = self.drives.iter().rposition(|drive| drive.is_some()).unwrap_or(0);
+		(last_mounted_drive as u8 + 1).max(5)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::tests::make_test_dir;
+	use crate::dos_file_system::StandardDosFileSystem;
+	
+	#[test] fn test_drive_letters() {
+		let floppy_path = make_test_dir("drive_a");
+		let hard_disk_path = make_test_dir("drive_c");
+		let mut drive_table = DosDriveTable::new();
+		drive_table.mount(0, Box::new(StandardDosFileSystem::new(floppy_path.clone()))).unwrap();
+		drive_table.mount(2, Box::new(StandardDosFileSystem::new(hard_disk_path.clone()))).unwrap();
+		
+		let handle = drive_table.create(b"A:SAVE.DAT", 0).unwrap();
+		assert_eq!(drive_table.write(handle, b"abc"), Ok(3));
+		drive_table.close(handle).unwrap();
+		assert!(floppy_path.join("SAVE.DAT").exists());
+		
+		let handle = drive_table.create(b"GAME.CFG", 0).unwrap();
+		drive_table.close(handle).unwrap();
+		assert!(hard_disk_path.join("GAME.CFG").exists());
+		
+		assert_eq!(drive_table.set_current_drive(1), Err(DosErrorCode::InvalidDrive));
+		drive_table.set_current_drive(0).unwrap();
+		assert_eq!(drive_table.root_path(), Some(&*floppy_path));
+		let handle = drive_table.open(b"SAVE.DAT", DosFileAccessMode::ReadOnly).unwrap();
+		let config_handle = drive_table.open(b"C:GAME.CFG", DosFileAccessMode::ReadWrite).unwrap();
+		assert_eq!(drive_table.open_handles(), vec![
+			OpenHandleInfo { handle, dos_path: b"A:\\SAVE.DAT".to_vec(), real_path: floppy_path.join("SAVE.DAT"), access_mode: DosFileAccessMode::ReadOnly },
+			OpenHandleInfo { handle: config_handle, dos_path: b"C:\\GAME.CFG".to_vec(), real_path: hard_disk_path.join("GAME.CFG"), access_mode: DosFileAccessMode::ReadWrite },
+		]);
+		let mut data = [0; 3];
+		assert_eq!(drive_table.read(handle, &mut data), Ok(3));
+		assert_eq!(&data, b"abc");
+		
+		// Swapping the disk invalidates handles on it.
+		drive_table.mount(0, Box::new(StandardDosFileSystem::new(hard_disk_path.clone()))).unwrap();
+		assert_eq!(drive_table.read(handle, &mut data), Err(DosErrorCode::InvalidFileHandle));
+		assert_eq!(drive_table.open(b"A:SAVE.DAT", DosFileAccessMode::ReadOnly), Err(DosErrorCode::FileNotFound));
+	}
+}
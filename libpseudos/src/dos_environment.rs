@@ -0,0 +1,80 @@
+// The environment block is a list of NUL terminated "NAME=value" strings, ended by an empty string.
+// DOS 3+ follows it with a word count of 1 and the full path of the program.
+// https://en.wikipedia.org/wiki/Environment_variable#DOS
+// http://www.ctyme.com/intr/rb-2939.htm
+
+/// Splits a "NAME=value" argument into the name and value. The name is uppercased like the SET
+/// command does, and the value is kept as is.
+pub fn parse_environment_var(var: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+	let equals_pos = match var.iter().position(|c| *c == b'=') {
+		Some(pos) if pos > 0 => pos,
+		_ => return Err(format!("Environment variable must look like NAME=value: {}", String::from_utf8_lossy(var))),
+	};
+	let name = var[..equals_pos].to_ascii_uppercase();
+	let value = var[equals_pos + 1..].to_vec();
+	if name.iter().chain(value.iter()).any(|c| *c == 0) {
+		return Err(format!("Environment variable can't contain a NUL: {}", String::from_utf8_lossy(var)));
+	}
+	Ok((name, value))
+}
+
+/// Builds an environment block from the variables and the full DOS path of the program. Later
+/// variables replace earlier ones with the same name.
+pub fn build_environment_block(vars: &[(Vec<u8>, Vec<u8>)], program_path: &[u8]) -> Vec<u8> {
+	let mut unique_vars: Vec<&(Vec<u8>, Vec<u8>)> = vec![];
+	for var in vars {
+		match unique_vars.iter().position(|existing| existing.0 == var.0) {
+			Some(pos) => unique_vars[pos] = var,
+			None => unique_vars.push(var),
+		}
+	}
+
+	let mut environment_block = vec![];
+	for (name, value) in unique_vars {
+		environment_block.extend(name);
+		environment_block.push(b'=');
+		environment_block.extend(value);
+		environment_block.push(0);
+	}
+	// An empty environment still needs its terminating empty string.
+	if environment_block.is_empty() {
+		environment_block.push(0);
+	}
+	environment_block.push(0);
+	environment_block.extend(&[1, 0]);
+	environment_block.extend(program_path);
+	environment_block.push(0);
+	environment_block
+}
+
+/// The program path after the variables in an environment block, if it has one.
+pub fn environment_program_path(environment_block: &[u8]) -> Option<&[u8]> {
+	let vars_end = environment_block.windows(2).position(|pair| pair == [0, 0])? + 2;
+	let after_vars = &environment_block[vars_end ..];
+	if after_vars.len() < 2 || after_vars[.. 2] != [1, 0] {
+		return None;
+	}
+	after_vars[2 ..].split(|c| *c == 0).next()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_build_environment_block() {
+		let vars = vec![
+			parse_environment_var(b"path=C:\\;C:\\GAMES").unwrap(),
+			parse_environment_var(b"Blaster=A220 I5").unwrap(),
+			parse_environment_var(b"PATH=C:\\").unwrap(),
+		];
+		assert_eq!(build_environment_block(&vars, b"C:\\ZZT.EXE"), b"PATH=C:\\\0BLASTER=A220 I5\0\0\x01\0C:\\ZZT.EXE\0".to_vec());
+		assert_eq!(build_environment_block(&[], b"C:\\ZZT.EXE"), b"\0\0\x01\0C:\\ZZT.EXE\0".to_vec());
+		assert!(parse_environment_var(b"=value").is_err());
+		assert!(parse_environment_var(b"NOVALUE").is_err());
+		assert_eq!(parse_environment_var(b"EMPTY="), Ok((b"EMPTY".to_vec(), vec![])));
+
+		assert_eq!(environment_program_path(&build_environment_block(&vars, b"C:\\ZZT.EXE")), Some(&b"C:\\ZZT.EXE"[..]));
+		assert_eq!(environment_program_path(&build_environment_block(&[], b"C:\\ZZT.EXE")), Some(&b"C:\\ZZT.EXE"[..]));
+		assert_eq!(environment_program_path(b"PATH=C:\\\0\0"), None);
+	}
+}
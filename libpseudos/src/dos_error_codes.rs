@@ -1,14 +1,19 @@
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum DosErrorCode {
+	InvalidFunction = 0x01,
 	FileNotFound = 0x02,
 	PathNotFound = 0x03,
 	NoFileHandlesLeft = 0x04,
 	AccessDenied = 0x05,
 	InvalidFileHandle = 0x06,
+	MemoryControlBlocksDestroyed = 0x07,
 	InsufficientMemory = 0x08,
+	InvalidMemoryBlockAddress = 0x09,
 	InvalidFileAccessMode = 0x0c,
 	InvalidData = 0x0d,
+	InvalidDrive = 0x0f,
 	NoMoreFiles = 0x12,
+	LockViolation = 0x21,
 	FileAlreadyExists = 0x50,
 }
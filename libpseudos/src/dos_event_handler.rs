@@ -1,20 +1,88 @@
+use crate::bios_floppy::{DiskStatus, FloppyImage, FLOPPY_SECTOR_BYTES};
+use crate::dos_clock::{DosClock, DosDate, DosTime, BIOS_TICKS_PER_DAY, CLOCK_DEVICE_RECORD_BYTES};
+use crate::dos_code_page::cp437_to_string;
+use crate::dos_command_tail::{fcb_drive_prefix, fcb_name_matches, fcb_padded_name, fcb_unpadded_name, parse_fcb_filename, MAX_COMMAND_TAIL_BYTES};
+use crate::dos_country_info::CountryInfo;
 use crate::dos_error_codes::DosErrorCode;
-use crate::dos_file_system::{DosFileAccessMode, DosFileSeekOrigin, DosFileSystem};
+use crate::dos_file_locks::FileLockTable;
+use crate::dos_file_system::{dos_device_name, find_data_filename, DosFileAccessMode, DosFileSeekOrigin, DosFileSystem, DosMediaId, DOS_FIND_DATA_BYTES, DOS_FIRST_FILE_HANDLE, DOS_MEDIA_ID_BYTES};
+use crate::dos_function_audit::{FunctionAudit, FunctionSupport};
+use crate::dos_interrupt_hooks::{run_interrupt_hooks, InterruptHookFn, InterruptHooks};
+use crate::dos_low_memory_guard::{LowMemoryGuard, LowMemoryProtection, LowMemoryWrite, VectorWatch};
+use crate::dos_memory;
+use crate::bios_keyboard::{KeyboardCommandEffect, KeyboardController, KeyboardLeds, Typematic};
+use crate::dos_mouse::{MouseArea, MouseDriver, MOUSE_BUTTON_COUNT};
+use crate::dos_scrollback::Scrollback;
+use crate::dos_video_stats::{OutputMethod, VideoStats};
 use crate::bios_loader::*;
+use crate::emulator::{open_floppy, EmulatorError, EmulatorOptions};
+use crate::exe_loader::{initialise_dos_program_segment_prefix, restore_psp_vectors, PROGRAM_PSP_SEGMENT};
+use crate::machine::Machine;
 
-use xachtsechs::types::{EventHandler, Flag, Reg, RegHalf};
+use xachtsechs::types::{EventHandler, Flag, Reg, RegHalf, StepResult};
 use xachtsechs::machine8086::{INTERRUPT_TABLE_ENTRY_BYTES, Machine8086};
 
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DosInterruptResult {
 	ShouldReturn,
 	ShouldReturnAndWaitForEvents,
 	ShouldBlockForKeypress,
+	// The program has terminated with the given return code.
+	ShouldExit(u8),
 }
 
+// How run_headless gets through the start of a program, like a timed intro or splash screen. While
+// it's fast forwarding, the timer interrupt comes after far fewer instructions, so the program sees
+// time go by quickly.
 #[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeadlessStartup {
+	Normal,
+	// Fast forwards through the given number of timer ticks.
+	FastForwardTicks(u64),
+	// Fast forwards until the program first reads or checks for a key.
+	FastForwardUntilInput,
+}
+
+// What to do when a program calls a function that isn't implemented.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum UnknownFuncAction {
+	Panic,
+	// Return with CF=1 and AX=1 (invalid function), which is what programs that probe for
+	// functions expect when a function isn't there.
+	#[default]
+	ReturnError,
+	// Print the function to stderr, then return an error like ReturnError.
+	Log,
+}
+
+// Panics or logs about something the program did that isn't implemented, as the action says. What
+// the program sees is up to the caller.
+fn report_unknown_function(action: UnknownFuncAction, description: &str) {
+	match action {
+		UnknownFuncAction::Panic => panic!("{}", description),
+		UnknownFuncAction::Log => eprintln!("{}", description),
+		UnknownFuncAction::ReturnError => {}
+	}
+}
+
+// Where text written with int 21h AH=02h and AH=09h goes.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum DosOutputMode {
+	// Written at the cursor like int 10h AH=0Eh.
+	#[default]
+	Screen,
+	// Written to the host's stdout, so it can be read without looking at the video memory.
+	Stdout,
+	Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum MachineType {
 	EGA,
 }
@@ -56,21 +124,147 @@ pub struct VideoMode {
 	text_page_count: u32,
 	// This is the number of bytes per page in memory.
 	text_page_bytes: u32,
+	// The frames per second the monitor is driven at, which the retrace timing comes from. VGA
+	// modes would be 70Hz.
+	refresh_rate_hz: f64,
+}
+
+impl VideoMode {
+	/// The size of the displayed image in pixels.
+	pub fn pixel_dims(&self) -> (u32, u32) {
+		self.pixel_dims
+	}
+	
+	/// The size of each character cell in pixels.
+	pub fn char_pixel_dims(&self) -> (u32, u32) {
+		self.char_pixel_dims
+	}
+	
+	/// The number of columns and rows of text.
+	pub fn text_dims(&self) -> (u32, u32) {
+		self.text_dims
+	}
+	
+	/// The mode number int 10h AH=00h sets it with.
+	pub fn mode_index(&self) -> u8 {
+		self.mode_index
+	}
 }
 
 pub const EGA_MODES: [VideoMode; 1] = [
 	VideoMode {
-		mode_index: 3, vga_mode: VGAMode::Text, pixel_dims: (640, 480), text_dims: (80, 25),
+		mode_index: 3, vga_mode: VGAMode::Text, pixel_dims: (640, 350), text_dims: (80, 25),
 		char_pixel_dims: (8, 14), text_address: 0xb8000, text_page_count: 8, text_page_bytes: 0x1000,
+		refresh_rate_hz: 60.,
 	},
 ];
 
+// The reload value the BIOS programs into PIT channel 0, giving the standard 18.2Hz timer tick.
+// Channel 0 divides the 1.19MHz PIT clock by the reload value to produce the timer interrupt
+// (https://wiki.osdev.org/Programmable_Interval_Timer).
+const PIT_STANDARD_RELOAD: u32 = 0x10000;
+// The attributes of the blanks the BIOS clears the screen with when it sets the video mode, and
+// that the teletype scrolls in: grey on black. The blanks themselves are spaces, never NULs, since
+// programs read them back and draw them again, and character 0 isn't blank in every font.
+const DEFAULT_BLANK_ATTRIBUTES: u8 = 0x07;
+// The number of file handles in the table in the PSP.
+const PSP_HANDLE_TABLE_ENTRIES: u16 = 20;
+// Handles for the CLOCK$ device are numbered from here, so they're never ones the file system
+// gives out.
+const CLOCK_DEVICE_FIRST_HANDLE: u16 = 0x80;
+// Duplicates of the console's standard handles are numbered from here, below the CLOCK$ handles.
+const CONSOLE_DUPLICATE_FIRST_HANDLE: u16 = 0x60;
+// The longest line a read from the console takes, not counting the CR, as the 128 byte buffer DOS
+// reads it into has room for.
+const CONSOLE_LINE_MAX_CHARS: usize = 127;
+// How many unfinished file searches are kept before the oldest is dropped. A program that stops
+// searching part way through never says so.
+const MAX_FILE_SEARCHES: usize = 64;
+// A normal FCB, up to the end of its random record number, and the header an extended FCB has
+// before it. http://www.ctyme.com/intr/rb-2574.htm
+const FCB_BYTES: usize = 0x25;
+const EXTENDED_FCB_HEADER_BYTES: usize = 7;
+// An unopened FCB that AH=11h and AH=12h write to the DTA: the drive and the directory entry.
+const FOUND_FCB_BYTES: usize = 33;
+// The BIOS keyboard buffer has 16 slots, but one is always left empty to tell a full buffer from
+// an empty one. http://stanislavs.org/helppc/bios_data_area.html
+const BIOS_KEY_BUFFER_CAPACITY: usize = 15;
+// Timing of the EGA 350 line text mode, which scans 364 lines per frame. The length of a frame
+// comes from the refresh rate, and the scanlines split it evenly.
+// http://www.minuszerodegrees.net/ibm_ega/ibm_ega_switch_settings.htm
+const CRT_FRAME_SCANLINES: u64 = 364;
+// The scanlines after the displayed ones are spent in vertical retrace.
+const CRT_DISPLAYED_SCANLINES: u64 = 350;
+// How much of each scanline is spent drawing before horizontal blanking starts.
+const CRT_DISPLAYED_SCANLINE_FRACTION: f64 = 0.8;
+// Roughly how many PIT clocks pass per emulated instruction, given the runner executes 4000
+// instructions per standard timer tick. Used to make the channel 0 count look like it's moving.
+const PIT_CLOCKS_PER_INSTRUCTION: usize = 16;
+// How often run_headless raises the timer interrupt, matching the runner at the standard rate.
+const HEADLESS_STEPS_PER_TIMER_INTERRUPT: usize = 4000;
+// How often it raises it while fast forwarding through the start of a program.
+const HEADLESS_FAST_FORWARD_STEPS_PER_TIMER_INTERRUPT: usize = 40;
+// The rate the PIT counts at, which the speaker event timestamps are measured in.
+pub const PIT_FREQUENCY_HZ: f64 = 1193181.666;
+// The time between standard 18.2Hz timer ticks.
+pub const STANDARD_TICK_SECONDS: f64 = PIT_STANDARD_RELOAD as f64 / PIT_FREQUENCY_HZ;
+
+// A change to the sound coming out of the PC speaker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeakerEvent {
+	// When the change happened, in PIT clocks since the machine started. Timer interrupts keep
+	// advancing this even while the program is blocked, so it follows emulated time.
+	pub pit_clock: u64,
+	// The frequency of the square wave the speaker plays from this point, or None for silence.
+	pub frequency: Option<f64>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PortStates {
 	port_61: u16,
 	crt_index_register: u16,
-	cga_status_register: u16,
+	// The CRTC start address (registers 0Ch and 0Dh), which is where the displayed screen starts, in
+	// character cells from the start of text memory. Programs flip pages by changing it.
+	crtc_start_address: u16,
 	cga_palette_register: u16,
+	// The attribute controller register that the next data write to port 0x3c0 sets.
+	attribute_controller_index: u8,
+	// Whether the next write to port 0x3c0 is data rather than a register index. Reading port
+	// 0x3da sets it back to the index.
+	attribute_controller_data_next: bool,
+	// PIT channel 0 reload value, where 0 means 0x10000.
+	pit_channel0_reload: u16,
+	// Access mode from the last port 0x43 command for channel 0: 1 = low byte only, 2 = high byte
+	// only, 3 = low byte then high byte.
+	pit_channel0_access_mode: u8,
+	// For access mode 3, whether the next port 0x40 access is for the high byte.
+	pit_channel0_high_byte_next: bool,
+	// The low byte written to port 0x40 while waiting for the high byte in access mode 3.
+	pit_channel0_low_byte: u8,
+	// Count value latched by a port 0x43 latch command, waiting to be read from port 0x40.
+	pit_channel0_latch: Option<u16>,
+	// Instruction count at the last timer interrupt, used to approximate the current count.
+	pit_channel0_reload_instruction: usize,
+	// PIT clocks accumulated by timer interrupts that haven't yet added up to a BIOS tick.
+	pit_bios_tick_clocks: u32,
+	// Total PIT clocks covered by the timer interrupts so far.
+	pit_total_clocks: u64,
+	// PIT channel 2 (speaker) reload value, where 0 means 0x10000. The other fields work the same
+	// as for channel 0.
+	pit_channel2_reload: u16,
+	pit_channel2_access_mode: u8,
+	pit_channel2_high_byte_next: bool,
+	pit_channel2_low_byte: u8,
+	// The speaker frequency given by the last speaker event.
+	speaker_frequency: Option<f64>,
+	// The refresh rate the port 0x3da retrace timing uses instead of the video mode's, if set.
+	refresh_rate_hz: Option<f64>,
+}
+
+impl Default for PortStates {
+	fn default() -> PortStates {
+		PortStates::new()
+	}
 }
 
 impl PortStates {
@@ -78,23 +272,187 @@ impl PortStates {
 		PortStates {
 			port_61: 0,
 			crt_index_register: 0,
-			cga_status_register: 0,
+			crtc_start_address: 0,
 			cga_palette_register: 0,
+			attribute_controller_index: 0,
+			attribute_controller_data_next: false,
+			pit_channel0_reload: 0,
+			pit_channel0_access_mode: 3,
+			pit_channel0_high_byte_next: false,
+			pit_channel0_low_byte: 0,
+			pit_channel0_latch: None,
+			pit_channel0_reload_instruction: 0,
+			pit_bios_tick_clocks: 0,
+			pit_total_clocks: 0,
+			pit_channel2_reload: 0,
+			pit_channel2_access_mode: 3,
+			pit_channel2_high_byte_next: false,
+			pit_channel2_low_byte: 0,
+			speaker_frequency: None,
+			refresh_rate_hz: None,
+		}
+	}
+	
+	fn pit_channel0_reload_clocks(&self) -> u32 {
+		if self.pit_channel0_reload == 0 { PIT_STANDARD_RELOAD } else { self.pit_channel0_reload as u32 }
+	}
+	
+	fn pit_channel2_reload_clocks(&self) -> u32 {
+		if self.pit_channel2_reload == 0 { PIT_STANDARD_RELOAD } else { self.pit_channel2_reload as u32 }
+	}
+}
+
+// Applies a byte written to the data port of a PIT channel to its reload value. Returns true once
+// the reload value is complete, which takes two writes in access mode 3.
+fn write_pit_reload_byte(reload: &mut u16, access_mode: u8, high_byte_next: &mut bool, low_byte: &mut u8, value: u8) -> bool {
+	match access_mode {
+		1 => {
+			*reload = value as u16;
+			true
+		}
+		2 => {
+			*reload = (value as u16) << 8;
+			true
+		}
+		_ => {
+			if *high_byte_next {
+				*reload = *low_byte as u16 + ((value as u16) << 8);
+				*high_byte_next = false;
+				true
+			} else {
+				*low_byte = value;
+				*high_byte_next = true;
+				false
+			}
 		}
 	}
 }
 
+fn get_bios_ticks(machine: &dyn Machine) -> u32 {
+	let timer_low = machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW);
+	let timer_high = machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_HIGH);
+	timer_low as u32 + ((timer_high as u32) << 16)
+}
+
+fn set_bios_ticks(machine: &mut dyn Machine, ticks: u32) {
+	machine.set_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW, (ticks & 0xffff) as u16);
+	machine.set_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_HIGH, ((ticks >> 16) & 0xffff) as u16);
+}
+
+// Adds to the BIOS tick counter at 0040:006Ch. At midnight the counter starts again from 0, and
+// the overflow flag is set for int 1Ah AH=00h to report.
+fn add_bios_ticks(machine: &mut dyn Machine, ticks: u32) {
+	let mut new_timer = get_bios_ticks(machine).wrapping_add(ticks);
+	if new_timer >= BIOS_TICKS_PER_DAY {
+		new_timer %= BIOS_TICKS_PER_DAY;
+		machine.set_data_u8(&BIOS_TIMER_OVERFLOW, 1);
+	}
+	set_bios_ticks(machine, new_timer);
+}
+
+/// The palette registers the EGA BIOS sets at startup, with the border colour last. Colour 6 uses
+/// 0x14 so that it shows as brown rather than dark yellow.
+pub const DEFAULT_EGA_PALETTE: [u8; 17] = [
+	0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+	0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, 0x3f,
+	0x00,
+];
+
+// Where the border colour is in DosEventHandler::ega_palette, after the 16 palette registers.
+const EGA_OVERSCAN_REGISTER: usize = 16;
+
+// Turns a CGA colour, with the intensity in bit 3, into the EGA colour with the same look, which
+// has it in bit 4.
+fn irgb_to_ega_colour(colour: u8) -> u8 {
+	(colour & 0x07) | ((colour & 0x08) << 1)
+}
+
+// Blanks a character cell of text memory: a space, coloured by the attributes.
+fn blank_cell(machine: &mut dyn Machine, char_addr: u32, attributes: u8) {
+	machine.poke_u8(char_addr, b' ');
+	machine.poke_u8(char_addr + 1, attributes);
+}
+
+// Gets the cursor shape the BIOS sets when changing to a video mode with the given font height.
+fn default_cursor_type(char_height: u8) -> u16 {
+	if char_height > 8 {
+		(((char_height - 3) as u16) << 8) + (char_height - 2) as u16
+	} else {
+		(((char_height - 2) as u16) << 8) + (char_height - 1) as u16
+	}
+}
+
+// Programs often set cursor shapes that assume an 8 line font, so EGA/VGA BIOSes scale them to the
+// real font height. This is the same calculation as SeaBIOS does:
+// https://github.com/coreboot/seabios/blob/master/vgasrc/vgabios.c
+fn emulate_cursor_scanlines(start: u8, end: u8, char_height: u8) -> (u8, u8) {
+	let scale = |line: u8| ((line as u32 + 1) * char_height as u32 / 8) as u8;
+	let scaled_start = if end != start + 1 {
+		scale(start) - 1
+	} else {
+		scale(end) - 2
+	};
+	(scaled_start, scale(end) - 1)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct KeyPressInfo {
 	pub scan_code: u8,
 	pub ascii_char: u8,
 }
 
+// The characters typed by the keys on each row of a US keyboard, without and with shift, and the
+// scan code of the row's first key.
+// http://stanislavs.org/helppc/scan_codes.html
+const CHARACTER_ROWS: &[(u8, &[u8], &[u8])] = &[
+	(0x02, b"1234567890-=", b"!@#$%^&*()_+"),
+	(0x10, b"qwertyuiop[]", b"QWERTYUIOP{}"),
+	(0x1e, b"asdfghjkl;'`", b"ASDFGHJKL:\"~"),
+	(0x2b, b"\\zxcvbnm,./", b"|ZXCVBNM<>?"),
+];
+
+impl KeyPressInfo {
+	/// Gets the key press that types a printable character other than space on a US keyboard,
+	/// with shift if it needs it.
+	pub fn from_character(c: u8) -> Option<KeyPressInfo> {
+		CHARACTER_ROWS.iter().find_map(|&(first_scan_code, chars, shifted_chars)| {
+			chars.iter().position(|row_char| *row_char == c)
+				.or_else(|| shifted_chars.iter().position(|row_char| *row_char == c))
+				.map(|key_index| KeyPressInfo { scan_code: first_scan_code + key_index as u8, ascii_char: c })
+		})
+	}
+}
+
+// The variants are in the order of their bits in the BIOS shift status byte, from bit 0 to bit 7
+// (http://stanislavs.org/helppc/int_16-2.html).
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KeyModType {
-	Shift,
+	RightShift,
+	LeftShift,
+	// Either Ctrl key.
 	Ctrl,
+	// Either Alt key.
 	Alt,
+	// The lock and insert modifiers are toggles rather than being held down.
+	ScrollLock,
+	NumLock,
+	CapsLock,
+	Insert,
+}
+
+impl KeyModType {
+	pub fn bit(&self) -> u8 {
+		match self {
+			KeyModType::RightShift => 0b0000_0001,
+			KeyModType::LeftShift => 0b0000_0010,
+			KeyModType::Ctrl => 0b0000_0100,
+			KeyModType::Alt => 0b0000_1000,
+			KeyModType::ScrollLock => 0b0001_0000,
+			KeyModType::NumLock => 0b0010_0000,
+			KeyModType::CapsLock => 0b0100_0000,
+			KeyModType::Insert => 0b1000_0000,
+		}
+	}
 }
 
 #[derive(Debug)]
@@ -102,229 +460,1794 @@ pub struct DosEventHandler {
 	pub machine_type: MachineType,
 	pub video_mode: VideoMode,
 	pub port_states: PortStates,
-	pub file_system: Box<DosFileSystem>,
+	pub file_system: Box<dyn DosFileSystem>,
 	pub disk_trasnsfer_address: u32,
 	pub seconds_since_start: f64,
-	pub key_mod: u8,
+	// The typematic rate and delay, and the commands the program sends to the keyboard.
+	pub keyboard: KeyboardController,
 	pub result: DosInterruptResult,
 	pub key_press_queue: VecDeque<KeyPressInfo>,
+	// Whether cursor shapes set for an 8 line font are scaled to the current font height. Toggled
+	// with int 10h AH=12h BL=34h.
+	pub cursor_emulation: bool,
+	// Changes to the PC speaker that the runner hasn't played yet.
+	pub speaker_events: VecDeque<SpeakerEvent>,
+	// If set, the BIOS and DOS functions the program calls are recorded here.
+	pub function_audit: Option<FunctionAudit>,
+	// The EGA attribute controller's 16 palette registers, followed by the border colour
+	// (overscan) register. Set and read with int 10h AH=10h.
+	pub ega_palette: [u8; 17],
+	// Whether the top bit of an attribute makes the character blink, rather than making the
+	// background bright. Set with int 10h AX=1003h, and back on after a mode set.
+	pub blink_enabled: bool,
+	// What to do when the program calls an int 21h function that doesn't exist.
+	pub unknown_dos_func_action: UnknownFuncAction,
+	// What to do when the program calls an interrupt that has no handler.
+	pub unknown_interrupt_action: UnknownFuncAction,
+	// Where the DOS character output functions write to.
+	pub dos_output_mode: DosOutputMode,
+	// File handles that child PSPs made with int 21h AH=55h don't get a copy of.
+	pub no_inherit: HashSet<u16>,
+	// The date, time and currency formats returned by int 21h AH=38h.
+	pub country_info: CountryInfo,
+	// The major and minor DOS version returned by int 21h AH=30h.
+	pub dos_version: (u8, u8),
+	// Callbacks from the embedding application that run before the built-in interrupt handling.
+	pub interrupt_hooks: InterruptHooks,
+	// The file handles of the files opened with FCBs, by the FCB's address.
+	pub fcb_handles: HashMap<u32, u16>,
+	// What's left of the last line typed for a read from the console, which AH=3Fh returns before
+	// taking more keys.
+	pub con_input: VecDeque<u8>,
+	// The line being typed for a read from the console, until Enter is pressed.
+	pub con_line: Vec<u8>,
+	// Where int 05h (print screen) saves the text on the screen, as there's no printer. The screen
+	// isn't saved anywhere without it.
+	pub print_screen_dir: Option<std::path::PathBuf>,
+	// The disk in floppy drive 0, which the BIOS disk services (int 13h) read and write sectors of.
+	// The drive is empty without it.
+	pub floppy: Option<FloppyImage>,
+	// If set, how the program changes video memory is counted here (see dos_video_stats).
+	pub video_stats: Option<VideoStats>,
+	// If set, the text written with the DOS output functions and int 10h AH=0Eh is kept here, even
+	// after it's scrolled off the screen.
+	pub scrollback: Option<Scrollback>,
+	// The mouse driver (int 33h), which the frontend moves. Without it, programs are told there's
+	// no mouse.
+	pub mouse: Option<MouseDriver>,
+	// The date and time, which int 21h and the CLOCK$ device read and set.
+	pub clock: DosClock,
+	// The handles the program has opened CLOCK$ with. The file system doesn't know about them.
+	pub clock_device_handles: HashSet<u16>,
+	// The standard handles (0 to 4) that int 21h AH=46h made refer to a file, and the file system
+	// handle each of them uses now. The rest are the console.
+	pub redirected_std_handles: HashMap<u16, u16>,
+	// The handles int 21h AH=45h made as duplicates of a standard handle that was the console, and
+	// the standard handle each of them acts as. A program keeps one of these to put stdout back
+	// after redirecting it. The file system doesn't know about them.
+	pub console_duplicate_handles: HashMap<u16, u16>,
+	// The files left to find in each unfinished search, oldest first. A search made with int 21h
+	// AH=4Eh is kept by the DTA address it was made with, and one made with AH=11h by the FCB
+	// address, like DOS keeps the search in the DTA or FCB, so a program can have a search going in
+	// each of several DTAs at once.
+	pub file_searches: Vec<(u32, VecDeque<[u8; DOS_FIND_DATA_BYTES]>)>,
+	// The ranges of files locked with int 21h AH=5Ch.
+	pub file_locks: FileLockTable,
+	// How many times the program has read or checked for a key, through the BIOS or DOS.
+	pub keyboard_polls: u64,
+	// If set, the interrupt vectors and BIOS data are checked once a frame for programs writing
+	// over them by mistake (see dos_low_memory_guard).
+	pub low_memory_guard: Option<LowMemoryGuard>,
+	// If set, every change the program makes to the interrupt vectors is reported once a frame.
+	pub vector_watch: Option<VectorWatch>,
 }
 
 impl DosEventHandler {
-	pub fn init_machine(&mut self, machine: &mut Machine8086) {
+	/// Makes a handler with the settings in the options, and sets up the BIOS data for its video
+	/// mode in the machine. The program should already be loaded, since loading it sets up the rest
+	/// of the BIOS data.
+	pub fn new(machine: &mut dyn Machine, file_system: Box<dyn DosFileSystem>, options: &EmulatorOptions) -> Result<DosEventHandler, EmulatorError> {
+		options.validate()?;
+		let floppy = open_floppy(&options.dos)?;
+		Ok(DosEventHandler::with_floppy(machine, file_system, options, floppy))
+	}
+	
+	// Makes a handler from options that have been checked, with the floppy image they give already
+	// opened. Nothing can fail once the file system has been handed over, which Emulator::restart
+	// relies on so that a failed restart doesn't lose the file system.
+	pub(crate) fn with_floppy(machine: &mut dyn Machine, file_system: Box<dyn DosFileSystem>, options: &EmulatorOptions, floppy: Option<FloppyImage>) -> DosEventHandler {
+		let video = &options.video;
+		let dos = &options.dos;
+		let mut handler = DosEventHandler {
+			machine_type: video.machine_type,
+			video_mode: video.machine_type.lookup_video_mode(video.mode).expect("The video mode was checked"),
+			port_states: PortStates::new(),
+			file_system,
+			disk_trasnsfer_address: 0,
+			seconds_since_start: 0.,
+			keyboard: KeyboardController::new(),
+			result: DosInterruptResult::ShouldReturn,
+			key_press_queue: VecDeque::new(),
+			cursor_emulation: video.cursor_emulation,
+			speaker_events: VecDeque::new(),
+			function_audit: if dos.audit { Some(FunctionAudit::new()) } else { None },
+			ega_palette: DEFAULT_EGA_PALETTE,
+			blink_enabled: video.blink_enabled,
+			unknown_dos_func_action: dos.unknown_function_action,
+			unknown_interrupt_action: dos.unknown_interrupt_action,
+			dos_output_mode: dos.output_mode,
+			no_inherit: HashSet::new(),
+			country_info: CountryInfo::for_country_code(dos.country_code).expect("The country code was checked"),
+			dos_version: dos.dos_version,
+			interrupt_hooks: InterruptHooks::new(),
+			fcb_handles: HashMap::new(),
+			con_input: VecDeque::new(),
+			con_line: vec![],
+			print_screen_dir: video.print_screen_dir.clone(),
+			floppy,
+			video_stats: None,
+			scrollback: dos.scrollback_lines.map(Scrollback::new),
+			mouse: if options.input.mouse { Some(MouseDriver::new()) } else { None },
+			clock: DosClock::new(),
+			clock_device_handles: HashSet::new(),
+			redirected_std_handles: HashMap::new(),
+			console_duplicate_handles: HashMap::new(),
+			file_searches: vec![],
+			file_locks: FileLockTable::new(),
+			keyboard_polls: 0,
+			low_memory_guard: None,
+			vector_watch: None,
+		};
+		handler.set_refresh_rate_hz(video.refresh_rate_hz);
+		handler.init_machine(machine);
+		// The BIOS sets the video mode when the PC starts, so the screen starts out blank rather
+		// than full of NULs.
+		handler.clear_text_pages(machine);
+		if dos.low_memory_protection != LowMemoryProtection::Off {
+			handler.low_memory_guard = Some(LowMemoryGuard::new(machine, dos.low_memory_protection));
+		}
+		if dos.trace_vectors {
+			handler.vector_watch = Some(VectorWatch::new(machine));
+		}
+		if video.stats {
+			handler.video_stats = Some(VideoStats::new(machine));
+		}
+		handler
+	}
+
+	pub fn init_machine(&mut self, machine: &mut dyn Machine) {
 		//self.set_video_mode(3);
 		machine.set_data_u8(&BIOS_VIDEO_MODE_INDEX, self.video_mode.mode_index);
 		machine.set_data_u16(&BIOS_TEXT_COLUMN_COUNT, self.video_mode.text_dims.0 as u16);
 		machine.set_data_u16(&BIOS_TEXT_PAGE_BYTES, self.video_mode.text_page_bytes as u16);
-		machine.set_data_u16(&BIOS_VIDEO_IO_PORT_ADDRESS, 0x3d4 as u16);
-		machine.set_data_u16(&BIOS_TEXT_ROW_COUNT, self.video_mode.text_dims.1 as u16);
+		machine.set_data_u16(&BIOS_VIDEO_IO_PORT_ADDRESS, 0x3d4);
+		machine.set_data_u8(&BIOS_TEXT_LAST_ROW, (self.video_mode.text_dims.1 - 1) as u8);
 		machine.set_data_u16(&BIOS_CHAR_HEIGHT, self.video_mode.char_pixel_dims.1 as u16);
+		machine.set_data_u16(&BIOS_CURSOR_TYPE, default_cursor_type(self.video_mode.char_pixel_dims.1 as u8));
+		if let Some(ref mut low_memory_guard) = self.low_memory_guard {
+			low_memory_guard.accept_bios_data(machine);
+		}
 	}
 
-	/*fn set_video_mode(&mut self, machine: &mut Machine8086, mode_index: u8) {
-		self.video_mode = self.lookup_video_mode(mode_index).unwrap();
-		
-	}*/
+	fn set_video_mode(&mut self, machine: &mut dyn Machine, mode_index: u8, clear_screen: bool) -> Result<(), String> {
+		self.video_mode = self.machine_type.lookup_video_mode(mode_index)?;
+		self.init_machine(machine);
+		machine.set_data_u8(&BIOS_ACTIVE_VIDEO_PAGE, 0);
+		machine.set_data_u16(&BIOS_ACTIVE_PAGE_OFFSET, 0);
+		self.port_states.crtc_start_address = 0;
+		self.ega_palette = DEFAULT_EGA_PALETTE;
+		self.blink_enabled = true;
+		for cursor_position in &BIOS_CURSOR_POSITION {
+			machine.set_data_u16(cursor_position, 0);
+		}
+		if clear_screen {
+			self.clear_text_pages(machine);
+		}
+		Ok(())
+	}
+	
+	// Fills every page of the video mode with blanks, like setting the mode does.
+	fn clear_text_pages(&self, machine: &mut dyn Machine) {
+		let text_bytes = self.video_mode.text_page_count * self.video_mode.text_page_bytes;
+		for char_addr in (self.video_mode.text_address .. self.video_mode.text_address + text_bytes).step_by(2) {
+			blank_cell(machine, char_addr, DEFAULT_BLANK_ATTRIBUTES);
+		}
+	}
+	
+	pub fn file_system(&self) -> &dyn DosFileSystem {
+		&*self.file_system
+	}
+	
+	pub fn file_system_mut(&mut self) -> &mut dyn DosFileSystem {
+		&mut *self.file_system
+	}
+	
+	/// Swaps in a different file system (eg. to change disks), returning the old one. Handles
+	/// opened by the program belong to the old file system and won't be valid in the new one.
+	pub fn replace_file_system(&mut self, file_system: Box<dyn DosFileSystem>) -> Box<dyn DosFileSystem> {
+		std::mem::replace(&mut self.file_system, file_system)
+	}
+	
+	/// Makes files saved, renamed or removed on the host since the program started show up the way
+	/// they are now, such as when editing the program's data files while it runs.
+	pub fn refresh_file_system(&mut self) {
+		self.file_system.refresh();
+	}
+	
+	/// Gets the host directory the current file system is mounted from, if it has one.
+	pub fn file_system_root_path(&self) -> Option<&std::path::Path> {
+		self.file_system.root_path()
+	}
+	
+	/// Gets the position of the cursor on the active video page as (column, row).
+	pub fn get_cursor_position(&self, machine: &dyn Machine) -> (u8, u8) {
+		let video_page = self.get_active_video_page(machine);
+		let cursor_pos_data = machine.get_data_u16(&BIOS_CURSOR_POSITION[video_page as usize]);
+		((cursor_pos_data & 0xff) as u8, (cursor_pos_data >> 8) as u8)
+	}
+	
+	/// Gets the first and last scanline of each character cell that the cursor should be drawn
+	/// over, or None if the cursor is hidden.
+	pub fn get_cursor_scanlines(&self, machine: &dyn Machine) -> Option<(u8, u8)> {
+		let cursor_type = machine.get_data_u16(&BIOS_CURSOR_TYPE);
+		let start = (cursor_type >> 8) as u8;
+		let end = (cursor_type & 0xff) as u8;
+		// Bit 5 of the start line hides the cursor, and so does a start line after the end line.
+		if start & 0x20 != 0 || (start & 0x1f) > (end & 0x1f) {
+			return None;
+		}
+		let start = start & 0x1f;
+		let end = end & 0x1f;
+		let char_height = self.video_mode.char_pixel_dims.1 as u8;
+		let (start, end) = if self.cursor_emulation && char_height > 8 && end < 8 {
+			emulate_cursor_scanlines(start, end, char_height)
+		} else {
+			(start, end)
+		};
+		Some((start.min(char_height - 1), end.min(char_height - 1)))
+	}
+	
+	/// Whether a shift key is held or a lock is on, from the shift status byte in the BIOS data,
+	/// which programs can change themselves.
+	pub fn get_key_mod(&self, machine: &dyn Machine, mod_type: KeyModType) -> bool {
+		machine.get_data_u8(&BIOS_KEYBOARD_FLAGS) & mod_type.bit() != 0
+	}
 	
-	pub fn set_key_mod(&mut self, mod_type: KeyModType, on: bool) {
-		let bit = match mod_type {
-			KeyModType::Shift => 0b0001,
-			KeyModType::Ctrl => 0b0100,
-			KeyModType::Alt => 0b1000,
+	/// Sets a bit of the shift status byte in the BIOS data. Ctrl and Alt also set their bits of
+	/// the extended byte, as the left keys, since which one is held isn't known.
+	pub fn set_key_mod(&mut self, machine: &mut dyn Machine, mod_type: KeyModType, on: bool) {
+		let set_bit = |flags: u8, bit: u8| if on { flags | bit } else { flags & !bit };
+		let flags = machine.get_data_u8(&BIOS_KEYBOARD_FLAGS);
+		machine.set_data_u8(&BIOS_KEYBOARD_FLAGS, set_bit(flags, mod_type.bit()));
+		let extended_bit = match mod_type {
+			KeyModType::Ctrl => 0b01,
+			KeyModType::Alt => 0b10,
+			_ => return,
 		};
-		if on {
-			self.key_mod |= bit;
+		let flags_2 = machine.get_data_u8(&BIOS_KEYBOARD_FLAGS_2);
+		machine.set_data_u8(&BIOS_KEYBOARD_FLAGS_2, set_bit(flags_2, extended_bit));
+	}
+	
+	/// The LEDs that show the lock toggles in the shift status byte, which is also what the
+	/// program sets with keyboard command EDh.
+	pub fn keyboard_leds(&self, machine: &dyn Machine) -> KeyboardLeds {
+		KeyboardLeds::from_key_mod(machine.get_data_u8(&BIOS_KEYBOARD_FLAGS))
+	}
+	
+	fn read_cga_status_register(&mut self, machine: &dyn Machine) -> u16 {
+		// http://www.techhelpmanual.com/901-color_graphics_adapter_i_o_ports.html
+		// The beam position comes from emulated time, so programs polling for retrace see it come
+		// and go at a plausible rate however fast the emulator runs.
+		let frame_position = self.get_pit_clock(machine) as f64 / self.frame_pit_clocks();
+		let scanline_position = frame_position.fract() * CRT_FRAME_SCANLINES as f64;
+		let vertical_retrace = scanline_position as u64 >= CRT_DISPLAYED_SCANLINES;
+		let horizontal_blanking = scanline_position.fract() >= CRT_DISPLAYED_SCANLINE_FRACTION;
+		// Bit 0 is set whenever the display isn't being drawn, and bit 3 during vertical retrace.
+		let mut status = 0;
+		if horizontal_blanking || vertical_retrace {
+			status |= 0b1;
+		}
+		if vertical_retrace {
+			status |= 0b1000;
+		}
+		status
+	}
+
+	/// The colour of the border around the screen, from the attribute controller's overscan
+	/// register. It's a 6 bit EGA colour, like the palette registers.
+	pub fn border_colour(&self) -> u8 {
+		self.ega_palette[EGA_OVERSCAN_REGISTER]
+	}
+	
+	fn set_border_colour(&mut self, colour: u8) {
+		self.ega_palette[EGA_OVERSCAN_REGISTER] = colour & 0x3f;
+	}
+	
+	fn read_attribute_controller(&self, index: u8) -> u8 {
+		match index as usize {
+			index if index < EGA_OVERSCAN_REGISTER => self.ega_palette[index],
+			0x11 => self.border_colour(),
+			_ => 0,
+		}
+	}
+	
+	fn write_attribute_controller(&mut self, index: u8, value: u8) {
+		match index as usize {
+			index if index < EGA_OVERSCAN_REGISTER => self.ega_palette[index] = value & 0x3f,
+			0x11 => self.set_border_colour(value),
+			_ => {}
+		}
+	}
+
+	/// The number of frames per second the display is refreshed at, which the vertical retrace seen
+	/// through port 0x3da follows.
+	pub fn refresh_rate_hz(&self) -> f64 {
+		self.port_states.refresh_rate_hz.unwrap_or(self.video_mode.refresh_rate_hz)
+	}
+	
+	/// Overrides the video mode's refresh rate, or goes back to it with None.
+	pub fn set_refresh_rate_hz(&mut self, refresh_rate_hz: Option<f64>) {
+		self.port_states.refresh_rate_hz = refresh_rate_hz;
+	}
+	
+	fn frame_pit_clocks(&self) -> f64 {
+		PIT_FREQUENCY_HZ / self.refresh_rate_hz()
+	}
+	
+	/// The number of vertical retraces that have started since the machine started, going by
+	/// emulated time and the current refresh rate.
+	pub fn vertical_retrace_count(&self, machine: &dyn Machine) -> u64 {
+		let retrace_start = CRT_DISPLAYED_SCANLINES as f64 / CRT_FRAME_SCANLINES as f64;
+		(self.get_pit_clock(machine) as f64 / self.frame_pit_clocks() + 1. - retrace_start) as u64
+	}
+	
+	/// The number of timer interrupts (0x08) that should be raised in the time of one standard
+	/// 18.2Hz BIOS tick, based on the reload value programmed into PIT channel 0.
+	pub fn timer_interrupts_per_standard_tick(&self) -> f64 {
+		PIT_STANDARD_RELOAD as f64 / self.port_states.pit_channel0_reload_clocks() as f64
+	}
+	
+	/// Moves the emulated clock forward by a number of standard ticks without running the timer
+	/// interrupts for them, eg. to catch up on time spent paused.
+	pub fn skip_standard_ticks(&mut self, machine: &mut dyn Machine, ticks: u64) {
+		self.seconds_since_start += ticks as f64 * STANDARD_TICK_SECONDS;
+		self.port_states.pit_total_clocks += ticks * PIT_STANDARD_RELOAD as u64;
+		add_bios_ticks(machine, ticks as u32);
+	}
+	
+	fn get_pit_channel0_count(&self, machine: &dyn Machine) -> u16 {
+		let reload = self.port_states.pit_channel0_reload_clocks() as usize;
+		let elapsed_instructions = machine.number_of_parsed_instructions().wrapping_sub(self.port_states.pit_channel0_reload_instruction);
+		let elapsed_clocks = elapsed_instructions.wrapping_mul(PIT_CLOCKS_PER_INSTRUCTION) % reload;
+		// The count register is 16 bits, so a full 0x10000 reload reads as 0.
+		((reload - elapsed_clocks) & 0xffff) as u16
+	}
+	
+	fn write_pit_command(&mut self, machine: &dyn Machine, value: u8) {
+		// http://stanislavs.org/helppc/8253.html
+		let channel = value >> 6;
+		let access_mode = (value >> 4) & 0b11;
+		if channel == 0 {
+			if access_mode == 0 {
+				// Latch the current count so it can be read without it changing between bytes.
+				self.port_states.pit_channel0_latch = Some(self.get_pit_channel0_count(machine));
+			} else {
+				self.port_states.pit_channel0_access_mode = access_mode;
+			}
+			self.port_states.pit_channel0_high_byte_next = false;
+		} else if channel == 2 {
+			// Latching channel 2 isn't supported, since nothing reads it back.
+			if access_mode != 0 {
+				self.port_states.pit_channel2_access_mode = access_mode;
+			}
+			self.port_states.pit_channel2_high_byte_next = false;
 		} else {
-			self.key_mod &= !bit;
+			// Channel 1 refreshes the DRAM, and channel 3 is the 8254's read-back command.
+			report_unknown_function(self.unknown_interrupt_action, &format!("Unhandled PIT command: 0x{:x}", value));
 		}
 	}
 	
-	pub fn set_cga_vertial_retrace(&mut self, vertical_retrace: bool) {
-		if vertical_retrace {
-			self.port_states.cga_status_register |= 0b1000u16;
+	fn write_pit_channel0_data(&mut self, value: u8) {
+		let port_states = &mut self.port_states;
+		write_pit_reload_byte(&mut port_states.pit_channel0_reload, port_states.pit_channel0_access_mode, &mut port_states.pit_channel0_high_byte_next, &mut port_states.pit_channel0_low_byte, value);
+	}
+	
+	fn write_pit_channel2_data(&mut self, machine: &dyn Machine, value: u8) {
+		let port_states = &mut self.port_states;
+		if write_pit_reload_byte(&mut port_states.pit_channel2_reload, port_states.pit_channel2_access_mode, &mut port_states.pit_channel2_high_byte_next, &mut port_states.pit_channel2_low_byte, value) {
+			self.update_speaker(machine);
+		}
+	}
+	
+	/// The current emulated time in PIT clocks since the machine started, based on the timer
+	/// interrupts so far and the instructions run since the last one.
+	pub fn get_pit_clock(&self, machine: &dyn Machine) -> u64 {
+		let reload = self.port_states.pit_channel0_reload_clocks() as usize;
+		let elapsed_instructions = machine.number_of_parsed_instructions().wrapping_sub(self.port_states.pit_channel0_reload_instruction);
+		// Never go past the next timer interrupt, so the clock doesn't go backwards when it happens.
+		let elapsed_clocks = elapsed_instructions.saturating_mul(PIT_CLOCKS_PER_INSTRUCTION).min(reload);
+		self.port_states.pit_total_clocks + elapsed_clocks as u64
+	}
+	
+	// Adds a speaker event if the sound the speaker should be making has changed. The speaker plays
+	// the PIT channel 2 square wave when bits 0 (timer gate) and 1 (speaker data) of port 0x61 are
+	// set (http://www.techhelpmanual.com/889-ppi_port_61h.html).
+	fn update_speaker(&mut self, machine: &dyn Machine) {
+		let frequency = if self.port_states.port_61 & 0b11 == 0b11 {
+			Some(PIT_FREQUENCY_HZ / self.port_states.pit_channel2_reload_clocks() as f64)
 		} else {
-			self.port_states.cga_status_register &= !0b1000u16;
-			// Toggle the first bit.
-			self.port_states.cga_status_register ^= 0b1u16;
+			None
+		};
+		if frequency != self.port_states.speaker_frequency {
+			self.port_states.speaker_frequency = frequency;
+			self.speaker_events.push_back(SpeakerEvent {
+				pit_clock: self.get_pit_clock(machine),
+				frequency,
+			});
+		}
+	}
+	
+	fn read_pit_channel0_data(&mut self, machine: &dyn Machine) -> u8 {
+		let count = match self.port_states.pit_channel0_latch {
+			Some(latch) => latch,
+			None => self.get_pit_channel0_count(machine),
+		};
+		let (value, finished) = match self.port_states.pit_channel0_access_mode {
+			1 => (count & 0xff, true),
+			2 => (count >> 8, true),
+			_ => {
+				if self.port_states.pit_channel0_high_byte_next {
+					self.port_states.pit_channel0_high_byte_next = false;
+					(count >> 8, true)
+				} else {
+					self.port_states.pit_channel0_high_byte_next = true;
+					(count & 0xff, false)
+				}
+			}
+		};
+		if finished {
+			self.port_states.pit_channel0_latch = None;
 		}
+		value as u8
 	}
 
-	fn get_page_origin_address(&self, machine: &Machine8086, video_page: u8) -> u32 {
+	// The active page from the BIOS data area. Programs can poke any value there, so pages past the
+	// last one with a cursor position are treated as the last page, like int 10h AH=08h does.
+	fn get_active_video_page(&self, machine: &dyn Machine) -> u8 {
+		machine.get_data_u8(&BIOS_ACTIVE_VIDEO_PAGE).min(BIOS_CURSOR_POSITION.len() as u8 - 1)
+	}
+	
+	fn get_page_origin_address(&self, machine: &dyn Machine, video_page: u8) -> u32 {
 		let page_bytes = machine.get_data_u16(&BIOS_TEXT_PAGE_BYTES);
 		self.video_mode.text_address + (video_page as u32 * page_bytes as u32)
 	}
 	
-	fn get_screen_character_address(&self, machine: &Machine8086, page_origin_address: u32, x: u8, y: u8) -> u32 {
+	// The BIOS keeps the number of the last row, so the count is one more.
+	fn get_text_row_count(&self, machine: &dyn Machine) -> u8 {
+		machine.get_data_u8(&BIOS_TEXT_LAST_ROW).saturating_add(1)
+	}
+	
+	fn get_screen_character_address(&self, machine: &dyn Machine, page_origin_address: u32, x: u8, y: u8) -> u32 {
 		let bytes_per_char = 2;
 		let column_count = machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT);
 		page_origin_address + (((y as u32 * column_count as u32) + x as u32) * bytes_per_char)
 	}
 	
-	fn handle_interrupt_10h(&mut self, machine: &mut Machine8086) {
-		// Video (http://www.ctyme.com/intr/int-10.htm)
-		let video_int = machine.get_reg_u8(Reg::AX, RegHalf::High);
-		//println!("Video interrupt: 0x{:x}", video_int);
-		match video_int {
-			0x00 => {
-				// TODO: Set video mode.
-			}
-			0x01 => {
-				// TODO: Set text-mode cursor shape.
-			}
-			0x02 => {
-				// Set cursor position.
-				let bh = machine.get_reg_u8(Reg::BX, RegHalf::High);
-				let video_page = if bh == 0xff { machine.get_data_u8(&BIOS_ACTIVE_VIDEO_PAGE) } else { bh };
-				
-				let dh = machine.get_reg_u8(Reg::DX, RegHalf::High);
-				let dl = machine.get_reg_u8(Reg::DX, RegHalf::Low);
-				let cursor_pos_data = ((dh as u16) << 8) + dl as u16;
-				machine.set_data_u16(&BIOS_CURSOR_POSITION[video_page as usize], cursor_pos_data);
-			}
-			0x03 => {
-				// TODO: Get cursor position and size.
-			}
-			0x06 => {
-				// Scroll the text up within a rectangular area on the active page.
-				let video_page = machine.get_data_u8(&BIOS_ACTIVE_VIDEO_PAGE);
-				let num_lines = machine.get_reg_u8(Reg::AX, RegHalf::Low);
-				let blank_char_attributes = machine.get_reg_u8(Reg::BX, RegHalf::High);
-				let rect_top = machine.get_reg_u8(Reg::CX, RegHalf::High);
-				let rect_left = machine.get_reg_u8(Reg::CX, RegHalf::Low);
-				let rect_bottom = machine.get_reg_u8(Reg::DX, RegHalf::High);
-				let rect_right = machine.get_reg_u8(Reg::DX, RegHalf::Low);
-				let page_addr = self.get_page_origin_address(machine, video_page);
-				
-				if num_lines == 0 {
-					// Clear the window.
-					for y in rect_top ..= rect_bottom {
-						for x in rect_left ..= rect_right {
-							let char_addr = self.get_screen_character_address(machine, page_addr, x, y);
-							machine.poke_u8(char_addr, 0);
-							machine.poke_u8(char_addr + 1, blank_char_attributes);
-						}
-					}
+	// Scrolls a rectangle of the active page (inclusive of the bottom right corner) by num_lines,
+	// filling the new lines with blanks coloured by blank_char_attributes.
+	fn scroll_window(&mut self, machine: &mut dyn Machine, scroll_up: bool, num_lines: u8, blank_char_attributes: u8, (rect_left, rect_top): (u8, u8), (rect_right, rect_bottom): (u8, u8)) {
+		let video_page = self.get_active_video_page(machine);
+		let column_count = machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT);
+		let row_count = self.get_text_row_count(machine) as u16;
+		// Like a real BIOS, keep the rectangle on the screen.
+		let rect_bottom = rect_bottom.min((row_count.max(1) - 1).min(0xff) as u8);
+		let rect_right = rect_right.min((column_count.max(1) - 1).min(0xff) as u8);
+		if rect_top > rect_bottom || rect_left > rect_right {
+			return;
+		}
+		let page_addr = self.get_page_origin_address(machine, video_page);
+		
+		let window_height = (rect_bottom - rect_top) as u16 + 1;
+		// Scrolling by 0 lines, or by more lines than the window has, clears the window.
+		let num_lines = if num_lines == 0 || num_lines as u16 > window_height { window_height } else { num_lines as u16 };
+		// Go through the rows in the order that doesn't overwrite rows that haven't moved yet.
+		for row in 0 .. window_height {
+			let y = if scroll_up { rect_top + row as u8 } else { rect_bottom - row as u8 };
+			for x in rect_left ..= rect_right {
+				let to_addr = self.get_screen_character_address(machine, page_addr, x, y);
+				if row + num_lines < window_height {
+					let from_y = if scroll_up { y + num_lines as u8 } else { y - num_lines as u8 };
+					let from_addr = self.get_screen_character_address(machine, page_addr, x, from_y);
+					let char_data = machine.peek_u16(from_addr);
+					machine.poke_u16(to_addr, char_data);
 				} else {
-					for y in rect_top ..= (rect_bottom - num_lines) {
-						for x in rect_left ..= rect_right {
-							let from_addr = self.get_screen_character_address(machine, page_addr, x, y + 1);
-							let to_addr = self.get_screen_character_address(machine, page_addr, x, y);
-							let char_data = machine.peek_u16(from_addr);
-							machine.poke_u16(to_addr, char_data);
-						}
-					}
-					for y in (rect_bottom - num_lines + 1) ..= rect_bottom {
-						for x in rect_left ..= rect_right {
-							let char_addr = self.get_screen_character_address(machine, page_addr, x, y);
-							machine.poke_u8(char_addr, 0);
-							machine.poke_u8(char_addr + 1, blank_char_attributes);
-						}
-					}
+					blank_cell(machine, to_addr, blank_char_attributes);
 				}
 			}
+		}
+	}
+	
+	/// Writes a character at the cursor on the active page and moves the cursor, like a terminal.
+	/// Control characters move the cursor instead, and the screen scrolls up when the cursor goes
+	/// past the bottom (http://www.ctyme.com/intr/rb-0106.htm).
+	pub fn write_tty_char(&mut self, machine: &mut dyn Machine, c: u8) {
+		let video_page = self.get_active_video_page(machine);
+		let column_count = machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT).clamp(1, 0xff) as u8;
+		let row_count = self.get_text_row_count(machine).max(1);
+		// Int 10h AH=02h can put the cursor anywhere, so it's brought back onto the screen first.
+		let (cursor_x, cursor_y) = self.get_cursor_position(machine);
+		let mut cursor_x = cursor_x.min(column_count - 1);
+		let mut cursor_y = cursor_y.min(row_count - 1);
+		match c {
+			0x07 => {
+				// TODO: Beep
+			}
 			0x08 => {
-				// Read char and attributes at cursor position
-				// TODO
-				/*let bh = machine.get_reg_u8(Reg::BX, RegHalf::High);
-				let bl = machine.get_reg_u8(Reg::BX, RegHalf::Low);
-				let video_page = if bh == 0xff { machine.get_data_u8(&BIOS_ACTIVE_VIDEO_PAGE) } else { bh };
-				let (cursor_x, cursor_y) = split_u16_high_low(machine.get_data_u16(&BIOS_CURSOR_POSITION[video_page as usize]));
-				let page_bytes = machine.get_data_u16(&BIOS_TEXT_PAGE_BYTES);
-				let column_count = machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT);
-				let bytes_per_char = 2;
-				let addr = self.video_mode.text_address + (video_page as u32 * page_bytes as u32) + (((cursor_y as u32 * column_count as u32) + cursor_x as u32) * bytes_per_char);
-				dbg!((addr, video_page, column_count, cursor_x, cursor_y, bl, bh));
-				let char_colour_attrs = machine.peek_u16(addr);
-				machine.set_reg_u16(Reg::AX, char_colour_attrs);*/
-				
-				// Copying ZETA to test that it actually works:
-				let bh = machine.get_reg_u8(Reg::BX, RegHalf::High);
-				let bl = machine.get_reg_u8(Reg::BX, RegHalf::Low);
-				let addr = self.video_mode.text_address + (((bl as u32 * 80 as u32) + bh as u32) * 2);
-				machine.set_reg_u8(Reg::AX, RegHalf::Low, machine.peek_u8(addr));
-				machine.set_reg_u8(Reg::BX, RegHalf::High, machine.peek_u8(addr + 1));
+				cursor_x = cursor_x.saturating_sub(1);
 			}
-			0x0f => {
-				// Get current video mode
-				let text_column_count = machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT);
-				machine.set_reg_u8(Reg::AX, RegHalf::High, text_column_count as u8);
-				// Video modes covered in: http://www.ctyme.com/intr/rb-0069.htm
-				// 3 is the 80x25 colour mode
-				machine.set_reg_u8(Reg::AX, RegHalf::Low, 3);
-				// Active display page (http://www.ctyme.com/intr/rb-0091.htm)
-				machine.set_reg_u8(Reg::BX, RegHalf::High, machine.get_data_u8(&BIOS_ACTIVE_VIDEO_PAGE));
+			b'\n' => {
+				cursor_y = cursor_y.saturating_add(1);
 			}
-			0x11 => {
-				let func11 = machine.get_reg_u8(Reg::AX, RegHalf::Low);
-				match func11 {
-					0x30 => {
-						// TODO: Get font information
-						
-						// Copying ZETA:
-						machine.set_flag(Flag::Carry, true);
-					}
-					_ => panic!("Unknown video 0x11 func: 0x{:x}", func11)
-				}
+			b'\r' => {
+				cursor_x = 0;
 			}
-			0x12 => {
-				// Alternate function select
-				let func12 = machine.get_reg_u8(Reg::BX, RegHalf::Low);
-				match func12 {
-					0x30 => {
-						// TODO: Select vertical resolution.
-					}
-					_ => panic!("Unknown video 0x12 func: 0x{:x}", func12)
+			_ => {
+				// The character keeps the colour of the cell it's written over.
+				let page_addr = self.get_page_origin_address(machine, video_page);
+				let char_addr = self.get_screen_character_address(machine, page_addr, cursor_x, cursor_y);
+				machine.poke_u8(char_addr, c);
+				cursor_x = cursor_x.saturating_add(1);
+				if cursor_x >= column_count {
+					cursor_x = 0;
+					cursor_y = cursor_y.saturating_add(1);
 				}
 			}
-			_ => panic!("Unknown video func: 0x{:x}", video_int)
 		}
+		if cursor_y >= row_count {
+			cursor_y = row_count - 1;
+			self.scroll_window(machine, true, 1, DEFAULT_BLANK_ATTRIBUTES, (0, 0), (column_count - 1, row_count - 1));
+		}
+		machine.set_data_u16(&BIOS_CURSOR_POSITION[video_page as usize], ((cursor_y as u16) << 8) + cursor_x as u16);
 	}
-}
-
-impl EventHandler for DosEventHandler {
-	fn handle_interrupt(&mut self, machine: &mut Machine8086, interrupt_index: u8) {
-		// https://www.shsu.edu/~csc_tjm/spring2001/cs272/interrupt.html
-		//println!("Handle interrupt: 0x{:x}", interrupt_index);
-		self.result = DosInterruptResult::ShouldReturn;
-		
-		match interrupt_index {
-			// BIOS Interrupts (0x00-0x1F):
-			0x02 => {
-				// Non-maskable interrupt
-				panic!("Memory corruption error, apparently...");
+	
+	/// The text being displayed as Unicode, with a line for each row and no spaces at the ends of
+	/// the lines.
+	pub fn get_screen_unicode_text(&self, machine: &dyn Machine) -> String {
+		let mut text = String::new();
+		for row in self.get_screen_text(machine) {
+			text += cp437_to_string(&row).trim_end_matches([' ', '\u{a0}']);
+			text.push('\n');
+		}
+		text
+	}
+	
+	// Int 05h saves the screen's text to a file in print_screen_dir, named by the time, since
+	// there's no printer to send it to. The BIOS print screen status byte says whether it worked.
+	// http://www.ctyme.com/intr/rb-0066.htm
+	fn print_screen(&mut self, machine: &mut dyn Machine) {
+		let print_screen_dir = match self.print_screen_dir {
+			Some(ref print_screen_dir) => print_screen_dir,
+			None => return,
+		};
+		let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_millis()).unwrap_or(0);
+		let path = print_screen_dir.join(format!("pseudos-screen-{}.txt", time_ms));
+		match std::fs::write(&path, self.get_screen_unicode_text(machine)) {
+			Ok(()) => {
+				eprintln!("Saved the screen's text to {}", path.display());
+				machine.set_data_u8(&BIOS_PRINT_SCREEN_STATUS, 0);
 			}
-			0x04 => {
-				// Overflow
-				panic!("Overflow");
+			Err(err) => {
+				eprintln!("Couldn't save the screen's text to {}: {}", path.display(), err);
+				machine.set_data_u8(&BIOS_PRINT_SCREEN_STATUS, 0xff);
 			}
-			0x08 => {
-				// Timer interrupt. This is supposed to be injected by an external source exactly
-				// 18.2 times per second.
-				// TODO 777497
-				let timer_low = machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW);
-				let timer_high = machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_HIGH);
-				let timer = timer_low as u32 + ((timer_high as u32) << 16);
-				let new_timer = timer.wrapping_add(1);
-				//println!("Time: {}", new_timer);
-				let new_timer_low = (new_timer & 0xffff) as u16;
-				let new_timer_high = ((new_timer >> 16) & 0xffff) as u16;
-				machine.set_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW, new_timer_low);
-				machine.set_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_HIGH, new_timer_high);
-				// Emit user timer tick.
-				machine.interrupt_on_next_step(0x1c);
+		}
+	}
+	
+	// The handle to give the file system for a handle the program passed, which is only different
+	// for a standard handle that's been made to refer to a file, and for a duplicate of the
+	// console, which is the standard handle it acts as.
+	fn file_handle(&self, handle: u16) -> u16 {
+		if let Some(&std_handle) = self.console_duplicate_handles.get(&handle) {
+			return std_handle;
+		}
+		self.redirected_std_handles.get(&handle).copied().unwrap_or(handle)
+	}
+	
+	// Finds all the files matching a search at once, so the search doesn't depend on the file
+	// system's own search, which there is only one of. Error code if nothing matches.
+	fn find_all_files(&mut self, attributes: u16, search_spec: &[u8]) -> Result<VecDeque<[u8; DOS_FIND_DATA_BYTES]>, DosErrorCode> {
+		let mut found_files = VecDeque::new();
+		let mut find_data = [0; DOS_FIND_DATA_BYTES];
+		self.file_system.find_first_file(&mut find_data, attributes, search_spec)?;
+		found_files.push_back(find_data);
+		while self.file_system.find_next_file(&mut find_data).is_ok() {
+			found_files.push_back(find_data);
+		}
+		Ok(found_files)
+	}
+	
+	// Keeps the files a search found under the DTA or FCB address it's kept by, in place of any
+	// search already kept there.
+	fn start_file_search(&mut self, search_addr: u32, found_files: VecDeque<[u8; DOS_FIND_DATA_BYTES]>) {
+		self.file_searches.retain(|(addr, _)| *addr != search_addr);
+		if self.file_searches.len() >= MAX_FILE_SEARCHES {
+			self.file_searches.remove(0);
+		}
+		self.file_searches.push((search_addr, found_files));
+	}
+	
+	// Takes the next file from the search kept under search_addr, forgetting the search once it's
+	// out of files.
+	fn next_found_file(&mut self, search_addr: u32) -> Option<[u8; DOS_FIND_DATA_BYTES]> {
+		let index = self.file_searches.iter().position(|(addr, _)| *addr == search_addr)?;
+		let find_data = self.file_searches[index].1.pop_front();
+		if self.file_searches[index].1.is_empty() {
+			self.file_searches.remove(index);
+		}
+		find_data
+	}
+	
+	// Makes a new handle that acts as the console for int 21h AH=45h, like the standard handle
+	// std_handle does.
+	fn duplicate_console_handle(&mut self, std_handle: u16) -> u16 {
+		let handle = (CONSOLE_DUPLICATE_FIRST_HANDLE ..).find(|handle| !self.console_duplicate_handles.contains_key(handle)).unwrap();
+		self.console_duplicate_handles.insert(handle, std_handle);
+		handle
+	}
+	
+	// Makes new_handle a duplicate of handle for int 21h AH=46h.
+	fn force_duplicate_handle(&mut self, handle: u16, new_handle: u16) -> Result<(), DosErrorCode> {
+		let file_handle = self.file_handle(handle);
+		let is_console = |handle: u16| handle < DOS_FIRST_FILE_HANDLE;
+		if self.clock_device_handles.contains(&file_handle) {
+			// CLOCK$ has no position to share, so the new handle is just CLOCK$ too. The standard
+			// handles can only be the console or a file.
+			if is_console(new_handle) {
+				return Err(DosErrorCode::InvalidFileHandle);
+			}
+			if new_handle != file_handle && !self.clock_device_handles.contains(&new_handle) && self.console_duplicate_handles.remove(&new_handle).is_none() {
+				// Whatever file the handle was is closed first, if it was one.
+				self.file_locks.remove_handle(new_handle);
+				self.file_system.close(new_handle).ok();
+			}
+			self.clock_device_handles.insert(new_handle);
+			return Ok(());
+		}
+		if is_console(file_handle) {
+			// Only a standard handle or another duplicate of the console (or CLOCK$) can become
+			// the console.
+			if let Some(std_handle) = self.console_duplicate_handles.get_mut(&new_handle) {
+				*std_handle = file_handle;
+				return Ok(());
+			}
+			if self.clock_device_handles.remove(&new_handle) {
+				self.console_duplicate_handles.insert(new_handle, file_handle);
+				return Ok(());
+			}
+			if !is_console(new_handle) {
+				return Err(DosErrorCode::InvalidFileHandle);
+			}
+			if let Some(old_file_handle) = self.redirected_std_handles.remove(&new_handle) {
+				self.file_locks.remove_handle(old_file_handle);
+				self.file_system.close(old_file_handle)?;
+			}
+			return Ok(());
+		}
+		if is_console(new_handle) {
+			let redirect_handle = self.file_system.duplicate(file_handle)?;
+			self.file_locks.duplicate_handle(file_handle, redirect_handle);
+			if let Some(old_file_handle) = self.redirected_std_handles.insert(new_handle, redirect_handle) {
+				self.file_locks.remove_handle(old_file_handle);
+				self.file_system.close(old_file_handle)?;
+			}
+			Ok(())
+		} else {
+			self.file_system.force_duplicate(file_handle, new_handle)?;
+			self.clock_device_handles.remove(&new_handle);
+			self.console_duplicate_handles.remove(&new_handle);
+			self.file_locks.remove_handle(new_handle);
+			self.file_locks.duplicate_handle(file_handle, new_handle);
+			Ok(())
+		}
+	}
+	
+	// Gets the host path of the file a file system handle refers to, which is what file locks are
+	// for, so that every handle for the file is affected by them.
+	fn locked_file_path(&self, file_handle: u16) -> Result<std::path::PathBuf, DosErrorCode> {
+		self.file_system.open_handles().into_iter()
+			.find(|open_handle| open_handle.handle == file_handle)
+			.map(|open_handle| open_handle.real_path)
+			.ok_or(DosErrorCode::InvalidFileHandle)
+	}
+	
+	// Checks that the next count bytes of a file can be read or written through a file system
+	// handle, which they can't if another handle has locked any of them.
+	fn check_file_lock_access(&mut self, file_handle: u16, count: usize) -> Result<(), DosErrorCode> {
+		if self.file_locks.is_empty() || count == 0 {
+			return Ok(());
+		}
+		let path = self.locked_file_path(file_handle)?;
+		let offset = self.file_system.tell(file_handle)?;
+		self.file_locks.check_access(&path, file_handle, offset, count as u32)
+	}
+	
+	fn open_clock_device(&mut self) -> u16 {
+		// A file can have been forced into a handle in the CLOCK$ range with AH=46h.
+		let file_handles: HashSet<u16> = self.file_system.open_handles().iter().map(|open_handle| open_handle.handle).collect();
+		let handle = (CLOCK_DEVICE_FIRST_HANDLE ..).find(|handle| !self.clock_device_handles.contains(handle) && !file_handles.contains(handle)).unwrap();
+		self.clock_device_handles.insert(handle);
+		handle
+	}
+	
+	// The device information word int 21h AX=4400h gives for a handle. The console and CLOCK$ are
+	// character devices, and a file gives the drive it's on.
+	// http://www.ctyme.com/intr/rb-2820.htm
+	fn device_info(&self, handle: u16) -> Result<u16, DosErrorCode> {
+		let file_handle = self.file_handle(handle);
+		if self.clock_device_handles.contains(&file_handle) {
+			// A device (bit 7) that's the clock (bit 3), and isn't at the end of its input (bit 6).
+			return Ok(0x80c8);
+		}
+		match file_handle {
+			// The console, which is standard input (bit 0) and output (bit 1), and has the int 29h
+			// fast output (bit 4).
+			0 ..= 2 => Ok(0x80d3),
+			// AUX and PRN, which can also be sent IOCTL strings (bit 14 for PRN, which is the
+			// output until busy bit 13 too).
+			3 => Ok(0x80c0),
+			4 => Ok(0xa8c0),
+			_ => {
+				let open_handle = self.file_system.open_handles().into_iter()
+					.find(|open_handle| open_handle.handle == file_handle)
+					.ok_or(DosErrorCode::InvalidFileHandle)?;
+				let drive = match open_handle.dos_path.get(..2) {
+					Some(&[letter, b':']) => letter.to_ascii_uppercase().wrapping_sub(b'A'),
+					_ => self.file_system.current_drive(),
+				};
+				Ok((drive & 0x3f) as u16)
+			}
+		}
+	}
+	
+	// Sets the BIOS tick count to the time of day after the time is set, like DOS does with int 1Ah
+	// AH=01h, which also forgets any midnight that hasn't been reported.
+	fn sync_bios_ticks_to_clock(&self, machine: &mut dyn Machine) {
+		set_bios_ticks(machine, self.clock.bios_ticks(self.seconds_since_start));
+		machine.set_data_u8(&BIOS_TIMER_OVERFLOW, 0);
+	}
+	
+	/// Adds a callback that runs when the program calls an interrupt, before the built-in handling.
+	/// With an AH value it only runs for that function of the interrupt, otherwise for all of them.
+	/// The hook for the exact AH runs before the one for all functions. Calls a hook handles are left
+	/// out of the function audit.
+	pub fn hook_interrupt(&mut self, interrupt_index: u8, ah: Option<u8>, callback: InterruptHookFn) {
+		self.interrupt_hooks.add(interrupt_index, ah, callback);
+	}
+	
+	/// Removes a callback added with hook_interrupt. Returns whether there was one.
+	pub fn unhook_interrupt(&mut self, interrupt_index: u8, ah: Option<u8>) -> bool {
+		self.interrupt_hooks.remove(interrupt_index, ah)
+	}
+	
+	/// Writes a character to standard output, which goes to the screen and/or the host's stdout
+	/// depending on dos_output_mode.
+	pub fn write_dos_output_char(&mut self, machine: &mut dyn Machine, c: u8) {
+		if let Some(ref mut scrollback) = self.scrollback {
+			scrollback.write_char(c);
+		}
+		if self.dos_output_mode != DosOutputMode::Stdout {
+			if let Some(ref mut video_stats) = self.video_stats {
+				video_stats.sample(machine, OutputMethod::Direct);
+			}
+			self.write_tty_char(machine, c);
+			if let Some(ref mut video_stats) = self.video_stats {
+				video_stats.record_dos_console_char(machine);
+			}
+		}
+		if self.dos_output_mode != DosOutputMode::Screen {
+			let mut stdout = std::io::stdout();
+			// There's nowhere to report a broken stdout to, so the output is just lost.
+			stdout.write_all(&[c]).and_then(|_| stdout.flush()).ok();
+		}
+	}
+	
+	/// The address of the text being displayed, which follows the CRTC start address. It's the
+	/// active page unless the program has set the start address itself.
+	pub fn display_start_address(&self) -> u32 {
+		self.video_mode.text_address + self.port_states.crtc_start_address as u32 * 2
+	}
+	
+	/// The characters being displayed, as a list of rows.
+	pub fn get_screen_text(&self, machine: &dyn Machine) -> Vec<Vec<u8>> {
+		let page_addr = self.display_start_address();
+		let column_count = machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT).min(0xff) as u8;
+		let row_count = self.get_text_row_count(machine);
+		(0 .. row_count).map(|y| {
+			(0 .. column_count).map(|x| machine.peek_u8(self.get_screen_character_address(machine, page_addr, x, y))).collect()
+		}).collect()
+	}
+	
+	/// The character at a row and column of the text being displayed, for checking what a
+	/// program drew.
+	pub fn get_char_at(&self, machine: &dyn Machine, row: u8, column: u8) -> u8 {
+		machine.peek_u8(self.get_screen_character_address(machine, self.display_start_address(), column, row))
+	}
+	
+	/// The attribute (colours) at a row and column of the text being displayed.
+	pub fn get_attr_at(&self, machine: &dyn Machine, row: u8, column: u8) -> u8 {
+		machine.peek_u8(self.get_screen_character_address(machine, self.display_start_address(), column, row) + 1)
+	}
+	
+	/// The text being displayed, as character and attribute byte pairs, with the mouse cursor put
+	/// on it. The cursor is never written to video memory (see dos_mouse).
+	pub fn get_composed_text(&self, machine: &dyn Machine) -> Vec<u8> {
+		let start_address = self.display_start_address() as usize;
+		let mut text = machine.memory()[start_address .. start_address + self.video_mode.text_page_bytes as usize].to_vec();
+		if let Some(ref mouse) = self.mouse {
+			mouse.compose_text_cursor(&mut text, machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT));
+		}
+		text
+	}
+	
+	/// Runs the program without a window until it exits, returning its exit code. Timer interrupts
+	/// are raised every few thousand instructions like the runner does, but no time is waited for.
+	/// The startup can fast forward through a timed intro by raising them much more often at
+	/// first. Gives an error if the program needs a keypress that isn't queued, or doesn't exit
+	/// within max_steps instructions.
+	pub fn run_headless(&mut self, machine: &mut Machine8086, max_steps: usize, startup: HeadlessStartup) -> Result<u8, String> {
+		let start_keyboard_polls = self.keyboard_polls;
+		let mut timer_interrupts = 0;
+		let mut steps_since_timer_interrupt = 0;
+		for _ in 0 .. max_steps {
+			let fast_forwarding = match startup {
+				HeadlessStartup::Normal => false,
+				HeadlessStartup::FastForwardTicks(ticks) => timer_interrupts < ticks,
+				HeadlessStartup::FastForwardUntilInput => self.keyboard_polls == start_keyboard_polls,
+			};
+			let steps_per_timer_interrupt = if fast_forwarding { HEADLESS_FAST_FORWARD_STEPS_PER_TIMER_INTERRUPT } else { HEADLESS_STEPS_PER_TIMER_INTERRUPT };
+			steps_since_timer_interrupt += 1;
+			if steps_since_timer_interrupt >= steps_per_timer_interrupt {
+				steps_since_timer_interrupt = 0;
+				timer_interrupts += 1;
+				machine.interrupt_on_next_step(0x08);
+				self.check_low_memory(machine);
+			}
+			if let StepResult::Interrupt = machine.step(self)? {
+				match self.result {
+					DosInterruptResult::ShouldReturn | DosInterruptResult::ShouldReturnAndWaitForEvents => {
+						machine.return_from_interrupt();
+					}
+					DosInterruptResult::ShouldBlockForKeypress => {
+						return Err("Blocked waiting for a keypress".to_string());
+					}
+					DosInterruptResult::ShouldExit(exit_code) => {
+						return Ok(exit_code);
+					}
+				}
+			}
+		}
+		Err(format!("Program didn't exit within {} steps", max_steps))
+	}
+	
+	/// Checks whether the program wrote over the interrupt vectors or BIOS data by mistake since the
+	/// last check, if it's being watched, and reports anything it did, followed by the vectors it
+	/// changed if they're being traced. The runners call this at the end of each frame.
+	pub fn check_low_memory(&mut self, machine: &mut dyn Machine) -> Vec<LowMemoryWrite> {
+		let mut writes = match self.low_memory_guard {
+			Some(ref mut low_memory_guard) => low_memory_guard.end_frame(machine),
+			None => vec![],
+		};
+		// After the guard, so the vectors it puts back aren't changes.
+		if let Some(ref mut vector_watch) = self.vector_watch {
+			writes.extend(vector_watch.end_frame(machine));
+		}
+		for write in &writes {
+			eprintln!("{}", write);
+		}
+		writes
+	}
+	
+	// Ends the program, putting back the vectors it was started with. Every way of terminating
+	// should go through here. Child programs can't be run, so the program that ends is always the
+	// one that was loaded.
+	fn terminate_program(&mut self, machine: &mut dyn Machine, exit_code: u8) {
+		restore_psp_vectors(machine, PROGRAM_PSP_SEGMENT);
+		self.result = DosInterruptResult::ShouldExit(exit_code);
+	}
+	
+	fn is_no_inherit(&self, handle: u16) -> bool {
+		self.no_inherit.contains(&handle)
+	}
+	
+	// Makes a PSP at the given segment with the same command tail and environment as the program's
+	// PSP. A child PSP has the program's PSP as its parent, and leaves out the handles that aren't
+	// inherited. Only the first handle_table_size handles (at most 20) go in the handle table.
+	// http://www.ctyme.com/intr/rb-2682.htm
+	fn create_program_segment_prefix(&mut self, machine: &mut dyn Machine, psp_segment: u16, handle_table_size: u16, child: bool) {
+		let current_psp_start = (PROGRAM_PSP_SEGMENT as u32) << 4;
+		let tail_len = (machine.peek_u8(current_psp_start + 0x80) as usize).min(MAX_COMMAND_TAIL_BYTES);
+		let tail_start = current_psp_start as usize + 0x81;
+		let command_line_tail = machine.memory()[tail_start .. tail_start + tail_len].to_vec();
+		let environment_segment = machine.peek_u16(current_psp_start + 0x2c);
+		initialise_dos_program_segment_prefix(machine, psp_segment, &command_line_tail, environment_segment, &*self.file_system)
+			.expect("The command tail is limited to the maximum length");
+		
+		let psp_start = (psp_segment as u32) << 4;
+		let parent_psp_segment = if child { PROGRAM_PSP_SEGMENT } else { machine.peek_u16(current_psp_start + 0x16) };
+		machine.poke_u16(psp_start + 0x16, parent_psp_segment);
+		
+		// The handle table, with 0xff for closed handles.
+		let handle_table_size = handle_table_size.min(PSP_HANDLE_TABLE_ENTRIES);
+		for handle in 0 .. PSP_HANDLE_TABLE_ENTRIES {
+			let inherited = handle < handle_table_size
+				&& self.file_system.tell(handle).is_ok()
+				&& !(child && self.is_no_inherit(handle));
+			machine.poke_u8(psp_start + 0x18 + handle as u32, if inherited { handle as u8 } else { 0xff });
+		}
+		machine.poke_u16(psp_start + 0x32, handle_table_size);
+		machine.poke_u16(psp_start + 0x34, 0x18);
+		machine.poke_u16(psp_start + 0x36, psp_segment);
+	}
+	
+	fn handle_unknown_function(&mut self, machine: &mut dyn Machine, action: UnknownFuncAction, description: String) {
+		report_unknown_function(action, &description);
+		machine.set_flag(Flag::Carry, true);
+		machine.set_reg_u16(Reg::AX, DosErrorCode::InvalidFunction as u16);
+	}
+	
+	// Reads from the console for int 21h AH=3Fh, like DOS's cooked mode: a line is typed and
+	// echoed to the screen, with backspace taking back the last character, and nothing is returned
+	// until Enter is pressed. The line then ends with CR and LF, and is returned over as many reads
+	// as it takes (so a one byte read gets the LF on its own call). Extended keys have no character
+	// and are skipped, and characters past the longest line are dropped. Returns None when it has
+	// to wait for a key. Writing to the console isn't cooked, so Ctrl+Z (1Ah) is written to the
+	// screen like any other character, as MS-DOS does.
+	fn read_console(&mut self, machine: &mut dyn Machine, destination_addr: u32, count: usize) -> Option<u16> {
+		if count == 0 {
+			return Some(0);
+		}
+		while self.con_input.is_empty() {
+			self.keyboard_polls += 1;
+			let key_press_info = match self.key_press_queue.pop_front() {
+				Some(key_press_info) => key_press_info,
+				None => {
+					self.result = DosInterruptResult::ShouldBlockForKeypress;
+					return None;
+				}
+			};
+			match key_press_info.ascii_char {
+				0 => {}
+				b'\r' => {
+					self.write_dos_output_char(machine, b'\r');
+					self.write_dos_output_char(machine, b'\n');
+					self.con_input.extend(self.con_line.drain(..));
+					self.con_input.extend(b"\r\n");
+				}
+				0x08 => {
+					if self.con_line.pop().is_some() {
+						for &c in b"\x08 \x08".iter() {
+							self.write_dos_output_char(machine, c);
+						}
+					}
+				}
+				c => {
+					if self.con_line.len() < CONSOLE_LINE_MAX_CHARS {
+						self.write_dos_output_char(machine, c);
+						self.con_line.push(c);
+					}
+				}
+			}
+		}
+		let read_count = count.min(self.con_input.len());
+		for (offset, c) in self.con_input.drain(..read_count).enumerate() {
+			machine.poke_u8(destination_addr + offset as u32, c);
+		}
+		Some(read_count as u16)
+	}
+	
+	// Finds the normal FCB for the FCB at DS:DX, which is after the header if it's an extended FCB,
+	// and gets the attributes at the end of the header of an extended one. None if the FCB goes past
+	// the end of memory.
+	fn get_fcb_address(&self, machine: &dyn Machine) -> Option<(usize, Option<u8>)> {
+		let fcb_key = machine.get_seg_reg(Reg::DS, Reg::DX) as usize;
+		let memory = machine.memory();
+		let extended = *memory.get(fcb_key)? == 0xff;
+		let fcb_addr = if extended { fcb_key + EXTENDED_FCB_HEADER_BYTES } else { fcb_key };
+		if fcb_addr + FCB_BYTES > memory.len() {
+			return None;
+		}
+		Some((fcb_addr, if extended { Some(memory[fcb_key + 6]) } else { None }))
+	}
+	
+	// Int 21h AH=0Fh and AH=16h. The file is opened by handle, which is kept in fcb_handles for the
+	// other FCB functions to use, and the FCB's fields are filled in like DOS does.
+	// http://www.ctyme.com/intr/rb-2574.htm
+	fn open_fcb_file(&mut self, machine: &mut dyn Machine, create: bool) -> Result<(), DosErrorCode> {
+		let fcb_key = machine.get_seg_reg(Reg::DS, Reg::DX);
+		let (fcb_addr, attributes) = self.get_fcb_address(machine).ok_or(DosErrorCode::InvalidData)?;
+		let attributes = attributes.unwrap_or(0) as u16;
+		let drive = machine.memory()[fcb_addr];
+		let mut filename = fcb_drive_prefix(drive).ok_or(DosErrorCode::InvalidDrive)?;
+		filename.extend(fcb_unpadded_name(&machine.memory()[fcb_addr + 1 .. fcb_addr + 12]));
+		
+		let handle = if create {
+			self.file_system.create(&filename, attributes)?
+		} else {
+			// FCBs are opened for reading and writing, unless the file is read-only. Opening for
+			// writing would create the file, so make sure it's there first.
+			self.file_system.get_attributes(&filename)?;
+			match self.file_system.open(&filename, DosFileAccessMode::ReadWrite) {
+				Err(DosErrorCode::AccessDenied) => self.file_system.open(&filename, DosFileAccessMode::ReadOnly)?,
+				result => result?,
+			}
+		};
+		let file_size = self.file_system.seek(handle, 0, DosFileSeekOrigin::End)?;
+		self.file_system.seek(handle, 0, DosFileSeekOrigin::Start)?;
+		// Opening an FCB again without closing it loses the old handle, so close it here.
+		if let Some(old_handle) = self.fcb_handles.insert(fcb_key, handle) {
+			self.file_system.close(old_handle).ok();
+		}
+		
+		let current_drive = self.file_system.current_drive();
+		let fcb = &mut machine.memory_mut()[fcb_addr .. fcb_addr + 0x20];
+		if drive == 0 {
+			fcb[0] = current_drive + 1;
+		}
+		// The current block, record size, file size, and then the date and time.
+		fcb[0x0c .. 0x0e].copy_from_slice(&0u16.to_le_bytes());
+		fcb[0x0e .. 0x10].copy_from_slice(&0x80u16.to_le_bytes());
+		fcb[0x10 .. 0x14].copy_from_slice(&file_size.to_le_bytes());
+		fcb[0x14 .. 0x18].iter_mut().for_each(|c| *c = 0);
+		Ok(())
+	}
+	
+	// Int 21h AH=14h and AH=15h, for an FCB opened with AH=0Fh or AH=16h. The record number is the
+	// current block times 128 plus the current record, and it's moved on after each record. Returns
+	// AL: 0 for success, 1 for the end of the file (or a full disk when writing), 2 when the DTA is
+	// too small, and 3 for a partial record at the end of the file, which is padded with zeros. The
+	// DTA is only kept as a linear address, so it can't tell when the record would wrap around its
+	// segment, only when it would go past the end of memory.
+	// http://www.ctyme.com/intr/rb-2579.htm
+	fn fcb_sequential_io(&mut self, machine: &mut dyn Machine, write: bool) -> u8 {
+		let fcb_key = machine.get_seg_reg(Reg::DS, Reg::DX);
+		let (fcb_addr, handle) = match (self.get_fcb_address(machine), self.fcb_handles.get(&fcb_key)) {
+			(Some((fcb_addr, _)), Some(&handle)) => (fcb_addr, handle),
+			_ => return 1,
+		};
+		let block = machine.peek_u16(fcb_addr as u32 + 0x0c) as u32;
+		let record = (machine.memory()[fcb_addr + 0x20] & 0x7f) as u32;
+		let record_size = match machine.peek_u16(fcb_addr as u32 + 0x0e) {
+			0 => 0x80,
+			record_size => record_size as usize,
+		};
+		let dta_addr = self.disk_trasnsfer_address as usize;
+		if dta_addr + record_size > machine.memory().len() {
+			return 2;
+		}
+		let record_number = block * 128 + record;
+		// A big enough block number and record size put the record past where a file can reach,
+		// which is the same as the end of the file, or a full disk.
+		let position = record_number as u64 * record_size as u64;
+		let end = position + record_size as u64;
+		if end > u32::MAX as u64 || self.file_system.seek(handle, position as u32, DosFileSeekOrigin::Start).is_err() {
+			return 1;
+		}
+		
+		let status = if write {
+			match self.file_system.write(handle, &machine.memory()[dta_addr .. dta_addr + record_size]) {
+				Ok(written_count) if written_count as usize == record_size => {
+					// Keep the file size in the FCB up to date as the file grows.
+					let end = end as u32;
+					let file_size_bytes = &mut machine.memory_mut()[fcb_addr + 0x10 .. fcb_addr + 0x14];
+					if end > u32::from_le_bytes([file_size_bytes[0], file_size_bytes[1], file_size_bytes[2], file_size_bytes[3]]) {
+						file_size_bytes.copy_from_slice(&end.to_le_bytes());
+					}
+					0
+				}
+				_ => return 1,
+			}
+		} else {
+			let destination = &mut machine.memory_mut()[dta_addr .. dta_addr + record_size];
+			let mut read_count = 0;
+			while read_count < record_size {
+				match self.file_system.read(handle, &mut destination[read_count..]) {
+					Ok(0) | Err(_) => break,
+					Ok(count) => read_count += count as usize,
+				}
+			}
+			if read_count == 0 {
+				return 1;
+			}
+			destination[read_count..].iter_mut().for_each(|c| *c = 0);
+			if read_count == record_size { 0 } else { 3 }
+		};
+		
+		let next_record_number = record_number + 1;
+		machine.poke_u16(fcb_addr as u32 + 0x0c, (next_record_number / 128) as u16);
+		machine.memory_mut()[fcb_addr + 0x20] = (next_record_number % 128) as u8;
+		status
+	}
+	
+	// Puts the next file of the search kept with the current DTA in the DTA, for int 21h AH=4Eh and
+	// AH=4Fh. Error NoMoreFiles once the search is out of files.
+	fn write_next_found_file(&mut self, machine: &mut dyn Machine) {
+		match self.next_found_file(self.disk_trasnsfer_address) {
+			Some(find_data) => {
+				let dta_addr = self.disk_trasnsfer_address as usize;
+				let memory = machine.memory_mut();
+				let end = (dta_addr + DOS_FIND_DATA_BYTES).min(memory.len());
+				if dta_addr < end {
+					memory[dta_addr..end].copy_from_slice(&find_data[..end - dta_addr]);
+				}
+				machine.set_flag(Flag::Carry, false);
+				machine.set_reg_u16(Reg::AX, 0);
+			}
+			None => {
+				machine.set_flag(Flag::Carry, true);
+				machine.set_reg_u16(Reg::AX, DosErrorCode::NoMoreFiles as u16);
+			}
+		}
+	}
+	
+	// Int 21h AH=11h and AH=12h. The FCB is the same for both calls, so the search is kept by its
+	// address. All the files are searched for and then matched here, since '?' in an FCB also
+	// matches the padding after a shorter name.
+	// http://www.ctyme.com/intr/rb-2574.htm
+	fn find_fcb_file(&mut self, machine: &mut dyn Machine, first: bool) -> bool {
+		let search_addr = machine.get_seg_reg(Reg::DS, Reg::DX) as usize;
+		// An extended FCB has the attributes to search for at the end of its header, and the FCB
+		// written to the DTA gets the same header.
+		let (fcb_addr, extended_attributes) = match self.get_fcb_address(machine) {
+			Some(fcb_address) => fcb_address,
+			None => return false,
+		};
+		let extended = extended_attributes.is_some();
+		let attributes = extended_attributes.unwrap_or(0) as u16;
+		let found_fcb_bytes = if extended { EXTENDED_FCB_HEADER_BYTES + FOUND_FCB_BYTES } else { FOUND_FCB_BYTES };
+		if self.disk_trasnsfer_address as usize + found_fcb_bytes > machine.memory().len() {
+			return false;
+		}
+		let drive = machine.memory()[fcb_addr];
+		let pattern = machine.memory()[fcb_addr + 1 .. fcb_addr + 12].to_vec();
+		
+		if first {
+			let mut search_spec = match fcb_drive_prefix(drive) {
+				Some(search_spec) => search_spec,
+				None => return false,
+			};
+			search_spec.push(b'*');
+			let found_files = self.find_all_files(attributes, &search_spec).unwrap_or_default().into_iter()
+				.filter(|find_data| fcb_name_matches(&pattern, &fcb_padded_name(find_data_filename(find_data))))
+				.collect();
+			self.start_file_search(search_addr as u32, found_files);
+		}
+		let find_data = match self.next_found_file(search_addr as u32) {
+			Some(find_data) => find_data,
+			None => return false,
+		};
+		let padded_name = fcb_padded_name(find_data_filename(&find_data));
+		let mut dta_addr = self.disk_trasnsfer_address as usize;
+		if extended {
+			machine.memory_mut()[dta_addr .. dta_addr + EXTENDED_FCB_HEADER_BYTES].copy_from_slice(&[0xff, 0, 0, 0, 0, 0, attributes as u8]);
+			dta_addr += EXTENDED_FCB_HEADER_BYTES;
+		}
+		// The drive byte, then the file's directory entry.
+		// http://www.ctyme.com/intr/rb-2574.htm
+		let fcb = &mut machine.memory_mut()[dta_addr .. dta_addr + FOUND_FCB_BYTES];
+		fcb[0] = drive;
+		fcb[1..12].copy_from_slice(&padded_name);
+		fcb[12] = find_data[0x15];
+		fcb[13..23].iter_mut().for_each(|c| *c = 0);
+		fcb[23..27].copy_from_slice(&find_data[0x16..0x1a]);
+		fcb[27..29].iter_mut().for_each(|c| *c = 0);
+		fcb[29..33].copy_from_slice(&find_data[0x1a..0x1e]);
+		true
+	}
+	
+	// Int 21h AX=440Dh: BL is the drive (0 is the current one), CH the category and CL the minor
+	// code. Each supported category and minor code pair gets an arm.
+	// http://www.ctyme.com/intr/rb-2896.htm
+	fn handle_generic_block_ioctl(&mut self, machine: &mut dyn Machine) -> Result<(), DosErrorCode> {
+		let drive = match machine.get_reg_u8(Reg::BX, RegHalf::Low) {
+			0 => self.file_system.current_drive(),
+			drive => drive - 1,
+		};
+		let category = machine.get_reg_u8(Reg::CX, RegHalf::High);
+		let minor_code = machine.get_reg_u8(Reg::CX, RegHalf::Low);
+		// Like a read with AH=3Fh, a buffer at the top of memory stops at the end of it.
+		let memory_bytes = machine.memory().len();
+		let buffer_addr = (machine.get_seg_reg(Reg::DS, Reg::DX) as usize).min(memory_bytes);
+		let buffer_bytes = DOS_MEDIA_ID_BYTES.min(memory_bytes - buffer_addr);
+		match (category, minor_code) {
+			// Get media ID.
+			(0x08, 0x66) => {
+				let media_id = self.file_system.drive_media_id(drive)?;
+				machine.insert_contiguous_bytes(&media_id.to_bytes()[.. buffer_bytes], buffer_addr);
+				Ok(())
+			}
+			// Set media ID. Whatever is past the end of memory reads as zeros.
+			(0x08, 0x46) => {
+				let mut bytes = [0; DOS_MEDIA_ID_BYTES];
+				bytes[.. buffer_bytes].copy_from_slice(&machine.memory()[buffer_addr .. buffer_addr + buffer_bytes]);
+				self.file_system.set_drive_media_id(drive, DosMediaId::from_bytes(&bytes))
+			}
+			_ => Err(DosErrorCode::InvalidFunction),
+		}
+	}
+	
+	fn handle_interrupt_10h(&mut self, machine: &mut dyn Machine) {
+		// Video (http://www.ctyme.com/intr/int-10.htm)
+		let video_int = machine.get_reg_u8(Reg::AX, RegHalf::High);
+		//println!("Video interrupt: 0x{:x}", video_int);
+		match video_int {
+			0x00 => {
+				// Set video mode. Bit 7 of the mode asks for the screen to not be cleared.
+				let al = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+				if let Err(err) = self.set_video_mode(machine, al & 0x7f, al & 0x80 == 0) {
+					eprintln!("{}", err);
+				}
+			}
+			0x01 => {
+				// Set text-mode cursor shape. The raw value is stored, and the hiding and scaling
+				// is applied when the cursor is drawn.
+				let cursor_type = machine.get_reg_u16(Reg::CX);
+				machine.set_data_u16(&BIOS_CURSOR_TYPE, cursor_type);
+			}
+			0x02 => {
+				// Set cursor position. Pages that don't exist are ignored.
+				let bh = machine.get_reg_u8(Reg::BX, RegHalf::High);
+				let video_page = if bh == 0xff { self.get_active_video_page(machine) } else { bh };
+				
+				let dh = machine.get_reg_u8(Reg::DX, RegHalf::High);
+				let dl = machine.get_reg_u8(Reg::DX, RegHalf::Low);
+				let cursor_pos_data = ((dh as u16) << 8) + dl as u16;
+				if let Some(cursor_position) = BIOS_CURSOR_POSITION.get(video_page as usize) {
+					machine.set_data_u16(cursor_position, cursor_pos_data);
+				}
+			}
+			0x03 => {
+				// Get cursor position and size. Like AH=08h, pages past the last are read as the last.
+				let bh = machine.get_reg_u8(Reg::BX, RegHalf::High);
+				let video_page = if bh == 0xff { self.get_active_video_page(machine) } else { bh.min(BIOS_CURSOR_POSITION.len() as u8 - 1) };
+				let cursor_pos_data = machine.get_data_u16(&BIOS_CURSOR_POSITION[video_page as usize]);
+				machine.set_reg_u16(Reg::CX, machine.get_data_u16(&BIOS_CURSOR_TYPE));
+				machine.set_reg_u16(Reg::DX, cursor_pos_data);
+			}
+			0x04 => {
+				// Read light pen position. AH=0 means the pen hasn't been triggered, which is all
+				// programs ever see without a light pen.
+				machine.set_reg_u8(Reg::AX, RegHalf::High, 0);
+			}
+			0x05 => {
+				// Select the active page, which is also the one displayed.
+				let video_page = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+				if (video_page as u32) < self.video_mode.text_page_count {
+					let page_offset = video_page as u16 * machine.get_data_u16(&BIOS_TEXT_PAGE_BYTES);
+					machine.set_data_u8(&BIOS_ACTIVE_VIDEO_PAGE, video_page);
+					machine.set_data_u16(&BIOS_ACTIVE_PAGE_OFFSET, page_offset);
+					self.port_states.crtc_start_address = page_offset / 2;
+				}
+			}
+			0x06 | 0x07 => {
+				// Scroll the text up (0x06) or down (0x07) by AL lines within the rectangle from CX
+				// to DX on the active page, filling the new lines with blanks coloured by BH.
+				let num_lines = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+				let blank_char_attributes = machine.get_reg_u8(Reg::BX, RegHalf::High);
+				let top_left = (machine.get_reg_u8(Reg::CX, RegHalf::Low), machine.get_reg_u8(Reg::CX, RegHalf::High));
+				let bottom_right = (machine.get_reg_u8(Reg::DX, RegHalf::Low), machine.get_reg_u8(Reg::DX, RegHalf::High));
+				self.scroll_window(machine, video_int == 0x06, num_lines, blank_char_attributes, top_left, bottom_right);
+			}
+			0x08 => {
+				// Read the character (into AL) and attributes (into AH) at the cursor of page BH.
+				let video_page = machine.get_reg_u8(Reg::BX, RegHalf::High).min(BIOS_CURSOR_POSITION.len() as u8 - 1);
+				let cursor_pos_data = machine.get_data_u16(&BIOS_CURSOR_POSITION[video_page as usize]);
+				let page_addr = self.get_page_origin_address(machine, video_page);
+				let char_addr = self.get_screen_character_address(machine, page_addr, (cursor_pos_data & 0xff) as u8, (cursor_pos_data >> 8) as u8);
+				machine.set_reg_u16(Reg::AX, machine.peek_u16(char_addr));
+			}
+			0x0b => {
+				// Set the background and border colour (BH=0) or the CGA graphics palette (BH=1).
+				// In text modes BH=0 only sets the border, to the colour in the low 4 bits of BL,
+				// with the intensity bit moved to where the EGA palette registers have it.
+				if machine.get_reg_u8(Reg::BX, RegHalf::High) == 0 {
+					let bl = machine.get_reg_u8(Reg::BX, RegHalf::Low);
+					self.set_border_colour(irgb_to_ega_colour(bl));
+				}
+			}
+			0x0e => {
+				// Teletype output
+				let c = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+				if let Some(ref mut scrollback) = self.scrollback {
+					scrollback.write_char(c);
+				}
+				self.write_tty_char(machine, c);
+			}
+			0x0f => {
+				// Get current video mode
+				let text_column_count = machine.get_data_u16(&BIOS_TEXT_COLUMN_COUNT);
+				machine.set_reg_u8(Reg::AX, RegHalf::High, text_column_count as u8);
+				// Video modes covered in: http://www.ctyme.com/intr/rb-0069.htm
+				machine.set_reg_u8(Reg::AX, RegHalf::Low, self.video_mode.mode_index);
+				// Active display page (http://www.ctyme.com/intr/rb-0091.htm)
+				machine.set_reg_u8(Reg::BX, RegHalf::High, machine.get_data_u8(&BIOS_ACTIVE_VIDEO_PAGE));
 			}
 			0x10 => {
+				// Palette registers (http://www.ctyme.com/intr/rb-0116.htm)
+				let func10 = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+				let border_index = EGA_OVERSCAN_REGISTER;
+				match func10 {
+					0x00 => {
+						// Set the palette register in BL to BH.
+						let bl = machine.get_reg_u8(Reg::BX, RegHalf::Low);
+						if (bl as usize) < border_index {
+							self.write_attribute_controller(bl, machine.get_reg_u8(Reg::BX, RegHalf::High));
+						}
+					}
+					0x01 => {
+						// Set the border colour to BH.
+						self.set_border_colour(machine.get_reg_u8(Reg::BX, RegHalf::High));
+					}
+					0x02 => {
+						// Set all the palette registers and the border colour from the 17 bytes at ES:DX.
+						let addr = machine.get_seg_reg(Reg::ES, Reg::DX);
+						for index in 0 .. border_index {
+							self.write_attribute_controller(index as u8, machine.peek_u8(addr + index as u32));
+						}
+						self.set_border_colour(machine.peek_u8(addr + border_index as u32));
+					}
+					0x07 => {
+						// Read the palette register in BL into BH.
+						let bl = machine.get_reg_u8(Reg::BX, RegHalf::Low) as usize;
+						if bl < border_index {
+							machine.set_reg_u8(Reg::BX, RegHalf::High, self.ega_palette[bl]);
+						}
+					}
+					0x08 => {
+						// Read the border colour into BH.
+						machine.set_reg_u8(Reg::BX, RegHalf::High, self.border_colour());
+					}
+					0x03 => {
+						// Toggle blinking: BL=0 makes the top attribute bit a bright background, and 1
+						// makes it blink (http://www.ctyme.com/intr/rb-0117.htm).
+						self.blink_enabled = machine.get_reg_u8(Reg::BX, RegHalf::Low) != 0;
+					}
+					0x09 => {
+						// Read all the palette registers and the border colour into the 17 bytes at ES:DX.
+						let addr = machine.get_seg_reg(Reg::ES, Reg::DX);
+						for (index, register) in self.ega_palette.iter().enumerate() {
+							machine.poke_u8(addr + index as u32, *register);
+						}
+					}
+					_ => {
+						let action = self.unknown_interrupt_action;
+						self.handle_unknown_function(machine, action, format!("Unknown video 0x10 func: 0x{:x}", func10));
+					}
+				}
+			}
+			0x11 => {
+				let func11 = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+				match func11 {
+					0x30 => {
+						// TODO: Get font information
+						
+						// Copying ZETA:
+						machine.set_flag(Flag::Carry, true);
+					}
+					_ => {
+						let action = self.unknown_interrupt_action;
+						self.handle_unknown_function(machine, action, format!("Unknown video 0x11 func: 0x{:x}", func11));
+					}
+				}
+			}
+			0x12 => {
+				// Alternate function select
+				let func12 = machine.get_reg_u8(Reg::BX, RegHalf::Low);
+				match func12 {
+					0x30 => {
+						// Select vertical resolution: AL=0 is 200 lines, 1 is 350 and 2 is 400. The
+						// character height changes with it, so there are still as many rows of text.
+						// AL=12h says the function is supported.
+						// http://www.ctyme.com/intr/rb-0162.htm
+						let char_height = match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+							0 => Some(8),
+							1 => Some(14),
+							2 => Some(16),
+							_ => None,
+						};
+						if let Some(char_height) = char_height {
+							self.video_mode.char_pixel_dims.1 = char_height;
+							self.video_mode.pixel_dims.1 = char_height * self.video_mode.text_dims.1;
+							machine.set_data_u8(&BIOS_TEXT_LAST_ROW, (self.video_mode.text_dims.1 - 1) as u8);
+							machine.set_data_u16(&BIOS_CHAR_HEIGHT, char_height as u16);
+							if let Some(ref mut low_memory_guard) = self.low_memory_guard {
+								low_memory_guard.accept_bios_data(machine);
+							}
+							machine.set_reg_u8(Reg::AX, RegHalf::Low, 0x12);
+						}
+					}
+					0x34 => {
+						// Cursor emulation: AL=0 enables it, AL=1 disables it.
+						match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+							0 => self.cursor_emulation = true,
+							1 => self.cursor_emulation = false,
+							_ => {}
+						}
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, 0x12);
+					}
+					_ => {
+						let action = self.unknown_interrupt_action;
+						self.handle_unknown_function(machine, action, format!("Unknown video 0x12 func: 0x{:x}", func12));
+					}
+				}
+			}
+			0xfe => {
+				// Get the virtual screen buffer, for DESQview and TopView. Leaving ES:DI as it was
+				// tells the program there's no virtual screen, so it writes to video memory.
+				// http://www.ctyme.com/intr/rb-0305.htm
+			}
+			0xff => {
+				// Update the real screen from the virtual screen buffer. There isn't one, so there's
+				// nothing to do.
+				// http://www.ctyme.com/intr/rb-0306.htm
+			}
+			0xf0 ..= 0xfd => {
+				// Vendor extensions, like the EGA Register Interface Library at F0h-F7h. Programs
+				// tend to call these to check for something optional, so they're reported without
+				// changing any registers.
+				report_unknown_function(self.unknown_interrupt_action, &format!("Unhandled vendor video func: 0x{:x}", video_int));
+			}
+			_ => {
+				let action = self.unknown_interrupt_action;
+				self.handle_unknown_function(machine, action, format!("Unknown video func: 0x{:x}", video_int));
+			}
+		}
+	}
+	
+	fn handle_interrupt_13h(&mut self, machine: &mut dyn Machine) {
+		// Disk services (http://www.ctyme.com/intr/int-13.htm). Only floppy drive 0 is there, and
+		// only with an image in it. Hard disks (drive 80h and up) aren't modelled, since the C:
+		// drive is a DOS file system with no sectors behind it.
+		let disk_int = machine.get_reg_u8(Reg::AX, RegHalf::High);
+		let drive = machine.get_reg_u8(Reg::DX, RegHalf::Low);
+		let status_location = if drive & 0x80 == 0 { &BIOS_DISKETTE_STATUS } else { &BIOS_HARD_DISK_STATUS };
+		match disk_int {
+			0x01 => {
+				// Get the status of the last operation.
+				let status = machine.get_data_u8(status_location);
+				machine.set_reg_u8(Reg::AX, RegHalf::High, status);
+				machine.set_flag(Flag::Carry, status != DiskStatus::Ok as u8);
+				return;
+			}
+			0x15 => {
+				// Get the disk type: 2 is a floppy drive that can tell when the disk is changed, and 0
+				// is no drive. It doesn't change the status of the last operation.
+				let disk_type = if drive == 0 && self.floppy.is_some() { 2 } else { 0 };
+				machine.set_reg_u8(Reg::AX, RegHalf::High, disk_type);
+				machine.set_flag(Flag::Carry, false);
+				return;
+			}
+			0x00 | 0x02 | 0x03 | 0x08 => {}
+			_ => {
+				// The status is the BIOS's invalid function, in AH rather than AX.
+				let action = self.unknown_interrupt_action;
+				self.handle_unknown_function(machine, action, format!("Unknown disk func: 0x{:x}", disk_int));
+				machine.set_data_u8(status_location, DiskStatus::InvalidFunction as u8);
+				machine.set_reg_u8(Reg::AX, RegHalf::High, DiskStatus::InvalidFunction as u8);
+				return;
+			}
+		}
+		let result = match self.floppy {
+			Some(ref mut floppy) if drive == 0 => floppy_function(machine, floppy, disk_int),
+			// Nothing answers for an empty drive.
+			_ if drive & 0x80 == 0 => Err(DiskStatus::Timeout),
+			_ => Err(DiskStatus::InvalidFunction),
+		};
+		let status = result.err().unwrap_or(DiskStatus::Ok);
+		machine.set_data_u8(status_location, status as u8);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, status as u8);
+		machine.set_flag(Flag::Carry, status != DiskStatus::Ok);
+	}
+}
+
+// Runs an int 13h function on the floppy in drive 0. The sector functions take the cylinder in CH
+// (with its top two bits in the top of CL), the sector in the rest of CL, the head in DH, the
+// number of sectors in AL and the buffer at ES:BX, and set AL to the number of sectors transferred.
+fn floppy_function(machine: &mut dyn Machine, floppy: &mut FloppyImage, disk_int: u8) -> Result<(), DiskStatus> {
+	let cl = machine.get_reg_u8(Reg::CX, RegHalf::Low);
+	let cylinder = machine.get_reg_u8(Reg::CX, RegHalf::High) as u16 | ((cl as u16 & 0xc0) << 2);
+	let sector = cl & 0x3f;
+	let head = machine.get_reg_u8(Reg::DX, RegHalf::High);
+	let count = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+	let buffer_addr = machine.get_seg_reg(Reg::ES, Reg::BX) as usize;
+	let buffer_range = buffer_addr .. buffer_addr + count as usize * FLOPPY_SECTOR_BYTES;
+	match disk_int {
+		0x00 => {
+			// Reset the drive.
+		}
+		0x02 | 0x03 => {
+			if buffer_range.end > machine.memory().len() {
+				machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+				return Err(DiskStatus::InvalidFunction);
+			}
+			let result = if disk_int == 0x02 {
+				floppy.read_sectors(cylinder, head, sector, count).map(|data| machine.memory_mut()[buffer_range].copy_from_slice(data))
+			} else {
+				floppy.write_sectors(cylinder, head, sector, &machine.memory()[buffer_range])
+			};
+			// Sectors are transferred all together or not at all.
+			machine.set_reg_u8(Reg::AX, RegHalf::Low, if result.is_ok() { count } else { 0 });
+			result?;
+		}
+		0x08 => {
+			// Get the drive parameters, as the largest cylinder, head and sector numbers. ES:DI
+			// points at the diskette parameter table that int 1Eh points at, which describes a
+			// 1.44M drive whatever the disk.
+			let geometry = floppy.geometry();
+			let max_cylinder = geometry.cylinders - 1;
+			machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+			machine.set_reg_u8(Reg::BX, RegHalf::Low, geometry.drive_type);
+			machine.set_reg_u8(Reg::CX, RegHalf::High, max_cylinder as u8);
+			machine.set_reg_u8(Reg::CX, RegHalf::Low, geometry.sectors_per_track | ((max_cylinder >> 2) as u8 & 0xc0));
+			machine.set_reg_u8(Reg::DX, RegHalf::High, geometry.heads - 1);
+			// The number of floppy drives.
+			machine.set_reg_u8(Reg::DX, RegHalf::Low, 1);
+			let entry_addr = 0x1e * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+			let (table_offset, table_segment) = (machine.peek_u16(entry_addr), machine.peek_u16(entry_addr + 2));
+			machine.set_reg_u16(Reg::DI, table_offset);
+			machine.set_reg_u16(Reg::ES, table_segment);
+		}
+		// Unknown functions are reported before getting here.
+		_ => return Err(DiskStatus::InvalidFunction),
+	}
+	Ok(())
+}
+
+// How well each function is implemented, for the function audit. These must be kept up to date
+// with the dispatch code: any function not listed is treated as unimplemented.
+pub const INT_10H_SUPPORT: &[(u16, FunctionSupport)] = &[
+	// Only mode 3 exists.
+	(0x00, FunctionSupport::Partial),
+	(0x01, FunctionSupport::Full),
+	(0x02, FunctionSupport::Full),
+	(0x03, FunctionSupport::Full),
+	(0x04, FunctionSupport::Full),
+	(0x05, FunctionSupport::Full),
+	(0x06, FunctionSupport::Full),
+	(0x07, FunctionSupport::Full),
+	(0x08, FunctionSupport::Full),
+	// There are no graphics modes, so the CGA graphics palette can't be selected.
+	(0x0b, FunctionSupport::Partial),
+	// Ignores the page and colour, and doesn't beep.
+	(0x0e, FunctionSupport::Partial),
+	// Reports the current mode, but not bit 7 of AL for a mode that was set without clearing the
+	// screen.
+	(0x0f, FunctionSupport::Partial),
+	// Only the palette register and blink functions are there, and only the border colour is
+	// used when drawing.
+	(0x10, FunctionSupport::Partial),
+	(0x11, FunctionSupport::Partial),
+	(0x12, FunctionSupport::Partial),
+	// Vendor extensions, which are logged and ignored.
+	(0xf0, FunctionSupport::Partial), (0xf1, FunctionSupport::Partial), (0xf2, FunctionSupport::Partial),
+	(0xf3, FunctionSupport::Partial), (0xf4, FunctionSupport::Partial), (0xf5, FunctionSupport::Partial),
+	(0xf6, FunctionSupport::Partial), (0xf7, FunctionSupport::Partial), (0xf8, FunctionSupport::Partial),
+	(0xf9, FunctionSupport::Partial), (0xfa, FunctionSupport::Partial), (0xfb, FunctionSupport::Partial),
+	(0xfc, FunctionSupport::Partial), (0xfd, FunctionSupport::Partial),
+	// Reports that there's no virtual screen.
+	(0xfe, FunctionSupport::Full),
+	(0xff, FunctionSupport::Full),
+];
+// There's only a floppy drive, and only when an image is attached.
+pub const INT_13H_SUPPORT: &[(u16, FunctionSupport)] = &[
+	(0x00, FunctionSupport::Full),
+	(0x01, FunctionSupport::Full),
+	(0x02, FunctionSupport::Partial),
+	(0x03, FunctionSupport::Partial),
+	(0x08, FunctionSupport::Partial),
+	(0x15, FunctionSupport::Partial),
+];
+pub const INT_16H_SUPPORT: &[(u16, FunctionSupport)] = &[
+	(0x00, FunctionSupport::Full),
+	(0x01, FunctionSupport::Full),
+	(0x02, FunctionSupport::Full),
+	// Only resetting and setting the typematic rate and delay.
+	(0x03, FunctionSupport::Partial),
+	(0x05, FunctionSupport::Full),
+];
+pub const INT_1AH_SUPPORT: &[(u16, FunctionSupport)] = &[
+	(0x00, FunctionSupport::Full),
+	(0x01, FunctionSupport::Full),
+];
+pub const INT_21H_SUPPORT: &[(u16, FunctionSupport)] = &[
+	(0x02, FunctionSupport::Full),
+	(0x09, FunctionSupport::Full),
+	(0x0d, FunctionSupport::Full),
+	(0x0e, FunctionSupport::Full),
+	// The file date and time aren't filled in.
+	(0x0f, FunctionSupport::Partial),
+	// The file time and size aren't filled in, like AH=4Eh.
+	(0x11, FunctionSupport::Partial),
+	(0x12, FunctionSupport::Partial),
+	// A segment wrap is only reported when the record would go past the end of memory.
+	(0x14, FunctionSupport::Partial),
+	(0x15, FunctionSupport::Partial),
+	// The file date and time aren't filled in.
+	(0x16, FunctionSupport::Partial),
+	(0x19, FunctionSupport::Full),
+	(0x1a, FunctionSupport::Full),
+	(0x1b, FunctionSupport::Full),
+	(0x1c, FunctionSupport::Full),
+	(0x25, FunctionSupport::Full),
+	(0x26, FunctionSupport::Full),
+	(0x29, FunctionSupport::Full),
+	(0x2a, FunctionSupport::Full),
+	(0x2b, FunctionSupport::Full),
+	(0x2c, FunctionSupport::Full),
+	(0x2d, FunctionSupport::Full),
+	(0x30, FunctionSupport::Full),
+	// Ctrl+Break checking is always off, and only AL=00h, 01h and 05h are known.
+	(0x33, FunctionSupport::Partial),
+	(0x35, FunctionSupport::Full),
+	(0x36, FunctionSupport::Full),
+	// Only a few countries are known, and there's no case map routine.
+	(0x38, FunctionSupport::Partial),
+	(0x3b, FunctionSupport::Full),
+	(0x3c, FunctionSupport::Full),
+	(0x3d, FunctionSupport::Full),
+	(0x3e, FunctionSupport::Full),
+	(0x3f, FunctionSupport::Full),
+	(0x40, FunctionSupport::Full),
+	(0x41, FunctionSupport::Full),
+	(0x42, FunctionSupport::Full),
+	(0x43, FunctionSupport::Full),
+	// Only AL=00h, 06h, 07h, and 0Dh with CX=0866h and 0846h.
+	(0x44, FunctionSupport::Partial),
+	(0x45, FunctionSupport::Full),
+	// The console can only be forced into the standard handles and duplicates of the console.
+	(0x46, FunctionSupport::Partial),
+	(0x47, FunctionSupport::Full),
+	(0x48, FunctionSupport::Full),
+	(0x49, FunctionSupport::Full),
+	(0x4a, FunctionSupport::Full),
+	(0x4c, FunctionSupport::Full),
+	// The file time and size aren't filled in.
+	(0x4e, FunctionSupport::Partial),
+	(0x4f, FunctionSupport::Full),
+	// The drive parameter blocks, file table and current directory structures are empty.
+	(0x52, FunctionSupport::Partial),
+	(0x55, FunctionSupport::Full),
+	(0x5c, FunctionSupport::Full),
+	// Only single byte character sets.
+	(0x63, FunctionSupport::Partial),
+	(0x68, FunctionSupport::Full),
+];
+pub const INT_33H_SUPPORT: &[(u16, FunctionSupport)] = &[
+	(0x0000, FunctionSupport::Full),
+	(0x0001, FunctionSupport::Full),
+	(0x0002, FunctionSupport::Full),
+	(0x0003, FunctionSupport::Full),
+	(0x0004, FunctionSupport::Full),
+	(0x0007, FunctionSupport::Full),
+	(0x0008, FunctionSupport::Full),
+	// Only the software text cursor.
+	(0x000a, FunctionSupport::Partial),
+	(0x0010, FunctionSupport::Full),
+];
+
+// Gets the function number the audit records for a call to an interrupt, or None if the interrupt
+// isn't audited.
+fn audited_function(machine: &dyn Machine, interrupt_index: u8) -> Option<u16> {
+	match interrupt_index {
+		0x10 | 0x13 | 0x16 | 0x1a | 0x21 => Some(machine.get_reg_u8(Reg::AX, RegHalf::High) as u16),
+		0x33 => Some(machine.get_reg_u16(Reg::AX)),
+		_ => None,
+	}
+}
+
+pub fn function_support(interrupt_index: u8, function: u16) -> FunctionSupport {
+	let support_table = match interrupt_index {
+		0x10 => INT_10H_SUPPORT,
+		0x13 => INT_13H_SUPPORT,
+		0x16 => INT_16H_SUPPORT,
+		0x1a => INT_1AH_SUPPORT,
+		0x21 => INT_21H_SUPPORT,
+		0x33 => INT_33H_SUPPORT,
+		_ => &[],
+	};
+	match support_table.iter().find(|(supported_function, _)| *supported_function == function) {
+		Some((_, support)) => *support,
+		None => FunctionSupport::Unimplemented,
+	}
+}
+
+impl DosEventHandler {
+	/// Handles an interrupt the program called, on any Machine. The CPU calls this through
+	/// EventHandler::handle_interrupt.
+	pub fn dispatch_interrupt(&mut self, machine: &mut dyn Machine, mut interrupt_index: u8) {
+		// https://www.shsu.edu/~csc_tjm/spring2001/cs272/interrupt.html
+		//println!("Handle interrupt: 0x{:x}", interrupt_index);
+		self.result = DosInterruptResult::ShouldReturn;
+		
+		// The serviced interrupts' vectors point at stubs that call back in with this one, so a
+		// program can chain to them.
+		if interrupt_index == HANDLER_CALLBACK_INTERRUPT {
+			match handler_callback_interrupt(machine) {
+				Some(serviced_index) => interrupt_index = serviced_index,
+				None => {
+					let action = self.unknown_interrupt_action;
+					self.handle_unknown_function(machine, action, format!("Unknown interrupt: 0x{:x}", interrupt_index));
+					return;
+				}
+			}
+		}
+		
+		if !self.interrupt_hooks.is_empty() && run_interrupt_hooks(self, machine, interrupt_index) {
+			return;
+		}
+		
+		if let Some(ref mut function_audit) = self.function_audit {
+			if let Some(function) = audited_function(machine, interrupt_index) {
+				let support = function_support(interrupt_index, function);
+				function_audit.record(interrupt_index, function, support, machine.get_reg_u16(Reg::CS), machine.get_reg_u16(Reg::IP));
+				if support == FunctionSupport::Unimplemented {
+					// Keep going instead of panicking, so the rest of the program gets audited too.
+					machine.set_flag(Flag::Carry, true);
+					return;
+				}
+			}
+		}
+		
+		match interrupt_index {
+			// BIOS Interrupts (0x00-0x1F):
+			0x02 | 0x04 => {
+				// A non-maskable interrupt (a parity error on real hardware) or INTO with the overflow
+				// flag set. The BIOS just returns from these, and the registers are left alone since
+				// the program didn't call a function.
+				let description = if interrupt_index == 0x02 { "Non-maskable interrupt" } else { "Overflow interrupt" };
+				report_unknown_function(self.unknown_interrupt_action, description);
+			}
+			0x05 => {
+				// Print screen.
+				self.print_screen(machine);
+			}
+			0x08 => {
+				// Timer interrupt. This is supposed to be injected by an external source at the
+				// rate programmed into PIT channel 0, which is 18.2 times per second by default.
+				// TODO 777497
+				self.port_states.pit_total_clocks += self.port_states.pit_channel0_reload_clocks() as u64;
+				self.port_states.pit_channel0_reload_instruction = machine.number_of_parsed_instructions();
+				// The BIOS tick counter keeps counting at 18.2Hz even if the timer is sped up.
+				self.port_states.pit_bios_tick_clocks += self.port_states.pit_channel0_reload_clocks();
+				while self.port_states.pit_bios_tick_clocks >= PIT_STANDARD_RELOAD {
+					self.port_states.pit_bios_tick_clocks -= PIT_STANDARD_RELOAD;
+					add_bios_ticks(machine, 1);
+				}
+				// Emit user timer tick.
+				machine.interrupt_on_next_step(0x1c);
+			}
+			0x10 => {
+				let video_function = machine.get_reg_u8(Reg::AX, RegHalf::High);
+				if let Some(ref mut video_stats) = self.video_stats {
+					video_stats.sample(machine, OutputMethod::Direct);
+				}
 				self.handle_interrupt_10h(machine);
+				if let Some(ref mut video_stats) = self.video_stats {
+					video_stats.record_bios_call(machine, video_function);
+				}
+			}
+			0x13 => {
+				self.handle_interrupt_13h(machine);
 			}
 			0x14 => {
 				// Serial port services
-				let serial_int = machine.get_reg_u8(Reg::AX, RegHalf::High);
-				//println!("Serial port interrupt: {}", serial_int);
 			}
 			0x16 => {
 				// Keyboard driver
 				let key_int = machine.get_reg_u8(Reg::AX, RegHalf::High);
 				//println!("Keyboard Interrupt: 0x{:x}", key_int);
+				if key_int == 0x00 || key_int == 0x01 {
+					self.keyboard_polls += 1;
+				}
 				match key_int {
 					0x00 => {
 						// Wait for keypress and read character.
@@ -347,9 +2270,71 @@ impl EventHandler for DosEventHandler {
 						}
 					}
 					0x02 => {
-						machine.set_reg_u8(Reg::AX, RegHalf::Low, self.key_mod);
+						let key_mod = machine.get_data_u8(&BIOS_KEYBOARD_FLAGS);
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, key_mod);
+					}
+					0x03 => {
+						// Set the typematic rate and delay: AL=00h puts back the defaults, and
+						// AL=05h sets the rate from BL and the delay from BH. The other subfunctions
+						// are for the PCjr, and are ignored.
+						// http://stanislavs.org/helppc/int_16-3.html
+						match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+							0x00 => self.keyboard.typematic = Typematic::default(),
+							0x05 => {
+								self.keyboard.typematic = Typematic {
+									rate: machine.get_reg_u8(Reg::BX, RegHalf::Low) & 0x1f,
+									delay: machine.get_reg_u8(Reg::BX, RegHalf::High) & 0x03,
+								};
+							}
+							_ => {}
+						}
+					}
+					0x05 => {
+						// Store a keystroke as if it was typed, with the scan code in CH and the
+						// character in CL. AL=1 if the buffer is full.
+						// http://stanislavs.org/helppc/int_16-5.html
+						if self.key_press_queue.len() < BIOS_KEY_BUFFER_CAPACITY {
+							self.key_press_queue.push_back(KeyPressInfo {
+								scan_code: machine.get_reg_u8(Reg::CX, RegHalf::High),
+								ascii_char: machine.get_reg_u8(Reg::CX, RegHalf::Low),
+							});
+							machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+						} else {
+							machine.set_reg_u8(Reg::AX, RegHalf::Low, 1);
+						}
+					}
+					_ => {
+						let action = self.unknown_interrupt_action;
+						self.handle_unknown_function(machine, action, format!("Unknown keyboard interrupt: 0x{:x}", key_int));
+					}
+				}
+			}
+			0x1a => {
+				// Time of day services
+				// http://stanislavs.org/helppc/int_1a.html
+				let time_func = machine.get_reg_u8(Reg::AX, RegHalf::High);
+				match time_func {
+					0x00 => {
+						// Read the tick count into CX:DX. AL says whether midnight has passed since
+						// the last read, which only the first read after it is told.
+						let ticks = get_bios_ticks(machine);
+						machine.set_reg_u16(Reg::CX, (ticks >> 16) as u16);
+						machine.set_reg_u16(Reg::DX, ticks as u16);
+						let overflow = machine.get_data_u8(&BIOS_TIMER_OVERFLOW);
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, overflow);
+						machine.set_data_u8(&BIOS_TIMER_OVERFLOW, 0);
+					}
+					0x01 => {
+						// Set the tick count from CX:DX, which moves the DOS time with it.
+						let ticks = ((machine.get_reg_u16(Reg::CX) as u32) << 16) + machine.get_reg_u16(Reg::DX) as u32;
+						self.clock.set_bios_ticks(self.seconds_since_start, ticks);
+						set_bios_ticks(machine, ticks.min(BIOS_TICKS_PER_DAY - 1));
+						machine.set_data_u8(&BIOS_TIMER_OVERFLOW, 0);
+					}
+					_ => {
+						let action = self.unknown_interrupt_action;
+						self.handle_unknown_function(machine, action, format!("Unknown time of day interrupt: 0x{:x}", time_func));
 					}
-					_ => panic!("Unknown keyboard interrupt: 0x{:x}", key_int)
 				}
 			}
 			0x1c => {
@@ -359,46 +2344,197 @@ impl EventHandler for DosEventHandler {
 			// This is the DOS interrupt.
 			// http://spike.scu.edu.au/~barry/interrupts.html
 			// http://stanislavs.org/helppc/int_21.html
+			0x20 => {
+				// Terminate the program, with an exit code of 0.
+				// http://stanislavs.org/helppc/int_20.html
+				self.terminate_program(machine, 0);
+			}
 			0x21 => {
 				let dos_int = machine.get_reg_u8(Reg::AX, RegHalf::High);
 				//println!("DOS Interrupt: 0x{:x}", dos_int);
 				match dos_int {
+					0x02 => {
+						// Write the character in DL to standard output.
+						let c = machine.get_reg_u8(Reg::DX, RegHalf::Low);
+						self.write_dos_output_char(machine, c);
+					}
+					0x09 => {
+						// Write the '$' terminated string at DS:DX to standard output. A string that is
+						// missing its '$' stops at the end of the segment or the end of memory.
+						let start_addr = machine.get_seg_reg(Reg::DS, Reg::DX) as usize;
+						let segment_bytes = 0x10000 - machine.get_reg_u16(Reg::DX) as usize;
+						let end_addr = (start_addr + segment_bytes).min(machine.memory().len());
+						let text: Vec<u8> = machine.memory()[start_addr.min(end_addr)..end_addr].iter()
+							.take_while(|&&c| c != b'$')
+							.cloned()
+							.collect();
+						for c in text {
+							self.write_dos_output_char(machine, c);
+						}
+					}
+					0x0d => {
+						// Disk reset, which writes all the open files out to disk. There's no way to
+						// report an error, so it's only logged.
+						if let Err(error_code) = self.file_system.flush_all() {
+							eprintln!("Failed to flush files: {:?}", error_code);
+						}
+						machine.set_flag(Flag::Carry, false);
+					}
+					0x0e => {
+						// Select the current drive, and get the number of drives.
+						let drive = machine.get_reg_u8(Reg::DX, RegHalf::Low);
+						// An invalid drive is ignored.
+						self.file_system.set_current_drive(drive).ok();
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, self.file_system.drive_count());
+					}
+					0x0f | 0x16 => {
+						// Open (0x0f) or create (0x16) the file named by the FCB at DS:DX. AL is 0 if
+						// it worked.
+						let result = self.open_fcb_file(machine, dos_int == 0x16);
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, if result.is_ok() { 0 } else { 0xff });
+					}
+					0x11 | 0x12 => {
+						// Find the first (0x11) or next (0x12) file matching the FCB at DS:DX, and
+						// write it to the DTA as an unopened FCB. AL is 0 if one was found.
+						let found = self.find_fcb_file(machine, dos_int == 0x11);
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, if found { 0 } else { 0xff });
+					}
+					0x14 | 0x15 => {
+						// Read (0x14) or write (0x15) the FCB at DS:DX's current record through the
+						// DTA, and move on to the next record.
+						let status = self.fcb_sequential_io(machine, dos_int == 0x15);
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, status);
+					}
+					0x19 => {
+						// Get the current drive.
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, self.file_system.current_drive());
+					}
+					0x1b | 0x1c => {
+						// Get allocation information for the default drive (0x1b) or the drive in DL
+						// (0x1c, where 0 is the default drive and 1 is A:).
+						let dl = if dos_int == 0x1b { 0 } else { machine.get_reg_u8(Reg::DX, RegHalf::Low) };
+						let drive = if dl == 0 { self.file_system.current_drive() } else { dl - 1 };
+						match self.file_system.drive_volume_info(drive) {
+							Ok(volume_info) => {
+								let geometry = volume_info.disk_geometry();
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, geometry.sectors_per_cluster as u8);
+								machine.set_reg_u16(Reg::CX, geometry.bytes_per_sector);
+								machine.set_reg_u16(Reg::DX, geometry.total_clusters);
+								machine.set_reg_u16(Reg::DS, DOS_DATA_SEGMENT);
+								machine.set_reg_u16(Reg::BX, DOS_MEDIA_DESCRIPTOR_OFFSET);
+							}
+							Err(_) => {
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, 0xff);
+							}
+						}
+					}
 					0x1a => {
 						// Set the Disk Transfer Address
 						self.disk_trasnsfer_address = machine.get_seg_reg(Reg::DS, Reg::DX);
 					}
 					0x25 => {
-						// Get ES:BX and store it as an entry of the interrupt vector/table (as the IP:CS).
-						let entry_addr = machine.get_reg_u8(Reg::AX, RegHalf::Low) as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+						// Store DS:DX as the entry of the interrupt vector table for the interrupt in AL.
+						// Each entry is the IP followed by the CS, which is the order the CPU reads
+						// them in when it calls the interrupt.
+						let interrupt_index = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+						let entry_addr = interrupt_index as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
 						let interrupt_ip = machine.get_reg_u16(Reg::DX);
 						let interrupt_cs = machine.get_reg_u16(Reg::DS);
 						machine.poke_u16(entry_addr, interrupt_ip);
 						machine.poke_u16(entry_addr + 2, interrupt_cs);
+						if let Some(ref mut low_memory_guard) = self.low_memory_guard {
+							low_memory_guard.accept_vector(machine, interrupt_index);
+						}
+					}
+					0x26 => {
+						// Create a PSP at segment DX, copied from the program's PSP.
+						let psp_segment = machine.get_reg_u16(Reg::DX);
+						self.create_program_segment_prefix(machine, psp_segment, PSP_HANDLE_TABLE_ENTRIES, false);
+					}
+					0x29 => {
+						// Parse the file name at DS:SI into the FCB at ES:DI, with the parsing
+						// options in AL. DS:SI is left pointing after the file name.
+						let flags = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+						// File names can't be longer than a command line, and like AH=09h the name
+						// stops at the end of memory. An FCB that doesn't fit isn't written.
+						let memory_bytes = machine.memory().len();
+						let input_addr = (machine.get_seg_reg(Reg::DS, Reg::SI) as usize).min(memory_bytes);
+						let input = machine.memory()[input_addr .. (input_addr + 0x80).min(memory_bytes)].to_vec();
+						let file_system = &self.file_system;
+						let parsed = parse_fcb_filename(&input, flags, &|drive| file_system.is_valid_drive(drive - 1));
+						let fcb_addr = machine.get_seg_reg(Reg::ES, Reg::DI) as usize;
+						if let Some(fcb) = machine.memory_mut().get_mut(fcb_addr .. fcb_addr + 12) {
+							parsed.write_to_fcb(fcb, flags);
+						}
+						let si = machine.get_reg_u16(Reg::SI);
+						machine.set_reg_u16(Reg::SI, si.wrapping_add(parsed.parsed_len as u16));
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, parsed.result_code());
+					}
+					0x2a => {
+						// Get the date: the year in CX, month in DH, day in DL and the day of the week
+						// in AL, with 0 for Sunday.
+						let date = self.clock.date(self.seconds_since_start);
+						machine.set_reg_u16(Reg::CX, date.year);
+						machine.set_reg_u8(Reg::DX, RegHalf::High, date.month);
+						machine.set_reg_u8(Reg::DX, RegHalf::Low, date.day);
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, date.day_of_week());
+					}
+					0x2b => {
+						// Set the date from the same registers. AL is FFh if the date doesn't exist.
+						let year = machine.get_reg_u16(Reg::CX);
+						let month = machine.get_reg_u8(Reg::DX, RegHalf::High);
+						let day = machine.get_reg_u8(Reg::DX, RegHalf::Low);
+						match DosDate::new(year, month, day) {
+							Some(date) => {
+								self.clock.set_date(self.seconds_since_start, date);
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+							}
+							None => machine.set_reg_u8(Reg::AX, RegHalf::Low, 0xff),
+						}
 					}
 					0x2c => {
-						// TODO 776406
 						// Get system time.
-						let hundredths = ((self.seconds_since_start * 100.) as usize % 100) as u8;
-						let second = (self.seconds_since_start as usize % 60) as u8;
-						let minute = ((self.seconds_since_start / 60.) as usize % 60) as u8;
-						let hour = ((self.seconds_since_start / 60. / 60.) as usize % 24) as u8;
-						machine.set_reg_u8(Reg::CX, RegHalf::High, hour);
-						machine.set_reg_u8(Reg::CX, RegHalf::Low, minute);
-						machine.set_reg_u8(Reg::DX, RegHalf::High, second);
-						machine.set_reg_u8(Reg::DX, RegHalf::Low, hundredths);
+						let time = self.clock.time(self.seconds_since_start);
+						machine.set_reg_u8(Reg::CX, RegHalf::High, time.hour);
+						machine.set_reg_u8(Reg::CX, RegHalf::Low, time.minute);
+						machine.set_reg_u8(Reg::DX, RegHalf::High, time.second);
+						machine.set_reg_u8(Reg::DX, RegHalf::Low, time.hundredths);
 						self.result = DosInterruptResult::ShouldReturnAndWaitForEvents;
 					}
+					0x2d => {
+						// Set the time from the same registers. AL is FFh if the time doesn't exist.
+						// The BIOS tick count is set to match, as DOS does through int 1Ah AH=01h.
+						let time = DosTime::new(
+							machine.get_reg_u8(Reg::CX, RegHalf::High), machine.get_reg_u8(Reg::CX, RegHalf::Low),
+							machine.get_reg_u8(Reg::DX, RegHalf::High), machine.get_reg_u8(Reg::DX, RegHalf::Low),
+						);
+						match time {
+							Some(time) => {
+								self.clock.set_time(self.seconds_since_start, time);
+								self.sync_bios_ticks_to_clock(machine);
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+							}
+							None => machine.set_reg_u8(Reg::AX, RegHalf::Low, 0xff),
+						}
+					}
 					0x30 => {
-						machine.set_reg_u8(Reg::AX, RegHalf::Low, 5);
-						machine.set_reg_u8(Reg::AX, RegHalf::High, 22);
+						// Get the DOS version, with the major version in AL and the minor in AH.
+						machine.set_reg_u8(Reg::AX, RegHalf::Low, self.dos_version.0);
+						machine.set_reg_u8(Reg::AX, RegHalf::High, self.dos_version.1);
 						machine.set_reg_u8(Reg::BX, RegHalf::High, 0xff);
 						machine.set_reg_u8(Reg::BX, RegHalf::Low, 0);
 						machine.set_reg_u16(Reg::CX, 0);
 					}
 					0x33 => {
-						// Modify Ctrl+Break shortcut functionality.
-						// TODO
-						machine.set_reg_u8(Reg::DX, RegHalf::Low, 0);
+						// Get (AL=00h) or set (AL=01h) whether DOS checks for Ctrl+Break, which it
+						// never does here, so the state is always off in DL. AL=05h gets the boot
+						// drive in DL, which is C:. Other sub-functions return FFh in AL.
+						match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+							0x00 => machine.set_reg_u8(Reg::DX, RegHalf::Low, 0),
+							0x01 => {}
+							0x05 => machine.set_reg_u8(Reg::DX, RegHalf::Low, 3),
+							_ => machine.set_reg_u8(Reg::AX, RegHalf::Low, 0xff),
+						}
 					}
 					0x35 => {
 						// Get an entry of the interrupt vector/table (IP:CS) and store it in ES:BX.
@@ -408,6 +2544,66 @@ impl EventHandler for DosEventHandler {
 						machine.set_reg_u16(Reg::BX, interrupt_ip);
 						machine.set_reg_u16(Reg::ES, interrupt_cs);
 					}
+					0x36 => {
+						// Get free disk space for the drive in DL (0 is the default drive, 1 is A:).
+						let dl = machine.get_reg_u8(Reg::DX, RegHalf::Low);
+						let drive = if dl == 0 { self.file_system.current_drive() } else { dl - 1 };
+						match self.file_system.drive_volume_info(drive) {
+							Ok(volume_info) => {
+								let geometry = volume_info.disk_geometry();
+								machine.set_reg_u16(Reg::AX, geometry.sectors_per_cluster);
+								machine.set_reg_u16(Reg::BX, geometry.free_clusters);
+								machine.set_reg_u16(Reg::CX, geometry.bytes_per_sector);
+								machine.set_reg_u16(Reg::DX, geometry.total_clusters);
+							}
+							Err(_) => {
+								machine.set_reg_u16(Reg::AX, 0xffff);
+							}
+						}
+					}
+					0x38 => {
+						// Get the info for a country into the buffer at DS:DX, or make it the current
+						// country if DX is FFFFh. AL is the country code, or FFh for the code in BX,
+						// or 0 for the current country. The country code is returned in BX.
+						let al = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+						let country_code = if al == 0xff { machine.get_reg_u16(Reg::BX) } else { al as u16 };
+						let country_info = if country_code == 0 || country_code == self.country_info.country_code {
+							Some(self.country_info.clone())
+						} else {
+							CountryInfo::for_country_code(country_code)
+						};
+						match country_info {
+							Some(country_info) => {
+								machine.set_reg_u16(Reg::BX, country_info.country_code);
+								if machine.get_reg_u16(Reg::DX) == 0xffff {
+									self.country_info = country_info;
+								} else {
+									let buffer_addr = machine.get_seg_reg(Reg::DS, Reg::DX);
+									machine.insert_contiguous_bytes(&country_info.to_bytes(), buffer_addr as usize);
+								}
+								machine.set_flag(Flag::Carry, false);
+							}
+							None => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, DosErrorCode::FileNotFound as u16);
+							}
+						}
+					}
+					0x3b => {
+						// Change the current directory to the path at DS:DX.
+						let path_addr = machine.get_seg_reg(Reg::DS, Reg::DX);
+						let path = machine.read_null_terminated_string(path_addr);
+						match self.file_system.set_current_directory(&path) {
+							Ok(()) => {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0);
+							}
+							Err(error_code) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+							}
+						}
+					}
 					0x3c => {
 						// CREATE
 						let filename_addr = machine.get_seg_reg(Reg::DS, Reg::DX);
@@ -428,7 +2624,9 @@ impl EventHandler for DosEventHandler {
 						// OPEN
 						let filename_addr = machine.get_seg_reg(Reg::DS, Reg::DX);
 						let filename = machine.read_null_terminated_string(filename_addr);
-						let access_mode = match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+						// Bit 7 of the mode stops child processes inheriting the handle.
+						let mode = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+						let access_mode = match mode & 0x7f {
 							0 => Some(DosFileAccessMode::ReadOnly),
 							1 => Some(DosFileAccessMode::WriteOnly),
 							2 => Some(DosFileAccessMode::ReadWrite),
@@ -436,8 +2634,18 @@ impl EventHandler for DosEventHandler {
 						};
 						
 						if let Some(access_mode) = access_mode {
-							match self.file_system.open(&filename, access_mode) {
+							let opened = if dos_device_name(&filename) == Some(&b"CLOCK$"[..]) {
+								Ok(self.open_clock_device())
+							} else {
+								self.file_system.open(&filename, access_mode)
+							};
+							match opened {
 								Ok(handle) => {
+									if mode & 0x80 != 0 {
+										self.no_inherit.insert(handle);
+									} else {
+										self.no_inherit.remove(&handle);
+									}
 									machine.set_flag(Flag::Carry, false);
 									machine.set_reg_u16(Reg::AX, handle);
 								}
@@ -454,9 +2662,24 @@ impl EventHandler for DosEventHandler {
 					0x3e => {
 						// CLOSE
 						let handle = machine.get_reg_u16(Reg::BX);
-						match self.file_system.close(handle) {
+						let closed = if self.clock_device_handles.remove(&handle) || self.console_duplicate_handles.remove(&handle).is_some() {
+							Ok(())
+						} else if let Some(file_handle) = self.redirected_std_handles.remove(&handle) {
+							self.file_locks.remove_handle(file_handle);
+							self.file_system.close(file_handle)
+						} else {
+							self.file_locks.remove_handle(handle);
+							self.file_system.close(handle)
+						};
+						match closed {
 							Ok(_) => {
+								self.no_inherit.remove(&handle);
+								// Nothing is returned in AX, but some programs check for it being 0
+								// rather than checking the carry flag, so it mustn't be left holding
+								// the function number. The same goes for the other file functions
+								// that don't return anything.
 								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0);
 							}
 							Err(error_code) => {
 								machine.set_flag(Flag::Carry, true);
@@ -466,17 +2689,33 @@ impl EventHandler for DosEventHandler {
 					}
 					0x3f => {
 						// READ
-						let handle = machine.get_reg_u16(Reg::BX);
-						let count = machine.get_reg_u16(Reg::CX) as usize;
-						let destination_addr = machine.get_seg_reg(Reg::DS, Reg::DX) as usize;
-						let rest_of_mem = &mut machine.memory[destination_addr..];
+						let handle = self.file_handle(machine.get_reg_u16(Reg::BX));
+						let requested_count = machine.get_reg_u16(Reg::CX) as usize;
+						let destination_addr = (machine.get_seg_reg(Reg::DS, Reg::DX) as usize).min(machine.memory().len());
+						let rest_of_mem = &mut machine.memory_mut()[destination_addr..];
+						// A read into the top of memory stops at the end of it, and returns the short
+						// count like a read that reaches the end of the file, rather than failing.
+						let count = requested_count.min(rest_of_mem.len());
 						
-						if rest_of_mem.len() < count {
-							machine.set_flag(Flag::Carry, true);
-							machine.set_reg_u16(Reg::AX, DosErrorCode::InsufficientMemory as u16);
+						if handle == 0 {
+							// Handle 0 is never a file, so it's always the console.
+							if let Some(read_count) = self.read_console(machine, destination_addr as u32, count) {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, read_count);
+							}
+						} else if self.clock_device_handles.contains(&handle) {
+							// Every read gets the record from the start.
+							let record = self.clock.device_record(self.seconds_since_start);
+							let read_count = count.min(CLOCK_DEVICE_RECORD_BYTES);
+							rest_of_mem[..read_count].copy_from_slice(&record[..read_count]);
+							machine.set_flag(Flag::Carry, false);
+							machine.set_reg_u16(Reg::AX, read_count as u16);
 						} else {
-							let destination = &mut rest_of_mem[..count];
-							match self.file_system.read(handle, destination) {
+							let read_result = self.check_file_lock_access(handle, count).and_then(|()| {
+								let destination = &mut machine.memory_mut()[destination_addr..destination_addr + count];
+								self.file_system.read(handle, destination)
+							});
+							match read_result {
 								Ok(read_count) => {
 									machine.set_flag(Flag::Carry, false);
 									machine.set_reg_u16(Reg::AX, read_count);
@@ -490,14 +2729,31 @@ impl EventHandler for DosEventHandler {
 					}
 					0x40 => {
 						// WRITE
-						let handle = machine.get_reg_u16(Reg::BX);
+						let handle = self.file_handle(machine.get_reg_u16(Reg::BX));
 						let count = machine.get_reg_u16(Reg::CX) as usize;
 						let destination_addr = machine.get_seg_reg(Reg::DS, Reg::DX) as usize;
-						let rest_of_mem = &mut machine.memory[destination_addr..];
+						let rest_of_mem = &mut machine.memory_mut()[destination_addr..];
 						
 						if rest_of_mem.len() < count {
 							machine.set_flag(Flag::Carry, true);
 							machine.set_reg_u16(Reg::AX, DosErrorCode::InvalidData as u16);
+						} else if handle == 1 || handle == 2 {
+							// Stdout and stderr are the console.
+							for addr in destination_addr .. destination_addr + count {
+								let c = machine.memory()[addr];
+								self.write_dos_output_char(machine, c);
+							}
+							machine.set_flag(Flag::Carry, false);
+							machine.set_reg_u16(Reg::AX, count as u16);
+						} else if self.clock_device_handles.contains(&handle) {
+							if self.clock.set_from_device_record(self.seconds_since_start, &rest_of_mem[..count]) {
+								self.sync_bios_ticks_to_clock(machine);
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, count as u16);
+							} else {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, DosErrorCode::InvalidData as u16);
+							}
 						} else {
 							if count == 0 {
 								// Count of 0 truncates or extends the file to the current position.
@@ -512,8 +2768,11 @@ impl EventHandler for DosEventHandler {
 									}
 								}
 							} else {
-								let write_data = &rest_of_mem[..count];
-								match self.file_system.write(handle, write_data) {
+								let write_result = self.check_file_lock_access(handle, count).and_then(|()| {
+									let write_data = &machine.memory()[destination_addr..destination_addr + count];
+									self.file_system.write(handle, write_data)
+								});
+								match write_result {
 									Ok(read_count) => {
 										machine.set_flag(Flag::Carry, false);
 										machine.set_reg_u16(Reg::AX, read_count);
@@ -526,59 +2785,280 @@ impl EventHandler for DosEventHandler {
 							}
 						}
 					}
-					0x42 => {
-						// SEEK
-						let handle = machine.get_reg_u16(Reg::BX);
-						let offset = ((machine.get_reg_u16(Reg::CX) as u32) << 16) + machine.get_reg_u16(Reg::DX) as u32;
-						let origin_mode = match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
-							0 => Some(DosFileSeekOrigin::Start),
-							1 => Some(DosFileSeekOrigin::Current),
-							2 => Some(DosFileSeekOrigin::End),
-							_ => None,
-						};
-						if let Some(origin_mode) = origin_mode {
-							match self.file_system.seek(handle, offset, origin_mode) {
-								Ok(new_file_position) => {
-									machine.set_flag(Flag::Carry, false);
-									machine.set_reg_u16(Reg::AX, (new_file_position & 0xffff) as u16);
-									machine.set_reg_u16(Reg::DX, ((new_file_position >> 16) & 0xffff) as u16);
-								}
-								Err(error_code) => {
-									machine.set_flag(Flag::Carry, true);
-									machine.set_reg_u16(Reg::AX, error_code as u16);
-								}
+					0x41 => {
+						// DELETE the file named at DS:DX.
+						let filename_addr = machine.get_seg_reg(Reg::DS, Reg::DX);
+						let filename = machine.read_null_terminated_string(filename_addr);
+						match self.file_system.delete(&filename) {
+							Ok(()) => {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0);
+							}
+							Err(error_code) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+							}
+						}
+					}
+					0x42 => {
+						// SEEK
+						let handle = self.file_handle(machine.get_reg_u16(Reg::BX));
+						let offset = ((machine.get_reg_u16(Reg::CX) as u32) << 16) + machine.get_reg_u16(Reg::DX) as u32;
+						let origin_mode = match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+							0 => Some(DosFileSeekOrigin::Start),
+							1 => Some(DosFileSeekOrigin::Current),
+							2 => Some(DosFileSeekOrigin::End),
+							_ => None,
+						};
+						if let Some(origin_mode) = origin_mode {
+							// Seeking by 0 from the current position is how programs get the
+							// position, which doesn't need to move the file. CLOCK$ is a device, so
+							// it stays at 0.
+							let result = if self.clock_device_handles.contains(&handle) {
+								Ok(0)
+							} else if origin_mode == DosFileSeekOrigin::Current && offset == 0 {
+								self.file_system.tell(handle)
+							} else {
+								self.file_system.seek(handle, offset, origin_mode)
+							};
+							match result {
+								Ok(new_file_position) => {
+									machine.set_flag(Flag::Carry, false);
+									machine.set_reg_u16(Reg::AX, (new_file_position & 0xffff) as u16);
+									machine.set_reg_u16(Reg::DX, ((new_file_position >> 16) & 0xffff) as u16);
+								}
+								Err(error_code) => {
+									machine.set_flag(Flag::Carry, true);
+									machine.set_reg_u16(Reg::AX, error_code as u16);
+								}
+							}
+						} else {
+							machine.set_flag(Flag::Carry, true);
+							machine.set_reg_u16(Reg::AX, DosErrorCode::InvalidFunction as u16);
+						}
+					}
+					0x43 => {
+						// Get (AL=00h) the attributes of the file named at DS:DX into CX, or set
+						// (AL=01h) them to CX.
+						let filename_addr = machine.get_seg_reg(Reg::DS, Reg::DX);
+						let filename = machine.read_null_terminated_string(filename_addr);
+						let result = match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+							0x00 => self.file_system.get_attributes(&filename).map(|attributes| {
+								machine.set_reg_u16(Reg::CX, attributes);
+							}),
+							0x01 => self.file_system.set_attributes(&filename, machine.get_reg_u16(Reg::CX)),
+							_ => Err(DosErrorCode::InvalidFunction),
+						};
+						match result {
+							Ok(()) => {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0);
+							}
+							Err(error_code) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+							}
+						}
+					}
+					0x44 => {
+						// I/O control
+						let io_func = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+						match io_func {
+							0 => {
+								// Get the device information in DX.
+								match self.device_info(machine.get_reg_u16(Reg::BX)) {
+									Ok(info) => {
+										machine.set_flag(Flag::Carry, false);
+										machine.set_reg_u16(Reg::DX, info);
+										machine.set_reg_u16(Reg::AX, info);
+									}
+									Err(error_code) => {
+										machine.set_flag(Flag::Carry, true);
+										machine.set_reg_u16(Reg::AX, error_code as u16);
+									}
+								}
+							}
+							6 => {
+								// Get input status. Handle 0 is the keyboard, and files have input
+								// available until the end is reached.
+								let handle = self.file_handle(machine.get_reg_u16(Reg::BX));
+								let ready = if handle == 0 {
+									self.keyboard_polls += 1;
+									Ok(!self.key_press_queue.is_empty())
+								} else if self.clock_device_handles.contains(&handle) {
+									Ok(true)
+								} else {
+									self.file_system.is_at_end(handle).map(|at_end| !at_end)
+								};
+								match ready {
+									Ok(ready) => {
+										machine.set_flag(Flag::Carry, false);
+										machine.set_flag(Flag::Zero, !ready);
+										machine.set_reg_u8(Reg::AX, RegHalf::Low, if ready { 0xff } else { 0 });
+									}
+									Err(error_code) => {
+										machine.set_flag(Flag::Carry, true);
+										machine.set_reg_u16(Reg::AX, error_code as u16);
+									}
+								}
+							}
+							7 => {
+								// Get output status. Stdout, stderr and files are always ready.
+								let handle = self.file_handle(machine.get_reg_u16(Reg::BX));
+								let valid = if handle == 1 || handle == 2 || self.clock_device_handles.contains(&handle) {
+									Ok(())
+								} else {
+									self.file_system.is_at_end(handle).map(|_| ())
+								};
+								match valid {
+									Ok(()) => {
+										machine.set_flag(Flag::Carry, false);
+										machine.set_flag(Flag::Zero, false);
+										machine.set_reg_u8(Reg::AX, RegHalf::Low, 0xff);
+									}
+									Err(error_code) => {
+										machine.set_flag(Flag::Carry, true);
+										machine.set_reg_u16(Reg::AX, error_code as u16);
+									}
+								}
+							}
+							0x0d => {
+								match self.handle_generic_block_ioctl(machine) {
+									Ok(()) => machine.set_flag(Flag::Carry, false),
+									Err(error_code) => {
+										machine.set_flag(Flag::Carry, true);
+										machine.set_reg_u16(Reg::AX, error_code as u16);
+									}
+								}
+							}
+							_ => {
+								let action = self.unknown_dos_func_action;
+								self.handle_unknown_function(machine, action, format!("Unknown IO func: 0x{:x}", io_func));
+							}
+						}
+					}
+					0x45 => {
+						// Duplicate the handle in BX, returning the new handle in AX. The new handle
+						// shares the file's position, or acts as the console if BX is the console.
+						let handle = self.file_handle(machine.get_reg_u16(Reg::BX));
+						let duplicated = if handle < DOS_FIRST_FILE_HANDLE {
+							Ok(self.duplicate_console_handle(handle))
+						} else if self.clock_device_handles.contains(&handle) {
+							Ok(self.open_clock_device())
+						} else {
+							self.file_system.duplicate(handle).inspect(|&new_handle| self.file_locks.duplicate_handle(handle, new_handle))
+						};
+						match duplicated {
+							Ok(new_handle) => {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, new_handle);
+							}
+							Err(error_code) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+							}
+						}
+					}
+					0x46 => {
+						// Force the handle in CX to refer to the same file as the handle in BX,
+						// closing what it referred to first. This is how programs redirect their own
+						// output, by making stdout (handle 1) a duplicate of a file. The standard
+						// handles are the console rather than files, so which of them refer to files
+						// is kept here, and making one a duplicate of a handle that's the console puts
+						// it back to the console.
+						let handle = machine.get_reg_u16(Reg::BX);
+						let new_handle = machine.get_reg_u16(Reg::CX);
+						match self.force_duplicate_handle(handle, new_handle) {
+							Ok(()) => {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0);
+							}
+							Err(error_code) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+							}
+						}
+					}
+					0x47 => {
+						// Get the current directory of the drive in DL (0 is the default drive, 1 is
+						// A:) into the 64 byte buffer at DS:SI, without the drive or leading backslash.
+						let dl = machine.get_reg_u8(Reg::DX, RegHalf::Low);
+						let drive = if dl == 0 { self.file_system.current_drive() } else { dl - 1 };
+						match self.file_system.drive_current_directory(drive) {
+							Ok(mut current_directory) => {
+								current_directory.truncate(63);
+								current_directory.push(0);
+								let buffer_addr = machine.get_seg_reg(Reg::DS, Reg::SI);
+								machine.insert_contiguous_bytes(&current_directory, buffer_addr as usize);
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0x0100);
+							}
+							Err(error_code) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
 							}
-						} else {
-							machine.set_flag(Flag::Carry, true);
-							machine.set_reg_u16(Reg::AX, DosErrorCode::InvalidData as u16);
 						}
 					}
-					0x44 => {
-						// I/O control
-						let io_func = machine.get_reg_u8(Reg::AX, RegHalf::Low);
-						match io_func {
-							0 => {
-								// Get device information
-								// TODO
-								machine.set_reg_u16(Reg::AX, 1);
+					0x48 => {
+						// Allocate BX paragraphs of memory, returning its segment in AX. If there
+						// isn't enough, BX is the most that could be allocated.
+						let paragraphs = machine.get_reg_u16(Reg::BX);
+						match dos_memory::allocate(machine, PROGRAM_PSP_SEGMENT, paragraphs) {
+							Ok(segment) => {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, segment);
+							}
+							Err((error_code, largest)) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+								machine.set_reg_u16(Reg::BX, largest);
+							}
+						}
+					}
+					0x49 => {
+						// Free the memory block at segment ES.
+						let segment = machine.get_reg_u16(Reg::ES);
+						match dos_memory::free(machine, segment) {
+							Ok(()) => {
+								machine.set_flag(Flag::Carry, false);
+							}
+							Err(error_code) => {
 								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
 							}
-							_ => println!("Unknown IO func: 0x{:x}", io_func)
 						}
 					}
 					0x4a => {
-						// Modify Allocated Memory Block (SETBLOCK)
-						unimplemented!();
+						// Resize the memory block at segment ES to BX paragraphs. If it can't grow
+						// that much, BX is the most it could have.
+						let segment = machine.get_reg_u16(Reg::ES);
+						let paragraphs = machine.get_reg_u16(Reg::BX);
+						match dos_memory::resize(machine, segment, paragraphs) {
+							Ok(()) => {
+								machine.set_flag(Flag::Carry, false);
+							}
+							Err((error_code, largest)) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+								machine.set_reg_u16(Reg::BX, largest);
+							}
+						}
+					}
+					0x4c => {
+						// Terminate the program with the return code in AL.
+						let exit_code = machine.get_reg_u8(Reg::AX, RegHalf::Low);
+						self.terminate_program(machine, exit_code);
 					}
 					0x4e => {
-						// Find first matching file for a filename glob.
+						// Find first matching file for a filename glob. The rest of the files are
+						// kept with the DTA for AH=4Fh.
 						let file_attributes = machine.get_reg_u16(Reg::CX);
 						let search_spec_addr = machine.get_seg_reg(Reg::DS, Reg::DX);
 						let search_spec = machine.read_null_terminated_string(search_spec_addr);
-						let rest_of_mem = &mut machine.memory[self.disk_trasnsfer_address as usize..];
-						match self.file_system.find_first_file(rest_of_mem, file_attributes, &search_spec) {
-							Ok(()) => {
-								machine.set_flag(Flag::Carry, false);
+						match self.find_all_files(file_attributes, &search_spec) {
+							Ok(found_files) => {
+								self.start_file_search(self.disk_trasnsfer_address, found_files);
+								self.write_next_found_file(machine);
 							}
 							Err(error_code) => {
 								machine.set_flag(Flag::Carry, true);
@@ -587,11 +3067,83 @@ impl EventHandler for DosEventHandler {
 						}
 					}
 					0x4f => {
-						// Find next matching file after the last 0x4e/0x4f interrupt.
-						let rest_of_mem = &mut machine.memory[self.disk_trasnsfer_address as usize..];
-						match self.file_system.find_next_file(rest_of_mem) {
+						// Find the next matching file of the search made with the current DTA.
+						self.write_next_found_file(machine);
+					}
+					0x52 => {
+						// Get the list of lists in ES:BX, which has the segment of the first MCB
+						// just before it.
+						machine.set_reg_u16(Reg::ES, DOS_TABLES_SEGMENT);
+						machine.set_reg_u16(Reg::BX, DOS_LIST_OF_LISTS_OFFSET);
+					}
+					0x55 => {
+						// Create a child PSP at segment DX, with a handle table of SI entries.
+						let psp_segment = machine.get_reg_u16(Reg::DX);
+						let handle_table_size = machine.get_reg_u16(Reg::SI);
+						self.create_program_segment_prefix(machine, psp_segment, handle_table_size, true);
+					}
+					0x5c => {
+						// Lock (AL=00h) or unlock (AL=01h) the SI:DI bytes at offset CX:DX of the
+						// file with handle BX. Reads and writes of a locked range through any other
+						// handle fail until it's unlocked or the handle is closed.
+						let file_handle = self.file_handle(machine.get_reg_u16(Reg::BX));
+						let offset = ((machine.get_reg_u16(Reg::CX) as u32) << 16) | machine.get_reg_u16(Reg::DX) as u32;
+						let length = ((machine.get_reg_u16(Reg::SI) as u32) << 16) | machine.get_reg_u16(Reg::DI) as u32;
+						let result = self.locked_file_path(file_handle).and_then(|path| {
+							match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+								0x00 => self.file_locks.lock(&path, file_handle, offset, length),
+								0x01 => self.file_locks.unlock(&path, file_handle, offset, length),
+								_ => Err(DosErrorCode::InvalidFunction),
+							}
+						});
+						match result {
 							Ok(()) => {
 								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0);
+							}
+							Err(error_code) => {
+								machine.set_flag(Flag::Carry, true);
+								machine.set_reg_u16(Reg::AX, error_code as u16);
+							}
+						}
+					}
+					0x63 => {
+						// Get the DBCS lead byte table (AL=00h), or the interim console flag
+						// (AL=02h, in DL). Setting the flag (AL=01h) is ignored.
+						// http://www.ctyme.com/intr/rb-3181.htm
+						match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+							0x00 => {
+								machine.set_reg_u16(Reg::DS, DOS_DATA_SEGMENT);
+								machine.set_reg_u16(Reg::SI, DOS_DBCS_LEAD_BYTE_TABLE_OFFSET);
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+							}
+							0x01 => {
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+							}
+							0x02 => {
+								machine.set_reg_u8(Reg::DX, RegHalf::Low, 0);
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+							}
+							_ => {
+								machine.set_reg_u8(Reg::AX, RegHalf::Low, 0xff);
+							}
+						}
+					}
+					0x68 => {
+						// Commit the file in BX to disk. Writes go straight to the host file, so there's
+						// nothing to do but check the handle, and the console and CLOCK$ are always
+						// committed.
+						// http://www.ctyme.com/intr/rb-3193.htm
+						let handle = self.file_handle(machine.get_reg_u16(Reg::BX));
+						let result = if handle < DOS_FIRST_FILE_HANDLE || self.clock_device_handles.contains(&handle) {
+							Ok(0)
+						} else {
+							self.file_system.tell(handle)
+						};
+						match result {
+							Ok(_) => {
+								machine.set_flag(Flag::Carry, false);
+								machine.set_reg_u16(Reg::AX, 0);
 							}
 							Err(error_code) => {
 								machine.set_flag(Flag::Carry, true);
@@ -599,74 +3151,2376 @@ impl EventHandler for DosEventHandler {
 							}
 						}
 					}
-					_ => panic!("Unknown DOS interrupt: 0x{:x}", dos_int)
+					_ => {
+						let action = self.unknown_dos_func_action;
+						self.handle_unknown_function(machine, action, format!("Unknown DOS interrupt: 0x{:x}", dos_int));
+					}
 				}
 			}
+			0x28 => {
+				// DOS idle. Programs call it while they wait for input, so the rest of the slice
+				// is skipped like when they get the time, and the runner sleeps instead.
+				// http://www.ctyme.com/intr/rb-2949.htm
+				self.result = DosInterruptResult::ShouldReturnAndWaitForEvents;
+			}
 			0x33 => {
 				// Mouse function calls
 				// http://stanislavs.org/helppc/int_33.html
 				let mouse_func = machine.get_reg_u16(Reg::AX);
-				match mouse_func {
-					0 => {
-						// TODO get mouse installed flag
+				match (mouse_func, self.mouse.as_mut()) {
+					(0x0000, None) => {
+						// Leaving AX as 0 says there's no mouse.
+					}
+					(0x0000, Some(mouse)) => {
+						// Reset the driver, and say it's there and how many buttons there are.
+						mouse.reset();
+						machine.set_reg_u16(Reg::AX, 0xffff);
+						machine.set_reg_u16(Reg::BX, MOUSE_BUTTON_COUNT);
+					}
+					(0x0001, Some(mouse)) => mouse.show_cursor(),
+					(0x0002, Some(mouse)) => mouse.hide_cursor(),
+					(0x0003, Some(mouse)) => {
+						// Get the buttons in BX and the position in CX and DX.
+						machine.set_reg_u16(Reg::BX, mouse.buttons);
+						machine.set_reg_u16(Reg::CX, mouse.x);
+						machine.set_reg_u16(Reg::DX, mouse.y);
+					}
+					(0x0004, Some(mouse)) => {
+						mouse.set_position(machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX));
 					}
-					_ => panic!("Unknown mouse function: 0x{:x}", mouse_func)
+					(0x0007, Some(mouse)) => {
+						// Limit the cursor to the columns from CX to DX.
+						mouse.set_x_range(machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX));
+					}
+					(0x0008, Some(mouse)) => {
+						// Limit the cursor to the rows from CX to DX.
+						mouse.set_y_range(machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX));
+					}
+					(0x000a, Some(mouse)) => {
+						// Set the text cursor. BX=0 is the software cursor, with the screen mask in CX
+						// and the cursor mask in DX. The hardware cursor (BX=1) is the one the BIOS uses
+						// for typing, so the mouse keeps the software one.
+						if machine.get_reg_u16(Reg::BX) == 0 {
+							mouse.set_text_cursor_masks(machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX));
+						}
+					}
+					(0x0010, Some(mouse)) => {
+						// Hide the cursor while it's in the area with corners (CX, DX) and (SI, DI).
+						mouse.set_exclusion_area(MouseArea::new(
+							machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX),
+							machine.get_reg_u16(Reg::SI), machine.get_reg_u16(Reg::DI),
+						));
+					}
+					(_, None) => {
+						// Without a driver the vector goes to an IRET, so nothing happens.
+					}
+					_ => {
+						let action = self.unknown_interrupt_action;
+						self.handle_unknown_function(machine, action, format!("Unknown mouse function: 0x{:x}", mouse_func));
+					}
+				}
+			}
+			_ => {
+				let action = self.unknown_interrupt_action;
+				self.handle_unknown_function(machine, action, format!("Unknown interrupt: 0x{:x}", interrupt_index));
+			}
+		}
+	}
+	
+	/// Reads an I/O port for the program, on any Machine.
+	pub fn read_port(&mut self, machine: &mut dyn Machine, port_index: u16) -> u16 {
+		// http://bochs.sourceforge.net/techspec/PORTS.LST
+		let value = match port_index {
+			0x40 => {
+				// PIT channel 0 count
+				self.read_pit_channel0_data(machine) as u16
+			}
+			0x60 => {
+				// The keyboard's answer to the last byte sent to it.
+				self.keyboard.read_data() as u16
+			}
+			0x61 => {
+				// "Keyboard Controller" control register.
+				self.port_states.port_61
+			}
+			0x64 => {
+				// Keyboard controller status
+				self.keyboard.read_status() as u16
+			}
+			0x201 => {
+				// TODO: Read joystick values.
+				0xf0
+			}
+			0x3c0 => {
+				// The attribute controller register index.
+				self.port_states.attribute_controller_index as u16
+			}
+			0x3c1 => {
+				// The attribute controller register the index is on.
+				self.read_attribute_controller(self.port_states.attribute_controller_index) as u16
+			}
+			0x3d5 => {
+				// CRT data register. Only the start address registers are kept.
+				match self.port_states.crt_index_register {
+					0x0c => self.port_states.crtc_start_address >> 8,
+					0x0d => self.port_states.crtc_start_address & 0xff,
+					_ => 0,
+				}
+			}
+			0x3da => {
+				// Reading the status also makes the next write to port 0x3c0 an index.
+				self.port_states.attribute_controller_data_next = false;
+				self.read_cga_status_register(machine)
+			}
+			_ => {
+				// Nothing drives the bus for a port with no device, so it reads as all ones.
+				report_unknown_function(self.unknown_interrupt_action, &format!("Unhandled input port index: 0x{:02x}", port_index));
+				0xff
+			}
+		};
+		//println!("Port in({}): {}", port_index, value);
+		value
+	}
+	
+	/// Writes an I/O port for the program, on any Machine.
+	pub fn write_port(&mut self, machine: &mut dyn Machine, port_index: u16, value: u16) {
+		//println!("Port out({}): {}", port_index, value);
+		match port_index {
+			0x40 => {
+				// PIT channel 0 reload value
+				self.write_pit_channel0_data(value as u8);
+			}
+			0x42 => {
+				// PIT channel 2 reload value, which sets the speaker frequency.
+				self.write_pit_channel2_data(machine, value as u8);
+			}
+			0x43 => {
+				// Programmable interrupt timer (PIT), control register
+				self.write_pit_command(machine, value as u8);
+			}
+			0x60 => {
+				// A command or data byte for the keyboard. Setting the LEDs sets the lock toggles,
+				// so they stay the same as each other.
+				if let KeyboardCommandEffect::SetLeds(leds) = self.keyboard.write_data(value as u8) {
+					let key_mod = leds.apply_to_key_mod(machine.get_data_u8(&BIOS_KEYBOARD_FLAGS));
+					machine.set_data_u8(&BIOS_KEYBOARD_FLAGS, key_mod);
+				}
+			}
+			0x61 => {
+				self.port_states.port_61 = value;
+				self.update_speaker(machine);
+			}
+			0x201 => {
+				// TODO: Something about joystick one-shots?
+			}
+			0x3c0 => {
+				// The attribute controller takes a register index and then its value, in turns.
+				// Only the palette and overscan registers are kept.
+				// http://www.osdever.net/FreeVGA/vga/attrreg.htm
+				if self.port_states.attribute_controller_data_next {
+					self.write_attribute_controller(self.port_states.attribute_controller_index, value as u8);
+				} else {
+					self.port_states.attribute_controller_index = value as u8 & 0x1f;
+				}
+				self.port_states.attribute_controller_data_next = !self.port_states.attribute_controller_data_next;
+			}
+			0x3d4 => {
+				self.port_states.crt_index_register = value;
+			}
+			0x3d5 => {
+				// CRT data register. The start address registers move the displayed screen, and the
+				// rest are ignored.
+				// http://www.osdever.net/FreeVGA/vga/crtcreg.htm#0C
+				let start_address = self.port_states.crtc_start_address;
+				match self.port_states.crt_index_register {
+					0x0c => self.port_states.crtc_start_address = (start_address & 0x00ff) | ((value & 0xff) << 8),
+					0x0d => self.port_states.crtc_start_address = (start_address & 0xff00) | (value & 0xff),
+					_ => {}
+				}
+			}
+			0x3d9 => {
+				// CGA colour select register. In text modes the low 4 bits are the border colour,
+				// and the rest are for graphics modes, which don't exist.
+				self.port_states.cga_palette_register = value;
+				self.set_border_colour(irgb_to_ega_colour(value as u8));
+			}
+			_ => report_unknown_function(self.unknown_interrupt_action, &format!("Unhandled output port index: 0x{:02x}", port_index)),
+		}
+	}
+}
+
+impl EventHandler for DosEventHandler {
+	fn handle_interrupt(&mut self, machine: &mut Machine8086, interrupt_index: u8) {
+		self.dispatch_interrupt(machine, interrupt_index);
+	}
+	
+	fn handle_port_input(&mut self, machine: &mut Machine8086, port_index: u16) -> u16 {
+		self.read_port(machine, port_index)
+	}
+	
+	fn handle_port_output(&mut self, machine: &mut Machine8086, port_index: u16, value: u16) {
+		self.write_port(machine, port_index, value);
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::dos_background_policy::{BackgroundMode, BackgroundPolicy, ResumeClock, SLOW_TICK_DIVISOR};
+	use crate::dos_country_info::COUNTRY_INFO_BYTES;
+	use crate::dos_environment::build_environment_block;
+	use crate::dos_file_system::{StandardDosFileSystem, DOS_ATTRIBUTE_ARCHIVE, DOS_ATTRIBUTE_DIRECTORY, DOS_ATTRIBUTE_READ_ONLY, DOS_ATTRIBUTE_VOLUME_LABEL};
+	use crate::exe_loader::load_com_into_machine;
+	use crate::machine::FakeMachine;
+	
+	pub(crate) fn make_test_handler() -> (DosEventHandler, Machine8086) {
+		let mut machine = Machine8086::new(1024*1024);
+		let mut options = EmulatorOptions::default();
+		// The support table test relies on unknown functions panicking.
+		options.dos.unknown_function_action = UnknownFuncAction::Panic;
+		options.dos.unknown_interrupt_action = UnknownFuncAction::Panic;
+		options.video.print_screen_dir = None;
+		let file_system = Box::new(StandardDosFileSystem::new(std::env::temp_dir().join("pseudos_test_nonexistent")));
+		let handler = DosEventHandler::new(&mut machine, file_system, &options).unwrap();
+		(handler, machine)
+	}
+	
+	/// Makes an empty directory for a test to put files in, named after the test so tests that run
+	/// at the same time don't share one.
+	pub(crate) fn make_test_dir(name: &str) -> std::path::PathBuf {
+		let dir_path = std::env::temp_dir().join(format!("pseudos_test_{}_{}", name, std::process::id()));
+		std::fs::remove_dir_all(&dir_path).ok();
+		std::fs::create_dir_all(&dir_path).unwrap();
+		dir_path
+	}
+	
+	/// Calls int 21h with DS:DX pointing at DX in the first segment, and returns AX as an error if
+	/// carry was set.
+	pub(crate) fn call_dos(handler: &mut DosEventHandler, machine: &mut Machine8086, ax: u16, bx: u16, cx: u16, dx: u16) -> Result<u16, u16> {
+		machine.set_reg_u16(Reg::AX, ax);
+		machine.set_reg_u16(Reg::BX, bx);
+		machine.set_reg_u16(Reg::CX, cx);
+		machine.set_reg_u16(Reg::DS, 0);
+		machine.set_reg_u16(Reg::DX, dx);
+		handler.handle_interrupt(machine, 0x21);
+		if machine.get_flag(Flag::Carry) { Err(machine.get_reg_u16(Reg::AX)) } else { Ok(machine.get_reg_u16(Reg::AX)) }
+	}
+	
+	fn set_cursor_type(handler: &mut DosEventHandler, machine: &mut Machine8086, start: u8, end: u8) {
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x01);
+		machine.set_reg_u8(Reg::CX, RegHalf::High, start);
+		machine.set_reg_u8(Reg::CX, RegHalf::Low, end);
+		handler.handle_interrupt(machine, 0x10);
+	}
+	
+	#[test] fn test_cursor_type_stored_raw() {
+		let (mut handler, mut machine) = make_test_handler();
+		set_cursor_type(&mut handler, &mut machine, 0x00, 0x0d);
+		assert_eq!(machine.get_data_u16(&BIOS_CURSOR_TYPE), 0x000d);
+		assert_eq!(handler.get_cursor_scanlines(&machine), Some((0, 13)));
+	}
+	
+	#[test] fn test_cursor_hidden() {
+		let (mut handler, mut machine) = make_test_handler();
+		set_cursor_type(&mut handler, &mut machine, 0x20, 0x07);
+		assert_eq!(machine.get_data_u16(&BIOS_CURSOR_TYPE), 0x2007);
+		assert_eq!(handler.get_cursor_scanlines(&machine), None);
+		set_cursor_type(&mut handler, &mut machine, 0x07, 0x06);
+		assert_eq!(handler.get_cursor_scanlines(&machine), None);
+	}
+	
+	#[test] fn test_cursor_reset_by_mode_change() {
+		let (mut handler, mut machine) = make_test_handler();
+		set_cursor_type(&mut handler, &mut machine, 0x20, 0x07);
+		machine.set_reg_u16(Reg::AX, 0x0003);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_data_u16(&BIOS_CURSOR_TYPE), 0x0b0c);
+		assert_eq!(handler.get_cursor_scanlines(&machine), Some((11, 12)));
+	}
+	
+	#[test] fn test_cursor_emulation_scaling() {
+		let (mut handler, mut machine) = make_test_handler();
+		set_cursor_type(&mut handler, &mut machine, 6, 7);
+		assert_eq!(machine.get_data_u16(&BIOS_CURSOR_TYPE), 0x0607);
+		assert_eq!(handler.get_cursor_scanlines(&machine), Some((12, 13)));
+		
+		// Turn off cursor emulation with int 10h AH=12h BL=34h AL=1.
+		machine.set_reg_u16(Reg::AX, 0x1201);
+		machine.set_reg_u8(Reg::BX, RegHalf::Low, 0x34);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0x12);
+		assert_eq!(handler.get_cursor_scanlines(&machine), Some((6, 7)));
+	}
+	
+	#[test] fn test_ioctl_stdin_input_status() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::AX, 0x4406);
+		machine.set_reg_u16(Reg::BX, 0);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0x00);
+		assert!(machine.get_flag(Flag::Zero));
+		
+		handler.key_press_queue.push_back(KeyPressInfo{scan_code: 0x1e, ascii_char: b'a'});
+		machine.set_reg_u16(Reg::AX, 0x4406);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0xff);
+		assert!(!machine.get_flag(Flag::Zero));
+		assert!(!machine.get_flag(Flag::Carry));
+	}
+	
+	#[test] fn test_store_keystroke() {
+		let (mut handler, mut machine) = make_test_handler();
+		for _ in 0 .. BIOS_KEY_BUFFER_CAPACITY {
+			machine.set_reg_u16(Reg::AX, 0x0500);
+			machine.set_reg_u16(Reg::CX, 0x1e61);
+			handler.handle_interrupt(&mut machine, 0x16);
+			assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0);
+		}
+		// The buffer is full now.
+		machine.set_reg_u16(Reg::AX, 0x0500);
+		handler.handle_interrupt(&mut machine, 0x16);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 1);
+		assert_eq!(handler.key_press_queue.len(), BIOS_KEY_BUFFER_CAPACITY);
+		
+		// The stored keystroke is read back like a typed one.
+		machine.set_reg_u16(Reg::AX, 0x0000);
+		handler.handle_interrupt(&mut machine, 0x16);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x1e61);
+	}
+	
+	// Fills rows 0 to 5 of column 0 with the letters A to F, scrolls rows 1 to 4 with the given
+	// function and line count, then returns the letters in rows 0 to 5.
+	fn scroll_test_window(scroll_function: u8, num_lines: u8) -> String {
+		let (mut handler, mut machine) = make_test_handler();
+		let page_addr = handler.get_page_origin_address(&machine, 0);
+		for y in 0 .. 6 {
+			let char_addr = handler.get_screen_character_address(&machine, page_addr, 0, y);
+			machine.poke_u8(char_addr, b'A' + y);
+			machine.poke_u8(char_addr + 1, 0x07);
+		}
+		machine.set_reg_u8(Reg::AX, RegHalf::High, scroll_function);
+		machine.set_reg_u8(Reg::AX, RegHalf::Low, num_lines);
+		machine.set_reg_u8(Reg::BX, RegHalf::High, 0x1f);
+		machine.set_reg_u16(Reg::CX, 0x0100);
+		machine.set_reg_u16(Reg::DX, 0x0400);
+		handler.handle_interrupt(&mut machine, 0x10);
+		(0 .. 6).map(|y| {
+			let char_addr = handler.get_screen_character_address(&machine, page_addr, 0, y);
+			if machine.peek_u8(char_addr) == b' ' {
+				// Blanks must get the requested attributes.
+				assert_eq!(machine.peek_u8(char_addr + 1), 0x1f);
+				'_'
+			} else {
+				machine.peek_u8(char_addr) as char
+			}
+		}).collect()
+	}
+	
+	#[test] fn test_scroll_window() {
+		let cases = [
+			(0x06, 1, "ACDE_F"),
+			(0x06, 2, "ADE__F"),
+			(0x06, 0, "A____F"),
+			(0x06, 4, "A____F"),
+			(0x06, 200, "A____F"),
+			(0x07, 1, "A_BCDF"),
+			(0x07, 2, "A__BCF"),
+			(0x07, 0, "A____F"),
+			(0x07, 5, "A____F"),
+			(0x07, 255, "A____F"),
+		];
+		for &(scroll_function, num_lines, expected) in cases.iter() {
+			assert_eq!(scroll_test_window(scroll_function, num_lines), expected, "AH={:02x}h AL={}", scroll_function, num_lines);
+		}
+	}
+	
+	// Describes the top left of the active page as a row of "character, attributes in hex" cells.
+	fn screen_cells(handler: &DosEventHandler, machine: &Machine8086, columns: u8, rows: u8) -> Vec<String> {
+		let page_addr = handler.get_page_origin_address(machine, 0);
+		(0 .. rows).map(|y| {
+			(0 .. columns).map(|x| {
+				let char_addr = handler.get_screen_character_address(machine, page_addr, x, y);
+				format!("{}{:02X}", machine.peek_u8(char_addr) as char, machine.peek_u8(char_addr + 1))
+			}).collect::<Vec<_>>().join(" ")
+		}).collect()
+	}
+	
+	// Fills the top left 4x4 cells with the letters A to P, then calls int 10h with AX and BX for the
+	// rectangle from CX to DX.
+	fn scroll_test_screen(ax: u16, bx: u16, cx: u16, dx: u16) -> (DosEventHandler, Machine8086) {
+		let (mut handler, mut machine) = make_test_handler();
+		let page_addr = handler.get_page_origin_address(&machine, 0);
+		for y in 0 .. 4 {
+			for x in 0 .. 4 {
+				let char_addr = handler.get_screen_character_address(&machine, page_addr, x, y);
+				machine.poke_u8(char_addr, b'A' + y * 4 + x);
+			}
+		}
+		machine.set_reg_u16(Reg::AX, ax);
+		machine.set_reg_u16(Reg::BX, bx);
+		machine.set_reg_u16(Reg::CX, cx);
+		machine.set_reg_u16(Reg::DX, dx);
+		handler.handle_interrupt(&mut machine, 0x10);
+		(handler, machine)
+	}
+	
+	#[test] fn test_scroll_blanks() {
+		// Scrolling up by a line blanks the bottom line with spaces in the attributes in BH, here red
+		// on blue. The screen starts out as grey on black spaces.
+		let (handler, machine) = scroll_test_screen(0x0601, 0x1400, 0x0000, 0x0303);
+		assert_eq!(screen_cells(&handler, &machine, 5, 5), vec![
+			"E07 F07 G07 H07  07",
+			"I07 J07 K07 L07  07",
+			"M07 N07 O07 P07  07",
+			" 14  14  14  14  07",
+			" 07  07  07  07  07",
+		]);
+		
+		// Scrolling by 0 lines clears the whole window the same way.
+		let (handler, machine) = scroll_test_screen(0x0700, 0x1400, 0x0101, 0x0203);
+		assert_eq!(screen_cells(&handler, &machine, 5, 4), vec![
+			"A07 B07 C07 D07  07",
+			"E07  14  14  14  07",
+			"I07  14  14  14  07",
+			"M07 N07 O07 P07  07",
+		]);
+		
+		// Reading a blank back with AH=08h gets a space in AL and the attributes in AH.
+		let (mut handler, mut machine) = scroll_test_screen(0x0601, 0x1400, 0x0000, 0x0303);
+		machine.set_reg_u16(Reg::AX, 0x0200);
+		machine.set_reg_u16(Reg::BX, 0x0000);
+		machine.set_reg_u16(Reg::DX, 0x0302);
+		handler.handle_interrupt(&mut machine, 0x10);
+		machine.set_reg_u16(Reg::AX, 0x0800);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x1420);
+		// It reads at the cursor of the page in BH, which needn't be the active one.
+		machine.set_reg_u16(Reg::AX, 0x0200);
+		machine.set_reg_u16(Reg::BX, 0x0100);
+		machine.set_reg_u16(Reg::DX, 0x0000);
+		handler.handle_interrupt(&mut machine, 0x10);
+		let page_addr = handler.get_page_origin_address(&machine, 1);
+		machine.poke_u16(page_addr, 0x4f41);
+		machine.set_reg_u16(Reg::AX, 0x0800);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x4f41);
+		
+		// The teletype scrolls in grey on black spaces.
+		machine.set_reg_u16(Reg::AX, 0x0200);
+		machine.set_reg_u16(Reg::BX, 0x0000);
+		machine.set_reg_u16(Reg::DX, 0x1800);
+		handler.handle_interrupt(&mut machine, 0x10);
+		machine.set_reg_u16(Reg::AX, 0x0e0a);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(screen_cells(&handler, &machine, 5, 3), vec![
+			"I07 J07 K07 L07  07",
+			"M07 N07 O07 P07  07",
+			" 14  14  14  14  07",
+		]);
+		machine.set_reg_u16(Reg::AX, 0x0800);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x0720);
+	}
+	
+	#[test] fn test_parse_filename_at_end_of_memory() {
+		let (mut handler, mut machine) = make_test_handler();
+		// A name past the end of memory is empty, and an FCB that would go past it isn't written.
+		let memory_bytes = machine.memory.len();
+		machine.memory[memory_bytes - 8 ..].copy_from_slice(b"TOWN.ZZT");
+		machine.set_reg_u16(Reg::AX, 0x2900);
+		machine.set_reg_u16(Reg::DS, 0xffff);
+		machine.set_reg_u16(Reg::SI, 0xfff8);
+		machine.set_reg_u16(Reg::ES, 0xffff);
+		machine.set_reg_u16(Reg::DI, 0x0008);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u16(Reg::SI), 0xfff8);
+		assert_eq!(&machine.memory[memory_bytes - 8 ..], b"TOWN.ZZT");
+		// A name that runs up to the end of memory is parsed up to there.
+		machine.set_reg_u16(Reg::DS, 0xf000);
+		machine.set_reg_u16(Reg::SI, 0xfff8);
+		machine.set_reg_u16(Reg::ES, 0);
+		machine.set_reg_u16(Reg::DI, 0x1000);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u16(Reg::SI), 0x0000);
+		assert_eq!(&machine.memory[0x1001 .. 0x100c], b"TOWN    ZZT");
+	}
+	
+	#[test] fn test_cursor_position_invalid_page() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_data_u16(&BIOS_CURSOR_POSITION[7], 0x0102);
+		// Setting the cursor of a page that doesn't exist does nothing.
+		machine.set_reg_u16(Reg::AX, 0x0200);
+		machine.set_reg_u16(Reg::BX, 0x0800);
+		machine.set_reg_u16(Reg::DX, 0x0304);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert!(BIOS_CURSOR_POSITION.iter().all(|cursor_position| machine.get_data_u16(cursor_position) != 0x0304));
+		// Getting it reads the last page's cursor.
+		machine.set_reg_u16(Reg::AX, 0x0300);
+		machine.set_reg_u16(Reg::BX, 0xfe00);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u16(Reg::DX), 0x0102);
+	}
+	
+	#[test] fn test_speaker_events_continue_while_blocked() {
+		let (mut handler, mut machine) = make_test_handler();
+		// The foreground is waiting for a key.
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x00);
+		handler.handle_interrupt(&mut machine, 0x16);
+		assert_eq!(handler.result, DosInterruptResult::ShouldBlockForKeypress);
+		
+		// A 1Ch hook plays a note on every tick, like ZZT's music player.
+		let notes: [u16; 4] = [0x0a97, 0x0974, 0x0870, 0x07f0];
+		for &reload in notes.iter() {
+			handler.handle_interrupt(&mut machine, 0x08);
+			handler.handle_port_output(&mut machine, 0x43, 0xb6);
+			handler.handle_port_output(&mut machine, 0x42, reload & 0xff);
+			handler.handle_port_output(&mut machine, 0x42, reload >> 8);
+			handler.handle_port_output(&mut machine, 0x61, 0x03);
+		}
+		handler.handle_interrupt(&mut machine, 0x08);
+		handler.handle_port_output(&mut machine, 0x61, 0x00);
+		
+		let events: Vec<SpeakerEvent> = handler.speaker_events.drain(..).collect();
+		assert_eq!(events.len(), notes.len() + 1);
+		for (index, event) in events.iter().enumerate() {
+			// One event per standard timer tick, with no gaps while blocked.
+			assert_eq!(event.pit_clock, (index as u64 + 1) * PIT_STANDARD_RELOAD as u64);
+		}
+		for (event, &reload) in events.iter().zip(notes.iter()) {
+			assert_eq!(event.frequency, Some(PIT_FREQUENCY_HZ / reload as f64));
+		}
+		assert_eq!(events.last().unwrap().frequency, None);
+	}
+	
+	#[test] fn test_run_exe_prints_to_screen() {
+		// A two paragraph MZ header followed by:
+		//   push cs; pop ds; mov dx, message; mov ah, 09h; int 21h; mov ax, 4c03h; int 21h
+		//   message: db "Hello, DOS!$"
+		let code: &[u8] = &[0x0e, 0x1f, 0xba, 0x0e, 0x00, 0xb4, 0x09, 0xcd, 0x21, 0xb8, 0x03, 0x4c, 0xcd, 0x21];
+		let message: &[u8] = b"Hello, DOS!$";
+		let file_len = 32 + code.len() + message.len();
+		let header_words: [u16; 14] = [0x5a4d, file_len as u16, 1, 0, 2, 0x10, 0xffff, 0x10, 0x100, 0, 0, 0, 0x1c, 0];
+		let mut exe = vec![];
+		header_words.iter().for_each(|word| exe.extend(&word.to_le_bytes()));
+		exe.resize(32, 0);
+		exe.extend(code);
+		exe.extend(message);
+		
+		let (mut handler, mut machine) = make_test_handler();
+		let mut exe_stream = std::io::Cursor::new(exe);
+		let exe_header = crate::exe_loader::MzHeader::parse(&mut exe_stream).unwrap();
+		exe_header.load_into_machine(&mut machine, &mut exe_stream, b"", &build_environment_block(&[], b"C:\\TEST.EXE"), &*handler.file_system).unwrap();
+		handler.init_machine(&mut machine);
+		
+		assert_eq!(handler.run_headless(&mut machine, 1000, HeadlessStartup::Normal), Ok(3));
+		let screen_text = handler.get_screen_text(&machine);
+		assert_eq!(&screen_text[0][..11], b"Hello, DOS!");
+		assert_eq!(handler.get_cursor_position(&machine), (11, 0));
+	}
+	
+	#[test] fn test_write_string_stops_at_segment_end() {
+		let (mut handler, mut machine) = make_test_handler();
+		// The string has no '$' before the end of segment 0, so the text past it isn't written.
+		machine.memory_mut()[0xfffc..0x10005].copy_from_slice(b"abcdefgh$");
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x0900, 0, 0, 0xfffc), Ok(0x0900));
+		assert_eq!(&handler.get_screen_text(&machine)[0][..5], b"abcd ");
+		assert_eq!(handler.get_cursor_position(&machine), (4, 0));
+	}
+	
+	#[test] fn test_tty_output_with_cursor_off_screen() {
+		let (mut handler, mut machine) = make_test_handler();
+		// A cursor put past the bottom right corner writes there, and then wraps onto a new line.
+		machine.set_data_u16(&BIOS_CURSOR_POSITION[0], 0xffff);
+		handler.write_tty_char(&mut machine, b'x');
+		assert_eq!(handler.get_screen_text(&machine)[23][79], b'x');
+		assert_eq!(handler.get_cursor_position(&machine), (0, 24));
+		machine.set_data_u16(&BIOS_CURSOR_POSITION[0], 0xff00);
+		handler.write_tty_char(&mut machine, b'\n');
+		assert_eq!(handler.get_cursor_position(&machine), (0, 24));
+		
+		// An active page past the last one is treated as the last one.
+		machine.set_data_u8(&BIOS_ACTIVE_VIDEO_PAGE, 0x09);
+		handler.write_tty_char(&mut machine, b'y');
+		assert_eq!(machine.get_data_u16(&BIOS_CURSOR_POSITION[7]), 0x0001);
+		assert_eq!(handler.get_cursor_position(&machine), (1, 0));
+	}
+	
+	#[test] fn test_cga_status_follows_beam() {
+		let (mut handler, mut machine) = make_test_handler();
+		handler.handle_interrupt(&mut machine, 0x08);
+		let mut previous_status = 0;
+		let mut retrace_starts = vec![];
+		let mut horizontal_blank_count = 0;
+		// Poll like a program doing "in al, dx; test al, 8; jz" for a couple of frames.
+		for poll_index in 0 .. 1000 {
+			machine.number_of_parsed_instructions += 3;
+			let status = handler.handle_port_input(&mut machine, 0x3da);
+			if status & 0b1000 != 0 {
+				// The display is never drawn during vertical retrace.
+				assert_eq!(status & 0b1, 0b1);
+				if previous_status & 0b1000 == 0 {
+					retrace_starts.push(poll_index);
+				}
+			} else if status & 0b1 != 0 {
+				horizontal_blank_count += 1;
+			}
+			previous_status = status;
+		}
+		assert!(horizontal_blank_count > 0);
+		// One retrace per frame of about 19886 clocks at 60Hz, at 48 clocks per poll.
+		assert_eq!(retrace_starts.len(), 2);
+		let frame_polls = handler.frame_pit_clocks() / (3 * PIT_CLOCKS_PER_INSTRUCTION) as f64;
+		assert!(((retrace_starts[1] - retrace_starts[0]) as f64 - frame_polls).abs() <= 1.);
+	}
+	
+	// Polls port 0x3dah every 3 instructions for a number of BIOS ticks, raising the timer interrupt
+	// after each tick's worth of instructions, and returns the number of retraces that started.
+	fn count_retrace_edges(handler: &mut DosEventHandler, machine: &mut Machine8086, tick_count: u16) -> usize {
+		let instructions_per_tick = PIT_STANDARD_RELOAD as usize / PIT_CLOCKS_PER_INSTRUCTION;
+		let mut previous_status = handler.handle_port_input(machine, 0x3da);
+		let mut retrace_edges = 0;
+		while machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW) < tick_count {
+			for _ in 0 .. instructions_per_tick / 3 {
+				machine.number_of_parsed_instructions += 3;
+				let status = handler.handle_port_input(machine, 0x3da);
+				if status & 0b1000 != 0 && previous_status & 0b1000 == 0 {
+					retrace_edges += 1;
+				}
+				previous_status = status;
+			}
+			machine.number_of_parsed_instructions += instructions_per_tick % 3;
+			handler.handle_interrupt(machine, 0x08);
+		}
+		retrace_edges
+	}
+	
+	#[test] fn test_retrace_rate_follows_refresh_rate() {
+		for &refresh_rate_hz in [None, Some(70.), Some(50.)].iter() {
+			let (mut handler, mut machine) = make_test_handler();
+			handler.set_refresh_rate_hz(refresh_rate_hz);
+			assert_eq!(handler.refresh_rate_hz(), refresh_rate_hz.unwrap_or(60.));
+			// 18 ticks is just under an emulated second.
+			let retrace_edges = count_retrace_edges(&mut handler, &mut machine, 18);
+			let expected_edges = handler.refresh_rate_hz() * 18. * STANDARD_TICK_SECONDS;
+			assert!((retrace_edges as f64 - expected_edges).abs() <= 1., "{} retraces at {}Hz", retrace_edges, handler.refresh_rate_hz());
+			assert_eq!(handler.vertical_retrace_count(&machine), retrace_edges as u64);
+		}
+	}
+	
+	#[test] fn test_select_vertical_resolution() {
+		let (mut handler, mut machine) = make_test_handler();
+		assert_eq!(handler.video_mode.pixel_dims(), (640, 350));
+		for &(al, height, char_height) in [(2, 400, 16), (0, 200, 8), (1, 350, 14)].iter() {
+			machine.set_reg_u16(Reg::AX, 0x1200 | al);
+			machine.set_reg_u8(Reg::BX, RegHalf::Low, 0x30);
+			handler.handle_interrupt(&mut machine, 0x10);
+			assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0x12);
+			assert_eq!(handler.video_mode.pixel_dims(), (640, height));
+			assert_eq!(handler.video_mode.char_pixel_dims(), (8, char_height));
+			assert_eq!(machine.get_data_u16(&BIOS_CHAR_HEIGHT), char_height as u16);
+			// The BIOS keeps the last row's number, with the character height straight after it.
+			assert_eq!(machine.memory[0x484], 24);
+		}
+		// Anything else isn't supported, and leaves the resolution alone.
+		machine.set_reg_u16(Reg::AX, 0x1203);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0x03);
+		assert_eq!(handler.video_mode.pixel_dims(), (640, 350));
+	}
+	
+	#[test] fn test_light_pen() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::AX, 0x0400);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::High), 0);
+	}
+	
+	#[test] fn test_vendor_video_functions() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::ES, 0xb800);
+		machine.set_reg_u16(Reg::DI, 0x0010);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0xfe);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!((machine.get_reg_u16(Reg::ES), machine.get_reg_u16(Reg::DI)), (0xb800, 0x0010));
+		// The other vendor functions are reported like unknown functions, but leave the registers
+		// alone, since programs only call them to check for something optional.
+		handler.unknown_interrupt_action = UnknownFuncAction::ReturnError;
+		for &ah in [0xff, 0xf0, 0xfa].iter() {
+			machine.set_flag(Flag::Carry, false);
+			machine.set_reg_u16(Reg::AX, (ah as u16) << 8);
+			handler.handle_interrupt(&mut machine, 0x10);
+			assert_eq!(machine.get_reg_u16(Reg::AX), (ah as u16) << 8);
+			assert!(!machine.get_flag(Flag::Carry));
+		}
+	}
+	
+	#[test] fn test_support_tables_match_dispatch() {
+		// Call every function with zeroed registers. Functions marked as unimplemented (or not
+		// listed) must panic, and functions marked as fully implemented must not.
+		let default_panic_hook = std::panic::take_hook();
+		std::panic::set_hook(Box::new(|_| {}));
+		let mut mismatches = vec![];
+		for &interrupt_index in [0x10u8, 0x16, 0x21].iter() {
+			for function in 0 ..= 0xffu16 {
+				let panicked = std::panic::catch_unwind(|| {
+					let (mut handler, mut machine) = make_test_handler();
+					// Strings read from DS:DX end straight away.
+					machine.poke_u8(0, b'$');
+					machine.set_reg_u8(Reg::AX, RegHalf::High, function as u8);
+					handler.handle_interrupt(&mut machine, interrupt_index);
+				}).is_err();
+				match function_support(interrupt_index, function) {
+					FunctionSupport::Unimplemented if !panicked => mismatches.push((interrupt_index, function)),
+					FunctionSupport::Full if panicked => mismatches.push((interrupt_index, function)),
+					_ => {}
 				}
 			}
-			_ => panic!("Unknown interrupt: 0x{:x}", interrupt_index)
 		}
+		std::panic::set_hook(default_panic_hook);
+		assert_eq!(mismatches, vec![]);
+	}
+	
+	#[test] fn test_audit_unimplemented_function() {
+		let (mut handler, mut machine) = make_test_handler();
+		handler.function_audit = Some(FunctionAudit::new());
+		machine.set_reg_u16(Reg::CS, 0x1234);
+		machine.set_reg_u16(Reg::IP, 0x0010);
+		for _ in 0 .. 2 {
+			// Write string, which isn't implemented.
+			machine.set_reg_u8(Reg::AX, RegHalf::High, 0x13);
+			handler.handle_interrupt(&mut machine, 0x10);
+			assert!(machine.get_flag(Flag::Carry));
+		}
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x19);
+		handler.handle_interrupt(&mut machine, 0x21);
+		
+		let report = handler.function_audit.as_ref().unwrap().report();
+		assert_eq!(report.len(), 2);
+		assert_eq!((report[0].interrupt, report[0].function, report[0].support, report[0].count), (0x10, 0x13, FunctionSupport::Unimplemented, 2));
+		assert_eq!((report[0].first_cs, report[0].first_ip), (0x1234, 0x0010));
+		assert_eq!((report[1].interrupt, report[1].function, report[1].support), (0x21, 0x19, FunctionSupport::Full));
 	}
 	
-	fn handle_port_input(&mut self, machine: &mut Machine8086, port_index: u16) -> u16 {
-		// http://bochs.sourceforge.net/techspec/PORTS.LST
-		let value = match port_index {
-			0x61 => {
-				// "Keyboard Controller" control register.
-				self.port_states.port_61
+	#[test] fn test_interrupt_vector_round_trip() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::AX, 0x251c);
+		machine.set_reg_u16(Reg::DS, 0x1234);
+		machine.set_reg_u16(Reg::DX, 0x5678);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.peek_u16(0x1c * INTERRUPT_TABLE_ENTRY_BYTES as u32), 0x5678);
+		assert_eq!(machine.peek_u16(0x1c * INTERRUPT_TABLE_ENTRY_BYTES as u32 + 2), 0x1234);
+		
+		machine.set_reg_u16(Reg::AX, 0x351c);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!((machine.get_reg_u16(Reg::ES), machine.get_reg_u16(Reg::BX)), (0x1234, 0x5678));
+	}
+	
+	// Runs ticks of host time the way the runner does, with the program in the background for
+	// some of them, and returns the BIOS tick counter and the time of day afterwards.
+	fn run_ticks_with_background(policy: &mut BackgroundPolicy, foreground_ticks: u64, background_ticks: u64) -> (u16, f64) {
+		let (mut handler, mut machine) = make_test_handler();
+		let run_tick = |policy: &mut BackgroundPolicy, handler: &mut DosEventHandler, machine: &mut Machine8086| {
+			if policy.should_run_tick() {
+				handler.seconds_since_start += STANDARD_TICK_SECONDS;
+				handler.handle_interrupt(machine, 0x08);
 			}
-			0x201 => {
-				// TODO: Read joystick values.
-				0xf0
+		};
+		for _ in 0 .. foreground_ticks {
+			run_tick(policy, &mut handler, &mut machine);
+		}
+		assert_eq!(policy.set_in_background(true), 0);
+		for _ in 0 .. background_ticks {
+			run_tick(policy, &mut handler, &mut machine);
+		}
+		let skipped_ticks = policy.set_in_background(false);
+		handler.skip_standard_ticks(&mut machine, skipped_ticks);
+		(machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW), handler.seconds_since_start)
+	}
+	
+	#[test] fn test_background_policies() {
+		let mut policy = BackgroundPolicy::new(BackgroundMode::Pause, ResumeClock::Continue);
+		let (ticks, seconds) = run_ticks_with_background(&mut policy, 5, 1000);
+		assert_eq!(ticks, 5);
+		assert!((seconds - 5. * STANDARD_TICK_SECONDS).abs() < 1e-9);
+		assert!(!policy.is_in_background());
+		
+		let mut policy = BackgroundPolicy::new(BackgroundMode::SlowTick, ResumeClock::Continue);
+		let (ticks, seconds) = run_ticks_with_background(&mut policy, 5, 1000);
+		assert_eq!(ticks, 5 + 1000 / SLOW_TICK_DIVISOR as u16);
+		assert!((seconds - 105. * STANDARD_TICK_SECONDS).abs() < 1e-9);
+		
+		// Syncing to the host jumps the clock forward without running the missed ticks.
+		let mut policy = BackgroundPolicy::new(BackgroundMode::SlowTick, ResumeClock::SyncToHost);
+		let (ticks, seconds) = run_ticks_with_background(&mut policy, 5, 1000);
+		assert_eq!(ticks, 5 + 1000);
+		assert!((seconds - 1005. * STANDARD_TICK_SECONDS).abs() < 1e-9);
+		
+		let mut policy = BackgroundPolicy::new(BackgroundMode::Pause, ResumeClock::SyncToHost);
+		let (ticks, _) = run_ticks_with_background(&mut policy, 5, 1000);
+		assert_eq!(ticks, 5 + 1000);
+	}
+	
+	#[test] fn test_dbcs_lead_byte_table() {
+		let (mut handler, mut machine) = make_test_handler();
+		initialise_bios_data_area(&mut machine);
+		machine.set_reg_u16(Reg::AX, 0x6300);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0);
+		let table_addr = machine.get_seg_reg(Reg::DS, Reg::SI);
+		assert_eq!(machine.peek_u16(table_addr), 0);
+	}
+	
+	#[test] fn test_unknown_function_returns_error() {
+		let (mut handler, mut machine) = make_test_handler();
+		handler.unknown_dos_func_action = UnknownFuncAction::default();
+		handler.unknown_interrupt_action = UnknownFuncAction::Log;
+		// DPMI isn't there, so probing for it fails rather than stopping the emulator.
+		machine.set_reg_u16(Reg::AX, 0xed00);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), DosErrorCode::InvalidFunction as u16);
+		
+		machine.set_flag(Flag::Carry, false);
+		machine.set_reg_u16(Reg::AX, 0x1687);
+		handler.handle_interrupt(&mut machine, 0x2f);
+		assert!(machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), DosErrorCode::InvalidFunction as u16);
+		
+		// So do IOCTL subfunctions that aren't handled.
+		machine.set_flag(Flag::Carry, false);
+		machine.set_reg_u16(Reg::AX, 0x4455);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), DosErrorCode::InvalidFunction as u16);
+	}
+	
+	#[test] fn test_file_attributes() {
+		let dir_path = make_test_dir("int21_attributes");
+		std::fs::write(dir_path.join("SAVE.DAT"), b"data").unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		file_system.enable_attribute_persistence();
+		handler.replace_file_system(Box::new(file_system));
+		let filename_addr = 0x1000;
+		machine.memory[filename_addr as usize .. filename_addr as usize + 9].copy_from_slice(b"SAVE.DAT\0");
+		
+		let access_denied = Err(DosErrorCode::AccessDenied as u16);
+		
+		call_dos(&mut handler, &mut machine, 0x4301, 0, DOS_ATTRIBUTE_READ_ONLY, filename_addr).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3d02, 0, 0, filename_addr), access_denied);
+		let handle = call_dos(&mut handler, &mut machine, 0x3d00, 0, 0, filename_addr).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, handle, 4, filename_addr), access_denied);
+		call_dos(&mut handler, &mut machine, 0x3e00, handle, 0, filename_addr).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4100, 0, 0, filename_addr), access_denied);
+		
+		// Clear read-only and the archive bit, then write to set the archive bit again.
+		call_dos(&mut handler, &mut machine, 0x4301, 0, 0, filename_addr).unwrap();
+		let handle = call_dos(&mut handler, &mut machine, 0x3d02, 0, 0, filename_addr).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, handle, 4, filename_addr), Ok(4));
+		call_dos(&mut handler, &mut machine, 0x3e00, handle, 0, filename_addr).unwrap();
+		call_dos(&mut handler, &mut machine, 0x4300, 0, 0, filename_addr).unwrap();
+		assert_eq!(machine.get_reg_u16(Reg::CX), DOS_ATTRIBUTE_ARCHIVE);
+		call_dos(&mut handler, &mut machine, 0x4301, 0, 0, filename_addr).unwrap();
+		
+		// The cleared archive bit is kept by a new file system on the same directory.
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		file_system.enable_attribute_persistence();
+		handler.replace_file_system(Box::new(file_system));
+		call_dos(&mut handler, &mut machine, 0x4300, 0, 0xffff, filename_addr).unwrap();
+		assert_eq!(machine.get_reg_u16(Reg::CX), 0);
+		call_dos(&mut handler, &mut machine, 0x4100, 0, 0, filename_addr).unwrap();
+		assert!(!dir_path.join("SAVE.DAT").exists());
+	}
+	
+	#[test] fn test_read_into_top_of_memory() {
+		let dir_path = make_test_dir("read_top");
+		std::fs::write(dir_path.join("SAVE.DAT"), b"0123456789abcdef").unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		machine.memory[0x1000 .. 0x1009].copy_from_slice(b"SAVE.DAT\0");
+		machine.set_reg_u16(Reg::AX, 0x3d00);
+		machine.set_reg_u16(Reg::DS, 0);
+		machine.set_reg_u16(Reg::DX, 0x1000);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		let handle = machine.get_reg_u16(Reg::AX);
+		
+		// There are only 8 bytes between F000:FFF8 and the end of memory, so only 8 are read.
+		machine.set_reg_u16(Reg::AX, 0x3f00);
+		machine.set_reg_u16(Reg::BX, handle);
+		machine.set_reg_u16(Reg::CX, 16);
+		machine.set_reg_u16(Reg::DS, 0xf000);
+		machine.set_reg_u16(Reg::DX, 0xfff8);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), 8);
+		assert_eq!(&machine.memory[0xffff8 ..], b"01234567");
+		
+		// The next read carries on from where the short one stopped.
+		machine.set_reg_u16(Reg::AX, 0x3f00);
+		machine.set_reg_u16(Reg::CX, 16);
+		machine.set_reg_u16(Reg::DS, 0);
+		machine.set_reg_u16(Reg::DX, 0x2000);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 8);
+		assert_eq!(&machine.memory[0x2000 .. 0x2008], b"89abcdef");
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_force_duplicate_handle() {
+		let dir_path = make_test_dir("force_duplicate");
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		machine.memory[0x1000 .. 0x1008].copy_from_slice(b"OUT.TXT\0");
+		machine.memory[0x2000 .. 0x2003].copy_from_slice(b"Hi!");
+		let handle = call_dos(&mut handler, &mut machine, 0x3c00, 0, 0, 0x1000).unwrap();
+		
+		// Stdout goes to the file once it's a duplicate of the file's handle, and shares its position.
+		call_dos(&mut handler, &mut machine, 0x4600, handle, 1, 0).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, 1, 2, 0x2000), Ok(2));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, handle, 1, 0x2002), Ok(1));
+		assert_eq!(std::fs::read(dir_path.join("OUT.TXT")).unwrap(), b"Hi!");
+		// Making it a duplicate of stderr puts it back to the console.
+		call_dos(&mut handler, &mut machine, 0x4600, 2, 1, 0).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, 1, 3, 0x2000), Ok(3));
+		assert_eq!(std::fs::read(dir_path.join("OUT.TXT")).unwrap(), b"Hi!");
+		assert!(handler.redirected_std_handles.is_empty());
+		
+		// A handle that isn't open can be made a duplicate, and one that is gets closed first.
+		let other_handle = handle + 3;
+		call_dos(&mut handler, &mut machine, 0x4600, handle, other_handle, 0).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4200, other_handle, 0, 0), Ok(0));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, handle, 3, 0x3000), Ok(3));
+		assert_eq!(&machine.memory[0x3000 .. 0x3003], b"Hi!");
+		let duplicate_handle = call_dos(&mut handler, &mut machine, 0x4500, handle, 0, 0).unwrap();
+		assert_ne!(duplicate_handle, handle);
+		call_dos(&mut handler, &mut machine, 0x4600, handle, duplicate_handle, 0).unwrap();
+		
+		// The source has to be open, and the console can't go in a file handle.
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4600, 0x40, 1, 0), Err(DosErrorCode::InvalidFileHandle as u16));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4600, 1, handle, 0), Err(DosErrorCode::InvalidFileHandle as u16));
+		
+		// A program keeps a duplicate of stdout, redirects stdout, and puts it back from the
+		// duplicate. AL isn't looked at.
+		let saved_stdout = call_dos(&mut handler, &mut machine, 0x4500, 1, 0, 0).unwrap();
+		call_dos(&mut handler, &mut machine, 0x4601, handle, 1, 0).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, 1, 3, 0x2000), Ok(3));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, saved_stdout, 3, 0x2000), Ok(3));
+		call_dos(&mut handler, &mut machine, 0x4600, saved_stdout, 1, 0).unwrap();
+		assert!(handler.redirected_std_handles.is_empty());
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, 1, 3, 0x2000), Ok(3));
+		assert_eq!(std::fs::read(dir_path.join("OUT.TXT")).unwrap(), b"Hi!Hi!");
+		call_dos(&mut handler, &mut machine, 0x3e00, saved_stdout, 0, 0).unwrap();
+		assert!(handler.console_duplicate_handles.is_empty());
+		
+		for &open_handle in [handle, other_handle, duplicate_handle].iter() {
+			call_dos(&mut handler, &mut machine, 0x3e00, open_handle, 0, 0).unwrap();
+		}
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_file_locking() {
+		let dir_path = make_test_dir("file_locking");
+		std::fs::write(dir_path.join("DATA.DB"), b"0123456789abcdef").unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		machine.memory[0x1000 .. 0x1008].copy_from_slice(b"DATA.DB\0");
+		// Locks bytes 4 to 7, with the length in SI:DI.
+		let lock = |handler: &mut DosEventHandler, machine: &mut Machine8086, al: u16, handle: u16| {
+			machine.set_reg_u16(Reg::SI, 0);
+			machine.set_reg_u16(Reg::DI, 4);
+			call_dos(handler, machine, 0x5c00 | al, handle, 0, 4)
+		};
+		let first_handle = call_dos(&mut handler, &mut machine, 0x3d02, 0, 0, 0x1000).unwrap();
+		let second_handle = call_dos(&mut handler, &mut machine, 0x3d02, 0, 0, 0x1000).unwrap();
+		assert_eq!(lock(&mut handler, &mut machine, 0, first_handle), Ok(0));
+		assert_eq!(lock(&mut handler, &mut machine, 0, second_handle), Err(DosErrorCode::LockViolation as u16));
+		
+		// The other handle can use the bytes before the lock, but not a read or write that reaches it.
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, second_handle, 4, 0x2000), Ok(4));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, second_handle, 2, 0x2000), Err(DosErrorCode::LockViolation as u16));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4000, second_handle, 2, 0x2000), Err(DosErrorCode::LockViolation as u16));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, first_handle, 8, 0x2000), Ok(8));
+		assert_eq!(&machine.memory[0x2000 .. 0x2008], b"01234567");
+		
+		// Only the handle that locked the range can unlock it, and then the other can read it.
+		assert_eq!(lock(&mut handler, &mut machine, 1, second_handle), Err(DosErrorCode::LockViolation as u16));
+		assert_eq!(lock(&mut handler, &mut machine, 1, first_handle), Ok(0));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, second_handle, 2, 0x2000), Ok(2));
+		assert_eq!(&machine.memory[0x2000 .. 0x2002], b"45");
+		
+		// Closing a handle releases its locks.
+		assert_eq!(lock(&mut handler, &mut machine, 0, second_handle), Ok(0));
+		call_dos(&mut handler, &mut machine, 0x3e00, second_handle, 0, 0).unwrap();
+		assert_eq!(lock(&mut handler, &mut machine, 0, first_handle), Ok(0));
+		assert_eq!(lock(&mut handler, &mut machine, 0, 0x40), Err(DosErrorCode::InvalidFileHandle as u16));
+		
+		// Duplicates made with AH=45h and AH=46h share the locks, which stay until the last of them
+		// is closed.
+		let duplicate_handle = call_dos(&mut handler, &mut machine, 0x4500, first_handle, 0, 0).unwrap();
+		call_dos(&mut handler, &mut machine, 0x4600, duplicate_handle, 0x30, 0).unwrap();
+		let third_handle = call_dos(&mut handler, &mut machine, 0x3d02, 0, 0, 0x1000).unwrap();
+		assert_eq!(lock(&mut handler, &mut machine, 1, duplicate_handle), Ok(0));
+		assert_eq!(lock(&mut handler, &mut machine, 0, 0x30), Ok(0));
+		call_dos(&mut handler, &mut machine, 0x3e00, first_handle, 0, 0).unwrap();
+		call_dos(&mut handler, &mut machine, 0x3e00, 0x30, 0, 0).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, third_handle, 8, 0x2000), Err(DosErrorCode::LockViolation as u16));
+		call_dos(&mut handler, &mut machine, 0x3e00, duplicate_handle, 0, 0).unwrap();
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, third_handle, 8, 0x2000), Ok(8));
+		call_dos(&mut handler, &mut machine, 0x3e00, third_handle, 0, 0).unwrap();
+		assert!(handler.file_locks.is_empty());
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_create_psp_inherits_handles() {
+		let dir_path = make_test_dir("create_psp");
+		std::fs::write(dir_path.join("SAVE.DAT"), b"data").unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		let filename_addr = 0x1000;
+		machine.memory[filename_addr .. filename_addr + 9].copy_from_slice(b"SAVE.DAT\0");
+		let program_psp_start = (PROGRAM_PSP_SEGMENT as usize) << 4;
+		machine.memory[program_psp_start + 0x80 .. program_psp_start + 0x84].copy_from_slice(b"\x02/q\r");
+		machine.poke_u16(program_psp_start as u32 + 0x2c, 0x80);
+		
+		// AH=55h takes the new PSP's memory size in SI.
+		machine.set_reg_u16(Reg::SI, 20);
+		// One handle is inherited and one is opened with the no-inherit bit.
+		let inherited = call_dos(&mut handler, &mut machine, 0x3d00, 0, 0, filename_addr as u16).unwrap();
+		let opened_private = call_dos(&mut handler, &mut machine, 0x3d80, 0, 0, filename_addr as u16).unwrap();
+		
+		let handle_table = |machine: &Machine8086, psp_segment: u16| {
+			let psp_start = (psp_segment as usize) << 4;
+			machine.memory[psp_start + 0x18 .. psp_start + 0x18 + 20].to_vec()
+		};
+		let mut expected_table = vec![0xff; 20];
+		for &handle in [inherited, opened_private].iter() {
+			expected_table[handle as usize] = handle as u8;
+		}
+		
+		// AH=26h copies every handle, and the command tail and environment.
+		call_dos(&mut handler, &mut machine, 0x2600, 0, 0, 0x2000).unwrap();
+		assert_eq!(handle_table(&machine, 0x2000), expected_table);
+		assert_eq!(&machine.memory[0x20080 .. 0x20084], b"\x02/q\r");
+		assert_eq!(machine.peek_u16(0x2002c), 0x80);
+		
+		// AH=55h leaves out the handles that aren't inherited.
+		call_dos(&mut handler, &mut machine, 0x5500, 0, 0, 0x3000).unwrap();
+		expected_table[opened_private as usize] = 0xff;
+		assert_eq!(handle_table(&machine, 0x3000), expected_table);
+		assert_eq!(machine.peek_u16(0x30016), PROGRAM_PSP_SEGMENT);
+		assert_eq!(machine.peek_u16(0x30032), 20);
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_terminate_restores_vectors() {
+		let (mut handler, mut machine) = make_test_handler();
+		load_com_into_machine(&mut machine, &[0xb8, 0x02, 0x4c, 0xcd, 0x21], b"", &build_environment_block(&[], b"C:\\HOOK.COM"), &*handler.file_system).unwrap();
+		let vector = |machine: &Machine8086, interrupt_index: u32| (machine.peek_u16(interrupt_index * 4 + 2), machine.peek_u16(interrupt_index * 4));
+		let original_vectors: Vec<_> = (0x22 ..= 0x24).map(|interrupt_index| vector(&machine, interrupt_index)).collect();
+		// Int 22h goes to the terminate stub, since there's no parent program.
+		let (segment, offset) = original_vectors[0];
+		assert_eq!(&machine.memory[((segment as usize) << 4) + offset as usize ..][.. 4], &[0xb4, 0x4c, 0xcd, 0x21]);
+		let program_psp_start = (PROGRAM_PSP_SEGMENT as u32) << 4;
+		assert_eq!(machine.peek_u16(program_psp_start + 0x0e), original_vectors[1].1);
+		assert_eq!(machine.peek_u16(program_psp_start + 0x10), original_vectors[1].0);
+		
+		// The program hooks Ctrl+Break and the critical error handler without unhooking them.
+		for &interrupt_index in [0x23u8, 0x24].iter() {
+			machine.set_reg_u16(Reg::AX, 0x2500 | interrupt_index as u16);
+			machine.set_reg_u16(Reg::DS, 0x1234);
+			machine.set_reg_u16(Reg::DX, 0x0100 + interrupt_index as u16);
+			handler.handle_interrupt(&mut machine, 0x21);
+		}
+		assert_eq!(vector(&machine, 0x23), (0x1234, 0x0123));
+		// A PSP made now keeps the hooked vectors, as they're the ones in use when it's made.
+		machine.set_reg_u16(Reg::AX, 0x5500);
+		machine.set_reg_u16(Reg::DX, 0x3000);
+		machine.set_reg_u16(Reg::SI, 20);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!((machine.peek_u16(0x30010), machine.peek_u16(0x3000e)), (0x1234, 0x0123));
+		
+		machine.set_reg_u16(Reg::AX, 0x4c02);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(handler.result, DosInterruptResult::ShouldExit(2));
+		let restored_vectors: Vec<_> = (0x22 ..= 0x24).map(|interrupt_index| vector(&machine, interrupt_index)).collect();
+		assert_eq!(restored_vectors, original_vectors);
+	}
+	
+	#[test] fn test_palette_registers() {
+		let (mut handler, mut machine) = make_test_handler();
+		let palette_addr = 0x1000;
+		machine.set_reg_u16(Reg::ES, 0);
+		machine.set_reg_u16(Reg::DX, palette_addr as u16);
+		
+		// Save the palette, change a register and the border, then read them back.
+		machine.set_reg_u16(Reg::AX, 0x1009);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(&machine.memory[palette_addr .. palette_addr + 17], &DEFAULT_EGA_PALETTE[..]);
+		machine.set_reg_u16(Reg::AX, 0x1000);
+		machine.set_reg_u16(Reg::BX, 0x3f06);
+		handler.handle_interrupt(&mut machine, 0x10);
+		machine.set_reg_u16(Reg::AX, 0x1001);
+		machine.set_reg_u16(Reg::BX, 0x0100);
+		handler.handle_interrupt(&mut machine, 0x10);
+		machine.set_reg_u16(Reg::AX, 0x1007);
+		machine.set_reg_u16(Reg::BX, 0x0006);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u8(Reg::BX, RegHalf::High), 0x3f);
+		machine.set_reg_u16(Reg::AX, 0x1008);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u8(Reg::BX, RegHalf::High), 0x01);
+		
+		// Restoring the saved palette undoes the changes.
+		machine.set_reg_u16(Reg::AX, 0x1002);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.ega_palette, DEFAULT_EGA_PALETTE);
+		
+		// Only the 6 bits the registers have are kept.
+		machine.memory[palette_addr .. palette_addr + 17].iter_mut().for_each(|c| *c = 0xff);
+		machine.set_reg_u16(Reg::AX, 0x1002);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.ega_palette, [0x3f; 17]);
+		machine.set_reg_u16(Reg::AX, 0x1008);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u8(Reg::BX, RegHalf::High), 0x3f);
+		machine.set_reg_u16(Reg::AX, 0x1000);
+		machine.set_reg_u16(Reg::BX, 0xc106);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.ega_palette[6], 0x01);
+	}
+	
+	#[test] fn test_border_colour() {
+		let (mut handler, mut machine) = make_test_handler();
+		assert_eq!(handler.border_colour(), 0x00);
+		// Int 10h AH=0Bh takes a CGA colour, with the intensity bit where the CGA has it.
+		machine.set_reg_u16(Reg::AX, 0x0b00);
+		machine.set_reg_u16(Reg::BX, 0x0009);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.border_colour(), 0x11);
+		// BH=1 is the graphics palette, which leaves the border alone.
+		machine.set_reg_u16(Reg::BX, 0x0104);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.border_colour(), 0x11);
+		
+		// Reading port 0x3da makes the next write to 0x3c0 the index, and the one after the data.
+		handler.write_port(&mut machine, 0x3c0, 0x05);
+		handler.read_port(&mut machine, 0x3da);
+		handler.write_port(&mut machine, 0x3c0, 0x11);
+		handler.write_port(&mut machine, 0x3c0, 0x3c);
+		assert_eq!(handler.border_colour(), 0x3c);
+		assert_eq!(handler.ega_palette[5], DEFAULT_EGA_PALETTE[5]);
+		handler.write_port(&mut machine, 0x3c0, 0x02);
+		handler.write_port(&mut machine, 0x3c0, 0x14);
+		assert_eq!(handler.ega_palette[2], 0x14);
+		assert_eq!(handler.read_port(&mut machine, 0x3c1), 0x14);
+		
+		handler.write_port(&mut machine, 0x3d9, 0x24);
+		assert_eq!(handler.border_colour(), 0x04);
+		
+		// A mode set puts the palette and border back.
+		machine.set_reg_u16(Reg::AX, 0x0003);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.ega_palette, DEFAULT_EGA_PALETTE);
+	}
+	
+	#[test] fn test_mouse_text_cursor() {
+		let (mut handler, mut machine) = make_test_handler();
+		let call_mouse = |handler: &mut DosEventHandler, machine: &mut Machine8086, ax: u16, bx: u16, cx: u16, dx: u16| {
+			machine.set_reg_u16(Reg::AX, ax);
+			machine.set_reg_u16(Reg::BX, bx);
+			machine.set_reg_u16(Reg::CX, cx);
+			machine.set_reg_u16(Reg::DX, dx);
+			handler.handle_interrupt(machine, 0x33);
+		};
+		call_mouse(&mut handler, &mut machine, 0x0000, 0, 0, 0);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0);
+		
+		handler.mouse = Some(MouseDriver::new());
+		call_mouse(&mut handler, &mut machine, 0x0000, 0, 0, 0);
+		assert_eq!((machine.get_reg_u16(Reg::AX), machine.get_reg_u16(Reg::BX)), (0xffff, 2));
+		machine.poke_u16(0xb8000 + (80 + 2) * 2, 0x1e41);
+		// Virtual (16, 8) is the cell at column 2, row 1.
+		call_mouse(&mut handler, &mut machine, 0x0004, 0, 16, 8);
+		call_mouse(&mut handler, &mut machine, 0x0003, 0, 0, 0);
+		assert_eq!((machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX)), (16, 8));
+		let cell = |text: &[u8]| (text[(80 + 2) * 2], text[(80 + 2) * 2 + 1]);
+		// The cursor starts hidden.
+		assert_eq!(cell(&handler.get_composed_text(&machine)), (b'A', 0x1e));
+		call_mouse(&mut handler, &mut machine, 0x0001, 0, 0, 0);
+		assert_eq!(cell(&handler.get_composed_text(&machine)), (b'A', 0x61));
+		
+		// Hiding twice and showing once leaves it hidden.
+		call_mouse(&mut handler, &mut machine, 0x0002, 0, 0, 0);
+		call_mouse(&mut handler, &mut machine, 0x0002, 0, 0, 0);
+		call_mouse(&mut handler, &mut machine, 0x0001, 0, 0, 0);
+		assert_eq!(cell(&handler.get_composed_text(&machine)), (b'A', 0x1e));
+		call_mouse(&mut handler, &mut machine, 0x0001, 0, 0, 0);
+		
+		// The screen and cursor masks from AX=000Ah are used.
+		call_mouse(&mut handler, &mut machine, 0x000a, 0, 0x00ff, 0x4f00);
+		assert_eq!(cell(&handler.get_composed_text(&machine)), (b'A', 0x4f));
+		
+		// Moving into the exclusion area hides it.
+		machine.set_reg_u16(Reg::SI, 100);
+		machine.set_reg_u16(Reg::DI, 20);
+		call_mouse(&mut handler, &mut machine, 0x0010, 0, 0, 0);
+		assert_eq!(cell(&handler.get_composed_text(&machine)), (b'A', 0x1e));
+		
+		// Video memory is never changed by the cursor.
+		assert_eq!(machine.peek_u16(0xb8000 + (80 + 2) * 2), 0x1e41);
+		
+		// The range from AX=0007h and AX=0008h keeps the cursor in.
+		call_mouse(&mut handler, &mut machine, 0x0007, 0, 320, 8);
+		call_mouse(&mut handler, &mut machine, 0x0008, 0, 16, 24);
+		call_mouse(&mut handler, &mut machine, 0x0004, 0, 600, 100);
+		call_mouse(&mut handler, &mut machine, 0x0003, 0, 0, 0);
+		assert_eq!((machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX)), (320, 24));
+		
+		// Functions that aren't supported fail, rather than stopping the emulator.
+		handler.unknown_interrupt_action = UnknownFuncAction::ReturnError;
+		call_mouse(&mut handler, &mut machine, 0x000c, 0, 0, 0);
+		assert!(machine.get_flag(Flag::Carry));
+	}
+	
+	#[test] fn test_unknown_bios_functions() {
+		let (mut handler, mut machine) = make_test_handler();
+		handler.unknown_interrupt_action = UnknownFuncAction::ReturnError;
+		for &(interrupt_index, ax, bx) in &[(0x10, 0x1055, 0), (0x10, 0x1100, 0), (0x10, 0x1200, 0x0055), (0x10, 0x5000, 0), (0x13, 0x4100, 0), (0x16, 0x7700, 0)] {
+			machine.set_flag(Flag::Carry, false);
+			machine.set_reg_u16(Reg::AX, ax);
+			machine.set_reg_u16(Reg::BX, bx);
+			handler.handle_interrupt(&mut machine, interrupt_index);
+			assert!(machine.get_flag(Flag::Carry), "int {:x}h AX={:04x}", interrupt_index, ax);
+		}
+		// Int 13h also keeps the invalid function status for AH=01h to read back.
+		assert_eq!(machine.get_data_u8(&BIOS_DISKETTE_STATUS), DiskStatus::InvalidFunction as u8);
+		
+		// INTO and NMIs return without changing anything.
+		machine.set_flag(Flag::Carry, false);
+		machine.set_reg_u16(Reg::AX, 0x1234);
+		handler.handle_interrupt(&mut machine, 0x04);
+		handler.handle_interrupt(&mut machine, 0x02);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x1234);
+		assert!(!machine.get_flag(Flag::Carry));
+		
+		// A port with nothing on it reads as all ones, and writes to it are dropped.
+		assert_eq!(handler.read_port(&mut machine, 0x378), 0xff);
+		handler.write_port(&mut machine, 0x378, 0x55);
+		// So are commands for the PIT channels that aren't modelled.
+		let pit_channel0_access_mode = handler.port_states.pit_channel0_access_mode;
+		handler.write_port(&mut machine, 0x43, 0x54);
+		assert_eq!(handler.port_states.pit_channel0_access_mode, pit_channel0_access_mode);
+	}
+	
+	#[test] fn test_blink_toggle() {
+		let (mut handler, mut machine) = make_test_handler();
+		assert!(handler.blink_enabled);
+		machine.set_reg_u16(Reg::AX, 0x1003);
+		machine.set_reg_u16(Reg::BX, 0x0000);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert!(!handler.blink_enabled);
+		machine.set_reg_u16(Reg::AX, 0x1003);
+		machine.set_reg_u16(Reg::BX, 0x0001);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert!(handler.blink_enabled);
+		
+		// Setting the mode turns blinking back on.
+		machine.set_reg_u16(Reg::AX, 0x1003);
+		machine.set_reg_u16(Reg::BX, 0x0000);
+		handler.handle_interrupt(&mut machine, 0x10);
+		machine.set_reg_u16(Reg::AX, 0x0003);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert!(handler.blink_enabled);
+	}
+	
+	#[test] fn test_dos_output_mode() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::DX, b'X' as u16);
+		
+		// Output that only goes to stdout leaves the screen alone.
+		handler.dos_output_mode = DosOutputMode::Stdout;
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x02);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(handler.get_screen_text(&machine)[0][0], b' ');
+		assert_eq!(handler.get_cursor_position(&machine), (0, 0));
+		
+		handler.dos_output_mode = DosOutputMode::Both;
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x02);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(handler.get_screen_text(&machine)[0][0], b'X');
+		assert_eq!(handler.get_cursor_position(&machine), (1, 0));
+	}
+	
+	#[test] fn test_dos_idle() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::AX, 0x1234);
+		handler.handle_interrupt(&mut machine, 0x28);
+		assert_eq!(handler.result, DosInterruptResult::ShouldReturnAndWaitForEvents);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x1234);
+	}
+	
+	#[test] fn test_set_typematic() {
+		let (mut handler, mut machine) = make_test_handler();
+		let set_typematic = |handler: &mut DosEventHandler, machine: &mut Machine8086, al: u8, bx: u16| {
+			machine.set_reg_u16(Reg::AX, 0x0300 | al as u16);
+			machine.set_reg_u16(Reg::BX, bx);
+			handler.handle_interrupt(machine, 0x16);
+		};
+		set_typematic(&mut handler, &mut machine, 0x05, 0x031f);
+		assert_eq!(handler.keyboard.typematic, Typematic { rate: 0x1f, delay: 3 });
+		assert_eq!(handler.keyboard.typematic.delay_seconds(), 1.);
+		// PCjr subfunctions and ones that don't exist leave it alone.
+		set_typematic(&mut handler, &mut machine, 0x04, 0);
+		set_typematic(&mut handler, &mut machine, 0x42, 0);
+		assert_eq!(handler.keyboard.typematic, Typematic { rate: 0x1f, delay: 3 });
+		set_typematic(&mut handler, &mut machine, 0x00, 0);
+		assert_eq!(handler.keyboard.typematic, Typematic::default());
+	}
+	
+	#[test] fn test_keyboard_leds_follow_toggles() {
+		let (mut handler, mut machine) = make_test_handler();
+		let read_shift_status = |handler: &mut DosEventHandler, machine: &mut Machine8086| {
+			machine.set_reg_u16(Reg::AX, 0x0200);
+			handler.handle_interrupt(machine, 0x16);
+			machine.get_reg_u8(Reg::AX, RegHalf::Low)
+		};
+		handler.set_key_mod(&mut machine, KeyModType::CapsLock, true);
+		assert_eq!(handler.keyboard_leds(&machine), KeyboardLeds { scroll_lock: false, num_lock: false, caps_lock: true });
+		assert_eq!(machine.peek_u8(BIOS_START + 0x17), KeyModType::CapsLock.bit());
+		
+		// Setting the LEDs through the keyboard sets the toggles, leaving the other bits.
+		handler.set_key_mod(&mut machine, KeyModType::LeftShift, true);
+		handler.write_port(&mut machine, 0x60, 0xed);
+		assert_eq!(handler.read_port(&mut machine, 0x64) & 1, 1);
+		assert_eq!(handler.read_port(&mut machine, 0x60), 0xfa);
+		handler.write_port(&mut machine, 0x60, 0x03);
+		assert_eq!(handler.keyboard_leds(&machine), KeyboardLeds { scroll_lock: true, num_lock: true, caps_lock: false });
+		assert_eq!(read_shift_status(&mut handler, &mut machine), KeyModType::LeftShift.bit() | KeyModType::ScrollLock.bit() | KeyModType::NumLock.bit());
+		assert_eq!(machine.peek_u8(BIOS_START + 0x17), KeyModType::LeftShift.bit() | KeyModType::ScrollLock.bit() | KeyModType::NumLock.bit());
+		
+		// A program that turns Caps Lock on in the BIOS data gets its LED, and the shift status.
+		machine.poke_u8(BIOS_START + 0x17, KeyModType::CapsLock.bit());
+		assert_eq!(handler.keyboard_leds(&machine), KeyboardLeds { scroll_lock: false, num_lock: false, caps_lock: true });
+		assert_eq!(read_shift_status(&mut handler, &mut machine), KeyModType::CapsLock.bit());
+		assert!(handler.get_key_mod(&machine, KeyModType::CapsLock));
+		
+		// Ctrl and Alt are in the extended byte too.
+		handler.set_key_mod(&mut machine, KeyModType::Alt, true);
+		handler.set_key_mod(&mut machine, KeyModType::Ctrl, true);
+		handler.set_key_mod(&mut machine, KeyModType::Ctrl, false);
+		assert_eq!(machine.peek_u8(BIOS_START + 0x17), KeyModType::CapsLock.bit() | KeyModType::Alt.bit());
+		assert_eq!(machine.peek_u8(BIOS_START + 0x18), 0b10);
+		handler.set_key_mod(&mut machine, KeyModType::Alt, false);
+		handler.set_key_mod(&mut machine, KeyModType::NumLock, true);
+		
+		// The typematic command and int 16h AH=03h change the same settings.
+		handler.write_port(&mut machine, 0x60, 0xf3);
+		handler.write_port(&mut machine, 0x60, 0x45);
+		assert_eq!(handler.keyboard.typematic, Typematic { rate: 0x05, delay: 2 });
+		machine.set_reg_u16(Reg::AX, 0x0305);
+		machine.set_reg_u16(Reg::BX, 0x0010);
+		handler.handle_interrupt(&mut machine, 0x16);
+		assert_eq!(handler.keyboard.typematic, Typematic { rate: 0x10, delay: 0 });
+		
+		handler.set_key_mod(&mut machine, KeyModType::NumLock, false);
+		assert_eq!(handler.keyboard_leds(&machine), KeyboardLeds { scroll_lock: false, num_lock: false, caps_lock: true });
+		assert_eq!(machine.peek_u8(BIOS_START + 0x17), KeyModType::CapsLock.bit());
+		assert_eq!(machine.peek_u8(BIOS_START + 0x18), 0);
+	}
+	
+	#[test] fn test_int_20h_terminates() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::AX, 0x4c05);
+		handler.handle_interrupt(&mut machine, 0x20);
+		assert_eq!(handler.result, DosInterruptResult::ShouldExit(0));
+	}
+	
+	#[test] fn test_scrollback() {
+		let (mut handler, mut machine) = make_test_handler();
+		handler.scrollback = Some(Scrollback::new(2));
+		// Both DOS and BIOS output is kept, wherever the DOS output goes.
+		handler.dos_output_mode = DosOutputMode::Stdout;
+		machine.set_reg_u16(Reg::AX, 0x0200);
+		for &c in b"A\r\n" {
+			machine.set_reg_u8(Reg::DX, RegHalf::Low, c);
+			handler.handle_interrupt(&mut machine, 0x21);
+		}
+		for &c in b"BC" {
+			machine.set_reg_u16(Reg::AX, 0x0e00 + c as u16);
+			handler.handle_interrupt(&mut machine, 0x10);
+		}
+		assert_eq!(handler.scrollback.as_ref().unwrap().lines(), vec![&b"A"[..], b"BC"]);
+	}
+	
+	#[test] fn test_country_info() {
+		let (mut handler, mut machine) = make_test_handler();
+		let buffer_addr = 0x1000;
+		let get_country_info = |handler: &mut DosEventHandler, machine: &mut Machine8086, ax: u16, bx: u16, dx: u16| {
+			machine.set_reg_u16(Reg::AX, ax);
+			machine.set_reg_u16(Reg::BX, bx);
+			machine.set_reg_u16(Reg::DS, 0);
+			machine.set_reg_u16(Reg::DX, dx);
+			handler.handle_interrupt(machine, 0x21);
+			if machine.get_flag(Flag::Carry) { Err(machine.get_reg_u16(Reg::AX)) } else { Ok(machine.get_reg_u16(Reg::BX)) }
+		};
+		
+		assert_eq!(get_country_info(&mut handler, &mut machine, 0x3800, 0, buffer_addr as u16), Ok(1));
+		assert_eq!(&machine.memory[buffer_addr .. buffer_addr + COUNTRY_INFO_BYTES], &CountryInfo::united_states().to_bytes()[..]);
+		
+		// Switch to Germany with the code in BX, then get the current country.
+		assert_eq!(get_country_info(&mut handler, &mut machine, 0x38ff, 49, 0xffff), Ok(49));
+		assert_eq!(get_country_info(&mut handler, &mut machine, 0x3800, 0, buffer_addr as u16), Ok(49));
+		assert_eq!(machine.memory[buffer_addr + 0x09], b',');
+		
+		assert_eq!(get_country_info(&mut handler, &mut machine, 0x38ff, 999, buffer_addr as u16), Err(DosErrorCode::FileNotFound as u16));
+		assert_eq!(handler.country_info.country_code, 49);
+	}
+	
+	#[test] fn test_disk_reset() {
+		let dir_path = make_test_dir("disk_reset");
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		let handle = handler.file_system.create(b"SAVE.DAT", 0).unwrap();
+		handler.file_system.write(handle, b"saved").unwrap();
+		
+		// The file is still open, but its data is on disk.
+		machine.set_flag(Flag::Carry, true);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x0d);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert_eq!(std::fs::read(dir_path.join("SAVE.DAT")).unwrap(), b"saved");
+		handler.file_system.close(handle).unwrap();
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_media_id_ioctl() {
+		let dir_path = make_test_dir("media_id");
+		let (mut handler, mut machine) = make_test_handler();
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		file_system.enable_attribute_persistence();
+		handler.replace_file_system(Box::new(file_system));
+		let buffer_addr = 0x1000;
+		let call_ioctl = |handler: &mut DosEventHandler, machine: &mut Machine8086, cx: u16| {
+			machine.set_reg_u16(Reg::AX, 0x440d);
+			machine.set_reg_u16(Reg::BX, 0);
+			machine.set_reg_u16(Reg::CX, cx);
+			machine.set_reg_u16(Reg::DS, 0);
+			machine.set_reg_u16(Reg::DX, buffer_addr as u16);
+			handler.handle_interrupt(machine, 0x21);
+			if machine.get_flag(Flag::Carry) { Err(machine.get_reg_u16(Reg::AX)) } else { Ok(()) }
+		};
+		
+		// The default has no label, and a serial number that's the same for the same directory.
+		call_ioctl(&mut handler, &mut machine, 0x0866).unwrap();
+		let default_media_id = handler.file_system.media_id().unwrap();
+		assert_eq!(default_media_id, StandardDosFileSystem::new(dir_path.clone()).media_id().unwrap());
+		assert_eq!(&machine.memory[buffer_addr + 0x06 .. buffer_addr + 0x19], b"NO NAME    FAT16   ");
+		
+		let media_id = DosMediaId { serial_number: 0x1234abcd, volume_label: *b"SAVES      " };
+		machine.insert_contiguous_bytes(&media_id.to_bytes(), buffer_addr);
+		call_ioctl(&mut handler, &mut machine, 0x0846).unwrap();
+		machine.insert_contiguous_bytes(&[0; DOS_MEDIA_ID_BYTES], buffer_addr);
+		call_ioctl(&mut handler, &mut machine, 0x0866).unwrap();
+		assert_eq!(&machine.memory[buffer_addr + 0x02 .. buffer_addr + 0x06], &[0xcd, 0xab, 0x34, 0x12]);
+		assert_eq!(&machine.memory[buffer_addr + 0x06 .. buffer_addr + 0x19], b"SAVES      FAT16   ");
+		
+		// FindFirst for the volume label bit finds the new label.
+		machine.memory[buffer_addr .. buffer_addr + 2].copy_from_slice(b"*\0");
+		handler.disk_trasnsfer_address = 0x2000;
+		machine.set_reg_u16(Reg::AX, 0x4e00);
+		machine.set_reg_u16(Reg::CX, DOS_ATTRIBUTE_VOLUME_LABEL);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert_eq!(machine.memory[0x2000 + 0x15], DOS_ATTRIBUTE_VOLUME_LABEL as u8);
+		assert_eq!(&machine.memory[0x2000 + 0x1e .. 0x2000 + 0x24], b"SAVES\0");
+		
+		// The new media ID is kept by a new file system on the same directory.
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		file_system.enable_attribute_persistence();
+		assert_eq!(file_system.media_id(), Ok(media_id));
+		
+		assert_eq!(call_ioctl(&mut handler, &mut machine, 0x0860), Err(DosErrorCode::InvalidFunction as u16));
+		assert_eq!(call_ioctl(&mut handler, &mut machine, 0x0366), Err(DosErrorCode::InvalidFunction as u16));
+		
+		// A buffer at the top of memory is cut short rather than running off the end.
+		machine.memory[0xffff0 ..].copy_from_slice(&media_id.to_bytes()[.. 0x10]);
+		machine.set_reg_u16(Reg::AX, 0x440d);
+		machine.set_reg_u16(Reg::CX, 0x0846);
+		machine.set_reg_u16(Reg::DS, 0xffff);
+		machine.set_reg_u16(Reg::DX, 0);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert_eq!(handler.file_system.media_id().unwrap().volume_label, *b"SAVES     \0");
+		machine.set_reg_u16(Reg::AX, 0x440d);
+		machine.set_reg_u16(Reg::CX, 0x0866);
+		machine.set_reg_u16(Reg::DS, 0xf000);
+		machine.set_reg_u16(Reg::DX, 0xfff8);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert_eq!(&machine.memory[0xffff8 ..], &media_id.to_bytes()[.. 8]);
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_fcb_find() {
+		let dir_path = make_test_dir("fcb_find");
+		std::fs::write(dir_path.join("TOWN.ZZT"), b"town").unwrap();
+		std::fs::write(dir_path.join("CITY.ZZT"), b"city").unwrap();
+		std::fs::write(dir_path.join("README"), b"readme").unwrap();
+		std::fs::create_dir_all(dir_path.join("SAVES")).unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		let fcb_addr = 0x1000;
+		let dta_addr = 0x2000;
+		handler.disk_trasnsfer_address = dta_addr as u32;
+		let call_find = |handler: &mut DosEventHandler, machine: &mut Machine8086, ah: u8| {
+			machine.set_reg_u8(Reg::AX, RegHalf::High, ah);
+			machine.set_reg_u16(Reg::DS, 0);
+			machine.set_reg_u16(Reg::DX, fcb_addr as u16);
+			handler.handle_interrupt(machine, 0x21);
+			match machine.get_reg_u8(Reg::AX, RegHalf::Low) {
+				0 => Some(machine.memory[dta_addr .. dta_addr + 12].to_vec()),
+				0xff => None,
+				al => panic!("Unexpected AL: 0x{:x}", al),
 			}
-			0x3da => {
-				let status = self.port_states.cga_status_register;
-				self.set_cga_vertial_retrace(false);
-				status
+		};
+		let find_all = |handler: &mut DosEventHandler, machine: &mut Machine8086| {
+			let mut found = vec![];
+			let mut result = call_find(handler, machine, 0x11);
+			while let Some(fcb) = result {
+				found.push(fcb);
+				result = call_find(handler, machine, 0x12);
 			}
-			_ => panic!("Unhandled input port index: 0x{:02x}", port_index)
+			found.sort();
+			found
 		};
-		//println!("Port in({}): {}", port_index, value);
-		value
+		
+		machine.memory[fcb_addr .. fcb_addr + 12].copy_from_slice(b"\0????????ZZT");
+		assert_eq!(find_all(&mut handler, &mut machine), vec![b"\0CITY    ZZT".to_vec(), b"\0TOWN    ZZT".to_vec()]);
+		// '?' matches the padding of names without an extension too.
+		machine.memory[fcb_addr .. fcb_addr + 12].copy_from_slice(b"\x03???????????");
+		assert_eq!(find_all(&mut handler, &mut machine), vec![b"\x03CITY    ZZT".to_vec(), b"\x03README     ".to_vec(), b"\x03TOWN    ZZT".to_vec()]);
+		assert_eq!(machine.memory[dta_addr + 12], DOS_ATTRIBUTE_ARCHIVE as u8);
+		assert_eq!(&machine.memory[dta_addr + 29 .. dta_addr + 33], &[0; 4]);
+		machine.memory[fcb_addr .. fcb_addr + 12].copy_from_slice(b"\0TOWN    EXE");
+		assert_eq!(call_find(&mut handler, &mut machine, 0x11), None);
+		// Drive bytes past Z: don't name a drive.
+		for &drive in &[27, 0xc0] {
+			machine.memory[fcb_addr] = drive;
+			assert_eq!(call_find(&mut handler, &mut machine, 0x11), None);
+		}
+		// Nothing is found with an FCB or a DTA that goes past the end of memory.
+		machine.memory[fcb_addr .. fcb_addr + 12].copy_from_slice(b"\0????????ZZT");
+		handler.disk_trasnsfer_address = machine.memory.len() as u32 - 20;
+		assert_eq!(call_find(&mut handler, &mut machine, 0x11), None);
+		handler.disk_trasnsfer_address = dta_addr as u32;
+		machine.set_reg_u16(Reg::AX, 0x1100);
+		machine.set_reg_u16(Reg::DS, 0xffff);
+		machine.set_reg_u16(Reg::DX, 0xfff0);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0xff);
+		machine.set_reg_u16(Reg::DS, 0);
+		
+		// An extended FCB can find directories, and gets an extended FCB back.
+		machine.memory[fcb_addr .. fcb_addr + 7].copy_from_slice(&[0xff, 0, 0, 0, 0, 0, DOS_ATTRIBUTE_DIRECTORY as u8]);
+		machine.memory[fcb_addr + 7 .. fcb_addr + 19].copy_from_slice(b"\0SAVES??    ");
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x11);
+		machine.set_reg_u16(Reg::DX, fcb_addr as u16);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0);
+		assert_eq!(&machine.memory[dta_addr .. dta_addr + 19], b"\xff\0\0\0\0\0\x10\0SAVES      ");
+		assert_eq!(machine.memory[dta_addr + 7 + 12], DOS_ATTRIBUTE_DIRECTORY as u8);
+		std::fs::remove_dir_all(&dir_path).ok();
 	}
 	
-	fn handle_port_output(&mut self, machine: &mut Machine8086, port_index: u16, value: u16) {
-		//println!("Port out({}): {}", port_index, value);
-		match port_index {
-			0x42 => {
-				// TODO: PIT cassette and speaker
-				println!("PIT: {}", value);
+	#[test] fn test_find_searches_kept_by_dta() {
+		let dir_path = make_test_dir("find_by_dta");
+		for name in &["TOWN.ZZT", "CITY.ZZT", "A.TXT", "B.TXT"] {
+			std::fs::write(dir_path.join(name), b"").unwrap();
+		}
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		let find = |handler: &mut DosEventHandler, machine: &mut Machine8086, dta_addr: u32, search_spec: Option<&[u8]>| {
+			handler.disk_trasnsfer_address = dta_addr;
+			if let Some(search_spec) = search_spec {
+				machine.insert_contiguous_bytes(search_spec, 0x1000);
+				machine.set_reg_u16(Reg::AX, 0x4e00);
+				machine.set_reg_u16(Reg::CX, 0);
+				machine.set_reg_u16(Reg::DS, 0);
+				machine.set_reg_u16(Reg::DX, 0x1000);
+			} else {
+				machine.set_reg_u16(Reg::AX, 0x4f00);
 			}
-			0x43 => {
-				// TODO: Programmable interrupt timer (PIT), control register
-				println!("PIT Control: {}", value);
+			handler.handle_interrupt(machine, 0x21);
+			if machine.get_flag(Flag::Carry) {
+				assert_eq!(machine.get_reg_u16(Reg::AX), DosErrorCode::NoMoreFiles as u16);
+				None
+			} else {
+				Some(find_data_filename(&machine.memory[dta_addr as usize .. dta_addr as usize + DOS_FIND_DATA_BYTES]).to_vec())
 			}
-			0x61 => {
-				self.port_states.port_61 = value;
+		};
+		
+		// A second search in another DTA doesn't end the first one.
+		let mut zzt_names = vec![find(&mut handler, &mut machine, 0x2000, Some(b"*.ZZT\0")).unwrap()];
+		let mut txt_names = vec![find(&mut handler, &mut machine, 0x3000, Some(b"*.TXT\0")).unwrap()];
+		zzt_names.push(find(&mut handler, &mut machine, 0x2000, None).unwrap());
+		txt_names.push(find(&mut handler, &mut machine, 0x3000, None).unwrap());
+		assert_eq!(find(&mut handler, &mut machine, 0x2000, None), None);
+		assert_eq!(find(&mut handler, &mut machine, 0x3000, None), None);
+		zzt_names.sort();
+		txt_names.sort();
+		assert_eq!(zzt_names, vec![b"CITY.ZZT".to_vec(), b"TOWN.ZZT".to_vec()]);
+		assert_eq!(txt_names, vec![b"A.TXT".to_vec(), b"B.TXT".to_vec()]);
+		
+		// A DTA that no search was made with has nothing to find.
+		assert_eq!(find(&mut handler, &mut machine, 0x4000, None), None);
+		assert!(handler.file_searches.is_empty());
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_fcb_open_create() {
+		let dir_path = make_test_dir("fcb_open");
+		std::fs::write(dir_path.join("TOWN.ZZT"), b"town data").unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		let call_fcb = |handler: &mut DosEventHandler, machine: &mut Machine8086, ah: u8, fcb_addr: usize| {
+			machine.set_reg_u8(Reg::AX, RegHalf::High, ah);
+			machine.set_reg_u16(Reg::DS, 0);
+			machine.set_reg_u16(Reg::DX, fcb_addr as u16);
+			handler.handle_interrupt(machine, 0x21);
+			machine.get_reg_u8(Reg::AX, RegHalf::Low)
+		};
+		
+		let open_fcb_addr = 0x1000;
+		machine.memory[open_fcb_addr .. open_fcb_addr + 0x25].iter_mut().for_each(|c| *c = 0);
+		machine.memory[open_fcb_addr .. open_fcb_addr + 12].copy_from_slice(b"\0TOWN    ZZT");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x0f, open_fcb_addr), 0);
+		// The default drive is filled in, along with the record size and file size.
+		assert_eq!(machine.memory[open_fcb_addr], 3);
+		assert_eq!(&machine.memory[open_fcb_addr + 0x0c .. open_fcb_addr + 0x14], &[0, 0, 0x80, 0, 9, 0, 0, 0]);
+		let handle = handler.fcb_handles[&(open_fcb_addr as u32)];
+		let mut data = [0; 4];
+		assert_eq!(handler.file_system.read(handle, &mut data), Ok(4));
+		assert_eq!(&data, b"town");
+		
+		// Creating truncates, and works through an extended FCB.
+		let create_fcb_addr = 0x1100;
+		machine.memory[create_fcb_addr .. create_fcb_addr + 7].copy_from_slice(&[0xff, 0, 0, 0, 0, 0, 0]);
+		machine.memory[create_fcb_addr + 7 .. create_fcb_addr + 19].copy_from_slice(b"\x03SAVE    DAT");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x16, create_fcb_addr), 0);
+		assert_eq!(&machine.memory[create_fcb_addr + 7 + 0x10 .. create_fcb_addr + 7 + 0x14], &[0; 4]);
+		let handle = handler.fcb_handles[&(create_fcb_addr as u32)];
+		assert_eq!(handler.file_system.write(handle, b"saved"), Ok(5));
+		assert_eq!(std::fs::read(dir_path.join("SAVE.DAT")).unwrap(), b"saved");
+		
+		machine.memory[open_fcb_addr + 1 .. open_fcb_addr + 12].copy_from_slice(b"MISSING    ");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x0f, open_fcb_addr), 0xff);
+		// Drive bytes past Z: don't name a drive.
+		machine.memory[open_fcb_addr .. open_fcb_addr + 12].copy_from_slice(b"\xc0TOWN    ZZT");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x0f, open_fcb_addr), 0xff);
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x16, open_fcb_addr), 0xff);
+		// An FCB that goes past the end of memory can't be used.
+		for &(ds, dx) in &[(0xffff, 0xfff0), (0xffff, 0x0000)] {
+			for &(ah, expected_al) in &[(0x0f, 0xff), (0x16, 0xff), (0x14, 1), (0x15, 1)] {
+				machine.set_reg_u8(Reg::AX, RegHalf::High, ah);
+				machine.set_reg_u16(Reg::DS, ds);
+				machine.set_reg_u16(Reg::DX, dx);
+				handler.handle_interrupt(&mut machine, 0x21);
+				assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), expected_al);
 			}
-			0x201 => {
-				// TODO: Something about joystick one-shots?
+		}
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_console_handles() {
+		let (mut handler, mut machine) = make_test_handler();
+		let buffer_addr = 0x1000;
+		let call_handle = |handler: &mut DosEventHandler, machine: &mut Machine8086, ah: u8, handle: u16, count: u16| {
+			machine.set_reg_u8(Reg::AX, RegHalf::High, ah);
+			machine.set_reg_u16(Reg::BX, handle);
+			machine.set_reg_u16(Reg::CX, count);
+			machine.set_reg_u16(Reg::DS, 0);
+			machine.set_reg_u16(Reg::DX, buffer_addr as u16);
+			handler.handle_interrupt(machine, 0x21);
+			(machine.get_flag(Flag::Carry), machine.get_reg_u16(Reg::AX))
+		};
+		
+		// Reading stdin waits for a whole line, and Enter is read as CR and then LF.
+		call_handle(&mut handler, &mut machine, 0x3f, 0, 1);
+		assert_eq!(handler.result, DosInterruptResult::ShouldBlockForKeypress);
+		handler.key_press_queue.push_back(KeyPressInfo { scan_code: 0x1e, ascii_char: b'a' });
+		handler.key_press_queue.push_back(KeyPressInfo { scan_code: 0x48, ascii_char: 0 });
+		call_handle(&mut handler, &mut machine, 0x3f, 0, 1);
+		assert_eq!(handler.result, DosInterruptResult::ShouldBlockForKeypress);
+		handler.key_press_queue.push_back(KeyPressInfo { scan_code: 0x1c, ascii_char: b'\r' });
+		assert_eq!(call_handle(&mut handler, &mut machine, 0x3f, 0, 1), (false, 1));
+		assert_eq!(machine.memory[buffer_addr], b'a');
+		assert_eq!(call_handle(&mut handler, &mut machine, 0x3f, 0, 1), (false, 1));
+		assert_eq!(machine.memory[buffer_addr], b'\r');
+		assert_eq!(call_handle(&mut handler, &mut machine, 0x3f, 0, 1), (false, 1));
+		assert_eq!(machine.memory[buffer_addr], b'\n');
+		assert!(handler.key_press_queue.is_empty());
+		// The typed line is echoed.
+		assert_eq!(handler.get_screen_text(&machine)[0][0], b'a');
+		assert_eq!(handler.get_cursor_position(&machine), (0, 1));
+		
+		// Backspace takes back the last character typed, on the screen too.
+		for &(scan_code, ascii_char) in [(0x23, b'h'), (0x12, b'e'), (0x26, b'x'), (0x0e, 0x08), (0x17, b'i'), (0x1c, b'\r')].iter() {
+			handler.key_press_queue.push_back(KeyPressInfo { scan_code, ascii_char });
+		}
+		assert_eq!(call_handle(&mut handler, &mut machine, 0x3f, 0, 16), (false, 5));
+		assert_eq!(&machine.memory[buffer_addr .. buffer_addr + 5], b"hei\r\n");
+		assert_eq!(&handler.get_screen_text(&machine)[1][..4], b"hei ");
+		assert_eq!(handler.get_cursor_position(&machine), (0, 2));
+		
+		// Ctrl+Z written to stdout goes to the screen as it is.
+		machine.memory[buffer_addr .. buffer_addr + 2].copy_from_slice(b"\x1aZ");
+		assert_eq!(call_handle(&mut handler, &mut machine, 0x40, 1, 2), (false, 2));
+		assert_eq!(&handler.get_screen_text(&machine)[2][..2], b"\x1aZ");
+	}
+
+	
+	#[test] fn test_fcb_sequential_io() {
+		let dir_path = make_test_dir("fcb_io");
+		let town_data: Vec<u8> = (0 .. 200).map(|i| i as u8).collect();
+		std::fs::write(dir_path.join("TOWN.ZZT"), &town_data).unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		let dta_addr = 0x2000;
+		handler.disk_trasnsfer_address = dta_addr as u32;
+		let call_fcb = |handler: &mut DosEventHandler, machine: &mut Machine8086, ah: u8, fcb_addr: usize| {
+			machine.set_reg_u8(Reg::AX, RegHalf::High, ah);
+			machine.set_reg_u16(Reg::DS, 0);
+			machine.set_reg_u16(Reg::DX, fcb_addr as u16);
+			handler.handle_interrupt(machine, 0x21);
+			machine.get_reg_u8(Reg::AX, RegHalf::Low)
+		};
+		
+		let read_fcb_addr = 0x1000;
+		machine.memory[read_fcb_addr .. read_fcb_addr + 0x25].iter_mut().for_each(|c| *c = 0);
+		machine.memory[read_fcb_addr .. read_fcb_addr + 12].copy_from_slice(b"\0TOWN    ZZT");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x14, read_fcb_addr), 1);
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x0f, read_fcb_addr), 0);
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x14, read_fcb_addr), 0);
+		assert_eq!(&machine.memory[dta_addr .. dta_addr + 0x80], &town_data[.. 0x80]);
+		assert_eq!(machine.memory[read_fcb_addr + 0x20], 1);
+		// The last record is only partly there, and is padded with zeros.
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x14, read_fcb_addr), 3);
+		assert_eq!(&machine.memory[dta_addr .. dta_addr + 72], &town_data[0x80 ..]);
+		assert!(machine.memory[dta_addr + 72 .. dta_addr + 0x80].iter().all(|c| *c == 0));
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x14, read_fcb_addr), 1);
+		
+		// Records are numbered across blocks, and a smaller record size can be set after opening.
+		let write_fcb_addr = 0x1100;
+		machine.memory[write_fcb_addr .. write_fcb_addr + 0x25].iter_mut().for_each(|c| *c = 0);
+		machine.memory[write_fcb_addr .. write_fcb_addr + 12].copy_from_slice(b"\0SAVE    DAT");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x16, write_fcb_addr), 0);
+		machine.poke_u16(write_fcb_addr as u32 + 0x0e, 2);
+		machine.memory[write_fcb_addr + 0x20] = 127;
+		machine.memory[dta_addr .. dta_addr + 2].copy_from_slice(b"ab");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x15, write_fcb_addr), 0);
+		assert_eq!(machine.peek_u16(write_fcb_addr as u32 + 0x0c), 1);
+		assert_eq!(machine.memory[write_fcb_addr + 0x20], 0);
+		machine.memory[dta_addr .. dta_addr + 2].copy_from_slice(b"cd");
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x15, write_fcb_addr), 0);
+		assert_eq!(&machine.memory[write_fcb_addr + 0x10 .. write_fcb_addr + 0x14], &[0x02, 0x01, 0, 0]);
+		let saved = std::fs::read(dir_path.join("SAVE.DAT")).unwrap();
+		assert_eq!(saved.len(), 0x102);
+		assert_eq!(&saved[0xfc ..], b"\0\0abcd");
+		
+		// A record past where a file can reach is the end of the file, and the FCB stays where it was.
+		machine.poke_u16(write_fcb_addr as u32 + 0x0c, 0xffff);
+		machine.poke_u16(write_fcb_addr as u32 + 0x0e, 0xffff);
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x15, write_fcb_addr), 1);
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x14, write_fcb_addr), 1);
+		assert_eq!(machine.peek_u16(write_fcb_addr as u32 + 0x0c), 0xffff);
+		
+		// A record that would go past the end of memory from the DTA means the DTA is too small.
+		handler.disk_trasnsfer_address = machine.memory.len() as u32 - 0x100;
+		machine.poke_u16(write_fcb_addr as u32 + 0x0c, 0);
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x15, write_fcb_addr), 2);
+		assert_eq!(call_fcb(&mut handler, &mut machine, 0x14, write_fcb_addr), 2);
+		assert_eq!(machine.peek_u16(write_fcb_addr as u32 + 0x0c), 0);
+		assert_eq!(std::fs::read(dir_path.join("SAVE.DAT")).unwrap().len(), 0x102);
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	#[test] fn test_page_flipping() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.memory[0xb8000] = b'A';
+		machine.memory[0xb9000] = b'B';
+		assert_eq!(handler.get_screen_text(&machine)[0][0], b'A');
+		
+		// The second page is shown by setting the CRTC start address to 800h cells.
+		handler.handle_port_output(&mut machine, 0x3d4, 0x0c);
+		handler.handle_port_output(&mut machine, 0x3d5, 0x08);
+		handler.handle_port_output(&mut machine, 0x3d4, 0x0d);
+		handler.handle_port_output(&mut machine, 0x3d5, 0x00);
+		assert_eq!(handler.display_start_address(), 0xb9000);
+		assert_eq!(handler.get_screen_text(&machine)[0][0], b'B');
+		handler.handle_port_output(&mut machine, 0x3d4, 0x0c);
+		assert_eq!(handler.handle_port_input(&mut machine, 0x3d5), 0x08);
+		
+		// Int 10h AH=05h sets the start address to the page it selects.
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x05);
+		machine.set_reg_u8(Reg::AX, RegHalf::Low, 0);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.display_start_address(), 0xb8000);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x05);
+		machine.set_reg_u8(Reg::AX, RegHalf::Low, 1);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.display_start_address(), 0xb9000);
+		assert_eq!(machine.get_data_u8(&BIOS_ACTIVE_VIDEO_PAGE), 1);
+		assert_eq!(machine.get_data_u16(&BIOS_ACTIVE_PAGE_OFFSET), 0x1000);
+		
+		// Setting the video mode goes back to the first page.
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x00);
+		machine.set_reg_u8(Reg::AX, RegHalf::Low, 0x83);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.display_start_address(), 0xb8000);
+	}
+
+	
+	#[test] fn test_print_screen() {
+		let dir_path = make_test_dir("print_screen");
+		let (mut handler, mut machine) = make_test_handler();
+		for (offset, &c) in b"\xc9\xcd\xbb Score: 10".iter().enumerate() {
+			machine.memory[0xb8000 + offset * 2] = c;
+		}
+		machine.memory[0xb8000 + 160] = b'>';
+		
+		// Nothing is saved without a directory to save to.
+		handler.handle_interrupt(&mut machine, 0x05);
+		assert_eq!(std::fs::read_dir(&dir_path).unwrap().count(), 0);
+		
+		handler.print_screen_dir = Some(dir_path.clone());
+		handler.handle_interrupt(&mut machine, 0x05);
+		let saved_paths: Vec<_> = std::fs::read_dir(&dir_path).unwrap().map(|entry| entry.unwrap().path()).collect();
+		assert_eq!(saved_paths.len(), 1);
+		let text = std::fs::read_to_string(&saved_paths[0]).unwrap();
+		let lines: Vec<&str> = text.lines().collect();
+		assert_eq!(lines.len(), 25);
+		assert_eq!(lines[0], "╔═╗ Score: 10");
+		assert_eq!(lines[1], ">");
+		assert_eq!(lines[2], "");
+		assert_eq!(machine.get_data_u8(&BIOS_PRINT_SCREEN_STATUS), 0);
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+	
+	// Calls int 13h with the buffer at 2000:0000, returning the carry flag.
+	fn disk_call(handler: &mut DosEventHandler, machine: &mut Machine8086, ax: u16, cx: u16, dx: u16) -> bool {
+		machine.set_reg_u16(Reg::AX, ax);
+		machine.set_reg_u16(Reg::CX, cx);
+		machine.set_reg_u16(Reg::DX, dx);
+		machine.set_reg_u16(Reg::ES, 0x2000);
+		machine.set_reg_u16(Reg::BX, 0);
+		handler.handle_interrupt(machine, 0x13);
+		machine.get_flag(Flag::Carry)
+	}
+	
+	#[test] fn test_int_13h_sectors() {
+		let (mut handler, mut machine) = make_test_handler();
+		let image: Vec<u8> = (0 .. 368640).map(|i| (i / FLOPPY_SECTOR_BYTES) as u8).collect();
+		handler.floppy = Some(FloppyImage::new(image, false).unwrap());
+		
+		// Read the last sector of the first track and the first sector on the other head.
+		assert!(!disk_call(&mut handler, &mut machine, 0x0202, 0x0009, 0x0000));
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x0002);
+		assert_eq!(machine.memory[0x20000], 8);
+		assert_eq!(machine.memory[0x20000 + FLOPPY_SECTOR_BYTES], 9);
+		
+		// Write them back to the end of cylinder 1 and the start of cylinder 2, which has its
+		// sectors numbered from 1 again.
+		machine.memory[0x20000] = 0xaa;
+		assert!(!disk_call(&mut handler, &mut machine, 0x0302, 0x0109, 0x0100));
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x0002);
+		let data = handler.floppy.as_ref().unwrap().data();
+		assert_eq!(data[35 * FLOPPY_SECTOR_BYTES], 0xaa);
+		assert_eq!(data[36 * FLOPPY_SECTOR_BYTES], 9);
+		assert_eq!(data[37 * FLOPPY_SECTOR_BYTES], 37);
+		assert!(!disk_call(&mut handler, &mut machine, 0x0201, 0x0201, 0x0000));
+		assert_eq!(machine.memory[0x20000], 9);
+		
+		// Sector 10 isn't on a 360K disk, and neither is cylinder 40.
+		assert!(disk_call(&mut handler, &mut machine, 0x0201, 0x000a, 0x0000));
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x0400);
+		assert!(disk_call(&mut handler, &mut machine, 0x0201, 0x2801, 0x0000));
+		assert!(disk_call(&mut handler, &mut machine, 0x0100, 0x0000, 0x0000));
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::High), 0x04);
+		assert_eq!(machine.get_data_u8(&BIOS_DISKETTE_STATUS), 0x04);
+		assert!(!disk_call(&mut handler, &mut machine, 0x0000, 0x0000, 0x0000));
+		assert_eq!(machine.get_data_u8(&BIOS_DISKETTE_STATUS), 0);
+	}
+	
+	#[test] fn test_int_13h_drive_parameters() {
+		let (mut handler, mut machine) = make_test_handler();
+		initialise_bios_data_area(&mut machine);
+		handler.floppy = Some(FloppyImage::new(vec![0; 368640], false).unwrap());
+		assert!(!disk_call(&mut handler, &mut machine, 0x0800, 0x0000, 0x0000));
+		assert_eq!(machine.get_reg_u8(Reg::BX, RegHalf::Low), 1);
+		assert_eq!(machine.get_reg_u16(Reg::CX), 0x2709);
+		assert_eq!(machine.get_reg_u16(Reg::DX), 0x0101);
+		// The diskette parameter table has the sectors per track at offset 4.
+		assert_eq!(machine.peek_u8(machine.get_seg_reg(Reg::ES, Reg::DI) + 4), 18);
+		
+		handler.floppy = Some(FloppyImage::new(vec![0; 1474560], false).unwrap());
+		assert!(!disk_call(&mut handler, &mut machine, 0x0800, 0x0000, 0x0000));
+		assert_eq!(machine.get_reg_u8(Reg::BX, RegHalf::Low), 4);
+		assert_eq!(machine.get_reg_u16(Reg::CX), 0x4f12);
+		assert_eq!(machine.get_reg_u16(Reg::DX), 0x0101);
+		assert!(!disk_call(&mut handler, &mut machine, 0x1500, 0x0000, 0x0000));
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::High), 2);
+	}
+	
+	#[test] fn test_int_13h_errors() {
+		let (mut handler, mut machine) = make_test_handler();
+		handler.floppy = Some(FloppyImage::new(vec![0xe5; 737280], true).unwrap());
+		machine.memory[0x20000] = 0x12;
+		assert!(disk_call(&mut handler, &mut machine, 0x0301, 0x0001, 0x0000));
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x0300);
+		assert_eq!(handler.floppy.as_ref().unwrap().data()[0], 0xe5);
+		// It can still be read.
+		assert!(!disk_call(&mut handler, &mut machine, 0x0201, 0x0001, 0x0000));
+		assert_eq!(machine.memory[0x20000], 0xe5);
+		
+		// An empty drive times out, and there's no second floppy drive or hard disk.
+		handler.floppy = None;
+		assert!(disk_call(&mut handler, &mut machine, 0x0201, 0x0001, 0x0000));
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::High), 0x80);
+		assert_eq!(machine.get_data_u8(&BIOS_DISKETTE_STATUS), 0x80);
+		assert!(disk_call(&mut handler, &mut machine, 0x0800, 0x0000, 0x0000));
+		assert!(!disk_call(&mut handler, &mut machine, 0x1500, 0x0000, 0x0001));
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::High), 0);
+		assert!(disk_call(&mut handler, &mut machine, 0x0201, 0x0001, 0x0080));
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::High), 0x01);
+		assert_eq!(machine.get_data_u8(&BIOS_HARD_DISK_STATUS), 0x01);
+		assert!(!disk_call(&mut handler, &mut machine, 0x1500, 0x0000, 0x0080));
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::High), 0);
+	}
+	
+	#[test] fn test_fake_machine() {
+		// Interrupts can be checked against a machine with no CPU.
+		let (mut handler, _) = make_test_handler();
+		let mut machine = FakeMachine::new(1024*1024);
+		handler.init_machine(&mut machine);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x30);
+		handler.dispatch_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x1605);
+		
+		// Set the cursor position, then read it back.
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x02);
+		machine.set_reg_u8(Reg::BX, RegHalf::High, 0);
+		machine.set_reg_u16(Reg::DX, 0x0c28);
+		handler.dispatch_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_data_u16(&BIOS_CURSOR_POSITION[0]), 0x0c28);
+		machine.set_reg_u16(Reg::DX, 0);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x03);
+		handler.dispatch_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u16(Reg::DX), 0x0c28);
+	}
+	
+	#[test] fn test_handler_callback_stub() {
+		// A program that chains to int 21h's vector calls its stub, which calls back into the
+		// handler with the return address pointing just past the int instruction.
+		let (mut handler, mut machine) = make_test_handler();
+		let (stub_segment, stub_offset) = bios_stub_far_pointer(handler_callback_stub(0x21).unwrap());
+		machine.set_reg_u16(Reg::SS, 0x2000);
+		machine.set_reg_u16(Reg::SP, 0x100);
+		machine.poke_u16(0x20100, stub_offset + 2);
+		machine.poke_u16(0x20102, stub_segment);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x30);
+		handler.dispatch_interrupt(&mut machine, HANDLER_CALLBACK_INTERRUPT);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x1605);
+		
+		// Anything else that calls it gets an error.
+		handler.unknown_interrupt_action = UnknownFuncAction::ReturnError;
+		machine.poke_u16(0x20102, 0x1234);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x30);
+		handler.dispatch_interrupt(&mut machine, HANDLER_CALLBACK_INTERRUPT);
+		assert!(machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), DosErrorCode::InvalidFunction as u16);
+	}
+	
+	#[test] fn test_set_date_and_time() {
+		let (mut handler, mut machine) = make_test_handler();
+		let read_date = |handler: &mut DosEventHandler, machine: &mut Machine8086| {
+			machine.set_reg_u8(Reg::AX, RegHalf::High, 0x2a);
+			handler.handle_interrupt(machine, 0x21);
+			(machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX), machine.get_reg_u8(Reg::AX, RegHalf::Low))
+		};
+		let read_time = |handler: &mut DosEventHandler, machine: &mut Machine8086| {
+			machine.set_reg_u8(Reg::AX, RegHalf::High, 0x2c);
+			handler.handle_interrupt(machine, 0x21);
+			(machine.get_reg_u16(Reg::CX), machine.get_reg_u16(Reg::DX))
+		};
+		let read_ticks = |handler: &mut DosEventHandler, machine: &mut Machine8086| {
+			machine.set_reg_u8(Reg::AX, RegHalf::High, 0x00);
+			handler.handle_interrupt(machine, 0x1a);
+			(((machine.get_reg_u16(Reg::CX) as u32) << 16) + machine.get_reg_u16(Reg::DX) as u32, machine.get_reg_u8(Reg::AX, RegHalf::Low))
+		};
+		
+		// 30 February doesn't exist, so the date stays as it was.
+		machine.set_reg_u16(Reg::AX, 0x2b00);
+		machine.set_reg_u16(Reg::CX, 1991);
+		machine.set_reg_u16(Reg::DX, 0x021e);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0xff);
+		assert_eq!(read_date(&mut handler, &mut machine), (1980, 0x0101, 2));
+		
+		// Thursday 28 February 1991, 23:59:58.
+		machine.set_reg_u16(Reg::AX, 0x2b00);
+		machine.set_reg_u16(Reg::CX, 1991);
+		machine.set_reg_u16(Reg::DX, 0x021c);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0);
+		machine.set_reg_u16(Reg::AX, 0x2d00);
+		machine.set_reg_u16(Reg::CX, 0x1840);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0xff);
+		machine.set_reg_u16(Reg::AX, 0x2d00);
+		machine.set_reg_u16(Reg::CX, 0x173b);
+		machine.set_reg_u16(Reg::DX, 0x3a00);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u8(Reg::AX, RegHalf::Low), 0);
+		assert_eq!(read_date(&mut handler, &mut machine), (1991, 0x021c, 4));
+		assert_eq!(read_time(&mut handler, &mut machine), (0x173b, 0x3a00));
+		// The tick count was moved to the same time.
+		let (ticks, overflow) = read_ticks(&mut handler, &mut machine);
+		assert_eq!((BIOS_TICKS_PER_DAY - ticks, overflow), (35, 0));
+		
+		// CLOCK$ reads the same date and time: days since 1980, then minutes, hours, hundredths
+		// and seconds.
+		machine.set_reg_u16(Reg::AX, 0x3d00);
+		machine.set_reg_u16(Reg::DS, 0x2000);
+		machine.set_reg_u16(Reg::DX, 0);
+		machine.insert_contiguous_bytes(b"CLOCK$\0", 0x20000);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		let clock_handle = machine.get_reg_u16(Reg::AX);
+		machine.set_reg_u16(Reg::AX, 0x3f00);
+		machine.set_reg_u16(Reg::BX, clock_handle);
+		machine.set_reg_u16(Reg::CX, 6);
+		machine.set_reg_u16(Reg::DX, 0x10);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 6);
+		let days = DosDate::new(1991, 2, 28).unwrap().days_since_1980();
+		assert_eq!(&machine.memory[0x20010 .. 0x20016], &[days as u8, (days >> 8) as u8, 59, 23, 0, 58]);
+		
+		// Run past midnight. The date goes to 1 March, and the overflow is only reported once.
+		for _ in 0 .. 40 {
+			handler.seconds_since_start += STANDARD_TICK_SECONDS;
+			handler.handle_interrupt(&mut machine, 0x08);
+		}
+		assert_eq!(read_date(&mut handler, &mut machine), (1991, 0x0301, 5));
+		assert_eq!(read_time(&mut handler, &mut machine).0, 0x0000);
+		assert_eq!(read_ticks(&mut handler, &mut machine), (5, 1));
+		assert_eq!(read_ticks(&mut handler, &mut machine), (5, 0));
+		
+		// Writing a record to CLOCK$ sets the date and time too.
+		machine.insert_contiguous_bytes(&[days as u8, (days >> 8) as u8, 30, 12, 0, 0], 0x20010);
+		machine.set_reg_u16(Reg::AX, 0x4000);
+		machine.set_reg_u16(Reg::BX, clock_handle);
+		machine.set_reg_u16(Reg::CX, 6);
+		machine.set_reg_u16(Reg::DX, 0x10);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 6);
+		assert_eq!(read_date(&mut handler, &mut machine), (1991, 0x021c, 4));
+		assert_eq!(read_time(&mut handler, &mut machine), (0x0c1e, 0x0000));
+		machine.set_reg_u16(Reg::AX, 0x3e00);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert!(handler.clock_device_handles.is_empty());
+	}
+	
+	#[test] fn test_clock_device_handle_functions() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.insert_contiguous_bytes(b"CLOCK$\0", 0x2000);
+		let clock_handle = call_dos(&mut handler, &mut machine, 0x3d00, 0, 0, 0x2000).unwrap();
+		
+		// IOCTL says it's the clock device, and it's always ready.
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4400, clock_handle, 0, 0x2000), Ok(0x80c8));
+		assert_eq!(machine.get_reg_u16(Reg::DX), 0x80c8);
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4406, clock_handle, 0, 0x2000).map(|ax| ax as u8), Ok(0xff));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4407, clock_handle, 0, 0x2000).map(|ax| ax as u8), Ok(0xff));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4400, 1, 0, 0x2000), Ok(0x80d3));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4400, 0x30, 0, 0x2000), Err(DosErrorCode::InvalidFileHandle as u16));
+		
+		// Seeking leaves it at 0, and committing it does nothing.
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4200, clock_handle, 0, 0x2000), Ok(0));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4202, clock_handle, 0, 0x2000), Ok(0));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x6800, clock_handle, 0, 0x2000), Ok(0));
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x6800, 0x30, 0, 0x2000), Err(DosErrorCode::InvalidFileHandle as u16));
+		
+		// A duplicate, or a handle forced to be a duplicate, reads the clock too.
+		let duplicate_handle = call_dos(&mut handler, &mut machine, 0x4500, clock_handle, 0, 0x2000).unwrap();
+		assert_ne!(duplicate_handle, clock_handle);
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4600, clock_handle, 0x30, 0x2000), Ok(0));
+		for &handle in &[duplicate_handle, 0x30] {
+			assert_eq!(call_dos(&mut handler, &mut machine, 0x4400, handle, 0, 0x2000), Ok(0x80c8));
+			assert_eq!(call_dos(&mut handler, &mut machine, 0x3f00, handle, CLOCK_DEVICE_RECORD_BYTES as u16, 0x2000), Ok(CLOCK_DEVICE_RECORD_BYTES as u16));
+		}
+		// The standard handles can't be the clock.
+		assert_eq!(call_dos(&mut handler, &mut machine, 0x4600, clock_handle, 1, 0x2000), Err(DosErrorCode::InvalidFileHandle as u16));
+		
+		for &handle in &[clock_handle, duplicate_handle, 0x30] {
+			assert_eq!(call_dos(&mut handler, &mut machine, 0x3e00, handle, 0, 0x2000), Ok(0));
+		}
+		assert!(handler.clock_device_handles.is_empty());
+	}
+	
+	#[test] fn test_free_environment() {
+		let (mut handler, mut machine) = make_test_handler();
+		let environment_block = build_environment_block(&[(b"PATH".to_vec(), b"C:\\".to_vec())], b"C:\\GAME.COM");
+		load_com_into_machine(&mut machine, &[0xb8, 0x00, 0x4c, 0xcd, 0x21], b"", &environment_block, &*handler.file_system).unwrap();
+		let environment_segment = machine.peek_u16(((PROGRAM_PSP_SEGMENT as u32) << 4) + 0x2c);
+		assert_eq!(dos_memory::allocated_environment_block(&machine, PROGRAM_PSP_SEGMENT), Some(b"PATH=C:\\\0\0".to_vec()));
+		
+		// The program shrinks itself to 64K, then frees its environment.
+		machine.set_reg_u16(Reg::AX, 0x4a00);
+		machine.set_reg_u16(Reg::ES, PROGRAM_PSP_SEGMENT);
+		machine.set_reg_u16(Reg::BX, 0x1000);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		machine.set_reg_u16(Reg::AX, 0x4900);
+		machine.set_reg_u16(Reg::ES, environment_segment);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert!(dos_memory::memory_blocks(&machine).is_ok());
+		// There's no environment left to copy to a child.
+		assert_eq!(dos_memory::allocated_environment_block(&machine, PROGRAM_PSP_SEGMENT), None);
+		// Freeing it again is fine, but not a segment that isn't a block.
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		machine.set_reg_u16(Reg::ES, environment_segment + 1);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), DosErrorCode::InvalidMemoryBlockAddress as u16);
+		
+		// A small block goes in the space the environment was in, but the PSP still points there.
+		machine.set_reg_u16(Reg::AX, 0x4800);
+		machine.set_reg_u16(Reg::BX, 0x10);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(!machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), environment_segment);
+		assert_eq!(machine.peek_u16(((PROGRAM_PSP_SEGMENT as u32) << 4) + 0x2c), environment_segment);
+		let blocks = dos_memory::memory_blocks(&machine).unwrap();
+		assert_eq!(blocks.iter().map(|block| (block.segment(), block.paragraphs, block.is_free())).collect::<Vec<_>>(),
+			vec![(environment_segment, 0x10, false), (environment_segment + 0x11, PROGRAM_PSP_SEGMENT - environment_segment - 0x12, true), (PROGRAM_PSP_SEGMENT, 0x1000, false), (PROGRAM_PSP_SEGMENT + 0x1001, 0xa000 - PROGRAM_PSP_SEGMENT - 0x1001, true)]);
+		
+		// Too much memory fails with the biggest block there is.
+		machine.set_reg_u16(Reg::AX, 0x4800);
+		machine.set_reg_u16(Reg::BX, 0xffff);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert!(machine.get_flag(Flag::Carry));
+		assert_eq!(machine.get_reg_u16(Reg::AX), DosErrorCode::InsufficientMemory as u16);
+		assert_eq!(machine.get_reg_u16(Reg::BX), 0xa000 - PROGRAM_PSP_SEGMENT - 0x1001);
+		
+		// A program walking the chain itself starts from the list of lists, and gets to the end of
+		// conventional memory on the 'Z' block.
+		machine.set_reg_u16(Reg::AX, 0x5200);
+		handler.handle_interrupt(&mut machine, 0x21);
+		let list_of_lists_addr = ((machine.get_reg_u16(Reg::ES) as u32) << 4) + machine.get_reg_u16(Reg::BX) as u32;
+		let mut mcb_segment = machine.peek_u16(list_of_lists_addr - 2) as u32;
+		let mut signatures = vec![];
+		loop {
+			let signature = machine.peek_u8(mcb_segment << 4);
+			signatures.push(signature);
+			mcb_segment += 1 + machine.peek_u16((mcb_segment << 4) + 3) as u32;
+			if signature != b'M' {
+				break;
 			}
-			0x3d4 => {
-				self.port_states.crt_index_register = value;
+		}
+		assert_eq!(signatures, b"MMMZ".to_vec());
+		assert_eq!(mcb_segment, 0xa000);
+	}
+	
+	#[test] fn test_get_video_mode() {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.set_reg_u16(Reg::AX, 0x0502);
+		handler.handle_interrupt(&mut machine, 0x10);
+		machine.set_reg_u16(Reg::AX, 0x0f00);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x5003);
+		assert_eq!(machine.get_reg_u8(Reg::BX, RegHalf::High), 2);
+		
+		// The mode and columns come from the current mode rather than being fixed.
+		handler.video_mode = VideoMode { mode_index: 0x07, text_dims: (40, 25), ..handler.video_mode.clone() };
+		handler.init_machine(&mut machine);
+		machine.set_reg_u16(Reg::AX, 0x0f00);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x2807);
+		assert_eq!(machine.get_data_u8(&BIOS_VIDEO_MODE_INDEX), 0x07);
+	}
+	
+	#[test] fn test_headless_fast_forward() {
+		// mov ax, 40h; mov ds, ax
+		// intro: mov al, [6ch]; cmp al, 10; jb intro
+		// mov ah, 01h; int 16h
+		// menu: mov al, [6ch]; cmp al, 20; jb menu
+		// mov ax, 4c00h; int 21h
+		let program = [
+			0xb8, 0x40, 0x00, 0x8e, 0xd8,
+			0xa0, 0x6c, 0x00, 0x3c, 0x0a, 0x72, 0xf9,
+			0xb4, 0x01, 0xcd, 0x16,
+			0xa0, 0x6c, 0x00, 0x3c, 0x14, 0x72, 0xf9,
+			0xb8, 0x00, 0x4c, 0xcd, 0x21,
+		];
+		let run = |startup: HeadlessStartup| {
+			let (mut handler, mut machine) = make_test_handler();
+			load_com_into_machine(&mut machine, &program, b"", &build_environment_block(&[], b"C:\\INTRO.COM"), &*handler.file_system).unwrap();
+			let result = handler.run_headless(&mut machine, 100_000, startup);
+			(result, machine.number_of_parsed_instructions())
+		};
+		// 20 ticks at the normal rate.
+		let (result, steps) = run(HeadlessStartup::Normal);
+		assert_eq!(result, Ok(0));
+		assert!((20 * HEADLESS_STEPS_PER_TIMER_INTERRUPT .. 21 * HEADLESS_STEPS_PER_TIMER_INTERRUPT).contains(&steps));
+		// Only the 10 ticks after the key check are at the normal rate.
+		let (result, steps) = run(HeadlessStartup::FastForwardUntilInput);
+		assert_eq!(result, Ok(0));
+		assert!((10 * HEADLESS_STEPS_PER_TIMER_INTERRUPT .. 11 * HEADLESS_STEPS_PER_TIMER_INTERRUPT).contains(&steps));
+		let (result, steps) = run(HeadlessStartup::FastForwardTicks(20));
+		assert_eq!(result, Ok(0));
+		assert!(steps < 21 * HEADLESS_FAST_FORWARD_STEPS_PER_TIMER_INTERRUPT);
+	}
+	
+	// A call to an int 21h function for test_int_21h_register_outputs: AX, the other registers it
+	// takes, the registers it returns something in (None when the value depends on the host), and
+	// whether it sets or clears the carry flag.
+	struct RegisterOutputCase {
+		ax: u16,
+		inputs: &'static [(Reg, u16)],
+		outputs: &'static [(Reg, Option<u16>)],
+		carry: Option<bool>,
+	}
+	
+	#[test] fn test_int_21h_register_outputs() {
+		const STRINGS: u16 = 0x2000;
+		const DATA_FILE_SIZE: usize = 0x12345;
+		let checked_regs = [Reg::AX, Reg::BX, Reg::CX, Reg::DX, Reg::SI, Reg::DI, Reg::BP, Reg::DS, Reg::ES];
+		let canaries = [0xa0a0, 0xb1b1, 0xc2c2, 0xd3d3, 0x5151, 0xd1d1, 0xb9b9, 0x5a5a, 0xe5e5];
+		let case = |ax, inputs, outputs, carry| RegisterOutputCase { ax, inputs, outputs, carry };
+		// The strings and buffers are in segment STRINGS, and the handles are the ones the file
+		// system gives out in this order. The FCB file keeps handle 6 open.
+		let cases = [
+			case(0x1a00, &[(Reg::DS, STRINGS), (Reg::DX, 0x400)], &[], None),
+			case(0x0200, &[(Reg::DX, b'A' as u16)], &[], None),
+			case(0x0900, &[(Reg::DS, STRINGS), (Reg::DX, 0x080)], &[], None),
+			case(0x0d00, &[], &[], Some(false)),
+			case(0x0e02, &[(Reg::DX, 2)], &[(Reg::AX, Some(0x0e03))], None),
+			case(0x1600, &[(Reg::DS, STRINGS), (Reg::DX, 0x100)], &[(Reg::AX, Some(0x1600))], None),
+			case(0x1500, &[(Reg::DS, STRINGS), (Reg::DX, 0x100)], &[(Reg::AX, Some(0x1500))], None),
+			case(0x0f00, &[(Reg::DS, STRINGS), (Reg::DX, 0x100)], &[(Reg::AX, Some(0x0f00))], None),
+			case(0x1400, &[(Reg::DS, STRINGS), (Reg::DX, 0x100)], &[(Reg::AX, Some(0x1401))], None),
+			case(0x1100, &[(Reg::DS, STRINGS), (Reg::DX, 0x100)], &[(Reg::AX, Some(0x1100))], None),
+			case(0x1200, &[(Reg::DS, STRINGS), (Reg::DX, 0x100)], &[(Reg::AX, Some(0x12ff))], None),
+			case(0x1900, &[], &[(Reg::AX, Some(0x1902))], None),
+			case(0x1b00, &[], &[(Reg::AX, None), (Reg::BX, None), (Reg::CX, None), (Reg::DX, None), (Reg::DS, None)], None),
+			case(0x1c00, &[(Reg::DX, 0)], &[(Reg::AX, None), (Reg::BX, None), (Reg::CX, None), (Reg::DX, None), (Reg::DS, None)], None),
+			case(0x2560, &[(Reg::DS, 0x1234), (Reg::DX, 0x5678)], &[], None),
+			case(0x3560, &[], &[(Reg::BX, Some(0x5678)), (Reg::ES, Some(0x1234))], None),
+			case(0x2600, &[(Reg::DX, 0x3000)], &[], None),
+			case(0x2900, &[(Reg::DS, STRINGS), (Reg::SI, 0x0a0), (Reg::ES, STRINGS), (Reg::DI, 0x600)], &[(Reg::AX, Some(0x2900)), (Reg::SI, Some(0x0a7))], None),
+			case(0x2a00, &[], &[(Reg::AX, None), (Reg::CX, None), (Reg::DX, None)], None),
+			case(0x2b00, &[(Reg::CX, 2000), (Reg::DX, 0x0101)], &[(Reg::AX, Some(0x2b00))], None),
+			case(0x2b00, &[(Reg::CX, 2000), (Reg::DX, 0x0d01)], &[(Reg::AX, Some(0x2bff))], None),
+			case(0x2c00, &[], &[(Reg::CX, None), (Reg::DX, None)], None),
+			case(0x2d00, &[(Reg::CX, 0x0c00), (Reg::DX, 0)], &[(Reg::AX, Some(0x2d00))], None),
+			case(0x3000, &[], &[(Reg::AX, Some(0x1605)), (Reg::BX, Some(0xff00)), (Reg::CX, Some(0))], None),
+			case(0x3300, &[(Reg::DX, 0x1234)], &[(Reg::DX, Some(0x1200))], None),
+			case(0x3301, &[(Reg::DX, 0x0001)], &[], None),
+			case(0x3305, &[], &[(Reg::DX, Some(0xd303))], None),
+			case(0x3600, &[(Reg::DX, 0)], &[(Reg::AX, None), (Reg::BX, None), (Reg::CX, None), (Reg::DX, None)], None),
+			case(0x3800, &[(Reg::DS, STRINGS), (Reg::DX, 0x200)], &[(Reg::BX, Some(1))], Some(false)),
+			case(0x3b00, &[(Reg::DS, STRINGS), (Reg::DX, 0x060)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x3c00, &[(Reg::DS, STRINGS), (Reg::DX, 0x020), (Reg::CX, 0)], &[(Reg::AX, Some(5))], Some(false)),
+			case(0x3e00, &[(Reg::BX, 5)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x3e00, &[(Reg::BX, 5)], &[(Reg::AX, Some(DosErrorCode::InvalidFileHandle as u16))], Some(true)),
+			case(0x3d02, &[(Reg::DS, STRINGS), (Reg::DX, 0x000)], &[(Reg::AX, Some(5))], Some(false)),
+			case(0x3f00, &[(Reg::BX, 5), (Reg::CX, 16), (Reg::DS, STRINGS), (Reg::DX, 0x200)], &[(Reg::AX, Some(16))], Some(false)),
+			case(0x4000, &[(Reg::BX, 5), (Reg::CX, 4), (Reg::DS, STRINGS), (Reg::DX, 0x200)], &[(Reg::AX, Some(4))], Some(false)),
+			case(0x4201, &[(Reg::BX, 5), (Reg::CX, 0), (Reg::DX, 0)], &[(Reg::AX, Some(20)), (Reg::DX, Some(0))], Some(false)),
+			case(0x4202, &[(Reg::BX, 5), (Reg::CX, 0), (Reg::DX, 0)], &[(Reg::AX, Some(0x2345)), (Reg::DX, Some(1))], Some(false)),
+			case(0x4201, &[(Reg::BX, 5), (Reg::CX, 0), (Reg::DX, 0)], &[(Reg::AX, Some(0x2345)), (Reg::DX, Some(1))], Some(false)),
+			case(0x4200, &[(Reg::BX, 5), (Reg::CX, 1), (Reg::DX, 0)], &[(Reg::AX, Some(0)), (Reg::DX, Some(1))], Some(false)),
+			case(0x4203, &[(Reg::BX, 5)], &[(Reg::AX, Some(DosErrorCode::InvalidFunction as u16))], Some(true)),
+			case(0x4301, &[(Reg::DS, STRINGS), (Reg::DX, 0x000), (Reg::CX, DOS_ATTRIBUTE_ARCHIVE)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x4300, &[(Reg::DS, STRINGS), (Reg::DX, 0x000)], &[(Reg::AX, Some(0)), (Reg::CX, Some(DOS_ATTRIBUTE_ARCHIVE))], Some(false)),
+			case(0x4400, &[(Reg::BX, 0)], &[(Reg::AX, Some(0x80d3)), (Reg::DX, Some(0x80d3))], Some(false)),
+			case(0x4406, &[(Reg::BX, 5)], &[(Reg::AX, Some(0x44ff))], Some(false)),
+			case(0x4407, &[(Reg::BX, 1)], &[(Reg::AX, Some(0x44ff))], Some(false)),
+			case(0x44ff, &[], &[(Reg::AX, Some(DosErrorCode::InvalidFunction as u16))], Some(true)),
+			case(0x4500, &[(Reg::BX, 5)], &[(Reg::AX, Some(7))], Some(false)),
+			case(0x4600, &[(Reg::BX, 5), (Reg::CX, 7)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x5c00, &[(Reg::BX, 5), (Reg::CX, 0), (Reg::DX, 0), (Reg::SI, 0), (Reg::DI, 16)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x5c00, &[(Reg::BX, 7), (Reg::CX, 0), (Reg::DX, 8), (Reg::SI, 0), (Reg::DI, 16)], &[(Reg::AX, Some(DosErrorCode::LockViolation as u16))], Some(true)),
+			case(0x5c01, &[(Reg::BX, 5), (Reg::CX, 0), (Reg::DX, 0), (Reg::SI, 0), (Reg::DI, 16)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x5c02, &[(Reg::BX, 5)], &[(Reg::AX, Some(DosErrorCode::InvalidFunction as u16))], Some(true)),
+			case(0x4700, &[(Reg::DX, 0), (Reg::DS, STRINGS), (Reg::SI, 0x200)], &[(Reg::AX, Some(0x0100))], Some(false)),
+			case(0x4a00, &[(Reg::ES, PROGRAM_PSP_SEGMENT), (Reg::BX, 0x1000)], &[], Some(false)),
+			case(0x4800, &[(Reg::BX, 0x10)], &[(Reg::AX, Some(PROGRAM_PSP_SEGMENT + 0x1001))], Some(false)),
+			case(0x4900, &[(Reg::ES, PROGRAM_PSP_SEGMENT + 0x1001)], &[], Some(false)),
+			case(0x4800, &[(Reg::BX, 0xffff)], &[(Reg::AX, Some(DosErrorCode::InsufficientMemory as u16)), (Reg::BX, None)], Some(true)),
+			case(0x4e00, &[(Reg::DS, STRINGS), (Reg::DX, 0x040), (Reg::CX, 0)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x4f00, &[], &[(Reg::AX, Some(DosErrorCode::NoMoreFiles as u16))], Some(true)),
+			case(0x4100, &[(Reg::DS, STRINGS), (Reg::DX, 0x020)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x5200, &[], &[(Reg::BX, Some(DOS_LIST_OF_LISTS_OFFSET)), (Reg::ES, Some(DOS_TABLES_SEGMENT))], None),
+			case(0x5500, &[(Reg::DX, 0x3100), (Reg::SI, 20)], &[], None),
+			case(0x6300, &[], &[(Reg::AX, Some(0x6300)), (Reg::DS, Some(DOS_DATA_SEGMENT)), (Reg::SI, Some(DOS_DBCS_LEAD_BYTE_TABLE_OFFSET))], None),
+			case(0x6301, &[], &[(Reg::AX, Some(0x6300))], None),
+			case(0x6302, &[], &[(Reg::AX, Some(0x6300)), (Reg::DX, Some(0xd300))], None),
+			case(0x6800, &[(Reg::BX, 6)], &[(Reg::AX, Some(0))], Some(false)),
+			case(0x4c07, &[], &[], None),
+		];
+		
+		let dir_path = make_test_dir("register_outputs");
+		std::fs::write(dir_path.join("DATA.TXT"), vec![b'x'; DATA_FILE_SIZE]).unwrap();
+		let (mut handler, mut machine) = make_test_handler();
+		// AX=44FFh checks the registers of an unknown function.
+		handler.unknown_dos_func_action = UnknownFuncAction::ReturnError;
+		handler.replace_file_system(Box::new(StandardDosFileSystem::new(dir_path.clone())));
+		// A program is loaded so there are memory blocks to allocate from.
+		load_com_into_machine(&mut machine, &[0xb8, 0x00, 0x4c, 0xcd, 0x21], b"", &build_environment_block(&[], b"C:\\TEST.COM"), &*handler.file_system).unwrap();
+		let strings_addr = STRINGS as usize * 16;
+		for &(offset, bytes) in [(0x000, &b"DATA.TXT\0"[..]), (0x020, b"NEW.DAT\0"), (0x040, b"*.TXT\0"), (0x060, b"\\\0"), (0x080, b"hi$"), (0x0a0, b"FCB.DAT\0"), (0x100, b"\0FCB     DAT")].iter() {
+			machine.memory[strings_addr + offset .. strings_addr + offset + bytes.len()].copy_from_slice(bytes);
+		}
+		
+		let mut mismatches = vec![];
+		for case in &cases {
+			for (&reg, &canary) in checked_regs.iter().zip(canaries.iter()) {
+				machine.set_reg_u16(reg, canary);
 			}
-			0x3d5 => {
-				// TODO: CRT data register
+			machine.set_reg_u16(Reg::AX, case.ax);
+			for &(reg, value) in case.inputs {
+				machine.set_reg_u16(reg, value);
 			}
-			0x3d9 => {
-				// TODO: CGA palette register.
-				self.port_states.cga_palette_register = value;
+			let before: Vec<u16> = checked_regs.iter().map(|&reg| machine.get_reg_u16(reg)).collect();
+			// The carry flag starts out the opposite of how the function should leave it.
+			machine.set_flag(Flag::Carry, case.carry != Some(true));
+			handler.handle_interrupt(&mut machine, 0x21);
+			for (&reg, &before_value) in checked_regs.iter().zip(before.iter()) {
+				let value = machine.get_reg_u16(reg);
+				let expected = match case.outputs.iter().find(|(output_reg, _)| *output_reg == reg) {
+					Some(&(_, expected)) => expected,
+					None => Some(before_value),
+				};
+				if let Some(expected) = expected.filter(|&expected| expected != value) {
+					mismatches.push(format!("AX={:04x} {:?}={:04x}, expected {:04x}", case.ax, reg, value, expected));
+				}
+			}
+			if let Some(carry) = case.carry {
+				if machine.get_flag(Flag::Carry) != carry {
+					mismatches.push(format!("AX={:04x} carry={}", case.ax, !carry));
+				}
 			}
-			_ => panic!("Unhandled output port index: 0x{:02x}", port_index)
 		}
+		assert_eq!(mismatches, Vec::<String>::new());
+		assert_eq!(handler.result, DosInterruptResult::ShouldExit(7));
+		
+		// Every function in the support table is checked, so new ones get a case.
+		let missing: Vec<u16> = INT_21H_SUPPORT.iter().map(|&(function, _)| function).filter(|&function| !cases.iter().any(|case| case.ax >> 8 == function)).collect();
+		assert_eq!(missing, vec![]);
+		std::fs::remove_dir_all(&dir_path).ok();
 	}
 }
@@ -0,0 +1,176 @@
+// Byte range locks on files, for int 21h AH=5Ch. With SHARE loaded, DOS only lets the handle that
+// locked a range read or write it, and reads and writes through any other handle fail with a lock
+// violation, as do locks that overlap a range that's already locked. Only one program runs at a
+// time here, so this only matters to programs that open the same file more than once, but
+// programs written for networks expect the locks to work rather than the function to be missing.
+// Files are told apart by their host path, so a file opened through two different DOS paths (such
+// as through a SUBST drive) is still the same file.
+//
+// In DOS a lock belongs to the open file rather than the handle, so the duplicates of a handle made
+// with int 21h AH=45h and AH=46h share its locks, and the locks stay until the last of them is
+// closed. Each duplicate is kept with the handle it's a duplicate of, which owns the locks.
+
+use crate::dos_error_codes::DosErrorCode;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileLock {
+	/// The host path of the locked file.
+	pub path: PathBuf,
+	/// The handle that locked the range, which, with its duplicates, is the only one that can still
+	/// use it.
+	pub handle: u16,
+	pub offset: u32,
+	pub length: u32,
+}
+
+impl FileLock {
+	fn overlaps(&self, path: &Path, offset: u32, length: u32) -> bool {
+		let (start, end) = (self.offset as u64, self.offset as u64 + self.length as u64);
+		let (other_start, other_end) = (offset as u64, offset as u64 + length as u64);
+		self.path == path && start < other_end && other_start < end
+	}
+}
+
+/// The locked ranges of every open file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileLockTable {
+	pub locks: Vec<FileLock>,
+	// The handle each duplicate shares its locks with.
+	duplicate_owners: HashMap<u16, u16>,
+}
+
+impl FileLockTable {
+	pub fn new() -> FileLockTable {
+		FileLockTable::default()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.locks.is_empty()
+	}
+
+	// The handle whose locks a handle shares, which is itself unless it's a duplicate.
+	fn owner(&self, handle: u16) -> u16 {
+		self.duplicate_owners.get(&handle).copied().unwrap_or(handle)
+	}
+
+	/// Makes new_handle share the locks of the handle it was made a duplicate of.
+	pub fn duplicate_handle(&mut self, handle: u16, new_handle: u16) {
+		let owner = self.owner(handle);
+		if new_handle != owner {
+			self.duplicate_owners.insert(new_handle, owner);
+		}
+	}
+
+	/// Locks a range of a file for a handle. None of the range can be locked already, even by the
+	/// same handle.
+	pub fn lock(&mut self, path: &Path, handle: u16, offset: u32, length: u32) -> Result<(), DosErrorCode> {
+		let handle = self.owner(handle);
+		if self.locks.iter().any(|lock| lock.overlaps(path, offset, length)) {
+			return Err(DosErrorCode::LockViolation);
+		}
+		self.locks.push(FileLock { path: path.to_path_buf(), handle, offset, length });
+		Ok(())
+	}
+
+	/// Unlocks a range the handle locked. The range has to be exactly one that was locked, not
+	/// part of one or several of them.
+	pub fn unlock(&mut self, path: &Path, handle: u16, offset: u32, length: u32) -> Result<(), DosErrorCode> {
+		let handle = self.owner(handle);
+		let lock_index = self.locks.iter()
+			.position(|lock| lock.path == path && lock.handle == handle && lock.offset == offset && lock.length == length)
+			.ok_or(DosErrorCode::LockViolation)?;
+		self.locks.remove(lock_index);
+		Ok(())
+	}
+
+	/// Checks that a handle can read or write a range of a file, which it can't if another handle
+	/// has locked any of it.
+	pub fn check_access(&self, path: &Path, handle: u16, offset: u32, length: u32) -> Result<(), DosErrorCode> {
+		let handle = self.owner(handle);
+		if self.locks.iter().any(|lock| lock.handle != handle && lock.overlaps(path, offset, length)) {
+			Err(DosErrorCode::LockViolation)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Forgets a handle that's been closed. Its locks are removed if it was the last handle for the
+	/// file, and otherwise passed on to the lowest of its duplicates.
+	pub fn remove_handle(&mut self, handle: u16) {
+		if self.duplicate_owners.remove(&handle).is_some() {
+			return;
+		}
+		let new_owner = self.duplicate_owners.iter().filter(|&(_, &owner)| owner == handle).map(|(&duplicate, _)| duplicate).min();
+		match new_owner {
+			Some(new_owner) => {
+				self.duplicate_owners.remove(&new_owner);
+				for owner in self.duplicate_owners.values_mut().filter(|owner| **owner == handle) {
+					*owner = new_owner;
+				}
+				for lock in self.locks.iter_mut().filter(|lock| lock.handle == handle) {
+					lock.handle = new_owner;
+				}
+			}
+			None => self.locks.retain(|lock| lock.handle != handle),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_lock_table() {
+		let data = Path::new("/dos/DATA.DB");
+		let other = Path::new("/dos/OTHER.DB");
+		let mut table = FileLockTable::new();
+		assert_eq!(table.lock(data, 5, 100, 50), Ok(()));
+		// Overlapping ranges can't be locked by any handle, but other files and the bytes either
+		// side can.
+		assert_eq!(table.lock(data, 6, 149, 10), Err(DosErrorCode::LockViolation));
+		assert_eq!(table.lock(data, 5, 90, 11), Err(DosErrorCode::LockViolation));
+		assert_eq!(table.lock(data, 6, 150, 10), Ok(()));
+		assert_eq!(table.lock(data, 6, 0, 100), Ok(()));
+		assert_eq!(table.lock(other, 6, 100, 50), Ok(()));
+		// Only the handle that locked a range can use it.
+		assert_eq!(table.check_access(data, 5, 120, 10), Ok(()));
+		assert_eq!(table.check_access(data, 6, 120, 10), Err(DosErrorCode::LockViolation));
+		assert_eq!(table.check_access(data, 5, 140, 20), Err(DosErrorCode::LockViolation));
+		assert_eq!(table.check_access(data, 7, 200, 10), Ok(()));
+		// Ranges that reach the end of the file size limit don't wrap around.
+		assert_eq!(table.lock(data, 5, 0xffff_fff0, 0x20), Ok(()));
+		assert_eq!(table.check_access(data, 6, 0xffff_ffff, 1), Err(DosErrorCode::LockViolation));
+		// Unlocking needs the exact range and the handle that locked it.
+		assert_eq!(table.unlock(data, 5, 100, 40), Err(DosErrorCode::LockViolation));
+		assert_eq!(table.unlock(data, 6, 100, 50), Err(DosErrorCode::LockViolation));
+		assert_eq!(table.unlock(data, 5, 100, 50), Ok(()));
+		assert_eq!(table.check_access(data, 6, 120, 10), Ok(()));
+		table.remove_handle(6);
+		assert_eq!(table.locks, vec![FileLock { path: data.to_path_buf(), handle: 5, offset: 0xffff_fff0, length: 0x20 }]);
+	}
+
+	#[test] fn test_duplicates_share_locks() {
+		let data = Path::new("/dos/DATA.DB");
+		let mut table = FileLockTable::new();
+		// Handles 6 and 7 are duplicates of 5, with 7 made from 6.
+		table.duplicate_handle(5, 6);
+		table.duplicate_handle(6, 7);
+		assert_eq!(table.lock(data, 6, 0, 10), Ok(()));
+		assert_eq!(table.check_access(data, 5, 0, 10), Ok(()));
+		assert_eq!(table.check_access(data, 7, 0, 10), Ok(()));
+		assert_eq!(table.check_access(data, 8, 0, 10), Err(DosErrorCode::LockViolation));
+		assert_eq!(table.unlock(data, 7, 0, 10), Ok(()));
+		assert_eq!(table.lock(data, 5, 0, 10), Ok(()));
+		// The locks stay while any of the handles are open.
+		table.remove_handle(5);
+		assert_eq!(table.check_access(data, 7, 0, 10), Ok(()));
+		assert_eq!(table.check_access(data, 8, 0, 10), Err(DosErrorCode::LockViolation));
+		table.remove_handle(7);
+		assert_eq!(table.check_access(data, 6, 0, 10), Ok(()));
+		table.remove_handle(6);
+		assert!(table.is_empty());
+		assert_eq!(table.check_access(data, 8, 0, 10), Ok(()));
+	}
+}
@@ -2,6 +2,7 @@ use crate::dos_error_codes::DosErrorCode;
 
 use std::io::{Read, Write, Seek};
 use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
 
 pub trait DosFileSystem : std::fmt::Debug {
 	/// Returns a file handle if successful. Error code if not.
@@ -10,16 +11,314 @@ pub trait DosFileSystem : std::fmt::Debug {
 	fn open(&mut self, filename: &[u8], access_mode: DosFileAccessMode) -> Result<u16, DosErrorCode>;
 	/// Retruns error code if close failed.
 	fn close(&mut self, handle: u16) -> Result<(), DosErrorCode>;
+	/// Returns a new handle for the same open file as the handle, which shares its position.
+	fn duplicate(&mut self, handle: u16) -> Result<u16, DosErrorCode>;
+	/// Makes new_handle refer to the same open file as the handle, closing the file new_handle was
+	/// open on first.
+	fn force_duplicate(&mut self, handle: u16, new_handle: u16) -> Result<(), DosErrorCode>;
 	/// Returns the byte count read. Error code if read failed.
 	fn read(&mut self, handle: u16, destination: &mut [u8]) -> Result<u16, DosErrorCode>;
 	/// Returns the byte count written. Error code if write failed.
 	fn write(&mut self, handle: u16, data: &[u8]) -> Result<u16, DosErrorCode>;
 	/// Returns the new position within the file relative to the start. Error code if seek failed.
 	fn seek(&mut self, handle: u16, offset: u32, origin: DosFileSeekOrigin) -> Result<u32, DosErrorCode>;
+	/// Returns the current position in the file.
+	fn tell(&mut self, handle: u16) -> Result<u32, DosErrorCode>;
 	/// Returns the new file length.
 	fn truncate(&mut self, handle: u16) -> Result<u32, DosErrorCode>;
+	/// Returns whether the file position is at (or past) the end of the file.
+	fn is_at_end(&mut self, handle: u16) -> Result<bool, DosErrorCode>;
+	/// Makes sure everything written to the open files is on the host's disk. The first error is
+	/// returned, after trying all the files.
+	fn flush_all(&mut self) -> Result<(), DosErrorCode>;
+	/// Closes every open file, for starting a program again on the same file system.
+	fn close_all(&mut self);
+	/// Forgets what's known about the host's files, so files added, renamed or removed on the host
+	/// are seen the way they are now.
+	fn refresh(&mut self) {}
+	/// Finds hidden, system and directory entries only if their DOS_ATTRIBUTE_* bits are in
+	/// attributes. Normal files are always found.
 	fn find_first_file(&mut self, destination: &mut [u8], attributes: u16, search_spec: &[u8]) -> Result<(), DosErrorCode>;
 	fn find_next_file(&mut self, destination: &mut [u8]) -> Result<(), DosErrorCode>;
+	/// Error code if the file doesn't exist, or is read-only.
+	fn delete(&mut self, filename: &[u8]) -> Result<(), DosErrorCode>;
+	/// Returns the DOS_ATTRIBUTE_* bits of a file or directory.
+	fn get_attributes(&mut self, filename: &[u8]) -> Result<u16, DosErrorCode>;
+	/// Sets the read-only, hidden, system and archive bits of a file or directory. Error code if
+	/// any other bits are given.
+	fn set_attributes(&mut self, filename: &[u8], attributes: u16) -> Result<(), DosErrorCode>;
+	/// Returns the host directory the file system is mounted from, if it has one.
+	fn root_path(&self) -> Option<&std::path::Path>;
+	/// Returns the files that are open, in handle order, for seeing what a program has left open.
+	fn open_handles(&self) -> Vec<OpenHandleInfo> {
+		vec![]
+	}
+	/// Returns the current drive, where 0 is A:.
+	fn current_drive(&self) -> u8 {
+		2
+	}
+	/// Error code if the drive doesn't exist.
+	fn set_current_drive(&mut self, drive: u8) -> Result<(), DosErrorCode> {
+		if drive == self.current_drive() {
+			Ok(())
+		} else {
+			Err(DosErrorCode::InvalidDrive)
+		}
+	}
+	/// Returns whether a drive (0 is A:) exists.
+	fn is_valid_drive(&self, drive: u8) -> bool {
+		drive == self.current_drive()
+	}
+	/// Returns the number of drive letters, including any unused ones before the last drive.
+	fn drive_count(&self) -> u8 {
+		self.current_drive() + 1
+	}
+	/// Returns the size and free space of the volume the file system stores files on.
+	fn volume_info(&self) -> Result<DosVolumeInfo, DosErrorCode>;
+	/// Returns the volume info for a drive (0 is A:). Error code if the drive doesn't exist.
+	fn drive_volume_info(&self, drive: u8) -> Result<DosVolumeInfo, DosErrorCode> {
+		if drive == self.current_drive() {
+			self.volume_info()
+		} else {
+			Err(DosErrorCode::InvalidDrive)
+		}
+	}
+	/// Returns the serial number and label of the volume.
+	fn media_id(&self) -> Result<DosMediaId, DosErrorCode>;
+	/// Changes the serial number and label of the volume.
+	fn set_media_id(&mut self, media_id: DosMediaId) -> Result<(), DosErrorCode>;
+	/// Returns the media ID of a drive (0 is A:). Error code if the drive doesn't exist.
+	fn drive_media_id(&self, drive: u8) -> Result<DosMediaId, DosErrorCode> {
+		if drive == self.current_drive() {
+			self.media_id()
+		} else {
+			Err(DosErrorCode::InvalidDrive)
+		}
+	}
+	/// Changes the media ID of a drive (0 is A:). Error code if the drive doesn't exist.
+	fn set_drive_media_id(&mut self, drive: u8, media_id: DosMediaId) -> Result<(), DosErrorCode> {
+		if drive == self.current_drive() {
+			self.set_media_id(media_id)
+		} else {
+			Err(DosErrorCode::InvalidDrive)
+		}
+	}
+	/// Returns the current directory without the drive or leading backslash, eg. b"GAMES\\ZZT".
+	/// It's empty for the root directory.
+	fn current_directory(&self) -> Vec<u8> {
+		vec![]
+	}
+	/// Changes the current directory. Error code if the directory doesn't exist.
+	fn set_current_directory(&mut self, path: &[u8]) -> Result<(), DosErrorCode> {
+		if parse_dos_dir_path(path, &[])?.is_empty() {
+			Ok(())
+		} else {
+			Err(DosErrorCode::PathNotFound)
+		}
+	}
+	/// Returns the current directory of a drive (0 is A:). Error code if the drive doesn't exist.
+	fn drive_current_directory(&self, drive: u8) -> Result<Vec<u8>, DosErrorCode> {
+		if drive == self.current_drive() {
+			Ok(self.current_directory())
+		} else {
+			Err(DosErrorCode::InvalidDrive)
+		}
+	}
+}
+
+// Handles 0 to 4 are the standard handles (stdin, stdout, stderr, stdaux and stdprn), so files are
+// numbered after them.
+pub const DOS_FIRST_FILE_HANDLE: u16 = 5;
+
+// The size of the find data that find_first_file and find_next_file write, which is what int 21h
+// AH=4Eh and AH=4Fh put at the DTA.
+// http://stanislavs.org/helppc/int_21-4e.html
+pub const DOS_FIND_DATA_BYTES: usize = 0x2b;
+const FIND_DATA_FILENAME_OFFSET: usize = 0x1e;
+
+// Gets the NUL terminated file name out of the find data.
+pub fn find_data_filename(find_data: &[u8]) -> &[u8] {
+	let filename = &find_data[FIND_DATA_FILENAME_OFFSET..];
+	match filename.iter().position(|c| *c == 0) {
+		Some(end) => &filename[..end],
+		None => filename,
+	}
+}
+
+// File attribute bits (http://stanislavs.org/helppc/file_attributes.html).
+pub const DOS_ATTRIBUTE_READ_ONLY: u16 = 0x01;
+pub const DOS_ATTRIBUTE_HIDDEN: u16 = 0x02;
+pub const DOS_ATTRIBUTE_SYSTEM: u16 = 0x04;
+pub const DOS_ATTRIBUTE_VOLUME_LABEL: u16 = 0x08;
+pub const DOS_ATTRIBUTE_DIRECTORY: u16 = 0x10;
+pub const DOS_ATTRIBUTE_ARCHIVE: u16 = 0x20;
+// The bits programs can change with int 21h AH=43h.
+const DOS_SETTABLE_ATTRIBUTES: u16 = DOS_ATTRIBUTE_READ_ONLY | DOS_ATTRIBUTE_HIDDEN | DOS_ATTRIBUTE_SYSTEM | DOS_ATTRIBUTE_ARCHIVE;
+
+/// Reads a whole file through a DOS file system, eg. to load a program from it.
+pub fn read_dos_file(file_system: &mut dyn DosFileSystem, filename: &[u8]) -> Result<Vec<u8>, DosErrorCode> {
+	let handle = file_system.open(filename, DosFileAccessMode::ReadOnly)?;
+	let mut data = vec![];
+	let mut buffer = [0; 0x1000];
+	loop {
+		match file_system.read(handle, &mut buffer) {
+			Ok(0) => break,
+			Ok(read_count) => data.extend(&buffer[..read_count as usize]),
+			Err(error_code) => {
+				file_system.close(handle).ok();
+				return Err(error_code);
+			}
+		}
+	}
+	file_system.close(handle)?;
+	Ok(data)
+}
+
+// FAT16 can't have more clusters than this.
+const MAX_DISK_CLUSTERS: u64 = 0xfff4;
+const MAX_SECTORS_PER_CLUSTER: u64 = 64;
+const DISK_BYTES_PER_SECTOR: u64 = 512;
+// The largest volume whose size can be reported through the 16 bit cluster counts, which is about
+// 2GB.
+pub const MAX_DOS_VOLUME_BYTES: u64 = MAX_DISK_CLUSTERS * MAX_SECTORS_PER_CLUSTER * DISK_BYTES_PER_SECTOR;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DosVolumeInfo {
+	pub total_bytes: u64,
+	pub free_bytes: u64,
+	pub read_only: bool,
+}
+
+/// The label reported for a volume that doesn't have one.
+pub const DOS_NO_VOLUME_LABEL: [u8; 11] = *b"NO NAME    ";
+/// The size of the media ID structure read and written by int 21h AX=440Dh.
+pub const DOS_MEDIA_ID_BYTES: usize = 0x19;
+// Volumes are always reported as FAT16, like the disk geometry.
+const DOS_FILE_SYSTEM_TYPE: &[u8; 8] = b"FAT16   ";
+
+/// The serial number and label of a volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DosMediaId {
+	pub serial_number: u32,
+	/// Padded with spaces. DOS_NO_VOLUME_LABEL means there's no label.
+	pub volume_label: [u8; 11],
+}
+
+impl DosMediaId {
+	/// Lays out the media ID like int 21h AX=440Dh CL=66h does.
+	/// http://www.ctyme.com/intr/rb-2896.htm
+	pub fn to_bytes(&self) -> [u8; DOS_MEDIA_ID_BYTES] {
+		let mut bytes = [0; DOS_MEDIA_ID_BYTES];
+		bytes[0x02 .. 0x06].copy_from_slice(&self.serial_number.to_le_bytes());
+		bytes[0x06 .. 0x11].copy_from_slice(&self.volume_label);
+		bytes[0x11 .. 0x19].copy_from_slice(DOS_FILE_SYSTEM_TYPE);
+		bytes
+	}
+	
+	/// Reads the serial number and label from a media ID structure. The file system type is ignored.
+	pub fn from_bytes(bytes: &[u8; DOS_MEDIA_ID_BYTES]) -> DosMediaId {
+		let mut serial_number_bytes = [0; 4];
+		serial_number_bytes.copy_from_slice(&bytes[0x02 .. 0x06]);
+		let mut volume_label = [0; 11];
+		volume_label.copy_from_slice(&bytes[0x06 .. 0x11]);
+		DosMediaId {
+			serial_number: u32::from_le_bytes(serial_number_bytes),
+			volume_label,
+		}
+	}
+	
+	fn has_label(&self) -> bool {
+		self.volume_label != DOS_NO_VOLUME_LABEL
+	}
+	
+	// The label as find_first_file returns it, with a dot before the last 3 characters if it's
+	// longer than 8.
+	fn label_file_name(&self) -> DosFileName {
+		let trim_end = |text: &[u8]| {
+			let end = text.iter().rposition(|c| *c != b' ').map(|pos| pos + 1).unwrap_or(0);
+			text[..end].to_vec()
+		};
+		DosFileName {
+			title: trim_end(&self.volume_label[..8]),
+			ext: trim_end(&self.volume_label[8..]),
+		}
+	}
+}
+
+// Gives each mounted directory its own serial number that stays the same between runs, using the
+// FNV-1a hash of its path.
+fn default_volume_serial_number(root_path: &std::path::Path) -> u32 {
+	let mut hash: u32 = 0x811c9dc5;
+	for byte in root_path.to_string_lossy().bytes() {
+		hash ^= byte as u32;
+		hash = hash.wrapping_mul(0x01000193);
+	}
+	hash
+}
+
+/// The layout of a disk as reported by int 21h AH=1Ch and AH=36h.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DosDiskGeometry {
+	pub sectors_per_cluster: u16,
+	pub bytes_per_sector: u16,
+	pub total_clusters: u16,
+	pub free_clusters: u16,
+}
+
+impl DosVolumeInfo {
+	/// Picks the smallest cluster size that lets the volume size fit in the 16 bit cluster counts,
+	/// clamping volumes that are too big even with the largest clusters.
+	pub fn disk_geometry(&self) -> DosDiskGeometry {
+		let mut sectors_per_cluster = 1;
+		while sectors_per_cluster < MAX_SECTORS_PER_CLUSTER && self.total_bytes / (sectors_per_cluster * DISK_BYTES_PER_SECTOR) > MAX_DISK_CLUSTERS {
+			sectors_per_cluster *= 2;
+		}
+		let cluster_bytes = sectors_per_cluster * DISK_BYTES_PER_SECTOR;
+		let total_clusters = (self.total_bytes / cluster_bytes).min(MAX_DISK_CLUSTERS);
+		let free_clusters = if self.read_only { 0 } else { (self.free_bytes / cluster_bytes).min(total_clusters) };
+		DosDiskGeometry {
+			sectors_per_cluster: sectors_per_cluster as u16,
+			bytes_per_sector: DISK_BYTES_PER_SECTOR as u16,
+			total_clusters: total_clusters as u16,
+			free_clusters: free_clusters as u16,
+		}
+	}
+}
+
+// Gets the total and available bytes of the host file system containing the path.
+#[cfg(unix)]
+fn host_volume_space(path: &std::path::Path) -> Option<(u64, u64)> {
+	use std::os::unix::ffi::OsStrExt;
+	let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+		return None;
+	}
+	let block_size = stat.f_frsize as u64;
+	Some((stat.f_blocks as u64 * block_size, stat.f_bavail as u64 * block_size))
+}
+
+#[cfg(not(unix))]
+fn host_volume_space(_path: &std::path::Path) -> Option<(u64, u64)> {
+	None
+}
+
+// Permissions::set_readonly(false) makes the file writable by everyone on Unix, so only the owner's
+// write bit is changed there.
+#[cfg(unix)]
+fn set_host_read_only(path: &std::path::Path, read_only: bool) -> std::io::Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	let mut permissions = std::fs::metadata(path)?.permissions();
+	let mode = permissions.mode();
+	permissions.set_mode(if read_only { mode & !0o222 } else { mode | 0o200 });
+	std::fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_host_read_only(path: &std::path::Path, read_only: bool) -> std::io::Result<()> {
+	let mut permissions = std::fs::metadata(path)?.permissions();
+	permissions.set_readonly(read_only);
+	std::fs::set_permissions(path, permissions)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -29,6 +328,16 @@ pub enum DosFileAccessMode {
 	ReadWrite,
 }
 
+/// A file handle that's open, and the host file it's for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenHandleInfo {
+	pub handle: u16,
+	// The full DOS path of the file, from the root directory.
+	pub dos_path: Vec<u8>,
+	pub real_path: std::path::PathBuf,
+	pub access_mode: DosFileAccessMode,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DosFileSeekOrigin {
 	Start,
@@ -45,7 +354,9 @@ struct DosFileName {
 impl DosFileName {
 	fn parse(dos_filename: &[u8]) -> DosFileName {
 		let (title, ext) = split_filename(dos_filename);
-		DosFileName{title: title.to_ascii_uppercase(), ext: ext.unwrap_or(&[]).to_ascii_uppercase()}
+		let mut title = title.to_ascii_uppercase();
+		title.truncate(8);
+		DosFileName{title, ext: ext.unwrap_or(&[]).to_ascii_uppercase()}
 	}
 
 	fn real_dos_name(&self) -> Vec<u8> {
@@ -61,8 +372,10 @@ impl DosFileName {
 #[derive(Debug)]
 struct DirListingCache {
 	dir_path: std::path::PathBuf,
-	real_to_dos_names: HashMap<String, DosFileName>,
-	dos_to_real_names: HashMap<DosFileName, String>,
+	// Host names are kept as OsStrings so names that aren't valid Unicode still map back to the
+	// exact file.
+	real_to_dos_names: HashMap<OsString, DosFileName>,
+	dos_to_real_names: HashMap<DosFileName, OsString>,
 }
 
 impl DirListingCache {
@@ -76,42 +389,61 @@ impl DirListingCache {
 		dir_listing
 	}
 	
-	fn get_dos_name(&mut self, real_filename: &str) -> DosFileName {
+	fn get_dos_name<S: AsRef<OsStr>>(&mut self, real_filename: S) -> DosFileName {
+		let real_filename = real_filename.as_ref();
 		if let Some(existing_dos_name) = self.real_to_dos_names.get(real_filename) {
 			existing_dos_name.clone()
 		} else {
-			let mut dos_name = real_to_dos_name(&real_filename, None);
+			// Names that had to be mangled always get a ~N suffix, so they can't be mistaken for
+			// the name they were shortened to.
 			let mut name_index = 1;
+			let mut dos_name = if is_exact_dos_name(real_filename) {
+				real_to_dos_name(real_filename, None)
+			} else {
+				name_index += 1;
+				real_to_dos_name(real_filename, Some(1))
+			};
 			while self.dos_to_real_names.contains_key(&dos_name) {
-				dos_name = real_to_dos_name(&real_filename, Some(name_index));
+				dos_name = real_to_dos_name(real_filename, Some(name_index));
 				name_index += 1;
 			}
-			self.dos_to_real_names.insert(dos_name.clone(), real_filename.to_string());
-			self.real_to_dos_names.insert(real_filename.to_string(), dos_name.clone());
+			self.dos_to_real_names.insert(dos_name.clone(), real_filename.to_os_string());
+			self.real_to_dos_names.insert(real_filename.to_os_string(), dos_name.clone());
 			dos_name
 		}
 	}
 	
-	fn get_real_name(&mut self, dos_filename: &DosFileName) -> String {
+	fn get_real_name(&mut self, dos_filename: &DosFileName) -> OsString {
 		self.list_dir(&mut |_|{});
-		if let Some(existing_real_name) = self.dos_to_real_names.get(&dos_filename) {
+		if let Some(existing_real_name) = self.dos_to_real_names.get(dos_filename) {
 			existing_real_name.clone()
 		} else {
-			let mut real_name = ascii_filename_to_string(&dos_filename.real_dos_name());
+			let real_name = OsString::from(ascii_filename_to_string(&dos_filename.real_dos_name()));
 			self.dos_to_real_names.insert(dos_filename.clone(), real_name.clone());
 			self.real_to_dos_names.insert(real_name.clone(), dos_filename.clone());
 			real_name
 		}
 	}
 
-	fn list_dir(&mut self, on_found_file: &mut FnMut(DosFileName)) {
+	// Forgets the names given out so far, for when files have been added or removed. The host
+	// directory is read again on every listing, so the next one gives out names from scratch.
+	fn invalidate(&mut self) {
+		self.real_to_dos_names.clear();
+		self.dos_to_real_names.clear();
+	}
+	
+	fn list_dir(&mut self, on_found_file: &mut dyn FnMut(DosFileName)) {
 		if let Ok(read_dir) = std::fs::read_dir(&self.dir_path) {
-			for dir_file in read_dir {
-				if let Ok(dir_file_entry) = dir_file {
-					if let Ok(dir_file_entry_name) = dir_file_entry.file_name().into_string() {
-						on_found_file(self.get_dos_name(&dir_file_entry_name));
-					}
-				}
+			let mut real_names: Vec<OsString> = read_dir.filter_map(|dir_file| dir_file.ok())
+				.map(|dir_file_entry| dir_file_entry.file_name())
+				.filter(|real_name| !is_emulator_file(real_name))
+				.collect();
+			// The host lists files in any order, so sort them to make sure the ~N suffixes given to
+			// clashing names are the same every time. Names that are already valid 8.3 names go first
+			// so they never get a suffix because of a mangled name.
+			real_names.sort_by(|a, b| (!is_exact_dos_name(a), a).cmp(&(!is_exact_dos_name(b), b)));
+			for real_name in real_names {
+				on_found_file(self.get_dos_name(&real_name));
 			}
 		}
 	}
@@ -119,24 +451,151 @@ impl DirListingCache {
 	//fn get_real_name(filename: &[u8])
 }
 
+// Characters that can't appear in a DOS file name (http://stanislavs.org/helppc/file_names.html).
+const DOS_INVALID_FILENAME_CHARS: &[u8] = b"\"*+,/:;<=>?[\\]|";
+// Names that refer to devices rather than files, regardless of the extension.
+const DOS_DEVICE_NAMES: &[&[u8]] = &[
+	b"CON", b"PRN", b"AUX", b"NUL", b"CLOCK$",
+	b"COM1", b"COM2", b"COM3", b"COM4", b"LPT1", b"LPT2", b"LPT3",
+];
+
+/// The device a path names, if it does. Like DOS, any directory and extension are ignored, so
+/// `C:\TEMP\CLOCK$.DAT` is still CLOCK$.
+pub fn dos_device_name(path: &[u8]) -> Option<&'static [u8]> {
+	let filename = strip_dos_drive(path).rsplit(|c| *c == b'\\' || *c == b'/').next().unwrap_or(&[]);
+	let dos_name = DosFileName::parse(filename);
+	DOS_DEVICE_NAMES.iter().find(|device_name| **device_name == &dos_name.title[..]).cloned()
+}
+
+fn strip_dos_drive(path: &[u8]) -> &[u8] {
+	if path.len() >= 2 && path[1] == b':' && path[0].is_ascii_alphabetic() { &path[2..] } else { path }
+}
+
+// Whether a path starts at the root directory rather than the current directory.
+fn is_absolute_dos_path(path: &[u8]) -> bool {
+	matches!(strip_dos_drive(path).first(), Some(b'\\') | Some(b'/'))
+}
+
+// Checks that a file path given by a program can only refer to something inside the mounted
+// directory. Returns the directory the file is in, as DOS names from the root, and the 8.3 file
+// name at the end of it.
+fn parse_dos_path(path: &[u8], current_dir: &[DosFileName]) -> Result<(Vec<DosFileName>, DosFileName), DosErrorCode> {
+	// DosDriveTable has already picked the drive and removed its letter, so one given straight to
+	// this file system is ignored.
+	let (dir_part, filename) = split_search_spec(path);
+	let dos_dir = parse_dos_dir_path(dir_part, current_dir)?;
+	match filename {
+		b"" | b"." | b".." => Err(DosErrorCode::PathNotFound),
+		_ => Ok((dos_dir, parse_dos_name_component(filename, false)?)),
+	}
+}
+
+// Resolves a directory path against the current directory (given as its components), returning the
+// components of the directory from the root. Going above the root is an error.
+fn parse_dos_dir_path(path: &[u8], current_dir: &[DosFileName]) -> Result<Vec<DosFileName>, DosErrorCode> {
+	let mut components = if is_absolute_dos_path(path) { vec![] } else { current_dir.to_vec() };
+	for component in strip_dos_drive(path).split(|c| *c == b'\\' || *c == b'/') {
+		match component {
+			b"" | b"." => {}
+			b".." => {
+				if components.pop().is_none() {
+					return Err(DosErrorCode::PathNotFound);
+				}
+			}
+			_ => components.push(parse_dos_name_component(component, false)?),
+		}
+	}
+	Ok(components)
+}
+
+// Splits a search spec like `..\SAVES\GAME?.SAV` into the directory to search, which keeps its
+// trailing backslash so `\*.*` still means the root, and the pattern to match in it.
+fn split_search_spec(search_spec: &[u8]) -> (&[u8], &[u8]) {
+	let drive_len = search_spec.len() - strip_dos_drive(search_spec).len();
+	match search_spec[drive_len..].iter().rposition(|c| *c == b'\\' || *c == b'/') {
+		Some(separator_pos) => search_spec.split_at(drive_len + separator_pos + 1),
+		None => search_spec.split_at(drive_len),
+	}
+}
+
+fn parse_dos_name_component(filename: &[u8], allow_wildcards: bool) -> Result<DosFileName, DosErrorCode> {
+	let (title, _) = split_filename(filename);
+	for &c in filename.iter() {
+		let allowed_wildcard = allow_wildcards && (c == b'*' || c == b'?');
+		if c < 0x20 || (DOS_INVALID_FILENAME_CHARS.contains(&c) && !allowed_wildcard) {
+			return Err(DosErrorCode::PathNotFound);
+		}
+	}
+	if title.contains(&b'.') {
+		return Err(DosErrorCode::PathNotFound);
+	}
+	
+	let dos_name = DosFileName::parse(filename);
+	if DOS_DEVICE_NAMES.contains(&&dos_name.title[..]) {
+		// TODO: Devices aren't supported yet, but they must never be treated as host files.
+		return Err(DosErrorCode::AccessDenied);
+	}
+	Ok(dos_name)
+}
+
 fn ascii_filename_to_string(ascii: &[u8]) -> String {
 	ascii.iter().map(|c| c.to_ascii_uppercase() as char).collect()
 }
 
-fn real_to_dos_name(filename: &str, extra_index: Option<usize>) -> DosFileName {
-	let mut ascii_name = vec![];
-	for c in filename.chars() {
-		if c <= 255 as char {
-			ascii_name.push((c as u8).to_ascii_uppercase());
-		} else {
-			ascii_name.push(b'_');
+// Whether the host name can be used as a DOS name as is (ignoring case), without being mangled.
+fn is_exact_dos_name(filename: &OsStr) -> bool {
+	!shorten_real_name(filename).2
+}
+
+// Converts a host name to the parts of a short name, following the Windows short name rules
+// (https://en.wikipedia.org/wiki/8.3_filename#VFAT_and_computer-generated_8.3_filenames): spaces
+// and leading dots are removed, the extension comes from after the last dot and other dots are
+// removed, characters DOS can't use become '_', and the result is uppercased and truncated. Also
+// returns whether anything was lost along the way, in which case the name needs a ~N suffix.
+fn shorten_real_name(filename: &OsStr) -> (Vec<u8>, Vec<u8>, bool) {
+	// Anything that isn't valid Unicode comes out as U+FFFD, which gets replaced like any other
+	// character outside Latin-1.
+	let filename = filename.to_string_lossy();
+	let without_leading_dots = filename.trim_start_matches('.');
+	let mut lossy = without_leading_dots.len() != filename.len();
+	let (title, ext) = match without_leading_dots.rfind('.') {
+		Some(dot_pos) => (&without_leading_dots[..dot_pos], Some(&without_leading_dots[dot_pos + 1..])),
+		None => (without_leading_dots, None),
+	};
+	
+	let mut shorten_part = |part: &str, max_len: usize| {
+		let mut short_part = vec![];
+		for c in part.chars() {
+			if c == ' ' || c == '.' {
+				lossy = true;
+			} else if c <= 255 as char && c > ' ' && !DOS_INVALID_FILENAME_CHARS.contains(&(c as u8)) {
+				short_part.push((c as u8).to_ascii_uppercase());
+			} else {
+				lossy = true;
+				short_part.push(b'_');
+			}
 		}
+		if short_part.len() > max_len {
+			lossy = true;
+			short_part.truncate(max_len);
+		}
+		short_part
+	};
+	let mut short_title = shorten_part(title, 8);
+	let short_ext = shorten_part(ext.unwrap_or(""), 3);
+	// A name that ends with a dot can't be told apart from the name without it.
+	if ext == Some("") {
+		lossy = true;
 	}
-	let (file_title, file_ext) = split_filename(&ascii_name);
-	let mut short_title = file_title.to_vec();
-	short_title.truncate(8);
-	let mut short_ext = file_ext.unwrap_or(&[]).to_vec();
-	short_ext.truncate(3);
+	if short_title.is_empty() {
+		lossy = true;
+		short_title.push(b'_');
+	}
+	(short_title, short_ext, lossy)
+}
+
+fn real_to_dos_name(filename: &OsStr, extra_index: Option<usize>) -> DosFileName {
+	let (mut short_title, short_ext, _) = shorten_real_name(filename);
 	
 	let mut title_index_text = vec![];
 	if let Some(extra_index) = extra_index {
@@ -183,9 +642,7 @@ fn filename_matches_spec(filename: &DosFileName, search_spec: &[u8]) -> bool {
 						}
 					}
 					just_processed_star = true;
-				} else if spec_char == b'?' {
-					spec_pos += 1;
-				} else if *c == spec_char {
+				} else if spec_char == b'?' || *c == spec_char {
 					spec_pos += 1;
 				} else {
 					return false;
@@ -210,12 +667,73 @@ fn filename_matches_spec(filename: &DosFileName, search_spec: &[u8]) -> bool {
 	title_matches && ext_matches
 }
 
+/// When attribute persistence is enabled, the hidden, system and archive bits are kept in this file
+/// in the root of the mounted directory, since hosts have nowhere portable to store them (the
+/// read-only bit uses the host's own permissions instead). Each line is the attribute bits in hex,
+/// a space, and then the host path of the file relative to the root with / between directories.
+/// Files whose names aren't valid Unicode can't be listed, so they lose their bits on restart. The
+/// file itself is hidden from programs.
+pub const ATTRIBUTES_FILE_NAME: &str = ".pseudos-attributes";
+/// When attribute persistence is enabled, the volume serial number and label are kept in this file
+/// in the root of the mounted directory. It's one line with the serial number in hex, a space, and
+/// then the label without its padding. The file itself is hidden from programs.
+pub const VOLUME_FILE_NAME: &str = ".pseudos-volume";
+/// The compatibility profile the runner uses for the programs in the mounted directory is read
+/// from this file in its root, in the form CompatProfile::parse reads. The file itself is hidden
+/// from programs.
+pub const PROFILE_FILE_NAME: &str = ".pseudos-profile";
+
+/// Host files whose names start with this are the emulator's own, like the ones above, and are
+/// hidden from programs. Frontends keep their own files in the mounted directory with it too.
+pub const EMULATOR_FILE_PREFIX: &str = ".pseudos-";
+
+// Whether a host file is one of the emulator's own files, which programs never see.
+fn is_emulator_file(real_name: &OsStr) -> bool {
+	real_name.to_str().is_some_and(|real_name| real_name.starts_with(EMULATOR_FILE_PREFIX))
+}
+
+#[derive(Debug)]
+struct OpenFile {
+	file: std::fs::File,
+	dos_path: Vec<u8>,
+	real_path: std::path::PathBuf,
+	access_mode: DosFileAccessMode,
+}
+
+impl OpenFile {
+	// Another handle for the same open file. The host shares the position between the two, like
+	// DOS does for duplicated handles.
+	fn try_clone(&self) -> Result<OpenFile, DosErrorCode> {
+		Ok(OpenFile {
+			file: self.file.try_clone().map_err(std_file_error_to_dos_error)?,
+			dos_path: self.dos_path.clone(),
+			real_path: self.real_path.clone(),
+			access_mode: self.access_mode,
+		})
+	}
+}
+
 #[derive(Debug)]
 pub struct StandardDosFileSystem {
 	root_path: std::path::PathBuf,
-	file_handles: Vec<Option<std::fs::File>>,
+	file_handles: Vec<Option<OpenFile>>,
+	// The listing of the current directory.
 	dir_listing: DirListingCache,
-	current_file_queue: Option<VecDeque<DosFileName>>,
+	// The current directory as DOS names from the root, and the host directory it maps to.
+	current_dir: Vec<DosFileName>,
+	current_dir_path: std::path::PathBuf,
+	// The names and attributes of the files left to return from the last find_first_file.
+	current_file_queue: Option<VecDeque<(DosFileName, u16)>>,
+	// The volume size reported to programs is capped to this, so huge host disks still give
+	// sensible answers.
+	volume_size_cap: u64,
+	// The hidden, system and archive bits, by host path relative to the root. Files that aren't
+	// listed only have the archive bit set, like files that were never backed up.
+	extra_attributes: HashMap<std::path::PathBuf, u16>,
+	// Whether extra_attributes is saved to ATTRIBUTES_FILE_NAME whenever it changes, and media_id to
+	// VOLUME_FILE_NAME.
+	persist_attributes: bool,
+	media_id: DosMediaId,
 }
 
 impl StandardDosFileSystem {
@@ -225,11 +743,159 @@ impl StandardDosFileSystem {
 			file_handles: vec![],
 			current_file_queue: None,
 			dir_listing: DirListingCache::new(root_path.clone()),
+			current_dir: vec![],
+			current_dir_path: root_path.clone(),
+			volume_size_cap: MAX_DOS_VOLUME_BYTES,
+			extra_attributes: HashMap::new(),
+			persist_attributes: false,
+			media_id: DosMediaId {
+				serial_number: default_volume_serial_number(&root_path),
+				volume_label: DOS_NO_VOLUME_LABEL,
+			},
+		}
+	}
+	
+	/// Changes the volume serial number from the one made up from the root path.
+	pub fn set_volume_serial_number(&mut self, serial_number: u32) {
+		self.media_id.serial_number = serial_number;
+		self.save_media_id();
+	}
+	
+	pub fn set_volume_size_cap(&mut self, volume_size_cap: u64) {
+		self.volume_size_cap = volume_size_cap;
+	}
+	
+	/// Loads the hidden, system and archive bits from ATTRIBUTES_FILE_NAME, and saves them there
+	/// whenever they change from now on. Without this they only last until the file system is
+	/// dropped. The volume serial number and label are kept in VOLUME_FILE_NAME the same way.
+	pub fn enable_attribute_persistence(&mut self) {
+		self.persist_attributes = true;
+		if let Ok(volume_text) = std::fs::read_to_string(self.root_path.join(VOLUME_FILE_NAME)) {
+			let line = volume_text.lines().next().unwrap_or("");
+			let mut parts = line.splitn(2, ' ');
+			if let (Some(Ok(serial_number)), Some(label)) = (parts.next().map(|serial| u32::from_str_radix(serial, 16)), parts.next()) {
+				let mut volume_label = [b' '; 11];
+				for (label_char, c) in volume_label.iter_mut().zip(label.bytes()) {
+					*label_char = c;
+				}
+				self.media_id = DosMediaId { serial_number, volume_label };
+			}
+		}
+		if let Ok(attributes_text) = std::fs::read_to_string(self.root_path.join(ATTRIBUTES_FILE_NAME)) {
+			for line in attributes_text.lines() {
+				let mut parts = line.splitn(2, ' ');
+				if let (Some(attributes), Some(relative_path)) = (parts.next(), parts.next()) {
+					if let Ok(attributes) = u16::from_str_radix(attributes, 16) {
+						let relative_path: std::path::PathBuf = relative_path.split('/').collect();
+						self.extra_attributes.insert(relative_path, attributes);
+					}
+				}
+			}
+		}
+	}
+	
+	/// Gets the host path that a DOS path maps to, whether or not the file exists yet. Relative paths
+	/// start from the current directory, and the directories on the way have to exist.
+	pub fn dos_to_host_path(&self, dos_path: &[u8]) -> Result<std::path::PathBuf, DosErrorCode> {
+		let components = parse_dos_dir_path(dos_path, &self.current_dir)?;
+		let (dos_name, dos_dir) = match components.split_last() {
+			Some(split) => split,
+			None => return Ok(self.root_path.clone()),
+		};
+		let real_dir_path = self.get_real_dir_path(dos_dir)?;
+		let real_name = DirListingCache::new(real_dir_path.clone()).get_real_name(dos_name);
+		let real_path = real_dir_path.join(real_name);
+		if std::fs::symlink_metadata(&real_path).is_ok() {
+			self.check_inside_root(&real_path)?;
+		}
+		Ok(real_path)
+	}
+	
+	/// Gets the absolute DOS path, like `\GAMES\ARCHIV~1.GZ`, that the program sees for a host path
+	/// inside the mounted directory. Host paths outside it are denied.
+	pub fn host_to_dos_path(&self, host_path: &std::path::Path) -> Result<Vec<u8>, DosErrorCode> {
+		let relative_path = host_path.strip_prefix(&self.root_path).map_err(|_| DosErrorCode::AccessDenied)?;
+		let mut real_dir_path = self.root_path.clone();
+		let mut dos_path = vec![];
+		for component in relative_path.components() {
+			let real_name = match component {
+				std::path::Component::Normal(real_name) => real_name,
+				_ => return Err(DosErrorCode::PathNotFound),
+			};
+			if is_emulator_file(real_name) {
+				return Err(DosErrorCode::FileNotFound);
+			}
+			dos_path.push(b'\\');
+			dos_path.extend(DirListingCache::new(real_dir_path.clone()).get_dos_name(real_name).real_dos_name());
+			real_dir_path.push(real_name);
+		}
+		if dos_path.is_empty() {
+			dos_path.push(b'\\');
+		}
+		Ok(dos_path)
+	}
+	
+	fn save_attributes(&self) {
+		if !self.persist_attributes {
+			return;
+		}
+		let mut lines: Vec<String> = self.extra_attributes.iter().filter_map(|(relative_path, attributes)| {
+			let components: Option<Vec<&str>> = relative_path.components().map(|component| component.as_os_str().to_str()).collect();
+			components.map(|components| format!("{:02x} {}\n", attributes, components.join("/")))
+		}).collect();
+		lines.sort();
+		if let Err(err) = std::fs::write(self.root_path.join(ATTRIBUTES_FILE_NAME), lines.concat()) {
+			eprintln!("Failed to save file attributes: {}", err);
+		}
+	}
+	
+	fn save_media_id(&self) {
+		if !self.persist_attributes {
+			return;
+		}
+		let label = String::from_utf8_lossy(&self.media_id.volume_label);
+		let volume_text = format!("{:08x} {}\n", self.media_id.serial_number, label.trim_end());
+		if let Err(err) = std::fs::write(self.root_path.join(VOLUME_FILE_NAME), volume_text) {
+			eprintln!("Failed to save the volume label: {}", err);
+		}
+	}
+	
+	fn relative_path<'a>(&self, real_path: &'a std::path::Path) -> &'a std::path::Path {
+		real_path.strip_prefix(&self.root_path).unwrap_or(real_path)
+	}
+	
+	fn get_real_attributes(&self, real_path: &std::path::Path) -> Result<u16, DosErrorCode> {
+		let metadata = std::fs::metadata(real_path).map_err(std_file_error_to_dos_error)?;
+		let extra_attributes = self.extra_attributes.get(self.relative_path(real_path)).cloned();
+		let mut attributes = if metadata.is_dir() {
+			extra_attributes.unwrap_or(0) | DOS_ATTRIBUTE_DIRECTORY
+		} else {
+			extra_attributes.unwrap_or(DOS_ATTRIBUTE_ARCHIVE)
+		};
+		if metadata.permissions().readonly() {
+			attributes |= DOS_ATTRIBUTE_READ_ONLY;
+		}
+		Ok(attributes)
+	}
+	
+	// Sets the attributes other than read-only, which lives on the host.
+	fn set_extra_attributes(&mut self, real_path: &std::path::Path, attributes: u16) {
+		let attributes = attributes & (DOS_ATTRIBUTE_HIDDEN | DOS_ATTRIBUTE_SYSTEM | DOS_ATTRIBUTE_ARCHIVE);
+		let relative_path = self.relative_path(real_path).to_path_buf();
+		if self.extra_attributes.insert(relative_path, attributes) != Some(attributes) {
+			self.save_attributes();
+		}
+	}
+	
+	fn is_read_only(&self, real_path: &std::path::Path) -> bool {
+		match self.get_real_attributes(real_path) {
+			Ok(attributes) => attributes & DOS_ATTRIBUTE_READ_ONLY != 0,
+			Err(_) => false,
 		}
 	}
 	
 	fn get_empty_slot(&mut self) -> usize {
-		match self.file_handles.iter().position(|ref slot| slot.is_none()) {
+		match self.file_handles.iter().position(|slot| slot.is_none()) {
 			Some(pos) => pos,
 			None => {
 				let pos = self.file_handles.len();
@@ -239,16 +905,27 @@ impl StandardDosFileSystem {
 		}
 	}
 	
-	fn get_file_from_handle(&mut self, handle: u16) -> Result<&mut std::fs::File, DosErrorCode> {
-		if handle == 0 {
+	fn get_open_file(&mut self, handle: u16) -> Result<&mut OpenFile, DosErrorCode> {
+		let handle_index = handle.checked_sub(DOS_FIRST_FILE_HANDLE).ok_or(DosErrorCode::InvalidFileHandle)? as usize;
+		if let Some(Some(ref mut open_file)) = self.file_handles.get_mut(handle_index) {
+			Ok(open_file)
+		} else {
 			Err(DosErrorCode::InvalidFileHandle)
+		}
+	}
+	
+	fn get_file_from_handle(&mut self, handle: u16) -> Result<&mut std::fs::File, DosErrorCode> {
+		Ok(&mut self.get_open_file(handle)?.file)
+	}
+	
+	// Gets the host path of a file that's open for writing. Writing through a handle opened as
+	// read-only is denied even if the host would allow it.
+	fn get_writable_path(&mut self, handle: u16) -> Result<std::path::PathBuf, DosErrorCode> {
+		let open_file = self.get_open_file(handle)?;
+		if open_file.access_mode == DosFileAccessMode::ReadOnly {
+			Err(DosErrorCode::AccessDenied)
 		} else {
-			let handle_index = (handle - 1) as usize;
-			if let Some(Some(ref mut file)) = self.file_handles.get_mut(handle_index) {
-				Ok(file)
-			} else {
-				Err(DosErrorCode::InvalidFileHandle)
-			}
+			Ok(open_file.real_path.clone())
 		}
 	}
 	
@@ -272,9 +949,60 @@ impl StandardDosFileSystem {
 		self.root_path.join(string_filename)
 	}*/
 	
-	fn get_real_filepath(&mut self, filename: &[u8]) -> std::path::PathBuf {
-		let real_name = self.dir_listing.get_real_name(&DosFileName::parse(filename));
-		self.root_path.join(real_name)
+	// Gets the full DOS path of a file, from the root directory, with the 8.3 name DOS would store.
+	fn get_dos_filepath(&self, filename: &[u8]) -> Result<Vec<u8>, DosErrorCode> {
+		let (dos_dir, dos_name) = parse_dos_path(filename, &self.current_dir)?;
+		let mut dos_path = vec![];
+		for dos_dir_name in dos_dir.iter().chain(std::iter::once(&dos_name)) {
+			dos_path.push(b'\\');
+			dos_path.extend(dos_dir_name.real_dos_name());
+		}
+		Ok(dos_path)
+	}
+	
+	fn get_real_filepath(&mut self, filename: &[u8]) -> Result<std::path::PathBuf, DosErrorCode> {
+		let (dos_dir, dos_name) = parse_dos_path(filename, &self.current_dir)?;
+		let dir_path = self.get_real_dir_path(&dos_dir)?;
+		let real_name = if dir_path == self.current_dir_path {
+			self.dir_listing.get_real_name(&dos_name)
+		} else {
+			DirListingCache::new(dir_path.clone()).get_real_name(&dos_name)
+		};
+		let mut real_name_components = std::path::Path::new(&real_name).components();
+		match (real_name_components.next(), real_name_components.next()) {
+			(Some(std::path::Component::Normal(_)), None) => {}
+			_ => return Err(DosErrorCode::PathNotFound),
+		}
+		
+		// Don't follow symlinks that lead outside the mounted directory.
+		let real_filepath = dir_path.join(real_name);
+		if std::fs::symlink_metadata(&real_filepath).is_ok() {
+			self.check_inside_root(&real_filepath)?;
+		}
+		Ok(real_filepath)
+	}
+	
+	// Gets the host directory for a directory given as DOS names from the root. Every directory on
+	// the way has to exist.
+	fn get_real_dir_path(&self, dos_dir: &[DosFileName]) -> Result<std::path::PathBuf, DosErrorCode> {
+		let mut real_dir_path = self.root_path.clone();
+		for dos_name in dos_dir {
+			let real_name = DirListingCache::new(real_dir_path.clone()).get_real_name(dos_name);
+			real_dir_path.push(real_name);
+			if !real_dir_path.is_dir() {
+				return Err(DosErrorCode::PathNotFound);
+			}
+		}
+		self.check_inside_root(&real_dir_path)?;
+		Ok(real_dir_path)
+	}
+	
+	fn check_inside_root(&self, real_path: &std::path::Path) -> Result<(), DosErrorCode> {
+		let canonical_root = self.root_path.canonicalize().map_err(std_file_error_to_dos_error)?;
+		match real_path.canonicalize() {
+			Ok(ref canonical_path) if canonical_path.starts_with(&canonical_root) => Ok(()),
+			_ => Err(DosErrorCode::AccessDenied),
+		}
 	}
 }
 
@@ -292,12 +1020,28 @@ fn std_file_error_to_dos_error(err: std::io::Error) -> DosErrorCode {
 
 impl DosFileSystem for StandardDosFileSystem {
 	fn create(&mut self, filename: &[u8], attributes: u16) -> Result<u16, DosErrorCode> {
-		let real_filepath = self.get_real_filepath(filename);
+		let real_filepath = self.get_real_filepath(filename)?;
+		let dos_path = self.get_dos_filepath(filename)?;
+		if self.is_read_only(&real_filepath) {
+			return Err(DosErrorCode::AccessDenied);
+		}
 		let slot = self.get_empty_slot();
-		match std::fs::File::create(real_filepath) {
+		// The handle can be read from as well as written to.
+		match std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&real_filepath) {
 			Ok(file) => {
-				self.file_handles[slot] = Some(file);
-				Ok(slot as u16 + 1)
+				self.set_extra_attributes(&real_filepath, attributes | DOS_ATTRIBUTE_ARCHIVE);
+				// Like DOS, the new handle can still write to a file created as read-only.
+				if attributes & DOS_ATTRIBUTE_READ_ONLY != 0 {
+					set_host_read_only(&real_filepath, true).map_err(std_file_error_to_dos_error)?;
+				}
+				self.file_handles[slot] = Some(OpenFile {
+					file,
+					dos_path,
+					real_path: real_filepath,
+					access_mode: DosFileAccessMode::ReadWrite,
+				});
+				self.dir_listing.invalidate();
+				Ok(slot as u16 + DOS_FIRST_FILE_HANDLE)
 			}
 			Err(err) => Err(std_file_error_to_dos_error(err)),
 		}
@@ -305,7 +1049,15 @@ impl DosFileSystem for StandardDosFileSystem {
 	
 	fn open(&mut self, filename: &[u8], access_mode: DosFileAccessMode) -> Result<u16, DosErrorCode> {
 		// TODO: 776655
-		let real_filepath = self.get_real_filepath(filename);
+		let real_filepath = self.get_real_filepath(filename)?;
+		let dos_path = self.get_dos_filepath(filename)?;
+		// The host will happily open a directory, but DOS refuses to.
+		if real_filepath.is_dir() {
+			return Err(DosErrorCode::AccessDenied);
+		}
+		if access_mode != DosFileAccessMode::ReadOnly && self.is_read_only(&real_filepath) {
+			return Err(DosErrorCode::AccessDenied);
+		}
 		let slot = self.get_empty_slot();
 		
 		let mut open_options = std::fs::OpenOptions::new();
@@ -315,27 +1067,42 @@ impl DosFileSystem for StandardDosFileSystem {
 			.write(access_mode == DosFileAccessMode::WriteOnly || access_mode == DosFileAccessMode::ReadWrite)
 			.create(access_mode == DosFileAccessMode::WriteOnly || access_mode == DosFileAccessMode::ReadWrite);
 		
-		match open_options.open(real_filepath) {
+		match open_options.open(&real_filepath) {
 			Ok(file) => {
-				self.file_handles[slot] = Some(file);
-				Ok(slot as u16 + 1)
+				self.file_handles[slot] = Some(OpenFile {
+					file,
+					dos_path,
+					real_path: real_filepath,
+					access_mode,
+				});
+				Ok(slot as u16 + DOS_FIRST_FILE_HANDLE)
 			}
 			Err(err) => Err(std_file_error_to_dos_error(err)),
 		}
 	}
 	
 	fn close(&mut self, handle: u16) -> Result<(), DosErrorCode> {
-		if handle == 0 {
-			Err(DosErrorCode::InvalidFileHandle)
-		} else {
-			let handle_index = (handle - 1) as usize;
-			if let Some(Some(ref mut file)) = self.file_handles.get_mut(handle_index) {
-				self.file_handles[handle_index] = None;
-				Ok(())
-			} else {
-				Err(DosErrorCode::InvalidFileHandle)
-			}
+		self.get_open_file(handle)?;
+		self.file_handles[(handle - DOS_FIRST_FILE_HANDLE) as usize] = None;
+		Ok(())
+	}
+	
+	fn duplicate(&mut self, handle: u16) -> Result<u16, DosErrorCode> {
+		let open_file = self.get_open_file(handle)?.try_clone()?;
+		let slot = self.get_empty_slot();
+		self.file_handles[slot] = Some(open_file);
+		Ok(slot as u16 + DOS_FIRST_FILE_HANDLE)
+	}
+	
+	fn force_duplicate(&mut self, handle: u16, new_handle: u16) -> Result<(), DosErrorCode> {
+		let new_slot = new_handle.checked_sub(DOS_FIRST_FILE_HANDLE).ok_or(DosErrorCode::InvalidFileHandle)? as usize;
+		let open_file = self.get_open_file(handle)?.try_clone()?;
+		if new_slot >= self.file_handles.len() {
+			self.file_handles.resize_with(new_slot + 1, || None);
 		}
+		// The file the slot had open is closed when it's dropped.
+		self.file_handles[new_slot] = Some(open_file);
+		Ok(())
 	}
 	
 	fn read(&mut self, handle: u16, destination: &mut [u8]) -> Result<u16, DosErrorCode> {
@@ -347,9 +1114,14 @@ impl DosFileSystem for StandardDosFileSystem {
 	}
 	
 	fn write(&mut self, handle: u16, data: &[u8]) -> Result<u16, DosErrorCode> {
+		let real_path = self.get_writable_path(handle)?;
 		let file = self.get_file_from_handle(handle)?;
 		match file.write(data) {
-			Ok(written_count) => Ok(written_count as u16),
+			Ok(written_count) => {
+				let attributes = self.get_real_attributes(&real_path)?;
+				self.set_extra_attributes(&real_path, attributes | DOS_ATTRIBUTE_ARCHIVE);
+				Ok(written_count as u16)
+			}
 			Err(err) => Err(std_file_error_to_dos_error(err)),
 		}
 	}
@@ -367,29 +1139,112 @@ impl DosFileSystem for StandardDosFileSystem {
 		}
 	}
 	
+	fn tell(&mut self, handle: u16) -> Result<u32, DosErrorCode> {
+		let file = self.get_file_from_handle(handle)?;
+		match file.stream_position() {
+			Ok(current_pos) => Ok(current_pos as u32),
+			Err(err) => Err(std_file_error_to_dos_error(err)),
+		}
+	}
+	
 	fn truncate(&mut self, handle: u16) -> Result<u32, DosErrorCode> {
+		let real_path = self.get_writable_path(handle)?;
+		let current_pos = self.tell(handle)?;
 		let file = self.get_file_from_handle(handle)?;
-		// TODO: Use file.stream_position() when it is stabilised:
-		match file.seek(std::io::SeekFrom::Current(0)) {
-			Ok(current_pos) => {
-				match file.set_len(current_pos) {
-					Ok(_) => Ok(current_pos as u32),
-					Err(err) => Err(std_file_error_to_dos_error(err)),
-				}
+		match file.set_len(current_pos as u64) {
+			Ok(_) => {
+				let attributes = self.get_real_attributes(&real_path)?;
+				self.set_extra_attributes(&real_path, attributes | DOS_ATTRIBUTE_ARCHIVE);
+				Ok(current_pos)
 			}
 			Err(err) => Err(std_file_error_to_dos_error(err)),
 		}
 	}
 	
+	fn is_at_end(&mut self, handle: u16) -> Result<bool, DosErrorCode> {
+		let current_pos = self.tell(handle)?;
+		let file = self.get_file_from_handle(handle)?;
+		match file.metadata() {
+			Ok(metadata) => Ok(current_pos as u64 >= metadata.len()),
+			Err(err) => Err(std_file_error_to_dos_error(err)),
+		}
+	}
+	
+	fn open_handles(&self) -> Vec<OpenHandleInfo> {
+		self.file_handles.iter().enumerate().filter_map(|(slot, open_file)| {
+			open_file.as_ref().map(|open_file| OpenHandleInfo {
+				handle: slot as u16 + DOS_FIRST_FILE_HANDLE,
+				dos_path: open_file.dos_path.clone(),
+				real_path: open_file.real_path.clone(),
+				access_mode: open_file.access_mode,
+			})
+		}).collect()
+	}
+	
+	fn flush_all(&mut self) -> Result<(), DosErrorCode> {
+		let mut result = Ok(());
+		for open_file in self.file_handles.iter().flatten() {
+			// Files aren't buffered by std, so only the host's own buffers need writing out.
+			if open_file.access_mode != DosFileAccessMode::ReadOnly {
+				if let Err(err) = open_file.file.sync_all() {
+					result = result.and(Err(std_file_error_to_dos_error(err)));
+				}
+			}
+		}
+		result
+	}
+	
+	fn close_all(&mut self) {
+		self.file_handles.clear();
+		self.current_file_queue = None;
+	}
+	
+	fn refresh(&mut self) {
+		self.dir_listing.invalidate();
+	}
+	
 	fn find_first_file(&mut self, destination: &mut [u8], attributes: u16, search_spec: &[u8]) -> Result<(), DosErrorCode> {
-		let real_filepath = self.get_real_filepath(search_spec);
-		let mut file_queue = VecDeque::new();
-		self.dir_listing.list_dir(&mut |dos_name| {
+		// The directory part is resolved now, so changing directory between calls doesn't change
+		// which directory the rest of the search lists.
+		let (dir_part, pattern) = split_search_spec(search_spec);
+		let search_dir = parse_dos_dir_path(dir_part, &self.current_dir)?;
+		let search_dir_path = self.get_real_dir_path(&search_dir)?;
+		let search_spec = parse_dos_name_component(pattern, true)?.real_dos_name();
+		// Searching for just the volume label bit finds the label instead of files.
+		// http://stanislavs.org/helppc/int_21-4e.html
+		if attributes == DOS_ATTRIBUTE_VOLUME_LABEL {
+			let mut file_queue = VecDeque::new();
+			let label_name = self.media_id.label_file_name();
+			if self.media_id.has_label() && filename_matches_spec(&label_name, &search_spec) {
+				file_queue.push_back((label_name, DOS_ATTRIBUTE_VOLUME_LABEL));
+			}
+			self.current_file_queue = Some(file_queue);
+			return self.find_next_file(destination);
+		}
+		let mut other_dir_listing;
+		let dir_listing = if search_dir_path == self.current_dir_path {
+			&mut self.dir_listing
+		} else {
+			other_dir_listing = DirListingCache::new(search_dir_path.clone());
+			&mut other_dir_listing
+		};
+		let mut matching_names = vec![];
+		dir_listing.list_dir(&mut |dos_name| {
 			//dbg!(ascii_filename_to_string(&dos_name.real_dos_name()));
-			if filename_matches_spec(&dos_name, search_spec) {
-				file_queue.push_back(dos_name);
+			if filename_matches_spec(&dos_name, &search_spec) {
+				matching_names.push(dos_name);
 			}
 		});
+		let real_paths: Vec<std::path::PathBuf> = matching_names.iter().map(|dos_name| search_dir_path.join(dir_listing.get_real_name(dos_name))).collect();
+		let mut file_queue = VecDeque::new();
+		for (dos_name, real_path) in matching_names.into_iter().zip(real_paths) {
+			if let Ok(file_attributes) = self.get_real_attributes(&real_path) {
+				let special_attributes = file_attributes & (DOS_ATTRIBUTE_HIDDEN | DOS_ATTRIBUTE_SYSTEM | DOS_ATTRIBUTE_DIRECTORY);
+				if special_attributes & !attributes == 0 {
+					file_queue.push_back((dos_name, file_attributes));
+				}
+			}
+		}
 		self.current_file_queue = Some(file_queue);
 		
 		self.find_next_file(destination)
@@ -397,11 +1252,12 @@ impl DosFileSystem for StandardDosFileSystem {
 	
 	fn find_next_file(&mut self, destination: &mut [u8]) -> Result<(), DosErrorCode> {
 		if let Some(ref mut current_file_queue) = self.current_file_queue {
-			if let Some(ref next_file) = current_file_queue.pop_front() {
+			if let Some((ref next_file, next_attributes)) = current_file_queue.pop_front() {
 				let next_name = next_file.real_dos_name();
 				// http://stanislavs.org/helppc/int_21-4e.html
 				let filename_off = 0x1e;
 				destination[0x15..=filename_off].iter_mut().for_each(|b| *b = 0);
+				destination[0x15] = next_attributes as u8;
 				let filename_dest = &mut destination[filename_off..];
 				filename_dest[..next_name.len()].clone_from_slice(&next_name);
 				filename_dest[next_name.len()] = 0;
@@ -413,17 +1269,480 @@ impl DosFileSystem for StandardDosFileSystem {
 			Err(DosErrorCode::NoMoreFiles)
 		}
 	}
+	
+	fn delete(&mut self, filename: &[u8]) -> Result<(), DosErrorCode> {
+		let real_filepath = self.get_real_filepath(filename)?;
+		let attributes = self.get_real_attributes(&real_filepath)?;
+		if attributes & (DOS_ATTRIBUTE_READ_ONLY | DOS_ATTRIBUTE_DIRECTORY) != 0 {
+			return Err(DosErrorCode::AccessDenied);
+		}
+		std::fs::remove_file(&real_filepath).map_err(std_file_error_to_dos_error)?;
+		if self.extra_attributes.remove(self.relative_path(&real_filepath)).is_some() {
+			self.save_attributes();
+		}
+		self.dir_listing.invalidate();
+		Ok(())
+	}
+	
+	fn get_attributes(&mut self, filename: &[u8]) -> Result<u16, DosErrorCode> {
+		let real_filepath = self.get_real_filepath(filename)?;
+		self.get_real_attributes(&real_filepath)
+	}
+	
+	fn set_attributes(&mut self, filename: &[u8], attributes: u16) -> Result<(), DosErrorCode> {
+		if attributes & !DOS_SETTABLE_ATTRIBUTES != 0 {
+			return Err(DosErrorCode::AccessDenied);
+		}
+		let real_filepath = self.get_real_filepath(filename)?;
+		let old_attributes = self.get_real_attributes(&real_filepath)?;
+		let read_only = attributes & DOS_ATTRIBUTE_READ_ONLY != 0;
+		if read_only != (old_attributes & DOS_ATTRIBUTE_READ_ONLY != 0) {
+			set_host_read_only(&real_filepath, read_only).map_err(std_file_error_to_dos_error)?;
+		}
+		self.set_extra_attributes(&real_filepath, attributes);
+		Ok(())
+	}
+	
+	fn root_path(&self) -> Option<&std::path::Path> {
+		Some(&self.root_path)
+	}
+	
+	fn current_directory(&self) -> Vec<u8> {
+		let names: Vec<Vec<u8>> = self.current_dir.iter().map(|dos_name| dos_name.real_dos_name()).collect();
+		names.join(&b'\\')
+	}
+	
+	fn set_current_directory(&mut self, path: &[u8]) -> Result<(), DosErrorCode> {
+		let new_dir = parse_dos_dir_path(path, &self.current_dir)?;
+		let new_dir_path = self.get_real_dir_path(&new_dir)?;
+		self.dir_listing = DirListingCache::new(new_dir_path.clone());
+		self.current_dir = new_dir;
+		self.current_dir_path = new_dir_path;
+		Ok(())
+	}
+	
+	fn media_id(&self) -> Result<DosMediaId, DosErrorCode> {
+		Ok(self.media_id)
+	}
+	
+	fn set_media_id(&mut self, media_id: DosMediaId) -> Result<(), DosErrorCode> {
+		self.media_id = media_id;
+		self.save_media_id();
+		Ok(())
+	}
+	
+	fn volume_info(&self) -> Result<DosVolumeInfo, DosErrorCode> {
+		let read_only = match std::fs::metadata(&self.root_path) {
+			Ok(metadata) => metadata.permissions().readonly(),
+			Err(err) => return Err(std_file_error_to_dos_error(err)),
+		};
+		// If the host can't say how big the disk is, pretend it's empty.
+		let (total_bytes, free_bytes) = host_volume_space(&self.root_path).unwrap_or((self.volume_size_cap, self.volume_size_cap));
+		let total_bytes = total_bytes.min(self.volume_size_cap);
+		Ok(DosVolumeInfo {
+			total_bytes,
+			free_bytes: if read_only { 0 } else { free_bytes.min(total_bytes) },
+			read_only,
+		})
+	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::dos_event_handler::tests::make_test_dir;
 	#[test] fn test_dir_listing_cache() {
-		let mut dir_listing = DirListingCache::new();
-		assert_eq!(String::from_utf8_lossy(&dir_listing.get_dos_name("foot.text").real_dos_name()), String::from_utf8_lossy(b"FOOT.TEX"));
-		assert_eq!(String::from_utf8_lossy(&dir_listing.get_dos_name("foot.text2").real_dos_name()), String::from_utf8_lossy(b"FOOT~1.TEX"));
-		assert_eq!(String::from_utf8_lossy(&dir_listing.get_dos_name("filewithlongname.txt").real_dos_name()), String::from_utf8_lossy(b"FILEWITH.TXT"));
-		assert_eq!(String::from_utf8_lossy(&dir_listing.get_dos_name("filewithlongername.txt").real_dos_name()), String::from_utf8_lossy(b"FILEWI~1.TXT"));
-		assert_eq!(String::from_utf8_lossy(&dir_listing.get_dos_name("filewithlongerername.txt").real_dos_name()), String::from_utf8_lossy(b"FILEWI~2.TXT"));
+		let mut dir_listing = DirListingCache::new(std::env::temp_dir().join("pseudos_test_nonexistent"));
+		let expected_names: &[(&str, &[u8])] = &[
+			("README.TXT", b"README.TXT"),
+			// Valid 8.3 names are only uppercased.
+			("readme.md", b"README.MD"),
+			("foot.text", b"FOOT~1.TEX"),
+			("foot.text2", b"FOOT~2.TEX"),
+			("filewithlongname.txt", b"FILEWI~1.TXT"),
+			("filewithlongername.txt", b"FILEWI~2.TXT"),
+			// Spaces are removed before truncating.
+			("My Saves.txt", b"MYSAVE~1.TXT"),
+			("A B", b"AB~1"),
+			// Only the last dot separates the extension, and other dots are removed.
+			("archive.tar.gz", b"ARCHIV~1.GZ"),
+			("v1.2.3", b"V12~1.3"),
+			// Leading dots are removed.
+			(".config", b"CONFIG~1"),
+			("...", b"_~1"),
+			("name.", b"NAME~1"),
+			// Names that only differ in characters that become '_' still get their own names.
+			("a+b.txt", b"A_B~1.TXT"),
+			("a=b.txt", b"A_B~2.TXT"),
+			("a[b.txt", b"A_B~3.TXT"),
+			("a_b.txt", b"A_B.TXT"),
+		];
+		for (real_name, dos_name) in expected_names {
+			assert_eq!(String::from_utf8_lossy(&dir_listing.get_dos_name(real_name).real_dos_name()), String::from_utf8_lossy(dos_name), "{}", real_name);
+		}
+		// The names stay the same once they're given out, and map back to the host names.
+		for (real_name, dos_name) in expected_names {
+			let dos_filename = dir_listing.get_dos_name(real_name);
+			assert_eq!(dos_filename.real_dos_name(), dos_name.to_vec());
+			assert_eq!(dir_listing.get_real_name(&dos_filename), OsString::from(real_name));
+		}
+		
+		// The title gets shorter to fit bigger numbers.
+		for name_index in 1 ..= 10 {
+			dir_listing.get_dos_name(format!("longfilename{}.txt", name_index));
+		}
+		assert_eq!(dir_listing.get_dos_name("longfilename9.txt").real_dos_name(), b"LONGFI~9.TXT".to_vec());
+		assert_eq!(dir_listing.get_dos_name("longfilename10.txt").real_dos_name(), b"LONGF~10.TXT".to_vec());
+	}
+	
+	#[test] fn test_dir_listing_follows_changes() {
+		let root_path = make_test_dir("listing_changes");
+		let mut file_system = StandardDosFileSystem::new(root_path.clone());
+		let mut find_result = [0u8; 0x2b];
+		assert_eq!(file_system.find_first_file(&mut find_result, 0, b"NEW.TXT"), Err(DosErrorCode::NoMoreFiles));
+		let handle = file_system.create(b"NEW.TXT", 0).unwrap();
+		file_system.close(handle).unwrap();
+		assert_eq!(file_system.find_first_file(&mut find_result, 0, b"NEW.TXT"), Ok(()));
+		assert_eq!(&find_result[0x1e..0x26], b"NEW.TXT\0");
+		file_system.delete(b"NEW.TXT").unwrap();
+		assert_eq!(file_system.find_first_file(&mut find_result, 0, b"NEW.TXT"), Err(DosErrorCode::NoMoreFiles));
+		
+		// Once a mangled name's file is gone, the name is free for the next one.
+		std::fs::write(root_path.join("longfilename1.txt"), b"").unwrap();
+		std::fs::write(root_path.join("longfilename2.txt"), b"").unwrap();
+		assert_eq!(file_system.find_first_file(&mut find_result, 0, b"LONGFI~2.TXT"), Ok(()));
+		file_system.delete(b"LONGFI~1.TXT").unwrap();
+		assert_eq!(file_system.find_first_file(&mut find_result, 0, b"LONGFI~2.TXT"), Err(DosErrorCode::NoMoreFiles));
+		let handle = file_system.open(b"LONGFI~1.TXT", DosFileAccessMode::ReadOnly).unwrap();
+		file_system.close(handle).unwrap();
+		assert!(root_path.join("longfilename2.txt").exists());
+	}
+	
+	#[test] fn test_path_traversal_rejected() {
+		let test_dir = make_test_dir("traversal");
+		let root_path = test_dir.join("root");
+		std::fs::create_dir(&root_path).unwrap();
+		let mut file_system = StandardDosFileSystem::new(root_path.clone());
+		assert_eq!(file_system.create(b"..\\..\\X", 0), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.create(b"..\\X", 0), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.open(b"C:\\..\\X", DosFileAccessMode::ReadWrite), Err(DosErrorCode::PathNotFound));
+		let absolute_path = test_dir.join("X");
+		let absolute_path = absolute_path.to_str().unwrap().as_bytes();
+		assert_eq!(file_system.create(absolute_path, 0), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.create(b"BAD\x01NAME", 0), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.create(b"A.B.C", 0), Err(DosErrorCode::PathNotFound));
+		assert!(!test_dir.join("X").exists());
+		assert!(!test_dir.join("x").exists());
+		assert_eq!(std::fs::read_dir(&root_path).unwrap().count(), 0);
+		
+		// Going down and back up stays inside the root.
+		assert!(file_system.create(b"\\SUB\\..\\OK.TXT", 0).is_ok());
+		assert!(root_path.join("OK.TXT").exists());
+	}
+	
+	#[test] fn test_device_names_rejected() {
+		let root_path = make_test_dir("devices");
+		let mut file_system = StandardDosFileSystem::new(root_path.clone());
+		assert_eq!(file_system.open(b"CON", DosFileAccessMode::ReadWrite), Err(DosErrorCode::AccessDenied));
+		assert_eq!(file_system.create(b"nul.txt", 0), Err(DosErrorCode::AccessDenied));
+		assert_eq!(std::fs::read_dir(&root_path).unwrap().count(), 0);
+	}
+	
+	#[cfg(unix)]
+	#[test] fn test_symlink_escape_rejected() {
+		let test_dir = make_test_dir("symlink");
+		let root_path = test_dir.join("root");
+		std::fs::create_dir(&root_path).unwrap();
+		std::fs::write(test_dir.join("secret.txt"), b"secret").unwrap();
+		std::os::unix::fs::symlink(test_dir.join("secret.txt"), root_path.join("LINK.TXT")).unwrap();
+		std::os::unix::fs::symlink(test_dir.join("missing.txt"), root_path.join("DANGLING.TXT")).unwrap();
+		let mut file_system = StandardDosFileSystem::new(root_path.clone());
+		assert_eq!(file_system.open(b"LINK.TXT", DosFileAccessMode::ReadOnly), Err(DosErrorCode::AccessDenied));
+		assert_eq!(file_system.create(b"LINK.TXT", 0), Err(DosErrorCode::AccessDenied));
+		assert_eq!(file_system.create(b"DANGLING.TXT", 0), Err(DosErrorCode::AccessDenied));
+		assert_eq!(std::fs::read(test_dir.join("secret.txt")).unwrap(), b"secret");
+		assert!(!test_dir.join("missing.txt").exists());
+	}
+	
+	#[test] fn test_volume_info() {
+		let root_path = make_test_dir("volume_info");
+		let mut file_system = StandardDosFileSystem::new(root_path.clone());
+		file_system.set_volume_size_cap(10 * 1024 * 1024);
+		let volume_info = file_system.volume_info().unwrap();
+		assert!(!volume_info.read_only);
+		assert!(volume_info.total_bytes <= 10 * 1024 * 1024);
+		assert!(volume_info.free_bytes <= volume_info.total_bytes);
+		assert_eq!(file_system.drive_volume_info(2), Ok(volume_info));
+		assert_eq!(file_system.drive_volume_info(0), Err(DosErrorCode::InvalidDrive));
+	}
+	
+	#[test] fn test_disk_geometry() {
+		let floppy = DosVolumeInfo{total_bytes: 1474560, free_bytes: 1024 * 1024, read_only: false};
+		assert_eq!(floppy.disk_geometry(), DosDiskGeometry{sectors_per_cluster: 1, bytes_per_sector: 512, total_clusters: 2880, free_clusters: 2048});
+		let read_only = DosVolumeInfo{read_only: true, ..floppy};
+		assert_eq!(read_only.disk_geometry().free_clusters, 0);
+		// A multi-terabyte host disk must not overflow the 16 bit counts.
+		let huge = DosVolumeInfo{total_bytes: 8 << 40, free_bytes: 3 << 40, read_only: false};
+		assert_eq!(huge.disk_geometry(), DosDiskGeometry{sectors_per_cluster: 64, bytes_per_sector: 512, total_clusters: 0xfff4, free_clusters: 0xfff4});
+	}
+	
+	#[test] fn test_unicode_names_round_trip() {
+		let root_path = make_test_dir("unicode_names");
+		let mut real_names: Vec<OsString> = vec!["café.txt".into(), "cafè.txt".into(), "caf_.txt".into(), "日本語.doc".into(), "archive.tar.gz".into()];
+		#[cfg(unix)] {
+			use std::os::unix::ffi::OsStringExt;
+			real_names.push(OsString::from_vec(b"caf\xe9.txt".to_vec()));
+		}
+		for real_name in &real_names {
+			std::fs::write(root_path.join(real_name), b"").unwrap();
+		}
+		
+		let mut dir_listing = DirListingCache::new(root_path.clone());
+		// Valid 8.3 names keep their name even if a mangled name would clash with it.
+		assert_eq!(dir_listing.get_dos_name("caf_.txt").real_dos_name(), b"CAF_.TXT".to_vec());
+		assert_eq!(dir_listing.get_dos_name("archive.tar.gz").real_dos_name(), b"ARCHIV~1.GZ".to_vec());
+		for real_name in &real_names {
+			let dos_name = dir_listing.get_dos_name(real_name);
+			assert_eq!(parse_dos_path(&dos_name.real_dos_name(), &[]), Ok((vec![], dos_name.clone())));
+			assert_eq!(&dir_listing.get_real_name(&dos_name), real_name);
+		}
+		
+		// A fresh listing of the same directory gives the same names.
+		let mut other_dir_listing = DirListingCache::new(root_path.clone());
+		for real_name in &real_names {
+			assert_eq!(other_dir_listing.get_dos_name(real_name), dir_listing.get_dos_name(real_name));
+		}
+	}
+	
+	#[test] fn test_tell() {
+		let mut file_system = StandardDosFileSystem::new(make_test_dir("tell"));
+		let handle = file_system.create(b"TELL.DAT", 0).unwrap();
+		assert_eq!(file_system.tell(handle), Ok(0));
+		file_system.write(handle, b"hello").unwrap();
+		assert_eq!(file_system.tell(handle), Ok(5));
+		file_system.seek(handle, 2, DosFileSeekOrigin::Start).unwrap();
+		assert_eq!(file_system.tell(handle), Ok(2));
+		assert_eq!(file_system.truncate(handle), Ok(2));
+		assert_eq!(file_system.is_at_end(handle), Ok(true));
+		assert_eq!(file_system.tell(handle + 1), Err(DosErrorCode::InvalidFileHandle));
+	}
+	
+	#[test] fn test_open_handles() {
+		let dir_path = make_test_dir("open_handles");
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		let save_handle = file_system.create(b"SAVE.DAT", 0).unwrap();
+		let config_handle = file_system.create(b"GAME.CFG", 0).unwrap();
+		file_system.close(save_handle).unwrap();
+		let save_handle = file_system.open(b"save.dat", DosFileAccessMode::ReadOnly).unwrap();
+		assert_eq!(file_system.open_handles(), vec![
+			OpenHandleInfo { handle: save_handle, dos_path: b"\\SAVE.DAT".to_vec(), real_path: dir_path.join("SAVE.DAT"), access_mode: DosFileAccessMode::ReadOnly },
+			OpenHandleInfo { handle: config_handle, dos_path: b"\\GAME.CFG".to_vec(), real_path: dir_path.join("GAME.CFG"), access_mode: DosFileAccessMode::ReadWrite },
+		]);
+		// Files in a subdirectory get its path.
+		std::fs::create_dir(dir_path.join("saves")).unwrap();
+		file_system.set_current_directory(b"SAVES").unwrap();
+		let slot_handle = file_system.create(b"slot1.sav", 0).unwrap();
+		assert_eq!(file_system.open_handles()[2].dos_path, b"\\SAVES\\SLOT1.SAV".to_vec());
+		file_system.close(slot_handle).unwrap();
+		file_system.close(save_handle).unwrap();
+		file_system.close(config_handle).unwrap();
+		assert_eq!(file_system.open_handles(), vec![]);
+	}
+	
+	#[test] fn test_open_edge_cases() {
+		let dir_path = make_test_dir("open_edge_cases");
+		std::fs::create_dir_all(dir_path.join("saves")).unwrap();
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		assert_eq!(file_system.open(b"SAVES", DosFileAccessMode::ReadOnly), Err(DosErrorCode::AccessDenied));
+		assert_eq!(file_system.open(b"SAVES", DosFileAccessMode::ReadWrite), Err(DosErrorCode::AccessDenied));
+		
+		// A writable file opened as read-only can't be written through that handle.
+		let handle = file_system.create(b"DATA.DAT", 0).unwrap();
+		file_system.close(handle).unwrap();
+		let handle = file_system.open(b"DATA.DAT", DosFileAccessMode::ReadOnly).unwrap();
+		assert_eq!(file_system.write(handle, b"abc"), Err(DosErrorCode::AccessDenied));
+		assert_eq!(file_system.truncate(handle), Err(DosErrorCode::AccessDenied));
+		file_system.close(handle).unwrap();
+		assert_eq!(std::fs::read(dir_path.join("DATA.DAT")).unwrap(), b"");
+	}
+	
+	#[test] fn test_create_existing_file() {
+		let mut file_system = StandardDosFileSystem::new(make_test_dir("create_existing_file"));
+		let write_handle = file_system.create(b"SCORES.DAT", 0).unwrap();
+		assert!(write_handle >= DOS_FIRST_FILE_HANDLE);
+		file_system.write(write_handle, b"12345").unwrap();
+		file_system.close(write_handle).unwrap();
+		assert_eq!(file_system.get_attributes(b"SCORES.DAT"), Ok(DOS_ATTRIBUTE_ARCHIVE));
+		
+		// Creating it again empties it, and a handle that was already open sees the empty file.
+		let read_handle = file_system.open(b"SCORES.DAT", DosFileAccessMode::ReadOnly).unwrap();
+		let create_handle = file_system.create(b"SCORES.DAT", DOS_ATTRIBUTE_HIDDEN).unwrap();
+		assert_ne!(create_handle, read_handle);
+		let mut data = [0; 5];
+		assert_eq!(file_system.read(read_handle, &mut data), Ok(0));
+		assert_eq!(file_system.is_at_end(read_handle), Ok(true));
+		assert_eq!(file_system.get_attributes(b"SCORES.DAT"), Ok(DOS_ATTRIBUTE_HIDDEN | DOS_ATTRIBUTE_ARCHIVE));
+		
+		file_system.write(create_handle, b"9").unwrap();
+		assert_eq!(file_system.read(read_handle, &mut data), Ok(1));
+		assert_eq!(data[0], b'9');
+		for handle in 0 .. DOS_FIRST_FILE_HANDLE {
+			assert_eq!(file_system.close(handle), Err(DosErrorCode::InvalidFileHandle));
+		}
+	}
+	
+	#[test] fn test_path_mapping() {
+		let dir_path = make_test_dir("path_mapping");
+		std::fs::create_dir_all(dir_path.join("my games").join("zzt")).unwrap();
+		std::fs::write(dir_path.join("my games").join("zzt").join("archive.tar.gz"), b"").unwrap();
+		std::fs::write(dir_path.join("ROOT.TXT"), b"").unwrap();
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		let archive_path = dir_path.join("my games").join("zzt").join("archive.tar.gz");
+		assert_eq!(file_system.host_to_dos_path(&archive_path), Ok(b"\\MYGAME~1\\ZZT\\ARCHIV~1.GZ".to_vec()));
+		assert_eq!(file_system.host_to_dos_path(&dir_path), Ok(b"\\".to_vec()));
+		assert_eq!(file_system.host_to_dos_path(&dir_path.join(ATTRIBUTES_FILE_NAME)), Err(DosErrorCode::FileNotFound));
+		assert_eq!(file_system.host_to_dos_path(&dir_path.join(".pseudos-palette")), Err(DosErrorCode::FileNotFound));
+		assert_eq!(file_system.host_to_dos_path(&dir_path.join(PROFILE_FILE_NAME)), Err(DosErrorCode::FileNotFound));
+		assert_eq!(file_system.host_to_dos_path(&std::env::temp_dir()), Err(DosErrorCode::AccessDenied));
+		
+		assert_eq!(file_system.dos_to_host_path(b"C:\\MYGAME~1\\ZZT\\ARCHIV~1.GZ"), Ok(archive_path.clone()));
+		// Files that don't exist yet get the name they'd be created with.
+		assert_eq!(file_system.dos_to_host_path(b"\\MYGAME~1\\new.dat"), Ok(dir_path.join("my games").join("NEW.DAT")));
+		assert_eq!(file_system.dos_to_host_path(b"\\MISSING\\NEW.DAT"), Err(DosErrorCode::PathNotFound));
+		file_system.set_current_directory(b"MYGAME~1").unwrap();
+		assert_eq!(file_system.dos_to_host_path(b"ZZT\\ARCHIV~1.GZ"), Ok(archive_path));
+		assert_eq!(file_system.dos_to_host_path(b"..\\ROOT.TXT"), Ok(dir_path.join("ROOT.TXT")));
+	}
+	
+	#[test] fn test_attributes() {
+		let dir_path = make_test_dir("attributes");
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		file_system.enable_attribute_persistence();
+		let handle = file_system.create(b"SAVE.DAT", 0).unwrap();
+		file_system.close(handle).unwrap();
+		let handle = file_system.create(b"SECRET.DAT", DOS_ATTRIBUTE_HIDDEN).unwrap();
+		file_system.close(handle).unwrap();
+		
+		// Read-only files can't be written or deleted, even though the process could.
+		file_system.set_attributes(b"SAVE.DAT", DOS_ATTRIBUTE_READ_ONLY).unwrap();
+		assert!(std::fs::metadata(dir_path.join("SAVE.DAT")).unwrap().permissions().readonly());
+		assert_eq!(file_system.open(b"SAVE.DAT", DosFileAccessMode::ReadWrite), Err(DosErrorCode::AccessDenied));
+		let handle = file_system.open(b"SAVE.DAT", DosFileAccessMode::ReadOnly).unwrap();
+		assert_eq!(file_system.write(handle, b"abc"), Err(DosErrorCode::AccessDenied));
+		file_system.close(handle).unwrap();
+		assert_eq!(file_system.delete(b"SAVE.DAT"), Err(DosErrorCode::AccessDenied));
+		assert_eq!(file_system.set_attributes(b"SAVE.DAT", DOS_ATTRIBUTE_DIRECTORY), Err(DosErrorCode::AccessDenied));
+		
+		// Writing sets the archive bit again after it's cleared.
+		file_system.set_attributes(b"SAVE.DAT", 0).unwrap();
+		assert_eq!(file_system.get_attributes(b"SAVE.DAT"), Ok(0));
+		let handle = file_system.open(b"SAVE.DAT", DosFileAccessMode::ReadWrite).unwrap();
+		assert_eq!(file_system.write(handle, b"abc"), Ok(3));
+		file_system.close(handle).unwrap();
+		assert_eq!(file_system.get_attributes(b"SAVE.DAT"), Ok(DOS_ATTRIBUTE_ARCHIVE));
+		file_system.set_attributes(b"SAVE.DAT", 0).unwrap();
+		
+		// Hidden files are only found when asked for, and the attributes file is never found.
+		let find_all = |file_system: &mut StandardDosFileSystem, attributes: u16| {
+			let mut find_data = [0; 0x2b];
+			let mut found = vec![];
+			let mut result = file_system.find_first_file(&mut find_data, attributes, b"*.*");
+			while result.is_ok() {
+				let name_end = find_data[0x1e..].iter().position(|c| *c == 0).unwrap();
+				found.push((find_data[0x1e..0x1e + name_end].to_vec(), find_data[0x15]));
+				result = file_system.find_next_file(&mut find_data);
+			}
+			found.sort();
+			found
+		};
+		assert_eq!(find_all(&mut file_system, 0), vec![(b"SAVE.DAT".to_vec(), 0)]);
+		assert_eq!(find_all(&mut file_system, DOS_ATTRIBUTE_HIDDEN), vec![
+			(b"SAVE.DAT".to_vec(), 0),
+			(b"SECRET.DAT".to_vec(), (DOS_ATTRIBUTE_HIDDEN | DOS_ATTRIBUTE_ARCHIVE) as u8),
+		]);
+		
+		// The attributes are still there after a restart.
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		file_system.enable_attribute_persistence();
+		assert_eq!(file_system.get_attributes(b"SAVE.DAT"), Ok(0));
+		assert_eq!(file_system.get_attributes(b"SECRET.DAT"), Ok(DOS_ATTRIBUTE_HIDDEN | DOS_ATTRIBUTE_ARCHIVE));
+		file_system.delete(b"SAVE.DAT").unwrap();
+		assert!(!dir_path.join("SAVE.DAT").exists());
+	}
+	
+	#[test] fn test_current_directory() {
+		let dir_path = make_test_dir("current_directory");
+		std::fs::create_dir_all(dir_path.join("games").join("zzt")).unwrap();
+		std::fs::write(dir_path.join("games").join("zzt").join("town.zzt"), b"town").unwrap();
+		std::fs::write(dir_path.join("ROOT.TXT"), b"root").unwrap();
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		assert_eq!(file_system.set_current_directory(b"\\GAMES\\MISSING"), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.set_current_directory(b"ROOT.TXT"), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.set_current_directory(b".."), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.current_directory(), b"");
+		
+		file_system.set_current_directory(b"games").unwrap();
+		file_system.set_current_directory(b"ZZT").unwrap();
+		assert_eq!(file_system.current_directory(), b"GAMES\\ZZT");
+		// Relative names are looked up in the current directory, and absolute names in the root.
+		assert!(file_system.open(b"TOWN.ZZT", DosFileAccessMode::ReadOnly).is_ok());
+		assert!(file_system.open(b"\\ROOT.TXT", DosFileAccessMode::ReadOnly).is_ok());
+		assert!(file_system.open(b"ROOT.TXT", DosFileAccessMode::ReadOnly).is_err());
+		// Paths can go back up through the parent directory, or start from the root on the drive.
+		assert!(file_system.open(b"..\\..\\ROOT.TXT", DosFileAccessMode::ReadOnly).is_ok());
+		assert!(file_system.open(b"C:\\GAMES\\ZZT\\TOWN.ZZT", DosFileAccessMode::ReadOnly).is_ok());
+		assert_eq!(file_system.open(b"..\\MISSING\\TOWN.ZZT", DosFileAccessMode::ReadOnly), Err(DosErrorCode::PathNotFound));
+		
+		file_system.set_current_directory(b"..\\..").unwrap();
+		// Relative paths can name a file in a subdirectory, for every file operation.
+		let handle = file_system.create(b"GAMES\\SLOT1.SAV", 0).unwrap();
+		assert_eq!(file_system.write(handle, b"save"), Ok(4));
+		file_system.close(handle).unwrap();
+		assert_eq!(std::fs::read(dir_path.join("games").join("SLOT1.SAV")).unwrap(), b"save");
+		assert_eq!(file_system.set_attributes(b"games\\slot1.sav", DOS_ATTRIBUTE_HIDDEN), Ok(()));
+		assert_eq!(file_system.get_attributes(b"GAMES\\SLOT1.SAV"), Ok(DOS_ATTRIBUTE_HIDDEN));
+		assert_eq!(file_system.delete(b"GAMES\\SLOT1.SAV"), Ok(()));
+		assert!(!dir_path.join("games").join("SLOT1.SAV").exists());
+		assert_eq!(file_system.current_directory(), b"");
+	}
+	
+	#[test] fn test_find_file_in_directory() {
+		let dir_path = make_test_dir("find_in_directory");
+		std::fs::create_dir_all(dir_path.join("levels")).unwrap();
+		std::fs::create_dir_all(dir_path.join("saves")).unwrap();
+		std::fs::write(dir_path.join("levels").join("one.dat"), b"1").unwrap();
+		std::fs::write(dir_path.join("levels").join("two.dat"), b"2").unwrap();
+		std::fs::write(dir_path.join("saves").join("game1.sav"), b"s").unwrap();
+		std::fs::write(dir_path.join("ROOT.DAT"), b"r").unwrap();
+		let mut file_system = StandardDosFileSystem::new(dir_path.clone());
+		let found_name = |find_data: &[u8]| {
+			let name_end = find_data[0x1e..].iter().position(|c| *c == 0).unwrap();
+			find_data[0x1e..0x1e + name_end].to_vec()
+		};
+		
+		// Only the 8.3 name goes in the DTA, without the directory.
+		let mut find_data = [0; 0x2b];
+		assert_eq!(file_system.find_first_file(&mut find_data, 0, b"LEVELS\\*.DAT"), Ok(()));
+		let first_name = found_name(&find_data);
+		// Changing directory part way through doesn't change the directory being searched.
+		file_system.set_current_directory(b"SAVES").unwrap();
+		assert_eq!(file_system.find_next_file(&mut find_data), Ok(()));
+		let mut names = vec![first_name, found_name(&find_data)];
+		names.sort();
+		assert_eq!(names, vec![b"ONE.DAT".to_vec(), b"TWO.DAT".to_vec()]);
+		assert_eq!(file_system.find_next_file(&mut find_data), Err(DosErrorCode::NoMoreFiles));
+		
+		// Relative specs start from the current directory, which is now SAVES.
+		assert_eq!(file_system.find_first_file(&mut find_data, 0, b"..\\SAVES\\GAME?.SAV"), Ok(()));
+		assert_eq!(found_name(&find_data), b"GAME1.SAV");
+		assert_eq!(file_system.find_first_file(&mut find_data, 0, b"C:..\\*.DAT"), Ok(()));
+		assert_eq!(found_name(&find_data), b"ROOT.DAT");
+		assert_eq!(file_system.find_first_file(&mut find_data, 0, b"\\LEVELS\\ONE.*"), Ok(()));
+		assert_eq!(found_name(&find_data), b"ONE.DAT");
+		
+		// A missing directory is a different error from a directory with nothing matching in it.
+		assert_eq!(file_system.find_first_file(&mut find_data, 0, b"\\MISSING\\*.*"), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.find_first_file(&mut find_data, 0, b"..\\..\\*.*"), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.find_first_file(&mut find_data, 0, b"\\LEVELS\\*.SAV"), Err(DosErrorCode::NoMoreFiles));
 	}
 }
@@ -0,0 +1,259 @@
+// Hooks that run once per frame, for automating a program from Rust: pressing Enter when a prompt
+// shows up, watching a value in memory, taking a screenshot when something happens, and so on. The
+// runner calls FrameHooks::run between frames, never while the machine is stepping.
+//
+// A hook gets a HookContext, which only borrows the machine and handler immutably. Anything that
+// changes them (key presses and pokes) is queued in the context and applied by run once all the
+// hooks have finished, when nothing else is borrowing the machine. That way a hook can't hold
+// mutable access to the machine while the handler does, and every hook in a frame sees the
+// machine as the frame left it, whatever the hooks before it asked for. Screenshots, state saves
+// and stopping are up to the runner, so they're returned from run for it to act on.
+
+use crate::dos_event_handler::{DosEventHandler, KeyPressInfo};
+use crate::machine::Machine;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookAction {
+	/// The hook runs again after the next frame.
+	Keep,
+	/// The hook is removed, and never runs again.
+	Remove,
+}
+
+/// A hook gets the context for the frame that just finished. Hooks have to be Send so the runner
+/// can be moved to another thread.
+pub type FrameHookFn = Box<dyn FnMut(&mut HookContext) -> HookAction + Send>;
+
+/// What the hooks asked the runner to do after a frame.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameHookRequests {
+	pub screenshot: bool,
+	pub save_state: bool,
+	/// Save the memory and registers (see dos_memory_dump).
+	pub dump_memory: bool,
+	pub stop: bool,
+}
+
+pub struct HookContext<'a> {
+	machine: &'a dyn Machine,
+	handler: &'a DosEventHandler,
+	frame_number: u64,
+	key_presses: Vec<KeyPressInfo>,
+	// Linear addresses and the bytes to write there.
+	pokes: Vec<(u32, u8)>,
+	requests: FrameHookRequests,
+}
+
+impl<'a> HookContext<'a> {
+	/// The number of frames run so far, counting the one that just finished.
+	pub fn frame_number(&self) -> u64 {
+		self.frame_number
+	}
+
+	/// The emulated time, which goes up by a timer tick each frame.
+	pub fn seconds_since_start(&self) -> f64 {
+		self.handler.seconds_since_start
+	}
+
+	/// The characters on the active page, as a list of rows.
+	pub fn screen_text(&self) -> Vec<Vec<u8>> {
+		self.handler.get_screen_text(self.machine)
+	}
+
+	/// Finds text on the active page, returning the column and row it starts at. Text doesn't
+	/// continue from one row to the next.
+	pub fn find_screen_text(&self, text: &[u8]) -> Option<(usize, usize)> {
+		if text.is_empty() {
+			return Some((0, 0));
+		}
+		self.screen_text().iter().enumerate().find_map(|(y, row)| {
+			row.windows(text.len()).position(|window| window == text).map(|x| (x, y))
+		})
+	}
+
+	pub fn screen_contains(&self, text: &[u8]) -> bool {
+		self.find_screen_text(text).is_some()
+	}
+
+	/// Reads memory at a linear address. Gives None if any of it is past the end of memory.
+	pub fn read_memory(&self, addr: u32, length: usize) -> Option<&[u8]> {
+		self.machine.memory().get(addr as usize .. addr as usize + length)
+	}
+
+	pub fn read_u8(&self, segment: u16, offset: u16) -> Option<u8> {
+		self.read_memory(segmented_address(segment, offset), 1).map(|bytes| bytes[0])
+	}
+
+	pub fn read_u16(&self, segment: u16, offset: u16) -> Option<u16> {
+		self.read_memory(segmented_address(segment, offset), 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+	}
+
+	/// Queues a key press, which goes after the keys already queued. The program can read it in
+	/// the next frame.
+	pub fn press_key(&mut self, key_press_info: KeyPressInfo) {
+		self.key_presses.push(key_press_info);
+	}
+
+	/// Queues a byte to write to memory once all the hooks have run.
+	pub fn poke_u8(&mut self, segment: u16, offset: u16, value: u8) {
+		self.pokes.push((segmented_address(segment, offset), value));
+	}
+
+	pub fn request_screenshot(&mut self) {
+		self.requests.screenshot = true;
+	}
+
+	pub fn request_save_state(&mut self) {
+		self.requests.save_state = true;
+	}
+
+	pub fn request_memory_dump(&mut self) {
+		self.requests.dump_memory = true;
+	}
+
+	/// Asks the runner to stop the emulation after this frame.
+	pub fn stop(&mut self) {
+		self.requests.stop = true;
+	}
+}
+
+fn segmented_address(segment: u16, offset: u16) -> u32 {
+	((segment as u32) << 4) + offset as u32
+}
+
+#[derive(Default)]
+pub struct FrameHooks {
+	hooks: Vec<FrameHookFn>,
+	frame_number: u64,
+}
+
+impl std::fmt::Debug for FrameHooks {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("FrameHooks").field("hook_count", &self.hooks.len()).field("frame_number", &self.frame_number).finish()
+	}
+}
+
+impl FrameHooks {
+	pub fn new() -> FrameHooks {
+		FrameHooks::default()
+	}
+
+	/// Adds a hook that runs after every frame, after the hooks that were added before it.
+	pub fn add_frame_hook<F: FnMut(&mut HookContext) -> HookAction + Send + 'static>(&mut self, hook: F) {
+		self.hooks.push(Box::new(hook));
+	}
+
+	pub fn len(&self) -> usize {
+		self.hooks.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.hooks.is_empty()
+	}
+
+	/// Runs the hooks in the order they were added, once a frame has finished. The keys they press
+	/// are added to the handler's key press queue and their pokes are written to memory afterwards,
+	/// in the order they were asked for.
+	pub fn run(&mut self, machine: &mut dyn Machine, handler: &mut DosEventHandler) -> FrameHookRequests {
+		self.frame_number += 1;
+		let mut context = HookContext {
+			machine,
+			handler,
+			frame_number: self.frame_number,
+			key_presses: vec![],
+			pokes: vec![],
+			requests: FrameHookRequests::default(),
+		};
+		self.hooks.retain_mut(|hook| hook(&mut context) == HookAction::Keep);
+		let HookContext { key_presses, pokes, requests, .. } = context;
+		handler.key_press_queue.extend(key_presses);
+		for (addr, value) in pokes {
+			if let Some(byte) = machine.memory_mut().get_mut(addr as usize) {
+				*byte = value;
+			}
+		}
+		requests
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_environment::build_environment_block;
+	use crate::dos_event_handler::tests::make_test_handler;
+	use crate::dos_frame_slicing::{FrameRun, SliceEnd, OPCODES_PER_STANDARD_TICK};
+	use crate::exe_loader::load_com_into_machine;
+
+	use std::sync::{Arc, Mutex};
+	use xachtsechs::machine8086::Machine8086;
+
+	// loop: mov ah, 01h; int 16h; jz loop; mov ah, 00h; int 16h; mov ah, 0eh; int 10h; jmp loop
+	const ECHO_PROGRAM: &[u8] = &[0xb4, 0x01, 0xcd, 0x16, 0x74, 0xfa, 0xb4, 0x00, 0xcd, 0x16, 0xb4, 0x0e, 0xcd, 0x10, 0xeb, 0xf0];
+
+	#[test] fn test_frame_hooks() {
+		let (mut handler, mut machine) = make_test_handler();
+		load_com_into_machine(&mut machine, ECHO_PROGRAM, b"", &build_environment_block(&[], b"C:\\ECHO.COM"), &*handler.file_system).unwrap();
+		handler.init_machine(&mut machine);
+		let run_frame = |handler: &mut DosEventHandler, machine: &mut Machine8086| {
+			let mut frame_run = FrameRun::new(OPCODES_PER_STANDARD_TICK, 1, 4);
+			while frame_run.run_slice(machine, handler).unwrap() != SliceEnd::Frame {}
+		};
+
+		let log = Arc::new(Mutex::new(vec![]));
+		let mut hooks = FrameHooks::new();
+		// Presses a key once on the second frame, and then removes itself.
+		let first_log = log.clone();
+		hooks.add_frame_hook(move |context| {
+			first_log.lock().unwrap().push(("first", context.frame_number()));
+			if context.frame_number() == 2 {
+				context.press_key(KeyPressInfo { scan_code: 0x25, ascii_char: b'k' });
+				context.poke_u8(0x9000, 0x10, 0x42);
+				HookAction::Remove
+			} else {
+				HookAction::Keep
+			}
+		});
+		// Pokes aren't seen by the other hooks until the next frame.
+		let second_log = log.clone();
+		hooks.add_frame_hook(move |context| {
+			second_log.lock().unwrap().push(("second", context.frame_number()));
+			if context.read_u8(0x9000, 0x10) == Some(0x42) {
+				context.stop();
+			}
+			HookAction::Keep
+		});
+
+		let mut requests = vec![];
+		for _ in 0 .. 3 {
+			run_frame(&mut handler, &mut machine);
+			requests.push(hooks.run(&mut machine, &mut handler));
+			if hooks.len() == 1 && !handler.key_press_queue.is_empty() {
+				// The key isn't read until the next frame runs.
+				assert!(!machine.memory[0xb8000 .. 0xb8000 + 160].contains(&b'k'));
+			}
+		}
+		assert_eq!(*log.lock().unwrap(), vec![("first", 1), ("second", 1), ("first", 2), ("second", 2), ("second", 3)]);
+		assert_eq!(requests.iter().map(|requests| requests.stop).collect::<Vec<_>>(), vec![false, false, true]);
+		assert!(handler.key_press_queue.is_empty());
+		assert_eq!(machine.memory[0xb8000], b'k');
+		assert_eq!(hooks.len(), 1);
+	}
+
+	#[test] fn test_find_screen_text() {
+		let (handler, mut machine) = make_test_handler();
+		machine.memory[0xb8000 + 160 + 4 .. 0xb8000 + 160 + 14].copy_from_slice(b"P\x07r\x07e\x07s\x07s\x07");
+		let context = HookContext {
+			machine: &machine,
+			handler: &handler,
+			frame_number: 1,
+			key_presses: vec![],
+			pokes: vec![],
+			requests: FrameHookRequests::default(),
+		};
+		assert_eq!(context.find_screen_text(b"Press"), Some((2, 1)));
+		assert!(!context.screen_contains(b"Pressed"));
+		assert_eq!(context.read_u16(0xb800, 160 + 4), Some(0x0750));
+		assert_eq!(context.read_memory(0xffffe, 4), None);
+	}
+}
@@ -0,0 +1,279 @@
+// How a runner splits the opcodes it runs for each frame (one standard timer tick) into slices, so
+// it can take input between them rather than only once per frame. A key that arrives mid-frame
+// then reaches the program within a slice instead of waiting for the next frame. The timer
+// interrupts are placed by opcode position within the frame, not by slice, so the number of timer
+// interrupts (and BIOS ticks) per frame is the same however the frame is sliced.
+
+use crate::dos_event_handler::{DosEventHandler, DosInterruptResult};
+
+use xachtsechs::machine8086::Machine8086;
+use xachtsechs::types::StepResult;
+
+use serde::{Deserialize, Serialize};
+
+/// The number of opcodes run for each standard timer tick.
+pub const OPCODES_PER_STANDARD_TICK: usize = 4000;
+/// Measured with examples/input_latency.rs, a program sees a key 1.6ms after it's pressed on
+/// average with 16 slices, rather than 27.5ms (half a frame) without slicing. More slices barely
+/// help, and cost an input poll each.
+pub const DEFAULT_SLICES_PER_FRAME: usize = 16;
+/// The fraction of the text page that has to change for the screen to be presented between
+/// slices. Measured with examples/input_latency.rs, presenting on any change is what brings the
+/// time from a key being pressed to the echoed key being on the screen down from half a frame to
+/// 1.6ms, so any change is presented by default. MAX_MID_FRAME_PRESENTS keeps programs that draw
+/// all the time from being presented after every slice.
+pub const DEFAULT_MID_FRAME_PRESENT_FRACTION: f64 = 0.;
+/// Presenting takes a while with the software renderer, so at most this many presents happen
+/// between slices, on top of the one at the end of the frame.
+pub const MAX_MID_FRAME_PRESENTS: usize = 1;
+
+// In a config file this is a table with the variant's name as kind, and the fraction if it has one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "fraction")]
+pub enum MidFramePresent {
+	/// Only present the screen at the end of each frame.
+	Never,
+	/// Present the screen between slices when at least this fraction of the text page's cells
+	/// changed since it was last presented.
+	WhenChanged(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameSlicing {
+	pub slices_per_frame: usize,
+	pub mid_frame_present: MidFramePresent,
+}
+
+impl Default for FrameSlicing {
+	fn default() -> FrameSlicing {
+		FrameSlicing {
+			slices_per_frame: DEFAULT_SLICES_PER_FRAME,
+			mid_frame_present: MidFramePresent::WhenChanged(DEFAULT_MID_FRAME_PRESENT_FRACTION),
+		}
+	}
+}
+
+impl FrameSlicing {
+	/// Whether the runner should present the screen after a slice, given how many of the text
+	/// page's cells changed since the last present, and how many times the screen has already been
+	/// presented between slices this frame.
+	pub fn should_present_mid_frame(&self, changed_cells: usize, cell_count: usize, mid_frame_presents: usize) -> bool {
+		match self.mid_frame_present {
+			MidFramePresent::Never => false,
+			MidFramePresent::WhenChanged(min_fraction) => {
+				mid_frame_presents < MAX_MID_FRAME_PRESENTS && changed_cells > 0 && changed_cells as f64 >= min_fraction * cell_count as f64
+			}
+		}
+	}
+}
+
+/// Counts the text cells (a character followed by its attribute) that are different between two
+/// copies of a text page.
+///
+/// Runners find out what changed on the screen by keeping a copy of the text page from the last
+/// present and comparing the whole page against it, rather than by watching for writes. The CPU
+/// writes straight into the machine's memory, so a program can change the screen through any
+/// segment that reaches it (B800:0000 and B000:8000 are the same bytes), with string instructions
+/// like rep movsw, or through BIOS calls, and the comparison sees all of them the same way. It
+/// costs a 4000 byte comparison for each check, which is a few microseconds, so checking after
+/// every slice is around 1000 comparisons a second with the default slicing.
+pub fn count_changed_cells(before: &[u8], after: &[u8]) -> usize {
+	before.chunks(2).zip(after.chunks(2)).filter(|(before_cell, after_cell)| before_cell != after_cell).count()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SliceEnd {
+	/// A slice finished, and there are more to run in this frame.
+	Slice,
+	/// The last slice of the frame finished.
+	Frame,
+	/// The program exited with the given code.
+	Exited(u8),
+}
+
+/// Runs a frame one slice at a time. Each of the frame's timer interrupts is raised at the start
+/// of an equal share of its opcodes, like when the frame isn't sliced. A frame can have no timer
+/// interrupts, when the timer is slower than the frame rate, and then all its opcodes still run.
+#[derive(Debug, Clone)]
+pub struct FrameRun {
+	timer_interrupt_count: usize,
+	opcodes_per_timer_interrupt: usize,
+	opcode_count: usize,
+	slice_count: usize,
+	slice_index: usize,
+	opcode_index: usize,
+}
+
+impl FrameRun {
+	pub fn new(opcode_count: usize, timer_interrupt_count: usize, slices_per_frame: usize) -> FrameRun {
+		let opcodes_per_timer_interrupt = opcode_count / timer_interrupt_count.max(1);
+		FrameRun {
+			timer_interrupt_count,
+			opcodes_per_timer_interrupt,
+			// Any opcodes left over from splitting them between the interrupts aren't run.
+			opcode_count: if timer_interrupt_count == 0 { opcode_count } else { opcodes_per_timer_interrupt * timer_interrupt_count },
+			slice_count: slices_per_frame.max(1),
+			slice_index: 0,
+			opcode_index: 0,
+		}
+	}
+
+	fn is_timer_interrupt(&self, opcode_index: usize) -> bool {
+		opcode_index < self.opcode_count && self.next_timer_interrupt(opcode_index) == opcode_index
+	}
+
+	// The position of the first timer interrupt at or after the given opcode, or the end of the
+	// frame if there are no more.
+	fn next_timer_interrupt(&self, opcode_index: usize) -> usize {
+		if self.timer_interrupt_count == 0 {
+			return self.opcode_count;
+		}
+		let interval = self.opcodes_per_timer_interrupt.max(1);
+		let next_interrupt = match opcode_index % interval {
+			0 => opcode_index,
+			past_interrupt => opcode_index - past_interrupt + interval,
+		};
+		next_interrupt.min(self.opcode_count)
+	}
+
+	/// Runs the next slice of the frame. When the program blocks for a keypress or waits for events,
+	/// the rest of the slice is skipped so the runner can take input sooner, but never past a timer
+	/// interrupt, so the interrupts still happen at their programmed rate while it waits.
+	pub fn run_slice(&mut self, machine: &mut Machine8086, handler: &mut DosEventHandler) -> Result<SliceEnd, String> {
+		let slice_end = self.opcode_count * (self.slice_index + 1) / self.slice_count;
+		while self.opcode_index < slice_end {
+			if self.is_timer_interrupt(self.opcode_index) {
+				machine.interrupt_on_next_step(0x08);
+			}
+			let step_result = machine.step(handler)?;
+			self.opcode_index += 1;
+			if let StepResult::Interrupt = step_result {
+				match handler.result {
+					DosInterruptResult::ShouldReturn => {
+						machine.return_from_interrupt();
+					}
+					DosInterruptResult::ShouldReturnAndWaitForEvents => {
+						machine.return_from_interrupt();
+						self.opcode_index = self.next_timer_interrupt(self.opcode_index).min(slice_end);
+					}
+					DosInterruptResult::ShouldBlockForKeypress => {
+						self.opcode_index = self.next_timer_interrupt(self.opcode_index).min(slice_end);
+					}
+					DosInterruptResult::ShouldExit(exit_code) => {
+						return Ok(SliceEnd::Exited(exit_code));
+					}
+				}
+			}
+		}
+		self.slice_index += 1;
+		if self.slice_index < self.slice_count {
+			Ok(SliceEnd::Slice)
+		} else {
+			if let Some(ref mut video_stats) = handler.video_stats {
+				video_stats.end_frame(machine);
+			}
+			handler.check_low_memory(machine);
+			Ok(SliceEnd::Frame)
+		}
+	}
+
+	/// The number of slices that have finished so far.
+	pub fn finished_slices(&self) -> usize {
+		self.slice_index
+	}
+
+	pub fn slice_count(&self) -> usize {
+		self.slice_count
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::bios_loader::BIOS_SYSTEM_TIMER_COUNTER_LOW;
+	use crate::dos_event_handler::tests::make_test_handler;
+
+	use xachtsechs::types::{EventHandler, Reg};
+
+	// Runs frames of a program that loops calling the interrupt function in AH, and returns the
+	// BIOS tick count.
+	fn run_frames(ah: u8, slices_per_frame: usize, timer_interrupts_per_frame: usize, frame_count: usize) -> u16 {
+		let (mut handler, mut machine) = make_test_handler();
+		// loop: mov ah, <ah>; int <interrupt>; jmp loop
+		let interrupt_index = match ah {
+			0x2c => 0x21,
+			0x28 => 0x28,
+			_ => 0x16,
+		};
+		machine.insert_contiguous_bytes(&[0xb4, ah, 0xcd, interrupt_index, 0xeb, 0xfa], 0x10000);
+		machine.set_reg_u16(Reg::CS, 0x1000);
+		machine.set_reg_u16(Reg::IP, 0);
+		for _ in 0 .. frame_count {
+			let mut frame_run = FrameRun::new(OPCODES_PER_STANDARD_TICK, timer_interrupts_per_frame, slices_per_frame);
+			while frame_run.run_slice(&mut machine, &mut handler).unwrap() == SliceEnd::Slice {}
+			assert_eq!(frame_run.finished_slices(), frame_run.slice_count());
+		}
+		machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW)
+	}
+
+	#[test] fn test_slicing_keeps_tick_rate() {
+		// Getting the time and DOS idle (given as AH=28h) wait for events, and reading a key with
+		// none queued blocks.
+		for &ah in [0x2c, 0x28, 0x00].iter() {
+			for &timer_interrupts_per_frame in [1, 2, 3, 7].iter() {
+				for &slices_per_frame in [1, 2, 5, 16, 64, OPCODES_PER_STANDARD_TICK * 2].iter() {
+					let ticks = run_frames(ah, slices_per_frame, timer_interrupts_per_frame, 10);
+					assert_eq!(ticks as usize, 10 * timer_interrupts_per_frame, "AH={:x}, {} slices", ah, slices_per_frame);
+				}
+			}
+		}
+	}
+
+	#[test] fn test_frame_without_timer_interrupt() {
+		let (mut handler, mut machine) = make_test_handler();
+		// loop: mov ah, 30h; int 21h; jmp loop
+		machine.insert_contiguous_bytes(&[0xb4, 0x30, 0xcd, 0x21, 0xeb, 0xfa], 0x10000);
+		machine.set_reg_u16(Reg::CS, 0x1000);
+		machine.set_reg_u16(Reg::IP, 0);
+		let mut frame_run = FrameRun::new(500, 0, 4);
+		while frame_run.run_slice(&mut machine, &mut handler).unwrap() == SliceEnd::Slice {}
+		assert_eq!(frame_run.finished_slices(), 4);
+		assert_eq!(frame_run.opcode_index, 500);
+		assert_eq!(machine.get_data_u16(&BIOS_SYSTEM_TIMER_COUNTER_LOW), 0);
+	}
+	
+	#[test] fn test_mid_frame_present_policy() {
+		let slicing = FrameSlicing { slices_per_frame: 16, mid_frame_present: MidFramePresent::WhenChanged(0.25) };
+		assert!(!slicing.should_present_mid_frame(0, 2000, 0));
+		assert!(!slicing.should_present_mid_frame(499, 2000, 0));
+		assert!(slicing.should_present_mid_frame(500, 2000, 0));
+		assert!(!slicing.should_present_mid_frame(2000, 2000, MAX_MID_FRAME_PRESENTS));
+		let slicing = FrameSlicing { slices_per_frame: 16, mid_frame_present: MidFramePresent::Never };
+		assert!(!slicing.should_present_mid_frame(2000, 2000, 0));
+
+		assert_eq!(count_changed_cells(b"a\x07b\x07c\x07", b"a\x07b\x1fd\x07"), 2);
+	}
+
+	#[test] fn test_changed_cells_see_every_write() {
+		let (mut handler, mut machine) = make_test_handler();
+		let text_page = |machine: &Machine8086| machine.memory[0xb8000 .. 0xb8000 + 80 * 25 * 2].to_vec();
+		let presented_text = text_page(&machine);
+
+		// Through a different segment that reaches the same memory.
+		let (segment, offset) = (0xb000, 0x8002);
+		machine.insert_contiguous_bytes(b"A\x1f", (segment << 4) + offset);
+		assert_eq!(count_changed_cells(&presented_text, &text_page(&machine)), 1);
+
+		// A block copy from elsewhere in memory, like rep movsw does.
+		machine.insert_contiguous_bytes(b"B\x1fC\x1fD\x1f", 0x20000);
+		machine.memory.copy_within(0x20000 .. 0x20006, 0xb8000 + 160);
+		assert_eq!(count_changed_cells(&presented_text, &text_page(&machine)), 4);
+
+		// Through the BIOS.
+		machine.set_reg_u16(Reg::AX, 0x0e45);
+		machine.set_reg_u16(Reg::BX, 0);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(count_changed_cells(&presented_text, &text_page(&machine)), 5);
+	}
+}
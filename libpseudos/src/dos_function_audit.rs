@@ -0,0 +1,109 @@
+// Records which BIOS and DOS functions a program calls, so missing features can be ranked by how
+// much programs need them. The support tables this compares against live next to the dispatch code
+// in dos_event_handler.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FunctionSupport {
+	Unimplemented,
+	Partial,
+	Full,
+}
+
+impl FunctionSupport {
+	pub fn name(&self) -> &'static str {
+		match self {
+			FunctionSupport::Unimplemented => "unimplemented",
+			FunctionSupport::Partial => "partial",
+			FunctionSupport::Full => "full",
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionAuditEntry {
+	pub interrupt: u8,
+	// AH for most interrupts, or AX for int 33h.
+	pub function: u16,
+	pub support: FunctionSupport,
+	pub count: usize,
+	// The CS:IP seen by the interrupt handler the first time the function was called.
+	pub first_cs: u16,
+	pub first_ip: u16,
+}
+
+impl FunctionAuditEntry {
+	fn function_name(&self) -> String {
+		let register = if self.interrupt == 0x33 { "AX" } else { "AH" };
+		format!("int {:02x}h {}={:02x}h", self.interrupt, register, self.function)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FunctionAudit {
+	entries: BTreeMap<(u8, u16), FunctionAuditEntry>,
+}
+
+impl FunctionAudit {
+	pub fn new() -> FunctionAudit {
+		FunctionAudit::default()
+	}
+
+	pub fn record(&mut self, interrupt: u8, function: u16, support: FunctionSupport, cs: u16, ip: u16) {
+		let entry = self.entries.entry((interrupt, function)).or_insert(FunctionAuditEntry {
+			interrupt,
+			function,
+			support,
+			count: 0,
+			first_cs: cs,
+			first_ip: ip,
+		});
+		entry.count += 1;
+	}
+
+	/// The functions called so far, with the least supported and most called first.
+	pub fn report(&self) -> Vec<FunctionAuditEntry> {
+		let mut report: Vec<FunctionAuditEntry> = self.entries.values().cloned().collect();
+		report.sort_by(|a, b| a.support.cmp(&b.support).then(b.count.cmp(&a.count)));
+		report
+	}
+
+	/// A human readable report, one function per line.
+	pub fn summary(&self) -> String {
+		let mut summary = String::new();
+		for entry in self.report() {
+			summary += &format!("{} ({}, {} calls, first at {:04x}:{:04x})\n", entry.function_name(), entry.support.name(), entry.count, entry.first_cs, entry.first_ip);
+		}
+		summary
+	}
+
+	/// The report as JSON, for combining the reports of many programs.
+	pub fn to_json(&self) -> String {
+		let entries: Vec<String> = self.report().iter().map(|entry| {
+			format!("{{\"interrupt\":{},\"function\":{},\"name\":\"{}\",\"support\":\"{}\",\"count\":{},\"first_cs\":{},\"first_ip\":{}}}",
+				entry.interrupt, entry.function, entry.function_name(), entry.support.name(), entry.count, entry.first_cs, entry.first_ip)
+		}).collect();
+		format!("{{\"functions\":[{}]}}", entries.join(","))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_report_order() {
+		let mut function_audit = FunctionAudit::new();
+		function_audit.record(0x21, 0x3d, FunctionSupport::Full, 0x1234, 0x10);
+		function_audit.record(0x21, 0x48, FunctionSupport::Partial, 0x1234, 0x20);
+		for ip in 0 .. 3 {
+			function_audit.record(0x10, 0x13, FunctionSupport::Unimplemented, 0x1234, 0x30 + ip);
+		}
+		function_audit.record(0x33, 0x0003, FunctionSupport::Unimplemented, 0x1234, 0x40);
+		let report = function_audit.report();
+		assert_eq!(report.iter().map(|entry| (entry.interrupt, entry.function)).collect::<Vec<_>>(), vec![(0x10, 0x13), (0x33, 0x03), (0x21, 0x48), (0x21, 0x3d)]);
+		assert_eq!((report[0].count, report[0].first_ip), (3, 0x30));
+		assert!(function_audit.summary().starts_with("int 10h AH=13h (unimplemented, 3 calls, first at 1234:0030)\n"));
+		assert!(function_audit.to_json().starts_with("{\"functions\":[{\"interrupt\":16,\"function\":19,\"name\":\"int 10h AH=13h\",\"support\":\"unimplemented\",\"count\":3,"));
+	}
+}
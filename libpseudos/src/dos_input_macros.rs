@@ -0,0 +1,348 @@
+// Input macros, for typing key sequences into a program with a host hotkey, and turbo keys that
+// repeat while their hotkey is held. Each line of a macro file defines a macro:
+//
+//   # Comments start with a hash.
+//   open_door = Down*3, wait 10, Space
+//   fire = turbo Space every 2
+//   option no-cancel
+//
+// A sequence macro types its steps in order once it's started, one key per emulated frame, so
+// programs that check for a key once a frame see each one. "KEY*N" types a key N times, and
+// "wait N" leaves N frames without a key. A turbo macro types its key every N frames for as long as
+// it's held. Keys are either a single character, typed as it is (so "A" is a capital A), or one of
+// the names in KEY_NAMES, which can be in any case. Pressing a real key stops a running sequence,
+// unless the file has an "option no-cancel" line.
+//
+// The keys go into the handler's key press queue, the same as real keys do.
+
+use crate::dos_event_handler::KeyPressInfo;
+
+use std::collections::VecDeque;
+
+/// The keys that are given by name rather than by their character, and their scan code and ASCII
+/// character.
+pub const KEY_NAMES: &[(&str, u8, u8)] = &[
+	("Esc", 0x01, 0x1b),
+	("Backspace", 0x0e, 0x08),
+	("Tab", 0x0f, 0x09),
+	("Enter", 0x1c, 0x0d),
+	("Space", 0x39, b' '),
+	("Comma", 0x33, b','),
+	("F1", 0x3b, 0), ("F2", 0x3c, 0), ("F3", 0x3d, 0), ("F4", 0x3e, 0), ("F5", 0x3f, 0),
+	("F6", 0x40, 0), ("F7", 0x41, 0), ("F8", 0x42, 0), ("F9", 0x43, 0), ("F10", 0x44, 0),
+	("Home", 0x47, 0),
+	("Up", 0x48, 0),
+	("PgUp", 0x49, 0),
+	("Left", 0x4b, 0),
+	("Right", 0x4d, 0),
+	("End", 0x4f, 0),
+	("Down", 0x50, 0),
+	("PgDn", 0x51, 0),
+	("Ins", 0x52, 0),
+	("Del", 0x53, 0),
+];
+
+/// Gets the key for a name from KEY_NAMES, or a single character.
+pub fn parse_key_name(name: &str) -> Option<KeyPressInfo> {
+	if let Some(&(_, scan_code, ascii_char)) = KEY_NAMES.iter().find(|(key_name, _, _)| key_name.eq_ignore_ascii_case(name)) {
+		return Some(KeyPressInfo { scan_code, ascii_char });
+	}
+	match name.as_bytes() {
+		&[c] => KeyPressInfo::from_character(c),
+		_ => None,
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacroStep {
+	/// Types a key, taking up a frame.
+	Key(KeyPressInfo),
+	/// Leaves a number of frames without a key.
+	Wait(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroAction {
+	/// Steps that run once, in order, when the macro is started.
+	Sequence(Vec<MacroStep>),
+	/// A key typed every interval_frames frames while the macro is held, starting with the frame
+	/// after it's pressed.
+	Turbo { key: KeyPressInfo, interval_frames: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputMacro {
+	pub name: String,
+	pub action: MacroAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RunningSequence {
+	macro_index: usize,
+	step_index: usize,
+	wait_frames_left: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct HeldTurbo {
+	macro_index: usize,
+	frames_until_key: u32,
+}
+
+/// Runs input macros. The runner starts and stops macros with hotkeys, and calls advance_frame once
+/// every emulated frame to type their keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroEngine {
+	pub macros: Vec<InputMacro>,
+	/// Whether a real key press stops the running sequence.
+	pub cancel_on_real_key: bool,
+	running_sequence: Option<RunningSequence>,
+	held_turbos: Vec<HeldTurbo>,
+}
+
+impl Default for MacroEngine {
+	fn default() -> MacroEngine {
+		MacroEngine::new(vec![])
+	}
+}
+
+fn parse_count(text: &str) -> Result<u32, String> {
+	text.trim().parse().map_err(|_| format!("\"{}\" isn't a number", text.trim()))
+}
+
+fn parse_key(text: &str) -> Result<KeyPressInfo, String> {
+	parse_key_name(text).ok_or_else(|| format!("Unknown key \"{}\"", text))
+}
+
+fn parse_steps(text: &str) -> Result<Vec<MacroStep>, String> {
+	let mut steps = vec![];
+	for step_text in text.split(',').map(str::trim) {
+		if let Some(wait_text) = step_text.strip_prefix("wait ") {
+			steps.push(MacroStep::Wait(parse_count(wait_text)?));
+			continue;
+		}
+		// A lone "*" is the key, not a repeat.
+		let (key_text, repeat_count) = match step_text.rfind('*') {
+			Some(star_pos) if star_pos > 0 => (step_text[..star_pos].trim(), parse_count(&step_text[star_pos + 1..])?),
+			_ => (step_text, 1),
+		};
+		let key = parse_key(key_text)?;
+		steps.extend((0 .. repeat_count).map(|_| MacroStep::Key(key)));
+	}
+	Ok(steps)
+}
+
+fn parse_action(text: &str) -> Result<MacroAction, String> {
+	if let Some(turbo_text) = text.strip_prefix("turbo ") {
+		let every_pos = turbo_text.find(" every ").ok_or("Expected \"turbo KEY every N\"")?;
+		let key = parse_key(turbo_text[..every_pos].trim())?;
+		let interval_frames = parse_count(&turbo_text[every_pos + " every ".len()..])?;
+		if interval_frames == 0 {
+			return Err("A turbo key can't be typed more than once a frame".to_string());
+		}
+		Ok(MacroAction::Turbo { key, interval_frames })
+	} else {
+		Ok(MacroAction::Sequence(parse_steps(text)?))
+	}
+}
+
+impl MacroEngine {
+	pub fn new(macros: Vec<InputMacro>) -> MacroEngine {
+		MacroEngine {
+			macros,
+			cancel_on_real_key: true,
+			running_sequence: None,
+			held_turbos: vec![],
+		}
+	}
+
+	/// Parses the text of a macro file. The name is used to say where errors are.
+	pub fn parse(text: &str, name: &str) -> Result<MacroEngine, String> {
+		let mut engine = MacroEngine::default();
+		for (line_index, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let location = format!("{}:{}", name, line_index + 1);
+			if line == "option no-cancel" {
+				engine.cancel_on_real_key = false;
+				continue;
+			}
+			let equals_pos = line.find('=').ok_or_else(|| format!("{}: Expected \"NAME = KEYS\"", location))?;
+			let macro_name = line[..equals_pos].trim().to_string();
+			if engine.find(&macro_name).is_some() {
+				return Err(format!("{}: There's already a macro called \"{}\"", location, macro_name));
+			}
+			let action = parse_action(line[equals_pos + 1..].trim()).map_err(|err| format!("{}: {}", location, err))?;
+			engine.macros.push(InputMacro { name: macro_name, action });
+		}
+		Ok(engine)
+	}
+
+	/// Gets the index of a macro by its name.
+	pub fn find(&self, name: &str) -> Option<usize> {
+		self.macros.iter().position(|input_macro| input_macro.name == name)
+	}
+
+	/// Starts a sequence from its first step, unless it's already running, in which case it carries
+	/// on. Only one sequence runs at a time, so another one that's running is stopped. Turbo keys
+	/// start repeating until stop is called.
+	pub fn start(&mut self, macro_index: usize) {
+		let already_running = self.active_macro_indices().any(|active_index| active_index == macro_index);
+		match self.macros.get(macro_index).map(|input_macro| &input_macro.action) {
+			Some(MacroAction::Sequence(_)) if !already_running => {
+				self.running_sequence = Some(RunningSequence { macro_index, step_index: 0, wait_frames_left: 0 });
+			}
+			Some(MacroAction::Turbo { .. }) if !already_running => {
+				self.held_turbos.push(HeldTurbo { macro_index, frames_until_key: 0 });
+			}
+			_ => {}
+		}
+	}
+
+	/// Stops a turbo key when its hotkey is released. Sequences carry on until they finish.
+	pub fn stop(&mut self, macro_index: usize) {
+		self.held_turbos.retain(|held| held.macro_index != macro_index);
+	}
+
+	/// Tells the engine the user pressed a real key, which stops the running sequence if
+	/// cancel_on_real_key is set. Returns whether a sequence was stopped.
+	pub fn real_key_pressed(&mut self) -> bool {
+		self.cancel_on_real_key && self.running_sequence.take().is_some()
+	}
+
+	/// The names of the running sequence and held turbo keys, for showing which are active.
+	pub fn active_macro_names(&self) -> Vec<&str> {
+		self.active_macro_indices().map(|macro_index| self.macros[macro_index].name.as_str()).collect()
+	}
+
+	fn active_macro_indices<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+		self.running_sequence.iter().map(|running| running.macro_index)
+			.chain(self.held_turbos.iter().map(|held| held.macro_index))
+	}
+
+	/// Types the keys for one emulated frame into the key press queue.
+	pub fn advance_frame(&mut self, key_press_queue: &mut VecDeque<KeyPressInfo>) {
+		if let Some(mut running) = self.running_sequence.take() {
+			let steps = match self.macros[running.macro_index].action {
+				MacroAction::Sequence(ref steps) => steps,
+				MacroAction::Turbo { .. } => unreachable!(),
+			};
+			if running.wait_frames_left > 0 {
+				running.wait_frames_left -= 1;
+			} else {
+				// Waits of 0 frames are skipped over to the step after them.
+				while let Some(&step) = steps.get(running.step_index) {
+					running.step_index += 1;
+					match step {
+						MacroStep::Key(key) => {
+							key_press_queue.push_back(key);
+							break;
+						}
+						MacroStep::Wait(0) => {}
+						MacroStep::Wait(frame_count) => {
+							running.wait_frames_left = frame_count - 1;
+							break;
+						}
+					}
+				}
+			}
+			if running.step_index < steps.len() || running.wait_frames_left > 0 {
+				self.running_sequence = Some(running);
+			}
+		}
+		for held in &mut self.held_turbos {
+			if held.frames_until_key == 0 {
+				if let MacroAction::Turbo { key, interval_frames } = self.macros[held.macro_index].action {
+					key_press_queue.push_back(key);
+					held.frames_until_key = interval_frames;
+				}
+			}
+			held.frames_until_key -= 1;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DOWN: KeyPressInfo = KeyPressInfo { scan_code: 0x50, ascii_char: 0 };
+	const SPACE: KeyPressInfo = KeyPressInfo { scan_code: 0x39, ascii_char: b' ' };
+	const CAPITAL_A: KeyPressInfo = KeyPressInfo { scan_code: 0x1e, ascii_char: b'A' };
+
+	// Runs the engine for some frames, calling the callback before each one, and returns the frame
+	// each key was typed in.
+	fn run_frames<F: FnMut(&mut MacroEngine, usize)>(engine: &mut MacroEngine, frame_count: usize, mut before_frame: F) -> Vec<(usize, KeyPressInfo)> {
+		let mut typed_keys = vec![];
+		let mut key_press_queue = VecDeque::new();
+		for frame in 0 .. frame_count {
+			before_frame(engine, frame);
+			engine.advance_frame(&mut key_press_queue);
+			typed_keys.extend(key_press_queue.drain(..).map(|key| (frame, key)));
+		}
+		typed_keys
+	}
+
+	#[test] fn test_parse_macros() {
+		let engine = MacroEngine::parse("# Doors.\nopen_door = Down*3, wait 10, Space\n\nfire = turbo A every 2\n", "test.mac").unwrap();
+		assert_eq!(engine.macros[0].action, MacroAction::Sequence(vec![
+			MacroStep::Key(DOWN), MacroStep::Key(DOWN), MacroStep::Key(DOWN), MacroStep::Wait(10), MacroStep::Key(SPACE),
+		]));
+		assert_eq!(engine.macros[1].action, MacroAction::Turbo { key: CAPITAL_A, interval_frames: 2 });
+		assert_eq!(engine.find("fire"), Some(1));
+		assert!(engine.cancel_on_real_key);
+		assert_eq!(parse_key_name("*"), Some(KeyPressInfo { scan_code: 0x09, ascii_char: b'*' }));
+		assert_eq!(parse_key_name("pgdn"), Some(KeyPressInfo { scan_code: 0x51, ascii_char: 0 }));
+
+		assert_eq!(MacroEngine::parse("a = Down, Jump\n", "bad.mac"), Err("bad.mac:1: Unknown key \"Jump\"".to_string()));
+		assert_eq!(MacroEngine::parse("\nfire = turbo Space every 0\n", "bad.mac"), Err("bad.mac:2: A turbo key can't be typed more than once a frame".to_string()));
+		assert_eq!(MacroEngine::parse("Down, Space\n", "bad.mac"), Err("bad.mac:1: Expected \"NAME = KEYS\"".to_string()));
+	}
+
+	#[test] fn test_run_macros() {
+		let mut engine = MacroEngine::parse("open_door = Down*3, wait 10, Space\nfire = turbo A every 3\n", "test.mac").unwrap();
+		let typed_keys = run_frames(&mut engine, 20, |engine, frame| {
+			if frame == 2 {
+				engine.start(0);
+			}
+		});
+		assert_eq!(typed_keys, vec![(2, DOWN), (3, DOWN), (4, DOWN), (15, SPACE)]);
+		assert!(engine.active_macro_names().is_empty());
+
+		// The turbo key repeats while held, alongside a sequence.
+		let typed_keys = run_frames(&mut engine, 12, |engine, frame| {
+			match frame {
+				1 => engine.start(1),
+				3 => engine.start(0),
+				// Starting a running sequence again, like a held hotkey repeating, does nothing.
+				4 => engine.start(0),
+				8 => engine.stop(1),
+				_ => {}
+			}
+		});
+		assert_eq!(typed_keys, vec![(1, CAPITAL_A), (3, DOWN), (4, DOWN), (4, CAPITAL_A), (5, DOWN), (7, CAPITAL_A)]);
+		assert_eq!(engine.active_macro_names(), vec!["open_door"]);
+
+		// A real key press stops the sequence, unless cancelling is turned off.
+		let mut engine = MacroEngine::parse("open_door = Down*3, wait 10, Space\n", "test.mac").unwrap();
+		let typed_keys = run_frames(&mut engine, 20, |engine, frame| {
+			match frame {
+				0 => engine.start(0),
+				2 => assert!(engine.real_key_pressed()),
+				_ => {}
+			}
+		});
+		assert_eq!(typed_keys, vec![(0, DOWN), (1, DOWN)]);
+		engine.cancel_on_real_key = false;
+		let typed_keys = run_frames(&mut engine, 20, |engine, frame| {
+			match frame {
+				0 => engine.start(0),
+				2 => assert!(!engine.real_key_pressed()),
+				_ => {}
+			}
+		});
+		assert_eq!(typed_keys, vec![(0, DOWN), (1, DOWN), (2, DOWN), (13, SPACE)]);
+	}
+}
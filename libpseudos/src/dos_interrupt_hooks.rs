@@ -0,0 +1,153 @@
+// Hooks that let an embedding application handle interrupts itself, like logging the text a program
+// writes with int 21h AH=09h, or providing an interrupt that pseudos doesn't know about. Hooks run
+// before the built-in handling, and can leave the call to it by returning NotHandled.
+
+use crate::dos_event_handler::DosEventHandler;
+use crate::machine::Machine;
+
+use xachtsechs::types::{Reg, RegHalf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterruptHookResult {
+	/// The hook dealt with the call, so the built-in handling is skipped.
+	Handled,
+	/// The call carries on to the next hook, or the built-in handling.
+	NotHandled,
+}
+
+/// A hook gets the handler and machine as they are when the interrupt happens. It can change the
+/// registers and memory like the built-in handling does, and set the handler's result to block or
+/// exit.
+pub type InterruptHookFn = Box<dyn FnMut(&mut DosEventHandler, &mut dyn Machine) -> InterruptHookResult>;
+
+struct InterruptHook {
+	interrupt_index: u8,
+	// The AH value the hook is for, or None for every function of the interrupt.
+	ah: Option<u8>,
+	callback: InterruptHookFn,
+}
+
+#[derive(Default)]
+pub struct InterruptHooks {
+	hooks: Vec<InterruptHook>,
+}
+
+impl std::fmt::Debug for InterruptHooks {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_list().entries(self.hooks.iter().map(|hook| (hook.interrupt_index, hook.ah))).finish()
+	}
+}
+
+impl InterruptHooks {
+	pub fn new() -> InterruptHooks {
+		InterruptHooks::default()
+	}
+
+	/// Adds a hook for an interrupt, and optionally only one AH value of it. A hook that was already
+	/// added for the same interrupt and AH is replaced.
+	pub fn add(&mut self, interrupt_index: u8, ah: Option<u8>, callback: InterruptHookFn) {
+		self.remove(interrupt_index, ah);
+		self.hooks.push(InterruptHook { interrupt_index, ah, callback });
+	}
+
+	/// Removes the hook for an interrupt and AH. Returns whether there was one.
+	pub fn remove(&mut self, interrupt_index: u8, ah: Option<u8>) -> bool {
+		let hook_count = self.hooks.len();
+		self.hooks.retain(|hook| hook.interrupt_index != interrupt_index || hook.ah != ah);
+		self.hooks.len() != hook_count
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.hooks.is_empty()
+	}
+
+	fn position(&self, interrupt_index: u8, ah: Option<u8>) -> Option<usize> {
+		self.hooks.iter().position(|hook| hook.interrupt_index == interrupt_index && hook.ah == ah)
+	}
+}
+
+/// Runs the hooks for an interrupt: the one for the current AH first, then the one for every AH,
+/// until one of them handles it. Returns whether one did.
+pub(crate) fn run_interrupt_hooks(handler: &mut DosEventHandler, machine: &mut dyn Machine, interrupt_index: u8) -> bool {
+	// The hooks are taken out of the handler while they run, since they're given the handler.
+	let mut hooks = std::mem::replace(&mut handler.interrupt_hooks, InterruptHooks::new());
+	let ah = machine.get_reg_u8(Reg::AX, RegHalf::High);
+	let mut handled = false;
+	for &hook_ah in [Some(ah), None].iter() {
+		if let Some(hook_index) = hooks.position(interrupt_index, hook_ah) {
+			if (hooks.hooks[hook_index].callback)(handler, machine) == InterruptHookResult::Handled {
+				handled = true;
+				break;
+			}
+		}
+	}
+	// Keep any hooks the callbacks added.
+	let added_hooks = std::mem::replace(&mut handler.interrupt_hooks, hooks);
+	for hook in added_hooks.hooks {
+		handler.interrupt_hooks.add(hook.interrupt_index, hook.ah, hook.callback);
+	}
+	handled
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::tests::make_test_handler;
+
+	use xachtsechs::types::EventHandler;
+
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	#[test] fn test_interrupt_hooks() {
+		let (mut handler, mut machine) = make_test_handler();
+		let log = Rc::new(RefCell::new(vec![]));
+		let hook_log = log.clone();
+		// Log the strings written with int 21h AH=09h instead of showing them.
+		handler.hook_interrupt(0x21, Some(0x09), Box::new(move |_handler, machine| {
+			let addr = machine.get_seg_reg(Reg::DS, Reg::DX);
+			let text: Vec<u8> = machine.memory()[addr as usize..].iter().take_while(|c| **c != b'$').cloned().collect();
+			hook_log.borrow_mut().push(text);
+			InterruptHookResult::Handled
+		}));
+		// A made up interrupt that returns a value in AX.
+		handler.hook_interrupt(0x60, None, Box::new(|_handler, machine| {
+			machine.set_reg_u16(Reg::AX, 0x1234);
+			InterruptHookResult::Handled
+		}));
+		// A hook that only watches int 21h, leaving the calls to the built-in handling.
+		let calls = Rc::new(RefCell::new(0));
+		let hook_calls = calls.clone();
+		handler.hook_interrupt(0x21, None, Box::new(move |_handler, _machine| {
+			*hook_calls.borrow_mut() += 1;
+			InterruptHookResult::NotHandled
+		}));
+
+		machine.insert_contiguous_bytes(b"Logged$", 0x20000);
+		machine.set_reg_u16(Reg::DS, 0x2000);
+		machine.set_reg_u16(Reg::DX, 0);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x09);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(*log.borrow(), vec![b"Logged".to_vec()]);
+		assert_eq!(handler.get_cursor_position(&machine), (0, 0));
+		assert_eq!(*calls.borrow(), 0);
+
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x02);
+		machine.set_reg_u8(Reg::DX, RegHalf::Low, b'A');
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(&handler.get_screen_text(&machine)[0][..1], b"A");
+		assert_eq!(*calls.borrow(), 1);
+
+		handler.handle_interrupt(&mut machine, 0x60);
+		assert_eq!(machine.get_reg_u16(Reg::AX), 0x1234);
+
+		assert!(handler.unhook_interrupt(0x21, Some(0x09)));
+		assert!(!handler.unhook_interrupt(0x21, Some(0x09)));
+		machine.set_reg_u16(Reg::DX, 0);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, 0x09);
+		handler.handle_interrupt(&mut machine, 0x21);
+		assert_eq!(log.borrow().len(), 1);
+		assert_eq!(&handler.get_screen_text(&machine)[0][..7], b"ALogged");
+		assert_eq!(*calls.borrow(), 2);
+	}
+}
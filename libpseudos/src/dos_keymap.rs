@@ -0,0 +1,137 @@
+// Keymaps, for changing which key the program sees when a host key is pressed. This is for non-US
+// keyboards, where the host keys don't line up with the US layout the built-in table assumes, and
+// for remapping keys for a particular program. Each line of a keymap file maps a host key, with the
+// modifiers that have to be held, to the scan code and ASCII character the program gets:
+//
+//   # Comments start with a hash.
+//   z = y
+//   shift+z = Y
+//   ctrl+Space = 39 00
+//   Right Alt = Enter
+//
+// Host keys are named the way SDL names them ("Z", "Space", "Left Ctrl"), in any case. The key the
+// program gets is either a key name or character that dos_input_macros::parse_key_name knows, or
+// the scan code and ASCII character as two hex bytes. A mapping only applies when exactly its
+// modifiers are held, and keys without a mapping go through the built-in table.
+
+use crate::dos_event_handler::KeyPressInfo;
+use crate::dos_input_macros::parse_key_name;
+
+/// The modifiers that have to be held for a keymap entry to apply. Left and right are the same.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeymapModifiers {
+	pub shift: bool,
+	pub ctrl: bool,
+	pub alt: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeymapEntry {
+	/// The host key's name, in lower case.
+	pub host_key: String,
+	pub modifiers: KeymapModifiers,
+	pub key: KeyPressInfo,
+}
+
+/// The mappings from a keymap file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Keymap {
+	pub entries: Vec<KeymapEntry>,
+}
+
+fn parse_hex_byte(text: &str) -> Option<u8> {
+	if text.len() == 2 {
+		u8::from_str_radix(text, 16).ok()
+	} else {
+		None
+	}
+}
+
+fn parse_dos_key(text: &str) -> Result<KeyPressInfo, String> {
+	let hex_bytes: Vec<&str> = text.split_whitespace().collect();
+	if let [scan_code, ascii_char] = hex_bytes[..] {
+		if let (Some(scan_code), Some(ascii_char)) = (parse_hex_byte(scan_code), parse_hex_byte(ascii_char)) {
+			return Ok(KeyPressInfo { scan_code, ascii_char });
+		}
+	}
+	parse_key_name(text).ok_or_else(|| format!("Unknown key \"{}\"", text))
+}
+
+// Splits the modifiers off the front of a host key, such as "ctrl+shift+A". A lone "+" is the key.
+fn parse_host_key(text: &str) -> Result<(String, KeymapModifiers), String> {
+	let mut modifiers = KeymapModifiers::default();
+	let mut rest = text;
+	while let Some(plus_pos) = rest.find('+').filter(|&plus_pos| plus_pos > 0) {
+		let modifier = match rest[..plus_pos].trim().to_ascii_lowercase().as_str() {
+			"shift" => &mut modifiers.shift,
+			"ctrl" => &mut modifiers.ctrl,
+			"alt" => &mut modifiers.alt,
+			other => return Err(format!("Unknown modifier \"{}\"", other)),
+		};
+		*modifier = true;
+		rest = &rest[plus_pos + 1..];
+	}
+	let host_key = rest.trim();
+	if host_key.is_empty() {
+		return Err("Expected a host key".to_string());
+	}
+	Ok((host_key.to_ascii_lowercase(), modifiers))
+}
+
+impl Keymap {
+	/// Parses the text of a keymap file. The name is used to say where errors are.
+	pub fn parse(text: &str, name: &str) -> Result<Keymap, String> {
+		let mut keymap = Keymap::default();
+		for (line_index, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let location = format!("{}:{}", name, line_index + 1);
+			// The host key can be "=" itself, so the last equals sign is the separator.
+			let equals_pos = line.rfind('=').filter(|&equals_pos| equals_pos > 0).ok_or_else(|| format!("{}: Expected \"HOST KEY = DOS KEY\"", location))?;
+			let (host_key, modifiers) = parse_host_key(&line[..equals_pos]).map_err(|err| format!("{}: {}", location, err))?;
+			let key = parse_dos_key(line[equals_pos + 1..].trim()).map_err(|err| format!("{}: {}", location, err))?;
+			if keymap.entries.iter().any(|entry| entry.host_key == host_key && entry.modifiers == modifiers) {
+				return Err(format!("{}: \"{}\" is already mapped", location, line[..equals_pos].trim()));
+			}
+			keymap.entries.push(KeymapEntry { host_key, modifiers, key });
+		}
+		Ok(keymap)
+	}
+
+	/// Gets the key the program should get for a host key, if the keymap has one for it with the
+	/// modifiers that are held.
+	pub fn lookup(&self, host_key: &str, modifiers: KeymapModifiers) -> Option<KeyPressInfo> {
+		self.entries.iter()
+			.find(|entry| entry.modifiers == modifiers && entry.host_key.eq_ignore_ascii_case(host_key))
+			.map(|entry| entry.key)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_parse_keymap() {
+		let keymap = Keymap::parse("# German\nz = y\nshift+z = Y\n\nCtrl + Space = 39 00\nRight Alt = Enter\n= = 0d 3d\n", "de.keymap").unwrap();
+		let none = KeymapModifiers::default();
+		let shift = KeymapModifiers { shift: true, ..none };
+		assert_eq!(keymap.lookup("Z", none), Some(KeyPressInfo { scan_code: 0x15, ascii_char: b'y' }));
+		assert_eq!(keymap.lookup("z", shift), Some(KeyPressInfo { scan_code: 0x15, ascii_char: b'Y' }));
+		assert_eq!(keymap.lookup("space", KeymapModifiers { ctrl: true, ..none }), Some(KeyPressInfo { scan_code: 0x39, ascii_char: 0 }));
+		assert_eq!(keymap.lookup("Right Alt", none), Some(KeyPressInfo { scan_code: 0x1c, ascii_char: 0x0d }));
+		assert_eq!(keymap.lookup("=", none), Some(KeyPressInfo { scan_code: 0x0d, ascii_char: b'=' }));
+		// Only the exact modifiers match, and other keys are left to the built-in table.
+		assert_eq!(keymap.lookup("Space", none), None);
+		assert_eq!(keymap.lookup("z", KeymapModifiers { alt: true, ..none }), None);
+		assert_eq!(keymap.lookup("x", none), None);
+	}
+
+	#[test] fn test_parse_keymap_errors() {
+		assert_eq!(Keymap::parse("z", "a.keymap"), Err("a.keymap:1: Expected \"HOST KEY = DOS KEY\"".to_string()));
+		assert_eq!(Keymap::parse("\nmeta+z = y", "a.keymap"), Err("a.keymap:2: Unknown modifier \"meta\"".to_string()));
+		assert_eq!(Keymap::parse("z = Nope", "a.keymap"), Err("a.keymap:1: Unknown key \"Nope\"".to_string()));
+		assert_eq!(Keymap::parse("z = y\nZ = x", "a.keymap"), Err("a.keymap:2: \"Z\" is already mapped".to_string()));
+	}
+}
@@ -0,0 +1,381 @@
+// Watches the interrupt vector table, and the parts of the BIOS data area that only change with the
+// video mode, for programs that write over them by mistake, usually through a null pointer. On a
+// real PC the damage only shows up later, when something calls a vector that was written over, and
+// programs often got away with it because nothing did. The memory is compared with a copy of it
+// once a frame, so a write is only known to have happened some time during the frame it's
+// reported in.
+//
+// Programs hook interrupts by writing vectors directly as well as through int 21h AH=25h, so not
+// every change to the table is a mistake. The vectors that changed during a frame are split into
+// runs of neighbouring vectors, and a run is taken as a scribble when:
+//
+//   - it starts at vector 0 and has more than one vector in it, which is what writing more than 4
+//     bytes through a null pointer does, or
+//   - it has SCRIBBLE_RUN_VECTORS or more vectors in it, wherever it starts, and at least one of
+//     them now holds something that can't be a handler: a far pointer into the interrupt table or
+//     BIOS data area, such as 0000:0000, or past the end of memory, such as FFFF:FFFF.
+//
+// Anything else, like one vector, a few that aren't next to each other, or a long run that all
+// point somewhere a handler could be, is taken as the program hooking them. So is a vector that now points at one of the emulator's stubs, which is a program
+// putting back a vector it hooked. A write of 4 bytes or less through a null pointer looks just like
+// hooking int 00h, so it isn't caught.
+//
+// With LowMemoryProtection::Restore, the vectors in a scribble that were still pointing at the
+// emulator's stubs are put back, so the timer and keyboard keep working. Vectors the program had
+// hooked are left as they were written, since what they should be is up to the program.
+//
+// Separately, VectorWatch traces every change to the interrupt vectors, however it was made, for
+// finding out which interrupts a program handles itself. It compares the table once a frame in the
+// same way, so a vector that changes more than once in a frame is only reported with where it
+// ended up.
+
+use crate::bios_loader::{is_bios_stub_far_pointer, BIOS_START};
+use crate::machine::Machine;
+
+use xachtsechs::machine8086::INTERRUPT_TABLE_ENTRY_BYTES;
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+const INTERRUPT_TABLE_BYTES: usize = 0x100 * INTERRUPT_TABLE_ENTRY_BYTES;
+// The length of a run of neighbouring vectors that's a scribble wherever it is.
+pub const SCRIBBLE_RUN_VECTORS: usize = 4;
+// The offsets in the BIOS data area that only the emulator should change, and only at startup or
+// when the video mode changes: the equipment word (at offset 10, where BIOS_EQUIPMENT is), the
+// memory size, the video mode, columns and page size, the video port, and the rows and character
+// height.
+const GUARDED_BIOS_DATA: [(u32, u32); 5] = [(10, 12), (0x13, 0x15), (0x49, 0x4e), (0x63, 0x65), (0x84, 0x87)];
+const BIOS_DATA_BYTES: usize = 0x100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LowMemoryProtection {
+	/// Nothing is watched, like a real PC.
+	Off,
+	/// Scribbles are reported.
+	Report,
+	/// Scribbles are reported, and the vectors in them that still pointed at the emulator's stubs
+	/// are put back.
+	Restore,
+}
+
+impl Default for LowMemoryProtection {
+	// Off by default, since real DOS didn't check, but debug builds report scribbles.
+	fn default() -> LowMemoryProtection {
+		if cfg!(debug_assertions) { LowMemoryProtection::Report } else { LowMemoryProtection::Off }
+	}
+}
+
+/// Something the program wrote over by mistake, or a vector it changed when they're traced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LowMemoryWrite {
+	/// An interrupt vector in a scribble, and whether it was put back.
+	Vector { interrupt_index: u8, frame: u64, restored: bool },
+	/// The first changed byte of a guarded part of the BIOS data area.
+	BiosData { offset: u16, frame: u64 },
+	/// An interrupt vector that now points at segment:offset, from VectorWatch.
+	VectorSet { interrupt_index: u8, segment: u16, offset: u16, frame: u64 },
+}
+
+impl fmt::Display for LowMemoryWrite {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			LowMemoryWrite::Vector { interrupt_index, frame, restored } => {
+				write!(f, "Program wrote to the int {:02X}h vector at approximately frame {}", interrupt_index, frame)?;
+				if restored {
+					write!(f, ", so it was put back")?;
+				}
+				Ok(())
+			}
+			LowMemoryWrite::BiosData { offset, frame } => write!(f, "Program wrote to BIOS data area offset {:02X}h at approximately frame {}", offset, frame),
+			LowMemoryWrite::VectorSet { interrupt_index, segment, offset, frame } => {
+				write!(f, "Program set the int {:02X}h vector to {:04X}:{:04X} at approximately frame {}", interrupt_index, segment, offset, frame)
+			}
+		}
+	}
+}
+
+/// Picks out the vectors that are in scribbles from the ones that changed, which must be in
+/// ascending order, given the memory they were changed in (see the top of this file for how).
+pub fn find_scribbled_vectors(changed_vectors: &[u8], memory: &[u8]) -> Vec<u8> {
+	let mut scribbled = vec![];
+	let mut run_start = 0;
+	for index in 1 ..= changed_vectors.len() {
+		let run_continues = index < changed_vectors.len() && changed_vectors[index] as usize == changed_vectors[index - 1] as usize + 1;
+		if !run_continues {
+			let run = &changed_vectors[run_start .. index];
+			let holds_non_handler = || run.iter().any(|&interrupt_index| !is_handler_vector(vector_entry(memory, interrupt_index), memory.len()));
+			if (run[0] == 0 && run.len() > 1) || (run.len() >= SCRIBBLE_RUN_VECTORS && holds_non_handler()) {
+				scribbled.extend_from_slice(run);
+			}
+			run_start = index;
+		}
+	}
+	scribbled
+}
+
+fn vector_entry(memory: &[u8], interrupt_index: u8) -> &[u8] {
+	let entry_addr = interrupt_index as usize * INTERRUPT_TABLE_ENTRY_BYTES;
+	&memory[entry_addr .. entry_addr + INTERRUPT_TABLE_ENTRY_BYTES]
+}
+
+// Whether a vector (IP then CS) points at one of the emulator's stubs.
+fn is_stub_vector(entry: &[u8]) -> bool {
+	is_bios_stub_far_pointer(u16::from_le_bytes([entry[2], entry[3]]), u16::from_le_bytes([entry[0], entry[1]]))
+}
+
+// Whether a vector (IP then CS) points somewhere a handler could be: after the BIOS data area, and
+// inside memory.
+fn is_handler_vector(entry: &[u8], memory_bytes: usize) -> bool {
+	let segment = u16::from_le_bytes([entry[2], entry[3]]) as usize;
+	let offset = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+	let addr = (segment << 4) + offset;
+	addr >= BIOS_START as usize + BIOS_DATA_BYTES && addr < memory_bytes
+}
+
+/// Keeps a copy of the interrupt vectors and the guarded BIOS data, to find what the program
+/// changed each frame.
+#[derive(Debug, Clone)]
+pub struct LowMemoryGuard {
+	pub protection: LowMemoryProtection,
+	vectors: Vec<u8>,
+	bios_data: Vec<u8>,
+	frame: u64,
+}
+
+impl LowMemoryGuard {
+	/// Starts with the vectors and BIOS data as they are in the machine now.
+	pub fn new(machine: &dyn Machine, protection: LowMemoryProtection) -> LowMemoryGuard {
+		let memory = machine.memory();
+		let bios_start = BIOS_START as usize;
+		LowMemoryGuard {
+			protection,
+			vectors: memory[.. INTERRUPT_TABLE_BYTES].to_vec(),
+			bios_data: memory[bios_start .. bios_start + BIOS_DATA_BYTES].to_vec(),
+			frame: 0,
+		}
+	}
+
+	/// Takes the vector as it is now as hooked properly, such as by int 21h AH=25h.
+	pub fn accept_vector(&mut self, machine: &dyn Machine, interrupt_index: u8) {
+		let entry_addr = interrupt_index as usize * INTERRUPT_TABLE_ENTRY_BYTES;
+		self.vectors[entry_addr .. entry_addr + INTERRUPT_TABLE_ENTRY_BYTES].copy_from_slice(vector_entry(machine.memory(), interrupt_index));
+	}
+
+	/// Takes the BIOS data as it is now as right, after the emulator changes it.
+	pub fn accept_bios_data(&mut self, machine: &dyn Machine) {
+		let bios_start = BIOS_START as usize;
+		self.bios_data.copy_from_slice(&machine.memory()[bios_start .. bios_start + BIOS_DATA_BYTES]);
+	}
+
+	/// Compares the memory with the copy at the end of a frame, and returns the scribbles. Vectors
+	/// are put back if the protection says to, and everything else that changed is taken as it is.
+	pub fn end_frame(&mut self, machine: &mut dyn Machine) -> Vec<LowMemoryWrite> {
+		self.frame += 1;
+		let mut writes = vec![];
+		let memory = machine.memory_mut();
+		if memory[.. INTERRUPT_TABLE_BYTES] != self.vectors[..] {
+			let changed_vectors: Vec<u8> = (0 ..= 0xffu8)
+				.filter(|&interrupt_index| vector_entry(memory, interrupt_index) != vector_entry(&self.vectors, interrupt_index))
+				.filter(|&interrupt_index| !is_stub_vector(vector_entry(memory, interrupt_index)))
+				.collect();
+			for interrupt_index in find_scribbled_vectors(&changed_vectors, memory) {
+				let entry_addr = interrupt_index as usize * INTERRUPT_TABLE_ENTRY_BYTES;
+				let entry_range = entry_addr .. entry_addr + INTERRUPT_TABLE_ENTRY_BYTES;
+				let restored = self.protection == LowMemoryProtection::Restore && is_stub_vector(&self.vectors[entry_range.clone()]);
+				if restored {
+					memory[entry_range.clone()].copy_from_slice(&self.vectors[entry_range]);
+				}
+				writes.push(LowMemoryWrite::Vector { interrupt_index, frame: self.frame, restored });
+			}
+			self.vectors.copy_from_slice(&memory[.. INTERRUPT_TABLE_BYTES]);
+		}
+		let bios_start = BIOS_START as usize;
+		let bios_data = &memory[bios_start .. bios_start + BIOS_DATA_BYTES];
+		for &(start, end) in GUARDED_BIOS_DATA.iter() {
+			if let Some(offset) = (start .. end).find(|&offset| bios_data[offset as usize] != self.bios_data[offset as usize]) {
+				writes.push(LowMemoryWrite::BiosData { offset: offset as u16, frame: self.frame });
+			}
+		}
+		self.bios_data.copy_from_slice(bios_data);
+		writes
+	}
+}
+
+/// Keeps a copy of the interrupt vectors, to report every one the program changes.
+#[derive(Debug, Clone)]
+pub struct VectorWatch {
+	vectors: Vec<u8>,
+	frame: u64,
+}
+
+impl VectorWatch {
+	/// Starts with the vectors as they are in the machine now.
+	pub fn new(machine: &dyn Machine) -> VectorWatch {
+		VectorWatch {
+			vectors: machine.memory()[.. INTERRUPT_TABLE_BYTES].to_vec(),
+			frame: 0,
+		}
+	}
+
+	/// Compares the vectors with the copy at the end of a frame, and returns the ones that changed.
+	pub fn end_frame(&mut self, machine: &dyn Machine) -> Vec<LowMemoryWrite> {
+		self.frame += 1;
+		let memory = machine.memory();
+		if memory[.. INTERRUPT_TABLE_BYTES] == self.vectors[..] {
+			return vec![];
+		}
+		let writes = (0 ..= 0xffu8)
+			.filter(|&interrupt_index| vector_entry(memory, interrupt_index) != vector_entry(&self.vectors, interrupt_index))
+			.map(|interrupt_index| {
+				let entry = vector_entry(memory, interrupt_index);
+				LowMemoryWrite::VectorSet {
+					interrupt_index,
+					segment: u16::from_le_bytes([entry[2], entry[3]]),
+					offset: u16::from_le_bytes([entry[0], entry[1]]),
+					frame: self.frame,
+				}
+			})
+			.collect();
+		self.vectors.copy_from_slice(&memory[.. INTERRUPT_TABLE_BYTES]);
+		writes
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::tests::make_test_handler;
+	use crate::bios_loader::{initialise_bios_data_area, BIOS_VIDEO_MODE_INDEX};
+
+	use xachtsechs::machine8086::Machine8086;
+	use xachtsechs::types::{EventHandler, Reg};
+
+	fn vector(machine: &Machine8086, interrupt_index: u8) -> (u16, u16) {
+		let entry_addr = interrupt_index as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+		(machine.peek_u16(entry_addr + 2), machine.peek_u16(entry_addr))
+	}
+
+	#[test] fn test_find_scribbled_vectors() {
+		// Every vector is 0000:0000 apart from int 50h to 53h, which point at 1234:0100.
+		let mut memory = vec![0; 0x20000];
+		for interrupt_index in 0x50 .. 0x54 {
+			memory[interrupt_index * 4 .. interrupt_index * 4 + 4].copy_from_slice(&[0x00, 0x01, 0x34, 0x12]);
+		}
+		// Single vectors and short runs away from vector 0 are hooks.
+		assert_eq!(find_scribbled_vectors(&[0x00], &memory), vec![]);
+		assert_eq!(find_scribbled_vectors(&[0x08, 0x09, 0x1c], &memory), vec![]);
+		// Any run from vector 0, and long runs anywhere that hold a non-handler, are scribbles.
+		assert_eq!(find_scribbled_vectors(&[0x00, 0x01, 0x1c], &memory), vec![0x00, 0x01]);
+		assert_eq!(find_scribbled_vectors(&[0x09, 0x40, 0x41, 0x42, 0x43], &memory), vec![0x40, 0x41, 0x42, 0x43]);
+		assert_eq!(find_scribbled_vectors(&[0x4f, 0x50, 0x51, 0x52, 0x53], &memory), vec![0x4f, 0x50, 0x51, 0x52, 0x53]);
+		// A long run that all point at handlers is a hook.
+		assert_eq!(find_scribbled_vectors(&[0x50, 0x51, 0x52, 0x53], &memory), vec![]);
+		assert_eq!(find_scribbled_vectors(&[], &memory), vec![]);
+		// Pointers into the BIOS data area or past the end of memory aren't handlers.
+		assert!(!is_handler_vector(&[0x17, 0x00, 0x40, 0x00], memory.len()));
+		assert!(!is_handler_vector(&[0xff, 0xff, 0xff, 0xff], memory.len()));
+		assert!(is_handler_vector(&[0x00, 0x00, 0x50, 0x00], memory.len()));
+	}
+
+	#[test] fn test_null_pointer_memset() {
+		let (mut handler, mut machine) = make_test_handler();
+		initialise_bios_data_area(&mut machine);
+		handler.low_memory_guard = Some(LowMemoryGuard::new(&machine, LowMemoryProtection::Restore));
+		let original_timer_vector = vector(&machine, 0x08);
+		// Hook int 09h and int 1Ch properly, then write zeros over the first 64 bytes.
+		for &interrupt_index in [0x09u8, 0x1c].iter() {
+			machine.set_reg_u16(Reg::AX, 0x2500 | interrupt_index as u16);
+			machine.set_reg_u16(Reg::DS, 0x1234);
+			machine.set_reg_u16(Reg::DX, 0x0100 + interrupt_index as u16);
+			handler.handle_interrupt(&mut machine, 0x21);
+		}
+		machine.memory[.. 64].iter_mut().for_each(|byte| *byte = 0);
+		let writes = handler.check_low_memory(&mut machine);
+		assert_eq!(writes.len(), 16);
+		assert_eq!(writes[0x08], LowMemoryWrite::Vector { interrupt_index: 0x08, frame: 1, restored: true });
+		assert_eq!(writes[0x08].to_string(), "Program wrote to the int 08h vector at approximately frame 1, so it was put back");
+		// The program's own int 09h handler isn't known, so it's left written over.
+		assert_eq!(writes[0x09], LowMemoryWrite::Vector { interrupt_index: 0x09, frame: 1, restored: false });
+		assert_eq!(vector(&machine, 0x08), original_timer_vector);
+		assert_eq!(vector(&machine, 0x09), (0, 0));
+		assert_eq!(vector(&machine, 0x1c), (0x1234, 0x011c));
+		// It's only reported once.
+		assert_eq!(handler.check_low_memory(&mut machine), vec![]);
+	}
+
+	#[test] fn test_direct_hook_of_neighbouring_vectors() {
+		let (mut handler, mut machine) = make_test_handler();
+		initialise_bios_data_area(&mut machine);
+		handler.low_memory_guard = Some(LowMemoryGuard::new(&machine, LowMemoryProtection::Restore));
+		// Hooking int 08h to 0Bh by writing the table directly, like a game taking over the timer,
+		// keyboard and serial ports, is left alone.
+		for interrupt_index in 0x08u32 .. 0x0c {
+			machine.poke_u16(interrupt_index * 4, 0x0100 + interrupt_index as u16);
+			machine.poke_u16(interrupt_index * 4 + 2, 0x1234);
+		}
+		assert_eq!(handler.check_low_memory(&mut machine), vec![]);
+		for interrupt_index in 0x08u32 .. 0x0c {
+			assert_eq!(vector(&machine, interrupt_index as u8), (0x1234, 0x0100 + interrupt_index as u16));
+		}
+	}
+
+	#[test] fn test_report_only() {
+		let (mut handler, mut machine) = make_test_handler();
+		initialise_bios_data_area(&mut machine);
+		handler.low_memory_guard = Some(LowMemoryGuard::new(&machine, LowMemoryProtection::Report));
+		// Hooking a vector directly, or putting it back to a stub, isn't reported.
+		let original_timer_vector = vector(&machine, 0x08);
+		machine.poke_u16(0x08 * 4, 0x5678);
+		assert_eq!(handler.check_low_memory(&mut machine), vec![]);
+		machine.poke_u16(0x08 * 4, original_timer_vector.1);
+		machine.memory[0x40 .. 0x50].iter_mut().for_each(|byte| *byte = 0xff);
+		machine.set_data_u8(&BIOS_VIDEO_MODE_INDEX, 0x13);
+		let writes = handler.check_low_memory(&mut machine);
+		assert_eq!(writes, vec![
+			LowMemoryWrite::Vector { interrupt_index: 0x10, frame: 2, restored: false },
+			LowMemoryWrite::Vector { interrupt_index: 0x11, frame: 2, restored: false },
+			LowMemoryWrite::Vector { interrupt_index: 0x12, frame: 2, restored: false },
+			LowMemoryWrite::Vector { interrupt_index: 0x13, frame: 2, restored: false },
+			LowMemoryWrite::BiosData { offset: 0x49, frame: 2 },
+		]);
+		assert_eq!(writes[4].to_string(), "Program wrote to BIOS data area offset 49h at approximately frame 2");
+		assert_eq!(vector(&machine, 0x10), (0xffff, 0xffff));
+		// Setting the video mode changes the BIOS data the proper way.
+		machine.set_reg_u16(Reg::AX, 0x0003);
+		handler.handle_interrupt(&mut machine, 0x10);
+		assert_eq!(handler.check_low_memory(&mut machine), vec![]);
+	}
+	#[test] fn test_vector_watch() {
+		let (mut handler, mut machine) = make_test_handler();
+		initialise_bios_data_area(&mut machine);
+		handler.low_memory_guard = Some(LowMemoryGuard::new(&machine, LowMemoryProtection::Restore));
+		handler.vector_watch = Some(VectorWatch::new(&machine));
+		assert_eq!(handler.check_low_memory(&mut machine), vec![]);
+		// Vectors set through int 21h AH=25h and written directly are both reported.
+		machine.set_reg_u16(Reg::AX, 0x2509);
+		machine.set_reg_u16(Reg::DS, 0x1234);
+		machine.set_reg_u16(Reg::DX, 0x0100);
+		handler.handle_interrupt(&mut machine, 0x21);
+		machine.poke_u16(0x1c * 4, 0x0200);
+		machine.poke_u16(0x1c * 4 + 2, 0x1234);
+		let writes = handler.check_low_memory(&mut machine);
+		assert_eq!(writes, vec![
+			LowMemoryWrite::VectorSet { interrupt_index: 0x09, segment: 0x1234, offset: 0x0100, frame: 2 },
+			LowMemoryWrite::VectorSet { interrupt_index: 0x1c, segment: 0x1234, offset: 0x0200, frame: 2 },
+		]);
+		assert_eq!(writes[1].to_string(), "Program set the int 1Ch vector to 1234:0200 at approximately frame 2");
+		assert_eq!(handler.check_low_memory(&mut machine), vec![]);
+		// A scribble the guard puts back isn't a change, but the vectors it leaves written over are.
+		let timer_vector = vector(&machine, 0x08);
+		machine.memory[0x08 * 4 .. 0x0c * 4].iter_mut().for_each(|byte| *byte = 0);
+		assert_eq!(handler.check_low_memory(&mut machine), vec![
+			LowMemoryWrite::Vector { interrupt_index: 0x08, frame: 4, restored: true },
+			LowMemoryWrite::Vector { interrupt_index: 0x09, frame: 4, restored: false },
+			LowMemoryWrite::Vector { interrupt_index: 0x0a, frame: 4, restored: true },
+			LowMemoryWrite::Vector { interrupt_index: 0x0b, frame: 4, restored: true },
+			LowMemoryWrite::VectorSet { interrupt_index: 0x09, segment: 0, offset: 0, frame: 4 },
+		]);
+		assert_eq!(vector(&machine, 0x08), timer_vector);
+	}
+}
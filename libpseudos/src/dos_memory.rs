@@ -0,0 +1,426 @@
+// DOS memory management (int 21h AH=48h, 49h and 4Ah). Like DOS, the blocks are kept as a chain of
+// memory control blocks (MCBs) in emulated memory, so a program that walks the chain itself sees
+// the same blocks the allocator does. Each MCB is the paragraph just before its block: an 'M', or a
+// 'Z' for the last block, then the segment of the PSP that owns the block (0 if it's free), the
+// size of the block in paragraphs, and at offset 8 the name of the owner as in DOS 4+.
+// http://www.ctyme.com/intr/rb-2983.htm#Table1422
+//
+// Freeing a block joins it up with the free blocks either side of it, so the chain never has two
+// free blocks next to each other unless a program has written the MCBs itself. Those are joined
+// up the next time something is allocated or resized, as DOS does.
+
+use crate::dos_error_codes::DosErrorCode;
+use crate::machine::Machine;
+
+const MCB_SIGNATURE_MORE: u8 = b'M';
+const MCB_SIGNATURE_LAST: u8 = b'Z';
+const PARAGRAPH_BYTES: u32 = 16;
+/// The owner of a block that's free.
+pub const MCB_OWNER_FREE: u16 = 0;
+/// The segment of the first MCB. The program that's loaded has its environment block straight
+/// after it.
+pub const FIRST_MCB_SEGMENT: u16 = 0x7f;
+// Conventional memory ends where video memory starts.
+const CONVENTIONAL_MEMORY_END_SEGMENT: u32 = 0xa000;
+const MCB_NAME_OFFSET: u32 = 8;
+pub const MCB_NAME_BYTES: usize = 8;
+/// The owner name DOS gives its own data, such as the environment of the first program.
+pub const MCB_SYSTEM_DATA_NAME: [u8; MCB_NAME_BYTES] = *b"SD\0\0\0\0\0\0";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryBlock {
+	pub mcb_segment: u16,
+	pub owner: u16,
+	pub paragraphs: u16,
+	pub is_last: bool,
+	/// The owner's file name without its extension, padded with zeros.
+	pub name: [u8; MCB_NAME_BYTES],
+}
+
+impl MemoryBlock {
+	/// The segment of the block itself, which is what programs are given.
+	pub fn segment(&self) -> u16 {
+		self.mcb_segment + 1
+	}
+
+	pub fn is_free(&self) -> bool {
+		self.owner == MCB_OWNER_FREE
+	}
+
+	fn next_mcb_segment(&self) -> u32 {
+		self.segment() as u32 + self.paragraphs as u32
+	}
+}
+
+fn read_mcb(machine: &dyn Machine, mcb_segment: u16) -> Result<MemoryBlock, DosErrorCode> {
+	let addr = mcb_segment as u32 * PARAGRAPH_BYTES;
+	if (addr + PARAGRAPH_BYTES) as usize > machine.memory().len() {
+		return Err(DosErrorCode::MemoryControlBlocksDestroyed);
+	}
+	let is_last = match machine.peek_u8(addr) {
+		MCB_SIGNATURE_MORE => false,
+		MCB_SIGNATURE_LAST => true,
+		_ => return Err(DosErrorCode::MemoryControlBlocksDestroyed),
+	};
+	let mut name = [0; MCB_NAME_BYTES];
+	let name_addr = (addr + MCB_NAME_OFFSET) as usize;
+	name.copy_from_slice(&machine.memory()[name_addr .. name_addr + MCB_NAME_BYTES]);
+	Ok(MemoryBlock { mcb_segment, owner: machine.peek_u16(addr + 1), paragraphs: machine.peek_u16(addr + 3), is_last, name })
+}
+
+fn write_mcb(machine: &mut dyn Machine, block: &MemoryBlock) {
+	let addr = block.mcb_segment as u32 * PARAGRAPH_BYTES;
+	machine.poke_u8(addr, if block.is_last { MCB_SIGNATURE_LAST } else { MCB_SIGNATURE_MORE });
+	machine.poke_u16(addr + 1, block.owner);
+	machine.poke_u16(addr + 3, block.paragraphs);
+	machine.insert_contiguous_bytes(&block.name, (addr + MCB_NAME_OFFSET) as usize);
+}
+
+/// The owner name for a program, which is its file name without the directory or extension, in
+/// upper case.
+pub fn program_owner_name(program_path: &[u8]) -> [u8; MCB_NAME_BYTES] {
+	let filename = program_path.rsplit(|c| [b'\\', b'/', b':'].contains(c)).next().unwrap_or(&[]);
+	let title = filename.split(|c| *c == b'.').next().unwrap_or(&[]);
+	let mut name = [0; MCB_NAME_BYTES];
+	for (name_char, c) in name.iter_mut().zip(title) {
+		*name_char = c.to_ascii_uppercase();
+	}
+	name
+}
+
+/// The segment just after the last memory block, where conventional memory (or the machine's memory)
+/// ends.
+pub fn memory_end_segment(machine: &dyn Machine) -> u16 {
+	CONVENTIONAL_MEMORY_END_SEGMENT.min(machine.memory().len() as u32 / PARAGRAPH_BYTES) as u16
+}
+
+/// Walks the MCB chain from the first block to the last. Fails if a block doesn't have an MCB
+/// signature, or the chain runs off the end of memory.
+pub fn memory_blocks(machine: &dyn Machine) -> Result<Vec<MemoryBlock>, DosErrorCode> {
+	let mut blocks = vec![];
+	let mut mcb_segment = FIRST_MCB_SEGMENT as u32;
+	loop {
+		if mcb_segment > 0xffff {
+			return Err(DosErrorCode::MemoryControlBlocksDestroyed);
+		}
+		let block = read_mcb(machine, mcb_segment as u16)?;
+		blocks.push(block);
+		if block.is_last {
+			return Ok(blocks);
+		}
+		mcb_segment = block.next_mcb_segment();
+	}
+}
+
+/// Checks that the chain is what the allocator keeps it as: every block's MCB is where the one
+/// before it says, the last one ends at the end of conventional memory, and no two free blocks are
+/// next to each other. The error says what's wrong.
+pub fn check_memory_chain(machine: &dyn Machine) -> Result<(), String> {
+	let blocks = memory_blocks(machine).map_err(|_| "The chain has an MCB without a signature, or runs off the end of memory".to_string())?;
+	for pair in blocks.windows(2) {
+		if pair[0].is_free() && pair[1].is_free() {
+			return Err(format!("The free blocks at {:04x} and {:04x} haven't been joined", pair[0].segment(), pair[1].segment()));
+		}
+	}
+	let last_block = blocks.last().unwrap();
+	if last_block.next_mcb_segment() != memory_end_segment(machine) as u32 {
+		return Err(format!("The last block ends at {:04x} instead of {:04x}", last_block.next_mcb_segment(), memory_end_segment(machine)));
+	}
+	Ok(())
+}
+
+/// Sets up the chain for a program that's just been loaded: its environment block from the first
+/// MCB up to its PSP, and then the rest of conventional memory for the program itself, named with
+/// program_name (see program_owner_name). DOS gives a program all the memory there is, so it has to
+/// shrink its block before it can allocate more.
+pub fn initialise_memory_blocks(machine: &mut dyn Machine, psp_segment: u16, program_name: [u8; MCB_NAME_BYTES]) {
+	let environment_block = MemoryBlock {
+		mcb_segment: FIRST_MCB_SEGMENT,
+		owner: psp_segment,
+		paragraphs: psp_segment - 1 - (FIRST_MCB_SEGMENT + 1),
+		is_last: false,
+		name: MCB_SYSTEM_DATA_NAME,
+	};
+	write_mcb(machine, &environment_block);
+	let program_block = MemoryBlock {
+		mcb_segment: psp_segment - 1,
+		owner: psp_segment,
+		paragraphs: memory_end_segment(machine).saturating_sub(psp_segment),
+		is_last: true,
+		name: program_name,
+	};
+	write_mcb(machine, &program_block);
+}
+
+fn find_block(blocks: &[MemoryBlock], segment: u16) -> Result<usize, DosErrorCode> {
+	blocks.iter().position(|block| block.segment() == segment).ok_or(DosErrorCode::InvalidMemoryBlockAddress)
+}
+
+// Gives the block at block_index the given size, owner and name, out of the space it has plus the
+// free blocks straight after it up to (and including) last_index. What's left over becomes a free
+// block.
+fn fit_block(machine: &mut dyn Machine, blocks: &[MemoryBlock], block_index: usize, last_index: usize, paragraphs: u16, owner: u16, name: [u8; MCB_NAME_BYTES]) {
+	let block = blocks[block_index];
+	let available = blocks[last_index].next_mcb_segment() - block.segment() as u32;
+	let is_last = blocks[last_index].is_last;
+	let leftover = available - paragraphs as u32;
+	write_mcb(machine, &MemoryBlock { paragraphs, owner, is_last: is_last && leftover == 0, name, ..block });
+	if leftover > 0 {
+		write_mcb(machine, &MemoryBlock {
+			mcb_segment: block.segment() + paragraphs,
+			owner: MCB_OWNER_FREE,
+			paragraphs: (leftover - 1) as u16,
+			is_last,
+			name: [0; MCB_NAME_BYTES],
+		});
+	}
+}
+
+// The index of the last free block in the run straight after block_index.
+fn last_free_after(blocks: &[MemoryBlock], block_index: usize) -> usize {
+	let mut last_index = block_index;
+	while !blocks[last_index].is_last && blocks[last_index + 1].is_free() {
+		last_index += 1;
+	}
+	last_index
+}
+
+fn available_paragraphs(blocks: &[MemoryBlock], block_index: usize, last_index: usize) -> u16 {
+	(blocks[last_index].next_mcb_segment() - blocks[block_index].segment() as u32).min(0xffff) as u16
+}
+
+/// Allocates a block of memory for a PSP (int 21h AH=48h), in the first free space that's big
+/// enough, and returns its segment. The block gets the name of the owner's own block. If there
+/// isn't one, the error comes with the size of the biggest block that could be allocated.
+pub fn allocate(machine: &mut dyn Machine, owner: u16, paragraphs: u16) -> Result<u16, (DosErrorCode, u16)> {
+	let blocks = memory_blocks(machine).map_err(|error_code| (error_code, 0))?;
+	let name = blocks.iter().find(|block| block.segment() == owner).map(|block| block.name).unwrap_or([0; MCB_NAME_BYTES]);
+	let mut largest = 0;
+	let mut block_index = 0;
+	while block_index < blocks.len() {
+		if blocks[block_index].is_free() {
+			let last_index = last_free_after(&blocks, block_index);
+			let available = available_paragraphs(&blocks, block_index, last_index);
+			if available >= paragraphs {
+				fit_block(machine, &blocks, block_index, last_index, paragraphs, owner, name);
+				return Ok(blocks[block_index].segment());
+			}
+			largest = largest.max(available);
+			block_index = last_index;
+		}
+		block_index += 1;
+	}
+	Err((DosErrorCode::InsufficientMemory, largest))
+}
+
+/// Frees the block at a segment (int 21h AH=49h), joining it up with any free blocks either side.
+pub fn free(machine: &mut dyn Machine, segment: u16) -> Result<(), DosErrorCode> {
+	let mut blocks = memory_blocks(machine)?;
+	let block_index = find_block(&blocks, segment)?;
+	blocks[block_index].owner = MCB_OWNER_FREE;
+	let mut first_index = block_index;
+	while first_index > 0 && blocks[first_index - 1].is_free() {
+		first_index -= 1;
+	}
+	let last_index = last_free_after(&blocks, first_index);
+	write_mcb(machine, &MemoryBlock {
+		paragraphs: (blocks[last_index].next_mcb_segment() - blocks[first_index].segment() as u32) as u16,
+		owner: MCB_OWNER_FREE,
+		is_last: blocks[last_index].is_last,
+		name: [0; MCB_NAME_BYTES],
+		..blocks[first_index]
+	});
+	Ok(())
+}
+
+/// Changes the size of the block at a segment (int 21h AH=4Ah), taking from the free blocks after
+/// it to grow. If it can't grow that much, it's left as it was, and the error comes with the
+/// biggest size it could have.
+pub fn resize(machine: &mut dyn Machine, segment: u16, paragraphs: u16) -> Result<(), (DosErrorCode, u16)> {
+	let blocks = memory_blocks(machine).map_err(|error_code| (error_code, 0))?;
+	let block_index = find_block(&blocks, segment).map_err(|error_code| (error_code, 0))?;
+	let last_index = last_free_after(&blocks, block_index);
+	let available = available_paragraphs(&blocks, block_index, last_index);
+	if paragraphs > available {
+		return Err((DosErrorCode::InsufficientMemory, available));
+	}
+	fit_block(machine, &blocks, block_index, last_index, paragraphs, blocks[block_index].owner, blocks[block_index].name);
+	Ok(())
+}
+
+/// The variables in the environment block a PSP points to (at offset 2Ch), up to and including the
+/// empty string that ends them, if the block is still allocated. A program can free its
+/// environment to save memory, and the pointer to it is left as it was, so it has to be checked
+/// before anything is copied from it.
+pub fn allocated_environment_block(machine: &dyn Machine, psp_segment: u16) -> Option<Vec<u8>> {
+	let environment_segment = machine.peek_u16(psp_segment as u32 * PARAGRAPH_BYTES + 0x2c);
+	let blocks = memory_blocks(machine).ok()?;
+	let block = blocks[find_block(&blocks, environment_segment).ok()?];
+	if block.is_free() {
+		return None;
+	}
+	let start = environment_segment as usize * PARAGRAPH_BYTES as usize;
+	let block_bytes = &machine.memory()[start .. start + block.paragraphs as usize * PARAGRAPH_BYTES as usize];
+	let end = block_bytes.windows(2).position(|pair| pair == [0, 0]).map(|pos| pos + 2).unwrap_or(block_bytes.len());
+	Some(block_bytes[..end].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::machine::FakeMachine;
+
+	const PSP_SEGMENT: u16 = 0x100;
+
+	fn make_test_machine() -> FakeMachine {
+		let mut machine = FakeMachine::new(0xa0000);
+		initialise_memory_blocks(&mut machine, PSP_SEGMENT, program_owner_name(b"C:\\GAMES\\zzt.exe"));
+		machine
+	}
+
+	fn block_sizes(machine: &FakeMachine) -> Vec<(u16, u16, u16)> {
+		memory_blocks(machine).unwrap().iter().map(|block| (block.segment(), block.owner, block.paragraphs)).collect()
+	}
+
+	#[test] fn test_allocate_and_free() {
+		let mut machine = make_test_machine();
+		assert_eq!(block_sizes(&machine), vec![(0x80, PSP_SEGMENT, 0x7f), (0x100, PSP_SEGMENT, 0x9f00)]);
+		// The program has everything, so it has to shrink first.
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0x10), Err((DosErrorCode::InsufficientMemory, 0)));
+		resize(&mut machine, PSP_SEGMENT, 0x1000).unwrap();
+		assert_eq!(block_sizes(&machine), vec![(0x80, PSP_SEGMENT, 0x7f), (0x100, PSP_SEGMENT, 0x1000), (0x1101, 0, 0x8eff)]);
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0x10), Ok(0x1101));
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0x20), Ok(0x1112));
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0xffff), Err((DosErrorCode::InsufficientMemory, 0x8ecd)));
+
+		// Freed blocks are joined up with the free blocks either side.
+		free(&mut machine, 0x1101).unwrap();
+		assert_eq!(block_sizes(&machine)[2 ..], [(0x1101, 0, 0x10), (0x1112, PSP_SEGMENT, 0x20), (0x1133, 0, 0x8ecd)]);
+		free(&mut machine, 0x1112).unwrap();
+		assert_eq!(free(&mut machine, 0x1234), Err(DosErrorCode::InvalidMemoryBlockAddress));
+		assert_eq!(block_sizes(&machine)[2 ..], [(0x1101, 0, 0x8eff)]);
+
+		// Free blocks next to each other that a program made itself are joined when they're needed.
+		write_mcb(&mut machine, &MemoryBlock { mcb_segment: 0x1111, owner: MCB_OWNER_FREE, paragraphs: 0x8eee, is_last: true, name: [0; 8] });
+		write_mcb(&mut machine, &MemoryBlock { mcb_segment: 0x1100, owner: MCB_OWNER_FREE, paragraphs: 0x10, is_last: false, name: [0; 8] });
+		assert_eq!(check_memory_chain(&machine), Err("The free blocks at 1101 and 1112 haven't been joined".to_string()));
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0x40), Ok(0x1101));
+		assert_eq!(block_sizes(&machine)[2 ..], [(0x1101, PSP_SEGMENT, 0x40), (0x1142, 0, 0x8ebe)]);
+
+		// Growing takes the free space after the block, and fails without changing anything if
+		// there isn't enough.
+		assert_eq!(resize(&mut machine, 0x1101, 0xffff), Err((DosErrorCode::InsufficientMemory, 0x8eff)));
+		assert_eq!(block_sizes(&machine)[2 ..], [(0x1101, PSP_SEGMENT, 0x40), (0x1142, 0, 0x8ebe)]);
+		resize(&mut machine, 0x1101, 0x8eff).unwrap();
+		assert_eq!(block_sizes(&machine)[2 ..], [(0x1101, PSP_SEGMENT, 0x8eff)]);
+
+		// A broken chain is reported rather than followed.
+		machine.memory[0x1100 * 16] = 0;
+		assert_eq!(memory_blocks(&machine), Err(DosErrorCode::MemoryControlBlocksDestroyed));
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 1), Err((DosErrorCode::MemoryControlBlocksDestroyed, 0)));
+	}
+
+	#[test] fn test_largest_block_after_fragmentation() {
+		let mut machine = make_test_machine();
+		resize(&mut machine, PSP_SEGMENT, 0x1000).unwrap();
+		let first = allocate(&mut machine, PSP_SEGMENT, 0x100).unwrap();
+		let middle = allocate(&mut machine, PSP_SEGMENT, 0x200).unwrap();
+		let last = allocate(&mut machine, PSP_SEGMENT, 0x300).unwrap();
+		// Take the rest, so the only free space is the hole left in the middle.
+		let (_, rest) = allocate(&mut machine, PSP_SEGMENT, 0xffff).unwrap_err();
+		allocate(&mut machine, PSP_SEGMENT, rest).unwrap();
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0xffff), Err((DosErrorCode::InsufficientMemory, 0)));
+
+		free(&mut machine, middle).unwrap();
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0xffff), Err((DosErrorCode::InsufficientMemory, 0x200)));
+		assert_eq!(allocate(&mut machine, PSP_SEGMENT, 0x200), Ok(middle));
+
+		// Freeing the blocks either side of a block makes it able to grow into the one after it.
+		free(&mut machine, middle).unwrap();
+		free(&mut machine, last).unwrap();
+		assert_eq!(resize(&mut machine, first, 0xffff), Err((DosErrorCode::InsufficientMemory, 0x100 + 0x201 + 0x301)));
+		assert_eq!(resize(&mut machine, first, 0x100 + 0x201 + 0x301), Ok(()));
+	}
+
+	#[test] fn test_reported_sizes_can_be_allocated() {
+		// Random allocations, frees and resizes, where every failure that reports a size is tried
+		// again with that size, which has to work.
+		let mut random_state = 1u32;
+		let mut random = || {
+			random_state = random_state.wrapping_mul(1103515245).wrapping_add(12345);
+			(random_state >> 16) as usize
+		};
+		let mut machine = make_test_machine();
+		resize(&mut machine, PSP_SEGMENT, 0x1000).unwrap();
+		let mut segments = vec![];
+		for _ in 0 .. 2000 {
+			match random() % 3 {
+				0 => {
+					let paragraphs = if random() % 4 == 0 { 0xffff } else { (random() % 0x3000) as u16 };
+					match allocate(&mut machine, PSP_SEGMENT, paragraphs) {
+						Ok(segment) => segments.push(segment),
+						// With no free blocks at all, there's nothing to allocate even 0 paragraphs
+						// from.
+						Err((DosErrorCode::InsufficientMemory, 0)) => {
+							assert!(memory_blocks(&machine).unwrap().iter().all(|block| !block.is_free()));
+						}
+						Err((DosErrorCode::InsufficientMemory, largest)) => {
+							assert!(largest < paragraphs);
+							segments.push(allocate(&mut machine, PSP_SEGMENT, largest).unwrap());
+						}
+						Err(error) => panic!("Unexpected error: {:?}", error),
+					}
+				}
+				1 if !segments.is_empty() => {
+					let segment = segments.swap_remove(random() % segments.len());
+					free(&mut machine, segment).unwrap();
+				}
+				2 if !segments.is_empty() => {
+					let segment = segments[random() % segments.len()];
+					let paragraphs = if random() % 4 == 0 { 0xffff } else { (random() % 0x3000) as u16 };
+					match resize(&mut machine, segment, paragraphs) {
+						Ok(()) => {}
+						Err((DosErrorCode::InsufficientMemory, largest)) => {
+							assert!(largest < paragraphs);
+							resize(&mut machine, segment, largest).unwrap();
+						}
+						Err(error) => panic!("Unexpected error: {:?}", error),
+					}
+				}
+				_ => {}
+			}
+			assert_eq!(check_memory_chain(&machine), Ok(()));
+		}
+	}
+
+	#[test] fn test_owner_names() {
+		let mut machine = make_test_machine();
+		assert_eq!(program_owner_name(b"C:\\GAMES\\zzt.exe"), *b"ZZT\0\0\0\0\0");
+		assert_eq!(program_owner_name(b"A:LONGNAME.COM"), *b"LONGNAME");
+		let blocks = memory_blocks(&machine).unwrap();
+		assert_eq!(blocks[0].name, MCB_SYSTEM_DATA_NAME);
+		assert_eq!(blocks[1].name, *b"ZZT\0\0\0\0\0");
+		assert_eq!(&machine.memory[(PSP_SEGMENT as usize - 1) * 16 + 8 ..][.. 3], b"ZZT");
+
+		// The program's allocations have its name, and free blocks have none.
+		resize(&mut machine, PSP_SEGMENT, 0x1000).unwrap();
+		let segment = allocate(&mut machine, PSP_SEGMENT, 0x10).unwrap();
+		let blocks = memory_blocks(&machine).unwrap();
+		assert_eq!((blocks[2].segment(), blocks[2].name), (segment, *b"ZZT\0\0\0\0\0"));
+		assert_eq!(blocks[3].name, [0; 8]);
+		free(&mut machine, segment).unwrap();
+		assert_eq!(memory_blocks(&machine).unwrap()[2].name, [0; 8]);
+	}
+
+	#[test] fn test_freed_environment() {
+		let mut machine = make_test_machine();
+		machine.poke_u16(PSP_SEGMENT as u32 * 16 + 0x2c, 0x80);
+		machine.insert_contiguous_bytes(b"PATH=C:\\\0COMSPEC=C:\\COMMAND.COM\0\0\x01\0C:\\GAME.EXE\0", 0x800);
+		assert_eq!(allocated_environment_block(&machine, PSP_SEGMENT).unwrap(), b"PATH=C:\\\0COMSPEC=C:\\COMMAND.COM\0\0".to_vec());
+
+		// Once it's freed, the PSP still points at it, but there's nothing to copy.
+		free(&mut machine, 0x80).unwrap();
+		assert_eq!(machine.peek_u16(PSP_SEGMENT as u32 * 16 + 0x2c), 0x80);
+		assert_eq!(allocated_environment_block(&machine, PSP_SEGMENT), None);
+	}
+}
@@ -0,0 +1,117 @@
+// Saves the machine's memory to a file for looking at in a hex editor or disassembler, along with
+// a text file of the registers and flags it was saved with. Each dump is named by the time, like
+// the print screen files, so the two files of a dump go together:
+//
+//   pseudos-memory-1700000000000.bin  the raw bytes, from the start of the range
+//   pseudos-memory-1700000000000.txt  the range, the registers and the flags
+//
+// The whole 1MB is saved unless a range of linear addresses is given.
+
+use crate::machine::Machine;
+
+use xachtsechs::types::{Flag, Reg};
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MEMORY_DUMP_FILE_PREFIX: &str = "pseudos-memory";
+
+// The flags in the order of their bits, with the names debuggers give them.
+const DUMPED_FLAGS: [(Flag, &str); 9] = [
+	(Flag::Carry, "CF"),
+	(Flag::Parity, "PF"),
+	(Flag::Adjust, "AF"),
+	(Flag::Zero, "ZF"),
+	(Flag::Sign, "SF"),
+	(Flag::Trap, "TF"),
+	(Flag::Interrupt, "IF"),
+	(Flag::Direction, "DF"),
+	(Flag::Overflow, "OF"),
+];
+
+/// Parses a range of linear addresses written as "START-END" in hex, where END isn't included,
+/// like "b8000-b8fa0".
+pub fn parse_memory_range(text: &str) -> Result<Range<u32>, String> {
+	let mut parts = text.splitn(2, '-');
+	let mut parse_addr = |name: &str| {
+		let part = parts.next().unwrap_or("").trim();
+		u32::from_str_radix(part, 16).map_err(|_| format!("The {} of memory range {} isn't a hex address", name, text))
+	};
+	let start = parse_addr("start")?;
+	let end = parse_addr("end")?;
+	if start >= end {
+		return Err(format!("Memory range {} is empty", text));
+	}
+	Ok(start .. end)
+}
+
+/// Describes the registers and flags, a line for each group.
+pub fn describe_registers(machine: &dyn Machine) -> String {
+	let regs = |regs: &[(Reg, &str)]| regs.iter().map(|(reg, name)| format!("{}={:04X}", name, machine.get_reg_u16(*reg))).collect::<Vec<_>>().join(" ");
+	let flags = DUMPED_FLAGS.iter().map(|(flag, name)| format!("{}={}", name, machine.get_flag(*flag) as u8)).collect::<Vec<_>>().join(" ");
+	format!("{}\n{}\nFlags: {}\n",
+		regs(&[(Reg::AX, "AX"), (Reg::BX, "BX"), (Reg::CX, "CX"), (Reg::DX, "DX"), (Reg::SI, "SI"), (Reg::DI, "DI"), (Reg::BP, "BP"), (Reg::SP, "SP")]),
+		regs(&[(Reg::CS, "CS"), (Reg::IP, "IP"), (Reg::DS, "DS"), (Reg::ES, "ES"), (Reg::SS, "SS")]),
+		flags)
+}
+
+/// Saves the memory in the range (or all of it), and the registers, as a dump in dir. Returns the
+/// path of the memory file.
+pub fn dump_memory(machine: &dyn Machine, dir: &Path, range: Option<Range<u32>>) -> Result<PathBuf, String> {
+	let memory = machine.memory();
+	let range = range.unwrap_or(0 .. memory.len() as u32);
+	if range.end as usize > memory.len() {
+		return Err(format!("Memory range {:05x}-{:05x} goes past the end of memory at {:05x}", range.start, range.end, memory.len()));
+	}
+	let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_millis()).unwrap_or(0);
+	let memory_path = dir.join(format!("{}-{}.bin", MEMORY_DUMP_FILE_PREFIX, time_ms));
+	let registers_path = memory_path.with_extension("txt");
+	let description = format!("Memory: {:05X}-{:05X} ({} bytes) in {}\n{}Instructions run: {}\n",
+		range.start, range.end, range.end - range.start,
+		memory_path.file_name().unwrap().to_string_lossy(),
+		describe_registers(machine),
+		machine.number_of_parsed_instructions());
+	std::fs::write(&memory_path, &memory[range.start as usize .. range.end as usize])
+		.and_then(|()| std::fs::write(&registers_path, description))
+		.map_err(|err| format!("Couldn't save the memory to {}: {}", memory_path.display(), err))?;
+	Ok(memory_path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::tests::make_test_dir;
+	use crate::machine::FakeMachine;
+
+	#[test] fn test_parse_memory_range() {
+		assert_eq!(parse_memory_range("b8000-B8FA0"), Ok(0xb8000 .. 0xb8fa0));
+		assert_eq!(parse_memory_range("b8000"), Err("The end of memory range b8000 isn't a hex address".to_string()));
+		assert_eq!(parse_memory_range("x-10"), Err("The start of memory range x-10 isn't a hex address".to_string()));
+		assert_eq!(parse_memory_range("10-10"), Err("Memory range 10-10 is empty".to_string()));
+	}
+
+	#[test] fn test_dump_memory() {
+		let dir_path = make_test_dir("memory_dump");
+		let mut machine = FakeMachine::new(0x1000);
+		machine.memory[0x100 .. 0x104].copy_from_slice(b"DUMP");
+		machine.set_reg_u16(Reg::AX, 0x4c00);
+		machine.set_reg_u16(Reg::CS, 0x1234);
+		machine.set_flag(Flag::Carry, true);
+		machine.set_flag(Flag::Zero, true);
+
+		let memory_path = dump_memory(&machine, &dir_path, Some(0x100 .. 0x104)).unwrap();
+		assert_eq!(std::fs::read(&memory_path).unwrap(), b"DUMP");
+		let description = std::fs::read_to_string(memory_path.with_extension("txt")).unwrap();
+		let lines: Vec<&str> = description.lines().collect();
+		assert_eq!(lines[0], format!("Memory: 00100-00104 (4 bytes) in {}", memory_path.file_name().unwrap().to_string_lossy()));
+		assert_eq!(lines[1], "AX=4C00 BX=0000 CX=0000 DX=0000 SI=0000 DI=0000 BP=0000 SP=0000");
+		assert_eq!(lines[2], "CS=1234 IP=0000 DS=0000 ES=0000 SS=0000");
+		assert_eq!(lines[3], "Flags: CF=1 PF=0 AF=0 ZF=1 SF=0 TF=0 IF=0 DF=0 OF=0");
+		assert_eq!(lines[4], "Instructions run: 0");
+
+		assert_eq!(std::fs::read(dump_memory(&machine, &dir_path, None).unwrap()).unwrap().len(), 0x1000);
+		assert!(dump_memory(&machine, &dir_path, Some(0xff0 .. 0x1010)).is_err());
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+}
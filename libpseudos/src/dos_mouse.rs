@@ -0,0 +1,218 @@
+// The state of a mouse driver (int 33h) and its text mode cursor. Positions are in the driver's
+// virtual screen of 640x200, which text modes map to the character grid in 8x8 cells (16x8 with 40
+// columns).
+// http://stanislavs.org/helppc/int_33.html
+//
+// A real driver draws the text cursor by changing the cell under it in video memory, and puts the
+// cell back when the cursor moves or is hidden. Here the cursor is only put on the composed frame
+// that's drawn, and video memory is never touched, so a program reading the screen under the
+// cursor always gets back what it wrote.
+
+pub const MOUSE_VIRTUAL_WIDTH: u16 = 640;
+pub const MOUSE_VIRTUAL_HEIGHT: u16 = 200;
+pub const MOUSE_BUTTON_COUNT: u16 = 2;
+
+// The default text cursor keeps the character and inverts the colours.
+const DEFAULT_SCREEN_MASK: u16 = 0x77ff;
+const DEFAULT_CURSOR_MASK: u16 = 0x7700;
+
+/// An area of the virtual screen, with the edges included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MouseArea {
+	pub left: u16,
+	pub top: u16,
+	pub right: u16,
+	pub bottom: u16,
+}
+
+impl MouseArea {
+	/// Makes an area from two corners, which can be given either way round.
+	pub fn new(x1: u16, y1: u16, x2: u16, y2: u16) -> MouseArea {
+		MouseArea { left: x1.min(x2), top: y1.min(y2), right: x1.max(x2), bottom: y1.max(y2) }
+	}
+
+	pub fn contains(&self, x: u16, y: u16) -> bool {
+		x >= self.left && x <= self.right && y >= self.top && y <= self.bottom
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct MouseDriver {
+	pub x: u16,
+	pub y: u16,
+	// Bit 0 is the left button and bit 1 the right.
+	pub buttons: u16,
+	// The cursor is shown when this is 0. Hiding takes one away and showing adds one back, up to 0,
+	// so a program that hides twice has to show twice.
+	visibility_counter: i16,
+	// The cursor is hidden while it's in here, until the area is cleared by showing the cursor.
+	exclusion_area: Option<MouseArea>,
+	// Where the cursor can go, which is the whole virtual screen until a program limits it.
+	range: MouseArea,
+	// Each cell under the cursor becomes (cell AND screen mask) XOR cursor mask, with the attribute
+	// in the high byte and the character in the low byte.
+	screen_mask: u16,
+	cursor_mask: u16,
+}
+
+impl Default for MouseDriver {
+	fn default() -> MouseDriver {
+		MouseDriver::new()
+	}
+}
+
+impl MouseDriver {
+	pub fn new() -> MouseDriver {
+		MouseDriver {
+			x: MOUSE_VIRTUAL_WIDTH / 2,
+			y: MOUSE_VIRTUAL_HEIGHT / 2,
+			buttons: 0,
+			visibility_counter: -1,
+			exclusion_area: None,
+			range: MouseArea::new(0, 0, MOUSE_VIRTUAL_WIDTH - 1, MOUSE_VIRTUAL_HEIGHT - 1),
+			screen_mask: DEFAULT_SCREEN_MASK,
+			cursor_mask: DEFAULT_CURSOR_MASK,
+		}
+	}
+
+	/// Puts the driver back how it starts (int 33h AX=0000h): the cursor is hidden in the middle of
+	/// the screen with the default masks.
+	pub fn reset(&mut self) {
+		let buttons = self.buttons;
+		*self = MouseDriver::new();
+		// The buttons are still held down however the driver is set up.
+		self.buttons = buttons;
+	}
+
+	/// Shows the cursor if it has been shown as many times as it was hidden, and clears the
+	/// exclusion area.
+	pub fn show_cursor(&mut self) {
+		self.visibility_counter = (self.visibility_counter + 1).min(0);
+		self.exclusion_area = None;
+	}
+
+	pub fn hide_cursor(&mut self) {
+		self.visibility_counter = self.visibility_counter.saturating_sub(1);
+	}
+
+	/// Whether the cursor is drawn where it is now.
+	pub fn is_cursor_visible(&self) -> bool {
+		self.visibility_counter == 0 && !matches!(self.exclusion_area, Some(area) if area.contains(self.x, self.y))
+	}
+
+	/// Moves the cursor, keeping it in its range.
+	pub fn set_position(&mut self, x: u16, y: u16) {
+		self.x = x.clamp(self.range.left, self.range.right);
+		self.y = y.clamp(self.range.top, self.range.bottom);
+	}
+
+	/// Limits the cursor to between two columns of the virtual screen (int 33h AX=0007h), which
+	/// can be given either way round, and moves it into the range.
+	pub fn set_x_range(&mut self, x1: u16, x2: u16) {
+		self.range = MouseArea::new(x1, self.range.top, x2, self.range.bottom);
+		self.set_position(self.x, self.y);
+	}
+
+	/// Limits the cursor to between two rows of the virtual screen (int 33h AX=0008h).
+	pub fn set_y_range(&mut self, y1: u16, y2: u16) {
+		self.range = MouseArea::new(self.range.left, y1, self.range.right, y2);
+		self.set_position(self.x, self.y);
+	}
+
+	pub fn set_exclusion_area(&mut self, area: MouseArea) {
+		self.exclusion_area = Some(area);
+	}
+
+	/// Sets the masks of the software text cursor (int 33h AX=000Ah with BX=0).
+	pub fn set_text_cursor_masks(&mut self, screen_mask: u16, cursor_mask: u16) {
+		self.screen_mask = screen_mask;
+		self.cursor_mask = cursor_mask;
+	}
+
+	/// The column and row of the character cell the cursor is over.
+	pub fn text_cell(&self, column_count: u16) -> (u16, u16) {
+		let cell_width = MOUSE_VIRTUAL_WIDTH / column_count.max(1);
+		(self.x / cell_width.max(1), self.y / 8)
+	}
+
+	/// Puts the cursor on a copy of a text page, given as character and attribute byte pairs, if
+	/// it's visible.
+	pub fn compose_text_cursor(&self, text: &mut [u8], column_count: u16) {
+		if !self.is_cursor_visible() {
+			return;
+		}
+		let (column, row) = self.text_cell(column_count);
+		if column >= column_count {
+			return;
+		}
+		let cell_index = (row as usize * column_count as usize + column as usize) * 2;
+		if let Some(cell) = text.get_mut(cell_index .. cell_index + 2) {
+			let cell_value = ((cell[1] as u16) << 8) | cell[0] as u16;
+			let cursor_value = (cell_value & self.screen_mask) ^ self.cursor_mask;
+			cell[0] = cursor_value as u8;
+			cell[1] = (cursor_value >> 8) as u8;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test] fn test_text_cursor() {
+		let mut mouse = MouseDriver::new();
+		assert!(!mouse.is_cursor_visible());
+		mouse.show_cursor();
+		assert!(mouse.is_cursor_visible());
+		// Hiding twice needs showing twice.
+		mouse.hide_cursor();
+		mouse.hide_cursor();
+		mouse.show_cursor();
+		assert!(!mouse.is_cursor_visible());
+		mouse.show_cursor();
+		assert!(mouse.is_cursor_visible());
+		// Showing more than hiding doesn't make the counter go above shown.
+		mouse.show_cursor();
+		mouse.hide_cursor();
+		assert!(!mouse.is_cursor_visible());
+		mouse.show_cursor();
+
+		// The cursor hides in the exclusion area, until showing clears it.
+		mouse.set_exclusion_area(MouseArea::new(100, 50, 0, 0));
+		mouse.set_position(16, 8);
+		assert!(!mouse.is_cursor_visible());
+		mouse.set_position(200, 8);
+		assert!(mouse.is_cursor_visible());
+		mouse.set_position(16, 8);
+		mouse.show_cursor();
+		assert!(mouse.is_cursor_visible());
+
+		// Virtual (16, 8) is column 2, row 1, and the default masks invert the colours.
+		let mut text = vec![0; 80 * 25 * 2];
+		text[(80 + 2) * 2 .. (80 + 2) * 2 + 2].copy_from_slice(&[b'A', 0x1e]);
+		mouse.compose_text_cursor(&mut text, 80);
+		assert_eq!(&text[(80 + 2) * 2 .. (80 + 2) * 2 + 2], &[b'A', 0x61]);
+		assert_eq!(mouse.text_cell(40), (1, 1));
+
+		mouse.set_text_cursor_masks(0x0000, 0x4f18);
+		mouse.set_position(MOUSE_VIRTUAL_WIDTH, MOUSE_VIRTUAL_HEIGHT);
+		mouse.compose_text_cursor(&mut text, 80);
+		assert_eq!(&text[(24 * 80 + 79) * 2 ..], &[0x18, 0x4f]);
+	}
+
+	#[test] fn test_range() {
+		let mut mouse = MouseDriver::new();
+		mouse.set_position(600, 150);
+		// The cursor is moved into a new range, which can be given either way round.
+		mouse.set_x_range(300, 100);
+		assert_eq!((mouse.x, mouse.y), (300, 150));
+		mouse.set_y_range(8, 64);
+		assert_eq!((mouse.x, mouse.y), (300, 64));
+		mouse.set_position(0, 0);
+		assert_eq!((mouse.x, mouse.y), (100, 8));
+		// Resetting puts the whole screen back.
+		mouse.reset();
+		mouse.set_position(MOUSE_VIRTUAL_WIDTH, 0);
+		assert_eq!((mouse.x, mouse.y), (MOUSE_VIRTUAL_WIDTH - 1, 0));
+	}
+}
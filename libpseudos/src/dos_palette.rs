@@ -0,0 +1,311 @@
+// Turns the 16 text colours into the RGB colours shown on the screen. Besides the standard VGA
+// colours there are presets for players who find them hard to tell apart or hard to read, and a
+// table of the player's own. CGA graphics palettes pick from the same 16 colours, so they go
+// through the same table. Other colours, such as EGA border colours that aren't one of the 16, are
+// shown as they are.
+
+use serde::{Deserialize, Serialize};
+
+pub type Rgb = (u8, u8, u8);
+
+// The colours of a standard VGA text mode.
+// https://en.wikipedia.org/wiki/Color_Graphics_Adapter#Color_palette
+pub const STANDARD_VGA_COLOURS: [Rgb; 16] = [
+	(0x00, 0x00, 0x00), (0x00, 0x00, 0xaa), (0x00, 0xaa, 0x00), (0x00, 0xaa, 0xaa),
+	(0xaa, 0x00, 0x00), (0xaa, 0x00, 0xaa), (0xaa, 0x55, 0x00), (0xaa, 0xaa, 0xaa),
+	(0x55, 0x55, 0x55), (0x55, 0x55, 0xff), (0x55, 0xff, 0x55), (0x55, 0xff, 0xff),
+	(0xff, 0x55, 0x55), (0xff, 0x55, 0xff), (0xff, 0xff, 0x55), (0xff, 0xff, 0xff),
+];
+
+// The dark colours are darker and the bright ones brighter, so bright text stands out more from
+// dark backgrounds.
+const HIGH_CONTRAST_COLOURS: [Rgb; 16] = [
+	(0x00, 0x00, 0x00), (0x00, 0x00, 0x80), (0x00, 0x80, 0x00), (0x00, 0x80, 0x80),
+	(0x80, 0x00, 0x00), (0x80, 0x00, 0x80), (0x80, 0x40, 0x00), (0x80, 0x80, 0x80),
+	(0xa0, 0xa0, 0xa0), (0xa0, 0xa0, 0xff), (0xa0, 0xff, 0xa0), (0xa0, 0xff, 0xff),
+	(0xff, 0xa0, 0xa0), (0xff, 0xa0, 0xff), (0xff, 0xff, 0xa0), (0xff, 0xff, 0xff),
+];
+
+// Without working green cones, red and green only differ in brightness. Green is moved towards
+// blue and red towards orange, so they differ in the blue-yellow direction that's still seen,
+// based on the Okabe-Ito colours. https://jfly.uni-koeln.de/color/
+const DEUTERANOPIA_COLOURS: [Rgb; 16] = [
+	(0x00, 0x00, 0x00), (0x00, 0x00, 0xaa), (0x00, 0x72, 0xb2), (0x56, 0xb4, 0xe9),
+	(0xd5, 0x5e, 0x00), (0xaa, 0x00, 0xaa), (0xaa, 0x80, 0x00), (0xaa, 0xaa, 0xaa),
+	(0x55, 0x55, 0x55), (0x55, 0x55, 0xff), (0x40, 0xa0, 0xff), (0x90, 0xe0, 0xff),
+	(0xe6, 0x9f, 0x00), (0xff, 0x55, 0xff), (0xf0, 0xe4, 0x42), (0xff, 0xff, 0xff),
+];
+
+// Without working red cones, red also looks dark, so the reds are made brighter and yellower as
+// well as the greens bluer.
+const PROTANOPIA_COLOURS: [Rgb; 16] = [
+	(0x00, 0x00, 0x00), (0x00, 0x00, 0xaa), (0x00, 0x72, 0xb2), (0x56, 0xb4, 0xe9),
+	(0xe6, 0x9f, 0x00), (0xaa, 0x00, 0xaa), (0xaa, 0x80, 0x00), (0xaa, 0xaa, 0xaa),
+	(0x55, 0x55, 0x55), (0x55, 0x55, 0xff), (0x40, 0xa0, 0xff), (0x90, 0xe0, 0xff),
+	(0xff, 0xd0, 0x40), (0xff, 0x55, 0xff), (0xf0, 0xf0, 0xa0), (0xff, 0xff, 0xff),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PalettePreset {
+	StandardVga,
+	HighContrast,
+	Deuteranopia,
+	Protanopia,
+	/// Each colour becomes a grey as bright as it is.
+	Monochrome,
+	/// The table in PaletteTransform::custom_colours.
+	Custom,
+}
+
+const PRESET_NAMES: &[(PalettePreset, &str)] = &[
+	(PalettePreset::StandardVga, "standard"),
+	(PalettePreset::HighContrast, "high-contrast"),
+	(PalettePreset::Deuteranopia, "deuteranopia"),
+	(PalettePreset::Protanopia, "protanopia"),
+	(PalettePreset::Monochrome, "monochrome"),
+	(PalettePreset::Custom, "custom"),
+];
+
+impl PalettePreset {
+	pub fn name(self) -> &'static str {
+		PRESET_NAMES.iter().find(|(preset, _)| *preset == self).unwrap().1
+	}
+
+	pub fn from_name(name: &str) -> Option<PalettePreset> {
+		PRESET_NAMES.iter().find(|(_, preset_name)| preset_name.eq_ignore_ascii_case(name)).map(|(preset, _)| *preset)
+	}
+}
+
+// How bright a colour looks, from the Rec. 601 luma weights.
+fn intensity((r, g, b): Rgb) -> u8 {
+	((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+}
+
+/// The colours of an attribute byte in a text mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextColours {
+	pub fore: Rgb,
+	pub back: Rgb,
+	/// Whether the character blinks, which takes the top bit of the background colour while
+	/// blinking is enabled.
+	pub blinking: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaletteTransform {
+	pub preset: PalettePreset,
+	/// The player's own colours, used by PalettePreset::Custom.
+	pub custom_colours: Option<[Rgb; 16]>,
+}
+
+impl Default for PaletteTransform {
+	fn default() -> PaletteTransform {
+		PaletteTransform {
+			preset: PalettePreset::StandardVga,
+			custom_colours: None,
+		}
+	}
+}
+
+impl PaletteTransform {
+	/// The 16 text colours. The custom preset without a custom table is the standard one.
+	pub fn colours(&self) -> [Rgb; 16] {
+		match self.preset {
+			PalettePreset::StandardVga => STANDARD_VGA_COLOURS,
+			PalettePreset::HighContrast => HIGH_CONTRAST_COLOURS,
+			PalettePreset::Deuteranopia => DEUTERANOPIA_COLOURS,
+			PalettePreset::Protanopia => PROTANOPIA_COLOURS,
+			PalettePreset::Monochrome => {
+				let mut colours = STANDARD_VGA_COLOURS;
+				for colour in colours.iter_mut() {
+					let grey = intensity(*colour);
+					*colour = (grey, grey, grey);
+				}
+				colours
+			}
+			PalettePreset::Custom => self.custom_colours.unwrap_or(STANDARD_VGA_COLOURS),
+		}
+	}
+
+	/// The RGB colour of one of the 16 text colours.
+	pub fn colour_to_rgb(&self, colour: u8) -> Rgb {
+		self.colours()[(colour & 0x0f) as usize]
+	}
+
+	pub fn attribute_colours(&self, attribute: u8, blink_enabled: bool) -> TextColours {
+		let mut back = attribute >> 4;
+		let blinking = blink_enabled && back >= 8;
+		if blinking {
+			back -= 8;
+		}
+		TextColours {
+			fore: self.colour_to_rgb(attribute & 0x0f),
+			back: self.colour_to_rgb(back),
+			blinking,
+		}
+	}
+
+	/// The colour of a 6 bit EGA colour, as the palette registers and border colour have, where
+	/// bits 0 to 2 are blue, green and red at two thirds brightness, and bits 3 to 5 add the other
+	/// third. The ones that are one of the 16 text colours go through the preset, and the rest are
+	/// left as they are.
+	pub fn ega_colour_to_rgb(&self, colour: u8) -> Rgb {
+		let channel = |primary_bit: u8| (colour >> primary_bit & 1) * 0xaa + (colour >> (primary_bit + 3) & 1) * 0x55;
+		let rgb = (channel(2), channel(1), channel(0));
+		match STANDARD_VGA_COLOURS.iter().position(|&standard| standard == rgb) {
+			Some(index) => self.colours()[index],
+			None => rgb,
+		}
+	}
+
+	/// Moves on to the next preset, for the hotkey that cycles through them. The custom one is
+	/// skipped if there's no custom table.
+	pub fn cycle_preset(&mut self) {
+		let mut preset_index = PRESET_NAMES.iter().position(|(preset, _)| *preset == self.preset).unwrap();
+		loop {
+			preset_index = (preset_index + 1) % PRESET_NAMES.len();
+			self.preset = PRESET_NAMES[preset_index].0;
+			if self.preset != PalettePreset::Custom || self.custom_colours.is_some() {
+				break;
+			}
+		}
+	}
+
+	/// Reads a palette config, where each line is one of:
+	/// - `preset NAME`, where NAME is standard, high-contrast, deuteranopia, protanopia, monochrome
+	///   or custom.
+	/// - `colour INDEX RRGGBB`, which sets one of the custom colours, with the index and colour in
+	///   hex. Custom colours that aren't set are the standard ones.
+	///
+	/// Blank lines and lines starting with # are skipped. Errors say where they are with the name.
+	pub fn parse(text: &str, name: &str) -> Result<PaletteTransform, String> {
+		let mut transform = PaletteTransform::default();
+		for (line_index, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let location = format!("{}:{}", name, line_index + 1);
+			let parts: Vec<&str> = line.split_whitespace().collect();
+			match parts[..] {
+				["preset", preset_name] => {
+					transform.preset = PalettePreset::from_name(preset_name).ok_or_else(|| format!("{}: Unknown preset \"{}\"", location, preset_name))?;
+				}
+				["colour", index, colour] => {
+					let index = usize::from_str_radix(index, 16).ok().filter(|index| *index < 16).ok_or_else(|| format!("{}: The colour index must be 0 to F", location))?;
+					let colour = match u32::from_str_radix(colour, 16) {
+						Ok(colour) if colour <= 0xffffff => colour,
+						_ => return Err(format!("{}: Expected a colour like FF8000", location)),
+					};
+					let custom_colours = transform.custom_colours.get_or_insert(STANDARD_VGA_COLOURS);
+					custom_colours[index] = ((colour >> 16) as u8, (colour >> 8) as u8, colour as u8);
+				}
+				_ => return Err(format!("{}: Expected \"preset NAME\" or \"colour INDEX RRGGBB\"", location)),
+			}
+		}
+		Ok(transform)
+	}
+
+	/// Writes the transform in the form parse reads.
+	pub fn to_config_text(&self) -> String {
+		let mut text = format!("preset {}\n", self.preset.name());
+		if let Some(ref custom_colours) = self.custom_colours {
+			for (index, (r, g, b)) in custom_colours.iter().enumerate() {
+				text += &format!("colour {:X} {:02X}{:02X}{:02X}\n", index, r, g, b);
+			}
+		}
+		text
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn with_preset(preset: PalettePreset) -> PaletteTransform {
+		PaletteTransform { preset, ..PaletteTransform::default() }
+	}
+
+	#[test] fn test_attribute_colours() {
+		// Light grey on blue, and bright red on green.
+		let standard = with_preset(PalettePreset::StandardVga);
+		assert_eq!(standard.attribute_colours(0x17, true), TextColours { fore: (0xaa, 0xaa, 0xaa), back: (0x00, 0x00, 0xaa), blinking: false });
+		assert_eq!(standard.attribute_colours(0x2c, true), TextColours { fore: (0xff, 0x55, 0x55), back: (0x00, 0xaa, 0x00), blinking: false });
+		// The top bit of the background blinks, or gives a bright background.
+		assert_eq!(standard.attribute_colours(0xcf, true), TextColours { fore: (0xff, 0xff, 0xff), back: (0xaa, 0x00, 0x00), blinking: true });
+		assert_eq!(standard.attribute_colours(0xcf, false), TextColours { fore: (0xff, 0xff, 0xff), back: (0xff, 0x55, 0x55), blinking: false });
+
+		let high_contrast = with_preset(PalettePreset::HighContrast);
+		assert_eq!(high_contrast.attribute_colours(0x07, true), TextColours { fore: (0x80, 0x80, 0x80), back: (0x00, 0x00, 0x00), blinking: false });
+		assert_eq!(high_contrast.attribute_colours(0x18, true), TextColours { fore: (0xa0, 0xa0, 0xa0), back: (0x00, 0x00, 0x80), blinking: false });
+
+		// Red and green end up apart in blue, which doesn't depend on the missing cones.
+		for preset in [PalettePreset::Deuteranopia, PalettePreset::Protanopia].iter() {
+			let transform = with_preset(*preset);
+			let colours = transform.attribute_colours(0x24, true);
+			assert!(colours.back.2 as i32 - colours.fore.2 as i32 >= 0x80, "{:?}", preset);
+			let bright_colours = transform.attribute_colours(0xac, false);
+			assert!(bright_colours.back.2 as i32 - bright_colours.fore.2 as i32 >= 0x80, "{:?}", preset);
+		}
+		assert_eq!(with_preset(PalettePreset::Deuteranopia).attribute_colours(0x24, true).fore, (0xd5, 0x5e, 0x00));
+		assert_eq!(with_preset(PalettePreset::Protanopia).attribute_colours(0x24, true).fore, (0xe6, 0x9f, 0x00));
+
+		let monochrome = with_preset(PalettePreset::Monochrome);
+		assert_eq!(monochrome.attribute_colours(0x1e, true), TextColours { fore: (0xeb, 0xeb, 0xeb), back: (0x13, 0x13, 0x13), blinking: false });
+
+		// Without a custom table, the custom preset is the standard one.
+		let mut custom = with_preset(PalettePreset::Custom);
+		assert_eq!(custom.colours(), STANDARD_VGA_COLOURS);
+		let mut custom_colours = STANDARD_VGA_COLOURS;
+		custom_colours[0] = (0x10, 0x20, 0x30);
+		custom.custom_colours = Some(custom_colours);
+		assert_eq!(custom.attribute_colours(0x0f, true).back, (0x10, 0x20, 0x30));
+	}
+
+	#[test] fn test_ega_colours() {
+		let standard = PaletteTransform::default();
+		assert_eq!(standard.ega_colour_to_rgb(0x00), (0x00, 0x00, 0x00));
+		assert_eq!(standard.ega_colour_to_rgb(0x01), (0x00, 0x00, 0xaa));
+		assert_eq!(standard.ega_colour_to_rgb(0x14), (0xaa, 0x55, 0x00));
+		assert_eq!(standard.ega_colour_to_rgb(0x3c), (0xff, 0x55, 0x55));
+		assert_eq!(standard.ega_colour_to_rgb(0x06), (0xaa, 0xaa, 0x00));
+		// The text colours follow the preset.
+		let high_contrast = with_preset(PalettePreset::HighContrast);
+		assert_eq!(high_contrast.ega_colour_to_rgb(0x3c), HIGH_CONTRAST_COLOURS[12]);
+		assert_eq!(high_contrast.ega_colour_to_rgb(0x06), (0xaa, 0xaa, 0x00));
+	}
+
+	#[test] fn test_cycle_preset() {
+		let mut transform = PaletteTransform::default();
+		let mut presets = vec![];
+		for _ in 0 .. 6 {
+			transform.cycle_preset();
+			presets.push(transform.preset);
+		}
+		assert_eq!(presets, vec![
+			PalettePreset::HighContrast, PalettePreset::Deuteranopia, PalettePreset::Protanopia,
+			PalettePreset::Monochrome, PalettePreset::StandardVga, PalettePreset::HighContrast,
+		]);
+		transform.preset = PalettePreset::Monochrome;
+		transform.custom_colours = Some(STANDARD_VGA_COLOURS);
+		transform.cycle_preset();
+		assert_eq!(transform.preset, PalettePreset::Custom);
+	}
+
+	#[test] fn test_config_round_trip() {
+		let transform = PaletteTransform::parse("# Brighter blue\npreset custom\ncolour 1 0000FF\n", "test.pal").unwrap();
+		let mut custom_colours = STANDARD_VGA_COLOURS;
+		custom_colours[1] = (0x00, 0x00, 0xff);
+		assert_eq!(transform, PaletteTransform { preset: PalettePreset::Custom, custom_colours: Some(custom_colours) });
+		assert_eq!(PaletteTransform::parse(&transform.to_config_text(), "test.pal"), Ok(transform));
+		for &(preset, _) in PRESET_NAMES {
+			let transform = with_preset(preset);
+			assert_eq!(PaletteTransform::parse(&transform.to_config_text(), "test.pal"), Ok(transform));
+		}
+
+		assert_eq!(PaletteTransform::parse("preset sepia", "test.pal"), Err("test.pal:1: Unknown preset \"sepia\"".to_string()));
+		assert_eq!(PaletteTransform::parse("\ncolour 10 000000", "test.pal"), Err("test.pal:2: The colour index must be 0 to F".to_string()));
+		assert_eq!(PaletteTransform::parse("colour 1 1000000", "test.pal"), Err("test.pal:1: Expected a colour like FF8000".to_string()));
+	}
+}
@@ -0,0 +1,227 @@
+// Patch files, for applying fixes and cheats to a program when it's loaded. Each line of a patch
+// file is an entry that replaces some bytes, as long as the bytes there are the ones expected:
+//
+//   # Comments start with a hash.
+//   file 1A2B: 48 65 -> 4A 6F
+//   mem 1234:0010: 05 -> 09
+//   mem-every-frame 1234:0020: 03 00 -> 63 00
+//
+// All the numbers are hex. "file" entries change the program's file data at an offset before it's
+// loaded, so an EXE's offsets count from the start of the MZ header, and the bytes are patched
+// before any relocation. "mem" entries change the machine's memory at SEG:OFF once the program has
+// been loaded. "mem-every-frame" entries are checked like "mem" entries when the program is
+// loaded, then the new bytes are written again by the runner at the start of every frame, to keep
+// a value frozen. The runner writes them between frames, never while the CPU is mid-step.
+
+use crate::dos_file_system::DosFileSystem;
+use crate::exe_loader::load_program_into_machine;
+use crate::machine::Machine;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchTarget {
+	/// An offset into the program's file data.
+	File(usize),
+	/// A SEG:OFF address, patched once after the program is loaded.
+	Memory(u16, u16),
+	/// A SEG:OFF address, patched after the program is loaded and again at the start of every frame.
+	MemoryEveryFrame(u16, u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchEntry {
+	pub target: PatchTarget,
+	pub old_bytes: Vec<u8>,
+	pub new_bytes: Vec<u8>,
+	/// Where the entry came from, like "cheats.pch:3", so errors can name it.
+	pub location: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PatchSet {
+	pub entries: Vec<PatchEntry>,
+}
+
+fn parse_hex(text: &str) -> Result<u32, String> {
+	u32::from_str_radix(text.trim(), 16).map_err(|_| format!("\"{}\" isn't a hex number", text.trim()))
+}
+
+fn parse_segment_offset(text: &str) -> Result<(u16, u16), String> {
+	let colon_pos = text.find(':').ok_or_else(|| format!("\"{}\" isn't a SEG:OFF address", text))?;
+	let segment = parse_hex(&text[..colon_pos])?;
+	let offset = parse_hex(&text[colon_pos + 1..])?;
+	if segment > 0xffff || offset > 0xffff {
+		return Err(format!("\"{}\" isn't a SEG:OFF address", text));
+	}
+	Ok((segment as u16, offset as u16))
+}
+
+fn parse_bytes(text: &str) -> Result<Vec<u8>, String> {
+	text.split_whitespace().map(|byte_text| {
+		u8::from_str_radix(byte_text, 16).map_err(|_| format!("\"{}\" isn't a hex byte", byte_text))
+	}).collect()
+}
+
+fn parse_entry(line: &str, location: String) -> Result<PatchEntry, String> {
+	let (kind, rest) = match line.find(char::is_whitespace) {
+		Some(space_pos) => (&line[..space_pos], &line[space_pos..]),
+		None => (line, ""),
+	};
+	let arrow_pos = rest.find("->").ok_or("Expected \"LOCATION: OLD -> NEW\"")?;
+	// The old bytes don't have colons in them, so the last colon ends the location.
+	let target_and_old = &rest[..arrow_pos];
+	let colon_pos = target_and_old.rfind(':').ok_or("Expected a colon after the location")?;
+	let target_text = target_and_old[..colon_pos].trim();
+	let target = match kind {
+		"file" => PatchTarget::File(parse_hex(target_text)? as usize),
+		"mem" => {
+			let (segment, offset) = parse_segment_offset(target_text)?;
+			PatchTarget::Memory(segment, offset)
+		}
+		"mem-every-frame" => {
+			let (segment, offset) = parse_segment_offset(target_text)?;
+			PatchTarget::MemoryEveryFrame(segment, offset)
+		}
+		_ => return Err(format!("Unknown patch kind \"{}\"", kind)),
+	};
+	let old_bytes = parse_bytes(&target_and_old[colon_pos + 1..])?;
+	let new_bytes = parse_bytes(&rest[arrow_pos + 2..])?;
+	if old_bytes.is_empty() {
+		return Err("There are no bytes to patch".to_string());
+	}
+	if old_bytes.len() != new_bytes.len() {
+		return Err(format!("There are {} old bytes but {} new bytes", old_bytes.len(), new_bytes.len()));
+	}
+	Ok(PatchEntry { target, old_bytes, new_bytes, location })
+}
+
+fn memory_addr(segment: u16, offset: u16) -> usize {
+	((segment as usize) << 4) + offset as usize
+}
+
+// Checks the old bytes are at the start of the data, then replaces them.
+fn patch_bytes(entry: &PatchEntry, data: &mut [u8], start: usize) -> Result<(), String> {
+	let end = start + entry.old_bytes.len();
+	if end > data.len() {
+		return Err(format!("{}: The patch goes past the end, at 0x{:x}", entry.location, data.len()));
+	}
+	if data[start..end] != entry.old_bytes[..] {
+		return Err(format!("{}: Expected {:02x?} but found {:02x?}", entry.location, entry.old_bytes, &data[start..end]));
+	}
+	data[start..end].copy_from_slice(&entry.new_bytes);
+	Ok(())
+}
+
+impl PatchSet {
+	/// Parses the text of a patch file. The name is used to say where entries came from in errors.
+	pub fn parse(text: &str, name: &str) -> Result<PatchSet, String> {
+		let mut entries = vec![];
+		for (line_index, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let location = format!("{}:{}", name, line_index + 1);
+			let entry = parse_entry(line, location.clone()).map_err(|err| format!("{}: {}", location, err))?;
+			entries.push(entry);
+		}
+		Ok(PatchSet { entries })
+	}
+
+	/// Adds the entries from another set after these ones, for when several patch files are used.
+	pub fn extend(&mut self, other: PatchSet) {
+		self.entries.extend(other.entries);
+	}
+
+	/// Applies the "file" entries to a program's file data before it's loaded.
+	pub fn apply_to_program_data(&self, program_data: &mut [u8]) -> Result<(), String> {
+		for entry in &self.entries {
+			if let PatchTarget::File(offset) = entry.target {
+				patch_bytes(entry, program_data, offset)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Applies the "mem" and "mem-every-frame" entries to the machine's memory once the program has
+	/// been loaded.
+	pub fn apply_to_memory(&self, machine: &mut dyn Machine) -> Result<(), String> {
+		for entry in &self.entries {
+			match entry.target {
+				PatchTarget::Memory(segment, offset) | PatchTarget::MemoryEveryFrame(segment, offset) => {
+					patch_bytes(entry, machine.memory_mut(), memory_addr(segment, offset))?;
+				}
+				PatchTarget::File(_) => {}
+			}
+		}
+		Ok(())
+	}
+
+	/// Writes the new bytes of the "mem-every-frame" entries again. The runner calls this at the
+	/// start of every frame. The old bytes aren't checked, since the program changes them.
+	pub fn apply_every_frame(&self, machine: &mut dyn Machine) {
+		for entry in &self.entries {
+			if let PatchTarget::MemoryEveryFrame(segment, offset) = entry.target {
+				machine.insert_contiguous_bytes(&entry.new_bytes, memory_addr(segment, offset));
+			}
+		}
+	}
+}
+
+/// Loads a program like load_program_into_machine, with the patches applied to its file data
+/// before it's loaded, and to memory afterwards.
+pub fn load_patched_program_into_machine(machine: &mut dyn Machine, program_data: &[u8], command_line_tail: &[u8], environment_block: &[u8], file_system: &dyn DosFileSystem, patches: &PatchSet) -> Result<(), String> {
+	let mut program_data = program_data.to_vec();
+	patches.apply_to_program_data(&mut program_data)?;
+	load_program_into_machine(machine, &program_data, command_line_tail, environment_block, file_system).map_err(|err| err.to_string())?;
+	patches.apply_to_memory(machine)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_environment::build_environment_block;
+	use crate::dos_event_handler::tests::make_test_handler;
+	use crate::dos_frame_slicing::{FrameRun, SliceEnd, OPCODES_PER_STANDARD_TICK};
+
+	// mov dx, message; mov ah, 09h; int 21h; loop: inc byte [counter]; jmp loop
+	// message: db "Hello$"
+	// counter: db 0
+	const COUNTER_PROGRAM: &[u8] = &[0xba, 0x0d, 0x01, 0xb4, 0x09, 0xcd, 0x21, 0xfe, 0x06, 0x13, 0x01, 0xeb, 0xfa, b'H', b'e', b'l', b'l', b'o', b'$', 0x00];
+	const COUNTER_ADDR: usize = 0x1000 + 0x113;
+
+	#[test] fn test_patch_program() {
+		let patches = PatchSet::parse("# Say Jello instead.\nfile D: 48 -> 4A\n\nmem-every-frame 100:113: 00 -> 63\n", "test.pch").unwrap();
+		assert_eq!(patches.entries.len(), 2);
+		assert_eq!(patches.entries[1].target, PatchTarget::MemoryEveryFrame(0x100, 0x113));
+
+		let (mut handler, mut machine) = make_test_handler();
+		load_patched_program_into_machine(&mut machine, COUNTER_PROGRAM, b"", &build_environment_block(&[], b"C:\\TEST.COM"), &*handler.file_system, &patches).unwrap();
+		handler.init_machine(&mut machine);
+		assert_eq!(machine.memory[COUNTER_ADDR], 0x63);
+		for _ in 0 .. 3 {
+			patches.apply_every_frame(&mut machine);
+			assert_eq!(machine.memory[COUNTER_ADDR], 0x63);
+			let mut frame_run = FrameRun::new(OPCODES_PER_STANDARD_TICK, 1, 4);
+			while frame_run.run_slice(&mut machine, &mut handler).unwrap() == SliceEnd::Slice {}
+			// The program keeps counting within each frame, from the frozen value.
+			assert_ne!(machine.memory[COUNTER_ADDR], 0x63);
+		}
+		assert_eq!(&handler.get_screen_text(&machine)[0][..5], b"Jello");
+	}
+
+	#[test] fn test_patch_mismatch() {
+		let patches = PatchSet::parse("file D: 48 65 -> 4A 6F\nfile E: 00 -> 01\n", "test.pch").unwrap();
+		let mut program_data = COUNTER_PROGRAM.to_vec();
+		assert_eq!(patches.apply_to_program_data(&mut program_data), Err("test.pch:2: Expected [00] but found [6f]".to_string()));
+
+		let patches = PatchSet::parse("mem 100:113: 05 -> 09\n", "test.pch").unwrap();
+		let (handler, mut machine) = make_test_handler();
+		let result = load_patched_program_into_machine(&mut machine, COUNTER_PROGRAM, b"", &build_environment_block(&[], b"C:\\TEST.COM"), &*handler.file_system, &patches);
+		assert_eq!(result, Err("test.pch:1: Expected [05] but found [00]".to_string()));
+
+		assert_eq!(PatchSet::parse("\nfile D: 48 65 -> 4A\n", "bad.pch"), Err("bad.pch:2: There are 2 old bytes but 1 new bytes".to_string()));
+		assert_eq!(PatchSet::parse("poke D: 48 -> 4A\n", "bad.pch"), Err("bad.pch:1: Unknown patch kind \"poke\"".to_string()));
+		assert_eq!(PatchSet::parse("file D 48 -> 4A\n", "bad.pch"), Err("bad.pch:1: Expected a colon after the location".to_string()));
+	}
+}
@@ -0,0 +1,122 @@
+// Keeps the text a program writes through the DOS and BIOS teletype output functions, for a log
+// pane or debugger in the frontend. Unlike the screen, lines that have scrolled off are still there,
+// up to a number of lines after which the oldest are dropped.
+
+use std::collections::VecDeque;
+
+use crate::dos_code_page::cp437_to_string;
+
+// The longest a line gets before the rest goes on the next line, like text wrapping at the edge of
+// the screen. A program that keeps writing without a line feed doesn't grow one line forever.
+pub const SCROLLBACK_MAX_LINE_CHARS: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct Scrollback {
+	capacity_lines: usize,
+	// The lines that have ended with a line feed, oldest first.
+	lines: VecDeque<Vec<u8>>,
+	// The line being written.
+	current_line: Vec<u8>,
+	// Where in the current line the next character goes, which is before the end after a carriage
+	// return or backspace.
+	column: usize,
+}
+
+impl Scrollback {
+	/// Keeps up to capacity_lines finished lines, as well as the one being written.
+	pub fn new(capacity_lines: usize) -> Scrollback {
+		Scrollback {
+			capacity_lines,
+			lines: VecDeque::new(),
+			current_line: vec![],
+			column: 0,
+		}
+	}
+
+	/// Adds a character as it's written, the way it would change the screen. A line feed ends the
+	/// line, carriage return goes back to the start of it and backspace goes back a character, so
+	/// what's written after them replaces what was there. Bell doesn't add anything.
+	pub fn write_char(&mut self, c: u8) {
+		match c {
+			b'\n' => self.end_line(),
+			0x08 => self.column = self.column.saturating_sub(1),
+			b'\r' => self.column = 0,
+			0x07 => {}
+			_ => {
+				if self.column == SCROLLBACK_MAX_LINE_CHARS {
+					self.end_line();
+				}
+				if self.column < self.current_line.len() {
+					self.current_line[self.column] = c;
+				} else {
+					self.current_line.push(c);
+				}
+				self.column += 1;
+			}
+		}
+	}
+
+	fn end_line(&mut self) {
+		let line = std::mem::take(&mut self.current_line);
+		self.column = 0;
+		if self.capacity_lines > 0 {
+			if self.lines.len() == self.capacity_lines {
+				self.lines.pop_front();
+			}
+			self.lines.push_back(line);
+		}
+	}
+
+	/// The finished lines, oldest first, and then the line being written if it has anything on it.
+	pub fn lines(&self) -> Vec<&[u8]> {
+		let mut lines: Vec<&[u8]> = self.lines.iter().map(|line| &line[..]).collect();
+		if !self.current_line.is_empty() {
+			lines.push(&self.current_line);
+		}
+		lines
+	}
+
+	/// The lines as Unicode, each ending with a line feed.
+	pub fn to_unicode_text(&self) -> String {
+		self.lines().iter().map(|line| cp437_to_string(line) + "\n").collect()
+	}
+
+	pub fn clear(&mut self) {
+		self.lines.clear();
+		self.current_line.clear();
+		self.column = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write_text(scrollback: &mut Scrollback, text: &[u8]) {
+		for &c in text {
+			scrollback.write_char(c);
+		}
+	}
+
+	#[test] fn test_scrollback() {
+		let mut scrollback = Scrollback::new(3);
+		write_text(&mut scrollback, b"One\r\nTwx\x08o\r\n\x07Three");
+		assert_eq!(scrollback.lines(), vec![&b"One"[..], b"Two", b"Three"]);
+		// The oldest lines go once there are too many.
+		write_text(&mut scrollback, b"\r\nFour\r\nFive\r\n");
+		assert_eq!(scrollback.lines(), vec![&b"Three"[..], b"Four", b"Five"]);
+		write_text(&mut scrollback, b"\x01");
+		assert_eq!(scrollback.to_unicode_text(), "Three\nFour\nFive\n☺\n");
+		scrollback.clear();
+		assert!(scrollback.lines().is_empty());
+
+		// A carriage return without a line feed writes over the same line, like a progress counter.
+		write_text(&mut scrollback, b"Loading 10%\rLoading 100%\r\nabcdef\rxy");
+		assert_eq!(scrollback.lines(), vec![&b"Loading 100%"[..], b"xycdef"]);
+
+		// A line that doesn't end carries on in the next one.
+		scrollback.clear();
+		write_text(&mut scrollback, &[b'a'; SCROLLBACK_MAX_LINE_CHARS + 1]);
+		assert_eq!(scrollback.lines(), vec![&[b'a'; SCROLLBACK_MAX_LINE_CHARS][..], b"a"]);
+	}
+}
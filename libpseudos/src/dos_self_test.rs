@@ -0,0 +1,747 @@
+// A self-test that checks the DOS and BIOS functions from inside the emulated machine, the way real
+// programs see them. The test program is a COM file put together by ProgramBuilder below (a tiny
+// assembler for the few instructions it needs), so it always matches the checks the host knows
+// about. It runs every check first, writing 'P' or 'F' for each one into a results table, then
+// prints a PASS or FAIL line for each to the screen with int 21h AH=09h, and exits with the number
+// of checks that failed. The host runs it headlessly and reads the results table out of the
+// program's memory.
+//
+// The checks are meant to be broad rather than deep: each one is a round trip through a group of
+// functions that real programs lean on, so a handler that's broken shows up as a named check.
+
+use crate::dos_drive_table::DosDriveTable;
+use crate::dos_environment::build_environment_block;
+use crate::dos_event_handler::HeadlessStartup;
+use crate::dos_file_system::StandardDosFileSystem;
+use crate::emulator::{Emulator, EmulatorOptions, ProgramImage};
+use crate::exe_loader::PROGRAM_PSP_SEGMENT;
+
+use std::path::Path;
+
+// COM programs are loaded at offset 0x100 of their segment, after the PSP.
+const COM_ORIGIN: u16 = 0x100;
+// Plenty for the waits in the program, but still stops a program that's gone wrong.
+const SELF_TEST_MAX_STEPS: usize = 2_000_000;
+const SELF_TEST_PROGRAM_NAME: &str = "SELFTEST.COM";
+const SELF_TEST_ENVIRONMENT_VAR: (&[u8], &[u8]) = (b"SELFTEST", b"1");
+
+// The register numbers used in instruction encodings.
+const AX: u8 = 0;
+const CX: u8 = 1;
+const DX: u8 = 2;
+const BX: u8 = 3;
+const AH: u8 = 4;
+const BH: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Label(usize);
+
+#[derive(Debug, Clone, Copy)]
+enum LabelTarget {
+	Code(usize),
+	Data(usize),
+}
+
+// A memory operand: a fixed offset in the data segment, or an offset from a label.
+#[derive(Debug, Clone, Copy)]
+enum Addr {
+	Fixed(u16),
+	Label(Label, u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Jump {
+	Always = 0xeb,
+	Zero = 0x74,
+	NotZero = 0x75,
+	Carry = 0x72,
+	NoCarry = 0x73,
+}
+
+impl Jump {
+	fn inverse(self) -> Jump {
+		match self {
+			Jump::Always => panic!("An unconditional jump can't be inverted"),
+			Jump::Zero => Jump::NotZero,
+			Jump::NotZero => Jump::Zero,
+			Jump::Carry => Jump::NoCarry,
+			Jump::NoCarry => Jump::Carry,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+	// A 16-bit address of a label (plus an offset) at a place in the code.
+	Address { at: usize, label: Label, offset: u16 },
+	// The 8-bit displacement of a short jump to a label.
+	ShortJump { at: usize, label: Label },
+}
+
+// Puts together a COM program from instructions and data. The data goes after the code, and
+// labels in either can be used before they're placed.
+#[derive(Debug, Default)]
+struct ProgramBuilder {
+	code: Vec<u8>,
+	data: Vec<u8>,
+	labels: Vec<Option<LabelTarget>>,
+	fixups: Vec<Fixup>,
+}
+
+impl ProgramBuilder {
+	fn new_label(&mut self) -> Label {
+		self.labels.push(None);
+		Label(self.labels.len() - 1)
+	}
+
+	// Places a label at the next instruction.
+	fn place(&mut self, label: Label) {
+		self.labels[label.0] = Some(LabelTarget::Code(self.code.len()));
+	}
+
+	fn place_data(&mut self, label: Label, bytes: &[u8]) {
+		self.labels[label.0] = Some(LabelTarget::Data(self.data.len()));
+		self.data.extend(bytes);
+	}
+
+	fn data(&mut self, bytes: &[u8]) -> Label {
+		let label = self.new_label();
+		self.place_data(label, bytes);
+		label
+	}
+
+	fn reserve(&mut self, byte_count: usize) -> Label {
+		self.data(&vec![0; byte_count])
+	}
+
+	fn emit(&mut self, bytes: &[u8]) {
+		self.code.extend(bytes);
+	}
+
+	fn emit_u16(&mut self, value: u16) {
+		self.emit(&value.to_le_bytes());
+	}
+
+	fn emit_addr(&mut self, addr: Addr) {
+		match addr {
+			Addr::Fixed(offset) => self.emit_u16(offset),
+			Addr::Label(label, offset) => {
+				self.fixups.push(Fixup::Address { at: self.code.len(), label, offset });
+				self.emit_u16(0);
+			}
+		}
+	}
+
+	// mov reg8, value
+	fn mov_r8(&mut self, reg: u8, value: u8) {
+		self.emit(&[0xb0 + reg, value]);
+	}
+
+	// mov reg16, value
+	fn mov_r16(&mut self, reg: u8, value: u16) {
+		self.emit(&[0xb8 + reg]);
+		self.emit_u16(value);
+	}
+
+	// mov reg16, offset label
+	fn mov_r16_label(&mut self, reg: u8, label: Label) {
+		self.emit(&[0xb8 + reg]);
+		self.emit_addr(Addr::Label(label, 0));
+	}
+
+	// mov dest, src (16-bit registers)
+	fn mov_r16_r16(&mut self, dest: u8, src: u8) {
+		self.emit(&[0x89, 0xc0 | src << 3 | dest]);
+	}
+
+	// mov ds, ax
+	fn mov_ds_ax(&mut self) {
+		self.emit(&[0x8e, 0xd8]);
+	}
+
+	// mov es, ax
+	fn mov_es_ax(&mut self) {
+		self.emit(&[0x8e, 0xc0]);
+	}
+
+	// push cs; pop ds
+	fn restore_ds(&mut self) {
+		self.emit(&[0x0e, 0x1f]);
+	}
+
+	// push cs; pop es
+	fn restore_es(&mut self) {
+		self.emit(&[0x0e, 0x07]);
+	}
+
+	// mov al, [addr]
+	fn load_al(&mut self, addr: Addr) {
+		self.emit(&[0xa0]);
+		self.emit_addr(addr);
+	}
+
+	// mov ax, [addr]
+	fn load_ax(&mut self, addr: Addr) {
+		self.emit(&[0xa1]);
+		self.emit_addr(addr);
+	}
+
+	// mov [addr], ax
+	fn store_ax(&mut self, addr: Addr) {
+		self.emit(&[0xa3]);
+		self.emit_addr(addr);
+	}
+
+	// mov byte [addr], value
+	fn store_byte(&mut self, addr: Addr, value: u8) {
+		self.emit(&[0xc6, 0x06]);
+		self.emit_addr(addr);
+		self.emit(&[value]);
+	}
+
+	// inc byte [addr]
+	fn inc_byte(&mut self, addr: Addr) {
+		self.emit(&[0xfe, 0x06]);
+		self.emit_addr(addr);
+	}
+
+	// cmp al, value
+	fn cmp_al(&mut self, value: u8) {
+		self.emit(&[0x3c, value]);
+	}
+
+	// cmp ax, value
+	fn cmp_ax(&mut self, value: u16) {
+		self.emit(&[0x3d]);
+		self.emit_u16(value);
+	}
+
+	// cmp ax, [addr]
+	fn cmp_ax_mem(&mut self, addr: Addr) {
+		self.emit(&[0x3b, 0x06]);
+		self.emit_addr(addr);
+	}
+
+	fn jump(&mut self, jump: Jump, label: Label) {
+		self.emit(&[jump as u8]);
+		self.fixups.push(Fixup::ShortJump { at: self.code.len(), label });
+		self.emit(&[0]);
+	}
+
+	fn int(&mut self, interrupt_index: u8) {
+		self.emit(&[0xcd, interrupt_index]);
+	}
+
+	// mov ah, func; int interrupt_index
+	fn call(&mut self, interrupt_index: u8, func: u8) {
+		self.mov_r8(AH, func);
+		self.int(interrupt_index);
+	}
+
+	// Resolves the labels, and returns the program with the data after the code.
+	fn finish(mut self) -> Vec<u8> {
+		let code_len = self.code.len();
+		let label_offset = |labels: &[Option<LabelTarget>], label: Label| match labels[label.0].expect("Every label should be placed") {
+			LabelTarget::Code(offset) => offset,
+			LabelTarget::Data(offset) => code_len + offset,
+		};
+		for fixup in &self.fixups {
+			match *fixup {
+				Fixup::Address { at, label, offset } => {
+					let addr = (COM_ORIGIN as usize + label_offset(&self.labels, label)) as u16 + offset;
+					self.code[at .. at + 2].copy_from_slice(&addr.to_le_bytes());
+				}
+				Fixup::ShortJump { at, label } => {
+					let displacement = label_offset(&self.labels, label) as isize - (at as isize + 1);
+					assert!(displacement >= i8::MIN as isize && displacement <= i8::MAX as isize, "Short jump out of range");
+					self.code[at] = displacement as i8 as u8;
+				}
+			}
+		}
+		self.code.extend(self.data);
+		self.code
+	}
+}
+
+/// The self-test program, with the names of its checks in the order of its results table.
+#[derive(Debug, Clone)]
+pub struct SelfTestProgram {
+	pub data: Vec<u8>,
+	pub check_names: Vec<&'static str>,
+	/// Where the results table is in the program's segment. Each check has a byte that's 'P' if it
+	/// passed, 'F' if it failed, or 0 if it wasn't reached.
+	pub results_offset: u16,
+}
+
+// Writes the checks, keeping track of which one is being written.
+struct SelfTestAssembler {
+	builder: ProgramBuilder,
+	check_names: Vec<&'static str>,
+	results: Label,
+}
+
+impl SelfTestAssembler {
+	fn result_addr(&self, check_index: usize) -> Addr {
+		Addr::Label(self.results, check_index as u16)
+	}
+
+	// Starts a check, which passes unless one of its fail_if conditions is met.
+	fn begin_check(&mut self, name: &'static str) {
+		self.check_names.push(name);
+		let result_addr = self.result_addr(self.check_names.len() - 1);
+		self.builder.store_byte(result_addr, b'P');
+	}
+
+	// Fails the current check if the flags are as the jump would test for.
+	fn fail_if(&mut self, condition: Jump) {
+		let skip = self.builder.new_label();
+		self.builder.jump(condition.inverse(), skip);
+		let result_addr = self.result_addr(self.check_names.len() - 1);
+		self.builder.store_byte(result_addr, b'F');
+		self.builder.place(skip);
+	}
+
+	// Fails the current check unless the byte at addr in the program's segment is value.
+	fn expect_byte(&mut self, addr: u16, value: u8) {
+		self.builder.load_al(Addr::Fixed(addr));
+		self.expect_al(value);
+	}
+
+	fn expect_al(&mut self, value: u8) {
+		self.builder.cmp_al(value);
+		self.fail_if(Jump::NotZero);
+	}
+
+	fn expect_ax(&mut self, value: u16) {
+		self.builder.cmp_ax(value);
+		self.fail_if(Jump::NotZero);
+	}
+
+	// Fails the current check unless the byte at offset in the segment in AX is value. DS points at
+	// the program again afterwards.
+	fn expect_byte_in_segment_ax(&mut self, offset: u16, value: u8) {
+		self.builder.mov_ds_ax();
+		self.builder.load_al(Addr::Fixed(offset));
+		self.builder.restore_ds();
+		self.expect_al(value);
+	}
+
+	fn expect_screen_char(&mut self, row: u16, column: u16, c: u8) {
+		self.builder.mov_r16(AX, 0xb800);
+		self.expect_byte_in_segment_ax((row * 80 + column) * 2, c);
+	}
+
+	// int 10h AH=03h for page 0, with the cursor position (row and column in DH and DL) put in AX.
+	fn get_cursor_into_ax(&mut self) {
+		self.builder.mov_r8(BH, 0);
+		self.builder.call(0x10, 0x03);
+		self.builder.mov_r16_r16(AX, DX);
+	}
+
+	fn psp_checks(&mut self, environment_block: &[u8]) {
+		// The PSP starts with int 20h, and the command tail is empty.
+		// http://www.ctyme.com/intr/rb-2682.htm
+		self.begin_check("psp fields");
+		self.expect_byte(0x00, 0xcd);
+		self.expect_byte(0x01, 0x20);
+		self.expect_byte(0x80, 0);
+		self.expect_byte(0x81, b'\r');
+
+		self.begin_check("environment block");
+		for (offset, &value) in environment_block.iter().enumerate() {
+			self.builder.load_ax(Addr::Fixed(0x2c));
+			self.expect_byte_in_segment_ax(offset as u16, value);
+		}
+	}
+
+	fn video_checks(&mut self) {
+		// Mode 3 clears the screen and puts the cursor at the top left.
+		self.builder.mov_r16(AX, 0x0003);
+		self.builder.int(0x10);
+
+		self.begin_check("video cursor");
+		self.builder.mov_r8(BH, 0);
+		self.builder.mov_r16(DX, 0x050a);
+		self.builder.call(0x10, 0x02);
+		self.get_cursor_into_ax();
+		self.expect_ax(0x050a);
+
+		self.begin_check("video teletype");
+		self.builder.mov_r16(AX, 0x0e00 | b'T' as u16);
+		self.builder.mov_r16(BX, 0x0007);
+		self.builder.int(0x10);
+		self.get_cursor_into_ax();
+		self.expect_ax(0x050b);
+		self.expect_screen_char(5, 10, b'T');
+
+		self.begin_check("video scroll");
+		// Scroll the whole screen up a line.
+		self.builder.mov_r16(AX, 0x0601);
+		self.builder.mov_r8(BH, 0x07);
+		self.builder.mov_r16(CX, 0x0000);
+		self.builder.mov_r16(DX, 0x184f);
+		self.builder.int(0x10);
+		self.expect_screen_char(4, 10, b'T');
+		self.expect_screen_char(5, 10, b' ');
+
+		// Clear the screen again for the results.
+		self.builder.mov_r16(AX, 0x0003);
+		self.builder.int(0x10);
+	}
+
+	fn keyboard_checks(&mut self) {
+		// Q, with its scan code.
+		let key = 0x1071;
+		self.begin_check("keyboard store");
+		self.builder.mov_r16(CX, key);
+		self.builder.call(0x16, 0x05);
+		self.expect_al(0);
+
+		self.begin_check("keyboard read");
+		let no_key = self.builder.new_label();
+		self.builder.call(0x16, 0x01);
+		self.fail_if(Jump::Zero);
+		// Reading with no key waiting would wait forever.
+		self.builder.jump(Jump::Zero, no_key);
+		self.builder.call(0x16, 0x00);
+		self.expect_ax(key);
+		self.builder.place(no_key);
+	}
+
+	fn timer_checks(&mut self) {
+		self.begin_check("timer ticks");
+		let ticks = self.builder.reserve(2);
+		self.builder.call(0x1a, 0x00);
+		self.builder.mov_r16_r16(AX, DX);
+		self.builder.store_ax(Addr::Label(ticks, 0));
+		// Count 4096 times round a loop, which is longer than a tick takes at any speed the CPU
+		// runs at.
+		let wait_count = self.builder.data(&[0, 0xf0]);
+		let wait = self.builder.new_label();
+		self.builder.place(wait);
+		self.builder.inc_byte(Addr::Label(wait_count, 0));
+		self.builder.jump(Jump::NotZero, wait);
+		self.builder.inc_byte(Addr::Label(wait_count, 1));
+		self.builder.jump(Jump::NotZero, wait);
+		self.builder.call(0x1a, 0x00);
+		self.builder.mov_r16_r16(AX, DX);
+		self.builder.cmp_ax_mem(Addr::Label(ticks, 0));
+		self.fail_if(Jump::Zero);
+	}
+
+	fn memory_checks(&mut self) {
+		// A COM program starts out owning all the memory, so give most of it back first. ES is still
+		// the PSP segment.
+		self.begin_check("memory resize");
+		self.builder.mov_r16(BX, 0x1000);
+		self.builder.call(0x21, 0x4a);
+		self.fail_if(Jump::Carry);
+
+		// Asking for too much fails, and says how much there is.
+		self.begin_check("memory largest block");
+		let largest = self.builder.reserve(2);
+		self.builder.mov_r16(BX, 0xffff);
+		self.builder.call(0x21, 0x48);
+		self.fail_if(Jump::NoCarry);
+		self.builder.mov_r16_r16(AX, BX);
+		self.builder.store_ax(Addr::Label(largest, 0));
+		self.builder.cmp_ax(0);
+		self.fail_if(Jump::Zero);
+
+		self.begin_check("memory allocate");
+		self.builder.load_ax(Addr::Label(largest, 0));
+		self.builder.mov_r16_r16(BX, AX);
+		self.builder.call(0x21, 0x48);
+		self.fail_if(Jump::Carry);
+		self.builder.mov_es_ax();
+
+		// Once it's freed, the largest block is back to the size it was.
+		self.begin_check("memory free");
+		self.builder.call(0x21, 0x49);
+		self.fail_if(Jump::Carry);
+		self.builder.restore_es();
+		self.builder.mov_r16(BX, 0xffff);
+		self.builder.call(0x21, 0x48);
+		self.builder.mov_r16_r16(AX, BX);
+		self.builder.cmp_ax_mem(Addr::Label(largest, 0));
+		self.fail_if(Jump::NotZero);
+	}
+
+	fn file_checks(&mut self) {
+		let file_name = self.builder.data(b"SELFTEST.DAT\0");
+		let file_data = b"PSEUDOS";
+		let write_data = self.builder.data(file_data);
+		let read_buffer = self.builder.reserve(file_data.len());
+
+		// The handle stays in BX through to the close.
+		self.begin_check("file create");
+		self.builder.mov_r16(CX, 0);
+		self.builder.mov_r16_label(DX, file_name);
+		self.builder.call(0x21, 0x3c);
+		self.fail_if(Jump::Carry);
+		self.builder.mov_r16_r16(BX, AX);
+
+		self.begin_check("file write");
+		self.builder.mov_r16(CX, file_data.len() as u16);
+		self.builder.mov_r16_label(DX, write_data);
+		self.builder.call(0x21, 0x40);
+		self.fail_if(Jump::Carry);
+		self.expect_ax(file_data.len() as u16);
+
+		self.begin_check("file seek");
+		self.builder.mov_r16(AX, 0x4200);
+		self.builder.mov_r16(CX, 0);
+		self.builder.mov_r16(DX, 1);
+		self.builder.int(0x21);
+		self.fail_if(Jump::Carry);
+		self.expect_ax(1);
+
+		// Reading from after the first byte only gets the rest.
+		self.begin_check("file read");
+		self.builder.mov_r16(CX, file_data.len() as u16);
+		self.builder.mov_r16_label(DX, read_buffer);
+		self.builder.call(0x21, 0x3f);
+		self.fail_if(Jump::Carry);
+		self.expect_ax(file_data.len() as u16 - 1);
+		for (index, &c) in file_data[1..].iter().enumerate() {
+			self.builder.load_al(Addr::Label(read_buffer, index as u16));
+			self.expect_al(c);
+		}
+
+		self.begin_check("file close");
+		self.builder.call(0x21, 0x3e);
+		self.fail_if(Jump::Carry);
+
+		// Once it's deleted, it can't be opened.
+		self.begin_check("file delete");
+		self.builder.mov_r16_label(DX, file_name);
+		self.builder.call(0x21, 0x41);
+		self.fail_if(Jump::Carry);
+		self.builder.mov_r16(AX, 0x3d00);
+		self.builder.mov_r16_label(DX, file_name);
+		self.builder.int(0x21);
+		self.fail_if(Jump::NoCarry);
+	}
+
+	fn find_checks(&mut self) {
+		let file_names: Vec<Label> = (1 ..= 3).map(|number| self.builder.data(format!("SELFTST{}.TMP\0", number).as_bytes())).collect();
+		let search_spec = self.builder.data(b"SELFTST?.TMP\0");
+		let disk_transfer_area = self.builder.reserve(0x80);
+		let found_count = self.builder.reserve(1);
+
+		self.begin_check("find file count");
+		for &file_name in &file_names {
+			self.builder.mov_r16(CX, 0);
+			self.builder.mov_r16_label(DX, file_name);
+			self.builder.call(0x21, 0x3c);
+			self.fail_if(Jump::Carry);
+			self.builder.mov_r16_r16(BX, AX);
+			self.builder.call(0x21, 0x3e);
+		}
+		self.builder.mov_r16_label(DX, disk_transfer_area);
+		self.builder.call(0x21, 0x1a);
+		let next = self.builder.new_label();
+		let done = self.builder.new_label();
+		self.builder.mov_r16(CX, 0);
+		self.builder.mov_r16_label(DX, search_spec);
+		self.builder.call(0x21, 0x4e);
+		self.builder.jump(Jump::Carry, done);
+		self.builder.place(next);
+		self.builder.inc_byte(Addr::Label(found_count, 0));
+		self.builder.call(0x21, 0x4f);
+		self.builder.jump(Jump::NoCarry, next);
+		self.builder.place(done);
+		self.builder.load_al(Addr::Label(found_count, 0));
+		self.expect_al(file_names.len() as u8);
+		for &file_name in &file_names {
+			self.builder.mov_r16_label(DX, file_name);
+			self.builder.call(0x21, 0x41);
+			self.fail_if(Jump::Carry);
+		}
+	}
+
+	// Prints a line for each check, counting the ones that failed, and exits with the count.
+	fn report(&mut self) {
+		let failed_count = self.builder.reserve(1);
+		for (check_index, name) in self.check_names.clone().into_iter().enumerate() {
+			let pass_message = self.builder.data(format!("PASS {}\r\n$", name).as_bytes());
+			let fail_message = self.builder.data(format!("FAIL {}\r\n$", name).as_bytes());
+			let failed = self.builder.new_label();
+			let print = self.builder.new_label();
+			let result_addr = self.result_addr(check_index);
+			self.builder.load_al(result_addr);
+			self.builder.cmp_al(b'P');
+			self.builder.jump(Jump::NotZero, failed);
+			self.builder.mov_r16_label(DX, pass_message);
+			self.builder.jump(Jump::Always, print);
+			self.builder.place(failed);
+			self.builder.inc_byte(Addr::Label(failed_count, 0));
+			self.builder.mov_r16_label(DX, fail_message);
+			self.builder.place(print);
+			self.builder.call(0x21, 0x09);
+		}
+		self.builder.load_al(Addr::Label(failed_count, 0));
+		self.builder.call(0x21, 0x4c);
+	}
+}
+
+/// Puts together the self-test program, which expects to be run with the given environment block.
+pub fn build_self_test_program(environment_block: &[u8]) -> SelfTestProgram {
+	let mut builder = ProgramBuilder::default();
+	let results = builder.new_label();
+	let mut assembler = SelfTestAssembler { builder, check_names: vec![], results };
+	assembler.psp_checks(environment_block);
+	assembler.video_checks();
+	assembler.keyboard_checks();
+	assembler.timer_checks();
+	assembler.memory_checks();
+	assembler.file_checks();
+	assembler.find_checks();
+	assembler.report();
+
+	let SelfTestAssembler { mut builder, check_names, results } = assembler;
+	builder.place_data(results, &vec![0; check_names.len()]);
+	let results_offset = match builder.labels[results.0] {
+		Some(LabelTarget::Data(offset)) => (COM_ORIGIN as usize + builder.code.len() + offset) as u16,
+		_ => unreachable!(),
+	};
+	SelfTestProgram { data: builder.finish(), check_names, results_offset }
+}
+
+/// How a self-test run went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+	/// Each check's name, and whether it passed.
+	pub checks: Vec<(&'static str, bool)>,
+	/// The text the program left on the screen.
+	pub screen_text: String,
+}
+
+impl SelfTestReport {
+	pub fn failed_checks(&self) -> Vec<&'static str> {
+		self.checks.iter().filter(|(_, passed)| !passed).map(|(name, _)| *name).collect()
+	}
+
+	pub fn passed(&self) -> bool {
+		self.checks.iter().all(|(_, passed)| *passed)
+	}
+}
+
+/// The self-test program loaded into an emulator, with the C: drive in a host directory. Interrupts
+/// can be hooked through the emulator's handler before it's run.
+pub struct SelfTest {
+	pub emulator: Emulator,
+	program: SelfTestProgram,
+}
+
+impl SelfTest {
+	/// Loads the self-test program. The files it makes on the C: drive go in work_dir, which
+	/// should be an empty directory.
+	pub fn new(work_dir: &Path) -> Result<SelfTest, String> {
+		let mut options = EmulatorOptions::default();
+		options.video.print_screen_dir = None;
+		options.dos.host_directory = work_dir.to_path_buf();
+		options.dos.program_name = SELF_TEST_PROGRAM_NAME.to_string();
+		options.dos.environment_vars = vec![(SELF_TEST_ENVIRONMENT_VAR.0.to_vec(), SELF_TEST_ENVIRONMENT_VAR.1.to_vec())];
+		let environment_block = build_environment_block(&options.dos.environment_vars, &options.dos.program_dos_path());
+		let program = build_self_test_program(&environment_block);
+		let image = ProgramImage::new(program.data.clone(), &options.dos.program_dos_path());
+		let mut drive_table = DosDriveTable::new();
+		drive_table.mount(2, Box::new(StandardDosFileSystem::new(work_dir.to_path_buf())))
+			.map_err(|err| format!("Can't mount {}: {:?}", work_dir.display(), err))?;
+		let emulator = Emulator::new(Some(image), Box::new(drive_table), options).map_err(|err| err.to_string())?;
+		Ok(SelfTest { emulator, program })
+	}
+
+	/// Runs the program to the end, and reads which checks passed from its results table.
+	pub fn run(mut self) -> Result<SelfTestReport, String> {
+		let Emulator { ref mut machine, ref mut handler, .. } = self.emulator;
+		let exit_code = handler.run_headless(machine, SELF_TEST_MAX_STEPS, HeadlessStartup::Normal)?;
+		let results_addr = ((PROGRAM_PSP_SEGMENT as usize) << 4) + self.program.results_offset as usize;
+		let results = &machine.memory[results_addr .. results_addr + self.program.check_names.len()];
+		let checks: Vec<(&'static str, bool)> = self.program.check_names.iter().cloned().zip(results.iter().map(|&result| result == b'P')).collect();
+		let failed_count = checks.iter().filter(|(_, passed)| !passed).count();
+		if exit_code as usize != failed_count {
+			return Err(format!("The self-test program exited with {}, but {} checks failed", exit_code, failed_count));
+		}
+		Ok(SelfTestReport { checks, screen_text: handler.get_screen_unicode_text(machine) })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::tests::make_test_dir;
+	use crate::dos_interrupt_hooks::InterruptHookResult;
+
+	use xachtsechs::types::{Flag, Reg};
+
+	fn make_self_test(name: &str) -> (SelfTest, std::path::PathBuf) {
+		let dir_path = make_test_dir(name);
+		(SelfTest::new(&dir_path).unwrap(), dir_path)
+	}
+
+	fn run_self_test(self_test: SelfTest, dir_path: &Path) -> SelfTestReport {
+		let report = self_test.run().unwrap();
+		// The program cleans up after itself, even when something failed.
+		assert_eq!(std::fs::read_dir(dir_path).unwrap().count(), 0);
+		std::fs::remove_dir_all(dir_path).ok();
+		report
+	}
+
+	#[test] fn test_builder_labels() {
+		let mut builder = ProgramBuilder::default();
+		let byte = builder.data(&[0x42]);
+		let end = builder.new_label();
+		builder.jump(Jump::Zero, end);
+		builder.load_al(Addr::Label(byte, 0));
+		builder.place(end);
+		assert_eq!(builder.finish(), vec![0x74, 0x03, 0xa0, 0x05, 0x01, 0x42]);
+	}
+
+	#[test] fn test_self_test_passes() {
+		let (self_test, dir_path) = make_self_test("self_test_passes");
+		let report = run_self_test(self_test, &dir_path);
+		assert_eq!(report.failed_checks(), Vec::<&str>::new());
+		assert!(report.passed());
+		assert_eq!(report.checks.len(), 19);
+		assert!(report.screen_text.starts_with("PASS psp fields\nPASS environment block\nPASS video cursor\n"));
+	}
+
+	#[test] fn test_broken_keyboard_read() {
+		let (mut self_test, dir_path) = make_self_test("self_test_keyboard");
+		self_test.emulator.handler.hook_interrupt(0x16, Some(0x00), Box::new(|_handler, machine| {
+			machine.set_reg_u16(Reg::AX, 0x1e61);
+			InterruptHookResult::Handled
+		}));
+		let report = run_self_test(self_test, &dir_path);
+		assert_eq!(report.failed_checks(), vec!["keyboard read"]);
+		assert!(report.screen_text.contains("FAIL keyboard read\n"));
+	}
+
+	#[test] fn test_broken_find_next() {
+		let (mut self_test, dir_path) = make_self_test("self_test_find");
+		self_test.emulator.handler.hook_interrupt(0x21, Some(0x4f), Box::new(|_handler, machine| {
+			machine.set_flag(Flag::Carry, true);
+			InterruptHookResult::Handled
+		}));
+		let report = run_self_test(self_test, &dir_path);
+		assert_eq!(report.failed_checks(), vec!["find file count"]);
+	}
+
+	#[test] fn test_broken_cursor_and_timer() {
+		let (mut self_test, dir_path) = make_self_test("self_test_cursor");
+		self_test.emulator.handler.hook_interrupt(0x10, Some(0x03), Box::new(|_handler, machine| {
+			machine.set_reg_u16(Reg::DX, 0);
+			InterruptHookResult::Handled
+		}));
+		// A clock that's stopped.
+		self_test.emulator.handler.hook_interrupt(0x1a, Some(0x00), Box::new(|_handler, machine| {
+			machine.set_reg_u16(Reg::CX, 0);
+			machine.set_reg_u16(Reg::DX, 0);
+			InterruptHookResult::Handled
+		}));
+		let report = run_self_test(self_test, &dir_path);
+		assert_eq!(report.failed_checks(), vec!["video cursor", "video teletype", "timer ticks"]);
+	}
+}
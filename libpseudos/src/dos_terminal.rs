@@ -0,0 +1,261 @@
+// Shows the text screen in a terminal and takes keys from it, for running without a display (eg.
+// over SSH). The screen is drawn with ANSI escape codes, with the characters converted from code
+// page 437, and the terminal's key codes are turned into BIOS key presses for a US keyboard.
+// https://en.wikipedia.org/wiki/ANSI_escape_code
+
+use crate::dos_code_page::cp437_to_char;
+use crate::dos_event_handler::KeyPressInfo;
+
+// The ANSI colour number of each of the 8 low intensity CGA colours, which are in a different
+// order.
+const CGA_TO_ANSI_COLOUR: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// Keeps track of what's on the terminal, so only the cells that changed are sent again.
+#[derive(Debug, Clone)]
+pub struct AnsiScreen {
+	columns: usize,
+	rows: usize,
+	// The characters and attributes that were last drawn, or empty before the first draw.
+	drawn_text: Vec<u8>,
+	// Whether the top bit of an attribute blinks rather than making the background bright.
+	blink_enabled: bool,
+}
+
+impl AnsiScreen {
+	pub fn new(columns: usize, rows: usize) -> AnsiScreen {
+		AnsiScreen {
+			columns,
+			rows,
+			drawn_text: vec![],
+			blink_enabled: true,
+		}
+	}
+
+	/// Sets whether the top bit of an attribute blinks, like int 10h AX=1003h. Everything is drawn
+	/// again when it changes.
+	pub fn set_blink_enabled(&mut self, blink_enabled: bool) {
+		if self.blink_enabled != blink_enabled {
+			self.blink_enabled = blink_enabled;
+			self.drawn_text.clear();
+		}
+	}
+
+	/// Gets the escape codes that bring the terminal up to date with the text page, given as
+	/// character and attribute byte pairs. The terminal's cursor is moved to the given column and
+	/// row, or hidden if there's no cursor.
+	pub fn update(&mut self, text: &[u8], cursor: Option<(u8, u8)>) -> String {
+		let text = &text[.. self.columns * self.rows * 2];
+		let redraw_all = self.drawn_text.len() != text.len();
+		let mut output = String::new();
+		let mut current_attribute = None;
+		// Where the terminal will print the next character, if it's known.
+		let mut terminal_position = None;
+		for row in 0 .. self.rows {
+			for column in 0 .. self.columns {
+				let cell_index = (row * self.columns + column) * 2;
+				let cell = &text[cell_index .. cell_index + 2];
+				if !redraw_all && &self.drawn_text[cell_index .. cell_index + 2] == cell {
+					continue;
+				}
+				if terminal_position != Some((column, row)) {
+					output += &format!("\x1b[{};{}H", row + 1, column + 1);
+				}
+				if current_attribute != Some(cell[1]) {
+					output += &attribute_sgr(cell[1], self.blink_enabled);
+					current_attribute = Some(cell[1]);
+				}
+				output.push(cp437_to_char(cell[0]));
+				// The terminal won't wrap to the next row until something is printed past the end.
+				terminal_position = if column + 1 < self.columns { Some((column + 1, row)) } else { None };
+			}
+		}
+		self.drawn_text = text.to_vec();
+		match cursor {
+			Some((column, row)) if (column as usize) < self.columns && (row as usize) < self.rows => {
+				output += &format!("\x1b[{};{}H\x1b[?25h", row + 1, column + 1);
+			}
+			_ => output += "\x1b[?25l",
+		}
+		output
+	}
+}
+
+// Gets the Select Graphic Rendition code for an attribute byte. The top bit of the background is
+// blinking, or a bright background when blinking is off.
+fn attribute_sgr(attribute: u8, blink_enabled: bool) -> String {
+	let foreground = attribute & 0x0f;
+	let foreground_code = if foreground >= 8 {
+		90 + CGA_TO_ANSI_COLOUR[foreground as usize - 8]
+	} else {
+		30 + CGA_TO_ANSI_COLOUR[foreground as usize]
+	};
+	let top_bit = attribute & 0x80 != 0;
+	let background_base = if top_bit && !blink_enabled { 100 } else { 40 };
+	let background_code = background_base + CGA_TO_ANSI_COLOUR[(attribute >> 4) as usize & 0x07];
+	let blink = if top_bit && blink_enabled { ";5" } else { "" };
+	format!("\x1b[0;{};{}{}m", foreground_code, background_code, blink)
+}
+
+/// Gets the key press for a character typed in the terminal, including control characters.
+pub fn ascii_key_press(c: u8) -> Option<KeyPressInfo> {
+	let key = |scan_code, ascii_char| Some(KeyPressInfo { scan_code, ascii_char });
+	match c {
+		b'\r' => key(0x1c, b'\r'),
+		b'\t' => key(0x0f, b'\t'),
+		0x08 | 0x7f => key(0x0e, 0x08),
+		0x1b => key(0x01, 0x1b),
+		b' ' => key(0x39, b' '),
+		// Ctrl with a letter types the letter's position in the alphabet.
+		0x01 ..= 0x1a => ascii_key_press(c + b'a' - 1).map(|key_press| KeyPressInfo { scan_code: key_press.scan_code, ascii_char: c }),
+		_ => KeyPressInfo::from_character(c),
+	}
+}
+
+// Gets the extended key (with no character) for the final byte of an "ESC [" or "ESC O" sequence
+// and its first number, eg. "ESC [ 5 ~" is Page Up.
+fn escape_sequence_scan_code(final_byte: u8, number: Option<u32>) -> Option<u8> {
+	match (final_byte, number) {
+		(b'A', _) => Some(0x48),
+		(b'B', _) => Some(0x50),
+		(b'C', _) => Some(0x4d),
+		(b'D', _) => Some(0x4b),
+		(b'H', _) | (b'~', Some(1)) | (b'~', Some(7)) => Some(0x47),
+		(b'F', _) | (b'~', Some(4)) | (b'~', Some(8)) => Some(0x4f),
+		(b'~', Some(2)) => Some(0x52),
+		(b'~', Some(3)) => Some(0x53),
+		(b'~', Some(5)) => Some(0x49),
+		(b'~', Some(6)) => Some(0x51),
+		// F1 to F4.
+		(b'P' ..= b'S', _) => Some(0x3b + final_byte - b'P'),
+		// F5 to F10, which skip 16 and 22.
+		(b'~', Some(15)) => Some(0x3f),
+		(b'~', Some(number @ 17 ..= 21)) => Some(0x40 + (number - 17) as u8),
+		_ => None,
+	}
+}
+
+/// Turns the bytes read from the terminal into key presses. An escape that isn't the start of a
+/// known sequence is the Escape key, and anything else that isn't on a US keyboard is left out.
+pub fn parse_terminal_keys(input: &[u8]) -> Vec<KeyPressInfo> {
+	let mut key_presses = vec![];
+	let mut pos = 0;
+	while pos < input.len() {
+		let c = input[pos];
+		pos += 1;
+		if c == 0x1b && pos < input.len() && (input[pos] == b'[' || input[pos] == b'O') {
+			let introducer = input[pos];
+			pos += 1;
+			// The parameters are numbers separated by semicolons, and only the first one is used.
+			let params_start = pos;
+			while pos < input.len() && (input[pos].is_ascii_digit() || input[pos] == b';') {
+				pos += 1;
+			}
+			let number = input[params_start .. pos].split(|c| *c == b';').next()
+				.and_then(|param| std::str::from_utf8(param).ok())
+				.and_then(|param| param.parse().ok());
+			if let Some(&final_byte) = input.get(pos) {
+				pos += 1;
+				let number = if introducer == b'O' { None } else { number };
+				if let Some(scan_code) = escape_sequence_scan_code(final_byte, number) {
+					key_presses.push(KeyPressInfo { scan_code, ascii_char: 0 });
+				}
+			}
+		} else if let Some(key_press) = ascii_key_press(c) {
+			key_presses.push(key_press);
+		}
+	}
+	key_presses
+}
+
+/// Puts the terminal in raw mode, so keys are read as they're typed without being echoed, and
+/// switches to the alternate screen. The terminal is put back the way it was when this is
+/// dropped.
+#[cfg(unix)]
+pub struct RawTerminal {
+	original_termios: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawTerminal {
+	pub fn new() -> Result<RawTerminal, String> {
+		let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+		if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+			return Err(std::io::Error::last_os_error().to_string());
+		}
+		let original_termios = termios;
+		unsafe { libc::cfmakeraw(&mut termios) };
+		// Reads return straight away, with whatever has been typed.
+		termios.c_cc[libc::VMIN] = 0;
+		termios.c_cc[libc::VTIME] = 0;
+		if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+			return Err(std::io::Error::last_os_error().to_string());
+		}
+		let mut terminal = RawTerminal { original_termios };
+		terminal.write("\x1b[?1049h\x1b[2J");
+		Ok(terminal)
+	}
+
+	/// Reads everything that's been typed since the last read, without waiting.
+	pub fn read_input(&mut self) -> Vec<u8> {
+		use std::io::Read;
+		let mut input = vec![];
+		let mut buffer = [0; 256];
+		while let Ok(count @ 1 ..= 256) = std::io::stdin().read(&mut buffer) {
+			input.extend_from_slice(&buffer[.. count]);
+		}
+		input
+	}
+
+	pub fn write(&mut self, output: &str) {
+		use std::io::Write;
+		let mut stdout = std::io::stdout();
+		stdout.write_all(output.as_bytes()).and_then(|_| stdout.flush()).ok();
+	}
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminal {
+	fn drop(&mut self) {
+		self.write("\x1b[0m\x1b[?25h\x1b[?1049l");
+		unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original_termios) };
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(scan_code: u8, ascii_char: u8) -> KeyPressInfo {
+		KeyPressInfo { scan_code, ascii_char }
+	}
+
+	#[test] fn test_parse_terminal_keys() {
+		assert_eq!(parse_terminal_keys(b"aZ1!\r"), vec![key(0x1e, b'a'), key(0x2c, b'Z'), key(0x02, b'1'), key(0x02, b'!'), key(0x1c, b'\r')]);
+		// Ctrl+C, backspace and a lone escape.
+		assert_eq!(parse_terminal_keys(b"\x03\x7f\x1b"), vec![key(0x2e, 0x03), key(0x0e, 0x08), key(0x01, 0x1b)]);
+		// Up, Page Down, F1, F5 and F10, with modifiers left out.
+		assert_eq!(parse_terminal_keys(b"\x1b[A\x1b[6~\x1bOP\x1b[15~\x1b[21;2~"), vec![key(0x48, 0), key(0x51, 0), key(0x3b, 0), key(0x3f, 0), key(0x44, 0)]);
+		// Unknown sequences and characters that aren't on the keyboard are skipped.
+		assert_eq!(parse_terminal_keys(b"\x1b[99~x\xc3\xa9"), vec![key(0x2d, b'x')]);
+		assert_eq!(parse_terminal_keys(b"\x1bx"), vec![key(0x01, 0x1b), key(0x2d, b'x')]);
+	}
+
+	#[test] fn test_ansi_screen() {
+		let mut text = vec![0; 4 * 2 * 2];
+		for cell in text.chunks_mut(2) {
+			cell.copy_from_slice(&[b' ', 0x07]);
+		}
+		text[0 .. 4].copy_from_slice(&[b'H', 0x1e, 0xdb, 0x9c]);
+		let mut screen = AnsiScreen::new(4, 2);
+		assert_eq!(
+			screen.update(&text, Some((1, 0))),
+			"\x1b[1;1H\x1b[0;93;44mH\x1b[0;91;44;5m█\x1b[0;37;40m  \x1b[2;1H    \x1b[1;2H\x1b[?25h"
+		);
+		// Only the changed cell is drawn again.
+		text[2 * 4 + 2] = b'!';
+		assert_eq!(screen.update(&text, None), "\x1b[2;2H\x1b[0;37;40m!\x1b[?25l");
+		// Turning blinking off makes the top bit a bright background, and draws everything again.
+		screen.set_blink_enabled(false);
+		assert!(screen.update(&text, None).starts_with("\x1b[1;1H\x1b[0;93;44mH\x1b[0;91;104m█"));
+	}
+}
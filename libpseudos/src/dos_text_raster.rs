@@ -0,0 +1,296 @@
+// Draws the text screen into an RGB image in software, so the frontend only has to upload one
+// image a frame and let SDL scale it, rather than filling and copying each character cell on the
+// canvas. Each pixel row of a character is drawn in one go from a byte of the font, with the
+// cell's colours worked out once for the whole row of cells. Screenshots save the same image.
+
+use crate::dos_palette::{PaletteTransform, Rgb, TextColours};
+
+pub const GLYPH_WIDTH: usize = 8;
+// The font has 14 pixel high characters, which are stretched to the video mode's height.
+pub const FONT_GLYPH_HEIGHT: usize = 14;
+const RGB_BYTES: usize = 3;
+
+/// The 256 characters of a font, as a byte for each row of pixels, with the leftmost pixel in the
+/// top bit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextFont {
+	rows: Vec<u8>,
+}
+
+impl TextFont {
+	/// Reads a font from an image with the characters side by side in order, where is_set says
+	/// whether the pixel at x, y is part of a character.
+	pub fn from_strip(is_set: impl Fn(usize, usize) -> bool) -> TextFont {
+		let mut rows = vec![0; 256 * FONT_GLYPH_HEIGHT];
+		for char_code in 0 .. 256 {
+			for y in 0 .. FONT_GLYPH_HEIGHT {
+				rows[char_code * FONT_GLYPH_HEIGHT + y] = (0 .. GLYPH_WIDTH)
+					.filter(|&x| is_set(char_code * GLYPH_WIDTH + x, y))
+					.fold(0, |row, x| row | (0x80 >> x));
+			}
+		}
+		TextFont { rows }
+	}
+
+	/// Reads a font from a strip image with 32 bit pixels, where the opaque pixels are the ones that
+	/// are part of a character. The pixels are in native byte order, and alpha_mask picks out their
+	/// alpha bits.
+	pub fn from_alpha_pixels(pixels: &[u8], pitch: usize, bytes_per_pixel: usize, alpha_mask: u32) -> Result<TextFont, String> {
+		if bytes_per_pixel != 4 || alpha_mask == 0 {
+			return Err("The font needs to have 32 bit pixels with alpha".to_string());
+		}
+		if pitch < 256 * GLYPH_WIDTH * 4 || pixels.len() < (FONT_GLYPH_HEIGHT - 1) * pitch + 256 * GLYPH_WIDTH * 4 {
+			return Err(format!("The font needs to be at least {}x{} pixels", 256 * GLYPH_WIDTH, FONT_GLYPH_HEIGHT));
+		}
+		Ok(TextFont::from_strip(|x, y| {
+			let addr = y * pitch + x * 4;
+			let pixel = u32::from_ne_bytes([pixels[addr], pixels[addr + 1], pixels[addr + 2], pixels[addr + 3]]);
+			let alpha = (pixel & alpha_mask) >> alpha_mask.trailing_zeros();
+			alpha > 0x7f
+		}))
+	}
+
+	fn glyph_row(&self, char_code: u8, y: usize) -> u8 {
+		self.rows[char_code as usize * FONT_GLYPH_HEIGHT + y]
+	}
+}
+
+/// What's on the screen, for TextRaster::render.
+#[derive(Debug, Clone)]
+pub struct TextScreen<'a> {
+	/// Character and attribute byte pairs, a row at a time.
+	pub text: &'a [u8],
+	pub columns: usize,
+	pub rows: usize,
+	/// The height of the character cells in pixels.
+	pub char_height: usize,
+	pub blink_enabled: bool,
+	/// Whether blinking characters are in the half of the blink where they're shown.
+	pub blink_visible: bool,
+	/// The cursor's column and row, and the first and last scanlines it covers, if it's showing.
+	pub cursor: Option<(usize, usize, u8, u8)>,
+}
+
+/// An RGB image of the screen, 3 bytes a pixel with no gaps between the rows.
+#[derive(Debug, Clone, Default)]
+pub struct TextRaster {
+	pub width: usize,
+	pub height: usize,
+	pub pixels: Vec<u8>,
+}
+
+impl TextRaster {
+	pub fn new() -> TextRaster {
+		TextRaster::default()
+	}
+
+	/// The number of bytes in each row of pixels.
+	pub fn pitch(&self) -> usize {
+		self.width * RGB_BYTES
+	}
+
+	pub fn pixel(&self, x: usize, y: usize) -> Rgb {
+		let addr = y * self.pitch() + x * RGB_BYTES;
+		(self.pixels[addr], self.pixels[addr + 1], self.pixels[addr + 2])
+	}
+
+	/// Draws the whole screen, sizing the image to fit it.
+	pub fn render(&mut self, font: &TextFont, palette: &PaletteTransform, screen: &TextScreen) {
+		self.width = screen.columns * GLYPH_WIDTH;
+		self.height = screen.rows * screen.char_height;
+		self.pixels.resize(self.width * self.height * RGB_BYTES, 0);
+		if self.pixels.is_empty() {
+			return;
+		}
+		let pitch = self.pitch();
+		let colours = palette.colours();
+		// The foreground (or None if it's hidden by blinking) and background of each cell in a row.
+		let mut cell_colours = Vec::with_capacity(screen.columns);
+		for (text_row, pixel_rows) in screen.text.chunks(screen.columns * 2).take(screen.rows).zip(self.pixels.chunks_mut(pitch * screen.char_height)) {
+			cell_colours.clear();
+			cell_colours.extend(text_row.chunks(2).map(|cell| {
+				let attribute = cell[1];
+				let mut back = attribute >> 4;
+				let blinking = screen.blink_enabled && back >= 8;
+				if blinking {
+					back -= 8;
+				}
+				let fore = if blinking && !screen.blink_visible { None } else { Some(colours[(attribute & 0x0f) as usize]) };
+				(fore, colours[back as usize])
+			}));
+			for (y, pixel_row) in pixel_rows.chunks_mut(pitch).enumerate() {
+				let font_y = y * FONT_GLYPH_HEIGHT / screen.char_height;
+				for ((cell, &(fore, back)), cell_pixels) in text_row.chunks(2).zip(cell_colours.iter()).zip(pixel_row.chunks_exact_mut(GLYPH_WIDTH * RGB_BYTES)) {
+					let glyph_row = match fore {
+						Some(_) => font.glyph_row(cell[0], font_y),
+						None => 0,
+					};
+					let fore = fore.unwrap_or(back);
+					for (x, pixel) in cell_pixels.chunks_exact_mut(RGB_BYTES).enumerate() {
+						let (r, g, b) = if glyph_row & (0x80 >> x) != 0 { fore } else { back };
+						pixel.copy_from_slice(&[r, g, b]);
+					}
+				}
+			}
+		}
+
+		// The cursor covers its scanlines of the cell, in the cell's foreground colour.
+		if let Some((column, row, start_line, end_line)) = screen.cursor {
+			if column < screen.columns && row < screen.rows {
+				let (r, g, b) = palette.colour_to_rgb(screen.text[(row * screen.columns + column) * 2 + 1]);
+				let last_line = (end_line as usize).min(screen.char_height - 1);
+				for line in start_line as usize ..= last_line {
+					let row_start = (row * screen.char_height + line) * pitch + column * GLYPH_WIDTH * RGB_BYTES;
+					for pixel in self.pixels[row_start .. row_start + GLYPH_WIDTH * RGB_BYTES].chunks_mut(RGB_BYTES) {
+						pixel[0] = r;
+						pixel[1] = g;
+						pixel[2] = b;
+					}
+				}
+			}
+		}
+	}
+
+	/// Draws the whole screen the way the SDL frontend used to, filling each cell's background and
+	/// then stamping its character over it, stretched a pixel at a time, followed by the cursor
+	/// (which, unlike before, is kept inside its cell). It's much slower than render, and is only kept so render_bench can compare them and the
+	/// tests can check they draw the same image.
+	pub fn render_by_cell(&mut self, font: &TextFont, palette: &PaletteTransform, screen: &TextScreen) {
+		self.width = screen.columns * GLYPH_WIDTH;
+		self.height = screen.rows * screen.char_height;
+		self.pixels.resize(self.width * self.height * RGB_BYTES, 0);
+		for row in 0 .. screen.rows {
+			for column in 0 .. screen.columns {
+				let cell_index = (row * screen.columns + column) * 2;
+				let char_code = screen.text[cell_index];
+				let TextColours { fore, back, blinking } = palette.attribute_colours(screen.text[cell_index + 1], screen.blink_enabled);
+				self.fill_rect(column * GLYPH_WIDTH, row * screen.char_height, GLYPH_WIDTH, screen.char_height, back);
+				if !blinking || screen.blink_visible {
+					for y in 0 .. screen.char_height {
+						let glyph_row = font.glyph_row(char_code, y * FONT_GLYPH_HEIGHT / screen.char_height);
+						for x in (0 .. GLYPH_WIDTH).filter(|&x| glyph_row & (0x80 >> x) != 0) {
+							self.fill_rect(column * GLYPH_WIDTH + x, row * screen.char_height + y, 1, 1, fore);
+						}
+					}
+				}
+			}
+		}
+		if let Some((column, row, start_line, end_line)) = screen.cursor {
+			if column < screen.columns && row < screen.rows {
+				let fore = palette.colour_to_rgb(screen.text[(row * screen.columns + column) * 2 + 1]);
+				let last_line = (end_line as usize).min(screen.char_height - 1);
+				if start_line as usize <= last_line {
+					self.fill_rect(column * GLYPH_WIDTH, row * screen.char_height + start_line as usize, GLYPH_WIDTH, last_line + 1 - start_line as usize, fore);
+				}
+			}
+		}
+	}
+
+	fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, (r, g, b): Rgb) {
+		let pitch = self.pitch();
+		for pixel_row in self.pixels[y * pitch ..].chunks_mut(pitch).take(height) {
+			for pixel in pixel_row[x * RGB_BYTES .. (x + width) * RGB_BYTES].chunks_mut(RGB_BYTES) {
+				pixel[0] = r;
+				pixel[1] = g;
+				pixel[2] = b;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_palette::{PalettePreset, STANDARD_VGA_COLOURS};
+
+	// A font where every character is a vertical bar in its leftmost column.
+	fn make_test_font() -> TextFont {
+		TextFont::from_strip(|x, _| x % GLYPH_WIDTH == 0)
+	}
+
+	#[test] fn test_render_text() {
+		let font = make_test_font();
+		// A 2x2 screen: bright white on blue, green on blinking red, and grey and yellow on red.
+		let text = [b'A', 0x1f, b'x', 0xc2, b' ', 0x07, b'B', 0x4e];
+		let mut screen = TextScreen { text: &text, columns: 2, rows: 2, char_height: 14, blink_enabled: true, blink_visible: true, cursor: None };
+		let mut raster = TextRaster::new();
+		raster.render(&font, &PaletteTransform::default(), &screen);
+		assert_eq!((raster.width, raster.height, raster.pixels.len()), (16, 28, 16 * 28 * 3));
+		let colour = |index: usize| STANDARD_VGA_COLOURS[index];
+		assert_eq!(raster.pixel(0, 0), colour(15));
+		assert_eq!(raster.pixel(1, 13), colour(1));
+		// The background of a blinking cell loses its top bit.
+		assert_eq!(raster.pixel(8, 5), colour(2));
+		assert_eq!(raster.pixel(12, 5), colour(4));
+		assert_eq!(raster.pixel(0, 20), colour(7));
+		assert_eq!(raster.pixel(8, 27), colour(14));
+		assert_eq!(raster.pixel(15, 14), colour(4));
+
+		// In the hidden half of the blink only the background shows.
+		screen.blink_visible = false;
+		raster.render(&font, &PaletteTransform::default(), &screen);
+		assert_eq!(raster.pixel(8, 5), colour(4));
+		assert_eq!(raster.pixel(8, 27), colour(14));
+
+		// Without blinking, the background gets all 16 colours.
+		screen.blink_enabled = false;
+		raster.render(&font, &PaletteTransform::default(), &screen);
+		assert_eq!(raster.pixel(8, 5), colour(2));
+		assert_eq!(raster.pixel(12, 5), colour(12));
+	}
+
+	#[test] fn test_render_matches_render_by_cell() {
+		// A font with a different pattern for each character, and a screen with every character and
+		// attribute on it.
+		let font = TextFont::from_strip(|x, y| (x / GLYPH_WIDTH + x % GLYPH_WIDTH * 3 + y) % 5 < 2);
+		let text: Vec<u8> = (0 .. 80 * 25).flat_map(|cell_index: usize| vec![cell_index as u8, (cell_index * 7 / 3) as u8]).collect();
+		let palettes = [PaletteTransform::default(), PaletteTransform { preset: PalettePreset::Monochrome, ..PaletteTransform::default() }];
+		let mut raster = TextRaster::new();
+		let mut by_cell_raster = TextRaster::new();
+		for palette in palettes.iter() {
+			for &char_height in &[8, 14, 16] {
+				for &(blink_enabled, blink_visible) in &[(true, true), (true, false), (false, false)] {
+					for &cursor in &[None, Some((79, 24, 0, 1)), Some((3, 7, 6, 13)), Some((0, 0, 12, 15))] {
+						let screen = TextScreen { text: &text, columns: 80, rows: 25, char_height, blink_enabled, blink_visible, cursor };
+						raster.render(&font, palette, &screen);
+						by_cell_raster.render_by_cell(&font, palette, &screen);
+						assert_eq!((raster.width, raster.height), (by_cell_raster.width, by_cell_raster.height));
+						assert!(raster.pixels == by_cell_raster.pixels, "{:?}", screen);
+					}
+				}
+			}
+		}
+	}
+
+	#[test] fn test_render_cursor_and_stretch() {
+		let font = TextFont::from_strip(|x, y| x / GLYPH_WIDTH == b'-' as usize && y == 7);
+		let text = [b'-', 0x07];
+		let cursor_screen = TextScreen { text: &text, columns: 1, rows: 1, char_height: 8, blink_enabled: true, blink_visible: true, cursor: Some((0, 0, 6, 9)) };
+		let mut raster = TextRaster::new();
+		raster.render(&font, &PaletteTransform::default(), &cursor_screen);
+		assert_eq!(raster.height, 8);
+		let grey = STANDARD_VGA_COLOURS[7];
+		let lit_rows: Vec<usize> = (0 .. 8).filter(|&y| raster.pixel(3, y) == grey).collect();
+		// Font row 7 of 14 lands on row 4 of 8, and the cursor is clipped to the cell.
+		assert_eq!(lit_rows, vec![4, 6, 7]);
+	}
+
+	#[test] fn test_render_empty_screen() {
+		let screen = TextScreen { text: &[], columns: 0, rows: 0, char_height: 0, blink_enabled: true, blink_visible: true, cursor: Some((0, 0, 0, 7)) };
+		let mut raster = TextRaster::new();
+		raster.render(&make_test_font(), &PaletteTransform::default(), &screen);
+		assert_eq!((raster.width, raster.height, raster.pixels.len()), (0, 0, 0));
+	}
+
+	#[test] fn test_font_from_alpha_pixels() {
+		// Only the top left pixel of character 1 is opaque, with the alpha in the top byte.
+		let pitch = 256 * GLYPH_WIDTH * 4;
+		let mut pixels = vec![0; pitch * FONT_GLYPH_HEIGHT];
+		pixels[GLYPH_WIDTH * 4 .. GLYPH_WIDTH * 4 + 4].copy_from_slice(&0xff000000u32.to_ne_bytes());
+		let font = TextFont::from_alpha_pixels(&pixels, pitch, 4, 0xff000000).unwrap();
+		assert_eq!(font, TextFont::from_strip(|x, y| (x, y) == (GLYPH_WIDTH, 0)));
+		assert!(TextFont::from_alpha_pixels(&pixels, pitch, 3, 0xff000000).is_err());
+		assert!(TextFont::from_alpha_pixels(&pixels, pitch, 4, 0).is_err());
+		assert!(TextFont::from_alpha_pixels(&pixels[.. pitch], pitch, 4, 0xff000000).is_err());
+	}
+}
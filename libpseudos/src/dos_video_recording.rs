@@ -0,0 +1,527 @@
+// Recording what the screen shows over time, for capturing a program being played without a
+// screen recorder, or sending someone exactly what the screen did. There are two kinds:
+//
+// - DeltaRecorder writes the compact .ptv format described below, with the cells that changed
+//   each frame, which RecordingReader plays back.
+// - RawFrameWriter draws each frame with TextRaster and writes the RGB24 pixels at a fixed frame
+//   rate, for piping into an encoder like ffmpeg.
+//
+// A frame is one standard timer tick (see dos_event_handler::STANDARD_TICK_SECONDS), which is how often the
+// runners record, so recordings play back at the speed the program ran regardless of the host.
+//
+// The .ptv format is a header followed by records, with every number little endian:
+//
+//   Header:
+//     4 bytes  "PTV" and 1Ah
+//     u16      format version (PTV_VERSION, currently 1)
+//     u32      the length of a frame in microseconds
+//
+//   Records, each starting with its tag byte:
+//     01h Mode     u8 video mode, u8 columns, u8 rows, u8 character height in pixels. Every cell
+//                  is a grey on black space (char 20h, attribute 07h) after it, until frames say
+//                  otherwise.
+//     02h Palette  16 colours, each u8 red, u8 green, u8 blue, for attributes 0 to 15.
+//     03h Frame    u32 frame number, u8 cursor column, u8 cursor row, u8 first and u8 last
+//                  scanline of the cursor (both FFh when it's hidden), u8 flags (bit 0 is set when
+//                  the top bit of attributes makes characters blink), u16 number of changed cells,
+//                  then for each changed cell u8 column, u8 row, u8 character, u8 attribute.
+//     04h Scanlines  Reserved for the changed runs of scanlines in graphics modes, which there
+//                  aren't any of yet.
+//
+// Mode and palette records come before the first frame, and again before any frame they changed
+// for. Frames where nothing changed aren't written, so frame numbers can skip. The file ends at the
+// end of a record. Readers must reject versions newer than they know, and records they don't know,
+// since they can't tell how long they are.
+
+use crate::dos_event_handler::DosEventHandler;
+use crate::dos_palette::{PalettePreset, PaletteTransform, Rgb};
+use crate::dos_text_raster::{TextFont, TextRaster, TextScreen};
+use crate::machine::Machine;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use std::io::{self, Read, Write};
+
+pub const PTV_MAGIC: [u8; 4] = *b"PTV\x1a";
+pub const PTV_VERSION: u16 = 1;
+const MODE_RECORD: u8 = 0x01;
+const PALETTE_RECORD: u8 = 0x02;
+const FRAME_RECORD: u8 = 0x03;
+const HIDDEN_CURSOR: u8 = 0xff;
+const BLINK_ENABLED_FLAG: u8 = 0x01;
+const BLANK_CELL: [u8; 2] = [b' ', 0x07];
+// How often the cursor and blinking characters blink, matching the SDL frontend.
+const CURSOR_BLINK_MS: u64 = 534;
+const CHAR_BLINK_MS: u64 = 450;
+
+/// Everything about the screen that's recorded each frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoFrame {
+	pub mode: u8,
+	pub columns: u8,
+	pub rows: u8,
+	/// The height of the character cells in pixels.
+	pub char_height: u8,
+	/// Character and attribute byte pairs, a row at a time.
+	pub text: Vec<u8>,
+	/// The cursor's column and row, and the first and last scanlines it covers, if it's showing.
+	pub cursor: Option<(u8, u8, u8, u8)>,
+	pub blink_enabled: bool,
+	pub palette: [Rgb; 16],
+}
+
+impl VideoFrame {
+	/// Takes what the program is showing on the screen now, drawn in the palette's colours.
+	pub fn capture(handler: &DosEventHandler, machine: &dyn Machine, palette: &PaletteTransform) -> VideoFrame {
+		let (columns, rows) = handler.video_mode.text_dims();
+		let mut text = handler.get_composed_text(machine);
+		text.truncate(columns as usize * rows as usize * 2);
+		let cursor = handler.get_cursor_scanlines(machine).map(|(start_line, end_line)| {
+			let (cursor_x, cursor_y) = handler.get_cursor_position(machine);
+			(cursor_x, cursor_y, start_line, end_line)
+		});
+		VideoFrame {
+			mode: handler.video_mode.mode_index(),
+			columns: columns as u8,
+			rows: rows as u8,
+			char_height: handler.video_mode.char_pixel_dims().1 as u8,
+			text,
+			cursor,
+			blink_enabled: handler.blink_enabled,
+			palette: palette.colours(),
+		}
+	}
+
+	/// A screen of blanks in a mode, like a mode record makes.
+	fn blank(mode: u8, columns: u8, rows: u8, char_height: u8, palette: [Rgb; 16]) -> VideoFrame {
+		VideoFrame {
+			mode,
+			columns,
+			rows,
+			char_height,
+			text: BLANK_CELL.iter().cloned().cycle().take(columns as usize * rows as usize * 2).collect(),
+			cursor: None,
+			blink_enabled: true,
+			palette,
+		}
+	}
+
+	fn same_mode(&self, other: &VideoFrame) -> bool {
+		(self.mode, self.columns, self.rows, self.char_height) == (other.mode, other.columns, other.rows, other.char_height)
+	}
+
+	/// The recorded colours, for drawing the frame with TextRaster.
+	pub fn palette_transform(&self) -> PaletteTransform {
+		PaletteTransform { preset: PalettePreset::Custom, custom_colours: Some(self.palette) }
+	}
+
+	/// The frame as TextRaster draws it, with the cursor and blinking characters in the part of the
+	/// blink for a time since the start.
+	pub fn text_screen(&self, time_ms: u64) -> TextScreen<'_> {
+		TextScreen {
+			text: &self.text,
+			columns: self.columns as usize,
+			rows: self.rows as usize,
+			char_height: self.char_height as usize,
+			blink_enabled: self.blink_enabled,
+			blink_visible: time_ms % CHAR_BLINK_MS < CHAR_BLINK_MS / 2,
+			cursor: match self.cursor {
+				Some((column, row, start_line, end_line)) if time_ms % CURSOR_BLINK_MS < CURSOR_BLINK_MS / 2 => {
+					Some((column as usize, row as usize, start_line, end_line))
+				}
+				_ => None,
+			},
+		}
+	}
+}
+
+/// Something frames of the screen can be recorded to.
+pub trait VideoRecorder {
+	/// Records the screen at the end of the next frame.
+	fn record(&mut self, frame: &VideoFrame) -> io::Result<()>;
+	/// Writes out anything that's buffered.
+	fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Writes the .ptv format, with the cells that changed each frame.
+pub struct DeltaRecorder<W: Write> {
+	writer: W,
+	frame_number: u32,
+	last_frame: Option<VideoFrame>,
+}
+
+impl<W: Write> DeltaRecorder<W> {
+	/// Writes the header, for frames of the given length.
+	pub fn new(mut writer: W, frame_micros: u32) -> io::Result<DeltaRecorder<W>> {
+		writer.write_all(&PTV_MAGIC)?;
+		writer.write_u16::<LittleEndian>(PTV_VERSION)?;
+		writer.write_u32::<LittleEndian>(frame_micros)?;
+		Ok(DeltaRecorder { writer, frame_number: 0, last_frame: None })
+	}
+
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+}
+
+impl<W: Write> VideoRecorder for DeltaRecorder<W> {
+	fn record(&mut self, frame: &VideoFrame) -> io::Result<()> {
+		let frame_number = self.frame_number;
+		self.frame_number += 1;
+		let writer = &mut self.writer;
+		// A new mode starts from a blank screen, so the cells are compared with that.
+		let blank_frame;
+		let last_frame = match self.last_frame {
+			Some(ref last_frame) if last_frame.same_mode(frame) => last_frame,
+			_ => {
+				writer.write_all(&[MODE_RECORD, frame.mode, frame.columns, frame.rows, frame.char_height])?;
+				blank_frame = VideoFrame::blank(frame.mode, frame.columns, frame.rows, frame.char_height, [(0, 0, 0); 16]);
+				&blank_frame
+			}
+		};
+		if self.last_frame.as_ref().map(|last_frame| last_frame.palette) != Some(frame.palette) {
+			writer.write_all(&[PALETTE_RECORD])?;
+			for &(r, g, b) in frame.palette.iter() {
+				writer.write_all(&[r, g, b])?;
+			}
+		}
+		let changed_cells: Vec<usize> = (0 .. frame.text.len() / 2)
+			.filter(|&cell_index| frame.text[cell_index * 2 .. cell_index * 2 + 2] != last_frame.text[cell_index * 2 .. cell_index * 2 + 2])
+			.collect();
+		// The cursor and flags always go with a new mode, since a mode record resets the screen.
+		let unchanged = changed_cells.is_empty() && match self.last_frame {
+			Some(ref last_frame) => last_frame.same_mode(frame) && last_frame.cursor == frame.cursor && last_frame.blink_enabled == frame.blink_enabled,
+			None => false,
+		};
+		if !unchanged {
+			writer.write_all(&[FRAME_RECORD])?;
+			writer.write_u32::<LittleEndian>(frame_number)?;
+			let (column, row, start_line, end_line) = frame.cursor.unwrap_or((0, 0, HIDDEN_CURSOR, HIDDEN_CURSOR));
+			let flags = if frame.blink_enabled { BLINK_ENABLED_FLAG } else { 0 };
+			writer.write_all(&[column, row, start_line, end_line, flags])?;
+			writer.write_u16::<LittleEndian>(changed_cells.len() as u16)?;
+			for cell_index in changed_cells {
+				let (row, column) = (cell_index / frame.columns as usize, cell_index % frame.columns as usize);
+				writer.write_all(&[column as u8, row as u8, frame.text[cell_index * 2], frame.text[cell_index * 2 + 1]])?;
+			}
+		}
+		self.last_frame = Some(frame.clone());
+		Ok(())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+}
+
+#[derive(Debug)]
+pub enum RecordingError {
+	/// The file doesn't start with the .ptv signature.
+	NotARecording,
+	/// The file is in a newer version of the format than this reads. Holds the version.
+	UnsupportedVersion(u16),
+	/// A record has a tag that isn't known. Holds the tag.
+	UnknownRecord(u8),
+	/// A record doesn't make sense, such as a cell off the screen. Holds what's wrong with it.
+	BadRecord(String),
+	/// The file ended part way through a record.
+	Truncated,
+	Io(io::Error),
+}
+
+impl std::fmt::Display for RecordingError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			RecordingError::NotARecording => write!(f, "Not a video recording: the file doesn't start with \"PTV\""),
+			RecordingError::UnsupportedVersion(version) => write!(f, "The recording is format version {}, but only up to version {} can be read", version, PTV_VERSION),
+			RecordingError::UnknownRecord(tag) => write!(f, "The recording has an unknown record type {:02X}h", tag),
+			RecordingError::BadRecord(reason) => write!(f, "The recording is invalid: {}", reason),
+			RecordingError::Truncated => write!(f, "The recording is truncated"),
+			RecordingError::Io(err) => write!(f, "Failed to read the recording: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for RecordingError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			RecordingError::Io(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for RecordingError {
+	fn from(err: io::Error) -> RecordingError {
+		if err.kind() == io::ErrorKind::UnexpectedEof {
+			RecordingError::Truncated
+		} else {
+			RecordingError::Io(err)
+		}
+	}
+}
+
+/// Plays back a .ptv recording a frame at a time.
+pub struct RecordingReader<R: Read> {
+	reader: R,
+	pub version: u16,
+	/// The length of a frame in microseconds.
+	pub frame_micros: u32,
+	screen: VideoFrame,
+}
+
+impl<R: Read> RecordingReader<R> {
+	/// Reads the header.
+	pub fn new(mut reader: R) -> Result<RecordingReader<R>, RecordingError> {
+		let mut magic = [0; 4];
+		reader.read_exact(&mut magic).map_err(|_| RecordingError::NotARecording)?;
+		if magic != PTV_MAGIC {
+			return Err(RecordingError::NotARecording);
+		}
+		let version = reader.read_u16::<LittleEndian>()?;
+		if version > PTV_VERSION {
+			return Err(RecordingError::UnsupportedVersion(version));
+		}
+		let frame_micros = reader.read_u32::<LittleEndian>()?;
+		Ok(RecordingReader { reader, version, frame_micros, screen: VideoFrame::blank(0, 0, 0, 0, [(0, 0, 0); 16]) })
+	}
+
+	/// The screen as of the last frame read.
+	pub fn screen(&self) -> &VideoFrame {
+		&self.screen
+	}
+
+	/// Reads up to the end of the next frame record, and returns its frame number, or None at the
+	/// end of the recording.
+	pub fn next_frame(&mut self) -> Result<Option<u32>, RecordingError> {
+		loop {
+			let mut tag = [0];
+			if self.reader.read(&mut tag)? == 0 {
+				return Ok(None);
+			}
+			match tag[0] {
+				MODE_RECORD => {
+					let mut mode = [0; 4];
+					self.reader.read_exact(&mut mode)?;
+					if mode[1] == 0 || mode[2] == 0 || mode[3] == 0 {
+						return Err(RecordingError::BadRecord(format!("a mode record has a {}x{} screen with {} pixel high characters", mode[1], mode[2], mode[3])));
+					}
+					self.screen = VideoFrame::blank(mode[0], mode[1], mode[2], mode[3], self.screen.palette);
+				}
+				PALETTE_RECORD => {
+					for colour in self.screen.palette.iter_mut() {
+						let mut rgb = [0; 3];
+						self.reader.read_exact(&mut rgb)?;
+						*colour = (rgb[0], rgb[1], rgb[2]);
+					}
+				}
+				FRAME_RECORD => {
+					let frame_number = self.reader.read_u32::<LittleEndian>()?;
+					if self.screen.columns == 0 {
+						return Err(RecordingError::BadRecord(format!("frame {} comes before the first mode record", frame_number)));
+					}
+					let mut state = [0; 5];
+					self.reader.read_exact(&mut state)?;
+					let [column, row, start_line, end_line, flags] = state;
+					self.screen.cursor = if start_line == HIDDEN_CURSOR { None } else { Some((column, row, start_line, end_line)) };
+					self.screen.blink_enabled = flags & BLINK_ENABLED_FLAG != 0;
+					let cell_count = self.reader.read_u16::<LittleEndian>()?;
+					for _ in 0 .. cell_count {
+						let mut cell = [0; 4];
+						self.reader.read_exact(&mut cell)?;
+						let [column, row, char_code, attribute] = cell;
+						if column >= self.screen.columns || row >= self.screen.rows {
+							return Err(RecordingError::BadRecord(format!("frame {} changes cell {},{} of a {}x{} screen", frame_number, column, row, self.screen.columns, self.screen.rows)));
+						}
+						let cell_addr = (row as usize * self.screen.columns as usize + column as usize) * 2;
+						self.screen.text[cell_addr] = char_code;
+						self.screen.text[cell_addr + 1] = attribute;
+					}
+					return Ok(Some(frame_number));
+				}
+				tag => return Err(RecordingError::UnknownRecord(tag)),
+			}
+		}
+	}
+}
+
+/// Draws each frame and writes the RGB24 pixels, with no gaps between rows, at a fixed frame rate,
+/// repeating or dropping frames to keep to it. The image is the size of the first frame's screen
+/// (see raw_frame_size), and the screen can't change size afterwards.
+pub struct RawFrameWriter<W: Write> {
+	writer: W,
+	font: TextFont,
+	raster: TextRaster,
+	frame_micros: u64,
+	fps: u32,
+	frames_recorded: u64,
+	frames_written: u64,
+	size: Option<(usize, usize)>,
+}
+
+/// The width and height of the images RawFrameWriter writes for a screen.
+pub fn raw_frame_size(columns: usize, rows: usize, char_height: usize) -> (usize, usize) {
+	(columns * crate::dos_text_raster::GLYPH_WIDTH, rows * char_height)
+}
+
+impl<W: Write> RawFrameWriter<W> {
+	/// Writes fps images a second, for frames of the given length.
+	pub fn new(writer: W, font: TextFont, frame_micros: u32, fps: u32) -> RawFrameWriter<W> {
+		RawFrameWriter {
+			writer,
+			font,
+			raster: TextRaster::new(),
+			frame_micros: frame_micros as u64,
+			fps,
+			frames_recorded: 0,
+			frames_written: 0,
+			size: None,
+		}
+	}
+}
+
+impl<W: Write> VideoRecorder for RawFrameWriter<W> {
+	fn record(&mut self, frame: &VideoFrame) -> io::Result<()> {
+		self.frames_recorded += 1;
+		// Every image due by the end of this frame shows it.
+		let end_micros = self.frames_recorded * self.frame_micros;
+		while self.frames_written * 1_000_000 / self.fps as u64 <= end_micros {
+			let time_ms = self.frames_written * 1000 / self.fps as u64;
+			self.raster.render(&self.font, &frame.palette_transform(), &frame.text_screen(time_ms));
+			let size = (self.raster.width, self.raster.height);
+			if *self.size.get_or_insert(size) != size {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "The screen changed size, which a raw video can't follow"));
+			}
+			self.writer.write_all(&self.raster.pixels)?;
+			self.frames_written += 1;
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_code_page::cp437_to_string;
+	use crate::dos_event_handler::tests::make_test_handler;
+
+	use xachtsechs::types::{EventHandler, Reg};
+
+	fn screen_lines(frame: &VideoFrame) -> String {
+		frame.text.chunks(frame.columns as usize * 2).map(|row| {
+			let chars: Vec<u8> = row.chunks(2).map(|cell| cell[0]).collect();
+			cp437_to_string(&chars).trim_end().to_string() + "\n"
+		}).collect()
+	}
+
+	fn machine_set(machine: &mut xachtsechs::machine8086::Machine8086, ax: u16, bx: u16, cx: u16, dx: u16) {
+		machine.set_reg_u16(Reg::AX, ax);
+		machine.set_reg_u16(Reg::BX, bx);
+		machine.set_reg_u16(Reg::CX, cx);
+		machine.set_reg_u16(Reg::DX, dx);
+	}
+
+	#[test] fn test_record_and_play_back() {
+		let (mut handler, mut machine) = make_test_handler();
+		let palette = PaletteTransform::default();
+		let mut recorder = DeltaRecorder::new(vec![], 54925).unwrap();
+		let call_bios = |handler: &mut DosEventHandler, machine: &mut _, ax: u16, bx: u16, cx: u16, dx: u16| {
+			machine_set(machine, ax, bx, cx, dx);
+			handler.handle_interrupt(machine, 0x10);
+		};
+		// Frame 0 is the blank screen, frame 1 has some text, frame 2 is the same, frame 3 scrolls the
+		// top left corner up in red on blue, and frame 4 turns blinking off and hides the cursor.
+		recorder.record(&VideoFrame::capture(&handler, &machine, &palette)).unwrap();
+		for &c in b"HELLO\r\nWORLD".iter() {
+			call_bios(&mut handler, &mut machine, 0x0e00 | c as u16, 0, 0, 0);
+		}
+		recorder.record(&VideoFrame::capture(&handler, &machine, &palette)).unwrap();
+		let frame_2_start = recorder.writer.len();
+		recorder.record(&VideoFrame::capture(&handler, &machine, &palette)).unwrap();
+		assert_eq!(recorder.writer.len(), frame_2_start);
+		call_bios(&mut handler, &mut machine, 0x0601, 0x1400, 0x0000, 0x0102);
+		recorder.record(&VideoFrame::capture(&handler, &machine, &palette)).unwrap();
+		call_bios(&mut handler, &mut machine, 0x1003, 0x0000, 0, 0);
+		call_bios(&mut handler, &mut machine, 0x0100, 0, 0x2000, 0);
+		let live_frame = VideoFrame::capture(&handler, &machine, &palette);
+		recorder.record(&live_frame).unwrap();
+		
+		let recording = recorder.into_inner();
+		let mut reader = RecordingReader::new(&recording[..]).unwrap();
+		assert_eq!((reader.version, reader.frame_micros), (PTV_VERSION, 54925));
+		let mut frame_numbers = vec![];
+		while let Some(frame_number) = reader.next_frame().unwrap() {
+			frame_numbers.push(frame_number);
+			if frame_number == 1 {
+				assert_eq!(screen_lines(reader.screen()), format!("HELLO\nWORLD\n{}", "\n".repeat(23)));
+				assert_eq!(reader.screen().cursor, Some((5, 1, 11, 12)));
+			}
+		}
+		assert_eq!(frame_numbers, vec![0, 1, 3, 4]);
+		// The played back screen is the live one.
+		assert_eq!(reader.screen(), &live_frame);
+		assert_eq!(screen_lines(reader.screen()), format!("WORLO\n   LD\n{}", "\n".repeat(23)));
+		assert_eq!(&reader.screen().text[160 .. 162], &[b' ', 0x14]);
+		assert_eq!((reader.screen().cursor, reader.screen().blink_enabled), (None, false));
+	}
+
+	#[test] fn test_recording_errors() {
+		let mut recording = vec![];
+		let mut recorder = DeltaRecorder::new(&mut recording, 54925).unwrap();
+		recorder.record(&VideoFrame::blank(3, 80, 25, 14, [(0, 0, 0); 16])).unwrap();
+		
+		// A newer version is rejected before anything else is read.
+		let mut future = recording.clone();
+		future[4] = 2;
+		match RecordingReader::new(&future[..]) {
+			Err(err) => assert_eq!(err.to_string(), "The recording is format version 2, but only up to version 1 can be read"),
+			Ok(_) => panic!("A future version was read"),
+		}
+		assert!(matches!(RecordingReader::new(&b"MZ\x90\x00"[..]), Err(RecordingError::NotARecording)));
+		let mut truncated = RecordingReader::new(&recording[.. recording.len() - 1]).unwrap();
+		assert!(matches!(truncated.next_frame(), Err(RecordingError::Truncated)));
+		let mut unknown = recording[.. 10].to_vec();
+		unknown.push(0x04);
+		assert!(matches!(RecordingReader::new(&unknown[..]).unwrap().next_frame(), Err(RecordingError::UnknownRecord(0x04))));
+		let mut off_screen = recording.clone();
+		off_screen.extend(&[FRAME_RECORD, 1, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 1, 0, 80, 0, b'X', 0x07]);
+		let mut reader = RecordingReader::new(&off_screen[..]).unwrap();
+		assert_eq!(reader.next_frame().unwrap(), Some(0));
+		assert!(matches!(reader.next_frame(), Err(RecordingError::BadRecord(_))));
+		
+		// A recording that stops after the header has no frames, and an empty screen.
+		let mut header_only = RecordingReader::new(&recording[.. 10]).unwrap();
+		assert_eq!(header_only.next_frame().unwrap(), None);
+		assert_eq!((header_only.screen().columns, header_only.screen().rows), (0, 0));
+		let mut no_mode = recording[.. 10].to_vec();
+		no_mode.extend(&[FRAME_RECORD, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0]);
+		assert!(matches!(RecordingReader::new(&no_mode[..]).unwrap().next_frame(), Err(RecordingError::BadRecord(_))));
+		for bad_mode in [[3, 0, 25, 14], [3, 80, 0, 14], [3, 80, 25, 0]].iter() {
+			let mut empty_mode = recording[.. 10].to_vec();
+			empty_mode.push(MODE_RECORD);
+			empty_mode.extend(bad_mode);
+			assert!(matches!(RecordingReader::new(&empty_mode[..]).unwrap().next_frame(), Err(RecordingError::BadRecord(_))));
+		}
+	}
+
+	#[test] fn test_raw_frames() {
+		let font = TextFont::from_strip(|_, _| false);
+		let mut writer = RawFrameWriter::new(vec![], font, 54925, 10);
+		let frame = VideoFrame::blank(3, 80, 25, 14, [(0, 0, 0xaa); 16]);
+		// 19 frames are just over a second, which is 11 images at 10 a second, counting the first.
+		for _ in 0 .. 19 {
+			writer.record(&frame).unwrap();
+		}
+		let (width, height) = raw_frame_size(80, 25, 14);
+		assert_eq!((width, height), (640, 350));
+		assert_eq!(writer.writer.len(), 11 * width * height * 3);
+		assert_eq!(&writer.writer[.. 3], &[0, 0, 0xaa]);
+		// The size can't change part way through. The next image is due in the second frame.
+		let taller = VideoFrame::blank(3, 80, 25, 16, [(0, 0, 0); 16]);
+		assert_eq!((0 .. 2).try_for_each(|_| writer.record(&taller)).map_err(|err| err.kind()), Err(io::ErrorKind::InvalidData));
+	}
+}
@@ -0,0 +1,281 @@
+// Statistics on how a program draws to the screen, for working out why a new program's screen is
+// blank or wrong: whether it writes to video memory itself, uses the BIOS, or goes through DOS,
+// and which parts of video memory it changes (another text page, the mono segment or the graphics
+// segment rather than the page being displayed).
+//
+// Like the runner's check for changes on the screen (see count_changed_cells), writes aren't
+// watched as they happen. A shadow copy of the video window is compared against memory, but in 4K
+// pages rather than cells. The comparison happens at the end of every frame, and around each
+// BIOS and DOS output call, so the changes those calls make can be told apart from the ones the
+// program makes directly.
+
+use std::collections::BTreeMap;
+
+use crate::machine::Machine;
+
+/// The start of the memory the video adapters can be reached through, from the EGA/VGA graphics
+/// segment at A000 to the end of the colour text segment at B800.
+pub const VIDEO_WINDOW_START: u32 = 0xa0000;
+pub const VIDEO_WINDOW_BYTES: usize = 0x20000;
+/// The changes are counted in pages of this size, which is the size of an 80x25 text page.
+pub const VIDEO_STATS_PAGE_BYTES: usize = 0x1000;
+const VIDEO_STATS_PAGE_COUNT: usize = VIDEO_WINDOW_BYTES / VIDEO_STATS_PAGE_BYTES;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OutputMethod {
+	/// The program wrote to video memory itself.
+	Direct,
+	/// Through int 10h.
+	Bios,
+	/// Through the int 21h functions that write to the console.
+	DosConsole,
+}
+
+impl OutputMethod {
+	pub fn name(&self) -> &'static str {
+		match self {
+			OutputMethod::Direct => "direct",
+			OutputMethod::Bios => "bios",
+			OutputMethod::DosConsole => "dos_console",
+		}
+	}
+
+	fn description(&self) -> &'static str {
+		match self {
+			OutputMethod::Direct => "direct writes",
+			OutputMethod::Bios => "int 10h",
+			OutputMethod::DosConsole => "int 21h console output",
+		}
+	}
+}
+
+/// Describes one of the pages the stats are counted in by its segment, eg. "B800 page 1" for the
+/// second 80x25 colour text page, or "A000 (graphics)".
+pub fn video_page_name(page_index: usize) -> String {
+	let segment = (VIDEO_WINDOW_START as usize + page_index * VIDEO_STATS_PAGE_BYTES) >> 4;
+	match segment {
+		0xb800 ..= 0xbfff => format!("B800 page {}", (segment - 0xb800) / 0x100),
+		0xb000 ..= 0xb7ff => format!("{:04X} (mono)", segment),
+		_ => format!("{:04X} (graphics)", segment),
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BiosFunctionStats {
+	pub calls: u64,
+	pub bytes_changed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct VideoStats {
+	// The video window as it was when it was last compared.
+	shadow: Vec<u8>,
+	frame_count: u64,
+	// The bytes changed in each page, by how they were changed.
+	page_bytes: BTreeMap<(OutputMethod, usize), u64>,
+	// Int 10h calls by AH, including ones that don't change video memory, like cursor moves.
+	bios_functions: BTreeMap<u8, BiosFunctionStats>,
+	dos_console_chars: u64,
+	// The bytes changed in each page during the frame that's running, and the one before it.
+	frame_page_bytes: [u64; VIDEO_STATS_PAGE_COUNT],
+	last_frame_page_bytes: [u64; VIDEO_STATS_PAGE_COUNT],
+}
+
+impl VideoStats {
+	/// Starts counting from what's in video memory now, so the screen the program starts with
+	/// isn't counted.
+	pub fn new(machine: &dyn Machine) -> VideoStats {
+		VideoStats {
+			shadow: video_window(machine).to_vec(),
+			frame_count: 0,
+			page_bytes: BTreeMap::new(),
+			bios_functions: BTreeMap::new(),
+			dos_console_chars: 0,
+			frame_page_bytes: [0; VIDEO_STATS_PAGE_COUNT],
+			last_frame_page_bytes: [0; VIDEO_STATS_PAGE_COUNT],
+		}
+	}
+
+	/// Puts the changes since the last comparison down to the given method, and returns the number
+	/// of bytes that changed. Before an output call, this is called with Direct for the changes the
+	/// program made since the last one.
+	pub fn sample(&mut self, machine: &dyn Machine, method: OutputMethod) -> u64 {
+		let window = video_window(machine);
+		let mut total_changed = 0;
+		for (page_index, (shadow_page, page)) in self.shadow.chunks_mut(VIDEO_STATS_PAGE_BYTES).zip(window.chunks(VIDEO_STATS_PAGE_BYTES)).enumerate() {
+			if shadow_page == page {
+				continue;
+			}
+			let changed = shadow_page.iter().zip(page).filter(|(before, after)| before != after).count() as u64;
+			*self.page_bytes.entry((method, page_index)).or_insert(0) += changed;
+			self.frame_page_bytes[page_index] += changed;
+			total_changed += changed;
+			shadow_page.copy_from_slice(page);
+		}
+		total_changed
+	}
+
+	/// Records an int 10h call with the function in AH, once it's finished.
+	pub fn record_bios_call(&mut self, machine: &dyn Machine, function: u8) {
+		let bytes_changed = self.sample(machine, OutputMethod::Bios);
+		let function_stats = self.bios_functions.entry(function).or_default();
+		function_stats.calls += 1;
+		function_stats.bytes_changed += bytes_changed;
+	}
+
+	/// Records a character written to the screen through DOS, once it's been written.
+	pub fn record_dos_console_char(&mut self, machine: &dyn Machine) {
+		self.sample(machine, OutputMethod::DosConsole);
+		self.dos_console_chars += 1;
+	}
+
+	/// Called at the end of each frame.
+	pub fn end_frame(&mut self, machine: &dyn Machine) {
+		self.sample(machine, OutputMethod::Direct);
+		self.frame_count += 1;
+		self.last_frame_page_bytes = self.frame_page_bytes;
+		self.frame_page_bytes = [0; VIDEO_STATS_PAGE_COUNT];
+	}
+
+	pub fn frame_count(&self) -> u64 {
+		self.frame_count
+	}
+
+	/// The bytes changed in each page during the last frame, by any method.
+	pub fn last_frame_page_bytes(&self) -> &[u64] {
+		&self.last_frame_page_bytes
+	}
+
+	pub fn bytes_changed(&self, method: OutputMethod) -> u64 {
+		self.page_bytes.iter().filter(|((page_method, _), _)| *page_method == method).map(|(_, bytes)| bytes).sum()
+	}
+
+	/// The bytes changed in a page, by any method.
+	pub fn page_bytes_changed(&self, page_index: usize) -> u64 {
+		self.page_bytes.iter().filter(|((_, index), _)| *index == page_index).map(|(_, bytes)| bytes).sum()
+	}
+
+	pub fn bios_functions(&self) -> &BTreeMap<u8, BiosFunctionStats> {
+		&self.bios_functions
+	}
+
+	/// How most of the changes to video memory were made, or None if there weren't any.
+	pub fn main_output_method(&self) -> Option<OutputMethod> {
+		[OutputMethod::Direct, OutputMethod::Bios, OutputMethod::DosConsole].iter()
+			.map(|method| (*method, self.bytes_changed(*method)))
+			.filter(|(_, bytes)| *bytes > 0)
+			.max_by_key(|(_, bytes)| *bytes)
+			.map(|(method, _)| method)
+	}
+
+	/// A human readable report. The address of the text being displayed is used to point out when
+	/// the program is drawing somewhere else.
+	pub fn summary(&self, display_start_address: u32) -> String {
+		let mut summary = format!("Video output over {} frames:\n", self.frame_count);
+		let total_bytes: u64 = self.page_bytes.values().sum();
+		if total_bytes == 0 {
+			summary += "No video memory (A000-BFFF) was written to\n";
+		}
+		let mut method_bytes: Vec<(OutputMethod, u64)> = [OutputMethod::Direct, OutputMethod::Bios, OutputMethod::DosConsole].iter()
+			.map(|method| (*method, self.bytes_changed(*method)))
+			.filter(|(_, bytes)| *bytes > 0)
+			.collect();
+		method_bytes.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+		for (method, bytes) in method_bytes {
+			let mut pages: Vec<(usize, u64)> = self.page_bytes.iter()
+				.filter(|((page_method, _), _)| *page_method == method)
+				.map(|((_, page_index), page_bytes)| (*page_index, *page_bytes))
+				.collect();
+			pages.sort_by_key(|(_, page_bytes)| std::cmp::Reverse(*page_bytes));
+			let page_names: Vec<String> = pages.iter().map(|(page_index, _)| video_page_name(*page_index)).collect();
+			summary += &format!("{}% of output via {} to {}\n", bytes * 100 / total_bytes, method.description(), page_names.join(", "));
+		}
+		for (function, function_stats) in &self.bios_functions {
+			summary += &format!("int 10h AH={:02x}h: {} calls, {} bytes changed\n", function, function_stats.calls, function_stats.bytes_changed);
+		}
+		if self.dos_console_chars > 0 {
+			summary += &format!("int 21h console output: {} characters\n", self.dos_console_chars);
+		}
+		let display_offset = display_start_address.wrapping_sub(VIDEO_WINDOW_START) as usize;
+		if total_bytes > 0 && display_offset < VIDEO_WINDOW_BYTES {
+			let display_page = display_offset / VIDEO_STATS_PAGE_BYTES;
+			if self.page_bytes_changed(display_page) == 0 {
+				summary += &format!("Nothing was written to the page being displayed ({})\n", video_page_name(display_page));
+			}
+		}
+		summary
+	}
+
+	/// The stats as JSON, with the pages given by segment.
+	pub fn to_json(&self) -> String {
+		let pages: Vec<String> = (0 .. VIDEO_STATS_PAGE_COUNT).filter(|page_index| self.page_bytes_changed(*page_index) > 0).map(|page_index| {
+			let method_bytes = |method| self.page_bytes.get(&(method, page_index)).cloned().unwrap_or(0);
+			format!("{{\"segment\":{},\"direct\":{},\"bios\":{},\"dos_console\":{}}}",
+				(VIDEO_WINDOW_START as usize + page_index * VIDEO_STATS_PAGE_BYTES) >> 4,
+				method_bytes(OutputMethod::Direct), method_bytes(OutputMethod::Bios), method_bytes(OutputMethod::DosConsole))
+		}).collect();
+		let bios_functions: Vec<String> = self.bios_functions.iter().map(|(function, function_stats)| {
+			format!("{{\"function\":{},\"calls\":{},\"bytes_changed\":{}}}", function, function_stats.calls, function_stats.bytes_changed)
+		}).collect();
+		let main_output_method = match self.main_output_method() {
+			Some(method) => format!("\"{}\"", method.name()),
+			None => "null".to_string(),
+		};
+		format!("{{\"frames\":{},\"main_output_method\":{},\"pages\":[{}],\"bios_functions\":[{}],\"dos_console_chars\":{}}}",
+			self.frame_count, main_output_method, pages.join(","), bios_functions.join(","), self.dos_console_chars)
+	}
+}
+
+fn video_window(machine: &dyn Machine) -> &[u8] {
+	&machine.memory()[VIDEO_WINDOW_START as usize .. VIDEO_WINDOW_START as usize + VIDEO_WINDOW_BYTES]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::DosEventHandler;
+	use crate::dos_event_handler::tests::make_test_handler;
+	use crate::dos_frame_slicing::{FrameRun, SliceEnd, OPCODES_PER_STANDARD_TICK};
+
+	use xachtsechs::types::Reg;
+	use xachtsechs::machine8086::Machine8086;
+
+	// Runs a looping program for a few frames with the stats on.
+	fn run_with_stats(program: &[u8]) -> (DosEventHandler, Machine8086) {
+		let (mut handler, mut machine) = make_test_handler();
+		machine.insert_contiguous_bytes(program, 0x10000);
+		machine.set_reg_u16(Reg::CS, 0x1000);
+		machine.set_reg_u16(Reg::IP, 0);
+		handler.video_stats = Some(VideoStats::new(&machine));
+		for _ in 0 .. 3 {
+			let mut frame_run = FrameRun::new(OPCODES_PER_STANDARD_TICK, 1, 1);
+			while frame_run.run_slice(&mut machine, &mut handler).unwrap() == SliceEnd::Slice {}
+		}
+		(handler, machine)
+	}
+
+	#[test] fn test_classify_output_method() {
+		// mov ax, 0b800h; mov ds, ax; loop: inc byte [0]; inc byte [160]; jmp loop
+		let (handler, _) = run_with_stats(&[0xb8, 0x00, 0xb8, 0x8e, 0xd8, 0xfe, 0x06, 0x00, 0x00, 0xfe, 0x06, 0xa0, 0x00, 0xeb, 0xf6]);
+		let video_stats = handler.video_stats.as_ref().unwrap();
+		assert_eq!(video_stats.main_output_method(), Some(OutputMethod::Direct));
+		assert_eq!(video_stats.frame_count(), 3);
+		assert!(video_stats.bios_functions().is_empty());
+		assert_eq!(video_stats.last_frame_page_bytes()[0x18], 2);
+		let summary = video_stats.summary(0xb9000);
+		assert!(summary.contains("100% of output via direct writes to B800 page 0\n"), "{}", summary);
+		assert!(summary.contains("Nothing was written to the page being displayed (B800 page 1)\n"), "{}", summary);
+
+		// loop: mov ax, 0e41h; int 10h; mov ah, 02h; mov bx, 0; mov dx, 0; int 10h; jmp loop
+		let (handler, _) = run_with_stats(&[0xb8, 0x41, 0x0e, 0xcd, 0x10, 0xb4, 0x02, 0xbb, 0x00, 0x00, 0xba, 0x00, 0x00, 0xcd, 0x10, 0xeb, 0xef]);
+		let video_stats = handler.video_stats.as_ref().unwrap();
+		assert_eq!(video_stats.main_output_method(), Some(OutputMethod::Bios));
+		assert_eq!(video_stats.bytes_changed(OutputMethod::Direct), 0);
+		let teletype = video_stats.bios_functions()[&0x0e];
+		let cursor_moves = video_stats.bios_functions()[&0x02];
+		assert!(teletype.calls > 1 && cursor_moves.calls > 1);
+		assert_eq!((teletype.bytes_changed, cursor_moves.bytes_changed), (1, 0));
+		assert!(video_stats.summary(0xb8000).starts_with("Video output over 3 frames:\n100% of output via int 10h to B800 page 0\nint 10h AH=02h: "));
+		assert!(video_stats.to_json().starts_with("{\"frames\":3,\"main_output_method\":\"bios\",\"pages\":[{\"segment\":47104,\"direct\":0,\"bios\":1,\"dos_console\":0}],"));
+	}
+}
@@ -0,0 +1,549 @@
+use crate::dos_error_codes::DosErrorCode;
+use crate::dos_file_system::{DosFileAccessMode, DosFileSeekOrigin, DosFileSystem, DosMediaId, DosVolumeInfo, OpenHandleInfo, find_data_filename, DOS_ATTRIBUTE_ARCHIVE, DOS_ATTRIBUTE_DIRECTORY, DOS_ATTRIBUTE_READ_ONLY, DOS_FIND_DATA_BYTES, DOS_FIRST_FILE_HANDLE};
+
+use std::collections::{HashSet, VecDeque};
+
+/// A file system made of other file systems stacked on top of each other, like a union or overlay
+/// file system. Files are looked up in each layer in order, so the first layer's files hide files
+/// with the same name in later layers. New files and files opened for writing always go to the
+/// first layer, and a file that's only in a later layer is copied to the first layer before it's
+/// opened for writing.
+///
+/// With a scratch directory as the first layer and the game's directory as the second, the game
+/// can be played without changing its files, and commit() then copies what it wrote down to the
+/// game's directory if the user wants to keep it.
+#[derive(Debug)]
+pub struct VirtualDosFileSystem {
+	layers: Vec<Box<dyn DosFileSystem>>,
+	// Layers that don't have the current directory are skipped until it changes again.
+	active_layers: Vec<bool>,
+	// Each handle given to the program maps to a layer and the handle within that layer.
+	file_handles: Vec<Option<(usize, u16)>>,
+	// The find data for the files left to return from the last find_first_file.
+	found_files: VecDeque<Vec<u8>>,
+	// The directories and names (in upper case) of files created or opened for writing in the first
+	// layer since the last commit.
+	dirty_files: HashSet<(Vec<u8>, Vec<u8>)>,
+}
+
+impl VirtualDosFileSystem {
+	/// The first layer has the highest priority, and is the one that gets written to.
+	pub fn new(layers: Vec<Box<dyn DosFileSystem>>) -> VirtualDosFileSystem {
+		let active_layers = vec![true; layers.len()];
+		VirtualDosFileSystem {
+			layers,
+			active_layers,
+			file_handles: vec![],
+			found_files: VecDeque::new(),
+			dirty_files: HashSet::new(),
+		}
+	}
+
+	/// Copies the files written to the first layer since the last commit into the second layer. The
+	/// copies in the first layer are kept, so the files read the same afterwards. Files that have
+	/// since been deleted are skipped. If copying a file fails, it and the files not yet copied stay
+	/// dirty for the next commit.
+	pub fn commit(&mut self) -> Result<(), DosErrorCode> {
+		if self.layers.len() < 2 {
+			return Err(DosErrorCode::InvalidDrive);
+		}
+		let mut dirty_files: Vec<(Vec<u8>, Vec<u8>)> = self.dirty_files.iter().cloned().collect();
+		dirty_files.sort();
+		let mut original_directory = b"\\".to_vec();
+		original_directory.extend(self.current_directory());
+		let mut result = Ok(());
+		for dirty_file in dirty_files {
+			result = self.commit_file(&dirty_file.0, &dirty_file.1);
+			if result.is_err() {
+				break;
+			}
+			self.dirty_files.remove(&dirty_file);
+		}
+		// The layers were moved to each file's directory, so put them all back.
+		self.set_current_directory(&original_directory)?;
+		result
+	}
+
+	/// Returns whether anything has been written to the first layer since the last commit.
+	pub fn has_uncommitted_changes(&self) -> bool {
+		!self.dirty_files.is_empty()
+	}
+
+	fn add_handle(&mut self, layer_index: usize, layer_handle: u16) -> u16 {
+		let slot = match self.file_handles.iter().position(|slot| slot.is_none()) {
+			Some(pos) => pos,
+			None => {
+				self.file_handles.push(None);
+				self.file_handles.len() - 1
+			}
+		};
+		self.file_handles[slot] = Some((layer_index, layer_handle));
+		slot as u16 + DOS_FIRST_FILE_HANDLE
+	}
+
+	fn get_layer_handle(&self, handle: u16) -> Result<(usize, u16), DosErrorCode> {
+		let handle_index = handle.checked_sub(DOS_FIRST_FILE_HANDLE).ok_or(DosErrorCode::InvalidFileHandle)? as usize;
+		match self.file_handles.get(handle_index) {
+			Some(Some(layer_handle)) => Ok(*layer_handle),
+			_ => Err(DosErrorCode::InvalidFileHandle),
+		}
+	}
+
+	fn get_handle_file_system(&mut self, handle: u16) -> Result<(&mut dyn DosFileSystem, u16), DosErrorCode> {
+		let (layer_index, layer_handle) = self.get_layer_handle(handle)?;
+		Ok((&mut *self.layers[layer_index], layer_handle))
+	}
+
+	fn get_write_layer(&mut self) -> Result<&mut dyn DosFileSystem, DosErrorCode> {
+		match self.layers.first_mut() {
+			Some(layer) if self.active_layers[0] => Ok(&mut **layer),
+			_ => Err(DosErrorCode::PathNotFound),
+		}
+	}
+
+	// Files are only ever opened by name in the current directory, so that's recorded with the name.
+	fn dirty_file_key(&self, filename: &[u8]) -> (Vec<u8>, Vec<u8>) {
+		(self.current_directory().to_ascii_uppercase(), filename.to_ascii_uppercase())
+	}
+
+	// Copies a file from the first layer to the second, moving both to the file's directory first.
+	fn commit_file(&mut self, directory: &[u8], filename: &[u8]) -> Result<(), DosErrorCode> {
+		let mut directory_path = b"\\".to_vec();
+		directory_path.extend(directory);
+		if self.layers[0].set_current_directory(&directory_path).is_err() {
+			return Ok(());
+		}
+		let data = match self.read_whole_file(0, filename) {
+			Ok(data) => data,
+			Err(DosErrorCode::FileNotFound) => return Ok(()),
+			Err(error_code) => return Err(error_code),
+		};
+		let target_layer = &mut self.layers[1];
+		target_layer.set_current_directory(&directory_path)?;
+		let handle = target_layer.create(filename, 0)?;
+		let write_result = target_layer.write(handle, &data);
+		target_layer.close(handle)?;
+		write_result.map(|_| ())
+	}
+
+	// Copies a file from the first later layer that has it to the first layer, so it can be written
+	// without changing the layer it came from.
+	fn copy_to_write_layer(&mut self, filename: &[u8]) -> Result<(), DosErrorCode> {
+		let layer_count = self.layers.len();
+		let layer_index = (1..layer_count)
+			.find(|&layer_index| self.active_layers[layer_index] && self.layers[layer_index].get_attributes(filename).is_ok())
+			.ok_or(DosErrorCode::FileNotFound)?;
+		let attributes = self.layers[layer_index].get_attributes(filename)?;
+		// These couldn't be opened for writing in their own layer either.
+		if attributes & (DOS_ATTRIBUTE_READ_ONLY | DOS_ATTRIBUTE_DIRECTORY) != 0 {
+			return Err(DosErrorCode::AccessDenied);
+		}
+		let data = self.read_whole_file(layer_index, filename)?;
+		let write_layer = self.get_write_layer()?;
+		let handle = write_layer.create(filename, attributes & !DOS_ATTRIBUTE_ARCHIVE)?;
+		let write_result = data.chunks(0x8000).try_for_each(|chunk| write_layer.write(handle, chunk).map(|_| ()));
+		write_layer.close(handle)?;
+		if write_result.is_err() {
+			// A partial copy would hide the whole file in the other layer.
+			write_layer.delete(filename).ok();
+		}
+		write_result
+	}
+
+	fn read_whole_file(&mut self, layer_index: usize, filename: &[u8]) -> Result<Vec<u8>, DosErrorCode> {
+		let layer = &mut self.layers[layer_index];
+		let handle = layer.open(filename, DosFileAccessMode::ReadOnly)?;
+		let mut data = vec![];
+		let mut buffer = [0; 0x1000];
+		let result = loop {
+			match layer.read(handle, &mut buffer) {
+				Ok(0) => break Ok(()),
+				Ok(read_count) => data.extend(&buffer[..read_count as usize]),
+				Err(error_code) => break Err(error_code),
+			}
+		};
+		layer.close(handle)?;
+		result.map(|()| data)
+	}
+}
+
+impl DosFileSystem for VirtualDosFileSystem {
+	fn create(&mut self, filename: &[u8], attributes: u16) -> Result<u16, DosErrorCode> {
+		let layer_handle = self.get_write_layer()?.create(filename, attributes)?;
+		let dirty_file = self.dirty_file_key(filename);
+		self.dirty_files.insert(dirty_file);
+		Ok(self.add_handle(0, layer_handle))
+	}
+
+	fn open(&mut self, filename: &[u8], access_mode: DosFileAccessMode) -> Result<u16, DosErrorCode> {
+		if access_mode != DosFileAccessMode::ReadOnly {
+			if self.get_write_layer()?.get_attributes(filename) == Err(DosErrorCode::FileNotFound) {
+				match self.copy_to_write_layer(filename) {
+					Ok(()) | Err(DosErrorCode::FileNotFound) => {}
+					Err(error_code) => return Err(error_code),
+				}
+			}
+			let layer_handle = self.get_write_layer()?.open(filename, access_mode)?;
+			let dirty_file = self.dirty_file_key(filename);
+			self.dirty_files.insert(dirty_file);
+			return Ok(self.add_handle(0, layer_handle));
+		}
+		let mut last_error = DosErrorCode::FileNotFound;
+		for layer_index in 0..self.layers.len() {
+			if !self.active_layers[layer_index] {
+				continue;
+			}
+			match self.layers[layer_index].open(filename, access_mode) {
+				Ok(layer_handle) => return Ok(self.add_handle(layer_index, layer_handle)),
+				Err(error_code) => last_error = error_code,
+			}
+		}
+		Err(last_error)
+	}
+
+	fn close(&mut self, handle: u16) -> Result<(), DosErrorCode> {
+		let (file_system, layer_handle) = self.get_handle_file_system(handle)?;
+		file_system.close(layer_handle)?;
+		self.file_handles[(handle - DOS_FIRST_FILE_HANDLE) as usize] = None;
+		Ok(())
+	}
+
+	fn duplicate(&mut self, handle: u16) -> Result<u16, DosErrorCode> {
+		let (layer_index, layer_handle) = self.get_layer_handle(handle)?;
+		let new_layer_handle = self.layers[layer_index].duplicate(layer_handle)?;
+		Ok(self.add_handle(layer_index, new_layer_handle))
+	}
+
+	fn force_duplicate(&mut self, handle: u16, new_handle: u16) -> Result<(), DosErrorCode> {
+		let new_slot = new_handle.checked_sub(DOS_FIRST_FILE_HANDLE).ok_or(DosErrorCode::InvalidFileHandle)? as usize;
+		let (layer_index, layer_handle) = self.get_layer_handle(handle)?;
+		if self.get_layer_handle(new_handle).is_ok() {
+			self.close(new_handle)?;
+		}
+		let new_layer_handle = self.layers[layer_index].duplicate(layer_handle)?;
+		if new_slot >= self.file_handles.len() {
+			self.file_handles.resize(new_slot + 1, None);
+		}
+		self.file_handles[new_slot] = Some((layer_index, new_layer_handle));
+		Ok(())
+	}
+
+	fn read(&mut self, handle: u16, destination: &mut [u8]) -> Result<u16, DosErrorCode> {
+		let (file_system, layer_handle) = self.get_handle_file_system(handle)?;
+		file_system.read(layer_handle, destination)
+	}
+
+	fn write(&mut self, handle: u16, data: &[u8]) -> Result<u16, DosErrorCode> {
+		let (file_system, layer_handle) = self.get_handle_file_system(handle)?;
+		file_system.write(layer_handle, data)
+	}
+
+	fn seek(&mut self, handle: u16, offset: u32, origin: DosFileSeekOrigin) -> Result<u32, DosErrorCode> {
+		let (file_system, layer_handle) = self.get_handle_file_system(handle)?;
+		file_system.seek(layer_handle, offset, origin)
+	}
+
+	fn tell(&mut self, handle: u16) -> Result<u32, DosErrorCode> {
+		let (file_system, layer_handle) = self.get_handle_file_system(handle)?;
+		file_system.tell(layer_handle)
+	}
+
+	fn truncate(&mut self, handle: u16) -> Result<u32, DosErrorCode> {
+		let (file_system, layer_handle) = self.get_handle_file_system(handle)?;
+		file_system.truncate(layer_handle)
+	}
+
+	fn is_at_end(&mut self, handle: u16) -> Result<bool, DosErrorCode> {
+		let (file_system, layer_handle) = self.get_handle_file_system(handle)?;
+		file_system.is_at_end(layer_handle)
+	}
+
+	fn flush_all(&mut self) -> Result<(), DosErrorCode> {
+		let mut result = Ok(());
+		for layer in &mut self.layers {
+			result = result.and(layer.flush_all());
+		}
+		result
+	}
+
+	fn close_all(&mut self) {
+		for layer in &mut self.layers {
+			layer.close_all();
+		}
+		self.file_handles.clear();
+		self.found_files.clear();
+	}
+
+	fn refresh(&mut self) {
+		for layer in &mut self.layers {
+			layer.refresh();
+		}
+	}
+
+	fn find_first_file(&mut self, destination: &mut [u8], attributes: u16, search_spec: &[u8]) -> Result<(), DosErrorCode> {
+		// Gather the matches from every layer up front, skipping names an earlier layer already
+		// found.
+		self.found_files.clear();
+		let mut found_any_layer = false;
+		let mut last_error = DosErrorCode::NoMoreFiles;
+		for (layer, active) in self.layers.iter_mut().zip(self.active_layers.iter()) {
+			if !active {
+				continue;
+			}
+			let mut find_data = vec![0; DOS_FIND_DATA_BYTES];
+			let mut result = layer.find_first_file(&mut find_data, attributes, search_spec);
+			match result {
+				Ok(()) => found_any_layer = true,
+				Err(DosErrorCode::NoMoreFiles) | Err(DosErrorCode::FileNotFound) => found_any_layer = true,
+				Err(error_code) => last_error = error_code,
+			}
+			while result.is_ok() {
+				let filename = find_data_filename(&find_data);
+				if !self.found_files.iter().any(|found_file| find_data_filename(found_file) == filename) {
+					self.found_files.push_back(find_data.clone());
+				}
+				result = layer.find_next_file(&mut find_data);
+			}
+		}
+		if !found_any_layer {
+			return Err(last_error);
+		}
+		self.find_next_file(destination)
+	}
+
+	fn find_next_file(&mut self, destination: &mut [u8]) -> Result<(), DosErrorCode> {
+		match self.found_files.pop_front() {
+			Some(find_data) => {
+				destination[..DOS_FIND_DATA_BYTES].copy_from_slice(&find_data);
+				Ok(())
+			}
+			None => Err(DosErrorCode::NoMoreFiles),
+		}
+	}
+
+	fn delete(&mut self, filename: &[u8]) -> Result<(), DosErrorCode> {
+		// Files in the other layers can't be deleted, since nothing would hide them afterwards.
+		match self.get_write_layer()?.delete(filename) {
+			Ok(()) => {
+				let dirty_file = self.dirty_file_key(filename);
+				self.dirty_files.remove(&dirty_file);
+				Ok(())
+			}
+			Err(DosErrorCode::FileNotFound) if self.get_attributes(filename).is_ok() => Err(DosErrorCode::AccessDenied),
+			result => result,
+		}
+	}
+	
+	fn get_attributes(&mut self, filename: &[u8]) -> Result<u16, DosErrorCode> {
+		let mut last_error = DosErrorCode::FileNotFound;
+		for (layer, active) in self.layers.iter_mut().zip(self.active_layers.iter()) {
+			if !active {
+				continue;
+			}
+			match layer.get_attributes(filename) {
+				Ok(attributes) => return Ok(attributes),
+				Err(error_code) => last_error = error_code,
+			}
+		}
+		Err(last_error)
+	}
+	
+	fn set_attributes(&mut self, filename: &[u8], attributes: u16) -> Result<(), DosErrorCode> {
+		// Like writing, this only changes files in the first layer.
+		self.get_write_layer()?.set_attributes(filename, attributes)
+	}
+	
+	fn root_path(&self) -> Option<&std::path::Path> {
+		self.layers.first().and_then(|layer| layer.root_path())
+	}
+
+	fn open_handles(&self) -> Vec<OpenHandleInfo> {
+		// Each layer numbers its own handles, so they're given the numbers the program knows them by.
+		self.file_handles.iter().enumerate().filter_map(|(slot, layer_handle)| {
+			let (layer_index, layer_handle) = (*layer_handle)?;
+			let open_handle = self.layers[layer_index].open_handles().into_iter().find(|open_handle| open_handle.handle == layer_handle)?;
+			Some(OpenHandleInfo { handle: slot as u16 + DOS_FIRST_FILE_HANDLE, ..open_handle })
+		}).collect()
+	}
+
+	fn volume_info(&self) -> Result<DosVolumeInfo, DosErrorCode> {
+		match self.layers.first() {
+			Some(layer) => layer.volume_info(),
+			None => Err(DosErrorCode::InvalidDrive),
+		}
+	}
+
+	fn media_id(&self) -> Result<DosMediaId, DosErrorCode> {
+		match self.layers.first() {
+			Some(layer) => layer.media_id(),
+			None => Err(DosErrorCode::InvalidDrive),
+		}
+	}
+
+	fn set_media_id(&mut self, media_id: DosMediaId) -> Result<(), DosErrorCode> {
+		self.get_write_layer()?.set_media_id(media_id)
+	}
+
+	fn current_directory(&self) -> Vec<u8> {
+		match self.layers.iter().zip(self.active_layers.iter()).find(|(_, active)| **active) {
+			Some((layer, _)) => layer.current_directory(),
+			None => vec![],
+		}
+	}
+
+	fn set_current_directory(&mut self, path: &[u8]) -> Result<(), DosErrorCode> {
+		// Relative paths are resolved by the first layer that has the new directory, and all the
+		// layers are then given the resulting absolute path.
+		let mut new_directory = None;
+		for (layer, active) in self.layers.iter_mut().zip(self.active_layers.iter()) {
+			if *active && layer.set_current_directory(path).is_ok() {
+				new_directory = Some(layer.current_directory());
+				break;
+			}
+		}
+		let mut new_directory_path = b"\\".to_vec();
+		new_directory_path.extend(new_directory.ok_or(DosErrorCode::PathNotFound)?);
+		for (layer, active) in self.layers.iter_mut().zip(self.active_layers.iter_mut()) {
+			*active = layer.set_current_directory(&new_directory_path).is_ok();
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_event_handler::tests::make_test_dir;
+	use crate::dos_file_system::StandardDosFileSystem;
+
+	#[test] fn test_layers() {
+		let saves_path = make_test_dir("layer_saves");
+		let game_path = make_test_dir("layer_game");
+		std::fs::write(game_path.join("ZZT.DAT"), b"game").unwrap();
+		std::fs::write(game_path.join("TOWN.ZZT"), b"original").unwrap();
+		std::fs::write(saves_path.join("TOWN.ZZT"), b"saved").unwrap();
+		std::fs::create_dir_all(game_path.join("DOCS")).unwrap();
+		let mut file_system = VirtualDosFileSystem::new(vec![
+			Box::new(StandardDosFileSystem::new(saves_path.clone())),
+			Box::new(StandardDosFileSystem::new(game_path.clone())),
+		]);
+
+		// The first layer hides files with the same name in later layers.
+		let mut data = [0; 5];
+		let handle = file_system.open(b"TOWN.ZZT", DosFileAccessMode::ReadOnly).unwrap();
+		assert_eq!(file_system.read(handle, &mut data), Ok(5));
+		assert_eq!(&data, b"saved");
+		let handle = file_system.open(b"ZZT.DAT", DosFileAccessMode::ReadOnly).unwrap();
+		assert_eq!(file_system.read(handle, &mut data[..4]), Ok(4));
+		assert_eq!(&data[..4], b"game");
+		let open_paths: Vec<_> = file_system.open_handles().into_iter().map(|open_handle| open_handle.real_path).collect();
+		assert_eq!(open_paths, vec![saves_path.join("TOWN.ZZT"), game_path.join("ZZT.DAT")]);
+
+		// Writes go to the first layer.
+		let handle = file_system.create(b"NEW.SAV", 0).unwrap();
+		file_system.write(handle, b"new").unwrap();
+		file_system.close(handle).unwrap();
+		assert!(saves_path.join("NEW.SAV").exists());
+
+		let mut find_all = |search_spec: &[u8]| {
+			let mut find_data = [0; DOS_FIND_DATA_BYTES];
+			let mut found_names = vec![];
+			let mut result = file_system.find_first_file(&mut find_data, 0, search_spec);
+			while result.is_ok() {
+				found_names.push(find_data_filename(&find_data).to_vec());
+				result = file_system.find_next_file(&mut find_data);
+			}
+			found_names
+		};
+		// Files that are in more than one layer are only found once.
+		assert_eq!(find_all(b"*.ZZT"), vec![b"TOWN.ZZT".to_vec()]);
+		assert_eq!(find_all(b"*.SAV"), vec![b"NEW.SAV".to_vec()]);
+		assert_eq!(find_all(b"*.DAT"), vec![b"ZZT.DAT".to_vec()]);
+
+		// Only the game layer has the directory, so only its files are visible there.
+		file_system.set_current_directory(b"DOCS").unwrap();
+		assert_eq!(file_system.current_directory(), b"DOCS");
+		assert_eq!(file_system.create(b"NEW.SAV", 0), Err(DosErrorCode::PathNotFound));
+		assert_eq!(file_system.open(b"TOWN.ZZT", DosFileAccessMode::ReadOnly), Err(DosErrorCode::FileNotFound));
+		file_system.set_current_directory(b"..").unwrap();
+		assert!(file_system.open(b"NEW.SAV", DosFileAccessMode::ReadOnly).is_ok());
+	}
+
+	#[test] fn test_commit() {
+		let scratch_path = make_test_dir("commit_scratch");
+		let game_path = make_test_dir("commit_game");
+		std::fs::write(game_path.join("TOWN.ZZT"), b"original").unwrap();
+		std::fs::create_dir_all(game_path.join("SAVES")).unwrap();
+		std::fs::create_dir_all(scratch_path.join("SAVES")).unwrap();
+		let mut file_system = VirtualDosFileSystem::new(vec![
+			Box::new(StandardDosFileSystem::new(scratch_path.clone())),
+			Box::new(StandardDosFileSystem::new(game_path.clone())),
+		]);
+		assert!(!file_system.has_uncommitted_changes());
+
+		let handle = file_system.create(b"TOWN.ZZT", 0).unwrap();
+		file_system.write(handle, b"changed").unwrap();
+		file_system.close(handle).unwrap();
+		file_system.set_current_directory(b"SAVES").unwrap();
+		let handle = file_system.create(b"SLOT1.SAV", 0).unwrap();
+		file_system.write(handle, b"save").unwrap();
+		file_system.close(handle).unwrap();
+		let handle = file_system.create(b"TEMP.TMP", 0).unwrap();
+		file_system.close(handle).unwrap();
+		file_system.delete(b"TEMP.TMP").unwrap();
+		// The game's directory is untouched until the commit.
+		assert_eq!(std::fs::read(game_path.join("TOWN.ZZT")).unwrap(), b"original");
+		assert!(file_system.has_uncommitted_changes());
+
+		file_system.commit().unwrap();
+		assert!(!file_system.has_uncommitted_changes());
+		assert_eq!(std::fs::read(game_path.join("TOWN.ZZT")).unwrap(), b"changed");
+		assert_eq!(std::fs::read(game_path.join("SAVES").join("SLOT1.SAV")).unwrap(), b"save");
+		assert!(!game_path.join("SAVES").join("TEMP.TMP").exists());
+
+		// Only files written since the last commit are copied by the next one.
+		std::fs::write(game_path.join("TOWN.ZZT"), b"edited on the host").unwrap();
+		let handle = file_system.open(b"SLOT1.SAV", DosFileAccessMode::ReadWrite).unwrap();
+		file_system.write(handle, b"SAVE").unwrap();
+		file_system.close(handle).unwrap();
+		file_system.commit().unwrap();
+		assert_eq!(std::fs::read(game_path.join("SAVES").join("SLOT1.SAV")).unwrap(), b"SAVE");
+		assert_eq!(std::fs::read(game_path.join("TOWN.ZZT")).unwrap(), b"edited on the host");
+	}
+
+	#[test] fn test_open_for_write_copies_file() {
+		let scratch_path = make_test_dir("copy_scratch");
+		let game_path = make_test_dir("copy_game");
+		std::fs::write(game_path.join("TOWN.ZZT"), b"original").unwrap();
+		std::fs::write(game_path.join("LOCKED.DAT"), b"locked").unwrap();
+		let mut file_system = VirtualDosFileSystem::new(vec![
+			Box::new(StandardDosFileSystem::new(scratch_path.clone())),
+			Box::new(StandardDosFileSystem::new(game_path.clone())),
+		]);
+		let mut permissions = std::fs::metadata(game_path.join("LOCKED.DAT")).unwrap().permissions();
+		permissions.set_readonly(true);
+		std::fs::set_permissions(game_path.join("LOCKED.DAT"), permissions).unwrap();
+
+		// Writing part of a file from the game layer keeps the rest of it, and leaves the game layer
+		// as it was.
+		let handle = file_system.open(b"TOWN.ZZT", DosFileAccessMode::ReadWrite).unwrap();
+		file_system.write(handle, b"O").unwrap();
+		let mut data = [0; 7];
+		assert_eq!(file_system.read(handle, &mut data), Ok(7));
+		assert_eq!(&data, b"riginal");
+		file_system.close(handle).unwrap();
+		assert_eq!(std::fs::read(scratch_path.join("TOWN.ZZT")).unwrap(), b"Original");
+		assert_eq!(std::fs::read(game_path.join("TOWN.ZZT")).unwrap(), b"original");
+		assert!(file_system.has_uncommitted_changes());
+
+		// It's only copied the first time.
+		std::fs::write(game_path.join("TOWN.ZZT"), b"edited on the host").unwrap();
+		let handle = file_system.open(b"TOWN.ZZT", DosFileAccessMode::WriteOnly).unwrap();
+		file_system.close(handle).unwrap();
+		assert_eq!(std::fs::read(scratch_path.join("TOWN.ZZT")).unwrap(), b"Original");
+
+		// Files that can't be written in the game layer aren't copied.
+		assert_eq!(file_system.open(b"LOCKED.DAT", DosFileAccessMode::ReadWrite), Err(DosErrorCode::AccessDenied));
+		assert!(!scratch_path.join("LOCKED.DAT").exists());
+	}
+}
@@ -0,0 +1,554 @@
+// The settings for an emulated machine, and the one place a machine is made from them. Front-ends
+// fill in an EmulatorOptions (starting from its defaults, which are how pseudos runs without any
+// arguments) and hand it to Emulator::new, which checks it and does all the setting up in order:
+// the program is loaded, then the handler is made, and then it sets up the BIOS data for its video
+// mode over what the loader put there. Restarting a program sets up a new machine the same way,
+// with only the file system carried over, so nothing from the old machine can leak into the new one.
+
+use crate::bios_floppy::FloppyImage;
+use crate::dos_background_policy::{BackgroundMode, ResumeClock};
+use crate::dos_country_info::CountryInfo;
+use crate::dos_drive_table::DosDriveTable;
+use crate::dos_environment::build_environment_block;
+use crate::dos_event_handler::{DosEventHandler, DosOutputMode, MachineType, UnknownFuncAction};
+use crate::dos_file_system::DosFileSystem;
+use crate::dos_frame_slicing::{FrameSlicing, MidFramePresent};
+use crate::dos_low_memory_guard::LowMemoryProtection;
+use crate::dos_palette::PaletteTransform;
+use crate::dos_patch::{load_patched_program_into_machine, PatchSet};
+use xachtsechs::machine8086::Machine8086;
+
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+
+// The whole real mode address space, which the BIOS stubs at the top of it need.
+const MEMORY_BYTES: usize = 1024*1024;
+pub const MIN_SPEED_MULTIPLIER: f64 = 0.125;
+pub const MAX_SPEED_MULTIPLIER: f64 = 16.;
+pub const MIN_REFRESH_RATE_HZ: f64 = 50.;
+pub const MAX_REFRESH_RATE_HZ: f64 = 120.;
+const MAX_SLICES_PER_FRAME: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CpuOptions {
+	/// How fast emulated time passes relative to real time.
+	pub speed_multiplier: f64,
+	/// How each frame's opcodes are split up between input polls and presents.
+	pub frame_slicing: FrameSlicing,
+}
+
+impl Default for CpuOptions {
+	fn default() -> CpuOptions {
+		CpuOptions {
+			speed_multiplier: 1.,
+			frame_slicing: FrameSlicing::default(),
+		}
+	}
+}
+
+// Writes an optional path as a path that's empty for None, for options whose default is a path.
+mod empty_path_is_none {
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+	use std::path::{Path, PathBuf};
+
+	pub fn serialize<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+		path.as_deref().unwrap_or_else(|| Path::new("")).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+		let path = PathBuf::deserialize(deserializer)?;
+		Ok(if path.as_os_str().is_empty() { None } else { Some(path) })
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VideoOptions {
+	pub machine_type: MachineType,
+	/// The video mode the machine starts in.
+	pub mode: u8,
+	/// Overrides the video mode's refresh rate.
+	pub refresh_rate_hz: Option<f64>,
+	/// Whether the top bit of an attribute starts off making the character blink.
+	pub blink_enabled: bool,
+	/// Whether cursor shapes set for an 8 line font start off scaled to the font height.
+	pub cursor_emulation: bool,
+	/// How many window pixels each emulated pixel takes up.
+	pub window_scale: u32,
+	/// Whether to count how the program changes video memory (see dos_video_stats).
+	pub stats: bool,
+	/// Where int 05h (print screen) saves the text on the screen. In a config file, an empty path
+	/// turns it off, since leaving it out gives the default.
+	#[serde(with = "empty_path_is_none")]
+	pub print_screen_dir: Option<PathBuf>,
+	/// How the text colours are shown (see dos_palette).
+	pub palette: PaletteTransform,
+}
+
+impl Default for VideoOptions {
+	fn default() -> VideoOptions {
+		VideoOptions {
+			machine_type: MachineType::EGA,
+			mode: 3,
+			refresh_rate_hz: None,
+			blink_enabled: true,
+			cursor_emulation: true,
+			window_scale: 2,
+			stats: false,
+			print_screen_dir: Some(PathBuf::from(".")),
+			palette: PaletteTransform::default(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DosOptions {
+	/// The host directory that's the C: drive.
+	pub host_directory: PathBuf,
+	/// The program in the root of the C: drive that's run.
+	pub program_name: String,
+	/// The variables in the program's environment, as well as the ones a batch file sets.
+	pub environment_vars: Vec<(Vec<u8>, Vec<u8>)>,
+	/// The country (by its dialling code) whose date, time and currency formats the program gets.
+	pub country_code: u16,
+	/// The major and minor DOS version the program is told it's running on.
+	pub dos_version: (u8, u8),
+	pub output_mode: DosOutputMode,
+	pub unknown_function_action: UnknownFuncAction,
+	pub unknown_interrupt_action: UnknownFuncAction,
+	/// Whether to record the BIOS and DOS functions the program calls.
+	pub audit: bool,
+	/// If set, keeps up to this many lines the program writes through the output functions (see
+	/// dos_scrollback).
+	pub scrollback_lines: Option<usize>,
+	/// A disk image to put in floppy drive 0.
+	pub floppy_image: Option<PathBuf>,
+	pub floppy_write_protected: bool,
+	/// Whether to watch for the program writing over the interrupt vectors and BIOS data by
+	/// mistake (see dos_low_memory_guard).
+	pub low_memory_protection: LowMemoryProtection,
+	/// Whether to report every change the program makes to the interrupt vectors, for seeing which
+	/// interrupts it handles itself (see dos_low_memory_guard).
+	pub trace_vectors: bool,
+}
+
+impl Default for DosOptions {
+	fn default() -> DosOptions {
+		DosOptions {
+			host_directory: PathBuf::from("./junk/dos"),
+			program_name: "ZZT.EXE".to_string(),
+			environment_vars: vec![],
+			country_code: CountryInfo::default().country_code,
+			dos_version: (5, 22),
+			output_mode: DosOutputMode::Screen,
+			unknown_function_action: UnknownFuncAction::Log,
+			unknown_interrupt_action: UnknownFuncAction::Log,
+			audit: false,
+			scrollback_lines: None,
+			floppy_image: None,
+			floppy_write_protected: false,
+			low_memory_protection: LowMemoryProtection::default(),
+			trace_vectors: false,
+		}
+	}
+}
+
+impl DosOptions {
+	/// The path of the program on the C: drive, as it goes in its environment block.
+	pub fn program_dos_path(&self) -> Vec<u8> {
+		format!("C:\\{}", self.program_name).into_bytes()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputOptions {
+	/// Whether the program gets a mouse driver (int 33h).
+	pub mouse: bool,
+	/// What to do while the window is minimised.
+	pub background_mode: BackgroundMode,
+	pub resume_clock: ResumeClock,
+}
+
+impl Default for InputOptions {
+	fn default() -> InputOptions {
+		InputOptions {
+			mouse: false,
+			background_mode: BackgroundMode::Pause,
+			resume_clock: ResumeClock::Continue,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioOptions {
+	/// The sample rate the PC speaker is played at.
+	pub sample_rate: i32,
+	/// How loud the PC speaker is, from 0 to 1.
+	pub volume: f32,
+}
+
+impl Default for AudioOptions {
+	fn default() -> AudioOptions {
+		AudioOptions {
+			sample_rate: 44100,
+			volume: 0.1,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmulatorOptions {
+	pub cpu: CpuOptions,
+	pub video: VideoOptions,
+	pub dos: DosOptions,
+	pub input: InputOptions,
+	pub audio: AudioOptions,
+}
+
+impl EmulatorOptions {
+	/// Reads options from the TOML text of a config file. Options it leaves out are the defaults.
+	pub fn from_config_text(text: &str) -> Result<EmulatorOptions, String> {
+		toml::from_str(text).map_err(|err| err.to_string())
+	}
+
+	/// Writes the options as the TOML text of a config file, in the shape from_config_text reads.
+	pub fn to_config_text(&self) -> String {
+		toml::to_string(self).expect("The options can all be written as TOML")
+	}
+
+	/// Checks every option, and names the first bad one in the error.
+	pub fn validate(&self) -> Result<(), EmulatorError> {
+		let invalid = |path: &'static str, requirement: String| Err(EmulatorError::InvalidOption { path, requirement });
+		if !(MIN_SPEED_MULTIPLIER ..= MAX_SPEED_MULTIPLIER).contains(&self.cpu.speed_multiplier) {
+			return invalid("cpu.speed_multiplier", format!("{} to {}", MIN_SPEED_MULTIPLIER, MAX_SPEED_MULTIPLIER));
+		}
+		if !(1 ..= MAX_SLICES_PER_FRAME).contains(&self.cpu.frame_slicing.slices_per_frame) {
+			return invalid("cpu.frame_slicing.slices_per_frame", format!("1 to {}", MAX_SLICES_PER_FRAME));
+		}
+		if let MidFramePresent::WhenChanged(fraction) = self.cpu.frame_slicing.mid_frame_present {
+			if !(0. ..= 1.).contains(&fraction) {
+				return invalid("cpu.frame_slicing.mid_frame_present", "a fraction from 0 to 1".to_string());
+			}
+		}
+		if self.video.machine_type.lookup_video_mode(self.video.mode).is_err() {
+			return invalid("video.mode", format!("a video mode of {:?}", self.video.machine_type));
+		}
+		if let Some(refresh_rate_hz) = self.video.refresh_rate_hz {
+			if !(MIN_REFRESH_RATE_HZ ..= MAX_REFRESH_RATE_HZ).contains(&refresh_rate_hz) {
+				return invalid("video.refresh_rate_hz", format!("{} to {}", MIN_REFRESH_RATE_HZ, MAX_REFRESH_RATE_HZ));
+			}
+		}
+		if self.video.window_scale == 0 {
+			return invalid("video.window_scale", "at least 1".to_string());
+		}
+		if self.dos.program_name.is_empty() || self.dos.program_name.contains(['\\', '/']) {
+			return invalid("dos.program_name", "a file name in the root of the C: drive".to_string());
+		}
+		if CountryInfo::for_country_code(self.dos.country_code).is_none() {
+			return invalid("dos.country_code", "the dialling code of a known country".to_string());
+		}
+		if !(2 ..= 9).contains(&self.dos.dos_version.0) || self.dos.dos_version.1 > 99 {
+			return invalid("dos.dos_version", "2.00 to 9.99".to_string());
+		}
+		if self.audio.sample_rate <= 0 {
+			return invalid("audio.sample_rate", "more than 0".to_string());
+		}
+		if !(0. ..= 1.).contains(&self.audio.volume) {
+			return invalid("audio.volume", "0 to 1".to_string());
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug)]
+pub enum EmulatorError {
+	InvalidOption { path: &'static str, requirement: String },
+	Floppy(String),
+	Load(String),
+}
+
+impl std::fmt::Display for EmulatorError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			EmulatorError::InvalidOption { path, requirement } => write!(f, "{} must be {}", path, requirement),
+			EmulatorError::Floppy(err) => write!(f, "Can't load the floppy image: {}", err),
+			EmulatorError::Load(err) => write!(f, "Can't load the program: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for EmulatorError {}
+
+/// A program to load when the machine starts.
+#[derive(Debug, Clone)]
+pub struct ProgramImage {
+	/// The EXE or COM file.
+	pub data: Vec<u8>,
+	/// Where the program is on the emulated drive, which goes at the end of its environment block.
+	pub dos_path: Vec<u8>,
+	pub command_line_tail: Vec<u8>,
+	pub patches: PatchSet,
+}
+
+impl ProgramImage {
+	pub fn new(data: Vec<u8>, dos_path: &[u8]) -> ProgramImage {
+		ProgramImage {
+			data,
+			dos_path: dos_path.to_vec(),
+			command_line_tail: vec![],
+			patches: PatchSet::default(),
+		}
+	}
+}
+
+/// A machine and its BIOS and DOS, set up from an EmulatorOptions.
+pub struct Emulator {
+	pub machine: Machine8086,
+	pub handler: DosEventHandler,
+	pub options: EmulatorOptions,
+	// The current drive and its directory when the emulator was made, which a restart goes back
+	// to.
+	start_drive: u8,
+	start_directory: Vec<u8>,
+}
+
+impl Emulator {
+	/// Makes a machine with the options, and loads the program into it with the variables in the
+	/// options as its environment. Without a program, nothing runs until one is loaded (which is
+	/// how a batch file starts).
+	pub fn new(program: Option<ProgramImage>, file_system: Box<dyn DosFileSystem>, options: EmulatorOptions) -> Result<Emulator, EmulatorError> {
+		options.validate()?;
+		let mut machine = load_machine(program.as_ref(), &*file_system, &options)?;
+		let start_drive = file_system.current_drive();
+		let start_directory = file_system.current_directory();
+		let handler = DosEventHandler::new(&mut machine, file_system, &options)?;
+		Ok(Emulator { machine, handler, options, start_drive, start_directory })
+	}
+	
+	/// Starts again from scratch with the options, such as to reload a program whose data files
+	/// have changed. Only the file system is kept (see take_file_system_for_restart), and it goes
+	/// back to the drive and directory the emulator started in. If the restart fails, such as when
+	/// the program can't be loaded, the emulator is left as it was.
+	pub fn restart(&mut self, program: Option<ProgramImage>) -> Result<(), EmulatorError> {
+		self.options.validate()?;
+		let mut machine = load_machine(program.as_ref(), self.handler.file_system(), &self.options)?;
+		let floppy = open_floppy(&self.options.dos)?;
+		let mut file_system = take_file_system_for_restart(&mut self.handler);
+		// The program may have changed drive or directory, so every drive goes back to its root
+		// and the program starts where it first did.
+		for drive in 0 .. file_system.drive_count() {
+			if file_system.is_valid_drive(drive) && file_system.set_current_drive(drive).is_ok() {
+				file_system.set_current_directory(b"\\").ok();
+			}
+		}
+		file_system.set_current_drive(self.start_drive).ok();
+		file_system.set_current_directory(&self.start_directory).ok();
+		self.handler = DosEventHandler::with_floppy(&mut machine, file_system, &self.options, floppy);
+		self.machine = machine;
+		Ok(())
+	}
+}
+
+// Makes a machine with the program loaded into it, if there is one.
+fn load_machine(program: Option<&ProgramImage>, file_system: &dyn DosFileSystem, options: &EmulatorOptions) -> Result<Machine8086, EmulatorError> {
+	let mut machine = Machine8086::new(MEMORY_BYTES);
+	if let Some(program) = program {
+		let environment_block = build_environment_block(&options.dos.environment_vars, &program.dos_path);
+		load_patched_program_into_machine(&mut machine, &program.data, &program.command_line_tail, &environment_block, file_system, &program.patches)
+			.map_err(EmulatorError::Load)?;
+	}
+	Ok(machine)
+}
+
+/// Takes the file system out of a handler to give to Emulator::new when restarting the program.
+/// The files the program left open are closed, and what's known about the host's files is
+/// forgotten. The mounts and the current directories stay as they were.
+pub fn take_file_system_for_restart(handler: &mut DosEventHandler) -> Box<dyn DosFileSystem> {
+	let mut file_system = handler.replace_file_system(Box::new(DosDriveTable::new()));
+	file_system.close_all();
+	file_system.refresh();
+	file_system
+}
+
+/// Opens the floppy image the options give, if there is one.
+pub(crate) fn open_floppy(options: &DosOptions) -> Result<Option<FloppyImage>, EmulatorError> {
+	match options.floppy_image {
+		Some(ref floppy_image) => FloppyImage::open(floppy_image, options.floppy_write_protected)
+			.map(Some)
+			.map_err(|err| EmulatorError::Floppy(format!("{}: {}", floppy_image.display(), err))),
+		None => Ok(None),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_error_codes::DosErrorCode;
+	use crate::dos_event_handler::tests::make_test_dir;
+	use crate::dos_file_system::{DosFileAccessMode, StandardDosFileSystem};
+	use crate::dos_frame_slicing::{FrameRun, SliceEnd, OPCODES_PER_STANDARD_TICK};
+	use crate::exe_loader::PROGRAM_PSP_SEGMENT;
+	use crate::bios_loader::BIOS_VIDEO_MODE_INDEX;
+	use xachtsechs::types::Reg;
+
+	// mov ax, 4c00h; int 21h
+	const EXIT_PROGRAM: &[u8] = &[0xb8, 0x00, 0x4c, 0xcd, 0x21];
+
+	#[test] fn test_default_options_are_valid() {
+		assert!(EmulatorOptions::default().validate().is_ok());
+		assert_eq!(DosOptions::default().program_dos_path(), b"C:\\ZZT.EXE".to_vec());
+	}
+
+	#[test] fn test_invalid_options() {
+		let check = |change: &dyn Fn(&mut EmulatorOptions), message: &str| {
+			let mut options = EmulatorOptions::default();
+			change(&mut options);
+			let err = Emulator::new(None, Box::new(DosDriveTable::new()), options).err().unwrap();
+			assert_eq!(err.to_string(), message);
+		};
+		check(&|options| options.video.refresh_rate_hz = Some(30.), "video.refresh_rate_hz must be 50 to 120");
+		check(&|options| options.video.mode = 0x42, "video.mode must be a video mode of EGA");
+		check(&|options| options.cpu.speed_multiplier = 0., "cpu.speed_multiplier must be 0.125 to 16");
+		check(&|options| options.cpu.frame_slicing.slices_per_frame = 0, "cpu.frame_slicing.slices_per_frame must be 1 to 1000");
+		check(&|options| options.dos.country_code = 9999, "dos.country_code must be the dialling code of a known country");
+		check(&|options| options.dos.dos_version = (5, 100), "dos.dos_version must be 2.00 to 9.99");
+		check(&|options| options.audio.volume = 2., "audio.volume must be 0 to 1");
+	}
+
+	#[test] fn test_non_default_options() {
+		let mut options = EmulatorOptions::default();
+		options.video.refresh_rate_hz = Some(60.);
+		options.video.blink_enabled = false;
+		options.video.cursor_emulation = false;
+		options.video.stats = true;
+		options.video.print_screen_dir = None;
+		options.video.palette = PaletteTransform::parse("preset protanopia", "test.pal").unwrap();
+		options.dos.environment_vars = vec![(b"PATH".to_vec(), b"C:\\".to_vec())];
+		options.dos.country_code = 44;
+		options.dos.dos_version = (3, 30);
+		options.dos.output_mode = DosOutputMode::Both;
+		options.dos.unknown_function_action = UnknownFuncAction::Panic;
+		options.dos.unknown_interrupt_action = UnknownFuncAction::Panic;
+		options.dos.audit = true;
+		options.dos.scrollback_lines = Some(100);
+		options.dos.low_memory_protection = LowMemoryProtection::Restore;
+		options.input.mouse = true;
+		let program = ProgramImage::new(EXIT_PROGRAM.to_vec(), b"C:\\EXIT.COM");
+		let emulator = Emulator::new(Some(program), Box::new(DosDriveTable::new()), options.clone()).unwrap();
+		let handler = &emulator.handler;
+		assert_eq!(handler.refresh_rate_hz(), 60.);
+		assert!(!handler.blink_enabled);
+		assert!(!handler.cursor_emulation);
+		assert!(handler.video_stats.is_some());
+		assert_eq!(handler.print_screen_dir, None);
+		let environment_addr = (emulator.machine.peek_u16(((PROGRAM_PSP_SEGMENT as u32) << 4) + 0x2c) as usize) << 4;
+		assert_eq!(&emulator.machine.memory[environment_addr ..][.. 24], b"PATH=C:\\\0\0\x01\0C:\\EXIT.COM\0");
+		assert_eq!(handler.country_info.country_code, 44);
+		assert_eq!(handler.dos_version, (3, 30));
+		assert_eq!(handler.dos_output_mode, DosOutputMode::Both);
+		assert_eq!(handler.unknown_dos_func_action, UnknownFuncAction::Panic);
+		assert_eq!(handler.unknown_interrupt_action, UnknownFuncAction::Panic);
+		assert!(handler.function_audit.is_some());
+		assert!(handler.scrollback.is_some());
+		assert_eq!(handler.low_memory_guard.as_ref().map(|guard| guard.protection), Some(LowMemoryProtection::Restore));
+		assert!(handler.mouse.is_some());
+		assert_eq!(emulator.machine.get_data_u8(&BIOS_VIDEO_MODE_INDEX), 3);
+		// The program's memory block is named after it.
+		let program_mcb_addr = (PROGRAM_PSP_SEGMENT as usize - 1) << 4;
+		assert_eq!(&emulator.machine.memory[program_mcb_addr + 8 ..][.. 8], b"EXIT\0\0\0\0");
+		assert_eq!(emulator.options, options);
+	}
+
+	#[test] fn test_config_round_trip() {
+		let mut options = EmulatorOptions::default();
+		assert_eq!(EmulatorOptions::from_config_text(&options.to_config_text()), Ok(options.clone()));
+		options.cpu.speed_multiplier = 1.5;
+		options.cpu.frame_slicing.mid_frame_present = MidFramePresent::Never;
+		options.video.refresh_rate_hz = Some(60.);
+		options.video.print_screen_dir = None;
+		options.video.palette = PaletteTransform::parse("preset custom
+colour 1 102030", "test.pal").unwrap();
+		options.dos.environment_vars = vec![(b"PATH".to_vec(), b"C:\\".to_vec())];
+		options.dos.dos_version = (3, 30);
+		options.dos.output_mode = DosOutputMode::Stdout;
+		options.dos.scrollback_lines = Some(100);
+		options.dos.low_memory_protection = LowMemoryProtection::Off;
+		options.input.background_mode = BackgroundMode::SlowTick;
+		options.audio.volume = 0.5;
+		let text = options.to_config_text();
+		assert_eq!(EmulatorOptions::from_config_text(&text), Ok(options));
+
+		// Options that are left out are the defaults.
+		let options = EmulatorOptions::from_config_text("[dos]\nprogram_name = \"GAME.EXE\"\n").unwrap();
+		assert_eq!(options.dos.program_name, "GAME.EXE");
+		assert_eq!(options.video, VideoOptions::default());
+		assert!(EmulatorOptions::from_config_text("[cpu]\nspeed_multiplier = \"fast\"\n").is_err());
+	}
+
+	// mov dx, 118h; mov ah, 3bh; int 21h (changing to SAVES, which is only there from the root)
+	// mov dx, 11eh; mov ax, 3d00h; int 21h (opening SAVES\DATA.TXT, which is never closed)
+	// mov dx, 127h; mov ah, 09h; int 21h; jmp $
+	// db "SAVES", 0, "DATA.TXT", 0, "Hello$"
+	const CHDIR_OPEN_AND_PRINT_PROGRAM: &[u8] = b"\xba\x18\x01\xb4\x3b\xcd\x21\xba\x1e\x01\xb8\x00\x3d\xcd\x21\xba\x27\x01\xb4\x09\xcd\x21\xeb\xfe\
+		SAVES\0DATA.TXT\0Hello$";
+
+	// Runs a few frames, then hashes what's on the screen and where the program is.
+	fn run_frames_and_hash(emulator: &mut Emulator) -> u64 {
+		use std::hash::{Hash, Hasher};
+		for _ in 0 .. 3 {
+			emulator.handler.seconds_since_start += crate::dos_event_handler::STANDARD_TICK_SECONDS;
+			let mut frame_run = FrameRun::new(OPCODES_PER_STANDARD_TICK, 1, 1);
+			while frame_run.run_slice(&mut emulator.machine, &mut emulator.handler).unwrap() != SliceEnd::Frame {}
+		}
+		let mut hasher = std::collections::hash_map::DefaultHasher::new();
+		emulator.handler.get_composed_text(&emulator.machine).hash(&mut hasher);
+		(emulator.machine.get_reg_u16(Reg::CS), emulator.machine.get_reg_u16(Reg::IP), emulator.machine.get_reg_u16(Reg::AX)).hash(&mut hasher);
+		hasher.finish()
+	}
+
+	#[test] fn test_restart_matches_cold_start() {
+		let dir_path = make_test_dir("restart");
+		std::fs::create_dir_all(dir_path.join("SAVES")).unwrap();
+		std::fs::write(dir_path.join("SAVES").join("DATA.TXT"), b"data").unwrap();
+		let mut options = EmulatorOptions::default();
+		options.video.print_screen_dir = None;
+		let program = ProgramImage::new(CHDIR_OPEN_AND_PRINT_PROGRAM.to_vec(), b"C:\\OPEN.COM");
+		let new_file_system = || Box::new(StandardDosFileSystem::new(dir_path.clone()));
+
+		let mut emulator = Emulator::new(Some(program.clone()), new_file_system(), options.clone()).unwrap();
+		let cold_start_hash = run_frames_and_hash(&mut emulator);
+		assert_eq!(emulator.handler.file_system().open_handles().len(), 1);
+		assert_eq!(emulator.handler.file_system().current_directory(), b"SAVES");
+		for _ in 0 .. 50 {
+			emulator.restart(Some(program.clone())).unwrap();
+			assert!(emulator.handler.file_system().open_handles().is_empty());
+			// The program starts in the root again, so its chdir works every time.
+			assert!(emulator.handler.file_system().current_directory().is_empty());
+			assert_eq!(run_frames_and_hash(&mut emulator), cold_start_hash);
+			// The file the program left open was closed, so it gets the same handle every time.
+			assert_eq!(emulator.machine.get_reg_u16(Reg::AX), 0x0905);
+			assert_eq!(emulator.handler.file_system().open_handles().len(), 1);
+		}
+		
+		// A program that can't be loaded leaves the emulator running the old one.
+		let too_big = ProgramImage::new(vec![0x90; 0x10000], b"C:\\BIG.COM");
+		assert!(matches!(emulator.restart(Some(too_big)), Err(EmulatorError::Load(_))));
+		assert_eq!(emulator.handler.file_system().open_handles().len(), 1);
+		assert_eq!(emulator.handler.file_system().current_directory(), b"SAVES");
+		assert_eq!(run_frames_and_hash(&mut emulator), cold_start_hash);
+
+		// A file made on the host after a program looked for it is found once the file system is
+		// refreshed, even where the host's names are case sensitive.
+		assert_eq!(emulator.handler.file_system_mut().open(b"LEVEL2.BRD", DosFileAccessMode::ReadOnly), Err(DosErrorCode::FileNotFound));
+		std::fs::write(dir_path.join("SAVES").join("level2.brd"), b"board").unwrap();
+		emulator.handler.refresh_file_system();
+		assert!(emulator.handler.file_system_mut().open(b"LEVEL2.BRD", DosFileAccessMode::ReadOnly).is_ok());
+		std::fs::remove_dir_all(&dir_path).ok();
+	}
+}
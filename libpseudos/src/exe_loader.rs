@@ -1,9 +1,13 @@
 use crate::bios_loader::initialise_bios_data_area;
+use crate::dos_command_tail::{command_tail_fcb_args, parse_fcb_filename, MAX_COMMAND_TAIL_BYTES};
+use crate::dos_file_system::DosFileSystem;
+use crate::dos_environment::environment_program_path;
+use crate::dos_memory::{initialise_memory_blocks, memory_end_segment, program_owner_name, FIRST_MCB_SEGMENT};
+use crate::machine::Machine;
 
-use xachtsechs::types::{DataLocation8, DataLocation16, Reg};
-use xachtsechs::machine8086::Machine8086;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::Seek;
+use xachtsechs::types::{Reg, RegHalf};
+use xachtsechs::machine8086::INTERRUPT_TABLE_ENTRY_BYTES;
+use byteorder::{LittleEndian, ReadBytesExt};
 
 // https://wiki.osdev.org/MZ
 
@@ -11,12 +15,80 @@ const EXE_PARAGRAPH_BYTES: usize = 16;
 // The Program Segment Prefix is 256 bytes in size, which is 16 paragraphs.
 const EXE_PROGRAM_SEGMENT_PREFIX_PARAGRAPHS: usize = 16;
 const EXE_BLOCK_BYTES: usize = 512;
+// Each relocation table entry is the offset and then the segment of a word to add the load
+// segment to.
+const EXE_RELOCATION_ENTRY_BYTES: usize = 4;
 // This is the paragraph where the EXE file puts the code data.
 const EXE_ORIGIN_PARAGRAPH: usize = 0x100;
+/// The segment of the PSP of the program that was loaded.
+pub const PROGRAM_PSP_SEGMENT: u16 = EXE_ORIGIN_PARAGRAPH as u16;
+// The environment block goes in the space between the DOS data and the PSP, straight after the
+// first MCB. It has to end before the MCB of the program's own block, the paragraph before the PSP.
+const ENVIRONMENT_PARAGRAPH: usize = FIRST_MCB_SEGMENT as usize + 1;
+const MAX_ENVIRONMENT_BYTES: usize = (EXE_ORIGIN_PARAGRAPH - 1 - ENVIRONMENT_PARAGRAPH) * EXE_PARAGRAPH_BYTES;
+// The PSP keeps copies of the terminate address (int 22h), Ctrl+Break (int 23h) and critical error
+// (int 24h) vectors from when the program started, which DOS puts back when the program ends. That
+// way a program can hook them without having to restore them.
+const PSP_SAVED_VECTORS_OFFSET: u32 = 0x0a;
+const PSP_SAVED_VECTOR_INTERRUPTS: [u8; 3] = [0x22, 0x23, 0x24];
+// "MZ" read as a little endian word.
+const MZ_SIGNATURE: u16 = 0x5a4d;
+// A COM program and its stack share the 64KB segment after the PSP, and the stack needs at least
+// the word pushed at the start.
+const MAX_COM_PROGRAM_BYTES: usize = 0x10000 - 0x100 - 2;
+
+#[derive(Debug)]
+pub enum ExeLoadError {
+	/// The file doesn't start with "MZ", so it may be a COM file instead.
+	BadSignature(u16),
+	/// The header's fields don't fit together. Holds what's wrong with them.
+	BadHeader(String),
+	/// The file ended before the header or program data did.
+	Truncated,
+	Io(std::io::Error),
+	/// The command tail is longer than the PSP has room for. Holds the length.
+	CommandTailTooLong(usize),
+	/// The environment block doesn't fit in the space set aside for it. Holds the length.
+	EnvironmentTooLarge(usize),
+	/// The COM program doesn't fit in a segment. Holds the length.
+	ProgramTooLarge(usize),
+}
+
+impl std::fmt::Display for ExeLoadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			ExeLoadError::BadSignature(signature) => write!(f, "Not an EXE file: the signature is 0x{:04x} rather than \"MZ\"", signature),
+			ExeLoadError::BadHeader(reason) => write!(f, "The EXE header is invalid: {}", reason),
+			ExeLoadError::Truncated => write!(f, "The EXE file is truncated"),
+			ExeLoadError::Io(err) => write!(f, "Failed to read the EXE file: {}", err),
+			ExeLoadError::CommandTailTooLong(len) => write!(f, "Command line tail too long: {} bytes, but the limit is {}", len, MAX_COMMAND_TAIL_BYTES),
+			ExeLoadError::EnvironmentTooLarge(len) => write!(f, "Environment too large: {} bytes, but the limit is {}", len, MAX_ENVIRONMENT_BYTES),
+			ExeLoadError::ProgramTooLarge(len) => write!(f, "COM program too large: {} bytes, but the limit is {}", len, MAX_COM_PROGRAM_BYTES),
+		}
+	}
+}
+
+impl std::error::Error for ExeLoadError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			ExeLoadError::Io(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<std::io::Error> for ExeLoadError {
+	fn from(err: std::io::Error) -> ExeLoadError {
+		if err.kind() == std::io::ErrorKind::UnexpectedEof {
+			ExeLoadError::Truncated
+		} else {
+			ExeLoadError::Io(err)
+		}
+	}
+}
 
 #[derive(Debug)]
 pub struct MzHeader {
-	signature: u16,
 	last_block_bytes: u16,
 	file_block_count: u16,
 	relocation_items: u16,
@@ -25,12 +97,9 @@ pub struct MzHeader {
 	maximum_memory_paragraphs: u16,
 	initial_ss: u16,
 	initial_sp: u16,
-	checksum: u16,
 	initial_ip: u16,
 	initial_cs: u16,
 	relocation_table: u16,
-	overlay: u16,
-	overlay_information: u16,
 }
 
 impl MzHeader {
@@ -38,25 +107,31 @@ impl MzHeader {
 		28
 	}
 
-	pub fn parse(stream: &mut std::io::Read) -> Result<MzHeader, String> {
-		let signature = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read signature: {}", e))?;
-		let last_block_bytes = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read last_block_bytes: {}", e))?;
-		let file_block_count = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read file_block_count: {}", e))?;
-		let relocation_items = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read relocation_items: {}", e))?;
-		let header_paragraph_count = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read header_paragraph_count: {}", e))?;
-		let minimum_memory_paragraphs = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read minimum_memory_paragraphs: {}", e))?;
-		let maximum_memory_paragraphs = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read maximum_memory_paragraphs: {}", e))?;
-		let initial_ss = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read initial_ss: {}", e))?;
-		let initial_sp = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read initial_sp: {}", e))?;
-		let checksum = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read checksum: {}", e))?;
-		let initial_ip = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read initial_ip: {}", e))?;
-		let initial_cs = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read initial_cs: {}", e))?;
-		let relocation_table = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read relocation_table: {}", e))?;
-		let overlay = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read overlay: {}", e))?;
-		let overlay_information = stream.read_u16::<LittleEndian>().map_err(|e| format!("Failed to read overlay_information: {}", e))?;
-		
-		Ok(MzHeader {
-			signature,
+	pub fn parse(stream: &mut dyn std::io::Read) -> Result<MzHeader, ExeLoadError> {
+		let signature = stream.read_u16::<LittleEndian>()?;
+		// Some linkers wrote the signature backwards, which DOS also accepts.
+		if signature != MZ_SIGNATURE && signature != MZ_SIGNATURE.swap_bytes() {
+			return Err(ExeLoadError::BadSignature(signature));
+		}
+		let last_block_bytes = stream.read_u16::<LittleEndian>()?;
+		let file_block_count = stream.read_u16::<LittleEndian>()?;
+		let relocation_items = stream.read_u16::<LittleEndian>()?;
+		let header_paragraph_count = stream.read_u16::<LittleEndian>()?;
+		let minimum_memory_paragraphs = stream.read_u16::<LittleEndian>()?;
+		let maximum_memory_paragraphs = stream.read_u16::<LittleEndian>()?;
+		let initial_ss = stream.read_u16::<LittleEndian>()?;
+		let initial_sp = stream.read_u16::<LittleEndian>()?;
+		// DOS doesn't check the checksum.
+		stream.read_u16::<LittleEndian>()?;
+		let initial_ip = stream.read_u16::<LittleEndian>()?;
+		let initial_cs = stream.read_u16::<LittleEndian>()?;
+		let relocation_table = stream.read_u16::<LittleEndian>()?;
+		// The overlay number, which is 0 for the main program, and the overlay information that
+		// follows it are only used by overlay managers.
+		stream.read_u16::<LittleEndian>()?;
+		stream.read_u16::<LittleEndian>()?;
+		
+		let header = MzHeader {
 			last_block_bytes,
 			file_block_count,
 			relocation_items,
@@ -65,13 +140,32 @@ impl MzHeader {
 			maximum_memory_paragraphs,
 			initial_ss,
 			initial_sp,
-			checksum,
 			initial_ip,
 			initial_cs,
 			relocation_table,
-			overlay,
-			overlay_information,
-		})
+		};
+		header.validate()?;
+		Ok(header)
+	}
+	
+	// Checks that the header's sizes fit together, so the layout worked out from them is
+	// consistent. Whether the file is as long as the header says is only known when the data is
+	// read.
+	fn validate(&self) -> Result<(), ExeLoadError> {
+		let bad_header = |reason: String| Err(ExeLoadError::BadHeader(reason));
+		if self.data_start() < MzHeader::byte_size() {
+			return bad_header(format!("The header is {} paragraphs, which is too small to hold its own {} bytes", self.header_paragraph_count, MzHeader::byte_size()));
+		}
+		if self.data_end() < self.data_start() {
+			return bad_header(format!("The file is {} bytes going by the block count, which is smaller than the {} byte header", self.data_end(), self.data_start()));
+		}
+		// The table can be anywhere in the header, even over the fixed fields, as long as it doesn't
+		// run into the program image.
+		if self.relocation_items > 0 && self.relocation_table_end() > self.data_start() {
+			return bad_header(format!("The relocation table ({} entries from 0x{:x} to 0x{:x}) goes past the end of the header at 0x{:x}",
+				self.relocation_items, self.relocation_table, self.relocation_table_end(), self.data_start()));
+		}
+		Ok(())
 	}
 	
 	pub fn data_start(&self) -> usize {
@@ -79,26 +173,72 @@ impl MzHeader {
 	}
 	
 	pub fn data_end(&self) -> usize {
-		let subtract_bytes = if self.last_block_bytes > 0 {
+		// 0 means the last block is full. Some linkers wrote 512 for that instead, and anything
+		// bigger is treated the same way rather than making the file longer than its blocks.
+		let subtract_bytes = if self.last_block_bytes > 0 && (self.last_block_bytes as usize) < EXE_BLOCK_BYTES {
 			EXE_BLOCK_BYTES - self.last_block_bytes as usize
 		} else {
 			0
 		};
-		(self.file_block_count as usize * EXE_BLOCK_BYTES) - subtract_bytes
+		(self.file_block_count as usize * EXE_BLOCK_BYTES).saturating_sub(subtract_bytes)
+	}
+	
+	fn relocation_table_end(&self) -> usize {
+		self.relocation_table as usize + self.relocation_items as usize * EXE_RELOCATION_ENTRY_BYTES
+	}
+	
+	/// Reads the relocation table from wherever the header says it is, giving the segment and
+	/// offset (relative to the start of the program image) of each word to relocate.
+	pub fn read_relocations<StreamType>(&self, stream: &mut StreamType) -> Result<Vec<(u16, u16)>, ExeLoadError>
+		where StreamType: std::io::Read + std::io::Seek
+	{
+		stream.seek(std::io::SeekFrom::Start(self.relocation_table as u64))?;
+		(0 .. self.relocation_items).map(|_| {
+			let offset = stream.read_u16::<LittleEndian>()?;
+			let segment = stream.read_u16::<LittleEndian>()?;
+			Ok((segment, offset))
+		}).collect()
+	}
+	
+	/// Describes where everything is in the file and how much memory the program needs, without
+	/// loading it.
+	pub fn describe(&self) -> String {
+		let image_bytes = self.data_end() - self.data_start();
+		let relocations = if self.relocation_items > 0 {
+			format!("{} entries from 0x{:x} to 0x{:x}", self.relocation_items, self.relocation_table, self.relocation_table_end())
+		} else {
+			"none".to_string()
+		};
+		let memory_bytes = |extra_paragraphs: u16| image_bytes + extra_paragraphs as usize * EXE_PARAGRAPH_BYTES;
+		format!("Header: {} bytes ({} paragraphs)\n\
+			Image: {} bytes, from 0x{:x} to 0x{:x} in the file\n\
+			Relocations: {}\n\
+			Memory: {} to {} bytes (the image plus {} to {} paragraphs)\n\
+			Entry point: {:04x}:{:04x}, stack: {:04x}:{:04x}\n",
+			self.data_start(), self.header_paragraph_count,
+			image_bytes, self.data_start(), self.data_end(),
+			relocations,
+			memory_bytes(self.minimum_memory_paragraphs), memory_bytes(self.maximum_memory_paragraphs), self.minimum_memory_paragraphs, self.maximum_memory_paragraphs,
+			self.initial_cs, self.initial_ip, self.initial_ss, self.initial_sp)
 	}
 	
-	pub fn extract_data<StreamType>(&self, stream: &mut StreamType) -> Result<Vec<u8>, std::io::Error>
+	pub fn extract_data<StreamType>(&self, stream: &mut StreamType) -> Result<Vec<u8>, ExeLoadError>
 		where StreamType: std::io::Read + std::io::Seek
 	{
-		stream.seek(std::io::SeekFrom::Start(self.data_start() as u64));
+		// The block count can say the file is up to 32MB, so check it's really that long before
+		// making room for the data.
+		let file_length = stream.seek(std::io::SeekFrom::End(0))?;
+		if self.data_end() as u64 > file_length {
+			return Err(ExeLoadError::Truncated);
+		}
+		stream.seek(std::io::SeekFrom::Start(self.data_start() as u64))?;
 		let data_length = self.data_end() - self.data_start();
-		let mut result = vec![];
-		result.resize(data_length, 0);
-		stream.read(&mut result)?;
+		let mut result = vec![0; data_length];
+		stream.read_exact(&mut result)?;
 		Ok(result)
 	}
 	
-	pub fn load_into_machine<StreamType>(&self, machine: &mut Machine8086, stream: &mut StreamType)
+	pub fn load_into_machine<StreamType>(&self, machine: &mut dyn Machine, stream: &mut StreamType, command_line_tail: &[u8], environment_block: &[u8], file_system: &dyn DosFileSystem) -> Result<(), ExeLoadError>
 		where StreamType: std::io::Read + std::io::Seek
 	{
 		machine.set_reg_u16(Reg::SP, self.initial_sp);
@@ -107,41 +247,131 @@ impl MzHeader {
 		let segment_offset = (EXE_ORIGIN_PARAGRAPH + EXE_PROGRAM_SEGMENT_PREFIX_PARAGRAPHS) as u16;
 		machine.set_reg_u16(Reg::SS, self.initial_ss.wrapping_add(segment_offset));
 		machine.set_reg_u16(Reg::CS, self.initial_cs.wrapping_add(segment_offset));
+		if self.initial_ss == 0 && self.initial_sp == 0 {
+			// Some linkers leave the stack as 0000:0000, which would have the first push wrap round
+			// to the end of the program's first segment, over its code. The stack goes at the top
+			// of the memory DOS gives the program instead (its image plus the most extra memory the
+			// header asks for), in the last 64K of it if there's that much.
+			let image_paragraphs = (self.data_end() - self.data_start()).div_ceil(EXE_PARAGRAPH_BYTES);
+			let allocation_end = segment_offset as usize + image_paragraphs + self.maximum_memory_paragraphs as usize;
+			let end_segment = allocation_end.min(memory_end_segment(machine) as usize) as u16;
+			let stack_segment = end_segment.saturating_sub(0x1000).max(segment_offset);
+			let stack_bytes = ((end_segment.saturating_sub(stack_segment) as u32) << 4).min(0x10000);
+			machine.set_reg_u16(Reg::SS, stack_segment);
+			machine.set_reg_u16(Reg::SP, stack_bytes.saturating_sub(2) as u16);
+		}
 		
 		machine.set_reg_u16(Reg::DS, EXE_ORIGIN_PARAGRAPH as u16);
 		machine.set_reg_u16(Reg::ES, EXE_ORIGIN_PARAGRAPH as u16);
 		
-		let exe_data = self.extract_data(stream).unwrap();
+		let exe_data = self.extract_data(stream)?;
+		let load_addr = (EXE_ORIGIN_PARAGRAPH + EXE_PROGRAM_SEGMENT_PREFIX_PARAGRAPHS) * EXE_PARAGRAPH_BYTES;
+		if load_addr + exe_data.len() > machine.memory().len() {
+			return Err(ExeLoadError::BadHeader(format!("The program image is {} bytes, which doesn't fit in memory", exe_data.len())));
+		}
 		machine.insert_contiguous_bytes(&exe_data, (EXE_ORIGIN_PARAGRAPH + 16) * EXE_PARAGRAPH_BYTES);
+		// The program's segments are relative to where it's loaded, so each word in the table
+		// gets the load segment added.
+		for (segment, offset) in self.read_relocations(stream)? {
+			let addr = ((segment_offset.wrapping_add(segment) as usize) << 4) + offset as usize;
+			if addr + 2 > machine.memory().len() {
+				return Err(ExeLoadError::BadHeader(format!("The relocation at {:04x}:{:04x} is outside of memory", segment, offset)));
+			}
+			let value = machine.peek_u16(addr as u32);
+			machine.poke_u16(addr as u32, value.wrapping_add(segment_offset));
+		}
 		
 		initialise_bios_data_area(machine);
-		initialise_dos_program_segment_prefix(machine, exe_data.len(), b"");
+		let environment_segment = insert_environment_block(machine, environment_block)?;
+		initialise_memory_blocks(machine, PROGRAM_PSP_SEGMENT, program_owner_name(environment_program_path(environment_block).unwrap_or(b"")));
+		let (fcb1_result, fcb2_result) = initialise_dos_program_segment_prefix(machine, PROGRAM_PSP_SEGMENT, command_line_tail, environment_segment, file_system)?;
+		// DOS tells the program whether the drives in the FCBs are valid through AL and AH.
+		machine.set_reg_u8(Reg::AX, RegHalf::Low, fcb1_result);
+		machine.set_reg_u8(Reg::AX, RegHalf::High, fcb2_result);
 		
-		/*for (i, b) in machine.memory[10000..20000].iter().enumerate() {
+		/*for (i, b) in machine.memory()[10000..20000].iter().enumerate() {
 			println!("{}: {:02x}", i + 10000, b);
 		}
 		panic!();*/
+		Ok(())
 	}
 }
 
-// https://en.wikipedia.org/wiki/Program_Segment_Prefix
-fn initialise_dos_program_segment_prefix(machine: &mut Machine8086, program_size: usize, command_line_tail: &[u8]) -> Result<(), String> {
-	// The DS register will be the PSP location when a program starts.
-	let psp_start = (EXE_ORIGIN_PARAGRAPH * EXE_PARAGRAPH_BYTES) as u32; //machine.get_seg_origin(Reg::DS);
-	// CP/M exit: Always 20h
-	//machine.poke_u16(psp_start + 0x00, 0x20);
+/// Loads a COM program, which is a plain memory image that starts at offset 0x100 in the same
+/// segment as its PSP. All the segment registers point at the PSP, and the stack starts at the
+/// top of the segment with a zero word on it.
+/// http://www.delorie.com/djgpp/doc/rbinter/id/51/29.html
+pub fn load_com_into_machine(machine: &mut dyn Machine, com_data: &[u8], command_line_tail: &[u8], environment_block: &[u8], file_system: &dyn DosFileSystem) -> Result<(), ExeLoadError> {
+	if com_data.len() > MAX_COM_PROGRAM_BYTES {
+		return Err(ExeLoadError::ProgramTooLarge(com_data.len()));
+	}
+	let psp_segment = PROGRAM_PSP_SEGMENT;
+	for segment_reg in [Reg::CS, Reg::DS, Reg::ES, Reg::SS].iter() {
+		machine.set_reg_u16(*segment_reg, psp_segment);
+	}
+	machine.set_reg_u16(Reg::IP, 0x100);
+	machine.set_reg_u16(Reg::SP, 0xfffe);
+	machine.poke_u16(((psp_segment as u32) << 4) + 0xfffe, 0);
+	
+	machine.insert_contiguous_bytes(com_data, (EXE_ORIGIN_PARAGRAPH + EXE_PROGRAM_SEGMENT_PREFIX_PARAGRAPHS) * EXE_PARAGRAPH_BYTES);
+	
+	initialise_bios_data_area(machine);
+	let environment_segment = insert_environment_block(machine, environment_block)?;
+	initialise_memory_blocks(machine, psp_segment, program_owner_name(environment_program_path(environment_block).unwrap_or(b"")));
+	let (fcb1_result, fcb2_result) = initialise_dos_program_segment_prefix(machine, psp_segment, command_line_tail, environment_segment, file_system)?;
+	machine.set_reg_u8(Reg::AX, RegHalf::Low, fcb1_result);
+	machine.set_reg_u8(Reg::AX, RegHalf::High, fcb2_result);
+	Ok(())
+}
+
+/// Loads an EXE or COM program from its file data. Like DOS, files starting with an EXE signature
+/// are loaded as EXE files whatever their extension, and anything else is a COM file.
+pub fn load_program_into_machine(machine: &mut dyn Machine, program_data: &[u8], command_line_tail: &[u8], environment_block: &[u8], file_system: &dyn DosFileSystem) -> Result<(), ExeLoadError> {
+	let mut stream = std::io::Cursor::new(program_data);
+	match MzHeader::parse(&mut stream) {
+		Ok(exe_header) => exe_header.load_into_machine(machine, &mut stream, command_line_tail, environment_block, file_system),
+		Err(ExeLoadError::BadSignature(_)) | Err(ExeLoadError::Truncated) => load_com_into_machine(machine, program_data, command_line_tail, environment_block, file_system),
+		Err(err) => Err(err),
+	}
+}
+
+// Puts the environment block in the space before the program's PSP, and returns its segment.
+fn insert_environment_block(machine: &mut dyn Machine, environment_block: &[u8]) -> Result<u16, ExeLoadError> {
+	if environment_block.len() > MAX_ENVIRONMENT_BYTES {
+		return Err(ExeLoadError::EnvironmentTooLarge(environment_block.len()));
+	}
+	machine.insert_contiguous_bytes(environment_block, ENVIRONMENT_PARAGRAPH * EXE_PARAGRAPH_BYTES);
+	Ok(ENVIRONMENT_PARAGRAPH as u16)
+}
+
+/// Fills in the PSP at the given segment with the command tail and the segment of an environment
+/// block that's already in memory. The first two arguments in the tail are parsed into the FCBs.
+/// Returns 0xff for each of the two FCBs that has an invalid drive, otherwise 0.
+/// https://en.wikipedia.org/wiki/Program_Segment_Prefix
+pub fn initialise_dos_program_segment_prefix(machine: &mut dyn Machine, psp_segment: u16, command_line_tail: &[u8], environment_segment: u16, file_system: &dyn DosFileSystem) -> Result<(u8, u8), ExeLoadError> {
+	let psp_start = (psp_segment as u32) << 4;
+	// int 20h, which a COM program returns to when it does a near ret with nothing else on its stack.
+	machine.poke_u16(psp_start, 0x20cd);
 	// These values are probably all wrong:
 	
 	// Segment after the memeory allocated to the program.
-	dbg!((psp_start, program_size));
 	machine.poke_u16(psp_start + 0x02, 0xa000);
 	
-	// +1 for the 0x0d teminator character.
-	let command_line_tail_len = command_line_tail.len() + 1;
-	if command_line_tail_len > 0xff {
-		return Err(format!("Command line tail too long: {}", command_line_tail.len()));
+	// Segment of the environment block.
+	machine.poke_u16(psp_start + 0x2c, environment_segment);
+	
+	for (vector_index, &interrupt_index) in PSP_SAVED_VECTOR_INTERRUPTS.iter().enumerate() {
+		let entry_addr = interrupt_index as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+		let saved_addr = psp_start + PSP_SAVED_VECTORS_OFFSET + vector_index as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+		let vector = machine.memory()[entry_addr as usize .. entry_addr as usize + INTERRUPT_TABLE_ENTRY_BYTES].to_vec();
+		machine.insert_contiguous_bytes(&vector, saved_addr as usize);
+	}
+	
+	// The length doesn't include the 0x0d terminator character.
+	if command_line_tail.len() > MAX_COMMAND_TAIL_BYTES {
+		return Err(ExeLoadError::CommandTailTooLong(command_line_tail.len()));
 	}
-	machine.poke_u8(psp_start + 0x80, command_line_tail_len as u8);
+	machine.poke_u8(psp_start + 0x80, command_line_tail.len() as u8);
 	let mut current_command_line_pos = psp_start + 0x81;
 	for byte in command_line_tail {
 		machine.poke_u8(current_command_line_pos, *byte);
@@ -149,5 +379,288 @@ fn initialise_dos_program_segment_prefix(machine: &mut Machine8086, program_size
 	}
 	machine.poke_u8(current_command_line_pos, 0x0d);
 	
-	Ok(())
+	// The first two arguments are parsed into the FCBs at 0x5c and 0x6c, like int 21h AH=29h does
+	// with only bit 0 of the flags set.
+	let fcb_args = command_tail_fcb_args(command_line_tail);
+	let mut fcb_results = [0u8; 2];
+	for (fcb_index, fcb_result) in fcb_results.iter_mut().enumerate() {
+		let fcb_arg = fcb_args.get(fcb_index).cloned().unwrap_or(b"");
+		let parsed = parse_fcb_filename(fcb_arg, 1, &|drive| file_system.is_valid_drive(drive - 1));
+		let mut fcb = [0u8; 16];
+		parsed.write_to_fcb(&mut fcb, 0);
+		let fcb_start = psp_start + 0x5c + (fcb_index as u32 * 0x10);
+		for (offset, byte) in fcb.iter().enumerate() {
+			machine.poke_u8(fcb_start + offset as u32, *byte);
+		}
+		*fcb_result = if parsed.invalid_drive { 0xff } else { 0 };
+	}
+	
+	Ok((fcb_results[0], fcb_results[1]))
+}
+
+/// Puts back the int 22h, 23h and 24h vectors saved in a PSP, for when its program ends.
+pub fn restore_psp_vectors(machine: &mut dyn Machine, psp_segment: u16) {
+	let psp_start = (psp_segment as u32) << 4;
+	for (vector_index, &interrupt_index) in PSP_SAVED_VECTOR_INTERRUPTS.iter().enumerate() {
+		let entry_addr = interrupt_index as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+		let saved_addr = psp_start + PSP_SAVED_VECTORS_OFFSET + vector_index as u32 * INTERRUPT_TABLE_ENTRY_BYTES as u32;
+		let vector = machine.memory()[saved_addr as usize .. saved_addr as usize + INTERRUPT_TABLE_ENTRY_BYTES].to_vec();
+		machine.insert_contiguous_bytes(&vector, entry_addr as usize);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dos_command_tail::build_command_tail;
+	use crate::dos_drive_table::DosDriveTable;
+	use crate::dos_environment::build_environment_block;
+	use crate::dos_file_system::StandardDosFileSystem;
+	use crate::machine::FakeMachine;
+	use xachtsechs::machine8086::Machine8086;
+	
+	#[test] fn test_psp_fcbs() {
+		let mut machine = Machine8086::new(1024*1024);
+		let mut drive_table = DosDriveTable::new();
+		drive_table.mount(2, Box::new(StandardDosFileSystem::new(std::env::temp_dir().join("pseudos_test_nonexistent")))).unwrap();
+		let command_line_tail = build_command_tail(&["b:save.dat", "/q", "*.zzt"]).unwrap();
+		let fcb_results = initialise_dos_program_segment_prefix(&mut machine, PROGRAM_PSP_SEGMENT, &command_line_tail, 0, &drive_table).unwrap();
+		let psp_start = EXE_ORIGIN_PARAGRAPH * EXE_PARAGRAPH_BYTES;
+		let psp = &machine.memory[psp_start .. psp_start + 0x100];
+		
+		assert_eq!(psp[0x80] as usize, command_line_tail.len());
+		assert_eq!(&psp[0x81 .. 0x81 + command_line_tail.len()], &command_line_tail[..]);
+		assert_eq!(psp[0x81 + command_line_tail.len()], 0x0d);
+		// B: isn't mounted, so the first FCB's drive is flagged as invalid.
+		assert_eq!(psp[0x5c], 2);
+		assert_eq!(&psp[0x5d .. 0x68], b"SAVE    DAT");
+		// The switch is skipped, and the wildcard fills the name.
+		assert_eq!(psp[0x6c], 0);
+		assert_eq!(&psp[0x6d .. 0x78], b"????????ZZT");
+		assert_eq!(fcb_results, (0xff, 0));
+		
+		let long_tail = vec![b'x'; MAX_COMMAND_TAIL_BYTES + 1];
+		match initialise_dos_program_segment_prefix(&mut machine, PROGRAM_PSP_SEGMENT, &long_tail, 0, &drive_table) {
+			Err(ExeLoadError::CommandTailTooLong(len)) => assert_eq!(len, MAX_COMMAND_TAIL_BYTES + 1),
+			result => panic!("Unexpected result: {:?}", result),
+		}
+	}
+	
+	#[test] fn test_psp_environment() {
+		let mut machine = Machine8086::new(1024*1024);
+		let drive_table = DosDriveTable::new();
+		let environment_block = build_environment_block(&[(b"BLASTER".to_vec(), b"A220".to_vec())], b"C:\\ZZT.EXE");
+		let environment_segment = insert_environment_block(&mut machine, &environment_block).unwrap();
+		initialise_dos_program_segment_prefix(&mut machine, PROGRAM_PSP_SEGMENT, b"", environment_segment, &drive_table).unwrap();
+		let psp_start = (EXE_ORIGIN_PARAGRAPH * EXE_PARAGRAPH_BYTES) as u32;
+		let environment_start = machine.peek_u16(psp_start + 0x2c) as usize * EXE_PARAGRAPH_BYTES;
+		assert_eq!(&machine.memory[environment_start .. environment_start + environment_block.len()], &environment_block[..]);
+		
+		let huge_environment_block = vec![b'x'; 0x10000];
+		match insert_environment_block(&mut machine, &huge_environment_block) {
+			Err(ExeLoadError::EnvironmentTooLarge(len)) => assert_eq!(len, 0x10000),
+			result => panic!("Unexpected result: {:?}", result),
+		}
+	}
+	
+	#[test] fn test_load_com() {
+		let mut machine = Machine8086::new(1024*1024);
+		let drive_table = DosDriveTable::new();
+		// mov ax, 4c00h; int 21h
+		let com_data = [0xb8, 0x00, 0x4c, 0xcd, 0x21];
+		load_program_into_machine(&mut machine, &com_data, b" /q", b"\0\0", &drive_table).unwrap();
+		let psp_start = EXE_ORIGIN_PARAGRAPH * EXE_PARAGRAPH_BYTES;
+		assert_eq!(&machine.memory[psp_start + 0x100 .. psp_start + 0x105], &com_data[..]);
+		assert_eq!(&machine.memory[psp_start + 0x80 .. psp_start + 0x84], b"\x03 /q");
+		assert_eq!(machine.get_reg_u16(Reg::CS), EXE_ORIGIN_PARAGRAPH as u16);
+		assert_eq!(machine.get_reg_u16(Reg::SS), EXE_ORIGIN_PARAGRAPH as u16);
+		assert_eq!((machine.get_reg_u16(Reg::IP), machine.get_reg_u16(Reg::SP)), (0x100, 0xfffe));
+		
+		match load_com_into_machine(&mut machine, &vec![0x90; 0x10000], b"", b"\0\0", &drive_table) {
+			Err(ExeLoadError::ProgramTooLarge(0x10000)) => {}
+			result => panic!("Unexpected result: {:?}", result),
+		}
+	}
+	
+	#[test] fn test_parse_errors() {
+		match MzHeader::parse(&mut &b"\xb4\x09\xba\x00\x01\xcd\x21"[..]) {
+			Err(ExeLoadError::BadSignature(0x09b4)) => {}
+			result => panic!("Unexpected result: {:?}", result),
+		}
+		match MzHeader::parse(&mut &b"MZ\x10\x00"[..]) {
+			Err(ExeLoadError::Truncated) => {}
+			result => panic!("Unexpected result: {:?}", result),
+		}
+		
+		// A header that says the file is one block long, but with no data after the header.
+		let mut exe_data = b"MZ\x00\x00\x01\x00".to_vec();
+		exe_data.resize(0x1e, 0);
+		exe_data[8] = 2;
+		let header = MzHeader::parse(&mut &exe_data[..]).unwrap();
+		match header.extract_data(&mut std::io::Cursor::new(exe_data)) {
+			Err(ExeLoadError::Truncated) => {}
+			result => panic!("Unexpected result: {:?}", result),
+		}
+	}
+	
+	// Builds an EXE from the header fields after the signature, with the header padded out to
+	// header_paragraph_count and the image after it. The file size fields are filled in.
+	fn build_exe(mut header_words: [u16; 13], image: &[u8]) -> Vec<u8> {
+		let header_bytes = header_words[3] as usize * EXE_PARAGRAPH_BYTES;
+		let file_length = header_bytes + image.len();
+		header_words[0] = (file_length % EXE_BLOCK_BYTES) as u16;
+		header_words[1] = (file_length / EXE_BLOCK_BYTES + (header_words[0] != 0) as usize) as u16;
+		let mut exe = MZ_SIGNATURE.to_le_bytes().to_vec();
+		header_words.iter().for_each(|word| exe.extend(&word.to_le_bytes()));
+		exe.resize(header_bytes.max(exe.len()), 0);
+		exe.extend(image);
+		exe
+	}
+	
+	#[test] fn test_relocations() {
+		// Two relocations in a table after the fixed header.
+		let mut exe = build_exe([0, 0, 2, 3, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], b"\x01\x00\xaa\xbb\x34\x12");
+		exe[0x1c .. 0x24].copy_from_slice(&[0, 0, 0, 0, 4, 0, 0, 0]);
+		let header = MzHeader::parse(&mut &exe[..]).unwrap();
+		assert_eq!(header.read_relocations(&mut std::io::Cursor::new(&exe)).unwrap(), vec![(0, 0), (0, 4)]);
+		assert_eq!(header.describe(), "Header: 48 bytes (3 paragraphs)\nImage: 6 bytes, from 0x30 to 0x36 in the file\n\
+			Relocations: 2 entries from 0x1c to 0x24\nMemory: 6 to 1048566 bytes (the image plus 0 to 65535 paragraphs)\n\
+			Entry point: 0000:0000, stack: 0000:0100\n");
+		let mut machine = Machine8086::new(1024*1024);
+		let drive_table = DosDriveTable::new();
+		load_program_into_machine(&mut machine, &exe, b"", b"\0\0", &drive_table).unwrap();
+		let load_segment = (EXE_ORIGIN_PARAGRAPH + EXE_PROGRAM_SEGMENT_PREFIX_PARAGRAPHS) as u16;
+		let load_addr = load_segment as u32 * EXE_PARAGRAPH_BYTES as u32;
+		assert_eq!(machine.peek_u16(load_addr), 1 + load_segment);
+		assert_eq!(machine.peek_u16(load_addr + 2), 0xbbaa);
+		assert_eq!(machine.peek_u16(load_addr + 4), 0x1234 + load_segment);
+		
+		// A table over the fixed header's fields, where the entry is made of the initial IP and CS.
+		let exe = build_exe([0, 0, 1, 2, 0, 0xffff, 0, 0x100, 0, 4, 0, 0x14, 0], b"\x01\x00\xaa\xbb\x34\x12");
+		let header = MzHeader::parse(&mut &exe[..]).unwrap();
+		assert_eq!(header.read_relocations(&mut std::io::Cursor::new(&exe)).unwrap(), vec![(0, 4)]);
+	}
+	
+	#[test] fn test_zero_stack() {
+		let exe = build_exe([0, 0, 0, 2, 0, 0xffff, 0, 0, 0, 0, 0, 0x1c, 0], &[0x90; 0x40]);
+		let mut machine = Machine8086::new(1024*1024);
+		load_program_into_machine(&mut machine, &exe, b"", b"\0\0", &DosDriveTable::new()).unwrap();
+		assert_eq!((machine.get_reg_u16(Reg::SS), machine.get_reg_u16(Reg::SP)), (0x9000, 0xfffe));
+		
+		// With less memory than 64K above the program, the stack is as big as the memory there is.
+		let mut machine = FakeMachine::new(0x8000);
+		load_program_into_machine(&mut machine, &exe, b"", b"\0\0", &DosDriveTable::new()).unwrap();
+		let load_segment = (EXE_ORIGIN_PARAGRAPH + EXE_PROGRAM_SEGMENT_PREFIX_PARAGRAPHS) as u16;
+		assert_eq!(machine.get_reg_u16(Reg::SS), load_segment);
+		assert_eq!(machine.get_reg_u16(Reg::SP), ((0x800 - load_segment) << 4) - 2);
+		
+		// The stack is at the top of the memory the header asks for, not of all the memory.
+		let exe = build_exe([0, 0, 0, 2, 0, 0x100, 0, 0, 0, 0, 0, 0x1c, 0], &[0x90; 0x40]);
+		let mut machine = Machine8086::new(1024*1024);
+		load_program_into_machine(&mut machine, &exe, b"", b"\0\0", &DosDriveTable::new()).unwrap();
+		assert_eq!((machine.get_reg_u16(Reg::SS), machine.get_reg_u16(Reg::SP)), (load_segment, (0x104 << 4) - 2));
+		
+		// A stack that's only zero in SS is left alone.
+		let exe = build_exe([0, 0, 0, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &[0x90; 0x40]);
+		let mut machine = Machine8086::new(1024*1024);
+		load_program_into_machine(&mut machine, &exe, b"", b"\0\0", &DosDriveTable::new()).unwrap();
+		assert_eq!((machine.get_reg_u16(Reg::SS), machine.get_reg_u16(Reg::SP)), (load_segment, 0x100));
+	}
+	
+	#[test] fn test_malformed_headers() {
+		let parse_error = |exe: &[u8]| match MzHeader::parse(&mut &exe[..]) {
+			Err(ExeLoadError::BadHeader(reason)) => reason,
+			result => panic!("Unexpected result: {:?}", result),
+		};
+		let image = [0x90; 0x300];
+		let mut exe = build_exe([0, 0, 0, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &image);
+		exe[8] = 0;
+		assert_eq!(parse_error(&exe), "The header is 0 paragraphs, which is too small to hold its own 28 bytes");
+		let mut exe = build_exe([0, 0, 0, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &image);
+		exe[4] = 0;
+		assert_eq!(parse_error(&exe), "The file is 0 bytes going by the block count, which is smaller than the 32 byte header");
+		let exe = build_exe([0, 0, 2, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &image);
+		assert_eq!(parse_error(&exe), "The relocation table (2 entries from 0x1c to 0x24) goes past the end of the header at 0x20");
+		
+		// A last block size over 511 means the last block is full.
+		let mut exe = build_exe([0, 0, 0, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &[0x90; 2 * EXE_BLOCK_BYTES - 32]);
+		exe[2 .. 4].copy_from_slice(&600u16.to_le_bytes());
+		let header = MzHeader::parse(&mut &exe[..]).unwrap();
+		assert_eq!(header.data_end(), 2 * EXE_BLOCK_BYTES);
+		assert_eq!(header.extract_data(&mut std::io::Cursor::new(&exe)).unwrap().len(), 2 * EXE_BLOCK_BYTES - 32);
+		
+		// Every truncation of a valid file, and lots of random changes to its header, either give an
+		// error or a layout that fits together, and never panic.
+		let valid_exe = build_exe([0, 0, 1, 3, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &image);
+		let mut corpus: Vec<Vec<u8>> = (0 .. 0x40).map(|length| valid_exe[.. length].to_vec()).collect();
+		corpus.push([&b"MZ"[..], &[0; 0x40]].concat());
+		let mut random_state = 1u32;
+		let mut random = || {
+			random_state = random_state.wrapping_mul(1103515245).wrapping_add(12345);
+			(random_state >> 16) as usize
+		};
+		for _ in 0 .. 2000 {
+			let mut exe = valid_exe.clone();
+			for _ in 0 .. 1 + random() % 4 {
+				let pos = 2 + random() % (MzHeader::byte_size() - 2);
+				exe[pos] = random() as u8;
+			}
+			corpus.push(exe);
+		}
+		let mut machine = Machine8086::new(1024*1024);
+		let drive_table = DosDriveTable::new();
+		for exe in corpus {
+			if let Ok(header) = MzHeader::parse(&mut &exe[..]) {
+				assert!(header.data_start() >= MzHeader::byte_size() && header.data_start() <= header.data_end());
+				assert!(header.relocation_items == 0 || header.relocation_table_end() <= header.data_start());
+				header.describe();
+				if let Ok(data) = header.extract_data(&mut std::io::Cursor::new(&exe)) {
+					assert_eq!(data.len(), header.data_end() - header.data_start());
+					assert!(header.data_end() <= exe.len());
+				}
+				if let Ok(relocations) = header.read_relocations(&mut std::io::Cursor::new(&exe)) {
+					assert_eq!(relocations.len(), header.relocation_items as usize);
+				}
+			}
+			load_program_into_machine(&mut machine, &exe, b"", b"\0\0", &drive_table).ok();
+		}
+	}
+	
+	#[test] fn test_adversarial_headers() {
+		let image = [0x90; 0x40];
+		let valid_exe = build_exe([0, 0, 0, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &image);
+		let with_word = |offset: usize, value: u16| {
+			let mut exe = valid_exe.clone();
+			exe[offset .. offset + 2].copy_from_slice(&value.to_le_bytes());
+			exe
+		};
+		
+		// The most blocks there can be claim a 32MB file, which is caught before anything is read.
+		let mut exe = with_word(4, 0xffff);
+		exe[2 .. 4].copy_from_slice(&[0, 0]);
+		let header = MzHeader::parse(&mut &exe[..]).unwrap();
+		assert_eq!(header.data_end(), 0xffff * EXE_BLOCK_BYTES);
+		match header.extract_data(&mut std::io::Cursor::new(&exe)) {
+			Err(ExeLoadError::Truncated) => {}
+			result => panic!("Unexpected result: {:?}", result),
+		}
+		// A header that's bigger than the whole file, the most relocations there can be, and a
+		// relocation table at the very end of the header's range.
+		let mut last_relocation_exe = with_word(0x18, 0xffff);
+		last_relocation_exe[6] = 1;
+		for exe in [with_word(8, 0xffff), with_word(6, 0xffff), last_relocation_exe].iter() {
+			match MzHeader::parse(&mut &exe[..]) {
+				Err(ExeLoadError::BadHeader(_)) => {}
+				result => panic!("Unexpected result: {:?}", result),
+			}
+		}
+		
+		// An image that doesn't fit in memory is an error, even on a machine with very little.
+		let exe = build_exe([0, 0, 0, 2, 0, 0xffff, 0, 0x100, 0, 0, 0, 0x1c, 0], &[0x90; 0x2000]);
+		let mut machine = FakeMachine::new(0x2000);
+		match load_program_into_machine(&mut machine, &exe, b"", b"\0\0", &DosDriveTable::new()) {
+			Err(ExeLoadError::BadHeader(reason)) => assert_eq!(reason, "The program image is 8192 bytes, which doesn't fit in memory"),
+			result => panic!("Unexpected result: {:?}", result),
+		}
+	}
 }
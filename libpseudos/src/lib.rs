@@ -1,8 +1,41 @@
+pub mod bios_floppy;
+pub mod bios_keyboard;
 pub mod bios_loader;
+pub mod dos_background_policy;
+pub mod dos_batch;
+pub mod dos_clock;
+pub mod dos_code_page;
+pub mod dos_command_tail;
+pub mod dos_compat_profile;
+pub mod dos_country_info;
+pub mod dos_drive_table;
+pub mod dos_environment;
 pub mod dos_event_handler;
 pub mod dos_error_codes;
+pub mod dos_file_locks;
 pub mod dos_file_system;
+pub mod dos_frame_hooks;
+pub mod dos_frame_slicing;
+pub mod dos_function_audit;
+pub mod dos_input_macros;
+pub mod dos_interrupt_hooks;
+pub mod dos_keymap;
+pub mod dos_low_memory_guard;
+pub mod dos_memory;
+pub mod dos_memory_dump;
+pub mod dos_mouse;
+pub mod dos_palette;
+pub mod dos_patch;
+pub mod dos_scrollback;
+pub mod dos_self_test;
+pub mod dos_terminal;
+pub mod dos_text_raster;
+pub mod dos_video_recording;
+pub mod dos_video_stats;
+pub mod dos_virtual_file_system;
+pub mod emulator;
 pub mod exe_loader;
+pub mod machine;
 
 // https://en.wikipedia.org/wiki/Program_Segment_Prefix
 // https://toonormal.com/2018/06/07/notes-ms-dos-dev-for-intel-8086-cpus-using-a-modern-pc/
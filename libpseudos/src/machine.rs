@@ -0,0 +1,299 @@
+// The parts of the emulated machine that the BIOS and DOS emulation use: registers, flags and
+// memory. The handler works through this rather than xachtsechs's Machine8086 directly, so it can
+// be driven by another CPU core, or by FakeMachine in tests that only check what an interrupt does.
+// Running instructions still needs a Machine8086, since its step calls the handler back with one.
+
+use xachtsechs::machine8086::Machine8086;
+use xachtsechs::types::{DataLocation8, DataLocation16, Flag, Reg, RegHalf};
+
+pub trait Machine {
+	fn get_reg_u8(&self, reg: Reg, half: RegHalf) -> u8;
+	fn set_reg_u8(&mut self, reg: Reg, half: RegHalf, value: u8);
+	fn get_reg_u16(&self, reg: Reg) -> u16;
+	fn set_reg_u16(&mut self, reg: Reg, value: u16);
+	fn get_flag(&self, flag: Flag) -> bool;
+	fn set_flag(&mut self, flag: Flag, on: bool);
+	fn memory(&self) -> &[u8];
+	fn memory_mut(&mut self) -> &mut [u8];
+	/// The number of instructions run so far, which the emulated clock is worked out from.
+	fn number_of_parsed_instructions(&self) -> usize;
+	/// Makes the next step call an interrupt before running any more of the program.
+	fn interrupt_on_next_step(&mut self, interrupt_index: u8);
+	/// Pops the flags and return address the CPU pushed when it called the interrupt.
+	fn return_from_interrupt(&mut self);
+	/// Reads and writes a location the way the CPU does, which is how the BIOS data area is
+	/// accessed.
+	fn get_data_u8(&self, location: &DataLocation8) -> u8;
+	fn set_data_u8(&mut self, location: &DataLocation8, value: u8);
+	fn get_data_u16(&self, location: &DataLocation16) -> u16;
+	fn set_data_u16(&mut self, location: &DataLocation16, value: u16);
+
+	/// The address of a segment register plus an offset register.
+	fn get_seg_reg(&self, segment_reg: Reg, offset_reg: Reg) -> u32 {
+		((self.get_reg_u16(segment_reg) as u32) << 4) + self.get_reg_u16(offset_reg) as u32
+	}
+
+	fn peek_u8(&self, addr: u32) -> u8 {
+		self.memory()[addr as usize]
+	}
+
+	fn poke_u8(&mut self, addr: u32, value: u8) {
+		self.memory_mut()[addr as usize] = value;
+	}
+
+	fn peek_u16(&self, addr: u32) -> u16 {
+		self.peek_u8(addr) as u16 | ((self.peek_u8(addr + 1) as u16) << 8)
+	}
+
+	fn poke_u16(&mut self, addr: u32, value: u16) {
+		self.poke_u8(addr, value as u8);
+		self.poke_u8(addr + 1, (value >> 8) as u8);
+	}
+
+	/// Reads the bytes from an address up to (but not including) the next NUL.
+	fn read_null_terminated_string(&self, addr: u32) -> Vec<u8> {
+		self.memory()[addr as usize ..].iter().take_while(|c| **c != 0).cloned().collect()
+	}
+
+	fn insert_contiguous_bytes(&mut self, bytes: &[u8], addr: usize) {
+		self.memory_mut()[addr .. addr + bytes.len()].copy_from_slice(bytes);
+	}
+}
+
+// Everything goes straight to the CPU's own methods, so it behaves exactly as it did before there
+// was a trait.
+impl Machine for Machine8086 {
+	fn get_reg_u8(&self, reg: Reg, half: RegHalf) -> u8 {
+		Machine8086::get_reg_u8(self, reg, half)
+	}
+
+	fn set_reg_u8(&mut self, reg: Reg, half: RegHalf, value: u8) {
+		Machine8086::set_reg_u8(self, reg, half, value)
+	}
+
+	fn get_reg_u16(&self, reg: Reg) -> u16 {
+		Machine8086::get_reg_u16(self, reg)
+	}
+
+	fn set_reg_u16(&mut self, reg: Reg, value: u16) {
+		Machine8086::set_reg_u16(self, reg, value)
+	}
+
+	fn get_flag(&self, flag: Flag) -> bool {
+		Machine8086::get_flag(self, flag)
+	}
+
+	fn set_flag(&mut self, flag: Flag, on: bool) {
+		Machine8086::set_flag(self, flag, on)
+	}
+
+	fn memory(&self) -> &[u8] {
+		&self.memory
+	}
+
+	fn memory_mut(&mut self) -> &mut [u8] {
+		&mut self.memory
+	}
+
+	fn number_of_parsed_instructions(&self) -> usize {
+		self.number_of_parsed_instructions
+	}
+
+	fn interrupt_on_next_step(&mut self, interrupt_index: u8) {
+		Machine8086::interrupt_on_next_step(self, interrupt_index)
+	}
+
+	fn return_from_interrupt(&mut self) {
+		Machine8086::return_from_interrupt(self)
+	}
+
+	fn get_seg_reg(&self, segment_reg: Reg, offset_reg: Reg) -> u32 {
+		Machine8086::get_seg_reg(self, segment_reg, offset_reg)
+	}
+
+	fn peek_u8(&self, addr: u32) -> u8 {
+		Machine8086::peek_u8(self, addr)
+	}
+
+	fn poke_u8(&mut self, addr: u32, value: u8) {
+		Machine8086::poke_u8(self, addr, value)
+	}
+
+	fn peek_u16(&self, addr: u32) -> u16 {
+		Machine8086::peek_u16(self, addr)
+	}
+
+	fn poke_u16(&mut self, addr: u32, value: u16) {
+		Machine8086::poke_u16(self, addr, value)
+	}
+
+	fn get_data_u8(&self, location: &DataLocation8) -> u8 {
+		Machine8086::get_data_u8(self, location)
+	}
+
+	fn set_data_u8(&mut self, location: &DataLocation8, value: u8) {
+		Machine8086::set_data_u8(self, location, value)
+	}
+
+	fn get_data_u16(&self, location: &DataLocation16) -> u16 {
+		Machine8086::get_data_u16(self, location)
+	}
+
+	fn set_data_u16(&mut self, location: &DataLocation16, value: u16) {
+		Machine8086::set_data_u16(self, location, value)
+	}
+
+	fn read_null_terminated_string(&self, addr: u32) -> Vec<u8> {
+		Machine8086::read_null_terminated_string(self, addr)
+	}
+
+	fn insert_contiguous_bytes(&mut self, bytes: &[u8], addr: usize) {
+		Machine8086::insert_contiguous_bytes(self, bytes, addr)
+	}
+}
+
+/// A machine with registers and memory but no CPU, for testing what the handler does with an
+/// interrupt without assembling a program. Set the registers, call the handler's
+/// dispatch_interrupt, and check the registers and memory afterwards. Interrupts it's asked to
+/// call are kept in pending_interrupts rather than run.
+#[derive(Debug, Clone)]
+pub struct FakeMachine {
+	pub memory: Vec<u8>,
+	pub number_of_parsed_instructions: usize,
+	pub pending_interrupts: Vec<u8>,
+	pub interrupt_returns: usize,
+	regs: [u16; 13],
+	flags: u16,
+}
+
+impl FakeMachine {
+	pub fn new(memory_bytes: usize) -> FakeMachine {
+		FakeMachine {
+			memory: vec![0; memory_bytes],
+			number_of_parsed_instructions: 0,
+			pending_interrupts: vec![],
+			interrupt_returns: 0,
+			regs: [0; 13],
+			flags: 0,
+		}
+	}
+
+	fn reg_index(reg: Reg) -> usize {
+		match reg {
+			Reg::AX => 0, Reg::BX => 1, Reg::CX => 2, Reg::DX => 3,
+			Reg::SI => 4, Reg::DI => 5, Reg::BP => 6, Reg::SP => 7, Reg::IP => 8,
+			Reg::CS => 9, Reg::DS => 10, Reg::ES => 11, Reg::SS => 12,
+			_ => panic!("FakeMachine has no {:?} register", reg),
+		}
+	}
+
+	// The bit of the flags register each flag is in.
+	fn flag_bit(flag: Flag) -> u16 {
+		#[allow(unreachable_patterns)]
+		match flag {
+			Flag::Carry => 1 << 0,
+			Flag::Parity => 1 << 2,
+			Flag::Adjust => 1 << 4,
+			Flag::Zero => 1 << 6,
+			Flag::Sign => 1 << 7,
+			Flag::Trap => 1 << 8,
+			Flag::Interrupt => 1 << 9,
+			Flag::Direction => 1 << 10,
+			Flag::Overflow => 1 << 11,
+			_ => panic!("FakeMachine has no {:?} flag", flag),
+		}
+	}
+}
+
+impl Machine for FakeMachine {
+	fn get_reg_u8(&self, reg: Reg, half: RegHalf) -> u8 {
+		let value = self.get_reg_u16(reg);
+		match half {
+			RegHalf::High => (value >> 8) as u8,
+			RegHalf::Low => value as u8,
+		}
+	}
+
+	fn set_reg_u8(&mut self, reg: Reg, half: RegHalf, value: u8) {
+		let old_value = self.get_reg_u16(reg);
+		let new_value = match half {
+			RegHalf::High => (old_value & 0x00ff) | ((value as u16) << 8),
+			RegHalf::Low => (old_value & 0xff00) | value as u16,
+		};
+		self.set_reg_u16(reg, new_value);
+	}
+
+	fn get_reg_u16(&self, reg: Reg) -> u16 {
+		self.regs[FakeMachine::reg_index(reg)]
+	}
+
+	fn set_reg_u16(&mut self, reg: Reg, value: u16) {
+		self.regs[FakeMachine::reg_index(reg)] = value;
+	}
+
+	fn get_flag(&self, flag: Flag) -> bool {
+		self.flags & FakeMachine::flag_bit(flag) != 0
+	}
+
+	fn set_flag(&mut self, flag: Flag, on: bool) {
+		if on {
+			self.flags |= FakeMachine::flag_bit(flag);
+		} else {
+			self.flags &= !FakeMachine::flag_bit(flag);
+		}
+	}
+
+	fn memory(&self) -> &[u8] {
+		&self.memory
+	}
+
+	fn memory_mut(&mut self) -> &mut [u8] {
+		&mut self.memory
+	}
+
+	fn number_of_parsed_instructions(&self) -> usize {
+		self.number_of_parsed_instructions
+	}
+
+	fn interrupt_on_next_step(&mut self, interrupt_index: u8) {
+		self.pending_interrupts.push(interrupt_index);
+	}
+
+	fn return_from_interrupt(&mut self) {
+		self.interrupt_returns += 1;
+	}
+
+	// Only memory locations are used for the BIOS data area, so they're all a fake machine has to
+	// support.
+	fn get_data_u8(&self, location: &DataLocation8) -> u8 {
+		#[allow(unreachable_patterns)]
+		match *location {
+			DataLocation8::MemoryAbs(addr) => self.peek_u8(addr),
+			_ => panic!("FakeMachine has no {:?} data location", location),
+		}
+	}
+
+	fn set_data_u8(&mut self, location: &DataLocation8, value: u8) {
+		#[allow(unreachable_patterns)]
+		match *location {
+			DataLocation8::MemoryAbs(addr) => self.poke_u8(addr, value),
+			_ => panic!("FakeMachine has no {:?} data location", location),
+		}
+	}
+
+	fn get_data_u16(&self, location: &DataLocation16) -> u16 {
+		#[allow(unreachable_patterns)]
+		match *location {
+			DataLocation16::MemoryAbs(addr) => self.peek_u16(addr),
+			_ => panic!("FakeMachine has no {:?} data location", location),
+		}
+	}
+
+	fn set_data_u16(&mut self, location: &DataLocation16, value: u16) {
+		#[allow(unreachable_patterns)]
+		match *location {
+			DataLocation16::MemoryAbs(addr) => self.poke_u16(addr, value),
+			_ => panic!("FakeMachine has no {:?} data location", location),
+		}
+	}
+}
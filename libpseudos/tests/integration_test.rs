@@ -0,0 +1,51 @@
+// Runs whole programs headlessly through the public API, checking what they leave on the screen
+// the way a golden-image test of a real program would.
+
+use libpseudos::dos_drive_table::DosDriveTable;
+use libpseudos::dos_event_handler::{DosEventHandler, HeadlessStartup, UnknownFuncAction};
+use libpseudos::dos_self_test::SelfTest;
+use libpseudos::emulator::{Emulator, EmulatorOptions, ProgramImage};
+use xachtsechs::machine8086::Machine8086;
+use xachtsechs::types::Reg;
+
+fn make_machine(com_data: &[u8]) -> (DosEventHandler, Machine8086) {
+	let mut options = EmulatorOptions::default();
+	options.dos.unknown_function_action = UnknownFuncAction::Panic;
+	options.dos.unknown_interrupt_action = UnknownFuncAction::Panic;
+	options.video.print_screen_dir = None;
+	let program = ProgramImage::new(com_data.to_vec(), b"C:\\TEST.COM");
+	let emulator = Emulator::new(Some(program), Box::new(DosDriveTable::new()), options).unwrap();
+	(emulator.handler, emulator.machine)
+}
+
+#[test] fn test_direct_video_write() {
+	// mov ax, 0b800h; mov ds, ax
+	// mov ax, 1f48h; mov [0], ax; ... for each letter of "HELLO", in white on blue
+	// mov ax, 4c00h; int 21h
+	let mut com_data = vec![0xb8, 0x00, 0xb8, 0x8e, 0xd8];
+	for (index, c) in b"HELLO".iter().enumerate() {
+		com_data.extend(&[0xb8, *c, 0x1f, 0xa3, index as u8 * 2, 0x00]);
+	}
+	com_data.extend(&[0xb8, 0x00, 0x4c, 0xcd, 0x21]);
+	let (mut handler, mut machine) = make_machine(&com_data);
+
+	assert_eq!(handler.run_headless(&mut machine, 1000, HeadlessStartup::Normal), Ok(0));
+	assert_eq!(machine.get_reg_u16(Reg::DS), 0xb800);
+	assert_eq!(handler.get_char_at(&machine, 0, 1), b'E');
+	assert_eq!(handler.get_attr_at(&machine, 0, 1), 0x1f);
+	// The rest of the screen is still the blanks it started with.
+	assert_eq!(handler.get_char_at(&machine, 0, 5), b' ');
+	assert_eq!(handler.get_attr_at(&machine, 0, 5), 0x07);
+	assert_eq!(handler.get_screen_unicode_text(&machine), format!("HELLO\n{}", "\n".repeat(24)));
+}
+
+#[test] fn test_self_test() {
+	// The built-in self-test goes through the loader, CPU, handler and file system together.
+	let dir_path = std::env::temp_dir().join(format!("pseudos_integration_self_test_{}", std::process::id()));
+	std::fs::remove_dir_all(&dir_path).ok();
+	std::fs::create_dir_all(&dir_path).unwrap();
+	let report = SelfTest::new(&dir_path).unwrap().run().unwrap();
+	std::fs::remove_dir_all(&dir_path).ok();
+	assert_eq!(report.failed_checks(), Vec::<&str>::new());
+	assert!(report.screen_text.contains("PASS file read\n"));
+}
@@ -0,0 +1,159 @@
+// Plays back a .ptv recording made with --record-video (see dos_video_recording.rs in libpseudos),
+// either in a window at the speed it was recorded, or by saving the screen at chosen frames as PNG
+// images and text files, for sending along with a bug report.
+//
+// Run with:
+//   cargo run --example ptv_play -- recording.ptv
+//   cargo run --example ptv_play -- recording.ptv --dump 0,100,250 [--out DIR]
+//
+// In a window, Escape or closing it quits, and the last frame stays up once the recording ends.
+// A dumped frame is the screen as it was at that frame, even if nothing changed in it.
+
+use libpseudos::dos_code_page::cp437_to_string;
+use libpseudos::dos_text_raster::{TextFont, TextRaster};
+use libpseudos::dos_video_recording::{RecordingReader, VideoFrame};
+
+use sdl2::event::Event;
+use sdl2::image::{LoadSurface, SaveSurface, INIT_PNG};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::surface::Surface;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const DOSFONT_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/res/dosfont.png");
+
+fn main() {
+	let mut args = std::env::args().skip(1);
+	let mut recording_path = None;
+	let mut dump_frames = None;
+	let mut out_dir = PathBuf::from(".");
+	while let Some(arg) = args.next() {
+		if arg == "--dump" {
+			let frames = args.next().expect("--dump needs a list of frame numbers like 0,100,250");
+			let mut frames: Vec<u32> = frames.split(',').map(|frame| frame.trim().parse().expect("--dump needs a list of frame numbers like 0,100,250")).collect();
+			frames.sort();
+			dump_frames = Some(frames);
+		} else if arg == "--out" {
+			out_dir = PathBuf::from(args.next().expect("--out needs a directory"));
+		} else {
+			recording_path = Some(arg);
+		}
+	}
+	let recording_path = recording_path.expect("Give the .ptv file to play back");
+	let result = File::open(&recording_path).map_err(|err| err.to_string())
+		.and_then(|file| RecordingReader::new(BufReader::new(file)).map_err(|err| err.to_string()))
+		.and_then(|reader| {
+			let _sdl_image = sdl2::image::init(INIT_PNG)?;
+			let font = load_font(Path::new(DOSFONT_FILE))?;
+			match dump_frames {
+				Some(frames) => dump(reader, &font, frames, &out_dir),
+				None => play(reader, &font),
+			}
+		});
+	if let Err(err) = result {
+		eprintln!("{}: {}", recording_path, err);
+		std::process::exit(1);
+	}
+}
+
+// The characters are the opaque pixels of a strip, as in the frontend.
+fn load_font(path: &Path) -> Result<TextFont, String> {
+	let surface = Surface::from_file(path)?;
+	let format = surface.pixel_format_enum();
+	let alpha_mask = format.into_masks()?.amask;
+	let pitch = surface.pitch() as usize;
+	surface.with_lock(|pixels| TextFont::from_alpha_pixels(pixels, pitch, format.byte_size_per_pixel(), alpha_mask))
+		.map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn play(mut reader: RecordingReader<BufReader<File>>, font: &TextFont) -> Result<(), String> {
+	let frame_micros = reader.frame_micros as u64;
+	let mut next_frame = reader.next_frame().map_err(|err| err.to_string())?;
+	if next_frame.is_none() {
+		return Err("The recording has no frames".to_string());
+	}
+	let mut shown = reader.screen().clone();
+	let mut raster = TextRaster::new();
+	raster.render(font, &shown.palette_transform(), &shown.text_screen(0));
+
+	let sdl_context = sdl2::init()?;
+	let window = sdl_context.video()?.window("PTV playback", raster.width as u32, raster.height as u32)
+		.position_centered()
+		.build()
+		.map_err(|err| err.to_string())?;
+	let mut canvas = window.into_canvas().software().build().map_err(|err| err.to_string())?;
+	let texture_creator = canvas.texture_creator();
+	let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, raster.width as u32, raster.height as u32).map_err(|err| err.to_string())?;
+	let mut event_pump = sdl_context.event_pump()?;
+	let start = Instant::now();
+	loop {
+		for event in event_pump.poll_iter() {
+			match event {
+				Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return Ok(()),
+				_ => {}
+			}
+		}
+		// Every frame that's due is applied, and the screen as of the last of them is shown.
+		let now_micros = start.elapsed().as_micros() as u64;
+		while let Some(frame_number) = next_frame {
+			if frame_number as u64 * frame_micros > now_micros {
+				break;
+			}
+			shown = reader.screen().clone();
+			next_frame = reader.next_frame().map_err(|err| err.to_string())?;
+		}
+		raster.render(font, &shown.palette_transform(), &shown.text_screen(now_micros / 1000));
+		texture.update(None, &raster.pixels, raster.pitch()).map_err(|err| err.to_string())?;
+		canvas.copy(&texture, None, None)?;
+		canvas.present();
+		std::thread::sleep(Duration::from_millis(10));
+	}
+}
+
+fn dump(mut reader: RecordingReader<BufReader<File>>, font: &TextFont, mut frames: Vec<u32>, out_dir: &Path) -> Result<(), String> {
+	let frame_micros = reader.frame_micros as u64;
+	// The screen as of the last frame read, which lasts until the next one.
+	let mut screen: Option<VideoFrame> = None;
+	loop {
+		let next_frame = reader.next_frame().map_err(|err| err.to_string())?;
+		while let Some(&frame_number) = frames.first() {
+			if next_frame.filter(|&next_frame| next_frame <= frame_number).is_some() {
+				break;
+			}
+			match screen {
+				Some(ref screen) => save_frame(screen, font, frame_number, frame_number as u64 * frame_micros / 1000, out_dir)?,
+				None => eprintln!("Frame {} is before the recording starts", frame_number),
+			}
+			frames.remove(0);
+		}
+		if next_frame.is_none() {
+			return Ok(());
+		}
+		screen = Some(reader.screen().clone());
+	}
+}
+
+// Saves the screen as frame-N.png and frame-N.txt in the directory.
+fn save_frame(screen: &VideoFrame, font: &TextFont, frame_number: u32, time_ms: u64, out_dir: &Path) -> Result<(), String> {
+	let mut raster = TextRaster::new();
+	raster.render(font, &screen.palette_transform(), &screen.text_screen(time_ms));
+	let png_path = out_dir.join(format!("frame-{}.png", frame_number));
+	let pitch = raster.pitch() as u32;
+	let surface = Surface::from_data(&mut raster.pixels, raster.width as u32, raster.height as u32, pitch, PixelFormatEnum::RGB24)?;
+	surface.save(&png_path)?;
+
+	let mut text = String::new();
+	for row in screen.text.chunks(screen.columns as usize * 2) {
+		let chars: Vec<u8> = row.chunks(2).map(|cell| cell[0]).collect();
+		text += cp437_to_string(&chars).trim_end();
+		text.push('\n');
+	}
+	let text_path = out_dir.join(format!("frame-{}.txt", frame_number));
+	std::fs::write(&text_path, text).map_err(|err| format!("Can't write {}: {}", text_path.display(), err))?;
+	println!("Saved frame {} to {} and {}", frame_number, png_path.display(), text_path.display());
+	Ok(())
+}
@@ -1,28 +1,173 @@
-use std::cmp::Ordering;
 
-use libpseudos::dos_event_handler::{DosEventHandler, DosInterruptResult, KeyModType, KeyPressInfo, MachineType, PortStates};
-use libpseudos::dos_file_system::StandardDosFileSystem;
-use libpseudos::exe_loader::MzHeader;
+use libpseudos::bios_keyboard::KeyboardLeds;
+use libpseudos::dos_background_policy::{BackgroundMode, BackgroundPolicy, ResumeClock};
+use libpseudos::dos_batch::{BatchInterpreter, BatchStep};
+use libpseudos::dos_event_handler::{DosEventHandler, DosInterruptResult, DosOutputMode, KeyModType, KeyPressInfo, SpeakerEvent, PIT_FREQUENCY_HZ, STANDARD_TICK_SECONDS};
+use libpseudos::dos_command_tail::build_command_tail;
+use libpseudos::dos_compat_profile::CompatProfile;
+use libpseudos::dos_drive_table::DosDriveTable;
+use libpseudos::dos_environment::{build_environment_block, parse_environment_var};
+use libpseudos::dos_file_system::{read_dos_file, DosFileAccessMode, DosFileSystem, StandardDosFileSystem, PROFILE_FILE_NAME};
+use libpseudos::dos_frame_slicing::{count_changed_cells, FrameRun, MidFramePresent, SliceEnd, OPCODES_PER_STANDARD_TICK};
+use libpseudos::dos_input_macros::MacroEngine;
+use libpseudos::dos_keymap::{Keymap, KeymapModifiers};
+use libpseudos::dos_low_memory_guard::LowMemoryProtection;
+use libpseudos::dos_memory_dump::{dump_memory, parse_memory_range};
+use libpseudos::dos_mouse::{MOUSE_VIRTUAL_HEIGHT, MOUSE_VIRTUAL_WIDTH};
+use libpseudos::dos_palette::{PalettePreset, PaletteTransform};
+use libpseudos::dos_patch::{load_patched_program_into_machine, PatchSet};
+use libpseudos::dos_self_test::SelfTest;
+#[cfg(unix)]
+use libpseudos::dos_terminal::{parse_terminal_keys, AnsiScreen, RawTerminal};
+use libpseudos::dos_text_raster::{TextFont, TextRaster, TextScreen};
+use libpseudos::dos_video_recording::{raw_frame_size, DeltaRecorder, RawFrameWriter, VideoFrame, VideoRecorder};
+use libpseudos::dos_video_stats::{video_page_name, VideoStats};
+use libpseudos::emulator::{Emulator, EmulatorOptions, ProgramImage, MAX_SPEED_MULTIPLIER, MIN_SPEED_MULTIPLIER};
+use libpseudos::exe_loader::{ExeLoadError, MzHeader};
 use xachtsechs::machine8086::Machine8086;
-use xachtsechs::types::{Reg, RegHalf, StepResult};
 
-use sdl2::image::{LoadTexture, INIT_PNG};
-use sdl2::event::Event;
+use sdl2::image::{LoadSurface, INIT_PNG};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::EventPump;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::rect::Rect;
-use sdl2::render::{WindowCanvas, Texture};
-use sdl2::audio::AudioSpecDesired;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{WindowCanvas, Texture, TextureCreator};
+use sdl2::surface::Surface;
+use sdl2::video::WindowContext;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
 
 const SCANCODE_LETTERS: &[u8] = b"qwertyuiopasdfghjklzxcvbnm";
+// Ctrl+], which quits when running in the terminal.
+#[cfg(unix)]
+const TERMINAL_QUIT_CHAR: u8 = 0x1d;
+
+// Real time between standard 18.2Hz timer ticks, which is how long each frame takes.
+const TIMER_TICK_MS: f64 = 54.9451;
+// How long a notice such as "reloaded" stays in the title.
+const NOTICE_MS: u64 = 2000;
+// The frame rate of the raw video --record-video pipes into a command.
+const RAW_VIDEO_FPS: u32 = 30;
+const DOSFONT_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/res/dosfont.png");
+// The colours the program is shown in are kept in this file in the root of the mounted directory,
+// in the form PaletteTransform::parse reads. The name starts with the file system's
+// EMULATOR_FILE_PREFIX, so programs don't see it.
+const PALETTE_FILE_NAME: &str = ".pseudos-palette";
+// Shown by --help, and after an argument that has the wrong form.
+const USAGE: &str = "\
+Usage: pseudos [OPTION]... [PROGRAM ARGUMENT]...
+
+Runs ZZT.EXE from the mounted C: drive, passing it the arguments that aren't options below. If
+the first of them is a .BAT file on the C: drive, the batch file is run instead.
+
+Options:
+  --help                       Show this text and exit.
+  --config FILE                Start from the options in a TOML config file.
+  --write-config FILE          Write the options the other arguments make to FILE, then exit.
+  --profile NAME|FILE          Use a compatibility profile (zzt or dos3) or a profile file, rather
+                               than the .pseudos-profile file or the program's built-in profile.
+  --env NAME=value             Add a variable to the program's environment.
+  --chdir PATH                 Start the program in a directory of the C: drive.
+  --batch FILE                 Run a batch file from the host before ZZT.EXE.
+  --patch FILE                 Apply the patches in a patch file. Can be given more than once.
+  --country=CODE               Use the date, time and currency formats of a country, by its
+                               dialling code.
+  --audit[=FILE.json]          Report the BIOS and DOS functions the program used when it exits.
+  --video-stats[=FILE.json]    Report how the program changed video memory when it exits.
+  --dos-output=screen|stdout|both
+                               Where text from the DOS output functions goes.
+  --background=pause|slow      Pause or slow the timer while the window is minimised.
+  --sync-clock                 Move the clock forward by the time spent minimised.
+  --persist-attributes         Keep file attributes, the volume label and the serial number
+                               between runs.
+  --volume-serial XXXX-XXXX    Set the serial number of the C: drive.
+  --floppy IMAGE               Put a 360K, 720K, 1.2M or 1.44M disk image in floppy drive 0.
+  --floppy-write-protect       Write protect the floppy disk image.
+  --slices-per-frame N         Split each frame's opcodes into N slices, with input between them.
+  --mid-frame-present=F|never  Present the screen between slices when at least the fraction F of
+                               the text page changed.
+  --refresh-rate HZ            Change how often the emulated display retraces.
+  --terminal                   Show the screen in the terminal instead of a window. Ctrl+] quits.
+  --mouse                      Give the program a mouse driver.
+  --palette NAME               Show the text colours with a preset (standard, high-contrast,
+                               deuteranopia, protanopia or monochrome). Ctrl+F10 cycles them.
+  --keymap FILE                Map host keys to the keys the program gets.
+  --macros FILE                Load input macros, which Ctrl+F1 to Ctrl+F8 start.
+  --record-video FILE|'|CMD'   Record the screen to a .ptv file, or pipe raw RGB24 frames to CMD.
+  --dump-range START-END       Limit Ctrl+F9 memory dumps to these linear addresses, in hex.
+  --protect-low-memory=off|report|restore
+                               Report writes over the interrupt vectors and BIOS data, and
+                               optionally put the emulator's vectors back.
+  --trace=vectors              Report every interrupt vector the program changes.
+  --inspect-exe FILE           Print the layout of an EXE file without running it.
+  --self-test                  Check the DOS and BIOS functions from inside the emulated machine.
+";
+
+// If the audio gets this far away from the time of the speaker events, such as when fast
+// forwarding or when the emulator can't keep up, it jumps to the events instead of drifting.
+const SPEAKER_MAX_DRIFT_CLOCKS: f64 = PIT_FREQUENCY_HZ / 4.;
+
+// Plays the PC speaker on the audio thread. The audio keeps its own PIT clock that advances in
+// real time, like emulated time does, so events play with their original timing even though the
+// runner hands them over once per frame. Between events the current tone keeps playing, so the
+// audio never starves while the program is blocked.
+struct SpeakerAudio {
+	events: Arc<Mutex<VecDeque<SpeakerEvent>>>,
+	sample_rate: f64,
+	volume: f32,
+	pit_clock: f64,
+	frequency: Option<f64>,
+	phase: f64,
+	// While silenced, the speaker events are dropped and silence is played instead.
+	silenced: bool,
+}
+
+impl AudioCallback for SpeakerAudio {
+	type Channel = f32;
+	
+	fn callback(&mut self, out: &mut [f32]) {
+		let mut events = self.events.lock().unwrap();
+		if self.silenced {
+			events.clear();
+			self.frequency = None;
+			out.iter_mut().for_each(|sample| *sample = 0.);
+			return;
+		}
+		if let Some(next_event) = events.front() {
+			if (next_event.pit_clock as f64 - self.pit_clock).abs() > SPEAKER_MAX_DRIFT_CLOCKS {
+				self.pit_clock = next_event.pit_clock as f64;
+			}
+		}
+		let clocks_per_sample = PIT_FREQUENCY_HZ / self.sample_rate;
+		for sample in out.iter_mut() {
+			while events.front().is_some_and(|event| event.pit_clock as f64 <= self.pit_clock) {
+				self.frequency = events.pop_front().unwrap().frequency;
+			}
+			*sample = match self.frequency {
+				Some(frequency) => {
+					self.phase = (self.phase + frequency / self.sample_rate) % 1.;
+					if self.phase < 0.5 { self.volume } else { -self.volume }
+				}
+				None => 0.,
+			};
+			self.pit_clock += clocks_per_sample;
+		}
+	}
+}
 
 fn scancode_to_key_info(keycode: Keycode, shifted: bool) -> Option<KeyPressInfo> {
 	// http://stanislavs.org/helppc/scan_codes.html
 	let key_index = keycode as u8;
 	let (scan_code, ascii_char, shifted_ascii_char) = match keycode {
-		_ if (b'a' ..= b'z').contains(&(keycode as u8)) => {
+		_ if (Keycode::A as i32 ..= Keycode::Z as i32).contains(&(keycode as i32)) => {
 			let lower_ascii_char = SCANCODE_LETTERS.iter().position(|c| *c == key_index).unwrap() as u8 + 0x10;
 			(lower_ascii_char, key_index, key_index + 0x20)
 		}
@@ -50,99 +195,536 @@ fn scancode_to_key_info(keycode: Keycode, shifted: bool) -> Option<KeyPressInfo>
 		Keycode::Tab => (0x0f, 0x09, 0),
 		Keycode::PageUp => (0x49, 0, 0x39),
 		Keycode::PageDown => (0x51, 0, 0x33),
-		_ if (Keycode::F1 as u8 ..= Keycode::F12 as u8).contains(&(keycode as u8)) => {
-			(0x3b + (keycode as u8 - Keycode::F1 as u8), 0, 0)
+		_ if (Keycode::F1 as i32 ..= Keycode::F12 as i32).contains(&(keycode as i32)) => {
+			(0x3b + (keycode as i32 - Keycode::F1 as i32) as u8, 0, 0)
 		}
 		_ => return None
 	};
 	Some(KeyPressInfo{scan_code, ascii_char: if shifted { shifted_ascii_char } else { ascii_char }})
 }
 
-fn get_ms_from_duration(duration: std::time::Duration) -> usize {
-	(duration.as_secs() * 1000) as usize + duration.subsec_millis() as usize
+// Sleeps until the given number of milliseconds since the epoch, unless that's already passed.
+fn sleep_until_ms(time_ms: f64) {
+	let now_ms = get_ms_from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+	if time_ms > now_ms as f64 {
+		std::thread::sleep(std::time::Duration::from_micros(((time_ms - now_ms as f64) * 1000.) as u64));
+	}
 }
 
-pub fn vga_colour_to_rgb(colour: u8) -> (u8, u8, u8) {
-	match colour {
-		0x0 => (0x00, 0x00, 0x00),
-		0x1 => (0x00, 0x00, 0xAA),
-		0x2 => (0x00, 0xAA, 0x00),
-		0x3 => (0x00, 0xAA, 0xAA),
-		0x4 => (0xAA, 0x00, 0x00),
-		0x5 => (0xAA, 0x00, 0xAA),
-		0x6 => (0xAA, 0x55, 0x00),
-		0x7 => (0xAA, 0xAA, 0xAA),
-		0x8 => (0x55, 0x55, 0x55),
-		0x9 => (0x55, 0x55, 0xFF),
-		0xA => (0x55, 0xFF, 0x55),
-		0xB => (0x55, 0xFF, 0xFF),
-		0xC => (0xFF, 0x55, 0x55),
-		0xD => (0xFF, 0x55, 0xFF),
-		0xE => (0xFF, 0xFF, 0x55),
-		0xF => (0xFF, 0xFF, 0xFF),
-		_ => (0, 0, 0)
-	}
+// Milliseconds since the epoch don't fit in 32 bits, so this is u64 even on 32-bit targets.
+fn get_ms_from_duration(duration: std::time::Duration) -> u64 {
+	duration.as_millis() as u64
+}
+
+// The text memory being displayed, which starts at the CRTC start address so programs can flip
+// between pages.
+fn displayed_text<'a>(machine: &'a Machine8086, handler: &DosEventHandler) -> &'a [u8] {
+	let start_address = handler.display_start_address() as usize;
+	&machine.memory[start_address .. start_address + 0x1000]
+}
+
+// ZZT.EXE, which is loaded once a --batch file finishes.
+struct MainProgram {
+	exe_data: Vec<u8>,
+	command_line_tail: Vec<u8>,
 }
 
 struct DosConsole {
-	machine: Machine8086,
-	dos_event_handler: DosEventHandler,
-	current_run_time_ms: usize,
+	// The machine, its BIOS and DOS, and the options they were made with. The frame slicing, window
+	// scale and audio options are used by the runner.
+	emulator: Emulator,
+	current_run_time_ms: u64,
+	// Fractional timer interrupts carried over to the next frame when the PIT has been programmed
+	// with a rate that isn't a whole multiple of the standard tick.
+	pending_timer_interrupts: f64,
+	// How fast emulated time passes relative to real time. Changed with Ctrl+Minus/Ctrl+Equals.
+	speed_multiplier: f64,
+	// While the backquote key is held, frames run as fast as possible.
+	fast_forward: bool,
+	// What to do while the window is minimised.
+	background_policy: BackgroundPolicy,
+	// The batch file that runs programs one after another, if one was given.
+	batch: Option<BatchInterpreter>,
+	// While the batch file is at a PAUSE, it's given the keys instead of the machine running.
+	batch_waiting_for_key: bool,
+	// The program that runs once a --batch file finishes.
+	main_program: Option<MainProgram>,
+	// The text memory as it was when the screen was last presented. It's compared against the whole
+	// text page to find changes, so writes through any segment or instruction are seen (see
+	// count_changed_cells).
+	presented_text: Vec<u8>,
+	// The patches given with --patch. Their frozen values are written again before every frame.
+	patches: PatchSet,
+	// The macros given with --macros, started with Ctrl+F1 to Ctrl+F8 in the order they're defined.
+	macro_engine: MacroEngine,
+	// Whether the video memory changed in the last frame is shown in the title, toggled with
+	// Ctrl+F11.
+	show_video_stats: bool,
+	// The keyboard LEDs shown in the title, so it can be updated when the program changes them.
+	shown_leds: KeyboardLeds,
+	// The linear addresses a memory dump saves, from --dump-range, or None for all of memory.
+	memory_dump_range: Option<Range<u32>>,
+	// The keys from --keymap, which are used before the built-in table in scancode_to_key_info.
+	keymap: Keymap,
+	// The program as it was first loaded, for Ctrl+Shift+F6 to start it again. Programs run by a
+	// batch file can't be restarted.
+	restart_program: Option<ProgramImage>,
+	// Set by Ctrl+Shift+F6, so the restart happens between frames.
+	restart_requested: bool,
+	// A short message shown in the title, and the run time it's shown until.
+	notice: Option<(&'static str, u64)>,
+	// Where --record-video sends the screen at the end of every frame.
+	video_recording: Option<VideoRecording>,
 }
 
-impl DosConsole {
-	fn draw_screen(&mut self, canvas: &mut WindowCanvas, dosfont_tex: &mut Texture, redraw_all: bool) {
-		let screen_mem = &self.machine.memory[0xb8000..0xb8000+0x1000];
-		let screen_width = 80;
-		let screen_height = 25;
-		for y in 0 .. screen_height {
-			for x in 0 .. screen_width {
-				let char_index = (x + (y * screen_width)) * 2;
-				let ref char_code = screen_mem[char_index];
-				let ref colour = screen_mem[char_index + 1];
-				let colour_fore = colour & 0x0f;
-				let mut colour_back = (colour & 0xf0) >> 4;
-				
-				let mut blinking = false;
-				
-				if colour_back >= 8 {
-					colour_back -= 8;
-					blinking = true;
-				}
-				
-				let fore_rgb = vga_colour_to_rgb(colour_fore);
-				let back_rgb = vga_colour_to_rgb(colour_back);
+// A --record-video recording, which is either a .ptv file or raw frames piped into a command.
+// The command is waited for when the recording is dropped, so it can finish writing its output.
+struct VideoRecording {
+	recorder: Option<Box<dyn VideoRecorder>>,
+	command: Option<Child>,
+}
 
-				let char_rect = Rect::new(8 * (*char_code as i32), 0, 8, 14);
-				let dest_rect = Rect::new(8 * (x as i32), 14 * (y as i32), 8, 14);
+impl VideoRecording {
+	// Starts recording to a file, or to a command when the target starts with "|". Raw frames are
+	// the size of the screen now.
+	fn open(target: &str, handler: &DosEventHandler) -> Result<VideoRecording, String> {
+		let frame_micros = (STANDARD_TICK_SECONDS * 1_000_000.).round() as u32;
+		if let Some(command_line) = target.strip_prefix('|') {
+			let shell = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+			let mut command = Command::new(shell.0).arg(shell.1).arg(command_line).stdin(Stdio::piped()).spawn()
+				.map_err(|err| format!("Can't run {}: {}", command_line, err))?;
+			let stdin = command.stdin.take().unwrap();
+			let font = load_font(Path::new(DOSFONT_FILE))?;
+			let (columns, rows) = handler.video_mode.text_dims();
+			let (width, height) = raw_frame_size(columns as usize, rows as usize, handler.video_mode.char_pixel_dims().1 as usize);
+			eprintln!("Recording {}x{} RGB24 frames at {} fps (for ffmpeg: -f rawvideo -pixel_format rgb24 -video_size {}x{} -framerate {} -i -)", width, height, RAW_VIDEO_FPS, width, height, RAW_VIDEO_FPS);
+			Ok(VideoRecording {
+				recorder: Some(Box::new(RawFrameWriter::new(stdin, font, frame_micros, RAW_VIDEO_FPS))),
+				command: Some(command),
+			})
+		} else {
+			let file = std::fs::File::create(target).map_err(|err| format!("Can't create {}: {}", target, err))?;
+			let recorder = DeltaRecorder::new(std::io::BufWriter::new(file), frame_micros).map_err(|err| format!("Can't write to {}: {}", target, err))?;
+			Ok(VideoRecording { recorder: Some(Box::new(recorder)), command: None })
+		}
+	}
+}
 
-				// Draw the character background:
-				canvas.set_draw_color(sdl2::pixels::Color::RGB(back_rgb.0, back_rgb.1, back_rgb.2));
-				canvas.fill_rect(dest_rect).ok();
+impl Drop for VideoRecording {
+	fn drop(&mut self) {
+		if let Some(mut recorder) = self.recorder.take() {
+			if let Err(err) = recorder.flush() {
+				eprintln!("Couldn't finish the video recording: {}", err);
+			}
+		}
+		// The recorder had the command's input, so it's been closed and the command can finish.
+		if let Some(mut command) = self.command.take() {
+			command.wait().ok();
+		}
+	}
+}
 
-				if !blinking || self.current_run_time_ms % 450 < 225 {
-					// Draw the character foreground:
-					dosfont_tex.set_color_mod(fore_rgb.0, fore_rgb.1, fore_rgb.2);
-					canvas.copy(&dosfont_tex, Some(char_rect), Some(dest_rect)).expect("Render failed");
+impl DosConsole {
+	fn draw_screen(&mut self, canvas: &mut WindowCanvas, screen_image: &mut ScreenImage) {
+		let screen_mem = self.emulator.handler.get_composed_text(&self.emulator.machine);
+		// The cursor is drawn over its scanlines in the character cell.
+		let cursor = match self.emulator.handler.get_cursor_scanlines(&self.emulator.machine) {
+			Some((start_line, end_line)) if self.current_run_time_ms % 534 < 267 => {
+				let (cursor_x, cursor_y) = self.emulator.handler.get_cursor_position(&self.emulator.machine);
+				Some((cursor_x as usize, cursor_y as usize, start_line, end_line))
+			}
+			_ => None,
+		};
+		let screen = TextScreen {
+			text: &screen_mem,
+			columns: 80,
+			rows: 25,
+			char_height: self.emulator.handler.video_mode.char_pixel_dims().1 as usize,
+			blink_enabled: self.emulator.handler.blink_enabled,
+			blink_visible: self.current_run_time_ms % 450 < 225,
+			cursor,
+		};
+		screen_image.raster.render(&screen_image.font, &self.emulator.options.video.palette, &screen);
+		// The letterbox around the screen is the border colour, which clearing fills in whatever
+		// the viewport is.
+		let (r, g, b) = self.emulator.options.video.palette.ega_colour_to_rgb(self.emulator.handler.border_colour());
+		canvas.set_draw_color(sdl2::pixels::Color::RGB(r, g, b));
+		canvas.clear();
+		screen_image.present(canvas);
+	}
+	
+	// Gets ready to run the next frame, which takes one standard timer tick of real time. At other
+	// speeds, the frame's opcodes and emulated time are scaled by the speed multiplier, so the
+	// program runs that many standard ticks in the time of one.
+	fn start_frame(&mut self) -> FrameRun {
+		self.emulator.handler.seconds_since_start += STANDARD_TICK_SECONDS * self.speed_multiplier;
+		
+		// The program may have sped up the timer, in which case the opcodes are split up between
+		// the extra timer interrupts.
+		self.pending_timer_interrupts += self.emulator.handler.timer_interrupts_per_standard_tick() * self.speed_multiplier;
+		let timer_interrupt_count = self.pending_timer_interrupts as usize;
+		self.pending_timer_interrupts -= timer_interrupt_count as f64;
+		
+		self.patches.apply_every_frame(&mut self.emulator.machine);
+		self.macro_engine.advance_frame(&mut self.emulator.handler.key_press_queue);
+		let opcode_count = (OPCODES_PER_STANDARD_TICK as f64 * self.speed_multiplier).round() as usize;
+		FrameRun::new(opcode_count, timer_interrupt_count, self.emulator.options.cpu.frame_slicing.slices_per_frame)
+	}
+	
+	// Carries on with the batch file after its program exits, or while it's waiting for a key.
+	// Returns false once there's nothing left to run.
+	fn continue_batch(&mut self) -> bool {
+		let batch = match self.batch {
+			Some(ref mut batch) => batch,
+			None => return false,
+		};
+		match batch.step(&mut self.emulator.handler, &mut self.emulator.machine) {
+			Ok(BatchStep::RunProgram) => {
+				self.batch_waiting_for_key = false;
+				true
+			}
+			Ok(BatchStep::WaitForKey) => {
+				self.batch_waiting_for_key = true;
+				true
+			}
+			Ok(BatchStep::Finished) => {
+				let main_program = match self.main_program.take() {
+					Some(main_program) => main_program,
+					None => return false,
+				};
+				// The program gets the environment the batch file left behind.
+				let environment_block = build_environment_block(batch.environment_vars(), &self.emulator.options.dos.program_dos_path());
+				match load_patched_program_into_machine(&mut self.emulator.machine, &main_program.exe_data, &main_program.command_line_tail, &environment_block, self.emulator.handler.file_system(), &self.patches) {
+					Ok(()) => {
+						self.emulator.handler.result = DosInterruptResult::ShouldReturn;
+						self.batch_waiting_for_key = false;
+						true
+					}
+					Err(err) => {
+						eprintln!("Failed to load {}: {}", self.emulator.options.dos.program_name, err);
+						false
+					}
 				}
 			}
+			Err(err) => {
+				eprintln!("{}", err);
+				false
+			}
 		}
 	}
 	
 	fn update_keymod(&mut self, keymod: sdl2::keyboard::Mod) {
-		self.dos_event_handler.set_key_mod(KeyModType::Shift, keymod.contains(sdl2::keyboard::LSHIFTMOD) || keymod.contains(sdl2::keyboard::RSHIFTMOD));
-		self.dos_event_handler.set_key_mod(KeyModType::Ctrl, keymod.contains(sdl2::keyboard::LCTRLMOD) || keymod.contains(sdl2::keyboard::RCTRLMOD));
-		self.dos_event_handler.set_key_mod(KeyModType::Alt, keymod.contains(sdl2::keyboard::LALTMOD) || keymod.contains(sdl2::keyboard::RALTMOD));
+		self.emulator.handler.set_key_mod(&mut self.emulator.machine, KeyModType::RightShift, keymod.contains(sdl2::keyboard::RSHIFTMOD));
+		self.emulator.handler.set_key_mod(&mut self.emulator.machine, KeyModType::LeftShift, keymod.contains(sdl2::keyboard::LSHIFTMOD));
+		self.emulator.handler.set_key_mod(&mut self.emulator.machine, KeyModType::Ctrl, keymod.contains(sdl2::keyboard::LCTRLMOD) || keymod.contains(sdl2::keyboard::RCTRLMOD));
+		self.emulator.handler.set_key_mod(&mut self.emulator.machine, KeyModType::Alt, keymod.contains(sdl2::keyboard::LALTMOD) || keymod.contains(sdl2::keyboard::RALTMOD));
+		self.emulator.handler.set_key_mod(&mut self.emulator.machine, KeyModType::NumLock, keymod.contains(sdl2::keyboard::NUMMOD));
+		self.emulator.handler.set_key_mod(&mut self.emulator.machine, KeyModType::CapsLock, keymod.contains(sdl2::keyboard::CAPSMOD));
+	}
+	
+	// Gets the key the --keymap file maps a host key to, with the modifiers that are held.
+	fn map_key(&self, keycode: Keycode, keymod: sdl2::keyboard::Mod) -> Option<KeyPressInfo> {
+		if self.keymap.entries.is_empty() {
+			return None;
+		}
+		let modifiers = KeymapModifiers {
+			shift: keymod.intersects(sdl2::keyboard::LSHIFTMOD | sdl2::keyboard::RSHIFTMOD),
+			ctrl: keymod.intersects(sdl2::keyboard::LCTRLMOD | sdl2::keyboard::RCTRLMOD),
+			alt: keymod.intersects(sdl2::keyboard::LALTMOD | sdl2::keyboard::RALTMOD),
+		};
+		self.keymap.lookup(&keycode.name(), modifiers)
+	}
+	
+	fn update_title(&self, canvas: &mut WindowCanvas) {
+		let mut title = if self.fast_forward {
+			"PseuDOS (fast forward)".to_string()
+		} else if self.speed_multiplier != 1. {
+			format!("PseuDOS ({}x speed)", self.speed_multiplier)
+		} else {
+			"PseuDOS".to_string()
+		};
+		let active_macro_names = self.macro_engine.active_macro_names();
+		if !active_macro_names.is_empty() {
+			title += &format!(" [{}]", active_macro_names.join(", "));
+		}
+		if let (true, Some(video_stats)) = (self.show_video_stats, self.emulator.handler.video_stats.as_ref()) {
+			// The page that changed the most, which is where the program is drawing.
+			let last_frame_page_bytes = video_stats.last_frame_page_bytes();
+			let busiest_page = (0 .. last_frame_page_bytes.len()).max_by_key(|page_index| last_frame_page_bytes[*page_index]).unwrap();
+			if last_frame_page_bytes[busiest_page] == 0 {
+				title += " [video: no changes]";
+			} else {
+				title += &format!(" [video: {} bytes/frame to {}]", last_frame_page_bytes.iter().sum::<u64>(), video_page_name(busiest_page));
+			}
+		}
+		if let Some((notice, _)) = self.notice {
+			title += &format!(" [{}]", notice);
+		}
+		let leds = self.shown_leds;
+		let lit_leds: Vec<&str> = [(leds.caps_lock, "Caps"), (leds.num_lock, "Num"), (leds.scroll_lock, "Scroll")].iter()
+			.filter(|(on, _)| *on).map(|(_, name)| *name).collect();
+		if !lit_leds.is_empty() {
+			title += &format!(" [{}]", lit_leds.join(" "));
+		}
+		canvas.window_mut().set_title(&title).ok();
+	}
+	
+	// Moves on to the next palette preset, and saves it so the program starts with it next time.
+	fn cycle_palette(&mut self) {
+		let palette = &mut self.emulator.options.video.palette;
+		palette.cycle_preset();
+		println!("Palette: {}", palette.preset.name());
+		let palette_path = self.emulator.options.dos.host_directory.join(PALETTE_FILE_NAME);
+		if let Err(err) = std::fs::write(&palette_path, palette.to_config_text()) {
+			eprintln!("Failed to save the palette to {}: {}", palette_path.display(), err);
+		}
+	}
+	
+	// Shows a message in the title for a couple of seconds.
+	fn show_notice(&mut self, notice: &'static str) {
+		self.notice = Some((notice, self.current_run_time_ms + NOTICE_MS));
+	}
+	
+	// Starts the program again from scratch for Ctrl+Shift+F6, keeping the window, the sound and
+	// the mounted drives, so changes to its data files can be tried quickly. If the restart fails,
+	// the program carries on running as it was.
+	fn soft_restart(&mut self) {
+		let program = match self.restart_program.clone() {
+			Some(program) => program,
+			None => {
+				eprintln!("Programs run by a batch file can't be restarted");
+				return;
+			}
+		};
+		if let Err(err) = self.emulator.restart(Some(program)) {
+			eprintln!("Couldn't restart: {}", err);
+			return;
+		}
+		self.pending_timer_interrupts = 0.;
+		self.presented_text.iter_mut().for_each(|byte| *byte = 0);
+		self.show_notice("reloaded");
+	}
+	
+	// Adds the screen to the --record-video recording at the end of a frame. The recording stops if
+	// it can't be written to.
+	fn record_video_frame(&mut self) {
+		let recorder = match self.video_recording.as_mut().and_then(|recording| recording.recorder.as_mut()) {
+			Some(recorder) => recorder,
+			None => return,
+		};
+		let frame = VideoFrame::capture(&self.emulator.handler, &self.emulator.machine, &self.emulator.options.video.palette);
+		if let Err(err) = recorder.record(&frame) {
+			eprintln!("Stopped recording video: {}", err);
+			self.video_recording = None;
+		}
+	}
+	
+	// Saves the memory and registers to files in the working directory (see dos_memory_dump), for
+	// Ctrl+F9 and frame hooks that ask for it.
+	fn save_memory_dump(&self) {
+		match dump_memory(&self.emulator.machine, Path::new("."), self.memory_dump_range.clone()) {
+			Ok(path) => println!("Saved the memory to {}", path.display()),
+			Err(err) => eprintln!("{}", err),
+		}
+	}
+	
+	// Lists the files the program has open, for tracking down leaked handles and saves going to
+	// the wrong place.
+	fn print_open_handles(&self) {
+		let open_handles = self.emulator.handler.file_system.open_handles();
+		if open_handles.is_empty() {
+			println!("No files are open");
+		}
+		for open_handle in open_handles {
+			let access = match open_handle.access_mode {
+				DosFileAccessMode::ReadOnly => "read",
+				DosFileAccessMode::WriteOnly => "write",
+				DosFileAccessMode::ReadWrite => "read/write",
+			};
+			println!("Handle {}: {} is {} ({})", open_handle.handle, String::from_utf8_lossy(&open_handle.dos_path), open_handle.real_path.display(), access);
+		}
+	}
+	
+	/// Handles emulator hotkeys, returning true if the key shouldn't be passed on to the program.
+	fn handle_hotkey(&mut self, keycode: Keycode, keymod: sdl2::keyboard::Mod, pressed: bool) -> bool {
+		let ctrl = keymod.contains(sdl2::keyboard::LCTRLMOD) || keymod.contains(sdl2::keyboard::RCTRLMOD);
+		let shift = keymod.contains(sdl2::keyboard::LSHIFTMOD) || keymod.contains(sdl2::keyboard::RSHIFTMOD);
+		match keycode {
+			Keycode::F5 if ctrl && shift => {
+				if pressed {
+					self.emulator.handler.refresh_file_system();
+					self.show_notice("files reloaded");
+				}
+				true
+			}
+			Keycode::F6 if ctrl && shift => {
+				if pressed {
+					self.restart_requested = true;
+				}
+				true
+			}
+			Keycode::Minus if ctrl => {
+				if pressed {
+					self.speed_multiplier = (self.speed_multiplier / 2.).max(MIN_SPEED_MULTIPLIER);
+				}
+				true
+			}
+			Keycode::Equals if ctrl => {
+				if pressed {
+					self.speed_multiplier = (self.speed_multiplier * 2.).min(MAX_SPEED_MULTIPLIER);
+				}
+				true
+			}
+			Keycode::Backquote => {
+				self.fast_forward = pressed;
+				true
+			}
+			Keycode::PrintScreen => {
+				// Like the BIOS keyboard handler, the key raises int 05h, which saves the screen's
+				// text unless the program has hooked it.
+				if pressed {
+					self.emulator.machine.interrupt_on_next_step(0x05);
+				}
+				true
+			}
+			Keycode::F11 if ctrl => {
+				// The stats are started the first time they're shown, if --video-stats didn't start
+				// them already.
+				if pressed {
+					self.show_video_stats = !self.show_video_stats;
+					if self.show_video_stats && self.emulator.handler.video_stats.is_none() {
+						self.emulator.handler.video_stats = Some(VideoStats::new(&self.emulator.machine));
+					}
+				}
+				true
+			}
+			Keycode::F12 if ctrl => {
+				if pressed {
+					self.print_open_handles();
+				}
+				true
+			}
+			Keycode::F9 if ctrl => {
+				if pressed {
+					self.save_memory_dump();
+				}
+				true
+			}
+			Keycode::F10 if ctrl => {
+				if pressed {
+					self.cycle_palette();
+				}
+				true
+			}
+			_ if ctrl && (Keycode::F1 as i32 ..= Keycode::F8 as i32).contains(&(keycode as i32)) => {
+				let macro_index = (keycode as i32 - Keycode::F1 as i32) as usize;
+				if macro_index >= self.macro_engine.macros.len() {
+					return false;
+				}
+				if pressed {
+					self.macro_engine.start(macro_index);
+				} else {
+					self.macro_engine.stop(macro_index);
+				}
+				true
+			}
+			_ => false
+		}
+	}
+	
+	// Moves the mouse driver's cursor to a point in the window, scaled from the viewport the screen
+	// is drawn in to the driver's virtual screen. Points in the border are kept to its edge.
+	fn move_mouse(&mut self, canvas: &WindowCanvas, x: i32, y: i32) {
+		if let Some(ref mut mouse) = self.emulator.handler.mouse {
+			let viewport = canvas.viewport();
+			let virtual_x = (x - viewport.x()).max(0) as u32 * MOUSE_VIRTUAL_WIDTH as u32 / viewport.width().max(1);
+			let virtual_y = (y - viewport.y()).max(0) as u32 * MOUSE_VIRTUAL_HEIGHT as u32 / viewport.height().max(1);
+			mouse.set_position(virtual_x.min(0xffff) as u16, virtual_y.min(0xffff) as u16);
+		}
+	}
+	
+	// Takes the window and keyboard events that came in since the last call. Returns false once the
+	// window has been closed.
+	fn handle_events(&mut self, event_pump: &mut EventPump, canvas: &mut WindowCanvas, screen_image: &mut ScreenImage, speaker_device: &mut AudioDevice<SpeakerAudio>, next_frame_time_ms: &mut f64) -> bool {
+		let mut running = true;
+		for event in event_pump.poll_iter() {
+			match event {
+				Event::Quit{..} => {
+					running = false;
+				}
+				Event::Window{win_event: WindowEvent::Minimized, ..} | Event::Window{win_event: WindowEvent::Hidden, ..} => {
+					self.background_policy.set_in_background(true);
+					// Paused programs don't make any sound, so don't keep playing the last tone.
+					speaker_device.lock().silenced = self.background_policy.mode == BackgroundMode::Pause;
+				}
+				Event::Window{win_event: WindowEvent::Restored, ..} | Event::Window{win_event: WindowEvent::Shown, ..} => {
+					let skipped_ticks = self.background_policy.set_in_background(false);
+					self.emulator.handler.skip_standard_ticks(&mut self.emulator.machine, skipped_ticks);
+					speaker_device.lock().silenced = false;
+					// Don't rush to catch up on the frames that were skipped.
+					*next_frame_time_ms = get_ms_from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap()) as f64;
+					self.draw_screen(canvas, screen_image);
+				}
+				Event::Window{..} => {
+					self.draw_screen(canvas, screen_image);
+				}
+				Event::KeyDown{keycode: keycode_opt, keymod, repeat, ..} => {
+					self.update_keymod(keymod);
+					let shifted = keymod.contains(sdl2::keyboard::LSHIFTMOD) || keymod.contains(sdl2::keyboard::RSHIFTMOD);
+					if let Some(keycode) = keycode_opt {
+						// SDL doesn't track these toggles, so flip them on each press.
+						let toggle_mod = match keycode {
+							Keycode::ScrollLock => Some(KeyModType::ScrollLock),
+							Keycode::Insert => Some(KeyModType::Insert),
+							_ => None,
+						};
+						if let (Some(toggle_mod), false) = (toggle_mod, repeat) {
+							let was_on = self.emulator.handler.get_key_mod(&self.emulator.machine, toggle_mod);
+							self.emulator.handler.set_key_mod(&mut self.emulator.machine, toggle_mod, !was_on);
+						}
+						if self.handle_hotkey(keycode, keymod, true) {
+							self.update_title(canvas);
+						} else if let Some(key_info) = self.map_key(keycode, keymod).or_else(|| scancode_to_key_info(keycode, shifted)) {
+							self.emulator.handler.key_press_queue.push_back(key_info);
+							if self.macro_engine.real_key_pressed() {
+								self.update_title(canvas);
+							}
+						}
+					}
+				}
+				Event::KeyUp{keycode: Some(keycode), keymod, ..} if self.handle_hotkey(keycode, keymod, false) => {
+					self.update_title(canvas);
+				}
+				Event::MouseMotion{x, y, ..} => {
+					self.move_mouse(canvas, x, y);
+				}
+				Event::MouseButtonDown{mouse_btn, x, y, ..} | Event::MouseButtonUp{mouse_btn, x, y, ..} => {
+					self.move_mouse(canvas, x, y);
+					let button_bit = match mouse_btn {
+						MouseButton::Left => 0b01,
+						MouseButton::Right => 0b10,
+						_ => 0,
+					};
+					if let Some(ref mut mouse) = self.emulator.handler.mouse {
+						if let Event::MouseButtonDown{..} = event {
+							mouse.buttons |= button_bit;
+						} else {
+							mouse.buttons &= !button_bit;
+						}
+					}
+				}
+				_ => {}
+			}
+		}
+		running
 	}
 	
 	fn run(&mut self) {
-		let mut step_count = 0;
-
 		//
 		// Init SDL2.
 		//
 		
-		let scale = 2;
+		let scale = self.emulator.options.video.window_scale;
 
 		let sdl_context = sdl2::init().unwrap();
 
@@ -150,12 +732,11 @@ impl DosConsole {
 		// Init video.
 		//
 
-		let render_width = 640;
-		let render_height = 350;
+		let mut render_dims = self.emulator.handler.video_mode.pixel_dims();
 		
 		let sdl_video = sdl_context.video().unwrap();
 		let _sdl_image = sdl2::image::init(INIT_PNG).unwrap();
-		let window = sdl_video.window("PseuDOS", render_width * scale, render_height * scale)
+		let window = sdl_video.window("PseuDOS", render_dims.0 * scale, render_dims.1 * scale)
 			.position_centered()
 			//.fullscreen_desktop()
 			.build()
@@ -166,135 +747,698 @@ impl DosConsole {
 		let mut canvas = window.into_canvas().software().build().unwrap();
 		let texture_creator = canvas.texture_creator();
 
-		let dosfont_file = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/res/dosfont.png"));
-		let mut dosfont_tex = texture_creator.load_texture(dosfont_file).unwrap();
+		let mut screen_image = ScreenImage::new(load_font(Path::new(DOSFONT_FILE)).unwrap(), &texture_creator, render_dims);
 
 		let mut running = true;
 
-		canvas.set_scale(scale as f32, scale as f32).ok();
-		canvas.set_viewport(Rect::new(((window_width / scale) as i32 / 2 - render_width as i32 / 2) as i32, ((window_height / scale) as i32 / 2 - render_height as i32 / 2) as i32, render_width, render_height));
+		//
+		// Init audio.
+		//
+		
+		let speaker_events = Arc::new(Mutex::new(VecDeque::new()));
+		let sdl_audio = sdl_context.audio().unwrap();
+		let speaker_spec = AudioSpecDesired {
+			freq: Some(self.emulator.options.audio.sample_rate),
+			channels: Some(1),
+			samples: Some(1024),
+		};
+		let mut speaker_device = sdl_audio.open_playback(None, &speaker_spec, |spec| {
+			SpeakerAudio {
+				events: speaker_events.clone(),
+				sample_rate: spec.freq as f64,
+				volume: self.emulator.options.audio.volume,
+				pit_clock: 0.,
+				frequency: None,
+				phase: 0.,
+				silenced: false,
+			}
+		}).unwrap();
+		speaker_device.resume();
+
+		set_viewport(&mut canvas, window_width, window_height, render_dims, scale);
 
 		//sdl_context.mouse().show_cursor(false);
 
 		let start_time_ms = get_ms_from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
-		let mut last_time_ms = start_time_ms;
+		let mut next_frame_time_ms = start_time_ms as f64;
 
-		self.draw_screen(&mut canvas, &mut dosfont_tex, true);
+		self.draw_screen(&mut canvas, &mut screen_image);
 
+		let mut event_pump = sdl_context.event_pump().unwrap();
+		let mut screenshot_requested = false;
 		while running {
-			for event in sdl_context.event_pump().unwrap().poll_iter() {
-				match event {
-					Event::Quit{..} => {
-						running = false;
-					}
-					Event::Window{..} => {
-						self.draw_screen(&mut canvas, &mut dosfont_tex, true);
-					}
-					Event::KeyDown{keycode: keycode_opt, keymod, ..} => {
-						self.update_keymod(keymod);
-						let shifted = keymod.contains(sdl2::keyboard::LSHIFTMOD) || keymod.contains(sdl2::keyboard::RSHIFTMOD);
-						if let Some(keycode) = keycode_opt {
-							if let Some(key_info) = scancode_to_key_info(keycode, shifted) {
-								self.dos_event_handler.key_press_queue.push_back(key_info);
-							}
-						}
-					}
-					_ => {}
-				}
+			running = self.handle_events(&mut event_pump, &mut canvas, &mut screen_image, &mut speaker_device, &mut next_frame_time_ms);
+			if self.restart_requested {
+				self.restart_requested = false;
+				self.soft_restart();
+				self.update_title(&mut canvas);
 			}
+			let frame_start_ms = next_frame_time_ms;
 			
-			self.machine.interrupt_on_next_step(0x08);
-			self.dos_event_handler.seconds_since_start += 54.9451/1000.;
-			self.dos_event_handler.set_cga_vertial_retrace(true);
-			
-			let num_opcodes_to_exec = 4000;
-			for _ in 0..num_opcodes_to_exec {
-				match self.machine.step(&mut self.dos_event_handler) {
-					Ok(StepResult::Interrupt) => {
-						match self.dos_event_handler.result {
-							DosInterruptResult::ShouldReturn => {
-								self.machine.return_from_interrupt();
+			// In the background, the policy decides whether this tick runs.
+			if self.batch_waiting_for_key {
+				if !self.continue_batch() {
+					return;
+				}
+			} else if self.background_policy.should_run_tick() {
+				// The frame runs in slices spread over the frame's time, with input taken between
+				// them, so keys reach the program without waiting for the next frame.
+				let macros_were_active = !self.macro_engine.active_macro_names().is_empty();
+				let mut frame_run = self.start_frame();
+				if macros_were_active && self.macro_engine.active_macro_names().is_empty() {
+					self.update_title(&mut canvas);
+				}
+				let mut mid_frame_presents = 0;
+				loop {
+					match frame_run.run_slice(&mut self.emulator.machine, &mut self.emulator.handler) {
+						Ok(SliceEnd::Slice) => {
+							let changed_cells = count_changed_cells(&self.presented_text, displayed_text(&self.emulator.machine, &self.emulator.handler));
+							if !self.background_policy.is_in_background() && self.emulator.options.cpu.frame_slicing.should_present_mid_frame(changed_cells, 80 * 25, mid_frame_presents) {
+								self.draw_screen(&mut canvas, &mut screen_image);
+								canvas.present();
+								self.presented_text.copy_from_slice(displayed_text(&self.emulator.machine, &self.emulator.handler));
+								mid_frame_presents += 1;
 							}
-							DosInterruptResult::ShouldReturnAndWaitForEvents => {
-								self.machine.return_from_interrupt();
-								break;
+							if !self.fast_forward {
+								sleep_until_ms(frame_start_ms + TIMER_TICK_MS * frame_run.finished_slices() as f64 / frame_run.slice_count() as f64);
 							}
-							DosInterruptResult::ShouldBlockForKeypress => {
+							if !self.handle_events(&mut event_pump, &mut canvas, &mut screen_image, &mut speaker_device, &mut next_frame_time_ms) {
+								running = false;
 								break;
 							}
 						}
+						Ok(SliceEnd::Frame) => break,
+						Ok(SliceEnd::Exited(exit_code)) => {
+							println!("Program exited with code {}", exit_code);
+							if !self.continue_batch() {
+								return;
+							}
+							break;
+						}
+						Err(err) => {
+							eprintln!("Step error: {}", err);
+							return;
+						}
 					}
-					Err(err) => {
-						eprintln!("Step error: {}", err);
-						return;
+				}
+				
+				self.record_video_frame();
+				let leds = self.emulator.handler.keyboard_leds(&self.emulator.machine);
+				let notice_expired = self.notice.is_some_and(|(_, until_ms)| self.current_run_time_ms >= until_ms);
+				if notice_expired {
+					self.notice = None;
+				}
+				if self.show_video_stats || leds != self.shown_leds || notice_expired {
+					self.shown_leds = leds;
+					self.update_title(&mut canvas);
+				}
+			}
+			
+			speaker_events.lock().unwrap().extend(self.emulator.handler.speaker_events.drain(..));
+			
+			// There's no point drawing frames nobody can see.
+			if !self.background_policy.is_in_background() {
+				// The program can change the vertical resolution, in which case the window follows.
+				if self.emulator.handler.video_mode.pixel_dims() != render_dims {
+					render_dims = self.emulator.handler.video_mode.pixel_dims();
+					canvas.window_mut().set_size(render_dims.0 * scale, render_dims.1 * scale).ok();
+					set_viewport(&mut canvas, render_dims.0 * scale, render_dims.1 * scale, render_dims, scale);
+					screen_image.resize(render_dims);
+				}
+				self.draw_screen(&mut canvas, &mut screen_image);
+
+				// Each frame takes a timer tick, so blinking and notices keep to real time at any speed.
+				self.current_run_time_ms += TIMER_TICK_MS as u64;
+				canvas.present();
+				self.presented_text.copy_from_slice(displayed_text(&self.emulator.machine, &self.emulator.handler));
+				
+				if screenshot_requested {
+					screenshot_requested = false;
+					// The screenshot is the image that was uploaded, at its own size rather than the window's.
+					let path = format!("pseudos-{}.bmp", get_ms_from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap()));
+					match screen_image.save_bmp(&path) {
+						Ok(()) => println!("Saved a screenshot to {}", path),
+						Err(err) => eprintln!("Couldn't save a screenshot: {}", err),
 					}
-					_ => {}
 				}
-				step_count += 1;
 			}
 			
-			/*if self.machine.number_of_parsed_instructions > 2000000 {
-				//println!("MEM: {:?}", &machine.memory[0xb8000..0xb8000+0x1000]);
-				/*use std::io::Write;
-				println!("ds: {}", self.machine.get_reg_u16(Reg::DS));
-				let mut file = std::fs::File::create("memdmp.dat").unwrap();
-				file.write_all(&self.machine.memory);*/
-				let ds = self.machine.get_reg_u16(Reg::DS) as u32;
-				/*for i in 0..16 {
-					let addr = (ds<<4)+((i<<9)+0x8d0a);
-					let length = self.machine.peek_u16(addr) as u32;
-					let mut arrstr = "[".to_string();
-					//println!("length: {}", length);
-					for sound_index in 0..length {
-						let sound_addr = addr + ((sound_index * 2) + 2);
-						let entry = self.machine.peek_u16(sound_addr);
-						arrstr += &format!("{}, ", entry);
-						//println!("> {}", entry);
+			// Wait until the next timer tick is due.
+			let now_ms = get_ms_from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+			next_frame_time_ms += TIMER_TICK_MS;
+			if self.fast_forward || next_frame_time_ms < now_ms as f64 {
+				// Don't try to catch up on frames that were missed.
+				next_frame_time_ms = now_ms as f64;
+			} else {
+				std::thread::sleep(std::time::Duration::from_micros(((next_frame_time_ms - now_ms as f64) * 1000.) as u64));
+			}
+		}
+	}
+	
+	// Runs in the terminal instead of a window, for when there's no display, such as over SSH. Only
+	// the text screen is shown, and there's no sound. Ctrl+] quits, as Ctrl+C goes to the program.
+	#[cfg(unix)]
+	fn run_terminal(&mut self) {
+		let mut terminal = match RawTerminal::new() {
+			Ok(terminal) => terminal,
+			Err(err) => {
+				eprintln!("Can't use the terminal: {}", err);
+				return;
+			}
+		};
+		let message = self.run_terminal_frames(&mut terminal);
+		drop(terminal);
+		if let Some(message) = message {
+			println!("{}", message);
+		}
+	}
+	
+	#[cfg(not(unix))]
+	fn run_terminal(&mut self) {
+		eprintln!("Running in the terminal is only supported on Unix");
+	}
+	
+	// Runs frames until the program finishes or Ctrl+] is pressed. Anything printed while the
+	// terminal is in use would go to the alternate screen and be lost, so the message to print
+	// afterwards is returned instead.
+	#[cfg(unix)]
+	fn run_terminal_frames(&mut self, terminal: &mut RawTerminal) -> Option<String> {
+		let mut screen = AnsiScreen::new(80, 25);
+		let mut next_frame_time_ms = get_ms_from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap()) as f64;
+		loop {
+			if !self.take_terminal_keys(terminal) {
+				return None;
+			}
+			if self.batch_waiting_for_key {
+				if !self.continue_batch() {
+					return None;
+				}
+			} else {
+				let frame_start_ms = next_frame_time_ms;
+				let mut frame_run = self.start_frame();
+				loop {
+					match frame_run.run_slice(&mut self.emulator.machine, &mut self.emulator.handler) {
+						Ok(SliceEnd::Slice) => {
+							sleep_until_ms(frame_start_ms + TIMER_TICK_MS * frame_run.finished_slices() as f64 / frame_run.slice_count() as f64);
+							if !self.take_terminal_keys(terminal) {
+								return None;
+							}
+						}
+						Ok(SliceEnd::Frame) => break,
+						Ok(SliceEnd::Exited(exit_code)) => {
+							if !self.continue_batch() {
+								return Some(format!("Program exited with code {}", exit_code));
+							}
+							break;
+						}
+						Err(err) => return Some(format!("Step error: {}", err)),
 					}
-					arrstr += "]";
-					println!("{}", arrstr);
-				}*/
-				for i in (0..256*2).step_by(2) {
-					let addr = (ds<<4)+(i+0x89f4);
-					let num = self.machine.peek_u16(addr);
-					println!("{}: {}", i/2, num);
 				}
-				
-				panic!();
-			}*/
+				self.record_video_frame();
+			}
+			self.emulator.handler.speaker_events.clear();
+			
+			let cursor = self.emulator.handler.get_cursor_scanlines(&self.emulator.machine)
+				.map(|_| self.emulator.handler.get_cursor_position(&self.emulator.machine));
+			screen.set_blink_enabled(self.emulator.handler.blink_enabled);
+			terminal.write(&screen.update(&self.emulator.handler.get_composed_text(&self.emulator.machine), cursor));
 			
-			self.draw_screen(&mut canvas, &mut dosfont_tex, false);
+			let now_ms = get_ms_from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap()) as f64;
+			next_frame_time_ms = (next_frame_time_ms + TIMER_TICK_MS).max(now_ms);
+			sleep_until_ms(next_frame_time_ms);
+		}
+	}
+	
+	// Gives the program the keys typed in the terminal. Returns false if Ctrl+] was pressed.
+	#[cfg(unix)]
+	fn take_terminal_keys(&mut self, terminal: &mut RawTerminal) -> bool {
+		let input = terminal.read_input();
+		if input.contains(&TERMINAL_QUIT_CHAR) {
+			return false;
+		}
+		for key_press in parse_terminal_keys(&input) {
+			self.emulator.handler.key_press_queue.push_back(key_press);
+			self.macro_engine.real_key_pressed();
+		}
+		true
+	}
+}
+
+// Centres the rendered image in the window at the given scale, with the window size in window
+// pixels.
+fn set_viewport(canvas: &mut WindowCanvas, window_width: u32, window_height: u32, render_dims: (u32, u32), scale: u32) {
+	let (render_width, render_height) = (render_dims.0 * scale, render_dims.1 * scale);
+	canvas.set_viewport(Rect::new(window_width as i32 / 2 - render_width as i32 / 2, window_height as i32 / 2 - render_height as i32 / 2, render_width, render_height));
+}
+
+// Reads the font image, which has the 256 characters side by side, with the pixels that are part
+// of a character being opaque.
+fn load_font(path: &Path) -> Result<TextFont, String> {
+	let surface = Surface::from_file(path)?;
+	let format = surface.pixel_format_enum();
+	let alpha_mask = format.into_masks()?.amask;
+	let pitch = surface.pitch() as usize;
+	surface.with_lock(|pixels| TextFont::from_alpha_pixels(pixels, pitch, format.byte_size_per_pixel(), alpha_mask))
+		.map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+// The text screen is drawn into an image in software (see dos_text_raster), which is uploaded to a
+// streaming texture once a frame and copied to the window in one go, with SDL doing the scaling.
+struct ScreenImage<'a> {
+	font: TextFont,
+	raster: TextRaster,
+	texture_creator: &'a TextureCreator<WindowContext>,
+	texture: Texture<'a>,
+}
 
-			self.current_run_time_ms += 5;
-			canvas.present();
+impl<'a> ScreenImage<'a> {
+	fn new(font: TextFont, texture_creator: &'a TextureCreator<WindowContext>, render_dims: (u32, u32)) -> ScreenImage<'a> {
+		ScreenImage {
+			font,
+			raster: TextRaster::new(),
+			texture_creator,
+			texture: texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, render_dims.0, render_dims.1).unwrap(),
+		}
+	}
+	
+	// Makes a new texture for when the video mode changes the size of the screen.
+	fn resize(&mut self, render_dims: (u32, u32)) {
+		self.texture = self.texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, render_dims.0, render_dims.1).unwrap();
+	}
+	
+	// Uploads the rendered image and copies it to the canvas.
+	fn present(&mut self, canvas: &mut WindowCanvas) {
+		let query = self.texture.query();
+		if (query.width as usize, query.height as usize) != (self.raster.width, self.raster.height) {
+			// The mode changed part way through a frame, so the texture is a frame behind.
+			return;
+		}
+		self.texture.update(None, &self.raster.pixels, self.raster.pitch()).expect("Texture update failed");
+		canvas.copy(&self.texture, None, None).expect("Render failed");
+	}
+	
+	fn save_bmp(&self, path: &str) -> Result<(), String> {
+		let mut pixels = self.raster.pixels.clone();
+		let surface = Surface::from_data(&mut pixels, self.raster.width as u32, self.raster.height as u32, self.raster.pitch() as u32, PixelFormatEnum::RGB24)?;
+		surface.save_bmp(path)
+	}
+}
+
+// Finds the compatibility profile to use: the one given with --profile, which is either the name of
+// a built-in profile or a profile file, or else the profile file in the program's directory, or
+// else the built-in profile for the program, if there's one.
+fn find_profile(profile_arg: Option<&str>, options: &EmulatorOptions) -> Result<Option<CompatProfile>, String> {
+	let read_profile = |path: &Path| {
+		let text = std::fs::read_to_string(path).map_err(|err| format!("Can't read profile {}: {}", path.display(), err))?;
+		let mut profile = CompatProfile::parse(&text, &path.to_string_lossy())?;
+		if profile.name.is_empty() {
+			profile.name = path.display().to_string();
+		}
+		Ok(profile)
+	};
+	match profile_arg {
+		Some(profile_arg) => match CompatProfile::built_in_named(profile_arg) {
+			Some(profile) => Ok(Some(profile)),
+			None => read_profile(Path::new(profile_arg)).map(Some),
+		},
+		None => {
+			let profile_path = options.dos.host_directory.join(PROFILE_FILE_NAME);
+			if profile_path.exists() {
+				read_profile(&profile_path).map(Some)
+			} else {
+				Ok(CompatProfile::built_in_for_program(&options.dos.program_name))
+			}
+		}
+	}
+}
+
+// Prints what --inspect-exe shows about an EXE file.
+fn inspect_exe(path: &str) -> Result<(), ExeLoadError> {
+	let exe_data = std::fs::read(path)?;
+	let mut stream = std::io::Cursor::new(&exe_data);
+	let exe_header = MzHeader::parse(&mut stream)?;
+	print!("{}", exe_header.describe());
+	exe_header.extract_data(&mut stream)?;
+	exe_header.read_relocations(&mut stream)?;
+	println!("File: {} bytes, {} after the image", exe_data.len(), exe_data.len() - exe_header.data_end());
+	Ok(())
+}
+
+// Runs the built-in self-test for --self-test in a fresh directory that's removed afterwards,
+// printing the program's screen and then the checks that failed. Returns the exit code.
+fn run_self_test() -> i32 {
+	let work_dir = std::env::temp_dir().join(format!("pseudos_self_test_{}", std::process::id()));
+	std::fs::remove_dir_all(&work_dir).ok();
+	if let Err(err) = std::fs::create_dir_all(&work_dir) {
+		eprintln!("Can't make {}: {}", work_dir.display(), err);
+		return 1;
+	}
+	let result = SelfTest::new(&work_dir).and_then(|self_test| self_test.run());
+	std::fs::remove_dir_all(&work_dir).ok();
+	match result {
+		Ok(report) => {
+			println!("{}", report.screen_text.trim_end());
+			if report.passed() {
+				println!("Self-test passed: {} checks", report.checks.len());
+				0
+			} else {
+				eprintln!("Self-test failed: {}", report.failed_checks().join(", "));
+				1
+			}
+		}
+		Err(err) => {
+			eprintln!("Self-test didn't finish: {}", err);
+			1
 		}
 	}
 }
 
 fn main() {
-	let mut file = std::fs::File::open("./junk/dos/ZZT.EXE").unwrap();
-	let exe_header = MzHeader::parse(&mut file).unwrap();
-	println!("{:#?}", exe_header);
-	let mut machine = Machine8086::new(1024*1024*1);
-	exe_header.load_into_machine(&mut machine, &mut file);
-	let mut event_handler = DosEventHandler {
-		machine_type: MachineType::EGA,
-		video_mode: MachineType::EGA.lookup_video_mode(3).unwrap(),
-		port_states: PortStates::new(),
-		file_system: Box::new(StandardDosFileSystem::new("./junk/dos".into())),
-		disk_trasnsfer_address: 0,
-		seconds_since_start: 0.,
-		key_mod: 0,
-		result: DosInterruptResult::ShouldReturn,
-		key_press_queue: std::collections::VecDeque::new(),
-		
+	let mut options = EmulatorOptions::default();
+	let mut audit_args = vec![];
+	let mut chdir_path = None;
+	let mut persist_attributes = false;
+	let mut patches = PatchSet::default();
+	let mut volume_serial_number = None;
+	let mut terminal = false;
+	let mut batch_file_path = None;
+	let mut video_stats_args = vec![];
+	let mut inspect_exe_path = None;
+	let mut macro_engine = MacroEngine::default();
+	let mut palette_preset = None;
+	let mut memory_dump_range = None;
+	let mut keymap = Keymap::default();
+	let mut record_video_target = None;
+	let mut write_config_path = None;
+	let mut program_args = vec![];
+	let mut args: Vec<String> = std::env::args().skip(1).collect();
+	if args.iter().any(|arg| arg == "--help") {
+		print!("{}", USAGE);
+		return;
+	}
+	// The config file goes first, and then the profile, so the other arguments can change what they
+	// set.
+	if let Some(index) = args.iter().position(|arg| arg == "--config") {
+		let config_path = args.get(index + 1).cloned().expect("--config needs a config file argument");
+		args.drain(index ..= index + 1);
+		let config_text = std::fs::read_to_string(&config_path).unwrap_or_else(|err| {
+			eprintln!("Can't read {}: {}", config_path, err);
+			std::process::exit(1);
+		});
+		options = EmulatorOptions::from_config_text(&config_text).unwrap_or_else(|err| {
+			eprintln!("{}: {}", config_path, err);
+			std::process::exit(1);
+		});
+	}
+	let profile_arg = args.iter().position(|arg| arg == "--profile").map(|index| {
+		let profile_arg = args.get(index + 1).cloned().expect("--profile needs a profile name or file argument");
+		args.drain(index ..= index + 1);
+		profile_arg
+	});
+	match find_profile(profile_arg.as_deref(), &options) {
+		Ok(Some(profile)) => {
+			// A profile that's picked for the program without asking is used quietly.
+			if profile_arg.is_some() {
+				eprintln!("Using the {} compatibility profile", profile.name);
+			}
+			profile.apply(&mut options);
+		}
+		Ok(None) => {}
+		Err(err) => {
+			eprintln!("{}", err);
+			std::process::exit(1);
+		}
+	}
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		if arg == "--audit" || arg.starts_with("--audit=") {
+			options.dos.audit = true;
+			audit_args.push(arg);
+		} else if arg == "--video-stats" || arg.starts_with("--video-stats=") {
+			options.video.stats = true;
+			video_stats_args.push(arg);
+		} else if arg == "--env" {
+			let var = args.next().expect("--env needs a NAME=value argument");
+			let var = parse_environment_var(var.as_bytes()).unwrap_or_else(|err| {
+				eprint!("{}\n\n{}", err, USAGE);
+				std::process::exit(1);
+			});
+			options.dos.environment_vars.push(var);
+		} else if arg == "--background=pause" {
+			options.input.background_mode = BackgroundMode::Pause;
+		} else if arg == "--background=slow" {
+			options.input.background_mode = BackgroundMode::SlowTick;
+		} else if arg == "--sync-clock" {
+			options.input.resume_clock = ResumeClock::SyncToHost;
+		} else if arg == "--persist-attributes" {
+			persist_attributes = true;
+		} else if arg == "--dos-output=screen" {
+			options.dos.output_mode = DosOutputMode::Screen;
+		} else if arg == "--dos-output=stdout" {
+			options.dos.output_mode = DosOutputMode::Stdout;
+		} else if arg == "--dos-output=both" {
+			options.dos.output_mode = DosOutputMode::Both;
+		} else if arg == "--slices-per-frame" {
+			let slices = args.next().expect("--slices-per-frame needs a number");
+			options.cpu.frame_slicing.slices_per_frame = slices.parse().expect("--slices-per-frame needs a number");
+		} else if arg == "--mid-frame-present=never" {
+			options.cpu.frame_slicing.mid_frame_present = MidFramePresent::Never;
+		} else if let Some(fraction) = arg.strip_prefix("--mid-frame-present=") {
+			let fraction = fraction.parse().expect("--mid-frame-present needs \"never\" or a fraction");
+			options.cpu.frame_slicing.mid_frame_present = MidFramePresent::WhenChanged(fraction);
+		} else if let Some(country_code) = arg.strip_prefix("--country=") {
+			options.dos.country_code = country_code.parse().expect("--country needs a country code");
+		} else if arg == "--patch" {
+			let patch_path = args.next().expect("--patch needs a patch file argument");
+			let patch_set = std::fs::read_to_string(&patch_path).map_err(|err| err.to_string()).and_then(|text| PatchSet::parse(&text, &patch_path)).unwrap_or_else(|err| {
+				eprintln!("Can't read patch file {}: {}", patch_path, err);
+				std::process::exit(1);
+			});
+			patches.extend(patch_set);
+		} else if arg == "--refresh-rate" {
+			let rate = args.next().expect("--refresh-rate needs a number of Hz");
+			options.video.refresh_rate_hz = Some(rate.parse().expect("--refresh-rate needs a number of Hz"));
+		} else if arg == "--macros" {
+			let macros_path = args.next().expect("--macros needs a macro file argument");
+			macro_engine = std::fs::read_to_string(&macros_path).map_err(|err| err.to_string()).and_then(|text| MacroEngine::parse(&text, &macros_path)).unwrap_or_else(|err| {
+				eprintln!("Can't read macro file {}: {}", macros_path, err);
+				std::process::exit(1);
+			});
+			if macro_engine.macros.len() > 8 {
+				eprintln!("Only the first 8 macros in {} have hotkeys", macros_path);
+			}
+		} else if arg == "--keymap" {
+			let keymap_path = args.next().expect("--keymap needs a keymap file argument");
+			keymap = std::fs::read_to_string(&keymap_path).map_err(|err| err.to_string()).and_then(|text| Keymap::parse(&text, &keymap_path)).unwrap_or_else(|err| {
+				eprintln!("Can't read keymap file {}: {}", keymap_path, err);
+				std::process::exit(1);
+			});
+		} else if arg == "--record-video" {
+			record_video_target = Some(args.next().expect("--record-video needs a file or |command argument"));
+		} else if arg == "--volume-serial" {
+			let serial = args.next().expect("--volume-serial needs a serial number like 1234-ABCD");
+			volume_serial_number = Some(u32::from_str_radix(&serial.replace('-', ""), 16).expect("--volume-serial needs a serial number like 1234-ABCD"));
+		} else if arg == "--floppy" {
+			options.dos.floppy_image = Some(args.next().expect("--floppy needs a disk image argument").into());
+		} else if arg == "--floppy-write-protect" {
+			options.dos.floppy_write_protected = true;
+		} else if arg == "--batch" {
+			batch_file_path = Some(args.next().expect("--batch needs a batch file argument"));
+		} else if arg == "--inspect-exe" {
+			inspect_exe_path = Some(args.next().expect("--inspect-exe needs an EXE file argument"));
+		} else if arg == "--terminal" {
+			terminal = true;
+		} else if arg == "--mouse" {
+			options.input.mouse = true;
+		} else if arg == "--palette" {
+			let preset_name = args.next().expect("--palette needs a preset name");
+			palette_preset = Some(PalettePreset::from_name(&preset_name).unwrap_or_else(|| {
+				eprintln!("Unknown palette {}", preset_name);
+				std::process::exit(1);
+			}));
+		} else if arg == "--dump-range" {
+			let range = args.next().expect("--dump-range needs a START-END argument");
+			memory_dump_range = Some(parse_memory_range(&range).unwrap_or_else(|err| {
+				eprintln!("{}", err);
+				std::process::exit(1);
+			}));
+		} else if arg == "--protect-low-memory=off" {
+			options.dos.low_memory_protection = LowMemoryProtection::Off;
+		} else if arg == "--protect-low-memory=report" {
+			options.dos.low_memory_protection = LowMemoryProtection::Report;
+		} else if arg == "--protect-low-memory=restore" {
+			options.dos.low_memory_protection = LowMemoryProtection::Restore;
+		} else if arg == "--trace=vectors" {
+			options.dos.trace_vectors = true;
+		} else if arg == "--write-config" {
+			write_config_path = Some(args.next().expect("--write-config needs a file argument"));
+		} else if arg == "--self-test" {
+			std::process::exit(run_self_test());
+		} else if arg == "--chdir" {
+			chdir_path = Some(args.next().expect("--chdir needs a path argument"));
+		} else {
+			program_args.push(arg);
+		}
+	}
+	if let Some(inspect_exe_path) = inspect_exe_path {
+		std::process::exit(match inspect_exe(&inspect_exe_path) {
+			Ok(()) => 0,
+			Err(err) => {
+				eprintln!("{}: {}", inspect_exe_path, err);
+				1
+			}
+		});
+	}
+	let palette_path = options.dos.host_directory.join(PALETTE_FILE_NAME);
+	if let Ok(palette_text) = std::fs::read_to_string(&palette_path) {
+		options.video.palette = PaletteTransform::parse(&palette_text, &palette_path.to_string_lossy()).unwrap_or_else(|err| {
+			eprintln!("Can't read palette file {}", err);
+			std::process::exit(1);
+		});
+	}
+	if let Some(palette_preset) = palette_preset {
+		options.video.palette.preset = palette_preset;
+	}
+	if let Some(write_config_path) = write_config_path {
+		if let Err(err) = std::fs::write(&write_config_path, options.to_config_text()) {
+			eprintln!("Can't write {}: {}", write_config_path, err);
+			std::process::exit(1);
+		}
+		return;
+	}
+	let audit_json_path = audit_args.iter().filter_map(|arg| arg.split_once('=')).map(|(_, path)| path.to_string()).next_back();
+	let video_stats_json_path = video_stats_args.iter().filter_map(|arg| arg.split_once('=')).map(|(_, path)| path.to_string()).next_back();
+	let mut file_system = StandardDosFileSystem::new(options.dos.host_directory.clone());
+	if persist_attributes {
+		file_system.enable_attribute_persistence();
+	}
+	if let Some(serial_number) = volume_serial_number {
+		file_system.set_volume_serial_number(serial_number);
+	}
+	let mut drive_table = DosDriveTable::new();
+	drive_table.mount(2, Box::new(file_system)).unwrap();
+	if let Some(ref chdir_path) = chdir_path {
+		if let Err(err) = drive_table.set_current_directory(chdir_path.as_bytes()) {
+			eprintln!("Can't change to directory {}: {:?}", chdir_path, err);
+			std::process::exit(1);
+		}
+	}
+	let batch_path = program_args.first().filter(|arg| arg.to_ascii_uppercase().ends_with(".BAT")).cloned();
+	if batch_path.is_some() && !patches.entries.is_empty() {
+		eprintln!("--patch can't be used with a batch file");
+		std::process::exit(1);
+	}
+	if batch_path.is_some() && batch_file_path.is_some() {
+		eprintln!("--batch can't be used with a batch file on the C: drive");
+		std::process::exit(1);
+	}
+	let mut program = None;
+	let mut main_program = None;
+	if batch_path.is_none() {
+		let exe_data = std::fs::read(options.dos.host_directory.join(&options.dos.program_name)).unwrap();
+		let exe_header = MzHeader::parse(&mut std::io::Cursor::new(&exe_data)).unwrap_or_else(|err| {
+			eprintln!("Failed to load {}: {}", options.dos.program_name, err);
+			std::process::exit(1);
+		});
+		println!("{:#?}", exe_header);
+		let command_line_tail = build_command_tail(&program_args).unwrap();
+		if batch_file_path.is_some() {
+			main_program = Some(MainProgram { exe_data, command_line_tail });
+		} else {
+			program = Some(ProgramImage {
+				data: exe_data,
+				dos_path: options.dos.program_dos_path(),
+				command_line_tail,
+				patches: patches.clone(),
+			});
+		}
+	}
+	let restart_program = program.clone();
+	let mut emulator = Emulator::new(program, Box::new(drive_table), options).unwrap_or_else(|err| {
+		eprintln!("{}", err);
+		std::process::exit(1);
+	});
+	let batch = if let Some(batch_file_path) = batch_file_path {
+		let batch_text = std::fs::read(&batch_file_path).unwrap_or_else(|err| {
+			eprintln!("Can't read {}: {}", batch_file_path, err);
+			std::process::exit(1);
+		});
+		Some(BatchInterpreter::new(&batch_text, emulator.options.dos.environment_vars.clone()))
+	} else {
+		batch_path.map(|batch_path| {
+			let batch_text = read_dos_file(emulator.handler.file_system_mut(), batch_path.as_bytes()).unwrap_or_else(|err| {
+				eprintln!("Can't read {}: {:?}", batch_path, err);
+				std::process::exit(1);
+			});
+			BatchInterpreter::new(&batch_text, emulator.options.dos.environment_vars.clone())
+		})
 	};
-	event_handler.init_machine(&mut machine);
 
     let mut console = DosConsole {
-		machine,
-		dos_event_handler: event_handler,
 		current_run_time_ms: 0,
+		pending_timer_interrupts: 0.,
+		speed_multiplier: emulator.options.cpu.speed_multiplier,
+		fast_forward: false,
+		background_policy: BackgroundPolicy::new(emulator.options.input.background_mode, emulator.options.input.resume_clock),
+		emulator,
+		batch,
+		batch_waiting_for_key: false,
+		main_program,
+		presented_text: vec![0; 0x1000],
+		patches,
+		macro_engine,
+		show_video_stats: false,
+		shown_leds: KeyboardLeds::default(),
+		memory_dump_range,
+		keymap,
+		restart_program,
+		restart_requested: false,
+		notice: None,
+		video_recording: None,
     };
-    console.run();
+	if let Some(record_video_target) = record_video_target {
+		match VideoRecording::open(&record_video_target, &console.emulator.handler) {
+			Ok(video_recording) => console.video_recording = Some(video_recording),
+			Err(err) => {
+				eprintln!("{}", err);
+				std::process::exit(1);
+			}
+		}
+	}
+    // The batch file runs up to its first program before the window opens.
+    if console.batch.is_none() || console.continue_batch() {
+		if terminal {
+			console.run_terminal();
+		} else {
+			console.run();
+		}
+	}
+	
+	if let Some(ref function_audit) = console.emulator.handler.function_audit {
+		print!("{}", function_audit.summary());
+		if let Some(audit_json_path) = audit_json_path {
+			if let Err(err) = std::fs::write(&audit_json_path, function_audit.to_json()) {
+				eprintln!("Failed to write audit report to {}: {}", audit_json_path, err);
+			}
+		}
+	}
+	if let Some(ref video_stats) = console.emulator.handler.video_stats {
+		print!("{}", video_stats.summary(console.emulator.handler.display_start_address()));
+		if let Some(video_stats_json_path) = video_stats_json_path {
+			if let Err(err) = std::fs::write(&video_stats_json_path, video_stats.to_json()) {
+				eprintln!("Failed to write video stats to {}: {}", video_stats_json_path, err);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test] fn test_get_ms_from_duration() {
+		assert_eq!(get_ms_from_duration(std::time::Duration::new(1, 999_999_999)), 1999);
+		// Over 49 days, which would overflow 32 bits.
+		assert_eq!(get_ms_from_duration(std::time::Duration::new(5_000_000_000, 123_000_000)), 5_000_000_000_123);
+	}
 }